@@ -0,0 +1,120 @@
+//! Replays recorded sessions of clients against the current server, so changes that would break clients
+//! already out there fail here rather than in the field. Transcripts are plaintexts as they went over the
+//! wire, see `transcripts/password-session.txt` for the format. A protocol change that keeps old clients
+//! working gets a new transcript; existing ones are only edited when breaking them is intended.
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+use vpn_server::server::Server;
+use vpn_shared::creds::Credentials;
+use vpn_shared::handshake;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::packet::ServerPacket;
+use vpn_shared::packet::HANDSHAKE_SESSION;
+use vpn_shared::packet::KEY_SIZE;
+
+fn encode(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Bytes of a transcript line, `None` for `..`.
+fn decode(line: &str) -> anyhow::Result<Vec<Option<u8>>> {
+  let digits: String = line.split_whitespace().collect();
+  if !digits.len().is_multiple_of(2) {
+    anyhow::bail!("Odd number of hex digits in {:?}", line);
+  }
+  (0..digits.len())
+    .step_by(2)
+    .map(|i| match &digits[i..i + 2] {
+      ".." => Ok(None),
+      byte => Ok(Some(u8::from_str_radix(byte, 16)?)),
+    })
+    .collect()
+}
+
+async fn replay(port: u16, transcript: &str) -> anyhow::Result<()> {
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  socket.connect((Ipv4Addr::LOCALHOST, port)).await?;
+  let ephemeral = KeyPair::generate();
+  let (mut key, mut session_id) = ([0u8; KEY_SIZE], HANDSHAKE_SESSION);
+
+  let lines = transcript.lines().enumerate().filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+  for (number, line) in lines {
+    let (direction, bytes) = line.split_at(1);
+    match direction {
+      ">" => {
+        let bytes = bytes
+          .replace("{key}", &encode(&ephemeral.public()))
+          .replace("{timestamp}", &encode(&handshake::unix_time().to_le_bytes()));
+        let plaintext =
+          decode(&bytes)?.into_iter().collect::<Option<Vec<_>>>().expect("sent bytes are known");
+        socket.send(&EncryptedPacket::seal(&key, session_id, &plaintext)?.to_bytes()).await?;
+      }
+      "<" => {
+        let mut buf = vec![0u8; 2048];
+        let len = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut buf)).await??;
+        let plaintext = EncryptedPacket::from_bytes(&buf[..len])?.open(&key)?;
+        let expected = decode(bytes)?;
+        let matches = plaintext.len() == expected.len()
+          && plaintext.iter().zip(&expected).all(|(byte, expected)| expected.is_none_or(|e| e == *byte));
+        assert!(matches, "line {}: expected {}, got {}", number + 1, bytes.trim(), encode(&plaintext));
+
+        if session_id == HANDSHAKE_SESSION {
+          let ServerPacket::KeyExchange { key: server_key, session_id: id, observed, .. } =
+            bincode::deserialize(&plaintext)?
+          else {
+            panic!("line {}: expected a key exchange", number + 1);
+          };
+          key = handshake::client_session_key(&ephemeral, &server_key, None, observed)?;
+          session_id = id;
+        }
+      }
+      _ => anyhow::bail!("line {}: unknown direction {:?}", number + 1, direction),
+    }
+  }
+  Ok(())
+}
+
+async fn serve(port: u16) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+  let server = Server::builder(Ipv4Addr::LOCALHOST, port)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![Credentials::new("old_client", "old_pass")])
+    .with_icmp_unreachable(true)
+    .build()
+    .await?;
+  let handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+  Ok(handle)
+}
+
+#[tokio::test]
+async fn test_password_session_transcript() -> anyhow::Result<()> {
+  let server_handle = serve(8200).await?;
+  replay(8200, include_str!("../transcripts/password-session.txt")).await?;
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_transform_transcript() -> anyhow::Result<()> {
+  let server_handle = serve(8201).await?;
+  replay(8201, include_str!("../transcripts/unknown-transform.txt")).await?;
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_raw_data_transcript() -> anyhow::Result<()> {
+  let server_handle = serve(8202).await?;
+  replay(8202, include_str!("../transcripts/raw-data-session.txt")).await?;
+  server_handle.abort();
+  Ok(())
+}
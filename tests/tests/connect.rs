@@ -1,12 +1,75 @@
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
 use std::str::FromStr;
 use std::sync::Once;
 use std::time::Duration;
 
+use ipnet::Ipv4Net;
+use tokio::net::UdpSocket;
 use tokio::time::sleep;
+use vpn_client::client::Backoff;
 use vpn_client::client::Client;
+use vpn_client::client::Refused;
+use vpn_client::fallback::TcpFallbackConfig;
+use vpn_client::rendezvous::RendezvousConfig as ClientRendezvousConfig;
+use vpn_client::ClientEvent;
+use vpn_server::authz::AuthRule;
+use vpn_server::authz::Condition;
+use vpn_server::authz::RuleAction;
+use vpn_server::bandwidth::BandwidthConfig;
+use vpn_server::cluster::Cluster;
+use vpn_server::cluster::ClusterConfig;
+use vpn_server::dns::InternalDns;
+use vpn_server::dns::InternalDnsConfig;
+use vpn_server::health;
+use vpn_server::health::AdminToken;
+use vpn_server::network::NetworkConfig;
+use vpn_server::network::Networks;
+use vpn_server::offload::OffloadConfig;
+use vpn_server::passwords::PasswordFile;
+use vpn_server::policy::GroupPolicy;
+use vpn_server::policy::Policies;
+use vpn_server::policy::Priority;
+use vpn_server::pool::AddressPool;
+use vpn_server::pool::AddressPoolConfig;
+use vpn_server::pool::PoolExhaustionConfig;
+use vpn_server::rekey::RekeyConfig;
+use vpn_server::reload::LoadedConfig;
+use vpn_server::rendezvous::RendezvousConfig;
+use vpn_server::revocation;
+use vpn_server::revocation::RevocationList;
 use vpn_server::server::Server;
+use vpn_server::workers::AdmissionConfig;
+use vpn_server::workers::SheddingConfig;
+use vpn_server::workers::WorkerConfig;
+use vpn_shared::cert::Certificate;
+use vpn_shared::cert::SigningKey;
 use vpn_shared::creds::Credentials;
+use vpn_shared::creds::KeyCredentials;
+use vpn_shared::dns;
+use vpn_shared::dns::Message;
+use vpn_shared::dns::RecordData;
+use vpn_shared::fragment;
+use vpn_shared::handshake;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::ip;
+use vpn_shared::limits::MAX_CREDENTIAL_LEN;
+use vpn_shared::packet::ClientPacket;
+use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::packet::ErrorCode;
+use vpn_shared::packet::Features;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::ReverseForward;
+use vpn_shared::packet::ServerPacket;
+use vpn_shared::packet::SessionId;
+use vpn_shared::packet::SessionParams;
+use vpn_shared::packet::HANDSHAKE_SESSION;
+use vpn_shared::packet::KEY_SIZE;
+use vpn_shared::socket::Network;
+use vpn_shared::transform;
 
 fn init_logging() {
   static INIT: Once = Once::new();
@@ -94,3 +157,2532 @@ async fn test_client_auth_failure() -> anyhow::Result<()> {
   server_handle.abort();
   Ok(())
 }
+
+#[tokio::test]
+async fn test_garbage_does_not_stop_server() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8002)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  sleep(Duration::from_millis(100)).await;
+
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  let unknown_session = EncryptedPacket::encrypt(&[1u8; KEY_SIZE], 0xdead, &ClientPacket::Ping)?;
+  socket.send_to(&unknown_session.to_bytes(), (Ipv4Addr::LOCALHOST, 8002)).await?;
+  socket.send_to(b"garbage", (Ipv4Addr::LOCALHOST, 8002)).await?;
+
+  sleep(Duration::from_millis(100)).await;
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8002)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .build()
+    .await?;
+
+  let client_handle = tokio::spawn(client.run());
+  sleep(Duration::from_millis(500)).await;
+
+  assert!(!client_handle.is_finished());
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_pinned_server_key() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server_key = KeyPair::generate();
+
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8003)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_private_key(server_key.secret())
+    .build()
+    .await?;
+
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  sleep(Duration::from_millis(100)).await;
+
+  let impostor = Client::builder(Ipv4Addr::LOCALHOST, 8003)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(1))
+    .with_server_public_key(KeyPair::generate().public())
+    .with_creds(credentials.clone())
+    .build()
+    .await?;
+
+  assert!(impostor.run().await.is_err());
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8003)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_server_public_key(server_key.public())
+    .with_creds(credentials.clone())
+    .build()
+    .await?;
+
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  // Clients that don't pin the key connect all the same.
+  let unpinned = Client::builder(Ipv4Addr::LOCALHOST, 8003)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .build()
+    .await?;
+  let mut unpinned_events = unpinned.subscribe();
+  let unpinned_handle = tokio::spawn(unpinned.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), unpinned_events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  unpinned_handle.abort();
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_revoked_key_is_disconnected() -> anyhow::Result<()> {
+  init_logging();
+
+  let client_key = KeyPair::generate();
+  let list = std::env::temp_dir().join(format!("vpn-revoked-{}", std::process::id()));
+  _ = std::fs::remove_file(&list);
+
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8004)
+    .with_client_keys(vec![KeyCredentials::new("alice", client_key.public())])
+    .with_revocation_list(RevocationList::load(list.clone())?)
+    .build()
+    .await?;
+
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  sleep(Duration::from_millis(100)).await;
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8004)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_key("alice".into(), client_key.clone())
+    .build()
+    .await?;
+
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  revocation::append(&list, &client_key.public())?;
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Disconnected { reason, .. } if reason == "Key revoked"));
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8004)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_key("alice".into(), client_key)
+    .build()
+    .await?;
+
+  match client.run().await {
+    Ok(_) => panic!("Expected the revoked key to be rejected"),
+    Err(e) => assert!(e.to_string().contains("Key revoked")),
+  }
+
+  _ = std::fs::remove_file(&list);
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_certificate_auth() -> anyhow::Result<()> {
+  init_logging();
+
+  let ca = SigningKey::from_bytes(&[9u8; 32]);
+  let client_key = KeyPair::generate();
+
+  let server =
+    Server::builder(Ipv4Addr::LOCALHOST, 8005).with_certificate_authority(ca.verifying_key()).build().await?;
+
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  sleep(Duration::from_millis(100)).await;
+
+  let expired = Certificate::issue(&ca, "alice", client_key.public(), Duration::ZERO)?;
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8005)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_certificate(expired, client_key.clone())
+    .build()
+    .await?;
+
+  match client.run().await {
+    Ok(_) => panic!("Expected the expired certificate to be rejected"),
+    Err(e) => assert!(e.to_string().contains("Certificate expired")),
+  }
+
+  // A certificate for someone else's key is useless without the private half.
+  let stolen = Certificate::issue(&ca, "alice", client_key.public(), Duration::from_secs(3600))?;
+  let thief = Client::builder(Ipv4Addr::LOCALHOST, 8005)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_certificate(stolen.clone(), KeyPair::generate())
+    .build()
+    .await?;
+
+  assert!(thief.run().await.is_err());
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8005)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_certificate(stolen, client_key)
+    .build()
+    .await?;
+
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect_until_refused() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let backoff =
+    Backoff { initial: Duration::from_millis(100), max: Duration::from_millis(500), jitter_pct: 0 };
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8006)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_millis(500))
+    .with_creds(credentials.clone())
+    .with_reconnect(backoff)
+    .build()
+    .await?;
+
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  // Nothing listens yet, so the client keeps trying until the server comes up.
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Reconnecting { attempt: 1, .. }));
+
+  let server =
+    Server::builder(Ipv4Addr::LOCALHOST, 8006).with_client_credentials(vec![credentials]).build().await?;
+
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  let connected = async {
+    while !matches!(events.recv().await?, ClientEvent::Connected) {}
+    anyhow::Ok(())
+  };
+  tokio::time::timeout(Duration::from_secs(5), connected).await??;
+
+  let impostor = Client::builder(Ipv4Addr::LOCALHOST, 8006)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(Credentials::from_str("test_user:wrong_pass")?)
+    .with_reconnect(backoff)
+    .build()
+    .await?;
+
+  let mut events = impostor.subscribe();
+  let result = tokio::time::timeout(Duration::from_secs(5), impostor.run()).await?;
+  assert!(result.is_err());
+  assert!(matches!(events.try_recv()?, ClientEvent::Stopped { code: ErrorCode::InvalidCredentials, .. }));
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_version_too_old() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  // No version supports compression, so every client is too old.
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8050)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_required_features(Features::COMPRESSION)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  let backoff =
+    Backoff { initial: Duration::from_millis(100), max: Duration::from_millis(500), jitter_pct: 0 };
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8050)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .with_reconnect(backoff)
+    .build()
+    .await?;
+
+  let mut events = client.subscribe();
+  let result = tokio::time::timeout(Duration::from_secs(5), client.run()).await?;
+  assert!(result.unwrap_err().to_string().contains("lacks compression"));
+  assert!(matches!(events.try_recv()?, ClientEvent::Stopped { code: ErrorCode::VersionTooOld, .. }));
+
+  server_handle.abort();
+  Ok(())
+}
+
+async fn send(
+  socket: &UdpSocket,
+  (key, session_id): (Key, SessionId),
+  packet: ClientPacket,
+) -> anyhow::Result<()> {
+  socket.send(&EncryptedPacket::encrypt(&key, session_id, &packet)?.to_bytes()).await?;
+  Ok(())
+}
+
+async fn recv(socket: &UdpSocket, key: &Key) -> anyhow::Result<ServerPacket> {
+  let mut buf = vec![0u8; 2048];
+  let len = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut buf)).await??;
+  EncryptedPacket::from_bytes(&buf[..len])?.decrypt(key)
+}
+
+/// Opens a session with the server on `port` from a new socket, authenticated with `credentials`.
+async fn connect(port: u16, credentials: Credentials) -> anyhow::Result<(UdpSocket, (Key, SessionId))> {
+  let (socket, session) = handshake(port).await?;
+  send(&socket, session, ClientPacket::Auth(credentials)).await?;
+  Ok((socket, session))
+}
+
+/// Opens a session with the server on `port` from a new socket without authenticating.
+async fn handshake(port: u16) -> anyhow::Result<(UdpSocket, (Key, SessionId))> {
+  pinned_handshake(port, None).await
+}
+
+/// Like `handshake`, with a server that has a static key.
+async fn pinned_handshake(
+  port: u16,
+  server_key: Option<&Key>,
+) -> anyhow::Result<(UdpSocket, (Key, SessionId))> {
+  let (socket, session, _) = feature_handshake(port, server_key, None).await?;
+  Ok((socket, session))
+}
+
+/// Like `pinned_handshake`, offering `features`; also returns the ones the server answered with.
+async fn feature_handshake(
+  port: u16,
+  server_key: Option<&Key>,
+  features: Option<Features>,
+) -> anyhow::Result<(UdpSocket, (Key, SessionId), Option<Features>)> {
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  socket.connect((Ipv4Addr::LOCALHOST, port)).await?;
+  let ephemeral = KeyPair::generate();
+  // Servers only mix their static key in for clients saying they pinned it.
+  let features = match server_key {
+    Some(_) => Some(features.unwrap_or(Features::LEGACY).union(Features::PINNED_KEY)),
+    None => features,
+  };
+  let key_exchange = ClientPacket::KeyExchange {
+    key: ephemeral.public(),
+    transforms: Vec::new(),
+    timestamp: handshake::unix_time(),
+    features,
+    limits: None,
+  };
+  socket
+    .send(&EncryptedPacket::encrypt(&[0u8; KEY_SIZE], HANDSHAKE_SESSION, &key_exchange)?.to_bytes())
+    .await?;
+  let ServerPacket::KeyExchange { key, session_id, observed, features, time, .. } =
+    recv(&socket, &[0u8; KEY_SIZE]).await?
+  else {
+    panic!("Expected a key exchange");
+  };
+  let mut session_key = handshake::client_session_key(&ephemeral, &key, server_key, observed)?;
+  if let Some(time) = time {
+    session_key = handshake::bind_time(&session_key, time);
+  }
+  Ok((socket, (session_key, session_id), features))
+}
+
+#[tokio::test]
+async fn test_roaming_requires_path_validation() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8007)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let server_addr = (Ipv4Addr::LOCALHOST, 8007);
+  let (old, session) = connect(8007, credentials).await?;
+  let session_key = session.0;
+  assert!(matches!(recv(&old, &session_key).await?, ServerPacket::AuthOk));
+
+  // The session doesn't move until the new address answers the challenge.
+  let new = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  new.connect(server_addr).await?;
+  send(&new, session, ClientPacket::Ping).await?;
+  let ServerPacket::PathChallenge(nonce) = recv(&new, &session_key).await? else {
+    panic!("Expected a path challenge");
+  };
+
+  send(&new, session, ClientPacket::PathResponse(nonce.wrapping_add(1))).await?;
+  send(&old, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&old, &session_key).await?, ServerPacket::Pong));
+
+  send(&new, session, ClientPacket::PathResponse(nonce)).await?;
+  sleep(Duration::from_millis(100)).await;
+  send(&new, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&new, &session_key).await?, ServerPacket::Pong));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_features_negotiated() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8031)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_stats_interval(Duration::from_millis(100))
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  // A client from the future, without roaming and stats but with a feature unknown to the server.
+  let offered = Features::from_bits(Features::FRAGMENTATION.bits() | 1 << 31);
+  let (old, session, agreed) = feature_handshake(8031, None, Some(offered)).await?;
+  assert_eq!(agreed, Some(Features::FRAGMENTATION));
+  send(&old, session, ClientPacket::Auth(credentials)).await?;
+  assert!(matches!(recv(&old, &session.0).await?, ServerPacket::AuthOk));
+
+  // Neither are stats pushed nor is the session moved to a new address.
+  let new = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  new.connect((Ipv4Addr::LOCALHOST, 8031)).await?;
+  send(&new, session, ClientPacket::Ping).await?;
+  assert!(recv(&new, &session.0).await.is_err());
+  send(&old, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&old, &session.0).await?, ServerPacket::Pong));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_address_pool() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let dns = vec![Ipv4Addr::new(1, 1, 1, 1)];
+  let pool = AddressPoolConfig { subnet: "10.8.0.0/30".parse()?, dns };
+  let route: Ipv4Net = "10.50.0.0/16".parse()?;
+  let group_dns = vec![Ipv4Addr::new(10, 50, 0, 53)];
+  let policies = Policies::new(BTreeMap::from([(
+    "staff".to_string(),
+    GroupPolicy {
+      members: vec!["test_user".into()],
+      routes: vec![route],
+      dns: group_dns.clone(),
+      ..Default::default()
+    },
+  )]));
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8008)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_address_pool(AddressPool::new(pool, None))
+    .with_policies(policies)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (first, (key, _)) = connect(8008, credentials.clone()).await?;
+  assert!(matches!(recv(&first, &key).await?, ServerPacket::AuthOk));
+  let ServerPacket::NetworkConfig { address, prefix_len, dns: pushed } = recv(&first, &key).await? else {
+    panic!("Expected a network configuration");
+  };
+  // The group's resolvers take the place of the pool's, and its routes follow.
+  assert_eq!((address, prefix_len, pushed), (Ipv4Addr::new(10, 8, 0, 2), 30, group_dns));
+  assert!(
+    matches!(recv(&first, &key).await?, ServerPacket::Routes { revision: 1, routes } if routes == [route])
+  );
+
+  // The first host is the server's, so the pool is exhausted.
+  let (second, (key, _)) = connect(8008, credentials).await?;
+  assert!(matches!(recv(&second, &key).await?, ServerPacket::PoolExhausted { retry_after_secs: 30 }));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_reclaim() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let pool = AddressPoolConfig { subnet: "10.8.0.0/30".parse()?, dns: Vec::new() };
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8027)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_address_pool(AddressPool::new(pool, None))
+    .with_pool_exhaustion(PoolExhaustionConfig { retry_after_secs: 30, reclaim_idle_secs: Some(1) })
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (first, (first_key, _)) = connect(8027, credentials.clone()).await?;
+  assert!(matches!(recv(&first, &first_key).await?, ServerPacket::AuthOk));
+  assert!(matches!(recv(&first, &first_key).await?, ServerPacket::NetworkConfig { .. }));
+
+  // The only session hasn't been silent for long enough yet.
+  let (second, (key, _)) = connect(8027, credentials.clone()).await?;
+  assert!(matches!(recv(&second, &key).await?, ServerPacket::PoolExhausted { retry_after_secs: 30 }));
+
+  sleep(Duration::from_millis(1100)).await;
+  let (third, (key, _)) = connect(8027, credentials).await?;
+  assert!(matches!(
+    recv(&first, &first_key).await?,
+    ServerPacket::Disconnect { code: ErrorCode::SessionLost, .. }
+  ));
+  assert!(matches!(recv(&third, &key).await?, ServerPacket::AuthOk));
+  let ServerPacket::NetworkConfig { address, .. } = recv(&third, &key).await? else {
+    panic!("Expected a network configuration");
+  };
+  assert_eq!(address, Ipv4Addr::new(10, 8, 0, 2));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_transforms() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8009)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_transforms(vec![transform::PAD.into(), transform::LZ.into(), transform::XCHACHA20.into()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8009)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .with_transforms(vec![transform::LZ.into(), transform::XCHACHA20.into(), transform::PAD.into()])
+    .build()
+    .await?;
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(async move {
+    if let Err(e) = client.run().await {
+      eprintln!("Client error: {}", e);
+    }
+  });
+
+  // Authentication already goes through the compressing, re-encrypting and padding pipeline on both ends.
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_networks() -> anyhow::Result<()> {
+  init_logging();
+
+  let admin = Credentials::from_str("admin:admin_pass")?;
+  let alice = Credentials::from_str("alice:alice_pass")?;
+  let acme = NetworkConfig {
+    subnet: "10.9.0.0/24".parse()?,
+    dns: vec![Ipv4Addr::new(10, 9, 0, 53)],
+    client_credentials: vec![alice.clone()],
+    client_keys: Vec::new(),
+    groups: BTreeMap::new(),
+  };
+  let pool = AddressPoolConfig { subnet: "10.8.0.0/24".parse()?, dns: Vec::new() };
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8010)
+    .with_client_credentials(vec![admin.clone()])
+    .with_address_pool(AddressPool::new(pool, None))
+    .with_networks(Networks::new(BTreeMap::from([("acme".to_string(), acme)])))
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, (key, _)) = connect(8010, admin).await?;
+  assert!(matches!(recv(&socket, &key).await?, ServerPacket::AuthOk));
+  let ServerPacket::NetworkConfig { address, .. } = recv(&socket, &key).await? else {
+    panic!("Expected a network configuration");
+  };
+  assert_eq!(address, Ipv4Addr::new(10, 8, 0, 2));
+
+  // Tenant users get addresses and resolvers of their own network.
+  let (socket, (key, _)) = connect(8010, alice).await?;
+  assert!(matches!(recv(&socket, &key).await?, ServerPacket::AuthOk));
+  let ServerPacket::NetworkConfig { address, prefix_len, dns } = recv(&socket, &key).await? else {
+    panic!("Expected a network configuration");
+  };
+  assert_eq!((address, prefix_len, dns), (Ipv4Addr::new(10, 9, 0, 2), 24, vec![Ipv4Addr::new(10, 9, 0, 53)]));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_scoped_admin_tokens() -> anyhow::Result<()> {
+  init_logging();
+
+  let alice = Credentials::from_str("alice:alice_pass")?;
+  let bob = Credentials::from_str("bob:bob_pass")?;
+  let network = |subnet: &str, credentials: &Credentials| -> anyhow::Result<NetworkConfig> {
+    Ok(NetworkConfig {
+      subnet: subnet.parse()?,
+      dns: Vec::new(),
+      client_credentials: vec![credentials.clone()],
+      client_keys: Vec::new(),
+      groups: BTreeMap::new(),
+    })
+  };
+  let networks = BTreeMap::from([
+    ("acme".to_string(), network("10.9.0.0/24", &alice)?),
+    ("globex".to_string(), network("10.10.0.0/24", &bob)?),
+  ]);
+  let health_address: SocketAddr = "127.0.0.1:8011".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8011)
+    .with_networks(Networks::new(networks))
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "acme-token".into(), network: Some("acme".into()) }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (alice_socket, (alice_key, _)) = connect(8011, alice).await?;
+  assert!(matches!(recv(&alice_socket, &alice_key).await?, ServerPacket::AuthOk));
+  let (bob_socket, (bob_key, _)) = connect(8011, bob).await?;
+  assert!(matches!(recv(&bob_socket, &bob_key).await?, ServerPacket::AuthOk));
+
+  let admin = |token: &'static str, method: &'static str, path: &'static str| {
+    tokio::task::spawn_blocking(move || health::admin_request(health_address, Some(token), method, path, ""))
+  };
+
+  let clients = admin("acme-token", "GET", "/clients").await??;
+  assert!(clients.contains("\"username\": \"alice\"") && clients.contains("\"network\": \"acme\""));
+  assert!(!clients.contains("bob"));
+
+  // Sessions of other networks and server-wide settings are out of reach.
+  assert_eq!(admin("acme-token", "DELETE", "/clients/bob").await??, "0");
+  assert!(admin("acme-token", "GET", "/log-level").await?.is_err());
+  assert!(admin("wrong-token", "GET", "/clients").await?.is_err());
+
+  assert_eq!(admin("acme-token", "DELETE", "/clients/alice").await??, "1");
+  loop {
+    match recv(&alice_socket, &alice_key).await? {
+      ServerPacket::NetworkConfig { .. } => continue,
+      packet => {
+        assert!(matches!(packet, ServerPacket::Disconnect { code: ErrorCode::Kicked, .. }));
+        break;
+      }
+    }
+  }
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_cluster_takes_over_sessions() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let secret = handshake::encode_key(&[9; KEY_SIZE]);
+  let node = |port: u16, peer: u16| ClusterConfig {
+    listen: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+    peers: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, peer))],
+    secret: secret.clone(),
+    announce_interval_secs: 10,
+    redis: None,
+  };
+
+  let mut handles = Vec::new();
+  for (port, cluster) in [(8012, node(8112, 8113)), (8013, node(8113, 8112))] {
+    let server = Server::builder(Ipv4Addr::LOCALHOST, port)
+      .with_client_credentials(vec![credentials.clone()])
+      .with_cluster(Cluster::bind(cluster).await?)
+      .build()
+      .await?;
+    handles.push(tokio::spawn(async move {
+      if let Err(e) = server.run().await {
+        eprintln!("Server error: {}", e);
+      }
+    }));
+  }
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8012, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+  sleep(Duration::from_millis(100)).await;
+
+  // The other node knows the session from the announcement and answers without a new handshake.
+  socket.connect((Ipv4Addr::LOCALHOST, 8013)).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+  sleep(Duration::from_millis(100)).await;
+
+  // The first node dropped the session when it moved, and takes it back the same way.
+  socket.connect((Ipv4Addr::LOCALHOST, 8012)).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+
+  for handle in handles {
+    handle.abort();
+  }
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_stats() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_password")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8014)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_max_clients(5)
+    .with_stats_interval(Duration::from_millis(100))
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8014, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  let ServerPacket::Stats { sent, quota_remaining, clients, max_clients, .. } =
+    recv(&socket, &session.0).await?
+  else {
+    panic!("Expected stats");
+  };
+  assert_eq!((sent, quota_remaining, clients, max_clients), (0, None, 1, 5));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_fragmented_auth() -> anyhow::Result<()> {
+  init_logging();
+
+  // Credentials as long as a typical OIDC token don't fit into one datagram.
+  let credentials = Credentials::new("test_user".to_string(), "p".repeat(3000));
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8015)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = handshake(8015).await?;
+  let fragments = fragment::split(&bincode::serialize(&ClientPacket::Auth(credentials))?)?;
+  assert_eq!(fragments.len(), 3);
+  for fragment in fragments.into_iter().rev() {
+    send(&socket, session, ClientPacket::Fragment(fragment)).await?;
+  }
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_preemption() -> anyhow::Result<()> {
+  init_logging();
+
+  let low = Credentials::from_str("low:pass")?;
+  let high = Credentials::from_str("high:pass")?;
+  let policies = Policies::new(BTreeMap::from([(
+    "oncall".to_string(),
+    GroupPolicy { members: vec!["high".into()], priority: Priority::High, ..Default::default() },
+  )]));
+  // Sessions waiting for their login count towards the limit too.
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8016)
+    .with_client_credentials(vec![low.clone(), high.clone()])
+    .with_max_clients(3)
+    .with_policies(policies)
+    .with_preemption(Duration::from_millis(300))
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (active, active_session) = connect(8016, low.clone()).await?;
+  assert!(matches!(recv(&active, &active_session.0).await?, ServerPacket::AuthOk));
+  let (idle, (idle_key, _)) = connect(8016, low.clone()).await?;
+  assert!(matches!(recv(&idle, &idle_key).await?, ServerPacket::AuthOk));
+
+  sleep(Duration::from_millis(400)).await;
+  send(&active, active_session, ClientPacket::Data(vec![0x45; 20])).await?;
+  sleep(Duration::from_millis(50)).await;
+
+  let (first, (key, _)) = connect(8016, high.clone()).await?;
+  assert!(matches!(recv(&first, &key).await?, ServerPacket::AuthOk));
+  assert!(matches!(
+    recv(&idle, &idle_key).await?,
+    ServerPacket::Disconnect { code: ErrorCode::Preempted, .. }
+  ));
+
+  // The remaining normal session was active too recently, and low-priority users never preempt.
+  let (second, (key, _)) = connect(8016, high).await?;
+  assert!(matches!(recv(&second, &key).await?, ServerPacket::AuthError { code: ErrorCode::ServerFull, .. }));
+  let (third, (key, _)) = connect(8016, low).await?;
+  assert!(matches!(recv(&third, &key).await?, ServerPacket::AuthError { code: ErrorCode::ServerFull, .. }));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_icmp_unreachable() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  // Without a tun the server has nowhere to forward packets to.
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8017)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_icmp_unreachable(true)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8017, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  // TCP SYN from 10.0.0.2:4660 to 10.20.0.1:80.
+  let mut syn = vec![0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 20, 0, 1];
+  syn.extend([0x12, 0x34, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0]);
+  send(&socket, session, ClientPacket::Data(syn.clone())).await?;
+
+  let ServerPacket::Data(reply) = recv(&socket, &session.0).await? else {
+    panic!("Expected an ICMP error");
+  };
+  assert_eq!(&reply[12..20], [10, 20, 0, 1, 10, 0, 0, 2]);
+  assert_eq!(reply[9], 1);
+  assert_eq!(&reply[20..22], [3, 0]);
+  assert_eq!(&reply[28..], &syn[..28]);
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_replayed_key_exchange() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8018)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  socket.connect((Ipv4Addr::LOCALHOST, 8018)).await?;
+  let ephemeral = KeyPair::generate();
+  let key_exchange = |timestamp| {
+    let packet = ClientPacket::KeyExchange {
+      key: ephemeral.public(),
+      transforms: Vec::new(),
+      timestamp,
+      features: None,
+      limits: None,
+    };
+    EncryptedPacket::encrypt(&[0u8; KEY_SIZE], HANDSHAKE_SESSION, &packet).map(|packet| packet.to_bytes())
+  };
+  let captured = key_exchange(handshake::unix_time())?;
+  socket.send(&captured).await?;
+  let ServerPacket::KeyExchange { key, session_id, observed, .. } = recv(&socket, &[0u8; KEY_SIZE]).await?
+  else {
+    panic!("Expected a key exchange");
+  };
+  let session = (handshake::client_session_key(&ephemeral, &key, None, observed)?, session_id);
+  send(&socket, session, ClientPacket::Auth(credentials)).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  // Neither the replay nor an exchange with a stale timestamp gets an answer or replaces the session.
+  socket.send(&captured).await?;
+  socket.send(&key_exchange(handshake::unix_time() - 3600)?).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_strict_handshakes() -> anyhow::Result<()> {
+  init_logging();
+
+  // Strict by default, without any group asking for it.
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8036)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8036, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  let key_exchange = || {
+    let packet = ClientPacket::KeyExchange {
+      key: KeyPair::generate().public(),
+      transforms: Vec::new(),
+      timestamp: handshake::unix_time(),
+      features: None,
+      limits: None,
+    };
+    EncryptedPacket::encrypt(&[0u8; KEY_SIZE], HANDSHAKE_SESSION, &packet).map(|packet| packet.to_bytes())
+  };
+
+  // Anyone sending from the client's address could have sealed this one.
+  socket.send(&key_exchange()?).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+
+  send(&socket, session, ClientPacket::Rehandshake).await?;
+  sleep(Duration::from_millis(100)).await;
+  socket.send(&key_exchange()?).await?;
+  assert!(matches!(recv(&socket, &[0u8; KEY_SIZE]).await?, ServerPacket::KeyExchange { .. }));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_rekey() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let health_address: SocketAddr = "127.0.0.1:8038".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8037)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_rekeying(RekeyConfig { on_roaming: true, decrypt_failures: 3 })
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "token".into(), network: None }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session, _) = feature_handshake(8037, None, Some(Features::SUPPORTED)).await?;
+  send(&socket, session, ClientPacket::Auth(credentials.clone())).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  // Answers the server's rekey with the key it was sealed with, and returns the session with the new key.
+  async fn answer(
+    socket: &UdpSocket,
+    (key, session_id): (Key, SessionId),
+  ) -> anyhow::Result<(Key, SessionId)> {
+    let ServerPacket::Rekey { key: server_key } = recv(socket, &key).await? else {
+      panic!("Expected a rekey");
+    };
+    let ephemeral = KeyPair::generate();
+    send(socket, (key, session_id), ClientPacket::Rekey { key: ephemeral.public() }).await?;
+    sleep(Duration::from_millis(100)).await;
+    Ok((handshake::client_rekey(&ephemeral, &server_key, &key)?, session_id))
+  }
+
+  let admin = move |path: String| {
+    tokio::task::spawn_blocking(move || {
+      health::admin_request(health_address, Some("token"), "POST", &path, "")
+    })
+  };
+  assert_eq!(admin(format!("/rekey/{:016x}", session.1)).await??, "rekeying");
+  let session = answer(&socket, session).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+
+  // Moving to a new address.
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  socket.connect((Ipv4Addr::LOCALHOST, 8037)).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  let ServerPacket::PathChallenge(nonce) = recv(&socket, &session.0).await? else {
+    panic!("Expected a path challenge");
+  };
+  send(&socket, session, ClientPacket::PathResponse(nonce)).await?;
+  let session = answer(&socket, session).await?;
+
+  // Packets of the session that don't open.
+  for _ in 0..3 {
+    send(&socket, ([7u8; KEY_SIZE], session.1), ClientPacket::Ping).await?;
+  }
+  let session = answer(&socket, session).await?;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+
+  // Sessions of clients predating rekeying are left alone.
+  let (legacy, legacy_session) = connect(8037, credentials).await?;
+  assert!(matches!(recv(&legacy, &legacy_session.0).await?, ServerPacket::AuthOk));
+  assert!(admin(format!("/rekey/{:016x}", legacy_session.1)).await?.is_err());
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_devices() -> anyhow::Result<()> {
+  init_logging();
+
+  let laptop = KeyPair::generate();
+  let phone = KeyPair::generate();
+  let device = |name: &str, key: &KeyPair| KeyCredentials {
+    device: Some(name.to_string()),
+    ..KeyCredentials::new("alice", key.public())
+  };
+  let health_address: SocketAddr = "127.0.0.1:8019".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8019)
+    .with_client_keys(vec![device("laptop", &laptop), device("phone", &phone)])
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "token".into(), network: None }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut events = Vec::new();
+  let mut handles = Vec::new();
+  for key in [laptop, phone] {
+    let client = Client::builder(Ipv4Addr::LOCALHOST, 8019)
+      .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+      .with_connect_timeout(Duration::from_secs(5))
+      .with_key("alice".into(), key)
+      .build()
+      .await?;
+    let mut subscription = client.subscribe();
+    handles.push(tokio::spawn(client.run()));
+    let event = tokio::time::timeout(Duration::from_secs(5), subscription.recv()).await??;
+    assert!(matches!(event, ClientEvent::Connected));
+    events.push(subscription);
+  }
+
+  let admin = |method: &'static str, path: &'static str| {
+    tokio::task::spawn_blocking(move || {
+      health::admin_request(health_address, Some("token"), method, path, "")
+    })
+  };
+  let clients = admin("GET", "/clients").await??;
+  assert!(clients.contains("\"device\": \"laptop\"") && clients.contains("\"device\": \"phone\""));
+
+  // Devices are disconnected one by one.
+  assert_eq!(admin("DELETE", "/clients/alice/phone").await??, "1");
+  let event = tokio::time::timeout(Duration::from_secs(5), events[1].recv()).await??;
+  assert!(matches!(event, ClientEvent::Disconnected { .. }));
+  let clients = admin("GET", "/clients").await??;
+  assert!(clients.contains("\"device\": \"laptop\"") && !clients.contains("\"device\": \"phone\""));
+
+  for handle in handles {
+    handle.abort();
+  }
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_renegotiate() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8020)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_transforms(vec![transform::PAD.into()])
+    .with_packet_pipe(1500)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let params = SessionParams { mtu: 1500, keepalive_secs: 5, transforms: Vec::new() };
+  let (session, updates) = tokio::sync::watch::channel(params.clone());
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8020)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .with_session_params(updates)
+    .build()
+    .await?;
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(async move {
+    if let Err(e) = client.run().await {
+      eprintln!("Client error: {}", e);
+    }
+  });
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  // The server keeps the keepalive within bounds and picks the transforms it accepts, taking the padding
+  // out of the MTU.
+  session.send_replace(SessionParams {
+    mtu: 1500,
+    keepalive_secs: 3600,
+    transforms: vec![transform::PAD.into()],
+  });
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  let agreed = SessionParams { mtu: 1435, keepalive_secs: 300, transforms: vec![transform::PAD.into()] };
+  assert_eq!(event, ClientEvent::Renegotiated(agreed));
+
+  // Sealed with the padded pipeline both ends switched to.
+  session.send_replace(params.clone());
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert_eq!(event, ClientEvent::Renegotiated(params));
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_deferred_handshake() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  // Sheds handshakes at any fill, but never data.
+  let shedding =
+    SheddingConfig { handshake_fill_pct: 0, normal_fill_pct: 101, high_fill_pct: 101, retry_after_secs: 7 };
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8021)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_workers(WorkerConfig { shedding: Some(shedding), ..Default::default() })
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8021)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .build()
+    .await?;
+  let error = tokio::time::timeout(Duration::from_secs(5), client.run()).await?.unwrap_err();
+  let refused = error.downcast_ref::<Refused>().unwrap();
+  assert_eq!(refused.code, ErrorCode::Overloaded);
+  assert_eq!(refused.retry_after, Some(Duration::from_secs(7)));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_admission() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  // Admits a single key exchange and none after it.
+  let admission = AdmissionConfig { handshakes_per_sec: 0, burst: 1, retry_after_secs: 3 };
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8039)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_workers(WorkerConfig { admission: Some(admission), ..Default::default() })
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let client = || {
+    Client::builder(Ipv4Addr::LOCALHOST, 8039)
+      .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+      .with_connect_timeout(Duration::from_secs(5))
+      .with_creds(credentials.clone())
+      .build()
+  };
+  let first = client().await?;
+  let mut events = first.subscribe();
+  let first_handle = tokio::spawn(first.run());
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  let error = tokio::time::timeout(Duration::from_secs(5), client().await?.run()).await?.unwrap_err();
+  let refused = error.downcast_ref::<Refused>().unwrap();
+  assert_eq!(refused.code, ErrorCode::Overloaded);
+  // Stretched by up to its length, so deferred clients don't come back together.
+  let retry_after = refused.retry_after.unwrap();
+  assert!((Duration::from_secs(3)..=Duration::from_secs(6)).contains(&retry_after));
+
+  first_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_session_tickets() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let pool = AddressPoolConfig { subnet: "10.8.0.0/29".parse()?, dns: Vec::new() };
+  let server_key = KeyPair::generate();
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8022)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_address_pool(AddressPool::new(pool, None))
+    .with_private_key(server_key.secret())
+    .with_session_tickets(Duration::from_secs(3600))
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let connect = |credentials| async {
+    let (socket, session) = pinned_handshake(8022, Some(&server_key.public())).await?;
+    send(&socket, session, ClientPacket::Auth(credentials)).await?;
+    anyhow::Ok((socket, session))
+  };
+  let (first, first_session) = connect(credentials.clone()).await?;
+  assert!(matches!(recv(&first, &first_session.0).await?, ServerPacket::AuthOk));
+  let ServerPacket::NetworkConfig { address, .. } = recv(&first, &first_session.0).await? else {
+    panic!("Expected a network configuration");
+  };
+  let ServerPacket::Ticket { ticket, lifetime_secs } = recv(&first, &first_session.0).await? else {
+    panic!("Expected a ticket");
+  };
+  assert!(lifetime_secs > 3500 && lifetime_secs <= 3600);
+
+  let (other, other_session) = connect(credentials).await?;
+  assert!(matches!(recv(&other, &other_session.0).await?, ServerPacket::AuthOk));
+
+  // The client restarted: it resumes without its credentials, replacing the session it left behind and
+  // getting the address back.
+  let (resumed, session) = connect(Credentials::Ticket(ticket.clone())).await?;
+  assert!(matches!(recv(&resumed, &session.0).await?, ServerPacket::AuthOk));
+  assert!(
+    matches!(recv(&resumed, &session.0).await?, ServerPacket::NetworkConfig { address: a, .. } if a == address)
+  );
+  let ServerPacket::Ticket { lifetime_secs: renewed, .. } = recv(&resumed, &session.0).await? else {
+    panic!("Expected a ticket");
+  };
+  assert!(renewed <= lifetime_secs);
+  send(&first, first_session, ClientPacket::Ping).await?;
+  assert!(recv(&first, &first_session.0).await.is_err());
+
+  let (forged, session) = connect(Credentials::Ticket(vec![0; ticket.len()])).await?;
+  assert!(matches!(
+    recv(&forged, &session.0).await?,
+    ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, .. }
+  ));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_ping_flood_is_limited() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("flooder:secret")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8023)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8023, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  for _ in 0..50 {
+    send(&socket, session, ClientPacket::Ping).await?;
+  }
+  let mut pongs = 0;
+  while let Ok(packet) = recv(&socket, &session.0).await {
+    assert!(matches!(packet, ServerPacket::Pong));
+    pongs += 1;
+  }
+  assert!((10..=12).contains(&pongs), "{} pongs", pongs);
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_client_packet_pipe() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("embedder:secret")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8024)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![credentials.clone()])
+    .with_icmp_unreachable(true)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut client = Client::builder(Ipv4Addr::LOCALHOST, 8024)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .build()
+    .await?;
+  let handle = client.handle().unwrap();
+  let mut incoming = client.incoming_packets().unwrap();
+  assert!(client.incoming_packets().is_none());
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  // The server has no tun, so the packet comes back as unreachable from its destination.
+  let packet =
+    vec![0x45, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0, 10, 8, 0, 2, 10, 0, 0, 1, 0x30, 0x39, 0, 53, 0, 8, 0, 0];
+  handle.send_ip_packet(packet.clone()).await?;
+  let reply = tokio::time::timeout(Duration::from_secs(2), incoming.recv()).await?.unwrap();
+  assert_eq!((reply[9], &reply[12..16], reply[20]), (1, &[10, 0, 0, 1][..], 3));
+  assert_eq!(&reply[28..], &packet[..]);
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_server_packet_pipe() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("embedder:secret")?;
+  let mut server = Server::builder(Ipv4Addr::LOCALHOST, 8025)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![credentials.clone()])
+    .with_packet_pipe(1400)
+    .build()
+    .await?;
+  let server_packets = server.packet_handle().unwrap();
+  let mut from_clients = server.client_packets().unwrap();
+  assert!(server.client_packets().is_none());
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut client = Client::builder(Ipv4Addr::LOCALHOST, 8025)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .build()
+    .await?;
+  let handle = client.handle().unwrap();
+  let mut incoming = client.incoming_packets().unwrap();
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  let packet =
+    vec![0x45, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0, 10, 8, 0, 2, 10, 0, 0, 1, 0x30, 0x39, 0, 53, 0, 8, 0, 0];
+  handle.send_ip_packet(packet.clone()).await?;
+  let received = tokio::time::timeout(Duration::from_secs(2), from_clients.recv()).await?.unwrap();
+  assert_eq!(received.packet, packet);
+
+  // Answered by the embedding application instead of whatever a tun device leads to.
+  let reply =
+    vec![0x45, 0, 0, 28, 0, 2, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 8, 0, 2, 0, 53, 0x30, 0x39, 0, 8, 0, 0];
+  server_packets.send_ip_packet(reply.clone()).await?;
+  let received = tokio::time::timeout(Duration::from_secs(2), incoming.recv()).await?.unwrap();
+  assert_eq!(received, reply);
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_transport() -> anyhow::Result<()> {
+  init_logging();
+
+  // Linked in-process without sockets, so the addresses needn't exist on the host.
+  let network = Network::new();
+  let server_address = Ipv4Addr::new(192, 0, 2, 1);
+  let credentials = Credentials::from_str("embedder:secret")?;
+  let small =
+    Server::builder(server_address, 6968).with_packet_pipe(500).with_memory_transport(Network::new());
+  let error = small.build().await.err().expect("an MTU below the minimum is refused");
+  assert!(format!("{:#}", error).contains("MTU 500 is below the minimum"), "{:#}", error);
+
+  let mut server = Server::builder(server_address, 6969)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![credentials.clone()])
+    .with_packet_pipe(1400)
+    .with_memory_transport(network.clone())
+    .build()
+    .await?;
+  let server_packets = server.packet_handle().unwrap();
+  let mut from_clients = server.client_packets().unwrap();
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  let mut client = Client::builder(server_address, 6969)
+    .with_listen_address(Ipv4Addr::new(192, 0, 2, 2), 0)
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .with_memory_transport(network)
+    .build()
+    .await?;
+  let handle = client.handle().unwrap();
+  let mut incoming = client.incoming_packets().unwrap();
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  let packet =
+    vec![0x45, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0, 10, 8, 0, 2, 10, 0, 0, 1, 0x30, 0x39, 0, 53, 0, 8, 0, 0];
+  handle.send_ip_packet(packet.clone()).await?;
+  let received = tokio::time::timeout(Duration::from_secs(2), from_clients.recv()).await?.unwrap();
+  assert_eq!(received.client, "192.0.2.2:49152".parse()?);
+  assert_eq!(received.packet, packet);
+
+  let reply =
+    vec![0x45, 0, 0, 28, 0, 2, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 8, 0, 2, 0, 53, 0x30, 0x39, 0, 8, 0, 0];
+  server_packets.send_ip_packet(reply.clone()).await?;
+  let received = tokio::time::timeout(Duration::from_secs(2), incoming.recv()).await?.unwrap();
+  assert_eq!(received, reply);
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_subnet_behind_client() -> anyhow::Result<()> {
+  init_logging();
+
+  let network = Network::new();
+  let server_address = Ipv4Addr::new(192, 0, 2, 1);
+  let credentials = Credentials::from_str("branch:secret")?;
+  let lan: Ipv4Net = "192.168.50.0/24".parse()?;
+  let policies = Policies::new(BTreeMap::from([(
+    "branches".to_string(),
+    GroupPolicy {
+      members: vec!["branch".into()],
+      subnets: vec!["192.168.0.0/16".parse()?],
+      ..Default::default()
+    },
+  )]));
+  let mut server = Server::builder(server_address, 6969)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![credentials.clone()])
+    .with_policies(policies)
+    .with_packet_pipe(1400)
+    .with_memory_transport(network.clone())
+    .build()
+    .await?;
+  let server_packets = server.packet_handle().unwrap();
+  let mut from_clients = server.client_packets().unwrap();
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  let other: Ipv4Net = "172.16.0.0/24".parse()?;
+  let mut client = Client::builder(server_address, 6969)
+    .with_listen_address(Ipv4Addr::new(192, 0, 2, 2), 0)
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .with_subnets(vec![lan, other])
+    .with_memory_transport(network)
+    .build()
+    .await?;
+  let handle = client.handle().unwrap();
+  let mut incoming = client.incoming_packets().unwrap();
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let subnets = tokio::time::timeout(Duration::from_secs(5), async {
+    loop {
+      if let ClientEvent::Subnets { accepted, rejected } = events.recv().await? {
+        return anyhow::Ok((accepted, rejected));
+      }
+    }
+  })
+  .await??;
+  assert_eq!(subnets, (vec![lan], vec![other]));
+
+  // A host of the LAN behind the client talks to the server's side and gets the answer routed back.
+  let packet = vec![
+    0x45, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0, 192, 168, 50, 10, 10, 0, 0, 1, 0x30, 0x39, 0, 53, 0, 8, 0, 0,
+  ];
+  handle.send_ip_packet(packet.clone()).await?;
+  let received = tokio::time::timeout(Duration::from_secs(2), from_clients.recv()).await?.unwrap();
+  assert_eq!(received.packet, packet);
+
+  let reply = vec![
+    0x45, 0, 0, 28, 0, 2, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 192, 168, 50, 10, 0, 53, 0x30, 0x39, 0, 8, 0, 0,
+  ];
+  server_packets.send_ip_packet(reply.clone()).await?;
+  let received = tokio::time::timeout(Duration::from_secs(2), incoming.recv()).await?.unwrap();
+  assert_eq!(received, reply);
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_route_exchange() -> anyhow::Result<()> {
+  init_logging();
+
+  let network = Network::new();
+  let server_address = Ipv4Addr::new(192, 0, 2, 1);
+  let sites: [(&str, Ipv4Net, Vec<Ipv4Net>); 3] = [
+    ("north", "192.168.50.0/24".parse()?, Vec::new()),
+    ("south", "192.168.60.0/24".parse()?, Vec::new()),
+    // Only reaches the north office, so it isn't told about the south one.
+    ("west", "192.168.70.0/24".parse()?, vec!["10.0.0.0/8".parse()?, "192.168.50.0/24".parse()?]),
+  ];
+  let groups = sites.iter().map(|(name, _, acl)| {
+    let policy = GroupPolicy {
+      members: vec![name.to_string()],
+      acl: acl.clone(),
+      subnets: vec!["192.168.0.0/16".parse().unwrap()],
+      ..Default::default()
+    };
+    (name.to_string(), policy)
+  });
+  let credentials: Vec<_> = sites
+    .iter()
+    .map(|(name, _, _)| Credentials::from_str(&format!("{}:secret", name)))
+    .collect::<Result<_, _>>()?;
+  let server = Server::builder(server_address, 6969)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(credentials.clone())
+    .with_policies(Policies::new(groups.collect()))
+    .with_route_exchange(true)
+    .with_packet_pipe(1400)
+    .with_memory_transport(network.clone())
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  let mut clients = Vec::new();
+  for ((_, lan, _), credentials) in sites.iter().zip(credentials) {
+    let client = Client::builder(server_address, 6969)
+      .with_listen_address(Ipv4Addr::new(192, 0, 2, 2), 0)
+      .with_creds(credentials)
+      .with_packet_pipe(1400)
+      .with_subnets(vec![*lan])
+      .with_memory_transport(network.clone())
+      .build()
+      .await?;
+    let events = client.subscribe();
+    clients.push((tokio::spawn(client.run()), events));
+  }
+
+  let expected = [vec![sites[1].1, sites[2].1], vec![sites[0].1, sites[2].1], vec![sites[0].1]];
+  for ((_, events), expected) in clients.iter_mut().zip(expected) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+      loop {
+        if let ClientEvent::Routes { routes } = events.recv().await? {
+          if routes == expected {
+            return anyhow::Ok(());
+          }
+        }
+      }
+    })
+    .await??;
+  }
+
+  for (handle, _) in clients {
+    handle.abort();
+  }
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_password_change() -> anyhow::Result<()> {
+  init_logging();
+
+  let path = std::env::temp_dir().join(format!("vpn-test-passwords-{}.json", std::process::id()));
+  PasswordFile::new(path.clone()).set("alice", "hunter22").await?;
+  let server_key = KeyPair::generate();
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8026)
+    .with_password_file(path.clone())
+    .with_private_key(server_key.secret())
+    .with_session_tickets(Duration::from_secs(3600))
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let connect = |credentials| async {
+    let (socket, session) = pinned_handshake(8026, Some(&server_key.public())).await?;
+    send(&socket, session, ClientPacket::Auth(credentials)).await?;
+    anyhow::Ok((socket, session))
+  };
+  let (socket, session) = connect(Credentials::from_str("alice:hunter22")?).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+  let ServerPacket::Ticket { ticket: old_ticket, .. } = recv(&socket, &session.0).await? else {
+    panic!("Expected a ticket");
+  };
+
+  let change = |old: &str, new: &str| ClientPacket::ChangePassword { old: old.into(), new: new.into() };
+  send(&socket, session, change("wrong", "correct horse")).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::PasswordChanged { error: Some(_) }));
+  send(&socket, session, change("hunter22", "short")).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::PasswordChanged { error: Some(_) }));
+  send(&socket, session, change("hunter22", "correct horse")).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::PasswordChanged { error: None }));
+  let ServerPacket::Ticket { ticket: new_ticket, .. } = recv(&socket, &session.0).await? else {
+    panic!("Expected a ticket");
+  };
+
+  // Neither the old password nor tickets issued before the change get in any more.
+  for credentials in [Credentials::from_str("alice:hunter22")?, Credentials::Ticket(old_ticket)] {
+    let (socket, session) = connect(credentials).await?;
+    assert!(matches!(
+      recv(&socket, &session.0).await?,
+      ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, .. }
+    ));
+  }
+  let (socket, session) = connect(Credentials::Ticket(new_ticket)).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  // `vpn-client change-password`.
+  let client = |credentials: &str| {
+    Client::builder(Ipv4Addr::LOCALHOST, 8026)
+      .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+      .with_creds(Credentials::from_str(credentials).unwrap())
+      .with_server_public_key(server_key.public())
+      .with_packet_pipe(1400)
+      .build()
+  };
+  assert!(client("alice:hunter22").await?.change_password("battery staple").await.is_err());
+  client("alice:correct horse").await?.change_password("battery staple").await?;
+  let (socket, session) = connect(Credentials::from_str("alice:battery staple")?).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+  let ServerPacket::Ticket { ticket, .. } = recv(&socket, &session.0).await? else {
+    panic!("Expected a ticket");
+  };
+
+  // Tickets don't outlive the account, and a refused resumption leaves the session it'd replace alone.
+  std::fs::write(&path, "{}")?;
+  let (resumed, resumed_session) = connect(Credentials::Ticket(ticket)).await?;
+  assert!(matches!(
+    recv(&resumed, &resumed_session.0).await?,
+    ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, .. }
+  ));
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+
+  server_handle.abort();
+  std::fs::remove_file(path)?;
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_happy_eyeballs() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8028)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  // IPv6 address of the server that swallows everything, like a network with broken IPv6.
+  let black_hole = UdpSocket::bind((Ipv6Addr::LOCALHOST, 8028)).await?;
+  sleep(Duration::from_millis(100)).await;
+
+  let client = Client::builder(Ipv4Addr::LOCALHOST, 8028)
+    .with_server_addresses(vec![Ipv6Addr::LOCALHOST.into()])
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .build()
+    .await?;
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  // IPv4 takes over after the head start instead of after IPv6 timing out.
+  let event = tokio::time::timeout(Duration::from_secs(2), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+  let mut buf = [0; 1500];
+  let (_, from) = black_hole.try_recv_from(&mut buf)?;
+  assert_eq!(from.ip(), Ipv6Addr::LOCALHOST);
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_fallback() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("embedder:secret")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8029)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_client_credentials(vec![credentials.clone()])
+    .with_icmp_unreachable(true)
+    .with_tcp_port(8029)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  // Nothing answers on the UDP port, as on a network that drops UDP.
+  let mut client = Client::builder(Ipv4Addr::LOCALHOST, 8030)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(1))
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .with_tcp_fallback(TcpFallbackConfig { port: 8029, after_timeouts: 1, state_file: None })
+    .build()
+    .await?;
+  let handle = client.handle().unwrap();
+  let mut incoming = client.incoming_packets().unwrap();
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  // The session goes on over TCP.
+  let packet =
+    vec![0x45, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0, 10, 8, 0, 2, 10, 0, 0, 1, 0x30, 0x39, 0, 53, 0, 8, 0, 0];
+  handle.send_ip_packet(packet.clone()).await?;
+  let reply = tokio::time::timeout(Duration::from_secs(2), incoming.recv()).await?.unwrap();
+  assert_eq!(&reply[28..], &packet[..]);
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_session_trace() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("traced:secret")?;
+  let health_address: SocketAddr = "127.0.0.1:8032".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8032)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "token".into(), network: None }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8032, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  let admin = move |method: &'static str, path: String, body: &'static str| {
+    tokio::task::spawn_blocking(move || {
+      health::admin_request(health_address, Some("token"), method, &path, body)
+    })
+  };
+  let path = format!("/traces/{:016x}", session.1);
+  assert!(admin("GET", path.clone(), "").await?.is_err());
+  assert_eq!(admin("PUT", path.clone(), "30").await??, "30");
+
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+  let trace = admin("GET", path.clone(), "").await??;
+  assert!(trace.contains("\"recording\": true"), "{}", trace);
+  let ping = trace.find("\"kind\": \"ping\"").expect("The ping is traced");
+  let pong = trace.find("\"kind\": \"pong\"").expect("The pong is traced");
+  assert!(ping < pong && trace.contains("\"flow\": \"sent\""), "{}", trace);
+
+  assert_eq!(admin("DELETE", path.clone(), "").await??, "deleted");
+  assert!(admin("GET", path, "").await?.is_err());
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_failing_task_stops_server() -> anyhow::Result<()> {
+  init_logging();
+
+  // The health endpoint can't bind its address, so it fails on every restart until it's given up on.
+  let taken = std::net::TcpListener::bind("127.0.0.1:8033")?;
+  let server =
+    Server::builder(Ipv4Addr::LOCALHOST, 8033).with_health_address(taken.local_addr()?).build().await?;
+  let error = tokio::time::timeout(Duration::from_secs(15), server.run()).await?.unwrap_err();
+  assert!(error.to_string().starts_with("Task health failed"), "{}", error);
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_reverse_forwards_need_nat() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let policies = Policies::new(BTreeMap::from([(
+    "staff".to_string(),
+    GroupPolicy { members: vec!["test_user".into()], forward_ports: vec![8080], ..Default::default() },
+  )]));
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8034)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_policies(policies)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  // Allowed by policy, but a server without NAT has nothing to forward with.
+  let (socket, session) = connect(8034, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+  let requested = vec![ReverseForward { protocol: 6, port: 8080 }, ReverseForward { protocol: 17, port: 53 }];
+  send(&socket, session, ClientPacket::RegisterForwards(requested.clone())).await?;
+  loop {
+    if let ServerPacket::Forwards { accepted, rejected } = recv(&socket, &session.0).await? {
+      assert_eq!((accepted, rejected), (Vec::new(), requested));
+      break;
+    }
+  }
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_bandwidth_graphs() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("graphed:secret")?;
+  let health_address: SocketAddr = "127.0.0.1:8035".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8035)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_health_address(health_address)
+    .with_bandwidth_graphs(BandwidthConfig { interval_secs: 1, window_secs: 60 })
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8035, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+  let admin = move |path: String| {
+    tokio::task::spawn_blocking(move || health::admin_request(health_address, None, "GET", &path, ""))
+  };
+  let path = format!("/bandwidth/{:016x}", session.1);
+
+  // The first sample of the session only sets where its graph starts from.
+  sleep(Duration::from_millis(1200)).await;
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+  sleep(Duration::from_millis(1200)).await;
+
+  let graph = admin(path).await??;
+  assert!(graph.contains("\"username\": \"graphed\"") && graph.contains("\"interval_secs\": 1"), "{}", graph);
+  assert!(graph.contains("\"bytes_in\": ") && !graph.contains("\"samples\": []"), "{}", graph);
+  assert!(admin("/bandwidth".to_string()).await??.contains(&format!("{:016x}", session.1)));
+  assert!(admin("/bandwidth/2a".to_string()).await?.is_err());
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_keys_are_passed_on() -> anyhow::Result<()> {
+  init_logging();
+
+  let alice = Credentials::from_str("alice:alice_pass")?;
+  let bob = Credentials::from_str("bob:bob_pass")?;
+  let pool = AddressPoolConfig { subnet: "10.8.0.0/24".parse()?, dns: Vec::new() };
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8040)
+    .with_client_credentials(vec![alice.clone(), bob.clone()])
+    .with_address_pool(AddressPool::new(pool, None))
+    .with_peer_encryption(true)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut sessions = Vec::new();
+  for credentials in [alice, bob] {
+    let (socket, session, features) = feature_handshake(8040, None, Some(Features::PEER_KEYS)).await?;
+    assert_eq!(features, Some(Features::PEER_KEYS));
+    send(&socket, session, ClientPacket::Auth(credentials)).await?;
+    let address = loop {
+      if let ServerPacket::NetworkConfig { address, .. } = recv(&socket, &session.0).await? {
+        break address;
+      }
+    };
+    sessions.push((socket, session, address));
+  }
+  let (alice_socket, alice_session, alice_address) = &sessions[0];
+  let (bob_socket, bob_session, bob_address) = &sessions[1];
+
+  // The offer reaches the other client with the sender's address in place of the target's.
+  let key = KeyPair::generate().public();
+  send(alice_socket, *alice_session, ClientPacket::PeerKey { peer: *bob_address, key, reply: false }).await?;
+  loop {
+    if let ServerPacket::PeerKey { peer, key: passed, reply } = recv(bob_socket, &bob_session.0).await? {
+      assert_eq!((peer, passed, reply), (*alice_address, key, false));
+      break;
+    }
+  }
+
+  // Addresses without a client can't take keys.
+  let nobody = Ipv4Addr::new(10, 8, 0, 200);
+  send(alice_socket, *alice_session, ClientPacket::PeerKey { peer: nobody, key, reply: false }).await?;
+  loop {
+    if let ServerPacket::PeerUnavailable { peer, client } = recv(alice_socket, &alice_session.0).await? {
+      assert_eq!((peer, client), (nobody, false));
+      break;
+    }
+  }
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_rendezvous() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let rendezvous = Server::builder(Ipv4Addr::LOCALHOST, 8041).with_rendezvous_service(true).build().await?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8042)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_rendezvous(RendezvousConfig {
+      address: "127.0.0.1:8041".to_string(),
+      name: "home".to_string(),
+      interval_secs: 20,
+    })
+    .build()
+    .await?;
+  let handles = [rendezvous, server].map(|server| {
+    tokio::spawn(async move {
+      if let Err(e) = server.run().await {
+        eprintln!("Server error: {}", e);
+      }
+    })
+  });
+  sleep(Duration::from_millis(200)).await;
+
+  // Only known by its name with the rendezvous.
+  let client = Client::builder(Ipv4Addr::UNSPECIFIED, 0)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_connect_timeout(Duration::from_secs(5))
+    .with_rendezvous(ClientRendezvousConfig {
+      address: "127.0.0.1:8041".to_string(),
+      name: "home".to_string(),
+      port_mapping: false,
+    })
+    .with_creds(credentials)
+    .build()
+    .await?;
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  client_handle.abort();
+  handles.iter().for_each(|handle| handle.abort());
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_hostnames_resolve_through_the_internal_dns() -> anyhow::Result<()> {
+  init_logging();
+
+  let alice = Credentials::from_str("alice:alice_pass")?;
+  let bob = Credentials::from_str("bob:bob_pass")?;
+  let carol = Credentials::from_str("carol:carol_pass")?;
+  let gateway = Ipv4Addr::new(10, 8, 0, 1);
+  let pool = AddressPoolConfig { subnet: "10.8.0.0/24".parse()?, dns: Vec::new() };
+  let config =
+    InternalDnsConfig { domain: "vpn.internal".to_string(), address: None, upstream: None, ttl_secs: 60 };
+  let policies = Policies::new(BTreeMap::from([(
+    "restricted".to_string(),
+    GroupPolicy { members: vec!["carol".into()], acl: vec!["10.0.1.0/24".parse()?], ..Default::default() },
+  )]));
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8043)
+    .with_client_credentials(vec![alice.clone(), bob.clone(), carol.clone()])
+    .with_policies(policies)
+    .with_address_pool(AddressPool::new(pool, None))
+    .with_internal_dns(InternalDns::bind(config, gateway, Vec::new())?)
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut sessions = Vec::new();
+  for credentials in [alice, bob, carol] {
+    let (socket, session, features) = feature_handshake(8043, None, Some(Features::HOSTNAMES)).await?;
+    assert_eq!(features, Some(Features::HOSTNAMES));
+    send(&socket, session, ClientPacket::Auth(credentials)).await?;
+    let address = loop {
+      if let ServerPacket::NetworkConfig { address, dns, .. } = recv(&socket, &session.0).await? {
+        assert_eq!(dns, [gateway]);
+        break address;
+      }
+    };
+    send(&socket, session, ClientPacket::RegisterHostname("Laptop".to_string())).await?;
+    let fqdn = loop {
+      if let ServerPacket::Hostname { fqdn, .. } = recv(&socket, &session.0).await? {
+        break fqdn;
+      }
+    };
+    sessions.push((socket, session, address, fqdn));
+  }
+  // The name stays with the first user to take it.
+  assert_eq!(sessions[0].3.as_deref(), Some("laptop.vpn.internal"));
+  assert_eq!(sessions[1].3, None);
+  let (alice_address, (bob_socket, bob_session, bob_address, _)) = (sessions[0].2, &sessions[1]);
+
+  let client = SocketAddrV4::new(*bob_address, 40000);
+  let resolver = SocketAddrV4::new(gateway, 53);
+  for (id, name) in [(1, "LAPTOP.vpn.internal"), (2, "desktop.vpn.internal"), (3, "example.com")] {
+    let query = ip::udp_packet(client, resolver, &dns::query(id, name, dns::TYPE_A)?);
+    send(bob_socket, *bob_session, ClientPacket::Data(query)).await?;
+    let answer = loop {
+      if let ServerPacket::Data(packet) = recv(bob_socket, &bob_session.0).await? {
+        let (source, destination, payload) = ip::udp_datagram(&packet).expect("a UDP answer");
+        assert_eq!((source, destination), (resolver, client));
+        break Message::parse(payload)?;
+      }
+    };
+    assert_eq!(answer.id, id);
+    match id {
+      1 => assert_eq!(answer.answers[0].data, RecordData::A(alice_address)),
+      2 => assert_eq!(answer.rcode, dns::RCODE_NXDOMAIN),
+      // Other names need upstream resolvers.
+      _ => assert_eq!(answer.rcode, dns::RCODE_REFUSED),
+    }
+  }
+
+  // The resolver is held to the ACL like any other destination.
+  let (carol_socket, carol_session, carol_address, _) = &sessions[2];
+  let client = SocketAddrV4::new(*carol_address, 40000);
+  let query = ip::udp_packet(client, resolver, &dns::query(4, "laptop.vpn.internal", dns::TYPE_A)?);
+  send(carol_socket, *carol_session, ClientPacket::Data(query)).await?;
+  assert!(recv(carol_socket, &carol_session.0).await.is_err());
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_server_time_is_bound_to_the_session() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("alice:alice_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8044)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session, features) = feature_handshake(8044, None, Some(Features::CLOCK)).await?;
+  assert_eq!(features, Some(Features::CLOCK));
+  send(&socket, session, ClientPacket::Auth(credentials)).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_auth_policy() -> anyhow::Result<()> {
+  init_logging();
+
+  let alice = Credentials::from_str("alice:alice_pass")?;
+  let mallory = Credentials::from_str("mallory:mallory_pass")?;
+  let rules = vec![
+    AuthRule {
+      when: Condition::parse("username == 'mallory'")?,
+      action: RuleAction::Deny,
+      group: None,
+      message: Some("Not today".to_string()),
+    },
+    AuthRule {
+      when: Condition::parse("source.inSubnet('127.0.0.0/8') && sessions == 0")?,
+      action: RuleAction::Group,
+      group: Some("local".to_string()),
+      message: None,
+    },
+  ];
+  let groups = BTreeMap::from([("local".to_string(), GroupPolicy::default())]);
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8045)
+    .with_client_credentials(vec![alice.clone(), mallory.clone()])
+    .with_policies(Policies::new(groups))
+    .with_auth_policy(rules)
+    .build()
+    .await?;
+  let clients = server.clients.clone();
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8045, mallory).await?;
+  assert!(matches!(
+    recv(&socket, &session.0).await?,
+    ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message } if message == "Not today"
+  ));
+
+  let (socket, session) = connect(8045, alice.clone()).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+  let addr = socket.local_addr()?;
+  assert_eq!(clients.get(&addr).map(|client| client.policy.groups.clone()), Some(vec!["local".to_string()]));
+
+  // The second session of the user isn't put in the group.
+  let (other, session) = connect(8045, alice).await?;
+  assert!(matches!(recv(&other, &session.0).await?, ServerPacket::AuthOk));
+  assert_eq!(clients.get(&other.local_addr()?).map(|client| client.policy.groups.is_empty()), Some(true));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_refuse_handshakes() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("alice:alice_pass")?;
+  let health_address: SocketAddr = "127.0.0.1:8046".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8046)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "token".into(), network: None }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (socket, session) = connect(8046, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  let admin = move |method: &'static str, path: &'static str, body: &'static str| {
+    tokio::task::spawn_blocking(move || {
+      health::admin_request(health_address, Some("token"), method, path, body)
+    })
+  };
+  // The UDP listener is the only one, so refusing handshakes on it locks new clients out.
+  assert!(admin("PUT", "/listeners/udp", "").await?.is_err());
+  assert!(admin("PUT", "/listeners/tcp", "").await?.is_err());
+  assert_eq!(admin("PUT", "/listeners/udp?force", "60").await??, "refusing");
+
+  assert!(handshake(8046).await.is_err());
+  send(&socket, session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::Pong));
+  let listeners = admin("GET", "/listeners", "").await??;
+  assert!(listeners.contains("\"accepting_handshakes\": false"), "{}", listeners);
+  assert!(listeners.contains("\"refused_handshakes\": 1"), "{}", listeners);
+  assert!(listeners.contains("\"handshake_packets\": 2"), "{}", listeners);
+
+  assert_eq!(admin("DELETE", "/listeners/udp", "").await??, "accepting");
+  handshake(8046).await?;
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_session_table() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("alice:alice_pass")?;
+  let health_address: SocketAddr = "127.0.0.1:8047".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8047)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "token".into(), network: None }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut sockets = Vec::new();
+  for _ in 0..3 {
+    let (socket, session) = connect(8047, credentials.clone()).await?;
+    assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+    sockets.push(socket);
+  }
+  // A session that never authenticated isn't listed.
+  let _unauthenticated = handshake(8047).await?;
+
+  let admin = move |path: &'static str| {
+    tokio::task::spawn_blocking(move || health::admin_request(health_address, Some("token"), "GET", path, ""))
+  };
+  let first = admin("/session-table?limit=2").await??;
+  assert!(first.contains("\"schema_version\": 1"), "{}", first);
+  assert!(first.contains("\"total\": 3") && first.contains("\"next_offset\": 2"), "{}", first);
+  assert_eq!(first.matches("\"username\": \"alice\"").count(), 2, "{}", first);
+  assert!(first.contains("\"last_handshake\": 1"), "{}", first);
+
+  let last = admin("/session-table?offset=2&limit=2").await??;
+  assert!(last.contains("\"next_offset\": null"), "{}", last);
+  assert_eq!(last.matches("\"username\": \"alice\"").count(), 1, "{}", last);
+  assert!(admin("/session-table?limit=0").await?.is_err());
+
+  let openmetrics = admin("/session-table?format=openmetrics").await??;
+  assert_eq!(openmetrics.matches("vpn_session_rx_bytes_total{").count(), 3, "{}", openmetrics);
+  assert!(openmetrics.ends_with("# EOF"), "{}", openmetrics);
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_oversized_credentials() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("test_user:test_pass")?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8048)
+    .with_client_credentials(vec![credentials.clone()])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let password = "p".repeat(MAX_CREDENTIAL_LEN as usize + 1);
+  let (socket, session) = connect(8048, Credentials::new("test_user", password.as_str())).await?;
+  assert!(matches!(
+    recv(&socket, &session.0).await?,
+    ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message } if message.contains("longer than")
+  ));
+
+  let (socket, session) = connect(8048, credentials).await?;
+  assert!(matches!(recv(&socket, &session.0).await?, ServerPacket::AuthOk));
+
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_crypto_offload() -> anyhow::Result<()> {
+  init_logging();
+
+  let credentials = Credentials::from_str("embedder:secret")?;
+  let mut server = Server::builder(Ipv4Addr::LOCALHOST, 8049)
+    .with_client_credentials(vec![credentials.clone()])
+    .with_packet_pipe(1400)
+    .with_offload(OffloadConfig { threshold_bytes: Some(0) })
+    .build()
+    .await?;
+  let server_packets = server.packet_handle().unwrap();
+  let mut from_clients = server.client_packets().unwrap();
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let mut client = Client::builder(Ipv4Addr::LOCALHOST, 8049)
+    .with_listen_address(Ipv4Addr::LOCALHOST, 0)
+    .with_creds(credentials)
+    .with_packet_pipe(1400)
+    .build()
+    .await?;
+  let handle = client.handle().unwrap();
+  let mut incoming = client.incoming_packets().unwrap();
+  let mut events = client.subscribe();
+  let client_handle = tokio::spawn(client.run());
+
+  let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+  assert!(matches!(event, ClientEvent::Connected));
+
+  // Every packet is opened and sealed on the blocking pool, many at once, and still arrives in order.
+  let packet = |id: u8, src: [u8; 4], dst: [u8; 4]| {
+    let mut packet = vec![0x45, 0, 0, 28, 0, id, 0, 0, 64, 17, 0, 0];
+    packet.extend(src.into_iter().chain(dst).chain([0x30, 0x39, 0, 53, 0, 8, 0, 0]));
+    packet
+  };
+  for id in 0..32 {
+    handle.send_ip_packet(packet(id, [10, 8, 0, 2], [10, 0, 0, 1])).await?;
+  }
+  for id in 0..32 {
+    let received = tokio::time::timeout(Duration::from_secs(2), from_clients.recv()).await?.unwrap();
+    assert_eq!(received.packet, packet(id, [10, 8, 0, 2], [10, 0, 0, 1]));
+  }
+
+  for id in 0..32 {
+    server_packets.send_ip_packet(packet(id, [10, 0, 0, 1], [10, 8, 0, 2])).await?;
+  }
+  for id in 0..32 {
+    let received = tokio::time::timeout(Duration::from_secs(2), incoming.recv()).await?.unwrap();
+    assert_eq!(received, packet(id, [10, 0, 0, 1], [10, 8, 0, 2]));
+  }
+
+  client_handle.abort();
+  server_handle.abort();
+  Ok(())
+}
+
+#[tokio::test]
+async fn test_reload() -> anyhow::Result<()> {
+  init_logging();
+
+  let path = std::env::temp_dir().join(format!("vpn-reload-{}.yml", std::process::id()));
+  let config = |users: &[&str], port: u16| {
+    let credentials: String = users
+      .iter()
+      .map(|user| format!("  - {{type: password, username: {}, password: pass}}\n", user))
+      .collect();
+    format!(
+      "listen-address: 127.0.0.1\nlisten-port: {}\nmax-clients: 10\nclient-timeout-secs: 30\n\
+       tun: {{name: vpn%d, address: 10.0.0.1, netmask: 255.255.255.0}}\nclient-credentials:\n{}",
+      port, credentials
+    )
+  };
+  std::fs::write(&path, config(&["alice", "bob"], 8051))?;
+
+  let (alice, bob) = (Credentials::from_str("alice:pass")?, Credentials::from_str("bob:pass")?);
+  let health_address: SocketAddr = "127.0.0.1:8052".parse()?;
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8051)
+    .with_client_credentials(vec![alice.clone(), bob.clone()])
+    .with_config_file(LoadedConfig::read(&path)?)
+    .with_health_address(health_address)
+    .with_admin_tokens(vec![AdminToken { token: "token".into(), network: None }])
+    .build()
+    .await?;
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+  sleep(Duration::from_millis(100)).await;
+
+  let (alice_socket, alice_session) = connect(8051, alice).await?;
+  assert!(matches!(recv(&alice_socket, &alice_session.0).await?, ServerPacket::AuthOk));
+  let (bob_socket, bob_session) = connect(8051, bob.clone()).await?;
+  assert!(matches!(recv(&bob_socket, &bob_session.0).await?, ServerPacket::AuthOk));
+
+  std::fs::write(&path, config(&["alice"], 8061))?;
+  let admin = move |path: &'static str| {
+    tokio::task::spawn_blocking(move || {
+      health::admin_request(health_address, Some("token"), "POST", path, "")
+    })
+  };
+
+  // Bodies are pretty-printed `ConfigDiff`s.
+  let preview = admin("/reload?dry-run").await??;
+  assert!(preview.contains("\"changed\": [\n    \"client-credentials\"\n  ]"));
+  assert!(preview.contains("\"pending\": [\n    \"listen-port\"\n  ]"));
+  assert!(preview.contains("\"username\": \"bob\""));
+  send(&bob_socket, bob_session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&bob_socket, &bob_session.0).await?, ServerPacket::Pong));
+
+  assert!(admin("/reload").await??.contains("\"reason\": \"credential-removal\""));
+  let disconnect = recv(&bob_socket, &bob_session.0).await?;
+  assert!(matches!(disconnect, ServerPacket::Disconnect { code: ErrorCode::InvalidCredentials, .. }));
+  send(&alice_socket, alice_session, ClientPacket::Ping).await?;
+  assert!(matches!(recv(&alice_socket, &alice_session.0).await?, ServerPacket::Pong));
+
+  let (bob_socket, bob_session) = connect(8051, bob).await?;
+  assert!(matches!(recv(&bob_socket, &bob_session.0).await?, ServerPacket::AuthError { .. }));
+  // The reloaded credentials count as loaded, the port is still pending.
+  let preview = admin("/reload?dry-run").await??;
+  assert!(preview.contains("\"changed\": []") && preview.contains("\"affected\": []"));
+  assert!(preview.contains("\"pending\": [\n    \"listen-port\"\n  ]"));
+
+  _ = std::fs::remove_file(&path);
+  server_handle.abort();
+  Ok(())
+}
@@ -0,0 +1,58 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use vpn_server::health::admin_request;
+use vpn_server::server::Server;
+
+async fn probe(address: SocketAddr, path: &str) -> anyhow::Result<String> {
+  let mut stream = TcpStream::connect(address).await?;
+  stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await?;
+
+  let mut response = String::new();
+  stream.read_to_string(&mut response).await?;
+  Ok(response)
+}
+
+async fn admin(address: SocketAddr, method: &str, path: &str, body: &str) -> anyhow::Result<String> {
+  let (method, path, body) = (method.to_string(), path.to_string(), body.to_string());
+  tokio::task::spawn_blocking(move || admin_request(address, None, &method, &path, &body)).await?
+}
+
+#[tokio::test]
+async fn test_health_endpoint() -> anyhow::Result<()> {
+  let health_address: SocketAddr = "127.0.0.1:8180".parse()?;
+
+  let server = Server::builder(Ipv4Addr::LOCALHOST, 8100)
+    .with_client_timeout(Duration::from_secs(30))
+    .with_health_address(health_address)
+    .build()
+    .await?;
+
+  let server_handle = tokio::spawn(async move {
+    if let Err(e) = server.run().await {
+      eprintln!("Server error: {}", e);
+    }
+  });
+
+  sleep(Duration::from_millis(100)).await;
+
+  assert!(probe(health_address, "/healthz").await?.starts_with("HTTP/1.1 200 OK"));
+  let ready = probe(health_address, "/readyz").await?;
+  assert!(ready.starts_with("HTTP/1.1 200 OK"));
+  assert!(ready.contains("startup: ok\n"), "{}", ready);
+  assert!(probe(health_address, "/nope").await?.starts_with("HTTP/1.1 404"));
+  assert!(admin(health_address, "PUT", "/log-level", "loud").await.is_err());
+  assert_eq!(admin(health_address, "GET", "/sessions/alice", "").await?, "[]");
+
+  server_handle.abort();
+  sleep(Duration::from_millis(100)).await;
+
+  assert!(probe(health_address, "/healthz").await?.starts_with("HTTP/1.1 503"));
+
+  Ok(())
+}
@@ -1,48 +1,293 @@
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::task::Poll;
 use std::time::Duration;
+use std::time::Instant;
 
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
 use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
-use tokio::time::Instant;
-
-use tun::AsyncDevice;
+use ipnet::Ipv4Net;
+use tun::AbstractDevice;
 
+use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::trace;
+use tracing::warn;
 
+use vpn_shared::cert::Certificate;
 use vpn_shared::creds::Credentials;
-use vpn_shared::packet::fill_random_bytes;
-use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::diagnose;
+use vpn_shared::diagnose::Protocol;
+use vpn_shared::ecn;
+use vpn_shared::gso;
+use vpn_shared::gso::GsoReader;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::iface;
+use vpn_shared::ip;
+use vpn_shared::logging;
+use vpn_shared::outer::OuterConfig;
+use vpn_shared::packet::datagram_size;
+use vpn_shared::packet::ErrorCode;
 use vpn_shared::packet::Key;
-use vpn_shared::packet::KEY_SIZE;
-use vpn_shared::packet::{ClientPacket, ServerPacket};
+use vpn_shared::packet::Notice;
+use vpn_shared::packet::SessionParams;
+use vpn_shared::packet::MAX_DATAGRAM_SIZE;
+use vpn_shared::peer::PeerEncryption;
+use vpn_shared::protocol::ClientAuth;
+use vpn_shared::protocol::Connection;
+use vpn_shared::protocol::ConnectionConfig;
+use vpn_shared::protocol::Event;
+use vpn_shared::protocol::PING_INTERVAL;
+use vpn_shared::rate;
+use vpn_shared::rate::TokenBucket;
+use vpn_shared::rendezvous;
+use vpn_shared::socket::Network;
+use vpn_shared::socket::Socket;
+use vpn_shared::stream::TcpTransport;
+use vpn_shared::supervisor::Fatal;
+use vpn_shared::supervisor::Restart;
+use vpn_shared::supervisor::Supervisor;
+use vpn_shared::transform::Registry;
+
+use crate::device::ClientHandle;
+use crate::device::Device;
+use crate::dns;
+use crate::events::ClientEvent;
+use crate::eyeballs;
+use crate::fallback::Fallback;
+use crate::fallback::TcpFallbackConfig;
+use crate::fallback::Transport;
+use crate::forward;
+use crate::forward::ForwardConfig;
+use crate::forward::ReverseForwardConfig;
+use crate::gateway;
+use crate::gateway::Gateway;
+use crate::gateway::GatewayConfig;
+use crate::idle;
+use crate::idle::IdleSuspendConfig;
+use crate::idle::IdleTimer;
+use crate::killswitch;
+use crate::killswitch::KillSwitch;
+use crate::killswitch::KillSwitchConfig;
+use crate::lan;
+use crate::lan::LanAccessConfig;
+use crate::portmap;
+use crate::portmap::PortMappingConfig;
+use crate::rendezvous::RendezvousConfig;
+use crate::resolver;
+use crate::resolver::Resolver;
+use crate::resume::TicketStore;
+use crate::routes;
+use crate::state::Change;
+use crate::state::SystemState;
+use crate::status::TunnelStats;
+
+/// The server refused to authenticate the client or ended its session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refused {
+  pub code: ErrorCode,
+  pub reason: String,
+  /// How long the server asked to wait before connecting again.
+  pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for Refused {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.reason)
+  }
+}
+
+impl std::error::Error for Refused {}
+
+/// Nothing answered the key exchange, as when the network drops the transport it went over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unanswered(pub String);
+
+impl fmt::Display for Unanswered {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for Unanswered {}
+
+/// Delay before each reconnect attempt, doubling from `initial` up to `max`, then stretched by a random
+/// share of up to `jitter_pct` percent, so clients that lost their sessions together don't come back
+/// together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+  pub initial: Duration,
+  pub max: Duration,
+  pub jitter_pct: u8,
+}
+
+impl Backoff {
+  pub fn delay(&self, attempt: u32) -> Duration {
+    self.initial.saturating_mul(2u32.saturating_pow(attempt)).min(self.max)
+  }
+
+  pub fn jittered(&self, delay: Duration) -> Duration {
+    rate::jitter(delay, self.jitter_pct as f64 / 100.0)
+  }
+}
+
+/// Key exchange with one of the addresses of the server, see `eyeballs`.
+struct Attempt {
+  addr: SocketAddr,
+  socket: Arc<Socket>,
+  connection: Connection,
+}
+
+/// Stops a task when its owner goes away, so a dropped client doesn't keep its socket bound.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+impl Drop for Client {
+  fn drop(&mut self) {
+    self.supervisor.stop();
+  }
+}
 
 pub struct ClientBuilder {
   server_address: Ipv4Addr,
   server_port: u16,
+  server_host: Option<String>,
+  resolver: Box<dyn Resolver>,
+  alternatives: Vec<IpAddr>,
   listen_address: Ipv4Addr,
   listen_port: u16,
   connect_timeout: Option<Duration>,
   credentials: Option<Credentials>,
+  key: Option<(String, KeyPair)>,
+  certificate: Option<(Certificate, KeyPair)>,
+  server_public_key: Option<Key>,
   tun_config: Option<tun::Configuration>,
+  tun_description: Option<String>,
+  tun_gso: bool,
+  /// MTU of the packet pipe used instead of a tun device.
+  packet_pipe: Option<u16>,
+  routes: watch::Receiver<Vec<Ipv4Net>>,
+  subnets: Vec<Ipv4Net>,
+  session_params: Option<watch::Receiver<SessionParams>>,
+  reconnect: Option<Backoff>,
+  port_mapping: Option<PortMappingConfig>,
+  ecn: bool,
+  outer: OuterConfig,
+  /// Network to bind on instead of UDP.
+  memory: Option<Network>,
+  mtu_fallback: bool,
+  accept_dns: bool,
+  transforms: Registry,
+  offered_transforms: Vec<String>,
+  kill_switch: Option<KillSwitchConfig>,
+  lan_access: Option<LanAccessConfig>,
+  gateway: Option<GatewayConfig>,
+  forwards: Vec<ForwardConfig>,
+  reverse_forwards: Vec<ReverseForwardConfig>,
+  peer_encryption: PeerEncryption,
+  rendezvous: Option<RendezvousConfig>,
+  hostname: Option<String>,
+  idle_suspend: Option<IdleSuspendConfig>,
+  max_upload_kbps: Option<u64>,
+  max_download_kbps: Option<u64>,
+  tickets: Option<TicketStore>,
+  tcp_fallback: Option<TcpFallbackConfig>,
+  system_state: Option<SystemState>,
 }
 
 pub struct Client {
-  socket: Arc<UdpSocket>,
+  socket: Arc<Socket>,
+  /// Socket for servers reached over IPv6, bound when first needed.
+  socket6: Option<Arc<Socket>>,
+  memory: Option<Network>,
   server_address: Ipv4Addr,
   server_port: u16,
+  server_host: Option<String>,
+  resolver: Box<dyn Resolver>,
+  alternatives: Vec<IpAddr>,
+  /// Address of the server that answered the last key exchange.
+  server_addr: Option<SocketAddr>,
+  /// Socket the last key exchange was won over, which the session goes on over.
+  session_socket: Option<Arc<Socket>>,
+  tcp_fallback: Option<Fallback>,
   connect_timeout: Duration,
   credentials: Option<Credentials>,
-  tun: AsyncDevice,
-
-  last_ping_sent: Instant,
+  key: Option<(String, KeyPair)>,
+  certificate: Option<(Certificate, KeyPair)>,
+  server_public_key: Option<Key>,
+  device: Device,
+  /// What the tun device was made from, to make it again if it's removed.
+  tun_config: tun::Configuration,
+  tun_description: Option<String>,
+  tun_gso: bool,
+  mtu: u16,
+  /// Address and resolvers the server leased to the session.
+  lease: Option<(Ipv4Net, Vec<Ipv4Addr>)>,
+  routes: watch::Receiver<Vec<Ipv4Net>>,
+  /// Whether the routes are being kept in place, see `routes::monitor`.
+  route_monitor: bool,
+  /// Set while the routes are down for idleness, which `routes::monitor` leaves them alone for.
+  routes_paused: Arc<AtomicBool>,
+  /// Tasks running alongside the sessions, stopped along with the client.
+  supervisor: Supervisor,
+  /// LANs behind the client to register with the server, see `ClientBuilder::with_subnets`.
+  subnets: Vec<Ipv4Net>,
+  /// Subnets of other sites the server advertised and that are routed into the tunnel.
+  site_routes: Vec<Ipv4Net>,
+  session_params: watch::Receiver<SessionParams>,
+  reconnect: Option<Backoff>,
+  port_mapping: Option<PortMappingConfig>,
+  ecn: bool,
+  mtu_fallback: bool,
+  accept_dns: bool,
+  transforms: Registry,
+  offered_transforms: Vec<String>,
+  kill_switch: Option<KillSwitchConfig>,
+  lan_access: Option<LanAccessConfig>,
+  gateway: Option<GatewayConfig>,
+  forwards: Vec<ForwardConfig>,
+  /// Ports of the server asked to be forwarded to the client on every connection.
+  reverse_forwards: Vec<ReverseForwardConfig>,
+  /// Whether traffic to other clients is sealed with keys the server doesn't know.
+  peer_encryption: PeerEncryption,
+  /// Rendezvous the server is looked up with on every connect, for a server behind NAT.
+  rendezvous: Option<RendezvousConfig>,
+  /// Name registered with the server's DNS on every connection.
+  hostname: Option<String>,
+  idle_suspend: Option<IdleSuspendConfig>,
+  /// Idleness of the current session, kept only with `idle_suspend` and a tun device.
+  idle: Option<IdleTimer>,
+  /// Local ranges cut out of the routes, looked up once on start.
+  bypassed: Vec<Ipv4Net>,
+  upload: Option<TokenBucket>,
+  /// When the next tun packet may be read without going over the upload limit.
+  upload_ready: Instant,
+  download: Option<TokenBucket>,
+  tickets: Option<TicketStore>,
+  /// Ticket to resume the session with instead of authenticating, kept only with a store.
+  ticket: Option<Vec<u8>>,
+  system_state: Option<SystemState>,
+  events: broadcast::Sender<ClientEvent>,
+  stats: Arc<TunnelStats>,
 }
 
 impl ClientBuilder {
@@ -50,11 +295,46 @@ impl ClientBuilder {
     Self {
       server_address,
       server_port,
+      server_host: None,
+      resolver: Box::new(resolver::System),
+      alternatives: Vec::new(),
       listen_address: Ipv4Addr::new(0, 0, 0, 0),
       listen_port: 6969,
       connect_timeout: None,
       credentials: None,
+      key: None,
+      certificate: None,
+      server_public_key: None,
       tun_config: None,
+      tun_description: None,
+      tun_gso: false,
+      packet_pipe: None,
+      routes: watch::channel(Vec::new()).1,
+      subnets: Vec::new(),
+      session_params: None,
+      reconnect: None,
+      port_mapping: None,
+      ecn: false,
+      outer: OuterConfig::default(),
+      memory: None,
+      mtu_fallback: false,
+      accept_dns: true,
+      transforms: Registry::default(),
+      offered_transforms: Vec::new(),
+      kill_switch: None,
+      lan_access: None,
+      gateway: None,
+      forwards: Vec::new(),
+      reverse_forwards: Vec::new(),
+      peer_encryption: PeerEncryption::Off,
+      rendezvous: None,
+      hostname: None,
+      idle_suspend: None,
+      max_upload_kbps: None,
+      max_download_kbps: None,
+      tickets: None,
+      tcp_fallback: None,
+      system_state: None,
     }
   }
 
@@ -64,10 +344,48 @@ impl ClientBuilder {
     self
   }
 
+  /// Looks `host` up before every connection attempt and races the key exchange over its addresses, see
+  /// `eyeballs`; the address given to `new` is used if the lookup fails, unless it's unspecified. IPv6 is
+  /// sent from an ephemeral port of its own, without ECN and the outer settings.
+  pub fn with_server_host(mut self, host: String) -> Self {
+    self.server_host = Some(host);
+    self
+  }
+
+  /// Looks up `with_server_host` through `resolver` instead of the system resolver.
+  pub fn with_resolver(mut self, resolver: Box<dyn Resolver>) -> Self {
+    self.resolver = resolver;
+    self
+  }
+
+  /// More addresses of the server, raced against the one given to `new` like those of `with_server_host`.
+  pub fn with_server_addresses(mut self, addresses: Vec<IpAddr>) -> Self {
+    self.alternatives = addresses;
+    self
+  }
+
   pub fn with_creds(mut self, credentials: Credentials) -> Self {
     self.credentials = Some(credentials);
     self
   }
+  /// Authenticates with a static key instead of a password; takes precedence over `with_creds`.
+  pub fn with_key(mut self, username: String, key: KeyPair) -> Self {
+    self.key = Some((username, key));
+    self
+  }
+
+  /// Authenticates with a certificate issued by the server's CA for `key`.
+  pub fn with_certificate(mut self, certificate: Certificate, key: KeyPair) -> Self {
+    self.certificate = Some((certificate, key));
+    self
+  }
+
+  /// Pins the server's static key; credentials are then only sent to a server holding its private half.
+  pub fn with_server_public_key(mut self, key: Key) -> Self {
+    self.server_public_key = Some(key);
+    self
+  }
+
   pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
     self.connect_timeout = Some(connect_timeout);
     self
@@ -78,52 +396,534 @@ impl ClientBuilder {
     self
   }
 
+  pub fn with_tun_description(mut self, description: String) -> Self {
+    self.tun_description = Some(description);
+    self
+  }
+
+  /// Reads the tun device as one made with `TunConfig::gso`, splitting the super-packets of TCP flows.
+  pub fn with_tun_gso(mut self, gso: bool) -> Self {
+    self.tun_gso = gso;
+    self
+  }
+
+  /// Exchanges tunneled packets with the application through `Client::handle` and
+  /// `Client::incoming_packets` instead of a tun device, e.g. for a userspace network stack. Nothing on the
+  /// system is configured then: routes are ignored, the leased address only shows up as
+  /// `ClientEvent::NetworkConfig`, and the kill switch and LAN access are refused.
+  pub fn with_packet_pipe(mut self, mtu: u16) -> Self {
+    self.packet_pipe = Some(mtu);
+    self
+  }
+
+  pub fn with_routes(mut self, routes: Vec<Ipv4Net>) -> Self {
+    self.routes = watch::channel(routes).1;
+    self
+  }
+
+  /// Like `with_routes`, but the routes are replaced on the fly whenever a new set is sent.
+  pub fn with_route_updates(mut self, routes: watch::Receiver<Vec<Ipv4Net>>) -> Self {
+    self.routes = routes;
+    self
+  }
+
+  /// LANs behind the client the server should route to it, making the client the gateway of a site; each
+  /// session registers them, and the server only takes those the user's policy allows.
+  pub fn with_subnets(mut self, subnets: Vec<Ipv4Net>) -> Self {
+    self.subnets = subnets;
+    self
+  }
+
+  /// Renegotiates the MTU, ping interval and transforms of each session to the sent ones, rather than
+  /// reconnecting for them, see `SessionParams`. Sessions start out with the tun MTU, `PING_INTERVAL` and
+  /// `with_transforms`; a different ping interval is renegotiated as soon as they're established.
+  pub fn with_session_params(mut self, params: watch::Receiver<SessionParams>) -> Self {
+    self.session_params = Some(params);
+    self
+  }
+
+  /// Reconnect after the connection fails or the server ends the session, unless the server refused the
+  /// client for good. Without it `run` returns on the first failure.
+  pub fn with_reconnect(mut self, backoff: Backoff) -> Self {
+    self.reconnect = Some(backoff);
+    self
+  }
+
+  /// Keep a port mapping for the listen port on the local gateway, see `portmap`.
+  pub fn with_port_mapping(mut self, config: PortMappingConfig) -> Self {
+    self.port_mapping = Some(config);
+    self
+  }
+
+  /// Copy ECN fields between tunneled packets and the datagrams carrying them, see `vpn_shared::ecn`.
+  pub fn with_ecn(mut self, ecn: bool) -> Self {
+    self.ecn = ecn;
+    self
+  }
+
+  /// DF bit and TTL of the datagrams carrying the tunnel.
+  pub fn with_outer(mut self, outer: OuterConfig) -> Self {
+    self.outer = outer;
+    self
+  }
+
+  /// Binds the listen address on `network` rather than a UDP socket, to connect to a server in the same
+  /// process listening on it, see `vpn_shared::socket`; ECN and the outer settings don't apply.
+  pub fn with_memory_transport(mut self, network: Network) -> Self {
+    self.memory = Some(network);
+    self
+  }
+
+  /// Probe the MTU along with each ping and lower it when only the probes get lost, i.e. something on the
+  /// path drops large packets; see `ClientEvent::MtuReduced`. Needs a server answering probes.
+  pub fn with_mtu_fallback(mut self, mtu_fallback: bool) -> Self {
+    self.mtu_fallback = mtu_fallback;
+    self
+  }
+
+  /// Use the resolvers the server pushes along with a leased address; on by default.
+  pub fn with_accept_dns(mut self, accept_dns: bool) -> Self {
+    self.accept_dns = accept_dns;
+    self
+  }
+
+  /// Transforms to offer the server, in order of preference, see `vpn_shared::transform`.
+  pub fn with_transforms(mut self, offered: Vec<String>) -> Self {
+    self.offered_transforms = offered;
+    self
+  }
+
+  /// Block traffic outside of the tunnel while the client runs, reconnects included, see `killswitch`.
+  pub fn with_kill_switch(mut self, config: KillSwitchConfig) -> Self {
+    self.kill_switch = Some(config);
+    self
+  }
+
+  /// Keeps the local network out of the tunnel, unless `strict` is set.
+  pub fn with_lan_access(mut self, config: LanAccessConfig) -> Self {
+    self.lan_access = (!config.strict).then_some(config);
+    self
+  }
+
+  /// Forwards the LAN on `config.lan_interface` into the tunnel while the client runs, see `gateway`.
+  pub fn with_gateway(mut self, config: GatewayConfig) -> Self {
+    self.gateway = Some(config);
+    self
+  }
+
+  /// Relays local ports to hosts behind the tunnel, see `forward`.
+  pub fn with_forwards(mut self, forwards: Vec<ForwardConfig>) -> Self {
+    self.forwards = forwards;
+    self
+  }
+
+  /// Asks the server to forward its ports to local services, see `forward::serve_reverse`.
+  pub fn with_reverse_forwards(mut self, forwards: Vec<ReverseForwardConfig>) -> Self {
+    self.reverse_forwards = forwards;
+    self
+  }
+
+  /// Seals packets to other clients with pairwise keys agreed through the server, see `vpn_shared::peer`.
+  pub fn with_peer_encryption(mut self, mode: PeerEncryption) -> Self {
+    self.peer_encryption = mode;
+    self
+  }
+
+  /// Registers `name` with the server's DNS on every connection, for other clients to reach this one by,
+  /// see `ClientEvent::Hostname`.
+  pub fn with_hostname(mut self, name: String) -> Self {
+    self.hostname = Some(name);
+    self
+  }
+
+  /// Takes the routes into the tunnel down while it's idle, keeping the session, and brings them back on
+  /// the first packet through it or when the server has traffic for the client, see `idle`. Traffic takes
+  /// the routes outside of the tunnel meanwhile, so it can't go along with the kill switch.
+  pub fn with_idle_suspend(mut self, config: IdleSuspendConfig) -> Self {
+    self.idle_suspend = Some(config);
+    self
+  }
+
+  /// Looks the server up with a rendezvous on every connect, see `rendezvous`; the configured address is
+  /// the fallback when the lookup fails, unless it's unspecified. Only applies over UDP.
+  pub fn with_rendezvous(mut self, rendezvous: RendezvousConfig) -> Self {
+    self.rendezvous = Some(rendezvous);
+    self
+  }
+
+  /// Limits the rate of datagrams sent to the server. Tun packets are read no faster than that, so the rest
+  /// waits in the device's queue, whose drops make TCP back off.
+  pub fn with_max_upload_kbps(mut self, kbps: u64) -> Self {
+    self.max_upload_kbps = Some(kbps);
+    self
+  }
+
+  /// Limits the rate of tunneled packets taken from the server; those over it are dropped. They've
+  /// crossed the link already, but TCP senders slow down to the limit after the drops.
+  pub fn with_max_download_kbps(mut self, kbps: u64) -> Self {
+    self.max_download_kbps = Some(kbps);
+    self
+  }
+
+  /// Switches to the server's TCP port when key exchanges over UDP go unanswered, see `fallback`.
+  pub fn with_tcp_fallback(mut self, config: TcpFallbackConfig) -> Self {
+    self.tcp_fallback = Some(config);
+    self
+  }
+
+  /// Keeps the session tickets the server issues in `store` and resumes the session with the one stored
+  /// there instead of authenticating, falling back to the credentials if the server refuses it.
+  pub fn with_ticket_store(mut self, store: TicketStore) -> Self {
+    self.tickets = Some(store);
+    self
+  }
+
+  /// Records the routes, resolvers and firewall rules the client sets up in `state`, so they can be
+  /// undone if it doesn't get to, see `state`.
+  pub fn with_system_state(mut self, state: SystemState) -> Self {
+    self.system_state = Some(state);
+    self
+  }
+
   pub async fn build(self) -> anyhow::Result<Client> {
-    let socket = Arc::new(UdpSocket::bind(format!("{}:{}", self.listen_address, self.listen_port)).await?);
-    let tun = tun::create_as_async(&self.tun_config.unwrap_or_default())?;
+    self.transforms.check(&self.offered_transforms)?;
+    if self.kill_switch.is_some() && (self.server_host.is_some() || !self.alternatives.is_empty()) {
+      anyhow::bail!("The kill switch only lets traffic to a single IPv4 address of the server out");
+    }
+    if self.kill_switch.is_some() && self.idle_suspend.is_some() {
+      anyhow::bail!("The kill switch would block all traffic while the tunnel is suspended for idleness");
+    }
+    let socket = match self.memory {
+      Some(ref network) => {
+        Socket::Memory(network.bind(SocketAddr::new(self.listen_address.into(), self.listen_port))?)
+      }
+      None => {
+        let addr = SocketAddr::new(self.listen_address.into(), self.listen_port);
+        let socket = UdpSocket::bind(addr).await.map_err(|e| diagnose::bind_error(Protocol::Udp, addr, e))?;
+        if self.ecn {
+          ecn::enable(&socket)?;
+        }
+        self.outer.apply(&socket)?;
+        Socket::Udp(socket)
+      }
+    };
+    let socket = Arc::new(socket);
+    let tun_config = self.tun_config.unwrap_or_default();
+    let (device, mtu) = match self.packet_pipe {
+      Some(_)
+        if self.kill_switch.is_some()
+          || self.lan_access.is_some()
+          || self.gateway.is_some()
+          || !self.forwards.is_empty()
+          || !self.reverse_forwards.is_empty() =>
+      {
+        anyhow::bail!(
+          "The kill switch, LAN access, gateway mode and port forwards need a tun device, not a packet pipe"
+        )
+      }
+      Some(mtu) => (Device::pipe(), mtu),
+      None => {
+        let device = create_tun(&tun_config, self.tun_description.as_deref(), self.tun_gso)?;
+        let mtu = device.tun_mtu()?;
+        (device, mtu)
+      }
+    };
+    let session_params = self.session_params.unwrap_or_else(|| {
+      let keepalive_secs = PING_INTERVAL.as_secs() as u32;
+      watch::channel(SessionParams { mtu, keepalive_secs, transforms: self.offered_transforms.clone() }).1
+    });
 
     Ok(Client {
       socket,
+      socket6: None,
+      memory: self.memory,
       server_address: self.server_address,
       server_port: self.server_port,
+      server_host: self.server_host,
+      resolver: self.resolver,
+      alternatives: self.alternatives,
+      server_addr: None,
+      session_socket: None,
+      tcp_fallback: self.tcp_fallback.map(Fallback::new),
       connect_timeout: self.connect_timeout.unwrap_or(Duration::from_secs(10)),
       credentials: self.credentials,
-      tun,
-      last_ping_sent: Instant::now(),
+      key: self.key,
+      certificate: self.certificate,
+      server_public_key: self.server_public_key,
+      device,
+      tun_config,
+      tun_description: self.tun_description,
+      tun_gso: self.tun_gso,
+      mtu,
+      lease: None,
+      routes: self.routes,
+      route_monitor: false,
+      routes_paused: Arc::default(),
+      supervisor: Supervisor::default(),
+      subnets: self.subnets,
+      site_routes: Vec::new(),
+      session_params,
+      reconnect: self.reconnect,
+      // Punches from the server only get through if the client's NAT lets them in, which a mapping ensures.
+      port_mapping: self.port_mapping.or_else(|| {
+        self
+          .rendezvous
+          .as_ref()
+          .filter(|rendezvous| rendezvous.port_mapping)
+          .map(|_| PortMappingConfig::default())
+      }),
+      ecn: self.ecn,
+      mtu_fallback: self.mtu_fallback,
+      accept_dns: self.accept_dns,
+      transforms: self.transforms,
+      offered_transforms: self.offered_transforms,
+      kill_switch: self.kill_switch,
+      lan_access: self.lan_access,
+      gateway: self.gateway,
+      forwards: self.forwards,
+      reverse_forwards: self.reverse_forwards,
+      peer_encryption: self.peer_encryption,
+      rendezvous: self.rendezvous,
+      hostname: self.hostname,
+      idle_suspend: self.idle_suspend,
+      idle: None,
+      bypassed: Vec::new(),
+      upload: self.max_upload_kbps.map(|kbps| rate_limit(kbps, mtu)),
+      upload_ready: Instant::now(),
+      download: self.max_download_kbps.map(|kbps| rate_limit(kbps, mtu)),
+      ticket: self.tickets.as_ref().and_then(TicketStore::load),
+      tickets: self.tickets,
+      system_state: self.system_state,
+      events: broadcast::channel(64).0,
+      stats: Arc::default(),
     })
   }
 }
 
+fn create_tun(config: &tun::Configuration, description: Option<&str>, gso: bool) -> anyhow::Result<Device> {
+  let tun = tun::create_as_async(config).map_err(diagnose::tun_error)?;
+  if let Some(description) = description {
+    if let Err(e) = iface::set_description(&tun.tun_name()?, description) {
+      warn!(target: logging::TUN, "Failed to set interface description: {}", e);
+    }
+  }
+  let gso = match gso {
+    true => {
+      gso::enable(&tun)?;
+      Some(GsoReader::default())
+    }
+    false => None,
+  };
+  Ok(Device::Tun { tun, gso })
+}
+
+/// Next datagram on any of `sockets`, received into the buffer of the same index, which is returned along.
+async fn recv_any(
+  sockets: &[Arc<Socket>],
+  bufs: &mut [Vec<u8>],
+) -> (usize, std::io::Result<(usize, SocketAddr, u8)>) {
+  let mut receiving: Vec<_> =
+    sockets.iter().zip(bufs.iter_mut()).map(|(socket, buf)| Box::pin(socket.recv_from(buf))).collect();
+  std::future::poll_fn(|cx| {
+    for (index, received) in receiving.iter_mut().enumerate() {
+      if let Poll::Ready(received) = received.as_mut().poll(cx) {
+        return Poll::Ready((index, received));
+      }
+    }
+    Poll::Pending
+  })
+  .await
+}
+
+/// Traffic let through at once on top of a bandwidth limit.
+const BURST_WINDOW: Duration = Duration::from_millis(50);
+
+/// Bucket for a limit of `kbps`; bursts hold at least one full datagram, which a policed limit would never
+/// let through otherwise.
+fn rate_limit(kbps: u64, mtu: u16) -> TokenBucket {
+  let rate = kbps as f64 * 1000.0 / 8.0;
+  TokenBucket::new(rate, (rate * BURST_WINDOW.as_secs_f64()).max(datagram_size(mtu) as f64))
+}
+
 impl Client {
   pub fn builder(server_address: Ipv4Addr, server_port: u16) -> ClientBuilder {
     ClientBuilder::new(server_address, server_port)
   }
 
+  pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+    self.events.subscribe()
+  }
+
+  /// Tunneled bytes and ping latency, see `status`.
+  pub fn stats(&self) -> Arc<TunnelStats> {
+    self.stats.clone()
+  }
+
+  /// Sends packets through the tunnel of a client built `with_packet_pipe`.
+  pub fn handle(&self) -> Option<ClientHandle> {
+    match self.device {
+      Device::Pipe { ref handle, .. } => Some(handle.clone()),
+      Device::Tun { .. } => None,
+    }
+  }
+
+  /// Packets received through the tunnel of a client built `with_packet_pipe`; given out once. Packets
+  /// the application doesn't take in time are dropped.
+  pub fn incoming_packets(&mut self) -> Option<mpsc::Receiver<Vec<u8>>> {
+    match self.device {
+      Device::Pipe { ref mut receiver, .. } => receiver.take(),
+      Device::Tun { .. } => None,
+    }
+  }
+
   pub async fn run(mut self) -> anyhow::Result<()> {
     info!("Starting client");
 
-    let key = match self.connect().await {
-      Ok(key) => key,
+    if let Some(config) = self.port_mapping.take() {
+      let (port, events) = (self.socket.local_addr()?.port(), self.events.clone());
+      self.supervisor.spawn("port-mapping", Restart::Always, move || {
+        portmap::maintain(config.clone(), port, events.clone())
+      });
+    }
+
+    if let Some(ref config) = self.lan_access {
+      self.bypassed = lan::bypassed(config, &self.device.tun_name()?).await?;
+      info!("Local network bypasses the tunnel: {:?}", self.bypassed);
+    }
+
+    let _kill_switch = match self.kill_switch.take() {
+      Some(config) => {
+        let rules = killswitch::Rules {
+          server: SocketAddrV4::new(self.server_address, self.server_port),
+          tcp_port: self.tcp_fallback.as_ref().map(Fallback::port),
+          tun: self.device.tun_name()?,
+          allowed: [config.allowed, self.bypassed.clone()].concat(),
+        };
+        let kill_switch = KillSwitch::enable(killswitch::platform()?, &rules)?;
+        self.record(Change::KillSwitch { firewall: kill_switch.state() });
+        Some(kill_switch)
+      }
+      None => None,
+    };
+
+    let _gateway = match self.gateway.take() {
+      Some(config) => {
+        let rules = gateway::Rules {
+          lan: config.lan_interface,
+          tun: self.device.tun_name()?,
+          masquerade: config.masquerade,
+        };
+        let gateway = Gateway::enable(&rules)?;
+        self.record(Change::Gateway { forwarding: gateway.forwarding().to_string() });
+        Some(gateway)
+      }
+      None => None,
+    };
+
+    for config in std::mem::take(&mut self.forwards) {
+      let device = self.device.tun_name()?;
+      self
+        .supervisor
+        .spawn("forward", Restart::Always, move || forward::serve(config.clone(), Some(device.clone())));
+    }
+    for config in self.reverse_forwards.clone() {
+      let device = self.device.tun_name()?;
+      self.supervisor.spawn("reverse-forward", Restart::Always, move || {
+        forward::serve_reverse(config.clone(), Some(device.clone()))
+      });
+    }
+
+    let supervisor = self.supervisor.clone();
+    let mut attempt = 0;
+    loop {
+      let error = tokio::select! {
+        result = self.connect_and_serve(&mut attempt) => match result {
+          Ok(()) => return Ok(()),
+          Err(e) => e,
+        },
+        error = supervisor.failed() => return Err(error),
+      };
+
+      let Some(backoff) = self.reconnect else {
+        return Err(error);
+      };
+
+      if error.is::<Fatal>() {
+        return Err(error);
+      }
+      if let Some(refused) = error.downcast_ref::<Refused>().filter(|r| r.code.is_permanent()) {
+        error!("Not reconnecting: {}", refused);
+        self.forget_ticket();
+        _ = self.events.send(ClientEvent::Stopped { code: refused.code, reason: refused.reason.clone() });
+        return Err(error);
+      }
+
+      let retry_after = error.downcast_ref::<Refused>().and_then(|refused| refused.retry_after);
+      let delay = backoff.jittered(backoff.delay(attempt).max(retry_after.unwrap_or_default()));
+      attempt += 1;
+      warn!("Reconnecting in {:?} (attempt {}): {}", delay, attempt, error);
+      _ = self.events.send(ClientEvent::Reconnecting { attempt, delay, reason: error.to_string() });
+      sleep(delay).await;
+    }
+  }
+
+  /// Connects and forwards traffic until the session ends; `Ok` if it ended for good.
+  async fn connect_and_serve(&mut self, attempt: &mut u32) -> anyhow::Result<()> {
+    match self.connect().await {
+      Ok(session) => {
+        *attempt = 0;
+        match self.serve(session).await {
+          Ok(_) if self.reconnect.is_none() => Ok(()),
+          Ok(refused) => Err(anyhow::Error::new(refused)),
+          Err(e) => Err(e),
+        }
+      }
       Err(e) => {
         error!("Failed to connect to server: {}", e);
-        return Err(e);
+        Err(e)
       }
+    }
+  }
+
+  /// Forwards traffic until the server ends the session, which is returned, or it's lost.
+  async fn serve(&mut self, mut connection: Connection) -> anyhow::Result<Refused> {
+    _ = self.events.send(ClientEvent::Connected);
+
+    let routes = self.routes.borrow().clone();
+    let updatable = self.routes.has_changed().is_ok();
+    if self.device.tun().is_some() && !self.route_monitor && (!routes.is_empty() || updatable) {
+      let dev = self.device.tun_name()?;
+      self.record(Change::Routes { dev: dev.clone() });
+      routes::install_all(&lan::exclude(&routes, &self.bypassed), &dev).await?;
+      let (updates, bypassed, events) = (self.routes.clone(), self.bypassed.clone(), self.events.clone());
+      let paused = self.routes_paused.clone();
+      self.supervisor.spawn("route-monitor", Restart::Always, move || {
+        routes::monitor(updates.clone(), bypassed.clone(), dev.clone(), events.clone(), paused.clone())
+      });
+      self.route_monitor = true;
+    }
+    // The server of a new session knows nothing of a suspension of the last one.
+    if self.is_suspended() {
+      self.resume(None).await?;
+    }
+    self.idle = match self.idle_suspend {
+      Some(ref config) if self.device.tun().is_some() => Some(IdleTimer::new(config, Instant::now())),
+      _ => None,
     };
 
     let (network_tx, mut network_rx) = mpsc::channel(100);
 
-    let server_addr = SocketAddr::new(self.server_address.into(), self.server_port);
-    let socket = Arc::clone(&self.socket);
+    let (server_addr, socket) = self.session()?;
+    let receiving = Arc::clone(&socket);
 
-    tokio::spawn(async move {
-      let mut buf = vec![0u8; 65536];
+    let _receiver = AbortOnDrop(tokio::spawn(async move {
+      let socket = receiving;
+      let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
       loop {
         match socket.recv_from(&mut buf).await {
-          Ok((len, _)) => {
-            if let Ok(packet) = EncryptedPacket::from_bytes(&buf[..len]).and_then(|p| p.decrypt(&key)) {
-              if network_tx.send(packet).await.is_err() {
-                break;
-              }
+          Ok((len, _, outer_ecn)) => {
+            if network_tx.send((buf[..len].to_vec(), outer_ecn)).await.is_err() {
+              break;
             }
           }
           Err(e) => {
@@ -132,140 +932,692 @@ impl Client {
           }
         }
       }
-    });
+    }));
 
-    let mut ping_sent_rx = self.start_ping(key, server_addr);
+    // Every session starts out with the defaults, so settings changed since the first one and a slower
+    // ping interval are renegotiated right away.
+    let mut params = self.session_params.clone();
+    if params.borrow().keepalive_secs as u64 != PING_INTERVAL.as_secs() {
+      params.mark_changed();
+    }
+    let mut renegotiable = true;
 
+    let mut outer_ecn = ip::ECN_NOT_ECT;
     loop {
-      tokio::select! {
-        _ = self.serve_tun(key, server_addr) => {}
-        Some(packet) = network_rx.recv() => {
-          match packet {
-            ServerPacket::Data(data) => {
-              if let Err(e) = self.tun.write(&data).await {
-                error!("Failed to write to tun: {}", e);
+      while let Some(datagram) = connection.poll_transmit() {
+        if let Err(e) = socket.send_to(&datagram, server_addr, ip::ECN_NOT_ECT).await {
+          error!("Failed to send to server: {}", e);
+        }
+      }
+
+      while let Some(event) = connection.poll_event() {
+        match event {
+          Event::Data(mut data) => {
+            self.record_activity(&mut connection).await?;
+            self.stats.record_received(data.len());
+            if self.download.as_mut().is_some_and(|bucket| !bucket.try_take(data.len() as f64)) {
+              trace!(target: logging::DATAPATH, "Dropping packet from server over the download limit; len: {}", data.len());
+              continue;
+            }
+            if !ecn::decapsulate(&mut data, outer_ecn) {
+              continue;
+            }
+            if let Err(e) = self.device.write(&data).await {
+              error!(target: logging::TUN, "Failed to write to tun: {}", e);
+            }
+          }
+          Event::NetworkConfig { address, prefix_len, dns } => {
+            self.configure(address, prefix_len, &dns).await?;
+          }
+          Event::Stats { sent, received, quota_remaining, clients, max_clients } => {
+            debug!(
+              "Sent {} and received {} bytes; {} of {} clients connected",
+              sent, received, clients, max_clients
+            );
+            _ =
+              self.events.send(ClientEvent::Stats { sent, received, quota_remaining, clients, max_clients });
+          }
+          Event::Notice(notice) => {
+            match notice {
+              Notice::QuotaWarning { percent, remaining } => {
+                warn!("{}% of the data quota is used, {} MiB left", percent, remaining / 1024 / 1024)
               }
+              Notice::InboundConnection { protocol, source, port } => {
+                let protocol = if protocol == ip::PROTO_UDP { "UDP" } else { "TCP" };
+                info!("Inbound {} connection from {} to port {}", protocol, source, port)
+              }
+              Notice::Wake => self.record_activity(&mut connection).await?,
+            }
+            _ = self.events.send(ClientEvent::Notice(notice));
+          }
+          Event::Pong { rtt } => {
+            info!("Ping latency: {:?}", rtt);
+            self.stats.record_rtt(rtt);
+          }
+          Event::Subnets { accepted, rejected } => {
+            if !rejected.is_empty() {
+              warn!("Server refused to route {:?} to the client", rejected);
             }
-            ServerPacket::Error(msg) => {
-              error!("Server error: {}", msg);
+            _ = self.events.send(ClientEvent::Subnets { accepted, rejected });
+          }
+          Event::Forwards { accepted, rejected } => {
+            if !rejected.is_empty() {
+              let ports: Vec<_> = rejected.iter().map(|forward| forward.port).collect();
+              warn!("Server refused to forward ports {:?} to the client", ports);
             }
-            ServerPacket::Pong => {
-              info!("Ping latency: {:?}", Instant::now().duration_since(self.last_ping_sent));
+            _ = self.events.send(ClientEvent::Forwards { accepted, rejected });
+          }
+          Event::Hostname { name, fqdn } => {
+            match fqdn {
+              Some(ref fqdn) => info!("Other clients reach this one as {}", fqdn),
+              None => warn!("Server refused the hostname {}", name),
             }
-            ServerPacket::Disconnect { reason } => {
-              info!("Disconnected from server: {}", reason);
-              return Ok(());
+            _ = self.events.send(ClientEvent::Hostname { name, fqdn });
+          }
+          Event::Routes(routes) => self.route_sites(routes).await?,
+          Event::MtuReduced { from, to, reason } => {
+            _ = self.events.send(ClientEvent::MtuReduced { from, to, reason });
+          }
+          Event::Renegotiated(params) => {
+            if params.mtu != self.mtu {
+              if let Some(tun) = self.device.tun() {
+                tun.set_mtu(params.mtu)?;
+              }
+              self.mtu = params.mtu;
             }
-            _ => {
-              error!("Unexpected packet from server: {:?}", packet);
+            self.offered_transforms = self.session_params.borrow().transforms.clone();
+            _ = self.events.send(ClientEvent::Renegotiated(params));
+          }
+          Event::Closed { code, reason } => {
+            _ = self.events.send(ClientEvent::Disconnected { code, reason: reason.clone() });
+            return match code {
+              Some(code) => Ok(Refused { code, reason, retry_after: None }),
+              None => Err(anyhow::anyhow!(reason)),
+            };
+          }
+          Event::Ticket { ticket, lifetime } => {
+            let Some(ref store) = self.tickets else {
+              continue;
+            };
+            match store.save(ticket.clone(), lifetime) {
+              Ok(()) => {
+                debug!(target: logging::HANDSHAKE, "Stored a session ticket valid for {:?}", lifetime)
+              }
+              Err(e) => warn!(target: logging::HANDSHAKE, "Failed to store the session ticket: {}", e),
             }
+            self.ticket = Some(ticket);
+          }
+          Event::ClockSkew { offset_secs } => _ = self.events.send(ClientEvent::ClockSkew { offset_secs }),
+          Event::PeerKeyRefused { peer } => _ = self.events.send(ClientEvent::PeerKeyRefused { peer }),
+          Event::Established | Event::PasswordChanged(_) => {}
+        }
+      }
+      outer_ecn = ip::ECN_NOT_ECT;
+
+      let Some(timeout) = connection.poll_timeout() else {
+        anyhow::bail!("Session closed");
+      };
+      let idle_deadline = self.idle.as_ref().and_then(IdleTimer::deadline);
+      tokio::select! {
+        result = self.serve_tun(&mut connection, &socket, server_addr) => result?,
+        datagram = network_rx.recv() => {
+          let Some((datagram, ecn)) = datagram else {
+            anyhow::bail!("Stopped receiving from server");
           };
+          if let Err(e) = connection.handle_datagram(Instant::now(), &datagram) {
+            trace!(target: logging::CRYPTO, "Dropping datagram from server: {}", e);
+          }
+          outer_ecn = ecn;
         }
-        Some(_) = ping_sent_rx.recv() => {
-          self.last_ping_sent = Instant::now();
+        changed = params.changed(), if renegotiable => {
+          if changed.is_err() {
+            // Nothing sends new settings any more.
+            renegotiable = false;
+            continue;
+          }
+          if let Err(e) = connection.renegotiate(params.borrow_and_update().clone()) {
+            error!("Failed to renegotiate the session: {}", e);
+          }
+        }
+        _ = tokio::time::sleep_until(timeout.into()) => {
+          connection.handle_timeout(Instant::now());
+        }
+        _ = tokio::time::sleep_until(idle_deadline.unwrap_or(timeout).into()), if idle_deadline.is_some() => {
+          if self.idle.as_mut().is_some_and(|timer| timer.expired(Instant::now(), idle::on_battery)) {
+            self.suspend(&mut connection).await?;
+          }
         }
       }
     }
   }
 
-  async fn connect(&mut self) -> anyhow::Result<Key> {
-    let Some(ref credentials) = self.credentials else {
-      anyhow::bail!("No credentials provided");
+  /// Whether the routes are down for idleness; stays set when bringing them back failed, so that the next
+  /// session tries again.
+  fn is_suspended(&self) -> bool {
+    self.routes_paused.load(Ordering::Relaxed)
+  }
+
+  /// Records a packet through the tunnel, which brings back routes suspended for idleness.
+  async fn record_activity(&mut self, connection: &mut Connection) -> anyhow::Result<()> {
+    if self.idle.as_mut().is_some_and(|timer| timer.active(Instant::now())) {
+      self.resume(Some(connection)).await?;
+    }
+    Ok(())
+  }
+
+  /// Takes the routes into the tunnel down, keeping the session and the lease.
+  async fn suspend(&mut self, connection: &mut Connection) -> anyhow::Result<()> {
+    let dev = self.device.tun_name()?;
+    self.routes_paused.store(true, Ordering::Relaxed);
+    let mut routes = match self.route_monitor {
+      true => lan::exclude(&self.routes.borrow(), &self.bypassed),
+      false => Vec::new(),
     };
+    routes.extend_from_slice(&self.site_routes);
+    for route in &routes {
+      if let Err(e) = routes::remove(route, &dev).await {
+        error!("{}", e);
+      }
+    }
+    if !connection.suspend(true)? {
+      debug!("Server can't wake the client; only outbound traffic brings the routes back");
+    }
+    info!("Tunnel is idle; took its routes down until traffic goes through it");
+    _ = self.events.send(ClientEvent::Suspended);
+    Ok(())
+  }
+
+  /// Brings back the routes taken down by `suspend`, telling the server along if the suspended session
+  /// still goes on.
+  async fn resume(&mut self, connection: Option<&mut Connection>) -> anyhow::Result<()> {
+    if let Some(timer) = self.idle.as_mut() {
+      timer.active(Instant::now());
+    }
+    let dev = self.device.tun_name()?;
+    if self.route_monitor {
+      let routes = lan::exclude(&self.routes.borrow(), &self.bypassed);
+      routes::install_all(&routes, &dev).await?;
+    }
+    routes::install_all(&self.site_routes, &dev).await?;
+    self.routes_paused.store(false, Ordering::Relaxed);
+    if let Some(connection) = connection {
+      connection.suspend(false)?;
+    }
+    info!("Traffic goes through the tunnel again; restored its routes");
+    _ = self.events.send(ClientEvent::Resumed);
+    Ok(())
+  }
 
-    let server_addr = SocketAddr::new(self.server_address.into(), self.server_port);
+  /// Authenticates over UDP or, where that goes unanswered, TCP, see `fallback`.
+  async fn connect(&mut self) -> anyhow::Result<Connection> {
+    if let Some(ref mut fallback) = self.tcp_fallback {
+      fallback.follow_network().await;
+    }
+    let mut switched = false;
+    loop {
+      let result = self.authenticate().await;
+      let Some(ref mut fallback) = self.tcp_fallback else {
+        return result;
+      };
+      match result {
+        Ok(_) => fallback.answered(),
+        // Switching back and forth at once would never give up; reconnecting starts over later.
+        Err(ref e) if e.is::<Unanswered>() && fallback.unanswered() && !switched => {
+          warn!(target: logging::HANDSHAKE, "{}; trying {} instead", e, fallback.transport());
+          switched = true;
+          continue;
+        }
+        Err(_) => {}
+      }
+      return result;
+    }
+  }
 
-    let mut session_key = [0u8; KEY_SIZE];
-    fill_random_bytes(&mut session_key);
+  async fn authenticate(&mut self) -> anyhow::Result<Connection> {
+    if let Some(ticket) = self.ticket.clone() {
+      info!(target: logging::HANDSHAKE, "Resuming the session with a ticket");
+      match self.handshake(ClientAuth::Credentials(Credentials::Ticket(ticket))).await {
+        Err(e) if e.downcast_ref::<Refused>().is_some_and(|refused| refused.code.is_permanent()) => {
+          warn!(target: logging::HANDSHAKE, "Server refused the session ticket: {}", e);
+          self.forget_ticket();
+        }
+        result => return result,
+      }
+    }
 
-    let keyexchange_packet =
-      EncryptedPacket::encrypt(&[0u8; KEY_SIZE], &ClientPacket::KeyExchange(session_key))?;
+    let auth = match (&self.key, &self.certificate, &self.credentials) {
+      (Some((username, key)), _, _) => ClientAuth::Key { username: username.clone(), key: key.clone() },
+      (None, Some((certificate, key)), _) => {
+        ClientAuth::Certificate { certificate: certificate.clone(), key: key.clone() }
+      }
+      (None, None, Some(credentials)) => ClientAuth::Credentials(credentials.clone()),
+      (None, None, None) => anyhow::bail!("No credentials provided"),
+    };
+    self.handshake(auth).await
+  }
 
-    self.socket.send_to(&keyexchange_packet.to_bytes(), server_addr).await?;
+  /// Authenticates over the first address of the server to answer the key exchange, see `eyeballs`.
+  async fn handshake(&mut self, auth: ClientAuth) -> anyhow::Result<Connection> {
+    let config = ConnectionConfig {
+      auth,
+      server_public_key: self.server_public_key,
+      transforms: self.transforms.clone(),
+      offered_transforms: self.offered_transforms.clone(),
+      handshake_timeout: self.connect_timeout,
+      mtu_probe: self.mtu_fallback.then_some(self.mtu),
+      subnets: self.subnets.clone(),
+      reverse_forwards: self.reverse_forwards.iter().map(ReverseForwardConfig::request).collect(),
+      peer_encryption: self.peer_encryption,
+      hostname: self.hostname.clone(),
+    };
+    self.session_socket = None;
+    let mut candidates = self.candidates().await?.into_iter().peekable();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut next_attempt = Instant::now();
+    let mut failure = None;
+    // Whether anything came back at all, telling a failing server from a network that drops the transport.
+    let mut answered = false;
 
-    info!("Waiting for key exchange...");
-    let mut buf = vec![0u8; 65536];
+    info!(target: logging::HANDSHAKE, "Waiting for key exchange...");
+    let mut bufs = Vec::new();
+    loop {
+      let now = Instant::now();
+      if now >= next_attempt || attempts.is_empty() {
+        match candidates.next() {
+          Some(addr) => {
+            debug!(target: logging::HANDSHAKE, "Starting key exchange with {}", addr);
+            next_attempt = now + eyeballs::ATTEMPT_DELAY;
+            match self.socket_for(addr).await {
+              Ok(socket) => {
+                attempts.push(Attempt { addr, socket, connection: Connection::new(config.clone(), now)? })
+              }
+              Err(e) => failure = Some(anyhow::anyhow!("{}: {}", addr, e)),
+            }
+            continue;
+          }
+          None if attempts.is_empty() => {
+            let error =
+              failure.unwrap_or_else(|| anyhow::anyhow!("The server has no addresses to connect to"));
+            return Err(if answered { error } else { Unanswered(error.to_string()).into() });
+          }
+          None => {}
+        }
+      }
 
-    match tokio::time::timeout(self.connect_timeout, self.socket.recv_from(&mut buf)).await {
-      Ok(Ok((len, _))) => match EncryptedPacket::from_bytes(&buf[..len])?.decrypt(&[0u8; KEY_SIZE])? {
-        ServerPacket::KeyExchange(server_key) => {
-          for i in 0..KEY_SIZE {
-            session_key[i] ^= server_key[i];
+      let mut index = 0;
+      while index < attempts.len() {
+        let attempt = &mut attempts[index];
+        let result = async {
+          while let Some(datagram) = attempt.connection.poll_transmit() {
+            attempt.socket.send_to(&datagram, attempt.addr, ip::ECN_NOT_ECT).await?;
+          }
+          match attempt.connection.poll_event() {
+            Some(Event::Closed { code: None, reason }) => Err(anyhow::anyhow!(reason)),
+            event => Ok(event),
+          }
+        }
+        .await;
+        match result {
+          Ok(Some(Event::Established)) => {
+            let attempt = attempts.swap_remove(index);
+            if self.server_addr.is_some_and(|previous| previous != attempt.addr) {
+              info!(target: logging::HANDSHAKE, "Connected to the server at {}", attempt.addr);
+            }
+            self.server_addr = Some(attempt.addr);
+            self.session_socket = Some(attempt.socket);
+            return Ok(attempt.connection);
+          }
+          Ok(Some(Event::Closed { code: Some(code), reason })) => {
+            let retry_after = attempts[index].connection.retry_after();
+            return Err(Refused { code, reason, retry_after }.into());
+          }
+          Ok(Some(Event::ClockSkew { offset_secs })) => {
+            _ = self.events.send(ClientEvent::ClockSkew { offset_secs });
+            index += 1;
           }
+          Ok(_) => index += 1,
+          Err(e) => {
+            debug!(target: logging::HANDSHAKE, "Key exchange with {} failed: {}", attempts[index].addr, e);
+            failure = Some(e);
+            attempts.swap_remove(index);
+            // Don't wait out the head start of an attempt that has already failed.
+            next_attempt = Instant::now();
+          }
+        }
+      }
+      if attempts.is_empty() {
+        continue;
+      }
 
-          info!("Successfully established secure connection; Authenticating...");
+      let timeout = attempts.iter().filter_map(|attempt| attempt.connection.poll_timeout()).min();
+      let Some(wake) = timeout.into_iter().chain(candidates.peek().map(|_| next_attempt)).min() else {
+        anyhow::bail!("Connection closed");
+      };
+      // Attempts share the UDP socket of their family, while each TCP one has a connection of its own.
+      let mut sockets: Vec<Arc<Socket>> = Vec::new();
+      for attempt in &attempts {
+        if !sockets.iter().any(|socket| Arc::ptr_eq(socket, &attempt.socket)) {
+          sockets.push(Arc::clone(&attempt.socket));
+        }
+      }
+      bufs.resize_with(sockets.len(), || vec![0u8; MAX_DATAGRAM_SIZE]);
+      let (socket, received) = tokio::select! {
+        received = recv_any(&sockets, &mut bufs) => received,
+        _ = tokio::time::sleep_until(wake.into()) => {
+          let now = Instant::now();
+          attempts.iter_mut().for_each(|attempt| attempt.connection.handle_timeout(now));
+          continue;
         }
-        _ => {
-          anyhow::bail!("Failed to establish secure connection");
+      };
+      let (from, datagram) = match received {
+        Ok((len, from, _)) => (from, &bufs[socket][..len]),
+        Err(e) => {
+          // The connection of a TCP attempt failed, and the attempt with it.
+          debug!(target: logging::HANDSHAKE, "Key exchange failed: {}", e);
+          failure = Some(e.into());
+          attempts.retain(|attempt| !Arc::ptr_eq(&attempt.socket, &sockets[socket]));
+          next_attempt = Instant::now();
+          continue;
+        }
+      };
+
+      // Sent by a server behind NAT to open it to this client, see `rendezvous`.
+      if rendezvous::is_rendezvous(datagram) {
+        continue;
+      }
+      let on_socket = |attempt: &&Attempt| Arc::ptr_eq(&attempt.socket, &sockets[socket]);
+      // A multi-homed server may answer from another of its addresses, which is only told apart from the
+      // rest of the race while a single attempt is waiting on the socket.
+      let position = match attempts.iter().position(|attempt| attempt.addr == from && on_socket(&attempt)) {
+        Some(index) => Some(index),
+        None if attempts.iter().filter(on_socket).count() == 1 => {
+          debug!(target: logging::HANDSHAKE, "Server answered from {} instead of the address dialed", from);
+          attempts.iter().position(|attempt| on_socket(&attempt))
+        }
+        None => None,
+      };
+      let Some(index) = position else {
+        trace!(target: logging::HANDSHAKE, "Dropping datagram from {} during the key exchange", from);
+        continue;
+      };
+      answered = true;
+      match attempts[index].connection.handle_datagram(Instant::now(), datagram) {
+        // The first server to answer wins; the others haven't been sent credentials yet.
+        Ok(()) => {
+          attempts.swap(0, index);
+          attempts.truncate(1);
+          candidates = Vec::new().into_iter().peekable();
+        }
+        Err(e) => {
+          failure = Some(e);
+          attempts.swap_remove(index);
+          next_attempt = Instant::now();
+        }
+      }
+    }
+  }
+
+  /// Addresses of the server to race the key exchange over, the one that answered last time first.
+  async fn candidates(&self) -> anyhow::Result<Vec<SocketAddr>> {
+    let port = match self.tcp_fallback {
+      Some(ref fallback) if fallback.transport() == Transport::Tcp => fallback.port(),
+      _ => self.server_port,
+    };
+    let configured = SocketAddr::new(self.server_address.into(), port);
+    let udp = self.tcp_fallback.as_ref().is_none_or(|fallback| fallback.transport() != Transport::Tcp);
+    if let Some(rendezvous) = self.rendezvous.as_ref().filter(|_| udp) {
+      // Looked up from the socket the key exchange goes out of, which the server opens its NAT to.
+      match rendezvous.locate(&self.socket, self.connect_timeout).await {
+        Ok(addr) => return Ok(vec![addr]),
+        Err(e) if self.server_address.is_unspecified() && self.server_host.is_none() => {
+          anyhow::bail!(
+            "Failed to look up {} with the rendezvous {}: {}",
+            rendezvous.name,
+            rendezvous.address,
+            e
+          )
+        }
+        Err(e) => warn!(
+          "Failed to look up {} with the rendezvous {}, using the configured address: {}",
+          rendezvous.name, rendezvous.address, e
+        ),
+      }
+    }
+    let mut candidates = match self.server_host {
+      Some(ref host) => match eyeballs::resolve(self.resolver.as_ref(), host, port).await {
+        Ok(resolved) => resolved,
+        Err(e) if self.server_address.is_unspecified() => {
+          anyhow::bail!("Failed to look up {} through the {} resolver: {}", host, self.resolver.name(), e)
+        }
+        Err(e) => {
+          warn!(
+            "Failed to look up {} through the {} resolver, using {}: {}",
+            host,
+            self.resolver.name(),
+            configured,
+            e
+          );
+          vec![configured]
         }
       },
-      _ => {
-        anyhow::bail!("Connection handshake timeout");
+      None => {
+        let alternatives = self.alternatives.iter().map(|address| SocketAddr::new(*address, port));
+        eyeballs::interleave(std::iter::once(configured).chain(alternatives).collect())
       }
+    };
+    if let Some(winner) = self.server_addr {
+      eyeballs::prefer(&mut candidates, winner);
     }
+    Ok(candidates)
+  }
 
-    let packet = EncryptedPacket::encrypt(&session_key, &ClientPacket::Auth(credentials.clone()))?;
-    self.socket.send_to(&packet.to_bytes(), server_addr).await?;
+  /// Socket to reach `addr` from; IPv6 gets one of its own on an ephemeral port, and each connection over
+  /// TCP one of its own.
+  async fn socket_for(&mut self, addr: SocketAddr) -> anyhow::Result<Arc<Socket>> {
+    if self.tcp_fallback.as_ref().is_some_and(|fallback| fallback.transport() == Transport::Tcp) {
+      return Ok(Arc::new(Socket::Tcp(TcpTransport::connect(addr))));
+    }
+    if addr.is_ipv4() {
+      return Ok(Arc::clone(&self.socket));
+    }
+    if let Some(ref socket) = self.socket6 {
+      return Ok(Arc::clone(socket));
+    }
 
-    let mut buf = vec![0u8; 65536];
+    let local = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+    let socket = Arc::new(match self.memory {
+      Some(ref network) => Socket::Memory(network.bind(local)?),
+      None => Socket::Udp(UdpSocket::bind(local).await?),
+    });
+    self.socket6 = Some(Arc::clone(&socket));
+    Ok(socket)
+  }
 
-    match tokio::time::timeout(self.connect_timeout, self.socket.recv_from(&mut buf)).await {
-      Ok(Ok((len, _))) => match EncryptedPacket::from_bytes(&buf[..len])?.decrypt(&session_key)? {
-        ServerPacket::AuthOk => {
-          info!("Authentication successful");
-          Ok(session_key)
-        }
-        ServerPacket::AuthError(message) => anyhow::bail!("Authentication failed: {}", message),
-        _ => anyhow::bail!("Unexpected response from server"),
-      },
-      _ => anyhow::bail!("Connection timeout"),
+  /// Address and socket of the server the last key exchange was won with.
+  fn session(&self) -> anyhow::Result<(SocketAddr, Arc<Socket>)> {
+    match (self.server_addr, &self.session_socket) {
+      (Some(addr), Some(socket)) => Ok((addr, Arc::clone(socket))),
+      _ => anyhow::bail!("No key exchange with the server"),
     }
   }
 
-  async fn serve_tun(&mut self, key: Key, server_addr: SocketAddr) -> anyhow::Result<()> {
-    let mut buf = vec![0u8; 65536];
-    match self.tun.read(&mut buf).await {
-      Ok(len) => {
-        let packet = EncryptedPacket::encrypt(&key, &ClientPacket::Data(buf[..len].to_vec()))?;
-        match self.socket.send_to(&packet.to_bytes(), server_addr).await {
-          Ok(_) => info!("Sent tun packet to server; len: {}", len),
-          Err(e) => {
-            error!("Failed to send data to server: {}", e);
+  /// Replaces the password of the client's credentials by `new`, in a session of its own that ends once the
+  /// server answered.
+  pub async fn change_password(mut self, new: &str) -> anyhow::Result<()> {
+    let Some(Credentials::Password { username, password }) = self.credentials.clone() else {
+      anyhow::bail!("Only password credentials can be changed");
+    };
+    let credentials = Credentials::Password { username, password: password.clone() };
+    let mut connection = self.handshake(ClientAuth::Credentials(credentials)).await?;
+    connection.change_password(&password, new)?;
+    let (server_addr, socket) = self.session()?;
+
+    let deadline = Instant::now() + self.connect_timeout;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+      while let Some(datagram) = connection.poll_transmit() {
+        socket.send_to(&datagram, server_addr, ip::ECN_NOT_ECT).await?;
+      }
+      while let Some(event) = connection.poll_event() {
+        match event {
+          Event::PasswordChanged(result) => {
+            connection.disconnect()?;
+            while let Some(datagram) = connection.poll_transmit() {
+              socket.send_to(&datagram, server_addr, ip::ECN_NOT_ECT).await?;
+            }
+            // Tickets of the old password are refused from now on.
+            if result.is_ok() {
+              self.forget_ticket();
+            }
+            return result.map_err(anyhow::Error::msg);
           }
+          Event::Closed { reason, .. } => anyhow::bail!(reason),
+          _ => {}
         }
       }
-      Err(e) => {
-        anyhow::bail!("Error reading from tun: {}", e);
+
+      tokio::select! {
+        received = socket.recv_from(&mut buf) => {
+          let (len, _, _) = received?;
+          if !rendezvous::is_rendezvous(&buf[..len]) {
+            connection.handle_datagram(Instant::now(), &buf[..len])?;
+          }
+        }
+        _ = tokio::time::sleep_until(deadline.into()) => anyhow::bail!("Server didn't answer"),
+      }
+    }
+  }
+
+  fn record(&self, change: Change) {
+    if let Some(ref state) = self.system_state {
+      state.record(change);
+    }
+  }
+
+  fn forget_ticket(&mut self) {
+    if self.ticket.take().is_some() {
+      if let Some(ref store) = self.tickets {
+        store.clear();
       }
     }
+  }
 
+  /// Routes the subnets of other sites the server advertised into the tunnel, in place of the ones it
+  /// advertised before.
+  async fn route_sites(&mut self, routes: Vec<Ipv4Net>) -> anyhow::Result<()> {
+    info!("Server advertised routes of the user's groups and other sites: {:?}", routes);
+    _ = self.events.send(ClientEvent::Routes { routes: routes.clone() });
+    let routes = lan::exclude(&routes, &self.bypassed);
+    // Suspended routes are brought back from `site_routes` on resume.
+    if self.device.tun().is_some() && !self.is_suspended() {
+      let dev = self.device.tun_name()?;
+      for route in self.site_routes.iter().filter(|route| !routes.contains(route)) {
+        match routes::remove(route, &dev).await {
+          Ok(()) => info!("Removed route {} via {}", route, dev),
+          Err(e) => error!("{}", e),
+        }
+      }
+      let added: Vec<_> = routes.iter().filter(|route| !self.site_routes.contains(route)).copied().collect();
+      self.record(Change::Routes { dev: dev.clone() });
+      routes::install_all(&added, &dev).await?;
+    }
+    self.site_routes = routes;
     Ok(())
   }
 
-  fn start_ping(&self, key: Key, server_addr: SocketAddr) -> Receiver<()> {
-    let socket = Arc::clone(&self.socket);
-    let interval = Duration::from_secs(5);
+  /// Takes the address and resolvers the server leased to this session.
+  async fn configure(&mut self, address: Ipv4Addr, prefix_len: u8, dns: &[Ipv4Addr]) -> anyhow::Result<()> {
+    let network = Ipv4Net::new(address, prefix_len)?;
+    info!("Server assigned address {}", network);
+    _ = self.events.send(ClientEvent::NetworkConfig { network, dns: dns.to_vec() });
+    self.lease = Some((network, dns.to_vec()));
+    self.apply_lease(network, dns).await
+  }
 
-    let (tx, rx) = mpsc::channel(1);
+  async fn apply_lease(&mut self, network: Ipv4Net, dns: &[Ipv4Addr]) -> anyhow::Result<()> {
+    let Some(tun) = self.device.tun() else {
+      return Ok(());
+    };
+    tun.set_address(network.addr().into())?;
+    tun.set_netmask(network.netmask().into())?;
 
-    tokio::spawn(async move {
-      loop {
-        match EncryptedPacket::encrypt(&key, &ClientPacket::Ping) {
-          Ok(packet) => {
-            if let Err(err) = socket.send_to(&packet.to_bytes(), server_addr).await {
-              error!("Failed to send ping: {}", err);
-            }
-            tx.send(()).await.unwrap();
+    if self.accept_dns && !dns.is_empty() {
+      let dev = self.device.tun_name()?;
+      self.record(Change::Dns { dev: dev.clone() });
+      match dns::apply(&dev, dns).await {
+        Ok(()) => info!("Using the server's resolvers {:?}", dns),
+        Err(e) => warn!("Failed to use the server's resolvers: {}", e),
+      }
+    }
+    Ok(())
+  }
+
+  async fn serve_tun(
+    &mut self,
+    connection: &mut Connection,
+    socket: &Socket,
+    server_addr: SocketAddr,
+  ) -> anyhow::Result<()> {
+    // Waiting before the read rather than after it, so that a packet isn't lost when another branch of the
+    // loop wins meanwhile.
+    tokio::time::sleep_until(self.upload_ready.into()).await;
+    let mut buf = vec![0u8; self.mtu as usize];
+    match self.device.read(&mut buf).await {
+      Ok(len) => {
+        self.record_activity(connection).await?;
+        let outer_ecn = if self.ecn { ecn::encapsulate(&buf[..len]) } else { ip::ECN_NOT_ECT };
+        // Packets to other clients wait in the connection until a pairwise key is agreed
+        let Some(packet) = connection.seal_data(Instant::now(), buf[..len].to_vec())? else {
+          return Ok(());
+        };
+        if let Some(ref mut bucket) = self.upload {
+          self.upload_ready = Instant::now() + bucket.take(packet.len() as f64);
+        }
+        match socket.send_to(&packet, server_addr, outer_ecn).await {
+          Ok(_) => {
+            info!(target: logging::DATAPATH, "Sent tun packet to server; len: {}", len);
+            self.stats.record_sent(len);
           }
           Err(e) => {
-            error!("Failed to encrypt ping packet: {}", e);
+            error!(target: logging::DATAPATH, "Failed to send data to server: {}", e);
           }
         }
-
-        sleep(interval).await;
       }
-    });
+      Err(e) => {
+        let name = self.device.tun_name()?;
+        if !iface::is_removed(&name, &e) {
+          anyhow::bail!("Error reading from tun: {}", e);
+        }
+        error!(target: logging::TUN, "Tun device {} was removed; recreating it", name);
+        _ = self.events.send(ClientEvent::TunRemoved { name: name.clone() });
+        if let Err(e) = self.recreate_tun(&name).await {
+          return Err(Fatal(format!("Tun device {} was removed and can't be recreated: {}", name, e)).into());
+        }
+        info!(target: logging::TUN, "Recreated tun device {}", name);
+        _ = self.events.send(ClientEvent::TunRecreated { name });
+      }
+    }
 
-    rx
+    Ok(())
+  }
+
+  /// Makes the tun device again under its old name, with the MTU, lease and routes it had.
+  async fn recreate_tun(&mut self, name: &str) -> anyhow::Result<()> {
+    let mut config = self.tun_config.clone();
+    config.tun_name(name).mtu(self.mtu);
+    self.device = create_tun(&config, self.tun_description.as_deref(), self.tun_gso)?;
+
+    if let Some((network, dns)) = self.lease.clone() {
+      self.apply_lease(network, &dns).await?;
+    }
+    self.record(Change::Routes { dev: name.to_string() });
+    if self.is_suspended() {
+      return Ok(());
+    }
+    if self.route_monitor {
+      let routes = lan::exclude(&self.routes.borrow(), &self.bypassed);
+      routes::install_all(&routes, name).await?;
+    }
+    routes::install_all(&self.site_routes, name).await
   }
 }
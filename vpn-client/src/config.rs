@@ -1,88 +1,367 @@
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
 use std::path::Path;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use ipnet::Ipv4Net;
 use serde::Deserialize;
+use serde_yml::Value;
+use tracing::warn;
 use vpn_shared::creds::Credentials;
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub struct TunConfig {
-  pub name: String,
-  pub address: Ipv4Addr,
-  pub netmask: Ipv4Addr,
-  pub mtu: Option<u16>,
-
-  #[serde(default = "default_tun_up")]
-  pub up: bool,
-}
+use vpn_shared::handshake;
+pub use vpn_shared::iface::TunConfig;
+use vpn_shared::logging::LogConfig;
+use vpn_shared::outer::OuterConfig;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::SessionParams;
+use vpn_shared::peer::PeerEncryption;
+use vpn_shared::protocol::PING_INTERVAL;
+use vpn_shared::transform::Registry;
+
+use crate::client::Backoff;
+use crate::discovery;
+use crate::discovery::DiscoveryConfig;
+use crate::discovery::Endpoint;
+use crate::fallback::TcpFallbackConfig;
+use crate::forward::ForwardConfig;
+use crate::forward::ReverseForwardConfig;
+use crate::gateway::GatewayConfig;
+use crate::idle::IdleSuspendConfig;
+use crate::instance;
+use crate::killswitch::KillSwitchConfig;
+use crate::lan::LanAccessConfig;
+use crate::oidc::OidcConfig;
+use crate::portmap::PortMappingConfig;
+use crate::profile;
+use crate::rendezvous::RendezvousConfig;
+use crate::resolver::ResolverConfig;
+use crate::resume::ResumeConfig;
+use crate::trusted::AutoConnectConfig;
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct ClientConfig {
-  pub server_address: Ipv4Addr,
-  pub server_port: u16,
+  /// Required unless `discovery` is set, with which it's the fallback when lookups fail.
+  #[serde(default)]
+  pub server_address: Option<Ipv4Addr>,
+  /// Hostname of the server, looked up on every connect; with both IPv6 and IPv4 addresses, the key
+  /// exchange is raced over them. `server_address` is then only the fallback when the lookup fails.
+  #[serde(default)]
+  pub server_host: Option<String>,
+  /// How `server_host` is looked up; the system resolver by default.
+  #[serde(default)]
+  pub resolver: Option<ResolverConfig>,
+  #[serde(default)]
+  pub server_port: Option<u16>,
+  /// TCP port of the server to switch to where UDP goes unanswered.
+  #[serde(default)]
+  pub tcp_fallback: Option<TcpFallbackConfig>,
+
+  #[serde(default)]
+  pub discovery: Option<DiscoveryConfig>,
+
+  /// Rendezvous a server behind NAT registers with; `server-address` is then optional.
+  #[serde(default)]
+  pub rendezvous: Option<RendezvousConfig>,
+
+  /// Hex-encoded static key printed by the server; without it the server isn't authenticated.
+  #[serde(default)]
+  pub server_public_key: Option<String>,
 
   pub listen_address: Ipv4Addr,
   pub listen_port: u16,
 
   pub connect_timeout_secs: u64,
 
-  pub credentials: Credentials,
+  #[serde(default)]
+  pub credentials: Option<Credentials>,
+
+  #[serde(default)]
+  pub key: Option<KeyConfig>,
+
+  #[serde(default)]
+  pub certificate: Option<CertificateConfig>,
+
+  /// Sign in through the provider's device flow on every start; needs the `oidc` feature.
+  #[serde(default)]
+  pub oidc: Option<OidcConfig>,
 
   #[serde(default = "default_tun_config")]
   pub tun: TunConfig,
-}
 
-fn default_tun_config() -> TunConfig {
-  TunConfig {
-    name: "tun0".to_string(),
-    address: Ipv4Addr::new(10, 0, 0, 1),
-    netmask: Ipv4Addr::new(255, 255, 255, 0),
-    mtu: Some(1500),
-    up: true,
-  }
+  #[serde(default)]
+  pub routes: Vec<Ipv4Net>,
+
+  /// LANs behind the client to have the server route to it, if the user may, see
+  /// `ClientBuilder::with_subnets`.
+  #[serde(default)]
+  pub subnets: Vec<Ipv4Net>,
+
+  #[serde(default)]
+  pub reconnect: ReconnectConfig,
+
+  #[serde(default)]
+  pub port_mapping: Option<PortMappingConfig>,
+
+  /// Carry ECN marks between tunneled packets and the datagrams carrying them (Linux only).
+  #[serde(default)]
+  pub ecn: bool,
+
+  /// DF bit and TTL of the datagrams carrying the tunnel.
+  #[serde(default)]
+  pub outer: OuterConfig,
+
+  /// Probe the MTU with each ping and lower it when only large packets get lost; needs a server answering
+  /// probes.
+  #[serde(default)]
+  pub mtu_fallback: bool,
+
+  /// Transforms to offer the server, in order of preference, see `vpn_shared::transform`.
+  #[serde(default)]
+  pub transforms: Vec<String>,
+
+  /// Limits on the client's own tunnel traffic, for metered or shared links; see
+  /// `ClientBuilder::with_max_upload_kbps` and `with_max_download_kbps`.
+  #[serde(default)]
+  pub max_upload_kbps: Option<u64>,
+  #[serde(default)]
+  pub max_download_kbps: Option<u64>,
+
+  /// How often to ping the server, keeping the session and NAT mappings on the way alive.
+  #[serde(default = "default_keepalive_secs")]
+  pub keepalive_secs: u32,
+
+  /// Use the resolvers pushed by servers that lease addresses.
+  #[serde(default = "default_accept_dns")]
+  pub accept_dns: bool,
+
+  #[serde(default)]
+  pub auto_connect: Option<AutoConnectConfig>,
+
+  #[serde(default)]
+  pub kill_switch: Option<KillSwitchConfig>,
+
+  #[serde(default)]
+  pub lan_access: Option<LanAccessConfig>,
+
+  #[serde(default)]
+  pub gateway: Option<GatewayConfig>,
+
+  /// Local ports relayed to hosts behind the tunnel without routes to them, see `forward`.
+  #[serde(default)]
+  pub forwards: Vec<ForwardConfig>,
+
+  /// Ports of the server forwarded to local services on request, see `forward::serve_reverse`.
+  #[serde(default)]
+  pub reverse_forwards: Vec<ReverseForwardConfig>,
+
+  /// Sealing of traffic to other clients with keys the server doesn't know, see `vpn_shared::peer`.
+  #[serde(default)]
+  pub peer_encryption: PeerEncryption,
+
+  /// Name other clients reach this one by through the server's internal DNS, e.g. `laptop` for
+  /// `laptop.vpn.internal`.
+  #[serde(default)]
+  pub hostname: Option<String>,
+
+  /// Takes the routes into the tunnel down while it's idle, e.g. on laptops to save battery.
+  #[serde(default)]
+  pub idle_suspend: Option<IdleSuspendConfig>,
+
+  /// Keeps session tickets of servers issuing them, to resume the session after a restart.
+  #[serde(default)]
+  pub resume: Option<ResumeConfig>,
+
+  #[serde(default)]
+  pub log: LogConfig,
+
+  /// Named sets of top-level settings, e.g. another server or routes, to connect with by
+  /// `vpn-client up <name>`; see `profile::apply`.
+  #[serde(default)]
+  pub profiles: BTreeMap<String, Value>,
 }
 
-fn default_tun_up() -> bool {
+fn default_accept_dns() -> bool {
   true
 }
 
-impl TunConfig {
-  pub fn to_tun_config(&self) -> tun::Configuration {
-    let mut config = tun::Configuration::default();
+fn default_keepalive_secs() -> u32 {
+  PING_INTERVAL.as_secs() as u32
+}
 
-    config.tun_name(&self.name).address(self.address).netmask(self.netmask);
+/// Reconnecting stops for good when the server rejects the credentials, key or certificate.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReconnectConfig {
+  #[serde(default = "default_true")]
+  pub enabled: bool,
+  #[serde(default = "default_initial_delay_secs")]
+  pub initial_delay_secs: u64,
+  #[serde(default = "default_max_delay_secs")]
+  pub max_delay_secs: u64,
+  /// Random share, in percent, each delay is stretched by.
+  #[serde(default = "default_jitter_pct")]
+  pub jitter_pct: u8,
+}
 
-    if self.up {
-      config.up();
+impl Default for ReconnectConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      initial_delay_secs: default_initial_delay_secs(),
+      max_delay_secs: default_max_delay_secs(),
+      jitter_pct: default_jitter_pct(),
     }
+  }
+}
 
-    if let Some(mtu) = self.mtu {
-      config.mtu(mtu);
+impl ReconnectConfig {
+  pub fn backoff(&self) -> Backoff {
+    Backoff {
+      initial: Duration::from_secs(self.initial_delay_secs),
+      max: Duration::from_secs(self.max_delay_secs),
+      jitter_pct: self.jitter_pct,
     }
+  }
+}
+
+pub(crate) fn default_true() -> bool {
+  true
+}
+
+fn default_initial_delay_secs() -> u64 {
+  1
+}
+
+fn default_max_delay_secs() -> u64 {
+  60
+}
+
+fn default_jitter_pct() -> u8 {
+  50
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct KeyConfig {
+  pub username: String,
+  #[serde(deserialize_with = "handshake::deserialize_key")]
+  pub private_key: Key,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CertificateConfig {
+  /// File with the certificate printed by `vpn-server ca issue`.
+  pub path: PathBuf,
+  #[serde(deserialize_with = "handshake::deserialize_key")]
+  pub private_key: Key,
+}
 
-    config
+fn default_tun_config() -> TunConfig {
+  TunConfig {
+    name: "tun0".to_string(),
+    address: Ipv4Addr::new(10, 0, 0, 1),
+    netmask: Ipv4Addr::new(255, 255, 255, 0),
+    mtu: Some(1500),
+    link_mtu: None,
+    up: true,
+    persist: false,
+    owner: None,
+    description: None,
+    gso: false,
   }
 }
 
 impl ClientConfig {
   pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    Self::from_file_with_profile(path, None)
+  }
+
+  pub fn from_file_with_profile<P: AsRef<Path>>(path: P, profile: Option<&str>) -> anyhow::Result<Self> {
     if !path.as_ref().exists() {
       anyhow::bail!("Configuration file not found: {}", path.as_ref().display());
     }
 
-    let contents = std::fs::read_to_string(path)?;
-    let config = serde_yml::from_str(&contents)?;
+    let contents = std::fs::read_to_string(&path)?;
+    let mut config = Self::parse(&contents, profile)?;
+    config.tun.name = instance::tun_name(&config.tun.name, path.as_ref(), profile);
+    Ok(config)
+  }
+
+  pub fn parse(contents: &str, profile: Option<&str>) -> anyhow::Result<Self> {
+    let config = match profile {
+      Some(profile) => serde_yml::from_value(profile::apply(serde_yml::from_str(contents)?, profile)?)?,
+      None => serde_yml::from_str(contents)?,
+    };
     Ok(config)
   }
 
+  /// Server to connect to, looked up through DNS with `discovery`. Keys and transforms from the
+  /// configuration take precedence over discovered ones.
+  pub async fn endpoint(&self) -> anyhow::Result<Endpoint> {
+    let configured = match (self.server_address, &self.server_host, self.server_port) {
+      (address, host, Some(port)) if address.is_some() || host.is_some() => Some(Endpoint {
+        address: address.unwrap_or(Ipv4Addr::UNSPECIFIED),
+        host: host.clone(),
+        port,
+        public_key: None,
+        transforms: Vec::new(),
+      }),
+      _ => None,
+    };
+
+    let mut endpoint = match (&self.discovery, configured) {
+      (Some(config), configured) => match (discovery::discover(config).await, configured) {
+        (Ok(endpoint), _) => endpoint,
+        (Err(e), Some(configured)) => {
+          warn!("Discovery under {} failed, using {}: {}", config.domain, configured.address, e);
+          configured
+        }
+        (Err(e), None) => anyhow::bail!("Discovery under {} failed: {}", config.domain, e),
+      },
+      (None, Some(configured)) => configured,
+      // Looked up with the rendezvous on every connect instead.
+      (None, None) if self.rendezvous.is_some() => Endpoint {
+        address: Ipv4Addr::UNSPECIFIED,
+        host: None,
+        port: 0,
+        public_key: None,
+        transforms: Vec::new(),
+      },
+      (None, None) => {
+        anyhow::bail!(
+          "server-address or server-host and server-port are required without discovery or rendezvous"
+        )
+      }
+    };
+
+    if let Some(ref key) = self.server_public_key {
+      endpoint.public_key = Some(handshake::parse_key(key)?);
+    }
+    if !self.transforms.is_empty() {
+      endpoint.transforms = self.transforms.clone();
+    }
+    Ok(endpoint)
+  }
+
   pub fn connect_timeout(&self) -> Duration {
     Duration::from_secs(self.connect_timeout_secs)
   }
 
-  pub fn tun_config(&self) -> tun::Configuration {
-    self.tun.to_tun_config()
+  /// Settings renegotiated with the server instead of reconnecting when they change; `transforms` are the
+  /// configured ones, which discovered ones are used instead of when empty.
+  pub fn session_params(&self) -> SessionParams {
+    SessionParams {
+      mtu: self.tun.mtu(),
+      keepalive_secs: self.keepalive_secs,
+      transforms: self.transforms.clone(),
+    }
+  }
+
+  pub fn tun_config(&self) -> anyhow::Result<tun::Configuration> {
+    self.tun.to_tun_config(Registry::default().overhead(&self.transforms)?)
   }
 }
 
@@ -114,11 +393,75 @@ mod tests {
 
     let config: ClientConfig = serde_yml::from_str(config_str).unwrap();
 
-    assert_eq!(config.server_port, 8000);
+    assert_eq!(config.server_port, Some(8000));
     assert_eq!(config.listen_port, 6969);
-    let creds = config.credentials;
+    let creds = config.credentials.unwrap();
 
     assert_eq!(creds, Credentials::from_str("test_user:test_password").unwrap());
+    assert!(config.routes.is_empty());
+  }
+
+  #[test]
+  fn test_oidc_config() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            oidc:
+              issuer: "https://idp.example.com/realms/corp"
+              client-id: "vpn"
+        "#;
+
+    let config: ClientConfig = serde_yml::from_str(config_str).unwrap();
+
+    assert!(config.credentials.is_none());
+    let oidc = config.oidc.unwrap();
+    assert_eq!(oidc.client_id, "vpn");
+    assert_eq!(oidc.scope, "openid profile");
+  }
+
+  #[test]
+  fn test_certificate_config() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            certificate:
+              path: "/etc/vpn/alice.crt"
+              private-key: "0101010101010101010101010101010101010101010101010101010101010101"
+        "#;
+
+    let config: ClientConfig = serde_yml::from_str(config_str).unwrap();
+
+    let certificate = config.certificate.unwrap();
+    assert_eq!(certificate.path, PathBuf::from("/etc/vpn/alice.crt"));
+    assert_eq!(certificate.private_key, [1; 32]);
+  }
+
+  #[test]
+  fn test_routes() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            credentials:
+              type: "password"
+              username: "test_user"
+              password: "test_password"
+            routes:
+              - "10.10.0.0/24"
+              - "192.168.100.0/22"
+        "#;
+
+    let config: ClientConfig = serde_yml::from_str(config_str).unwrap();
+
+    assert_eq!(config.routes, vec!["10.10.0.0/24".parse().unwrap(), "192.168.100.0/22".parse().unwrap()]);
   }
 
   #[test]
@@ -171,5 +514,228 @@ mod tests {
 
     assert_eq!(config.tun.mtu, None);
     assert!(config.tun.up);
+    assert!(!config.tun.persist);
+  }
+
+  #[test]
+  fn test_persistent_tun_config() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            credentials:
+              type: "password"
+              username: "test_user"
+              password: "test_password"
+            tun:
+              name: "corp0"
+              address: "192.168.1.1"
+              netmask: "255.255.255.0"
+              persist: true
+              owner: "vpnuser"
+              description: "corp vpn"
+        "#;
+
+    let config: ClientConfig = serde_yml::from_str(config_str).unwrap();
+
+    assert!(config.tun.persist);
+    assert_eq!(config.tun.owner.as_deref(), Some("vpnuser"));
+    assert_eq!(config.tun.description.as_deref(), Some("corp vpn"));
+  }
+
+  #[test]
+  fn test_profiles() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            routes: ["10.8.0.0/16"]
+            profiles:
+              exit-via-eu:
+                server-address: "203.0.113.5"
+                routes: ["0.0.0.0/1", "128.0.0.0/1"]
+        "#;
+
+    let config = ClientConfig::parse(config_str, None).unwrap();
+    assert_eq!(config.server_address, Some(Ipv4Addr::new(127, 0, 0, 1)));
+    assert!(config.profiles.contains_key("exit-via-eu"));
+
+    let config = ClientConfig::parse(config_str, Some("exit-via-eu")).unwrap();
+    assert_eq!(config.server_address, Some(Ipv4Addr::new(203, 0, 113, 5)));
+    assert_eq!(config.server_port, Some(8000));
+    assert_eq!(config.routes, vec!["0.0.0.0/1".parse().unwrap(), "128.0.0.0/1".parse().unwrap()]);
+    assert!(config.profiles.is_empty());
+  }
+
+  #[test]
+  fn test_lan_access() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            lan-access:
+              extra: ["172.16.5.0/24"]
+            profiles:
+              public-wifi:
+                lan-access:
+                  strict: true
+        "#;
+
+    let lan_access = ClientConfig::parse(config_str, None).unwrap().lan_access.unwrap();
+    assert!(!lan_access.strict);
+    assert_eq!(lan_access.extra, vec!["172.16.5.0/24".parse().unwrap()]);
+    assert!(ClientConfig::parse(config_str, Some("public-wifi")).unwrap().lan_access.unwrap().strict);
+  }
+
+  #[test]
+  fn test_outer() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            outer:
+              dont-fragment: true
+              ttl: 64
+        "#;
+
+    let config = ClientConfig::parse(config_str, None).unwrap();
+    assert_eq!(config.outer, OuterConfig { dont_fragment: Some(true), ttl: Some(64) });
+  }
+
+  #[test]
+  fn test_auto_connect() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            auto-connect:
+              trusted: ["Office-WiFi", "Home"]
+        "#;
+
+    let config: ClientConfig = serde_yml::from_str(config_str).unwrap();
+
+    let auto_connect = config.auto_connect.unwrap();
+    assert_eq!(auto_connect.trusted, vec!["Office-WiFi", "Home"]);
+    assert_eq!(auto_connect.check_interval_secs, 10);
+  }
+
+  #[test]
+  fn test_idle_suspend() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            idle-suspend:
+              after-secs: 300
+        "#;
+
+    let config = ClientConfig::parse(config_str, None).unwrap();
+    assert_eq!(config.idle_suspend, Some(IdleSuspendConfig { after_secs: 300, battery_only: true }));
+  }
+
+  #[tokio::test]
+  async fn test_endpoint() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            server-public-key: "0101010101010101010101010101010101010101010101010101010101010101"
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            transforms: ["pad"]
+        "#;
+
+    let endpoint = ClientConfig::parse(config_str, None).unwrap().endpoint().await.unwrap();
+    assert_eq!(
+      endpoint,
+      Endpoint {
+        address: Ipv4Addr::LOCALHOST,
+        host: None,
+        port: 8000,
+        public_key: Some([1; 32]),
+        transforms: vec!["pad".into()]
+      }
+    );
+
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            discovery:
+              domain: "example.com"
+              resolver: "10.8.0.1"
+        "#;
+
+    let config = ClientConfig::parse(config_str, None).unwrap();
+    let discovery = config.discovery.as_ref().unwrap();
+    assert_eq!(discovery.resolver, Some(Ipv4Addr::new(10, 8, 0, 1)));
+    assert_eq!(discovery.timeout_secs, 5);
+
+    let config_str = r#"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+        "#;
+    assert!(ClientConfig::parse(config_str, None).unwrap().endpoint().await.is_err());
+
+    let config_str =
+      config_str.replace("server-port", "server-host: \"vpn.example.com\"\n            server-port");
+    let config = ClientConfig::parse(&config_str, None).unwrap();
+    let endpoint = config.endpoint().await.unwrap();
+    assert_eq!(
+      (endpoint.address, endpoint.host.as_deref()),
+      (Ipv4Addr::UNSPECIFIED, Some("vpn.example.com"))
+    );
+  }
+
+  #[test]
+  fn test_session_params() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            transforms: ["pad"]
+        "#;
+
+    let config = ClientConfig::parse(config_str, None).unwrap();
+    let params = SessionParams { mtu: 1500, keepalive_secs: 5, transforms: vec!["pad".into()] };
+    assert_eq!(config.session_params(), params);
+
+    let config = ClientConfig::parse(
+      &config_str.replace("transforms", "keepalive-secs: 25\n            transforms"),
+      None,
+    )
+    .unwrap();
+    assert_eq!(config.session_params().keepalive_secs, 25);
+  }
+
+  #[test]
+  fn test_bandwidth_limits() {
+    let config_str = r#"
+            server-address: "127.0.0.1"
+            server-port: 8000
+            listen-address: "0.0.0.0"
+            listen-port: 6969
+            connect-timeout-secs: 10
+            max-upload-kbps: 512
+        "#;
+
+    let config = ClientConfig::parse(config_str, None).unwrap();
+    assert_eq!((config.max_upload_kbps, config.max_download_kbps), (Some(512), None));
   }
 }
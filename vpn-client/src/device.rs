@@ -0,0 +1,111 @@
+use std::io;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tun::AbstractDevice;
+use tun::AsyncDevice;
+
+use tracing::trace;
+use vpn_shared::gso;
+use vpn_shared::gso::GsoReader;
+use vpn_shared::logging;
+
+/// Packets waiting in either direction of a packet pipe, the counterpart of a tun device's queue.
+const PIPE_DEPTH: usize = 256;
+
+/// Sends packets into the tunnel of a client built `with_packet_pipe`, see `Client::handle`.
+#[derive(Clone)]
+pub struct ClientHandle {
+  outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+impl ClientHandle {
+  /// Sends an IPv4 packet to the server, waiting while the client is behind with earlier ones. Packets sent
+  /// while there's no session are dropped, as a tun device would.
+  pub async fn send_ip_packet(&self, packet: Vec<u8>) -> anyhow::Result<()> {
+    self.outgoing.send(packet).await.map_err(|_| anyhow::anyhow!("Client stopped"))
+  }
+}
+
+/// Where the client reads the packets it tunnels from and writes the ones it receives to.
+pub(crate) enum Device {
+  Tun {
+    tun: AsyncDevice,
+    /// Splits what the device reads when it was made for segmentation offload, see `TunConfig::gso`.
+    gso: Option<GsoReader>,
+  },
+  /// Packets exchanged with the application rather than the system.
+  Pipe {
+    handle: ClientHandle,
+    outgoing: mpsc::Receiver<Vec<u8>>,
+    incoming: mpsc::Sender<Vec<u8>>,
+    /// Taken by the application with `Client::incoming_packets`.
+    receiver: Option<mpsc::Receiver<Vec<u8>>>,
+  },
+}
+
+impl Device {
+  pub fn pipe() -> Self {
+    let (outgoing_tx, outgoing) = mpsc::channel(PIPE_DEPTH);
+    let (incoming, receiver) = mpsc::channel(PIPE_DEPTH);
+    Self::Pipe {
+      handle: ClientHandle { outgoing: outgoing_tx },
+      outgoing,
+      incoming,
+      receiver: Some(receiver),
+    }
+  }
+
+  pub fn tun(&mut self) -> Option<&mut AsyncDevice> {
+    match self {
+      Self::Tun { tun, .. } => Some(tun),
+      Self::Pipe { .. } => None,
+    }
+  }
+
+  /// MTU the tun device was made with.
+  pub fn tun_mtu(&self) -> anyhow::Result<u16> {
+    match self {
+      Self::Tun { tun, .. } => Ok(tun.mtu()?),
+      Self::Pipe { .. } => anyhow::bail!("A client with a packet pipe has no tun device"),
+    }
+  }
+
+  /// Name of the tun device, for the system configuration that goes with one.
+  pub fn tun_name(&self) -> anyhow::Result<String> {
+    match self {
+      Self::Tun { tun, .. } => Ok(tun.tun_name()?),
+      Self::Pipe { .. } => anyhow::bail!("A client with a packet pipe has no tun device"),
+    }
+  }
+
+  pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Self::Tun { tun, gso: None } => tun.read(buf).await,
+      Self::Tun { tun, gso: Some(reader) } => reader.recv(tun, buf).await,
+      Self::Pipe { outgoing, .. } => loop {
+        // The device holds a handle itself, so the channel stays open.
+        let packet = outgoing.recv().await.expect("handle is kept");
+        if packet.len() > buf.len() {
+          trace!(target: logging::TUN, "Dropping packet larger than the MTU from the application; len: {}", packet.len());
+          continue;
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        return Ok(packet.len());
+      },
+    }
+  }
+
+  pub async fn write(&mut self, packet: &[u8]) -> io::Result<()> {
+    match self {
+      Self::Tun { tun, gso: None } => tun.write(packet).await.map(drop),
+      Self::Tun { tun, gso: Some(_) } => gso::send(tun, packet).await.map(drop),
+      // Dropped when the application is behind or no longer listening, like a full tun queue would.
+      Self::Pipe { incoming, .. } => {
+        _ = incoming.try_send(packet.to_vec());
+        Ok(())
+      }
+    }
+  }
+}
@@ -0,0 +1,331 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::dns;
+use vpn_shared::dns::Message;
+use vpn_shared::dns::Record;
+use vpn_shared::dns::RecordData;
+use vpn_shared::handshake;
+use vpn_shared::packet::fill_random_bytes;
+use vpn_shared::packet::Key;
+
+use crate::leaktest::parse_nameservers;
+
+/// Prefix of the SRV and TXT records under the configured domain.
+pub const SERVICE: &str = "_sberlinux-vpn._udp";
+
+const DNS_PORT: u16 = 53;
+
+/// Service type servers with `mdns` advertise on the local network.
+pub const LAN_SERVICE: &str = "_sberlinux-vpn._udp.local";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Looks the server up under `domain` on every connect, so it can move without clients being
+/// reconfigured: SRV `_sberlinux-vpn._udp.<domain>` names the host and port, and an optional TXT record
+/// of the same name carries `key=<hex>` and `transforms=<a,b>`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiscoveryConfig {
+  pub domain: String,
+
+  /// Defaults to the first resolver of /etc/resolv.conf.
+  #[serde(default)]
+  pub resolver: Option<Ipv4Addr>,
+
+  #[serde(default = "default_timeout_secs")]
+  pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+  5
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+  /// Unspecified when the server is only known by `host`.
+  pub address: Ipv4Addr,
+  /// Hostname looked up on every connect, see `ClientBuilder::with_server_host`.
+  pub host: Option<String>,
+  pub port: u16,
+  pub public_key: Option<Key>,
+  pub transforms: Vec<String>,
+}
+
+/// Server that answered `browse`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanServer {
+  pub name: String,
+  pub address: Ipv4Addr,
+  pub port: u16,
+  pub public_key: Option<Key>,
+}
+
+/// Hints of the TXT record; unknown keys are skipped so records can grow.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Hints {
+  public_key: Option<Key>,
+  transforms: Vec<String>,
+}
+
+pub async fn discover(config: &DiscoveryConfig) -> anyhow::Result<Endpoint> {
+  let resolver = match config.resolver {
+    Some(resolver) => resolver,
+    None => *parse_nameservers(&std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default())
+      .first()
+      .ok_or(anyhow::anyhow!("No resolver in /etc/resolv.conf; set discovery.resolver"))?,
+  };
+  let resolver = Resolver { address: resolver, timeout: Duration::from_secs(config.timeout_secs) };
+  let name = format!("{}.{}", SERVICE, config.domain.trim_end_matches('.'));
+
+  let srv = resolver.query(&name, dns::TYPE_SRV).await?;
+  let (port, target) = best_target(&srv).ok_or(anyhow::anyhow!("No SRV record for {}", name))?;
+  let address = match address_of(&srv, &target) {
+    Some(address) => address,
+    None => address_of(&resolver.query(&target, dns::TYPE_A).await?, &target)
+      .ok_or(anyhow::anyhow!("No A record for {}", target))?,
+  };
+
+  let hints = parse_hints(resolver.query(&name, dns::TYPE_TXT).await?.answers.iter())?;
+  info!("Discovered {}:{} ({}) through {}", address, port, target, name);
+  Ok(Endpoint { address, host: None, port, public_key: hints.public_key, transforms: hints.transforms })
+}
+
+/// Asks servers on the local network to announce themselves through mDNS and collects the answers that
+/// arrive within `timeout`. The query is sent from an ephemeral port, so responders answer it directly.
+pub async fn browse(timeout: Duration) -> anyhow::Result<Vec<LanServer>> {
+  let mut id = [0; 2];
+  fill_random_bytes(&mut id);
+  let id = u16::from_be_bytes(id);
+
+  let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+  socket.set_multicast_ttl_v4(255)?;
+  socket.send_to(&dns::query(id, LAN_SERVICE, dns::TYPE_PTR)?, (MDNS_GROUP, MDNS_PORT)).await?;
+
+  let deadline = tokio::time::Instant::now() + timeout;
+  let mut servers: Vec<LanServer> = Vec::new();
+  let mut buf = vec![0; 9000];
+  while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+    let (len, src) = received?;
+    let Ok(message) = Message::parse(&buf[..len]) else {
+      continue;
+    };
+    if !message.response || message.id != id {
+      continue;
+    }
+    for server in lan_servers(&message, src.ip()) {
+      if !servers.iter().any(|known| known.name == server.name) {
+        servers.push(server);
+      }
+    }
+  }
+
+  servers.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(servers)
+}
+
+/// Servers announced in an mDNS response from `source`, which is their address when no A record came along.
+fn lan_servers(message: &Message, source: IpAddr) -> Vec<LanServer> {
+  let mut servers = Vec::new();
+  for record in message.answers.iter().filter(|record| record.name.eq_ignore_ascii_case(LAN_SERVICE)) {
+    let RecordData::Ptr(ref instance) = record.data else {
+      continue;
+    };
+    let Some((port, host)) = message.records().find_map(|record| match &record.data {
+      RecordData::Srv { port, target, .. } if record.name.eq_ignore_ascii_case(instance) => {
+        Some((*port, target.clone()))
+      }
+      _ => None,
+    }) else {
+      continue;
+    };
+    let address = match (address_of(message, &host), source) {
+      (Some(address), _) => address,
+      (None, IpAddr::V4(source)) => source,
+      (None, IpAddr::V6(_)) => continue,
+    };
+    let hints =
+      match parse_hints(message.records().filter(|record| record.name.eq_ignore_ascii_case(instance))) {
+        Ok(hints) => hints,
+        Err(e) => {
+          warn!("Skipping {} announced by {}: {}", instance, source, e);
+          continue;
+        }
+      };
+
+    let name = instance.strip_suffix(&format!(".{}", LAN_SERVICE)).unwrap_or(instance).to_string();
+    servers.push(LanServer { name, address, port, public_key: hints.public_key });
+  }
+  servers
+}
+
+struct Resolver {
+  address: Ipv4Addr,
+  timeout: Duration,
+}
+
+impl Resolver {
+  async fn query(&self, name: &str, record_type: u16) -> anyhow::Result<Message> {
+    let mut id = [0; 2];
+    fill_random_bytes(&mut id);
+    let id = u16::from_be_bytes(id);
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((self.address, DNS_PORT)).await?;
+    socket.send(&dns::query(id, name, record_type)?).await?;
+
+    let mut buf = vec![0; 4096];
+    let message = tokio::time::timeout(self.timeout, async {
+      loop {
+        let len = socket.recv(&mut buf).await?;
+        match Message::parse(&buf[..len]) {
+          Ok(message) if message.id == id => return anyhow::Ok(message),
+          _ => continue,
+        }
+      }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("{} didn't answer for {}", self.address, name))??;
+
+    match message.rcode {
+      0 if message.truncated => anyhow::bail!("Answer for {} doesn't fit a datagram", name),
+      // NXDOMAIN is an empty answer; a missing TXT record isn't an error.
+      0 | 3 => Ok(message),
+      rcode => anyhow::bail!("{} answered {} for {}", self.address, rcode, name),
+    }
+  }
+}
+
+/// Port and host of the SRV record with the lowest priority and, among those, the largest weight. A
+/// target of `.` means the service is deliberately unavailable.
+fn best_target(message: &Message) -> Option<(u16, String)> {
+  message
+    .answers
+    .iter()
+    .filter_map(|record| match &record.data {
+      RecordData::Srv { priority, weight, port, target } if !target.is_empty() => {
+        Some((*priority, u16::MAX - weight, *port, target.clone()))
+      }
+      _ => None,
+    })
+    .min()
+    .map(|(_, _, port, target)| (port, target))
+}
+
+fn address_of(message: &Message, host: &str) -> Option<Ipv4Addr> {
+  message.records().find_map(|record| match record.data {
+    RecordData::A(address) if record.name.eq_ignore_ascii_case(host) => Some(address),
+    _ => None,
+  })
+}
+
+fn parse_hints<'a>(records: impl Iterator<Item = &'a Record>) -> anyhow::Result<Hints> {
+  let mut hints = Hints::default();
+  let strings = records.filter_map(|record| match &record.data {
+    RecordData::Txt(strings) => Some(strings),
+    _ => None,
+  });
+
+  for pair in strings.flatten().flat_map(|string| string.split_whitespace()) {
+    match pair.split_once('=') {
+      Some(("key", key)) => hints.public_key = Some(handshake::parse_key(key)?),
+      Some(("transforms", transforms)) => {
+        hints.transforms = transforms.split(',').filter(|t| !t.is_empty()).map(String::from).collect()
+      }
+      _ => {}
+    }
+  }
+  Ok(hints)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(answers: Vec<RecordData>, additional: Vec<Record>) -> Message {
+    let answers = answers.into_iter().map(|data| Record {
+      name: "_sberlinux-vpn._udp.example.com".into(),
+      ttl: 60,
+      data,
+    });
+    Message { answers: answers.collect(), additional, ..Default::default() }
+  }
+
+  fn srv(priority: u16, weight: u16, port: u16, target: &str) -> RecordData {
+    RecordData::Srv { priority, weight, port, target: target.into() }
+  }
+
+  #[test]
+  fn test_best_target() {
+    let answer = message(
+      vec![
+        srv(20, 100, 1, "backup.example.com"),
+        srv(10, 1, 2, "b.example.com"),
+        srv(10, 50, 3, "a.example.com"),
+      ],
+      vec![Record {
+        name: "a.example.com".into(),
+        ttl: 60,
+        data: RecordData::A(Ipv4Addr::new(203, 0, 113, 5)),
+      }],
+    );
+    assert_eq!(best_target(&answer), Some((3, "a.example.com".into())));
+    assert_eq!(address_of(&answer, "a.example.com"), Some(Ipv4Addr::new(203, 0, 113, 5)));
+    assert_eq!(address_of(&answer, "b.example.com"), None);
+
+    assert_eq!(best_target(&message(vec![srv(0, 0, 0, "")], vec![])), None);
+  }
+
+  #[test]
+  fn test_parse_hints() {
+    let key = "01".repeat(32);
+    let txt =
+      message(vec![RecordData::Txt(vec![format!("key={} transforms=pad,xor", key), "v=1".into()])], vec![]);
+    let hints = parse_hints(txt.answers.iter()).unwrap();
+    assert_eq!(hints.public_key, Some([1; 32]));
+    assert_eq!(hints.transforms, vec!["pad", "xor"]);
+
+    assert_eq!(parse_hints(message(vec![], vec![]).answers.iter()).unwrap(), Hints::default());
+    assert!(
+      parse_hints(message(vec![RecordData::Txt(vec!["key=zz".into()])], vec![]).answers.iter()).is_err()
+    );
+  }
+
+  #[test]
+  fn test_lan_servers() {
+    let record = |name: &str, data| Record { name: name.into(), ttl: 120, data };
+    let instance = format!("Lab.{}", LAN_SERVICE);
+    let message = Message {
+      response: true,
+      answers: vec![record(LAN_SERVICE, RecordData::Ptr(instance.clone()))],
+      additional: vec![
+        record(&instance, srv(0, 0, 9696, "gw.local")),
+        record(&instance, RecordData::Txt(vec![format!("key={}", "01".repeat(32))])),
+      ],
+      ..Default::default()
+    };
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+
+    let expected = LanServer {
+      name: "Lab".into(),
+      address: Ipv4Addr::new(192, 168, 1, 10),
+      port: 9696,
+      public_key: Some([1; 32]),
+    };
+    assert_eq!(lan_servers(&message, source), [expected]);
+
+    let mut with_address = message.clone();
+    with_address.additional.push(record("GW.local", RecordData::A(Ipv4Addr::new(10, 0, 0, 2))));
+    assert_eq!(lan_servers(&with_address, source)[0].address, Ipv4Addr::new(10, 0, 0, 2));
+
+    let mut without_srv = message;
+    without_srv.additional.remove(0);
+    assert!(lan_servers(&without_srv, source).is_empty());
+  }
+}
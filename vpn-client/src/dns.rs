@@ -0,0 +1,29 @@
+use std::net::Ipv4Addr;
+
+use tokio::process::Command;
+
+/// Makes `servers` the resolvers of the tun through systemd-resolved, routing every query to them; the
+/// settings go away with the device.
+pub async fn apply(dev: &str, servers: &[Ipv4Addr]) -> anyhow::Result<()> {
+  let servers: Vec<String> = servers.iter().map(ToString::to_string).collect();
+  resolvectl(&[&["dns", dev], servers.iter().map(String::as_str).collect::<Vec<_>>().as_slice()].concat())
+    .await?;
+  resolvectl(&["domain", dev, "~."]).await
+}
+
+/// Drops the resolvers `apply` set on the tun, for a device that outlived the client.
+pub async fn revert(dev: &str) -> anyhow::Result<()> {
+  resolvectl(&["revert", dev]).await
+}
+
+async fn resolvectl(args: &[&str]) -> anyhow::Result<()> {
+  let output = Command::new("resolvectl").args(args).output().await?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "`resolvectl {}` failed: {}",
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(())
+}
@@ -0,0 +1,116 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use ipnet::Ipv4Net;
+use vpn_shared::packet::ErrorCode;
+use vpn_shared::packet::Notice;
+use vpn_shared::packet::ReverseForward;
+use vpn_shared::packet::SessionParams;
+
+use crate::portmap::Method;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+  Connected,
+  /// The server ended the session; `code` is `None` when the client gave up on it, e.g. the server stopped
+  /// answering.
+  Disconnected {
+    code: Option<ErrorCode>,
+    reason: String,
+  },
+  Reconnecting {
+    attempt: u32,
+    delay: Duration,
+    reason: String,
+  },
+  /// The gateway forwards `external` to the client's listen port; sent again whenever that changes.
+  PortMapped {
+    method: Method,
+    external: SocketAddrV4,
+  },
+  PortMappingFailed {
+    reason: String,
+  },
+  /// The server refused the client for a reason retrying can't fix; `run` returns after this.
+  Stopped {
+    code: ErrorCode,
+    reason: String,
+  },
+  /// Heads-up from the server, e.g. that the data quota is running out.
+  Notice(Notice),
+  /// The system clock is `offset_secs` ahead of the server's, behind when negative, by enough for
+  /// time-based tokens and one-time passwords to be refused, see `vpn_shared::protocol::CLOCK_SKEW_WARNING`.
+  ClockSkew {
+    offset_secs: i64,
+  },
+  /// Usage pushed by servers with `stats-interval-secs`.
+  Stats {
+    sent: u64,
+    received: u64,
+    quota_remaining: Option<u64>,
+    clients: u32,
+    max_clients: u32,
+  },
+  /// The server agreed to settings sent to `ClientBuilder::with_session_params`, which are in use now.
+  Renegotiated(SessionParams),
+  /// Large packets kept getting lost, so the MTU is being lowered from `from` to `to`; `Renegotiated`
+  /// follows once the server agrees.
+  MtuReduced {
+    from: u16,
+    to: u16,
+    reason: String,
+  },
+  /// The server routes `accepted` of the subnets sent to `ClientBuilder::with_subnets` to the client, and
+  /// refused `rejected`, e.g. because the user may not route them.
+  Subnets {
+    accepted: Vec<Ipv4Net>,
+    rejected: Vec<Ipv4Net>,
+  },
+  /// The server forwards the `accepted` ports of `ClientBuilder::with_reverse_forwards` to the client, and
+  /// refused `rejected`, e.g. because the user may not have them.
+  Forwards {
+    accepted: Vec<ReverseForward>,
+    rejected: Vec<ReverseForward>,
+  },
+  /// Other clients reach this one as `fqdn` through the server's DNS, or the server refused the name sent
+  /// to `ClientBuilder::with_hostname` when it's `None`.
+  Hostname {
+    name: String,
+    fqdn: Option<String>,
+  },
+  /// The client at `peer` offered a new pairwise key in place of the one agreed on, which the client keeps
+  /// for the session: the peer restarted, or the server tried to swap the key. Traffic with the peer stays
+  /// sealed with the old key until the client reconnects.
+  PeerKeyRefused {
+    peer: Ipv4Addr,
+  },
+  /// The server advertised `routes` behind other sites, which replace the ones it advertised before and
+  /// are routed into the tunnel.
+  Routes {
+    routes: Vec<Ipv4Net>,
+  },
+  /// The server leased `network` to the session, along with its resolvers.
+  NetworkConfig {
+    network: Ipv4Net,
+    dns: Vec<Ipv4Addr>,
+  },
+  /// A VPN route was removed or replaced by something else on the system and has been re-installed.
+  RouteRepaired {
+    route: Ipv4Net,
+  },
+  /// The tun device was deleted from outside, e.g. with `ip link del`; `TunRecreated` follows, or `run`
+  /// returns if it can't be made again.
+  TunRemoved {
+    name: String,
+  },
+  /// The removed tun device was made again with its address and routes.
+  TunRecreated {
+    name: String,
+  },
+  /// The tunnel was idle for `ClientBuilder::with_idle_suspend`, so its routes were taken down; the
+  /// session stays up, and `Resumed` follows with the next packet through it.
+  Suspended,
+  /// The routes taken down with `Suspended` are back.
+  Resumed,
+}
@@ -0,0 +1,88 @@
+//! Happy Eyeballs (RFC 8305) for servers given by hostname: the key exchange is started with each address
+//! in turn, IPv6 first and families alternating, the next one after `ATTEMPT_DELAY` without an answer. The
+//! first server to answer wins, so a network with broken IPv6 costs a quarter of a second instead of a
+//! handshake timeout.
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::resolver::Resolver;
+
+/// Head start of each attempt over the next, the Connection Attempt Delay of RFC 8305.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Addresses of `host` through `resolver`, in the order to try them; an address literal is taken as is.
+pub async fn resolve(resolver: &dyn Resolver, host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+  let addresses = match host.parse::<IpAddr>() {
+    Ok(address) => vec![address],
+    Err(_) => resolver.lookup(host).await?,
+  };
+  if addresses.is_empty() {
+    anyhow::bail!("{} has no addresses", host);
+  }
+  Ok(interleave(addresses.into_iter().map(|address| SocketAddr::new(address, port)).collect()))
+}
+
+/// Orders `addresses` for the attempts: alternating between the families starting with IPv6, each family
+/// keeping the resolver's order, duplicates dropped.
+pub fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+  let mut unique = Vec::new();
+  for address in addresses {
+    if !unique.contains(&address) {
+      unique.push(address);
+    }
+  }
+  let (mut v6, mut v4): (Vec<_>, Vec<_>) = unique.into_iter().partition(SocketAddr::is_ipv6);
+  v6.reverse();
+  v4.reverse();
+
+  let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+  loop {
+    match (v6.pop(), v4.pop()) {
+      (None, None) => return ordered,
+      (a, b) => ordered.extend(a.into_iter().chain(b)),
+    }
+  }
+}
+
+/// Moves `winner` to the front, so that the address that answered last time gets the head start.
+pub fn prefer(addresses: &mut [SocketAddr], winner: SocketAddr) {
+  if let Some(position) = addresses.iter().position(|address| *address == winner) {
+    addresses[..=position].rotate_right(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::resolver::StaticHosts;
+
+  fn addrs(addresses: &[&str]) -> Vec<SocketAddr> {
+    addresses.iter().map(|address| address.parse().unwrap()).collect()
+  }
+
+  #[test]
+  fn test_interleave() {
+    let resolved = addrs(&["192.0.2.1:9696", "192.0.2.2:9696", "[2001:db8::1]:9696", "192.0.2.1:9696"]);
+    assert_eq!(interleave(resolved), addrs(&["[2001:db8::1]:9696", "192.0.2.1:9696", "192.0.2.2:9696"]));
+    assert_eq!(interleave(addrs(&["192.0.2.1:9696"])), addrs(&["192.0.2.1:9696"]));
+    assert!(interleave(Vec::new()).is_empty());
+  }
+
+  #[test]
+  fn test_prefer() {
+    let mut addresses = addrs(&["[2001:db8::1]:9696", "192.0.2.1:9696", "[2001:db8::2]:9696"]);
+    prefer(&mut addresses, "192.0.2.1:9696".parse().unwrap());
+    assert_eq!(addresses, addrs(&["192.0.2.1:9696", "[2001:db8::1]:9696", "[2001:db8::2]:9696"]));
+    prefer(&mut addresses, "192.0.2.9:9696".parse().unwrap());
+    assert_eq!(addresses[0], "192.0.2.1:9696".parse().unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_resolve_literal() {
+    let resolver = StaticHosts::new(Default::default());
+    assert_eq!(resolve(&resolver, "127.0.0.1", 9696).await.unwrap(), addrs(&["127.0.0.1:9696"]));
+    assert!(resolve(&resolver, "vpn.example.com", 9696).await.is_err());
+  }
+}
@@ -0,0 +1,175 @@
+//! Fallback to TCP on networks that drop UDP: after `after-timeouts` key exchanges in a row that nothing
+//! answered, the client switches to the server's TCP port, and back to UDP if that goes unanswered as well.
+//! The transport that worked is remembered per network, told apart by its NetworkManager connections and
+//! SSIDs, so that reconnecting there starts over it right away.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::profile::write_private;
+use crate::trusted;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TcpFallbackConfig {
+  /// TCP port of the server, its `tcp-port`.
+  pub port: u16,
+
+  /// Unanswered key exchanges in a row before switching transports.
+  #[serde(default = "default_after_timeouts")]
+  pub after_timeouts: u32,
+
+  /// Where the transport of each network is kept across restarts; only in memory without it.
+  #[serde(default)]
+  pub state_file: Option<PathBuf>,
+}
+
+fn default_after_timeouts() -> u32 {
+  2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+  Udp,
+  Tcp,
+}
+
+impl fmt::Display for Transport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Udp => "UDP",
+      Self::Tcp => "TCP",
+    })
+  }
+}
+
+/// Transport the key exchange goes over, and when to switch it.
+pub struct Fallback {
+  config: TcpFallbackConfig,
+  transport: Transport,
+  unanswered: u32,
+  /// Network the machine is on, unless it isn't known.
+  network: Option<String>,
+  known: BTreeMap<String, Transport>,
+}
+
+impl Fallback {
+  pub fn new(config: TcpFallbackConfig) -> Self {
+    let known = match config.state_file {
+      Some(ref path) => match std::fs::read_to_string(path) {
+        Ok(contents) => serde_yml::from_str(&contents).unwrap_or_else(|e| {
+          warn!("Ignoring the transports in {}: {}", path.display(), e);
+          BTreeMap::new()
+        }),
+        Err(_) => BTreeMap::new(),
+      },
+      None => BTreeMap::new(),
+    };
+    Self { config, transport: Transport::Udp, unanswered: 0, network: None, known }
+  }
+
+  pub fn transport(&self) -> Transport {
+    self.transport
+  }
+
+  pub fn port(&self) -> u16 {
+    self.config.port
+  }
+
+  /// Follows the machine to another network, starting over the transport that worked there last, or UDP.
+  /// Without NetworkManager the network never changes, so the transport is only kept while the client runs.
+  pub async fn follow_network(&mut self) {
+    let network = match trusted::active_networks().await {
+      Ok(networks) if !networks.is_empty() => Some(networks.join(", ")),
+      _ => None,
+    };
+    if network == self.network {
+      return;
+    }
+
+    let transport = network.as_ref().and_then(|network| self.known.get(network)).copied();
+    self.transport = transport.unwrap_or(Transport::Udp);
+    self.unanswered = 0;
+    if let (Some(Transport::Tcp), Some(ref network)) = (transport, &network) {
+      info!("Connecting over TCP, as on {} before", network);
+    }
+    self.network = network;
+  }
+
+  /// Counts a key exchange nothing answered, switching transports after enough of them; returns whether
+  /// it did.
+  pub fn unanswered(&mut self) -> bool {
+    self.unanswered += 1;
+    if self.unanswered < self.config.after_timeouts.max(1) {
+      return false;
+    }
+    self.unanswered = 0;
+    self.transport = match self.transport {
+      Transport::Udp => Transport::Tcp,
+      Transport::Tcp => Transport::Udp,
+    };
+    true
+  }
+
+  /// Remembers that the current transport works on this network.
+  pub fn answered(&mut self) {
+    self.unanswered = 0;
+    let Some(ref network) = self.network else {
+      return;
+    };
+    if self.known.insert(network.clone(), self.transport) == Some(self.transport) {
+      return;
+    }
+    let Some(ref path) = self.config.state_file else {
+      return;
+    };
+    let saved = serde_yml::to_string(&self.known)
+      .map_err(anyhow::Error::from)
+      .and_then(|contents| Ok(write_private(path, contents.as_bytes())?));
+    if let Err(e) = saved {
+      warn!("Failed to save the transports to {}: {}", path.display(), e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_switching() {
+    let config = TcpFallbackConfig { port: 443, after_timeouts: 2, state_file: None };
+    let mut fallback = Fallback::new(config);
+    assert!(!fallback.unanswered());
+    assert!(fallback.unanswered());
+    assert_eq!(fallback.transport(), Transport::Tcp);
+
+    // An answer starts the count over.
+    assert!(!fallback.unanswered());
+    fallback.answered();
+    assert!(!fallback.unanswered());
+    assert!(fallback.unanswered());
+    assert_eq!(fallback.transport(), Transport::Udp);
+  }
+
+  #[test]
+  fn test_remembered() {
+    let path = std::env::temp_dir().join(format!("vpn-transports-{}", std::process::id()));
+    let config = TcpFallbackConfig { port: 443, after_timeouts: 1, state_file: Some(path.clone()) };
+    let mut fallback = Fallback::new(config.clone());
+    fallback.network = Some("Cafe-WiFi".to_string());
+    assert!(fallback.unanswered());
+    fallback.answered();
+
+    let fallback = Fallback::new(config);
+    assert_eq!(fallback.known.get("Cafe-WiFi"), Some(&Transport::Tcp));
+    std::fs::remove_file(path).unwrap();
+  }
+}
@@ -0,0 +1,312 @@
+//! Local ports forwarded through the tunnel to fixed hosts, like `ssh -L`, for selective access without
+//! routes: connections to `listen` are relayed to `target` over sockets bound to the tun device, so they go
+//! through the tunnel whatever the routing table says. Listening on a LAN address shares the forward with
+//! other hosts. Reverse forwards, like `ssh -R`, go the other way: the server forwards a port of its own to
+//! the client, which accepts on the tun device and relays to a local target. Linux only.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio::net::TcpSocket;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::task::AbortHandle;
+use tokio::task::JoinSet;
+use tracing::debug;
+use tracing::info;
+use vpn_shared::diagnose;
+use vpn_shared::diagnose::Protocol;
+use vpn_shared::ip;
+use vpn_shared::packet::ReverseForward;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a UDP peer's relay lasts without a reply from the target; the next datagram opens a new one.
+const UDP_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardProtocol {
+  #[default]
+  Tcp,
+  Udp,
+}
+
+impl ForwardProtocol {
+  /// IP protocol number.
+  pub fn number(self) -> u8 {
+    match self {
+      Self::Tcp => ip::PROTO_TCP,
+      Self::Udp => ip::PROTO_UDP,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ForwardConfig {
+  #[serde(default)]
+  pub protocol: ForwardProtocol,
+
+  /// Local address to accept on, e.g. `127.0.0.1:2222`.
+  pub listen: SocketAddr,
+
+  /// Host behind the tunnel the traffic goes to.
+  pub target: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReverseForwardConfig {
+  #[serde(default)]
+  pub protocol: ForwardProtocol,
+
+  /// Port of the server asked for, which the client accepts on through the tunnel as well.
+  pub port: u16,
+
+  /// Local service the traffic goes to, e.g. `127.0.0.1:80`.
+  pub target: SocketAddr,
+}
+
+impl ReverseForwardConfig {
+  pub fn request(&self) -> ReverseForward {
+    ReverseForward { protocol: self.protocol.number(), port: self.port }
+  }
+}
+
+/// Relays `config.listen` to its target through `device` for as long as the task runs; `None` connects
+/// through whatever the routes say.
+pub async fn serve(config: ForwardConfig, device: Option<String>) -> anyhow::Result<()> {
+  if device.is_some() && !cfg!(target_os = "linux") {
+    anyhow::bail!("Port forwards are only supported on Linux");
+  }
+  let target = SocketAddr::V4(config.target);
+  match config.protocol {
+    ForwardProtocol::Tcp => {
+      let listener = TcpListener::bind(config.listen)
+        .await
+        .map_err(|e| diagnose::bind_error(Protocol::Tcp, config.listen, e))?;
+      info!("Forwarding TCP {} to {} through the tunnel", config.listen, config.target);
+      relay_tcp(listener, target, device).await
+    }
+    ForwardProtocol::Udp => {
+      let listener = UdpSocket::bind(config.listen)
+        .await
+        .map_err(|e| diagnose::bind_error(Protocol::Udp, config.listen, e))?;
+      info!("Forwarding UDP {} to {} through the tunnel", config.listen, config.target);
+      relay_udp(listener, target, device).await
+    }
+  }
+}
+
+/// Accepts on `config.port` of `device`, i.e. what the server forwards to the client's address, and relays
+/// to the local target for as long as the task runs; `None` accepts on every interface.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub async fn serve_reverse(config: ReverseForwardConfig, device: Option<String>) -> anyhow::Result<()> {
+  if device.is_some() && !cfg!(target_os = "linux") {
+    anyhow::bail!("Port forwards are only supported on Linux");
+  }
+  let listen = SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port));
+  match config.protocol {
+    ForwardProtocol::Tcp => {
+      let socket = TcpSocket::new_v4()?;
+      #[cfg(target_os = "linux")]
+      if let Some(ref device) = device {
+        socket.bind_device(Some(device.as_bytes()))?;
+      }
+      socket.set_reuseaddr(true)?;
+      socket.bind(listen).map_err(|e| diagnose::bind_error(Protocol::Tcp, listen, e))?;
+      let listener = socket.listen(1024)?;
+      info!("Forwarding TCP port {} of the server to {}", config.port, config.target);
+      relay_tcp(listener, config.target, None).await
+    }
+    ForwardProtocol::Udp => {
+      let listener =
+        UdpSocket::bind(listen).await.map_err(|e| diagnose::bind_error(Protocol::Udp, listen, e))?;
+      #[cfg(target_os = "linux")]
+      if let Some(ref device) = device {
+        listener.bind_device(Some(device.as_bytes()))?;
+      }
+      info!("Forwarding UDP port {} of the server to {}", config.port, config.target);
+      relay_udp(listener, config.target, None).await
+    }
+  }
+}
+
+/// Relays connections accepted by `listener` to `target` through `device`.
+async fn relay_tcp(listener: TcpListener, target: SocketAddr, device: Option<String>) -> anyhow::Result<()> {
+  // Dropped along with the task, which closes the connections.
+  let mut connections = JoinSet::new();
+  loop {
+    let (mut inbound, peer) = listener.accept().await?;
+    while connections.try_join_next().is_some() {}
+    let device = device.clone();
+    connections.spawn(async move {
+      let mut outbound = match connect_tcp(target, device.as_deref()).await {
+        Ok(outbound) => outbound,
+        Err(e) => {
+          debug!("Failed to forward {} to {}: {}", peer, target, e);
+          return;
+        }
+      };
+      match tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await {
+        Ok((sent, received)) => {
+          debug!("Forwarded {} to {}: {} bytes sent, {} received", peer, target, sent, received)
+        }
+        Err(e) => debug!("Forwarding {} to {} ended: {}", peer, target, e),
+      }
+    });
+  }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+async fn connect_tcp(target: SocketAddr, device: Option<&str>) -> anyhow::Result<TcpStream> {
+  let socket = match target {
+    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+  };
+  #[cfg(target_os = "linux")]
+  if let Some(device) = device {
+    socket.bind_device(Some(device.as_bytes()))?;
+  }
+  Ok(tokio::time::timeout(CONNECT_TIMEOUT, socket.connect(target)).await??)
+}
+
+/// Relay of one UDP peer: datagrams from it go out of `socket`, and replies come back to it.
+struct Relay {
+  socket: Arc<UdpSocket>,
+  replies: AbortHandle,
+}
+
+impl Drop for Relay {
+  fn drop(&mut self) {
+    self.replies.abort();
+  }
+}
+
+/// Relays the datagrams `listener` receives to `target` through `device`, and the replies back.
+async fn relay_udp(listener: UdpSocket, target: SocketAddr, device: Option<String>) -> anyhow::Result<()> {
+  let listener = Arc::new(listener);
+
+  let mut relays: HashMap<SocketAddr, Relay> = HashMap::new();
+  let mut buf = vec![0u8; u16::MAX as usize];
+  loop {
+    let (len, peer) = listener.recv_from(&mut buf).await?;
+    relays.retain(|_, relay| !relay.replies.is_finished());
+    let socket = match relays.get(&peer) {
+      Some(relay) => Arc::clone(&relay.socket),
+      None => {
+        let socket = match open_udp(target, device.as_deref()).await {
+          Ok(socket) => Arc::new(socket),
+          Err(e) => {
+            debug!("Failed to forward {} to {}: {}", peer, target, e);
+            continue;
+          }
+        };
+        let replies = tokio::spawn(relay_replies(Arc::clone(&socket), Arc::clone(&listener), peer));
+        relays.insert(peer, Relay { socket: Arc::clone(&socket), replies: replies.abort_handle() });
+        socket
+      }
+    };
+    if let Err(e) = socket.send(&buf[..len]).await {
+      debug!("Failed to forward a datagram from {} to {}: {}", peer, target, e);
+    }
+  }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+async fn open_udp(target: SocketAddr, device: Option<&str>) -> anyhow::Result<UdpSocket> {
+  let socket = match target {
+    SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+    SocketAddr::V6(_) => UdpSocket::bind((std::net::Ipv6Addr::UNSPECIFIED, 0)).await?,
+  };
+  #[cfg(target_os = "linux")]
+  if let Some(device) = device {
+    socket.bind_device(Some(device.as_bytes()))?;
+  }
+  socket.connect(target).await?;
+  Ok(socket)
+}
+
+async fn relay_replies(socket: Arc<UdpSocket>, listener: Arc<UdpSocket>, peer: SocketAddr) {
+  let mut buf = vec![0u8; u16::MAX as usize];
+  while let Ok(Ok(len)) = tokio::time::timeout(UDP_IDLE, socket.recv(&mut buf)).await {
+    if let Err(e) = listener.send_to(&buf[..len], peer).await {
+      debug!("Failed to relay a reply to {}: {}", peer, e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::AsyncReadExt;
+  use tokio::io::AsyncWriteExt;
+
+  use super::*;
+
+  async fn local(protocol: ForwardProtocol, target: SocketAddr) -> SocketAddr {
+    // A free port from the system, bound again right away.
+    let listen = match protocol {
+      ForwardProtocol::Tcp => std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap(),
+      ForwardProtocol::Udp => std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap(),
+    };
+    let SocketAddr::V4(target) = target else { unreachable!() };
+    tokio::spawn(serve(ForwardConfig { protocol, listen, target }, None));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    listen
+  }
+
+  #[tokio::test]
+  async fn test_tcp() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen = local(ForwardProtocol::Tcp, target.local_addr().unwrap()).await;
+
+    let mut client = TcpStream::connect(listen).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let (mut accepted, _) = target.accept().await.unwrap();
+    let mut buf = [0u8; 4];
+    accepted.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    accepted.write_all(b"pong").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+  }
+
+  #[tokio::test]
+  async fn test_udp() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let listen = local(ForwardProtocol::Udp, target.local_addr().unwrap()).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"ping", listen).await.unwrap();
+    let mut buf = [0u8; 16];
+    let (len, relay) = target.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    target.send_to(b"pong", relay).await.unwrap();
+    let (len, from) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!((&buf[..len], from), (&b"pong"[..], listen));
+  }
+
+  #[tokio::test]
+  async fn test_reverse() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = std::net::TcpListener::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let config =
+      ReverseForwardConfig { protocol: ForwardProtocol::Tcp, port, target: target.local_addr().unwrap() };
+    assert_eq!(config.request(), ReverseForward { protocol: ip::PROTO_TCP, port });
+    tokio::spawn(serve_reverse(config, None));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let (mut accepted, _) = target.accept().await.unwrap();
+    let mut buf = [0u8; 4];
+    accepted.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+  }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+
+use serde::Deserialize;
+use tracing::error;
+use tracing::info;
+
+use crate::killswitch;
+
+const NAME: &str = "sberlinux_vpn_gateway";
+
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Lets hosts of a LAN the client is attached to reach the VPN through it, e.g. a Raspberry Pi putting a
+/// whole home network behind the tunnel: forwarding is turned on and traffic from the LAN is let into the
+/// tunnel, masqueraded as the client unless the server routes the LAN itself (see
+/// `ClientBuilder::with_subnets`). Linux only.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct GatewayConfig {
+  /// Interface of the LAN, e.g. `eth0`.
+  pub lan_interface: String,
+
+  #[serde(default = "default_masquerade")]
+  pub masquerade: bool,
+}
+
+fn default_masquerade() -> bool {
+  true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rules {
+  pub lan: String,
+  pub tun: String,
+  pub masquerade: bool,
+}
+
+/// Enabled gateway mode; dropping it removes the rules and restores forwarding to how it was.
+pub struct Gateway {
+  /// `ip_forward` before the gateway turned it on.
+  forwarding: String,
+}
+
+impl Gateway {
+  pub fn enable(rules: &Rules) -> anyhow::Result<Self> {
+    if !cfg!(target_os = "linux") {
+      anyhow::bail!("Gateway mode is only supported on Linux");
+    }
+
+    let forwarding = fs::read_to_string(IP_FORWARD)?.trim().to_string();
+    fs::write(IP_FORWARD, "1")?;
+    let gateway = Self { forwarding };
+    killswitch::run("nft", &["-f", "-"], Some(&nft_ruleset(rules)))
+      .map_err(|e| anyhow::anyhow!("Failed to enable gateway mode: {}", e))?;
+    info!("Gateway mode enabled: {} is forwarded into {}", rules.lan, rules.tun);
+    Ok(gateway)
+  }
+
+  pub fn forwarding(&self) -> &str {
+    &self.forwarding
+  }
+}
+
+impl Drop for Gateway {
+  fn drop(&mut self) {
+    match disable(&self.forwarding) {
+      Ok(()) => info!("Gateway mode disabled"),
+      Err(e) => error!("{}", e),
+    }
+  }
+}
+
+/// Removes the rules of gateway mode and sets `ip_forward` back to `forwarding`.
+pub fn disable(forwarding: &str) -> anyhow::Result<()> {
+  // Missing when enabling failed halfway.
+  _ = killswitch::run("nft", &["delete", "table", "ip", NAME], None);
+  fs::write(IP_FORWARD, forwarding).map_err(|e| anyhow::anyhow!("Failed to restore {}: {}", IP_FORWARD, e))
+}
+
+fn nft_ruleset(rules: &Rules) -> String {
+  // Without masquerading the server routes the LAN to the client, so its side may open connections too.
+  let (inbound, masquerade) = match rules.masquerade {
+    true => {
+      ("ct state established,related accept\n    drop", format!("oifname \"{}\" masquerade", rules.tun))
+    }
+    false => ("accept", String::new()),
+  };
+
+  // Adding the table first makes deleting it succeed on a clean system too.
+  format!(
+    "add table ip {name}
+delete table ip {name}
+table ip {name} {{
+  chain forward {{
+    type filter hook forward priority 0; policy accept;
+    iifname \"{lan}\" oifname \"{tun}\" accept
+    iifname \"{tun}\" oifname \"{lan}\" {inbound}
+  }}
+  chain postrouting {{
+    type nat hook postrouting priority 100; policy accept;
+    {masquerade}
+  }}
+}}
+",
+    name = NAME,
+    lan = rules.lan,
+    tun = rules.tun,
+    inbound = inbound,
+    masquerade = masquerade,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_nft_ruleset() {
+    let mut rules = Rules { lan: "eth0".into(), tun: "tun0".into(), masquerade: true };
+    let ruleset = nft_ruleset(&rules);
+    assert!(ruleset.contains("iifname \"eth0\" oifname \"tun0\" accept"));
+    assert!(
+      ruleset.contains("iifname \"tun0\" oifname \"eth0\" ct state established,related accept\n    drop")
+    );
+    assert!(ruleset.contains("oifname \"tun0\" masquerade"));
+
+    rules.masquerade = false;
+    let ruleset = nft_ruleset(&rules);
+    assert!(ruleset.contains("iifname \"tun0\" oifname \"eth0\" accept"));
+    assert!(!ruleset.contains("masquerade"));
+  }
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+/// How often the power source is looked at again while idle on mains power.
+const BATTERY_RECHECK: Duration = Duration::from_secs(60);
+
+/// Takes the tunnel's routes down after a long idleness, keeping the session, and brings them back on the
+/// first packet either way, see `ClientPacket::Suspended`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct IdleSuspendConfig {
+  /// Time without tunneled packets in either direction before suspending.
+  #[serde(default = "default_after_secs")]
+  pub after_secs: u64,
+  /// Only suspend while running on battery.
+  #[serde(default = "default_battery_only")]
+  pub battery_only: bool,
+}
+
+fn default_after_secs() -> u64 {
+  900
+}
+
+fn default_battery_only() -> bool {
+  true
+}
+
+impl Default for IdleSuspendConfig {
+  fn default() -> Self {
+    Self { after_secs: default_after_secs(), battery_only: default_battery_only() }
+  }
+}
+
+/// Whether the machine runs on a discharging battery; `false` where that can't be told.
+pub fn on_battery() -> bool {
+  discharging(Path::new(POWER_SUPPLY))
+}
+
+fn discharging(power_supply: &Path) -> bool {
+  let Ok(supplies) = std::fs::read_dir(power_supply) else {
+    return false;
+  };
+  supplies.flatten().any(|supply| {
+    let read = |name| std::fs::read_to_string(supply.path().join(name)).unwrap_or_default();
+    read("type").trim() == "Battery" && read("status").trim() == "Discharging"
+  })
+}
+
+/// Tracks the idleness of the tunnel for `IdleSuspendConfig`.
+pub struct IdleTimer {
+  after: Duration,
+  battery_only: bool,
+  last_active: Instant,
+  /// Earliest time to look at the power source again.
+  next_check: Instant,
+  suspended: bool,
+}
+
+impl IdleTimer {
+  pub fn new(config: &IdleSuspendConfig, now: Instant) -> Self {
+    Self {
+      after: Duration::from_secs(config.after_secs),
+      battery_only: config.battery_only,
+      last_active: now,
+      next_check: now,
+      suspended: false,
+    }
+  }
+
+  pub fn is_suspended(&self) -> bool {
+    self.suspended
+  }
+
+  /// Records a packet through the tunnel; returns whether it ends a suspension.
+  pub fn active(&mut self, now: Instant) -> bool {
+    self.last_active = now;
+    std::mem::take(&mut self.suspended)
+  }
+
+  /// When to call `expired` next; `None` while suspended.
+  pub fn deadline(&self) -> Option<Instant> {
+    (!self.suspended).then(|| (self.last_active + self.after).max(self.next_check))
+  }
+
+  /// Whether to suspend now; `on_battery` is only asked once the tunnel has been idle long enough.
+  pub fn expired(&mut self, now: Instant, on_battery: impl FnOnce() -> bool) -> bool {
+    if self.suspended || now < self.last_active + self.after {
+      return false;
+    }
+    if self.battery_only && !on_battery() {
+      self.next_check = now + BATTERY_RECHECK;
+      return false;
+    }
+    self.suspended = true;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_idle_timer() {
+    let now = Instant::now();
+    let config = IdleSuspendConfig { after_secs: 60, battery_only: true };
+    let mut timer = IdleTimer::new(&config, now);
+    let after = Duration::from_secs(60);
+    assert_eq!(timer.deadline(), Some(now + after));
+    assert!(!timer.expired(now + after / 2, || true));
+
+    // Traffic pushes the deadline out.
+    assert!(!timer.active(now + after / 2));
+    assert!(!timer.expired(now + after, || true));
+
+    // On mains power the power source is only looked at again later.
+    let idle = now + after * 2;
+    assert!(!timer.expired(idle, || false));
+    assert_eq!(timer.deadline(), Some(idle + BATTERY_RECHECK));
+
+    assert!(timer.expired(idle + BATTERY_RECHECK, || true));
+    assert!(timer.is_suspended());
+    assert_eq!(timer.deadline(), None);
+    assert!(timer.active(idle + BATTERY_RECHECK * 2));
+    assert!(!timer.is_suspended());
+  }
+
+  #[test]
+  fn test_on_battery() {
+    let dir = std::env::temp_dir().join(format!("vpn-power-supply-{}", std::process::id()));
+    let supply = |name: &str, kind: &str, status: Option<&str>| {
+      let path = dir.join(name);
+      std::fs::create_dir_all(&path).unwrap();
+      std::fs::write(path.join("type"), format!("{}\n", kind)).unwrap();
+      if let Some(status) = status {
+        std::fs::write(path.join("status"), format!("{}\n", status)).unwrap();
+      }
+    };
+
+    assert!(!discharging(&dir));
+    supply("AC", "Mains", None);
+    supply("BAT0", "Battery", Some("Charging"));
+    assert!(!discharging(&dir));
+    supply("BAT0", "Battery", Some("Discharging"));
+    assert!(discharging(&dir));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,230 @@
+//! Several clients on one host, e.g. a tunnel per user of a shared workstation. Each configuration already
+//! has its own control and state files next to it; what they'd still fight over is the tun, the listen port
+//! and the firewall. A running client leaves a file saying what it holds in a directory all clients share,
+//! and one starting refuses to take what another holds, naming it. A second kill switch is refused as well,
+//! as it would block the tunnel of the first, and a second gateway, which would share its rules.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+use vpn_shared::iface::NAME_INDEX_PLACEHOLDER;
+
+use crate::config::ClientConfig;
+
+/// Replaced in `tun.name` by the name of the profile, or of the configuration file without one, so every
+/// profile and configuration gets a tun of its own, e.g. `vpn-%p`.
+pub const PROFILE_PLACEHOLDER: &str = "%p";
+
+/// Longest interface name Linux allows.
+const MAX_NAME_LEN: usize = 15;
+
+const DIR: &str = "sberlinux-vpn-clients";
+
+/// What a running client holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Instance {
+  pub pid: u32,
+  pub config: PathBuf,
+  pub tun: String,
+  pub listen_port: u16,
+  pub kill_switch: bool,
+  /// LAN interface of gateway mode.
+  pub gateway: Option<String>,
+}
+
+impl Instance {
+  pub fn new(path: &Path, config: &ClientConfig) -> Self {
+    Self {
+      pid: std::process::id(),
+      config: fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+      tun: config.tun.name.clone(),
+      listen_port: config.listen_port,
+      kill_switch: config.kill_switch.is_some(),
+      gateway: config.gateway.as_ref().map(|gateway| gateway.lan_interface.clone()),
+    }
+  }
+
+  /// Why this client can't run alongside `other`.
+  fn conflict(&self, other: &Instance) -> Option<String> {
+    let fixed = |tun: &str| !tun.contains(NAME_INDEX_PLACEHOLDER);
+    if self.config == other.config {
+      Some(format!("is already connected with {}; use `up` to switch its profile", other.config.display()))
+    } else if self.tun == other.tun && fixed(&self.tun) {
+      Some(format!("uses interface {}; configure another name, e.g. vpn-%p", other.tun))
+    } else if self.listen_port == other.listen_port && self.listen_port != 0 {
+      Some(format!("listens on port {}", other.listen_port))
+    } else if self.kill_switch && other.kill_switch {
+      Some("has a kill switch, which would block this tunnel".to_string())
+    } else if self.gateway.is_some() && other.gateway.is_some() {
+      Some("runs in gateway mode".to_string())
+    } else {
+      None
+    }
+  }
+}
+
+/// `template` with `%p` replaced by the name of `profile`, or of `config` without one, cut to fit an
+/// interface name.
+pub fn tun_name(template: &str, config: &Path, profile: Option<&str>) -> String {
+  if !template.contains(PROFILE_PLACEHOLDER) {
+    return template.to_string();
+  }
+  let name = match profile {
+    Some(profile) => profile.to_string(),
+    None => config.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+  };
+  let room = (MAX_NAME_LEN + PROFILE_PLACEHOLDER.len()).saturating_sub(template.len());
+  let name: String =
+    name.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_').take(room).collect();
+  template.replacen(PROFILE_PLACEHOLDER, &name, 1)
+}
+
+/// Directory shared by the clients of every user.
+fn dir() -> PathBuf {
+  std::env::temp_dir().join(DIR)
+}
+
+/// Clients running on this host.
+pub fn running() -> anyhow::Result<Vec<Instance>> {
+  running_in(&dir())
+}
+
+fn running_in(dir: &Path) -> anyhow::Result<Vec<Instance>> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(e.into()),
+  };
+
+  let mut instances = Vec::new();
+  for entry in entries {
+    let path = entry?.path();
+    let Some(instance) =
+      fs::read(&path).ok().and_then(|contents| serde_json::from_slice::<Instance>(&contents).ok())
+    else {
+      continue;
+    };
+    if is_alive(instance.pid) {
+      instances.push(instance);
+    } else {
+      // Left by a client that didn't exit cleanly; only its user may remove it.
+      _ = fs::remove_file(&path);
+    }
+  }
+  instances.sort_by_key(|instance| instance.pid);
+  Ok(instances)
+}
+
+fn is_alive(pid: u32) -> bool {
+  if pid == std::process::id() {
+    true
+  } else if cfg!(target_os = "linux") {
+    Path::new("/proc").join(pid.to_string()).exists()
+  } else {
+    std::process::Command::new("kill")
+      .args(["-0", &pid.to_string()])
+      .stderr(std::process::Stdio::null())
+      .status()
+      .is_ok_and(|status| status.success())
+  }
+}
+
+/// Entry of a running client; dropping it removes it.
+pub struct Registration {
+  path: PathBuf,
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    _ = fs::remove_file(&self.path);
+  }
+}
+
+/// Announces `instance` to other clients, unless one of them holds what it needs.
+pub fn register(instance: &Instance) -> anyhow::Result<Registration> {
+  register_in(&dir(), instance)
+}
+
+fn register_in(dir: &Path, instance: &Instance) -> anyhow::Result<Registration> {
+  for other in running_in(dir)?.iter().filter(|other| other.pid != instance.pid) {
+    if let Some(conflict) = instance.conflict(other) {
+      anyhow::bail!("Another client (pid {}, {}) {}", other.pid, other.config.display(), conflict);
+    }
+  }
+
+  if !dir.exists() {
+    fs::create_dir_all(dir)?;
+    // Clients of every user register here, but only remove their own entries, as in /tmp.
+    #[cfg(unix)]
+    if let Err(e) = fs::set_permissions(dir, std::os::unix::fs::PermissionsExt::from_mode(0o1777)) {
+      warn!("Failed to share {} with other users: {}", dir.display(), e);
+    }
+  }
+  let path = dir.join(format!("{}.json", instance.pid));
+  fs::write(&path, serde_json::to_vec(instance)?)?;
+  Ok(Registration { path })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn instance(config: &str, tun: &str) -> Instance {
+    Instance {
+      pid: std::process::id(),
+      config: PathBuf::from(config),
+      tun: tun.to_string(),
+      listen_port: 0,
+      kill_switch: false,
+      gateway: None,
+    }
+  }
+
+  #[test]
+  fn test_tun_name() {
+    let config = Path::new("/home/alice/work.yml");
+    assert_eq!(tun_name("vpn-%p", config, None), "vpn-work");
+    assert_eq!(tun_name("vpn-%p", config, Some("home office")), "vpn-homeoffice");
+    assert_eq!(tun_name("vpn-%p", config, Some("a-very-long-profile")), "vpn-a-very-long");
+    assert_eq!(tun_name("tun0", config, Some("home")), "tun0");
+  }
+
+  #[test]
+  fn test_conflict() {
+    let alice = instance("/home/alice/vpn.yml", "vpn-alice");
+    let mut bob = instance("/home/bob/vpn.yml", "vpn-bob");
+    assert_eq!(alice.conflict(&bob), None);
+
+    bob.tun = "vpn-alice".into();
+    assert!(alice.conflict(&bob).unwrap().contains("vpn-alice"));
+    assert!(alice.conflict(&instance("/home/alice/vpn.yml", "tun1")).unwrap().contains("already connected"));
+
+    // Templates are resolved to free names as the tuns are made.
+    let (mut alice, mut bob) = (instance("/a.yml", "vpn%d"), instance("/b.yml", "vpn%d"));
+    assert_eq!(alice.conflict(&bob), None);
+    (alice.kill_switch, bob.kill_switch) = (true, true);
+    assert!(alice.conflict(&bob).unwrap().contains("kill switch"));
+  }
+
+  #[test]
+  fn test_registry() {
+    let dir = std::env::temp_dir().join(format!("vpn-client-instances-{}", std::process::id()));
+    let alice = instance("/home/alice/vpn.yml", "vpn-alice");
+    let registration = register_in(&dir, &alice).unwrap();
+    assert_eq!(running_in(&dir).unwrap(), std::slice::from_ref(&alice));
+
+    // Entries of clients that are gone don't count.
+    let stale = Instance { pid: u32::MAX, ..instance("/home/bob/vpn.yml", "vpn-alice") };
+    fs::write(dir.join("stale.json"), serde_json::to_vec(&stale).unwrap()).unwrap();
+    assert_eq!(running_in(&dir).unwrap(), [alice]);
+    assert!(!dir.join("stale.json").exists());
+
+    drop(registration);
+    assert!(running_in(&dir).unwrap().is_empty());
+    fs::remove_dir(&dir).unwrap();
+  }
+}
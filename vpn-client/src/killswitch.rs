@@ -0,0 +1,334 @@
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::process::Command;
+use std::process::Stdio;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use tracing::error;
+use tracing::info;
+
+const NAME: &str = "sberlinux_vpn_killswitch";
+
+/// Under `com.apple/*`, which the stock pf.conf already evaluates, so the system ruleset stays untouched.
+const PF_ANCHOR: &str = "com.apple/sberlinux-vpn-killswitch";
+
+/// Blocks all outgoing traffic but that through the tunnel and to the server while the client runs, so
+/// nothing leaks while the tunnel is down or reconnecting. DHCP is let through for the link to stay up.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct KillSwitchConfig {
+  /// Destinations still reachable outside the tunnel, e.g. the local network or a printer.
+  #[serde(default)]
+  pub allowed: Vec<Ipv4Net>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rules {
+  pub server: SocketAddrV4,
+  /// TCP port of the server to let out as well, for the fallback to TCP.
+  pub tcp_port: Option<u16>,
+  pub tun: String,
+  pub allowed: Vec<Ipv4Net>,
+}
+
+/// Firewall of the platform that enforces `Rules`; `enable` replaces whatever an earlier, uncleanly
+/// stopped client left behind.
+pub trait Firewall: Send {
+  fn enable(&mut self, rules: &Rules) -> anyhow::Result<()>;
+  fn disable(&mut self) -> anyhow::Result<()>;
+
+  /// What `disable` needs besides the rules themselves, for `platform_from` after a crash.
+  fn state(&self) -> String {
+    String::new()
+  }
+}
+
+/// Backend for the current platform.
+pub fn platform() -> anyhow::Result<Box<dyn Firewall>> {
+  if cfg!(target_os = "linux") {
+    Ok(Box::new(Nftables))
+  } else if cfg!(target_os = "macos") {
+    Ok(Box::new(Pf::default()))
+  } else if cfg!(windows) {
+    Ok(Box::new(WindowsFirewall::default()))
+  } else {
+    anyhow::bail!("Kill switch is not supported on this platform")
+  }
+}
+
+/// Backend for the current platform that can disable what one with `state` enabled.
+pub fn platform_from(state: String) -> anyhow::Result<Box<dyn Firewall>> {
+  let state = Some(state).filter(|state| !state.is_empty());
+  if cfg!(target_os = "macos") {
+    Ok(Box::new(Pf { token: state }))
+  } else if cfg!(windows) {
+    Ok(Box::new(WindowsFirewall { defaults: state }))
+  } else {
+    platform()
+  }
+}
+
+/// Enabled kill switch; dropping it lets traffic through again.
+pub struct KillSwitch(Box<dyn Firewall>);
+
+impl KillSwitch {
+  pub fn enable(mut firewall: Box<dyn Firewall>, rules: &Rules) -> anyhow::Result<Self> {
+    firewall.enable(rules).map_err(|e| anyhow::anyhow!("Failed to enable the kill switch: {}", e))?;
+    info!("Kill switch enabled: only {} and traffic to {} are allowed out", rules.tun, rules.server);
+    Ok(Self(firewall))
+  }
+
+  pub fn state(&self) -> String {
+    self.0.state()
+  }
+}
+
+impl Drop for KillSwitch {
+  fn drop(&mut self) {
+    match self.0.disable() {
+      Ok(()) => info!("Kill switch disabled"),
+      Err(e) => error!("Failed to disable the kill switch: {}", e),
+    }
+  }
+}
+
+/// Table of its own in the `inet` family, so IPv6 is blocked as well.
+pub struct Nftables;
+
+impl Firewall for Nftables {
+  fn enable(&mut self, rules: &Rules) -> anyhow::Result<()> {
+    run("nft", &["-f", "-"], Some(&nft_ruleset(rules)))
+  }
+
+  fn disable(&mut self) -> anyhow::Result<()> {
+    run("nft", &["delete", "table", "inet", NAME], None)
+  }
+}
+
+fn nft_ruleset(rules: &Rules) -> String {
+  let mut allowed = String::new();
+  if let Some(port) = rules.tcp_port {
+    allowed.push_str(&format!("    ip daddr {} tcp dport {} accept\n", rules.server.ip(), port));
+  }
+  for net in &rules.allowed {
+    allowed.push_str(&format!("    ip daddr {} accept\n", net));
+  }
+
+  // Adding the table first makes deleting it succeed on a clean system too.
+  format!(
+    "add table inet {name}
+delete table inet {name}
+table inet {name} {{
+  chain output {{
+    type filter hook output priority 0; policy drop;
+    oifname \"lo\" accept
+    oifname \"{tun}\" accept
+    ip daddr {address} udp dport {port} accept
+    udp sport 68 udp dport 67 accept
+{allowed}  }}
+}}
+",
+    name = NAME,
+    tun = rules.tun,
+    address = rules.server.ip(),
+    port = rules.server.port(),
+    allowed = allowed,
+  )
+}
+
+/// Rules in an anchor of their own, with pf enabled through a reference that's released on `disable`.
+#[derive(Default)]
+pub struct Pf {
+  token: Option<String>,
+}
+
+impl Firewall for Pf {
+  fn enable(&mut self, rules: &Rules) -> anyhow::Result<()> {
+    run("pfctl", &["-a", PF_ANCHOR, "-f", "-"], Some(&pf_rules(rules)))?;
+    if self.token.is_none() {
+      let output = Command::new("pfctl").arg("-E").output()?;
+      // The token is printed to stderr as `Token : <number>`.
+      let stderr = String::from_utf8_lossy(&output.stderr);
+      self.token =
+        stderr.lines().find_map(|line| line.strip_prefix("Token : ")).map(|t| t.trim().to_string());
+    }
+    Ok(())
+  }
+
+  fn disable(&mut self) -> anyhow::Result<()> {
+    run("pfctl", &["-a", PF_ANCHOR, "-F", "all"], None)?;
+    if let Some(token) = self.token.take() {
+      run("pfctl", &["-X", &token], None)?;
+    }
+    Ok(())
+  }
+
+  fn state(&self) -> String {
+    self.token.clone().unwrap_or_default()
+  }
+}
+
+fn pf_rules(rules: &Rules) -> String {
+  let mut pf = format!(
+    "pass out quick on lo0 all
+pass out quick on {tun} all
+pass out quick inet proto udp to {address} port {port}
+pass out quick inet proto udp from port 68 to port 67
+",
+    tun = rules.tun,
+    address = rules.server.ip(),
+    port = rules.server.port(),
+  );
+  if let Some(port) = rules.tcp_port {
+    pf.push_str(&format!("pass out quick inet proto tcp to {} port {}\n", rules.server.ip(), port));
+  }
+  for net in &rules.allowed {
+    pf.push_str(&format!("pass out quick inet to {}\n", net));
+  }
+  pf.push_str("block drop out all\n");
+  pf
+}
+
+/// Windows Firewall, which enforces its rules through WFP: outbound traffic is blocked by default on all
+/// profiles while the client's allow rules are in place, and the previous defaults are restored after.
+#[derive(Default)]
+pub struct WindowsFirewall {
+  defaults: Option<String>,
+}
+
+impl Firewall for WindowsFirewall {
+  fn enable(&mut self, rules: &Rules) -> anyhow::Result<()> {
+    if self.defaults.is_none() {
+      let defaults = powershell_output(
+        "Get-NetFirewallProfile | ForEach-Object { \"$($_.Name)=$($_.DefaultOutboundAction)\" }",
+      )?;
+      self.defaults = Some(defaults);
+    }
+    powershell(&windows_script(rules))
+  }
+
+  fn disable(&mut self) -> anyhow::Result<()> {
+    let mut script = format!("Remove-NetFirewallRule -Group '{}' -ErrorAction SilentlyContinue\n", NAME);
+    for line in self.defaults.take().unwrap_or_default().lines() {
+      if let Some((profile, action)) = line.trim().split_once('=') {
+        script
+          .push_str(&format!("Set-NetFirewallProfile -Name {} -DefaultOutboundAction {}\n", profile, action));
+      }
+    }
+    powershell(&script)
+  }
+
+  fn state(&self) -> String {
+    self.defaults.clone().unwrap_or_default()
+  }
+}
+
+fn windows_script(rules: &Rules) -> String {
+  let allow = |args: String| {
+    format!(
+      "New-NetFirewallRule -Group '{}' -DisplayName '{}' -Direction Outbound -Action Allow {} | Out-Null\n",
+      NAME, NAME, args
+    )
+  };
+
+  let mut script = format!("Remove-NetFirewallRule -Group '{}' -ErrorAction SilentlyContinue\n", NAME);
+  script.push_str(&allow(format!("-InterfaceAlias '{}'", rules.tun)));
+  script.push_str(&allow(format!(
+    "-Protocol UDP -RemoteAddress {} -RemotePort {}",
+    rules.server.ip(),
+    rules.server.port()
+  )));
+  if let Some(port) = rules.tcp_port {
+    script.push_str(&allow(format!(
+      "-Protocol TCP -RemoteAddress {} -RemotePort {}",
+      rules.server.ip(),
+      port
+    )));
+  }
+  script.push_str(&allow("-Protocol UDP -LocalPort 68 -RemotePort 67".to_string()));
+  script.push_str(&allow("-RemoteAddress 127.0.0.0/8,::1".to_string()));
+  for net in &rules.allowed {
+    script.push_str(&allow(format!("-RemoteAddress {}", net)));
+  }
+  script.push_str("Set-NetFirewallProfile -All -DefaultOutboundAction Block\n");
+  script
+}
+
+fn powershell(script: &str) -> anyhow::Result<()> {
+  powershell_output(script).map(drop)
+}
+
+fn powershell_output(script: &str) -> anyhow::Result<String> {
+  let output =
+    Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", script]).output()?;
+  if !output.status.success() {
+    anyhow::bail!("PowerShell failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub(crate) fn run(program: &str, args: &[&str], stdin: Option<&str>) -> anyhow::Result<()> {
+  let mut child = Command::new(program)
+    .args(args)
+    .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+    pipe.write_all(input.as_bytes())?;
+  }
+
+  let output = child.wait_with_output()?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "`{} {}` failed: {}",
+      program,
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rules() -> Rules {
+    Rules {
+      server: "203.0.113.5:9696".parse().unwrap(),
+      tcp_port: Some(443),
+      tun: "tun0".to_string(),
+      allowed: vec!["192.168.1.0/24".parse().unwrap()],
+    }
+  }
+
+  #[test]
+  fn test_nft_ruleset() {
+    let ruleset = nft_ruleset(&rules());
+    assert!(ruleset.contains("policy drop;"));
+    assert!(ruleset.contains("oifname \"tun0\" accept"));
+    assert!(ruleset.contains("ip daddr 203.0.113.5 udp dport 9696 accept"));
+    assert!(ruleset.contains("ip daddr 203.0.113.5 tcp dport 443 accept"));
+    assert!(ruleset.contains("ip daddr 192.168.1.0/24 accept"));
+  }
+
+  #[test]
+  fn test_pf_rules() {
+    let pf = pf_rules(&rules());
+    assert!(pf.contains("pass out quick inet proto udp to 203.0.113.5 port 9696\n"));
+    assert!(pf.contains("pass out quick inet proto tcp to 203.0.113.5 port 443\n"));
+    assert!(pf.contains("pass out quick inet to 192.168.1.0/24\n"));
+    assert!(pf.ends_with("block drop out all\n"));
+  }
+
+  #[test]
+  fn test_windows_script() {
+    let script = windows_script(&rules());
+    assert!(script.contains("-InterfaceAlias 'tun0'"));
+    assert!(script.contains("-Protocol UDP -RemoteAddress 203.0.113.5 -RemotePort 9696"));
+    assert!(script.contains("-Protocol TCP -RemoteAddress 203.0.113.5 -RemotePort 443"));
+    assert!(script.ends_with("Set-NetFirewallProfile -All -DefaultOutboundAction Block\n"));
+  }
+}
@@ -0,0 +1,104 @@
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// Link-local addresses and multicast, which printers and NAS boxes are often found through.
+pub const LINK_LOCAL: [Ipv4Net; 2] = [
+  Ipv4Net::new_assert(Ipv4Addr::new(169, 254, 0, 0), 16),
+  Ipv4Net::new_assert(Ipv4Addr::new(224, 0, 0, 0), 24),
+];
+
+/// Keeps the local network reachable while connected: the subnets of the local interfaces and link-local
+/// ranges are cut out of the tunnel routes and let through the kill switch.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct LanAccessConfig {
+  /// Sends the local network through the tunnel after all, e.g. from a profile for public Wi-Fi.
+  #[serde(default)]
+  pub strict: bool,
+
+  /// Ranges bypassing the tunnel besides the local subnets and `LINK_LOCAL`.
+  #[serde(default)]
+  pub extra: Vec<Ipv4Net>,
+}
+
+/// Ranges that bypass the tunnel, as of now; the subnets of `tun` itself aren't local.
+pub async fn bypassed(config: &LanAccessConfig, tun: &str) -> anyhow::Result<Vec<Ipv4Net>> {
+  let output = Command::new("ip").args(["-4", "-o", "addr", "show"]).output().await?;
+  if !output.status.success() {
+    anyhow::bail!("Failed to list local addresses: {}", String::from_utf8_lossy(&output.stderr).trim());
+  }
+
+  let mut ranges = local_subnets(&String::from_utf8_lossy(&output.stdout), tun);
+  ranges.extend(LINK_LOCAL);
+  ranges.extend(&config.extra);
+  Ok(Ipv4Net::aggregate(&ranges))
+}
+
+/// Subnets of globally scoped addresses in `ip -o addr` output, but those of `tun`.
+fn local_subnets(output: &str, tun: &str) -> Vec<Ipv4Net> {
+  output
+    .lines()
+    .filter_map(|line| {
+      let fields: Vec<_> = line.split_whitespace().collect();
+      if fields.get(1).is_none_or(|dev| *dev == tun) || !fields.windows(2).any(|w| w == ["scope", "global"]) {
+        return None;
+      }
+      let address = fields.windows(2).find(|w| w[0] == "inet")?[1];
+      address.parse::<Ipv4Net>().ok().map(|net| net.trunc())
+    })
+    .collect()
+}
+
+/// `routes` with `excluded` cut out, splitting routes that overlap them into the smaller ones around them.
+pub fn exclude(routes: &[Ipv4Net], excluded: &[Ipv4Net]) -> Vec<Ipv4Net> {
+  let mut result = Vec::new();
+  let mut pending: Vec<_> = routes.iter().rev().map(|route| route.trunc()).collect();
+  while let Some(route) = pending.pop() {
+    if excluded.iter().any(|net| net.contains(&route)) {
+      continue;
+    }
+    if !excluded.iter().any(|net| route.contains(net)) {
+      result.push(route);
+      continue;
+    }
+    // A route containing an excluded range is at most /31, so it splits in two.
+    let halves: Vec<_> = route.subnets(route.prefix_len() + 1).into_iter().flatten().collect();
+    pending.extend(halves.into_iter().rev());
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn nets(nets: &[&str]) -> Vec<Ipv4Net> {
+    nets.iter().map(|net| net.parse().unwrap()).collect()
+  }
+
+  #[test]
+  fn test_local_subnets() {
+    let output = "1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever
+2: wlan0    inet 192.168.1.23/24 brd 192.168.1.255 scope global dynamic wlan0\\       valid_lft 3600sec
+5: tun0    inet 10.8.0.2/24 scope global tun0\\       valid_lft forever preferred_lft forever
+";
+    assert_eq!(local_subnets(output, "tun0"), nets(&["192.168.1.0/24"]));
+  }
+
+  #[test]
+  fn test_exclude() {
+    let routes = nets(&["0.0.0.0/1", "128.0.0.0/1", "10.10.0.0/24"]);
+    let excluded = nets(&["192.168.1.0/24", "10.0.0.0/8"]);
+
+    let routes = exclude(&routes, &excluded);
+    assert_eq!(routes.len(), 7 + 23);
+    assert!(!routes
+      .iter()
+      .any(|route| excluded.iter().any(|net| net.contains(route) || route.contains(net))));
+    assert_eq!(Ipv4Net::aggregate(&[routes.clone(), excluded].concat()), nets(&["0.0.0.0/0"]));
+    assert_eq!(exclude(&nets(&["172.16.0.0/12"]), &nets(&["192.168.0.0/16"])), nets(&["172.16.0.0/12"]));
+  }
+}
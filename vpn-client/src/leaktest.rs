@@ -0,0 +1,156 @@
+use std::net::Ipv4Addr;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+
+use ipnet::Ipv4Net;
+
+use crate::ClientConfig;
+
+/// Names resolved to check DNS works while connected.
+const CANARY_NAMES: &[&str] = &["example.com", "example.net"];
+
+/// Destination of probes for a default route; TEST-NET-1, so nothing answers.
+const CANARY_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// Traceroute's first port, unlikely to reach a real service.
+const PROBE_PORT: u16 = 33434;
+
+#[derive(Debug)]
+pub struct Check {
+  pub name: String,
+  pub passed: bool,
+  pub detail: String,
+}
+
+impl Check {
+  fn new(name: String, passed: bool, detail: String) -> Self {
+    Self { name, passed, detail }
+  }
+}
+
+/// Checks that the configured routes and the DNS resolvers go through the tunnel of a running client and
+/// the server at `server` itself doesn't.
+pub fn run(config: &ClientConfig, server: Ipv4Addr) -> anyhow::Result<Vec<Check>> {
+  let tun = &config.tun;
+  if source_for(CANARY_ADDRESS).ok() != Some(tun.address)
+    && !config.routes.iter().any(|route| source_for(probe_address(*route)).ok() == Some(tun.address))
+  {
+    anyhow::bail!("Nothing is routed through {} ({}); is the client connected?", tun.name, tun.address);
+  }
+
+  let mut checks = Vec::new();
+
+  for route in &config.routes {
+    let probe = probe_address(*route);
+    let (passed, detail) = match source_for(probe) {
+      Ok(source) if source != tun.address => (false, format!("{} is reached from {}", probe, source)),
+      Ok(_) => match sends_through(&tun.name, probe) {
+        Some(false) => (false, format!("a probe to {} didn't leave through {}", probe, tun.name)),
+        _ => (true, format!("{} is reached through {}", probe, tun.name)),
+      },
+      Err(e) => (false, format!("{} is unreachable: {}", probe, e)),
+    };
+    checks.push(Check::new(format!("route {}", route), passed, detail));
+  }
+
+  checks.push(match source_for(server) {
+    Ok(source) if source == tun.address => {
+      Check::new(format!("server {}", server), false, "the server is routed into the tunnel".into())
+    }
+    Ok(source) => Check::new(format!("server {}", server), true, format!("reached from {}", source)),
+    Err(e) => Check::new(format!("server {}", server), false, e.to_string()),
+  });
+
+  for resolver in nameservers() {
+    let name = format!("dns {}", resolver);
+    if resolver.is_loopback() {
+      checks.push(Check::new(name, false, "a local resolver; its upstream servers can't be checked".into()));
+      continue;
+    }
+    checks.push(match source_for(resolver) {
+      Ok(source) if source == tun.address => Check::new(name, true, format!("reached through {}", tun.name)),
+      Ok(source) => Check::new(name, false, format!("reached outside the tunnel from {}", source)),
+      Err(e) => Check::new(name, false, e.to_string()),
+    });
+  }
+
+  for canary in CANARY_NAMES {
+    let name = format!("resolve {}", canary);
+    checks.push(match (*canary, 0).to_socket_addrs() {
+      Ok(mut addrs) => match addrs.next() {
+        Some(addr) => Check::new(name, true, format!("resolved to {}", addr.ip())),
+        None => Check::new(name, false, "no addresses".into()),
+      },
+      Err(e) => Check::new(name, false, e.to_string()),
+    });
+  }
+
+  Ok(checks)
+}
+
+/// Local address the kernel would send from to reach `destination`; connecting a UDP socket sends nothing.
+fn source_for(destination: Ipv4Addr) -> std::io::Result<Ipv4Addr> {
+  let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+  socket.connect((destination, PROBE_PORT))?;
+  match socket.local_addr()?.ip() {
+    std::net::IpAddr::V4(address) => Ok(address),
+    std::net::IpAddr::V6(address) => Err(std::io::Error::other(format!("unexpected source {}", address))),
+  }
+}
+
+/// Sends a probe to `destination` and reports whether the tun's packet counter moved; `None` where the
+/// counter can't be read.
+fn sends_through(tun: &str, destination: Ipv4Addr) -> Option<bool> {
+  let before = tx_packets(tun)?;
+  let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+  socket.send_to(b"vpn-client leak test", (destination, PROBE_PORT)).ok()?;
+  std::thread::sleep(std::time::Duration::from_millis(100));
+  Some(tx_packets(tun)? > before)
+}
+
+fn tx_packets(tun: &str) -> Option<u64> {
+  std::fs::read_to_string(format!("/sys/class/net/{}/statistics/tx_packets", tun)).ok()?.trim().parse().ok()
+}
+
+fn probe_address(route: Ipv4Net) -> Ipv4Addr {
+  match route.prefix_len() {
+    0 => CANARY_ADDRESS,
+    _ => route.hosts().next().unwrap_or(route.network()),
+  }
+}
+
+/// IPv4 resolvers of the system; with systemd-resolved's stub the upstream servers are read instead.
+fn nameservers() -> Vec<Ipv4Addr> {
+  let read = |path| std::fs::read_to_string(path).map(|text| parse_nameservers(&text)).unwrap_or_default();
+  let resolvers = read("/etc/resolv.conf");
+  match resolvers.iter().all(|resolver| resolver.is_loopback()) {
+    true => Some(read("/run/systemd/resolve/resolv.conf")).filter(|r| !r.is_empty()).unwrap_or(resolvers),
+    false => resolvers,
+  }
+}
+
+pub(crate) fn parse_nameservers(resolv_conf: &str) -> Vec<Ipv4Addr> {
+  resolv_conf
+    .lines()
+    .filter_map(|line| line.trim().strip_prefix("nameserver"))
+    .filter_map(|address| address.trim().parse().ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_nameservers() {
+    let resolv_conf = "# generated\nnameserver 10.8.0.1\nnameserver ::1\n  nameserver 1.1.1.1 \nsearch lan\n";
+    assert_eq!(parse_nameservers(resolv_conf), [Ipv4Addr::new(10, 8, 0, 1), Ipv4Addr::new(1, 1, 1, 1)]);
+  }
+
+  #[test]
+  fn test_probe_address() {
+    assert_eq!(probe_address("0.0.0.0/0".parse().unwrap()), CANARY_ADDRESS);
+    assert_eq!(probe_address("10.0.0.0/8".parse().unwrap()), Ipv4Addr::new(10, 0, 0, 1));
+    assert_eq!(probe_address("10.1.2.3/32".parse().unwrap()), Ipv4Addr::new(10, 1, 2, 3));
+  }
+}
@@ -1,6 +1,33 @@
 pub mod client;
 pub mod config;
+pub mod device;
+pub mod discovery;
+pub mod dns;
+pub mod events;
+pub mod eyeballs;
+pub mod fallback;
+pub mod forward;
+pub mod gateway;
+pub mod idle;
+pub mod instance;
+pub mod killswitch;
+pub mod lan;
+pub mod leaktest;
+pub mod oidc;
+pub mod portmap;
+pub mod profile;
+pub mod rendezvous;
+pub mod resolver;
+pub mod resume;
+pub mod routes;
+pub mod service;
+pub mod state;
+pub mod status;
+pub mod trusted;
+pub mod watch;
 
 pub use client::Client;
 pub use client::ClientBuilder;
 pub use config::ClientConfig;
+pub use device::ClientHandle;
+pub use events::ClientEvent;
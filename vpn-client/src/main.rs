@@ -1,41 +1,571 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+
 use clap::Parser;
+use clap::Subcommand;
+use ipnet::Ipv4Net;
+use serde_json::json;
+use tokio::sync::watch::Receiver;
+use tokio::sync::watch::Sender;
 use tracing::error;
+use tracing::info;
+use tracing::warn;
+use vpn_client::discovery;
+use vpn_client::eyeballs;
+use vpn_client::instance;
+use vpn_client::instance::Instance;
+use vpn_client::leaktest;
+use vpn_client::profile;
+use vpn_client::profile::ProfileControl;
+use vpn_client::resolver;
+use vpn_client::resolver::ResolverConfig;
+use vpn_client::resume::TicketStore;
+use vpn_client::service;
+use vpn_client::state;
+use vpn_client::state::SystemState;
+use vpn_client::status::StatusFeed;
+use vpn_client::status::StatusWatch;
+use vpn_client::trusted::NetworkMonitor;
+use vpn_client::watch::ConfigWatcher;
 use vpn_client::{Client, ClientConfig};
+use vpn_shared::cert::Certificate;
+use vpn_shared::creds::Credentials;
+use vpn_shared::handshake;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::iface::DEFAULT_MTU;
+use vpn_shared::logging;
+use vpn_shared::output::Format;
+use vpn_shared::packet::SessionParams;
+use vpn_shared::selftest;
+
+const RENEW_WARNING: Duration = Duration::from_secs(14 * 24 * 60 * 60);
 
 #[derive(Debug, Parser)]
 #[command(version)]
 struct Args {
   /// Path to the configuration file
-  #[arg(short, long)]
-  config: String,
+  #[arg(short, long, global = true)]
+  config: Option<String>,
+
+  /// Re-read the configuration when it changes: routes are applied on the fly, the MTU, keepalive and transforms renegotiated, anything else reconnects
+  #[arg(long)]
+  watch: bool,
+
+  /// Check the crypto primitives, permissions to create a tun device, the configuration and the listen
+  /// socket, print a JSON report and exit, with status 1 if anything failed
+  #[arg(long)]
+  selftest: bool,
+
+  /// How `leak-test`, `profiles`, `discover` and `status` print: `text`, or `json` for scripts
+  #[arg(long, global = true, default_value = "text", value_parser = Format::parse)]
+  output: Format,
+
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+  /// Start the client on boot as a Windows service or a macOS LaunchDaemon
+  #[command(subcommand)]
+  Service(ServiceCommand),
+
+  /// While connected, check that routes and DNS go through the tunnel and print a report
+  LeakTest,
+
+  /// Connect with the settings of a profile from `profiles`, or switch a client already running with
+  /// this configuration to it
+  Up { profile: String },
+
+  /// List the profiles of the configuration
+  Profiles,
+
+  /// Find servers on the local network that advertise themselves through mDNS
+  Discover {
+    /// How long to wait for answers, in seconds
+    #[arg(long, default_value_t = 3)]
+    timeout: u64,
+  },
+
+  /// Change the password of `credentials` on the server; the new one is read from the terminal
+  ChangePassword,
+
+  /// List the clients running on this host, of every user
+  Instances,
+
+  /// Print the state, latency, throughput and latest events of the client running with this configuration
+  Status {
+    /// Keep redrawing the status as it changes, until interrupted or the client stops
+    #[arg(long)]
+    follow: bool,
+  },
+
+  /// Undo the routes, DNS settings and firewall rules left by a client with this configuration that crashed
+  /// or was killed; a client does this itself on start too
+  Cleanup,
+}
+
+#[derive(Debug, Subcommand)]
+enum ServiceCommand {
+  /// Register the client with --config and start it; requires administrator rights
+  Install,
+
+  /// Stop the client and remove its registration
+  Uninstall,
+
+  /// Entry point the Windows service manager starts the client with
+  #[command(hide = true)]
+  Run,
+}
+
+fn real_main(args: Args) -> anyhow::Result<()> {
+  let config = || args.config.clone().ok_or(anyhow::anyhow!("--config is required"));
+
+  if args.selftest {
+    let report = tokio::runtime::Runtime::new()?.block_on(selftest(config()?));
+    println!("{}", report.to_json());
+    if !report.passed {
+      std::process::exit(1);
+    }
+    return Ok(());
+  }
+
+  match args.command {
+    Some(Command::Service(ServiceCommand::Install)) => {
+      let path = config()?;
+      service::install(Path::new(&path))?;
+      println!("Installed {} with {}", service::SERVICE_NAME, path);
+      Ok(())
+    }
+    Some(Command::Service(ServiceCommand::Uninstall)) => service::uninstall(),
+    Some(Command::Service(ServiceCommand::Run)) => {
+      let path = config()?;
+      service::run(move || connect(path, None, args.watch))
+    }
+    Some(Command::LeakTest) => {
+      let config = ClientConfig::from_file(config()?)?;
+      let server = tokio::runtime::Runtime::new()?.block_on(leak_test_server(&config))?;
+      leak_test(&config, server, args.output)
+    }
+    Some(Command::Up { profile }) => connect(config()?, Some(profile), args.watch),
+    Some(Command::Profiles) => {
+      let names: Vec<_> = ClientConfig::from_file(config()?)?.profiles.into_keys().collect();
+      print!("{}", args.output.render(&json!(names)));
+      Ok(())
+    }
+    Some(Command::Discover { timeout }) => discover(Duration::from_secs(timeout), args.output),
+    Some(Command::ChangePassword) => change_password(ClientConfig::from_file(config()?)?),
+    Some(Command::Instances) => {
+      print!("{}", args.output.render(&serde_json::to_value(instance::running()?)?));
+      Ok(())
+    }
+    Some(Command::Status { follow }) => status(Path::new(&config()?), follow, args.output),
+    Some(Command::Cleanup) => {
+      let path = state::path(Path::new(&config()?));
+      let undone = tokio::runtime::Runtime::new()?.block_on(state::restore(&path))?;
+      println!("Undid {} changes", undone);
+      Ok(())
+    }
+    None => connect(config()?, None, args.watch),
+  }
+}
+
+/// Report of `--selftest`: the configuration is loaded and checked as on start, but nothing is started.
+async fn selftest(path: String) -> selftest::Report {
+  let config = match ClientConfig::from_file(path) {
+    Ok(config) => match (config.tun_config(), config.endpoint().await) {
+      (Ok(_), Ok(_)) => Ok(config),
+      (Err(e), _) | (_, Err(e)) => Err(e),
+    },
+    Err(e) => Err(e),
+  };
+
+  let mut checks = vec![
+    selftest::crypto(),
+    selftest::Check::new(
+      "config",
+      config.as_ref().map(|_| "Configuration is valid".to_string()).map_err(|e| anyhow::anyhow!("{}", e)),
+    ),
+    selftest::tun(),
+  ];
+  checks.push(match config {
+    Ok(ref config) => {
+      let address = SocketAddr::new(config.listen_address.into(), config.listen_port);
+      selftest::udp_bind("socket", address).await
+    }
+    Err(_) => selftest::Check::skip("socket", "Configuration is invalid"),
+  });
+  selftest::Report::new("vpn-client", env!("CARGO_PKG_VERSION"), checks)
+}
+
+/// IPv4 address of the server to check the route of; with a hostname, the first one it has.
+async fn leak_test_server(config: &ClientConfig) -> anyhow::Result<Ipv4Addr> {
+  let endpoint = config.endpoint().await?;
+  let Some(host) = endpoint.host.filter(|_| endpoint.address.is_unspecified()) else {
+    return Ok(endpoint.address);
+  };
+  let resolver = config.resolver.as_ref().map(ResolverConfig::build).transpose()?;
+  let resolver = resolver.unwrap_or_else(|| Box::new(resolver::System));
+  let resolved = eyeballs::resolve(resolver.as_ref(), &host, endpoint.port).await?;
+  match resolved.iter().find_map(|addr| match addr.ip() {
+    IpAddr::V4(address) => Some(address),
+    IpAddr::V6(_) => None,
+  }) {
+    Some(address) => Ok(address),
+    None => anyhow::bail!("{} has no IPv4 address; the leak test only checks IPv4", host),
+  }
+}
+
+fn leak_test(config: &ClientConfig, server: Ipv4Addr, output: Format) -> anyhow::Result<()> {
+  let checks = leaktest::run(config, server)?;
+  match output {
+    Format::Json => {
+      let checks: Vec<_> = checks
+        .iter()
+        .map(|check| json!({ "name": check.name, "passed": check.passed, "detail": check.detail }))
+        .collect();
+      print!("{}", output.render(&json!(checks)));
+    }
+    Format::Text => {
+      for check in &checks {
+        println!("{}  {}: {}", if check.passed { "PASS" } else { "FAIL" }, check.name, check.detail);
+      }
+    }
+  }
+
+  let failed = checks.iter().filter(|check| !check.passed).count();
+  if failed > 0 {
+    anyhow::bail!("{} of {} checks failed", failed, checks.len());
+  }
+  Ok(())
 }
 
 #[tokio::main]
-async fn real_main(args: Args) -> anyhow::Result<()> {
-  let config = ClientConfig::from_file(&args.config)?;
+async fn discover(timeout: Duration, output: Format) -> anyhow::Result<()> {
+  let servers = discovery::browse(timeout).await?;
+  if output == Format::Json {
+    let servers: Vec<_> = servers
+      .iter()
+      .map(|server| {
+        let key = server.public_key.as_ref().map(handshake::encode_key);
+        json!({ "name": server.name, "address": server.address, "port": server.port, "public_key": key })
+      })
+      .collect();
+    print!("{}", output.render(&json!(servers)));
+    return Ok(());
+  }
+  if servers.is_empty() {
+    anyhow::bail!("No servers answered within {} seconds", timeout.as_secs());
+  }
+
+  for server in servers {
+    let key = server.public_key.map(|key| format!("  server-public-key: {}", handshake::encode_key(&key)));
+    println!("{}  {}:{}{}", server.name, server.address, server.port, key.unwrap_or_default());
+  }
+  Ok(())
+}
+
+#[tokio::main]
+async fn status(config: &Path, follow: bool, output: Format) -> anyhow::Result<()> {
+  let mut watch = StatusWatch::connect(config).await?;
+  while let Some(status) = watch.next().await? {
+    match output {
+      // A line of JSON per update when following, for scripts that read them as they come.
+      Format::Json if follow => println!("{}", serde_json::to_string(&status)?),
+      Format::Json => print!("{}", output.render(&serde_json::to_value(&status)?)),
+      // Clears the terminal before every update.
+      Format::Text if follow => print!("\x1b[2J\x1b[H{}", status.render(handshake::unix_time())),
+      Format::Text => print!("{}", status.render(handshake::unix_time())),
+    }
+    if !follow {
+      return Ok(());
+    }
+  }
+  anyhow::bail!("The client stopped")
+}
+
+fn change_password(config: ClientConfig) -> anyhow::Result<()> {
+  let Some(credentials @ Credentials::Password { .. }) = config.credentials.clone() else {
+    anyhow::bail!("Changing the password requires password credentials");
+  };
+  let password = rpassword::prompt_password("New password: ")?;
+  if rpassword::prompt_password("Repeat it: ")? != password {
+    anyhow::bail!("Passwords don't match");
+  }
+
+  tokio::runtime::Runtime::new()?.block_on(async {
+    let endpoint = config.endpoint().await?;
+    // Any port, so that a client already connected with this configuration doesn't get in the way.
+    let mut builder = Client::builder(endpoint.address, endpoint.port)
+      .with_listen_address(config.listen_address, 0)
+      .with_connect_timeout(config.connect_timeout())
+      .with_packet_pipe(DEFAULT_MTU)
+      .with_transforms(endpoint.transforms)
+      .with_creds(credentials);
+    if let Some(host) = endpoint.host {
+      builder = builder.with_server_host(host);
+    }
+    if let Some(ref rendezvous) = config.rendezvous {
+      builder = builder.with_rendezvous(rendezvous.clone());
+    }
+    if let Some(ref resolver) = config.resolver {
+      builder = builder.with_resolver(resolver.build()?);
+    }
+    if let Some(ref tcp_fallback) = config.tcp_fallback {
+      builder = builder.with_tcp_fallback(tcp_fallback.clone());
+    }
+    if let Some(ref resume) = config.resume {
+      let server = SocketAddr::new(endpoint.address.into(), endpoint.port);
+      builder = builder.with_ticket_store(TicketStore::open(resume, server)?);
+    }
+    if let Some(key) = endpoint.public_key {
+      builder = builder.with_server_public_key(key);
+    }
+    builder.build().await?.change_password(&password).await
+  })?;
+
+  println!("Password changed; update `credentials` in the configuration");
+  Ok(())
+}
+
+#[tokio::main]
+async fn connect(path: String, profile: Option<String>, watch: bool) -> anyhow::Result<()> {
+  let mut config = ClientConfig::from_file_with_profile(&path, profile.as_deref())?;
+
+  if let Some(ref profile) = profile {
+    if profile::request_switch(Path::new(&path), profile).await? {
+      println!("Switched the running client to {}", profile);
+      return Ok(());
+    }
+  }
 
-  let client = Client::builder(config.server_address, config.server_port)
+  logging::init(&config.log, "vpn-client")?;
+  #[cfg(unix)]
+  tokio::spawn(logging::cycle_on_sigusr1());
+
+  let mut feed = StatusFeed::bind(Path::new(&path)).await?;
+  let state = state::path(Path::new(&path));
+  match state::restore(&state).await {
+    Ok(0) => {}
+    Ok(undone) => warn!("Undid {} changes left by a client that didn't stop cleanly", undone),
+    Err(e) => error!("{}", e),
+  }
+
+  if !watch && profile.is_none() && config.auto_connect.is_none() {
+    let routes = tokio::sync::watch::channel(config.routes.clone()).1;
+    let session = Sender::new(config.session_params());
+    let _registration = instance::register(&Instance::new(Path::new(&path), &config))?;
+    let client = build(config, routes, &session, &state).await?;
+    feed.follow(&client);
+    return client.run().await;
+  }
+
+  let control = match profile {
+    Some(_) => Some(ProfileControl::bind(Path::new(&path)).await?),
+    None => None,
+  };
+  let mut watcher = watch.then(|| ConfigWatcher::new(path.clone().into(), profile.clone())).transpose()?;
+  let mut network = config.auto_connect.as_ref().map(NetworkMonitor::new);
+  let mut current = profile;
+
+  loop {
+    if let Some(ref mut network) = network {
+      loop {
+        tokio::select! {
+          _ = network.wait_for(false) => break,
+          (name, switched) = next_profile(control.as_ref(), &path) => {
+            watcher = watcher.is_some().then(|| ConfigWatcher::new(path.clone().into(), Some(name.clone()))).transpose()?;
+            (current, config) = (Some(name), switched);
+          }
+        }
+      }
+    }
+
+    let (routes, updates) = tokio::sync::watch::channel(config.routes.clone());
+    let session = Sender::new(config.session_params());
+    let _registration = instance::register(&Instance::new(Path::new(&path), &config))?;
+    let client = build(config, updates, &session, &state).await?;
+    feed.follow(&client);
+
+    // Dropping the client on a change closes its tun and socket, taking its routes and DNS settings with
+    // them, before the next one is built.
+    tokio::select! {
+      result = client.run() => return result,
+      changed = async { watcher.as_mut().unwrap().wait_for_reconnect(&routes, &session).await }, if watcher.is_some() => {
+        config = changed;
+      }
+      (name, switched) = next_profile(control.as_ref(), &path) => {
+        watcher = watcher.is_some().then(|| ConfigWatcher::new(path.clone().into(), Some(name.clone()))).transpose()?;
+        (current, config) = (Some(name), switched);
+      }
+      _ = async { network.as_mut().unwrap().wait_for(true).await }, if network.is_some() => {
+        info!("Disconnecting while on a trusted network");
+        config = ClientConfig::from_file_with_profile(&path, current.as_deref())?;
+      }
+    }
+  }
+}
+
+/// Waits for `vpn-client up` to ask for a profile the configuration has.
+async fn next_profile(control: Option<&ProfileControl>, path: &str) -> (String, ClientConfig) {
+  let Some(control) = control else {
+    return std::future::pending().await;
+  };
+
+  loop {
+    let name = control.recv().await;
+    match ClientConfig::from_file_with_profile(path, Some(&name)) {
+      Ok(config) => {
+        info!("Switching to profile {}", name);
+        return (name, config);
+      }
+      Err(e) => error!("Not switching to profile {}: {}", name, e),
+    }
+  }
+}
+
+async fn build(
+  config: ClientConfig,
+  routes: Receiver<Vec<Ipv4Net>>,
+  session: &Sender<SessionParams>,
+  state: &Path,
+) -> anyhow::Result<Client> {
+  let endpoint = config.endpoint().await?;
+  session.send_replace(SessionParams { transforms: endpoint.transforms.clone(), ..config.session_params() });
+  let mut builder = Client::builder(endpoint.address, endpoint.port)
     .with_listen_address(config.listen_address, config.listen_port)
     .with_connect_timeout(config.connect_timeout())
-    .with_tun_config(config.tun_config())
-    .with_creds(config.credentials)
-    .build()
-    .await?;
+    .with_tun_config(config.tun_config()?)
+    .with_route_updates(routes)
+    .with_subnets(config.subnets)
+    .with_session_params(session.subscribe())
+    .with_ecn(config.ecn)
+    .with_outer(config.outer)
+    .with_mtu_fallback(config.mtu_fallback)
+    .with_accept_dns(config.accept_dns)
+    .with_transforms(endpoint.transforms)
+    .with_system_state(SystemState::new(state.to_path_buf()));
 
-  client.run().await?;
+  if let Some(host) = endpoint.host {
+    builder = builder.with_server_host(host);
+  }
 
-  Ok(())
+  if let Some(rendezvous) = config.rendezvous {
+    builder = builder.with_rendezvous(rendezvous);
+  }
+
+  if let Some(ref resolver) = config.resolver {
+    builder = builder.with_resolver(resolver.build()?);
+  }
+
+  if let Some(tcp_fallback) = config.tcp_fallback {
+    builder = builder.with_tcp_fallback(tcp_fallback);
+  }
+
+  if let Some(kill_switch) = config.kill_switch {
+    builder = builder.with_kill_switch(kill_switch);
+  }
+
+  if let Some(kbps) = config.max_upload_kbps {
+    builder = builder.with_max_upload_kbps(kbps);
+  }
+
+  if let Some(kbps) = config.max_download_kbps {
+    builder = builder.with_max_download_kbps(kbps);
+  }
+
+  if let Some(ref resume) = config.resume {
+    let server = SocketAddr::new(endpoint.address.into(), endpoint.port);
+    builder = builder.with_ticket_store(TicketStore::open(resume, server)?);
+  }
+
+  if let Some(lan_access) = config.lan_access {
+    builder = builder.with_lan_access(lan_access);
+  }
+  if let Some(gateway) = config.gateway {
+    builder = builder.with_gateway(gateway);
+  }
+  if !config.forwards.is_empty() {
+    builder = builder.with_forwards(config.forwards);
+  }
+  if !config.reverse_forwards.is_empty() {
+    builder = builder.with_reverse_forwards(config.reverse_forwards);
+  }
+  builder = builder.with_peer_encryption(config.peer_encryption);
+  if let Some(hostname) = config.hostname {
+    builder = builder.with_hostname(hostname);
+  }
+  if let Some(idle_suspend) = config.idle_suspend {
+    builder = builder.with_idle_suspend(idle_suspend);
+  }
+
+  if let Some(port_mapping) = config.port_mapping {
+    builder = builder.with_port_mapping(port_mapping);
+  }
+
+  if config.reconnect.enabled {
+    builder = builder.with_reconnect(config.reconnect.backoff());
+  }
+
+  if let Some(credentials) = config.credentials {
+    builder = builder.with_creds(credentials);
+  }
+
+  if let Some(oidc) = config.oidc {
+    #[cfg(feature = "oidc")]
+    {
+      builder = builder.with_creds(Credentials::Token(vpn_client::oidc::login(&oidc).await?));
+    }
+    #[cfg(not(feature = "oidc"))]
+    anyhow::bail!(
+      "OIDC sign in with {} is configured, but the client was built without the oidc feature",
+      oidc.issuer
+    );
+  }
+
+  if let Some(certificate) = config.certificate {
+    let contents = std::fs::read_to_string(&certificate.path)
+      .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", certificate.path.display(), e))?;
+    let parsed = Certificate::decode(&contents)?;
+
+    match parsed.expires_at().duration_since(SystemTime::now()) {
+      Ok(left) if left < RENEW_WARNING => {
+        warn!("Certificate expires in {} days; renew it", left.as_secs() / 86400)
+      }
+      Ok(_) => {}
+      Err(_) => warn!("Certificate has expired; renew it with `vpn-server ca issue`"),
+    }
+
+    builder = builder.with_certificate(parsed, KeyPair::from_secret(certificate.private_key));
+  }
+
+  if let Some(key) = config.key {
+    builder = builder.with_key(key.username, KeyPair::from_secret(key.private_key));
+  }
+
+  if let Some(key) = endpoint.public_key {
+    builder = builder.with_server_public_key(key);
+  }
+
+  if let Some(description) = config.tun.description {
+    builder = builder.with_tun_description(description);
+  }
+  builder = builder.with_tun_gso(config.tun.gso);
+
+  builder.build().await
 }
 
 fn main() {
   let args = Args::parse();
-  setup_logging();
 
   if let Err(e) = real_main(args) {
+    // Errors before the configured logging is up still need to be seen.
+    _ = tracing_subscriber::fmt().try_init();
     error!("{}", e);
+    std::process::exit(1);
   }
 }
-
-fn setup_logging() {
-  tracing_subscriber::fmt().init();
-}
@@ -0,0 +1,118 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct OidcConfig {
+  /// Issuer URL; the device authorization and token endpoints come from its discovery document.
+  pub issuer: String,
+
+  pub client_id: String,
+
+  #[serde(default = "default_scope")]
+  pub scope: String,
+}
+
+fn default_scope() -> String {
+  "openid profile".to_string()
+}
+
+#[cfg(feature = "oidc")]
+pub use device::login;
+
+#[cfg(feature = "oidc")]
+mod device {
+  use std::time::Duration;
+
+  use serde::Deserialize;
+  use tracing::info;
+
+  use super::OidcConfig;
+
+  const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+  #[derive(Deserialize)]
+  struct Discovery {
+    device_authorization_endpoint: String,
+    token_endpoint: String,
+  }
+
+  #[derive(Deserialize)]
+  struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    #[serde(default)]
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+  }
+
+  fn default_interval() -> u64 {
+    5
+  }
+
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum TokenResponse {
+    Token { access_token: String },
+    Error { error: String },
+  }
+
+  /// Runs the OAuth device flow (RFC 8628): prints where to sign in, then waits until the user has and
+  /// returns the access token.
+  pub async fn login(config: &OidcConfig) -> anyhow::Result<String> {
+    let http = reqwest::Client::new();
+
+    let discovery = format!("{}/.well-known/openid-configuration", config.issuer.trim_end_matches('/'));
+    let discovery: Discovery = http.get(discovery).send().await?.error_for_status()?.json().await?;
+
+    let authorization: DeviceAuthorization = http
+      .post(&discovery.device_authorization_endpoint)
+      .form(&[("client_id", config.client_id.as_str()), ("scope", config.scope.as_str())])
+      .send()
+      .await?
+      .error_for_status()?
+      .json()
+      .await?;
+
+    match authorization.verification_uri_complete {
+      Some(ref uri) => println!("To sign in, open {}", uri),
+      None => println!(
+        "To sign in, open {} and enter the code {}",
+        authorization.verification_uri, authorization.user_code
+      ),
+    }
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = Duration::from_secs(authorization.interval);
+
+    while tokio::time::Instant::now() < deadline {
+      tokio::time::sleep(interval).await;
+
+      let response: TokenResponse = http
+        .post(&discovery.token_endpoint)
+        .form(&[
+          ("grant_type", DEVICE_CODE_GRANT),
+          ("device_code", authorization.device_code.as_str()),
+          ("client_id", config.client_id.as_str()),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+
+      match response {
+        TokenResponse::Token { access_token } => {
+          info!("Signed in with {}", config.issuer);
+          return Ok(access_token);
+        }
+        TokenResponse::Error { error } if error == "authorization_pending" => {}
+        TokenResponse::Error { error } if error == "slow_down" => interval += Duration::from_secs(5),
+        TokenResponse::Error { error } => anyhow::bail!("Sign in failed: {}", error),
+      }
+    }
+
+    anyhow::bail!("Sign in timed out")
+  }
+}
@@ -0,0 +1,285 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tracing::info;
+use tracing::warn;
+
+use crate::events::ClientEvent;
+
+const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Asks the local gateway to forward a public port to the client's listen port, so the client can be
+/// reached directly. NAT-PMP is always tried; UPnP needs the `upnp` feature.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PortMappingConfig {
+  /// Public port to ask for; the gateway may pick another one. Defaults to the listen port.
+  #[serde(default)]
+  pub external_port: Option<u16>,
+
+  #[serde(default = "default_lease_secs")]
+  pub lease_secs: u32,
+}
+
+fn default_lease_secs() -> u32 {
+  3600
+}
+
+impl Default for PortMappingConfig {
+  fn default() -> Self {
+    Self { external_port: None, lease_secs: default_lease_secs() }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+  NatPmp,
+  Upnp,
+}
+
+impl fmt::Display for Method {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::NatPmp => "NAT-PMP",
+      Self::Upnp => "UPnP",
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+  pub method: Method,
+  /// Unspecified when the gateway didn't report its public address.
+  pub external: SocketAddrV4,
+  pub lifetime: Duration,
+}
+
+/// Keeps a mapping for `local_port` alive for as long as the task runs, renewing it halfway through each
+/// lease and retrying periodically while no gateway grants one.
+pub async fn maintain(config: PortMappingConfig, local_port: u16, events: broadcast::Sender<ClientEvent>) {
+  let mut current: Option<Mapping> = None;
+
+  loop {
+    let wait = match map(&config, local_port).await {
+      Ok(mapping) => {
+        if current.map(|m| (m.method, m.external)) != Some((mapping.method, mapping.external)) {
+          info!("Mapped {} to local port {} with {}", mapping.external, local_port, mapping.method);
+          _ = events.send(ClientEvent::PortMapped { method: mapping.method, external: mapping.external });
+        }
+        current = Some(mapping);
+        mapping.lifetime / 2
+      }
+      Err(e) => {
+        warn!("Failed to map port {} on the gateway: {}", local_port, e);
+        _ = events.send(ClientEvent::PortMappingFailed { reason: e.to_string() });
+        current = None;
+        RETRY_INTERVAL
+      }
+    };
+
+    tokio::time::sleep(wait.max(Duration::from_secs(30))).await;
+  }
+}
+
+pub async fn map(config: &PortMappingConfig, local_port: u16) -> anyhow::Result<Mapping> {
+  let external_port = config.external_port.unwrap_or(local_port);
+  let gateway = default_gateway()?;
+
+  let nat_pmp = nat_pmp::map(gateway, local_port, external_port, config.lease_secs).await;
+  let error = match nat_pmp {
+    Ok(mapping) => return Ok(mapping),
+    Err(e) => e,
+  };
+
+  #[cfg(feature = "upnp")]
+  let upnp = upnp::map(gateway, local_port, external_port, config.lease_secs).await;
+  #[cfg(not(feature = "upnp"))]
+  let upnp: anyhow::Result<Mapping> = Err(anyhow::anyhow!("built without the upnp feature"));
+
+  upnp.map_err(|e| anyhow::anyhow!("NAT-PMP: {}; UPnP: {}", error, e))
+}
+
+/// Address of the gateway the default route goes through.
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+  #[cfg(target_os = "linux")]
+  {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    parse_proc_route(&routes).ok_or(anyhow::anyhow!("No default route"))
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  {
+    let output = std::process::Command::new("route").args(["-n", "get", "default"]).output()?;
+    String::from_utf8_lossy(&output.stdout)
+      .lines()
+      .find_map(|line| line.trim().strip_prefix("gateway:"))
+      .and_then(|gateway| gateway.trim().parse().ok())
+      .ok_or(anyhow::anyhow!("No default route"))
+  }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_route(routes: &str) -> Option<Ipv4Addr> {
+  routes.lines().skip(1).find_map(|line| {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    match fields[..] {
+      [_, "00000000", gateway, ..] => {
+        u32::from_str_radix(gateway, 16).ok().map(|g| Ipv4Addr::from(g.swap_bytes()))
+      }
+      _ => None,
+    }
+  })
+}
+
+mod nat_pmp {
+  use super::*;
+
+  const OP_EXTERNAL_ADDRESS: u8 = 0;
+  const OP_MAP_UDP: u8 = 1;
+
+  pub async fn map(
+    gateway: Ipv4Addr,
+    local_port: u16,
+    external_port: u16,
+    lease: u32,
+  ) -> anyhow::Result<Mapping> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+
+    let response = request(&socket, &map_request(local_port, external_port, lease)).await?;
+    let (port, lifetime) = parse_map_response(&response)?;
+
+    // The address is only informational, so a gateway that won't tell isn't an error.
+    let address = match request(&socket, &[0, OP_EXTERNAL_ADDRESS]).await {
+      Ok(response) => parse_address_response(&response).unwrap_or(Ipv4Addr::UNSPECIFIED),
+      Err(_) => Ipv4Addr::UNSPECIFIED,
+    };
+
+    Ok(Mapping {
+      method: Method::NatPmp,
+      external: SocketAddrV4::new(address, port),
+      lifetime: Duration::from_secs(lifetime.into()),
+    })
+  }
+
+  async fn request(socket: &UdpSocket, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    socket.send(request).await?;
+    let mut buf = [0u8; 16];
+    match tokio::time::timeout(NAT_PMP_TIMEOUT, socket.recv(&mut buf)).await {
+      Ok(len) => Ok(buf[..len?].to_vec()),
+      Err(_) => anyhow::bail!("Gateway didn't answer"),
+    }
+  }
+
+  pub(super) fn map_request(local_port: u16, external_port: u16, lease: u32) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = OP_MAP_UDP;
+    request[4..6].copy_from_slice(&local_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&lease.to_be_bytes());
+    request
+  }
+
+  fn check(response: &[u8], op: u8, len: usize) -> anyhow::Result<()> {
+    if response.len() < len || response[0] != 0 || response[1] != 128 + op {
+      anyhow::bail!("Malformed response");
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+      0 => Ok(()),
+      2 => anyhow::bail!("Not authorized"),
+      3 => anyhow::bail!("Gateway has no public address"),
+      4 => anyhow::bail!("Out of resources"),
+      code => anyhow::bail!("Refused with result code {}", code),
+    }
+  }
+
+  /// External port and lifetime in seconds.
+  pub(super) fn parse_map_response(response: &[u8]) -> anyhow::Result<(u16, u32)> {
+    check(response, OP_MAP_UDP, 16)?;
+    let port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((port, lifetime))
+  }
+
+  pub(super) fn parse_address_response(response: &[u8]) -> anyhow::Result<Ipv4Addr> {
+    check(response, OP_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+  }
+}
+
+#[cfg(feature = "upnp")]
+mod upnp {
+  use std::net::IpAddr;
+  use std::net::SocketAddr;
+
+  use igd_next::aio::tokio::search_gateway;
+  use igd_next::PortMappingProtocol;
+  use igd_next::SearchOptions;
+
+  use super::*;
+
+  pub async fn map(
+    gateway: Ipv4Addr,
+    local_port: u16,
+    external_port: u16,
+    lease: u32,
+  ) -> anyhow::Result<Mapping> {
+    let options = SearchOptions { timeout: Some(Duration::from_secs(5)), ..Default::default() };
+    let igd = search_gateway(options).await?;
+
+    // The gateway forwards to our LAN address, which is whatever the kernel picks to reach it.
+    let probe = UdpSocket::bind("0.0.0.0:0").await?;
+    probe.connect((gateway, 1)).await?;
+    let local = SocketAddr::new(probe.local_addr()?.ip(), local_port);
+
+    igd.add_port(PortMappingProtocol::UDP, external_port, local, lease, "sberlinux vpn-client").await?;
+    let address = match igd.get_external_ip().await {
+      Ok(IpAddr::V4(address)) => address,
+      _ => Ipv4Addr::UNSPECIFIED,
+    };
+
+    Ok(Mapping {
+      method: Method::Upnp,
+      external: SocketAddrV4::new(address, external_port),
+      lifetime: Duration::from_secs(lease.into()),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_proc_route() {
+    let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                  eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n\
+                  eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\n";
+    assert_eq!(parse_proc_route(routes), Some(Ipv4Addr::new(192, 168, 0, 1)));
+    assert_eq!(parse_proc_route("Iface\tDestination\n"), None);
+  }
+
+  #[test]
+  fn test_nat_pmp() {
+    assert_eq!(
+      nat_pmp::map_request(6969, 7000, 3600),
+      [0, 1, 0, 0, 0x1b, 0x39, 0x1b, 0x58, 0, 0, 0x0e, 0x10]
+    );
+
+    let response = [0, 129, 0, 0, 0, 0, 0, 9, 0x1b, 0x39, 0x1b, 0x59, 0, 0, 0x07, 0x08];
+    assert_eq!(nat_pmp::parse_map_response(&response).unwrap(), (7001, 1800));
+
+    let refused = [0, 129, 0, 2, 0, 0, 0, 9, 0x1b, 0x39, 0, 0, 0, 0, 0, 0];
+    assert!(nat_pmp::parse_map_response(&refused).unwrap_err().to_string().contains("Not authorized"));
+
+    let address = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+    assert_eq!(nat_pmp::parse_address_response(&address).unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+  }
+}
@@ -0,0 +1,179 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_yml::Value;
+use tokio::net::UdpSocket;
+use tracing::warn;
+use vpn_shared::handshake;
+use vpn_shared::packet;
+
+const PROFILES: &str = "profiles";
+
+/// How long `up` waits for a running client to confirm a switch before starting one itself.
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Top-level settings of `config` with those of profile `name` put over them; other profiles are dropped, so
+/// editing them doesn't concern a client connected with this one.
+pub fn apply(mut config: Value, name: &str) -> anyhow::Result<Value> {
+  let Some(mapping) = config.as_mapping_mut() else {
+    anyhow::bail!("Configuration is not a mapping");
+  };
+
+  let profiles = mapping.remove(PROFILES).unwrap_or_default();
+  let profile = match profiles.get(name) {
+    Some(Value::Mapping(profile)) => profile.clone(),
+    Some(_) => anyhow::bail!("Profile {} is not a mapping", name),
+    None => {
+      let names: Vec<_> =
+        profiles.as_mapping().into_iter().flat_map(|p| p.keys().filter_map(Value::as_str)).collect();
+      anyhow::bail!("Unknown profile {}; configured: {}", name, names.join(", "))
+    }
+  };
+
+  for (key, value) in profile {
+    if key.as_str() != Some(PROFILES) {
+      mapping.insert(key, value);
+    }
+  }
+  Ok(config)
+}
+
+/// Lets `vpn-client up <profile>` switch the profile of a client already running with the same configuration:
+/// the running client listens on loopback and leaves the port, with a token the request has to carry, in a
+/// file next to the configuration.
+pub struct ProfileControl {
+  socket: UdpSocket,
+  token: String,
+  path: PathBuf,
+}
+
+impl ProfileControl {
+  pub async fn bind(config: &Path) -> anyhow::Result<Self> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let mut token = [0; 16];
+    packet::fill_random_bytes(&mut token);
+    let token = handshake::encode_hex(&token);
+
+    let path = control_path(config);
+    write_private(&path, format!("{} {}", socket.local_addr()?.port(), token).as_bytes())
+      .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(Self { socket, token, path })
+  }
+
+  /// Waits for a request to switch and returns the profile it asks for.
+  pub async fn recv(&self) -> String {
+    let mut buf = [0; 512];
+    loop {
+      let (len, from) = match self.socket.recv_from(&mut buf).await {
+        Ok(received) => received,
+        Err(e) => {
+          warn!("Failed to receive a profile switch: {}", e);
+          continue;
+        }
+      };
+
+      let request = String::from_utf8_lossy(&buf[..len]);
+      match request.split_once(' ') {
+        Some((token, profile)) if token == self.token => {
+          _ = self.socket.send_to(b"ok", from).await;
+          return profile.to_string();
+        }
+        _ => warn!("Ignoring a profile switch from {} without a valid token", from),
+      }
+    }
+  }
+}
+
+impl Drop for ProfileControl {
+  fn drop(&mut self) {
+    _ = std::fs::remove_file(&self.path);
+  }
+}
+
+/// Asks a client running with `config` to switch to `profile`; `false` if none answered.
+pub async fn request_switch(config: &Path, profile: &str) -> anyhow::Result<bool> {
+  let Ok(contents) = std::fs::read_to_string(control_path(config)) else {
+    return Ok(false);
+  };
+  let Some((port, token)) = contents.trim().split_once(' ') else {
+    return Ok(false);
+  };
+  let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port.parse()?));
+
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+  socket.send_to(format!("{} {}", token, profile).as_bytes(), address).await?;
+
+  let mut buf = [0; 16];
+  match tokio::time::timeout(SWITCH_TIMEOUT, socket.recv_from(&mut buf)).await {
+    Ok(Ok((len, from))) => Ok(from == address && &buf[..len] == b"ok"),
+    // Left behind by a client that didn't exit cleanly.
+    _ => Ok(false),
+  }
+}
+
+fn control_path(config: &Path) -> PathBuf {
+  let mut path = config.as_os_str().to_owned();
+  path.push(".control");
+  PathBuf::from(path)
+}
+
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_apply() {
+    let config: Value = serde_yml::from_str(
+      r#"
+        server-address: "10.0.0.1"
+        routes: ["10.8.0.0/16"]
+        profiles:
+          work:
+            routes: ["10.20.0.0/16", "10.21.0.0/16"]
+          exit-via-eu:
+            server-address: "203.0.113.5"
+            routes: ["0.0.0.0/1", "128.0.0.0/1"]
+      "#,
+    )
+    .unwrap();
+
+    let work = apply(config.clone(), "work").unwrap();
+    assert_eq!(work["server-address"].as_str(), Some("10.0.0.1"));
+    assert_eq!(work["routes"].as_sequence().unwrap().len(), 2);
+
+    let eu = apply(config.clone(), "exit-via-eu").unwrap();
+    assert_eq!(eu["server-address"].as_str(), Some("203.0.113.5"));
+
+    let error = apply(config, "home").unwrap_err().to_string();
+    assert!(error.contains("configured: work, exit-via-eu"), "{}", error);
+  }
+
+  #[tokio::test]
+  async fn test_switch() {
+    let dir = std::env::temp_dir().join(format!("vpn-client-profile-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("client.yml");
+
+    assert!(!request_switch(&config, "home").await.unwrap());
+
+    let control = ProfileControl::bind(&config).await.unwrap();
+    let (switched, profile) = tokio::join!(request_switch(&config, "home"), control.recv());
+    assert!(switched.unwrap());
+    assert_eq!(profile, "home");
+
+    drop(control);
+    assert!(!control_path(&config).exists());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,33 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use vpn_shared::rendezvous;
+use vpn_shared::socket::Socket;
+
+/// Looks the server up with the rendezvous it registers with on every connect, for servers behind NAT
+/// without port forwarding, see `vpn_shared::rendezvous`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RendezvousConfig {
+  /// `host:port` of the rendezvous.
+  pub address: String,
+  /// Name the server registers as.
+  pub name: String,
+  /// Map the listen port on the local gateway, unless `port-mapping` says how, so the server's punches
+  /// reach the client through NATs that would drop them otherwise.
+  #[serde(default = "crate::config::default_true")]
+  pub port_mapping: bool,
+}
+
+impl RendezvousConfig {
+  /// Address of the server for the key exchange sent from `socket`, whose address the server is introduced
+  /// to so that it opens its NAT to it.
+  pub async fn locate(&self, socket: &Socket, timeout: Duration) -> anyhow::Result<SocketAddr> {
+    let rendezvous = tokio::net::lookup_host(&self.address)
+      .await?
+      .find(SocketAddr::is_ipv4)
+      .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", self.address))?;
+    rendezvous::lookup(socket, rendezvous, &self.name, timeout).await
+  }
+}
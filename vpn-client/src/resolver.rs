@@ -0,0 +1,208 @@
+//! Lookup of the server's hostname. The system resolver is whatever the network handed out, which on a
+//! hostile one can answer with another server or log the name; DNS over HTTPS or TLS (with the `hickory`
+//! feature) and static hosts keep the lookup away from it.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use serde::Deserialize;
+use tokio::net::lookup_host;
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Vec<IpAddr>>> + Send + 'a>>;
+
+/// Resolves hostnames to their addresses, in the order the source gave them.
+pub trait Resolver: Send + Sync {
+  fn name(&self) -> &str;
+  fn lookup<'a>(&'a self, host: &'a str) -> ResolveFuture<'a>;
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case", rename_all_fields = "kebab-case")]
+pub enum ResolverConfig {
+  System,
+  /// Fixed addresses; other names aren't resolved at all.
+  Hosts {
+    hosts: BTreeMap<String, Vec<IpAddr>>,
+  },
+  /// DNS over HTTPS to `servers`, whose certificates have to be valid for `tls-name`.
+  Https {
+    servers: Vec<IpAddr>,
+    tls_name: String,
+    #[serde(default = "default_https_port")]
+    port: u16,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+  },
+  /// DNS over TLS, like `Https`.
+  Tls {
+    servers: Vec<IpAddr>,
+    tls_name: String,
+    #[serde(default = "default_tls_port")]
+    port: u16,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+  },
+}
+
+fn default_https_port() -> u16 {
+  443
+}
+
+fn default_tls_port() -> u16 {
+  853
+}
+
+fn default_timeout_secs() -> u64 {
+  5
+}
+
+impl ResolverConfig {
+  pub fn build(&self) -> anyhow::Result<Box<dyn Resolver>> {
+    match self {
+      Self::System => Ok(Box::new(System)),
+      Self::Hosts { hosts } => Ok(Box::new(StaticHosts::new(hosts.clone()))),
+      #[cfg(feature = "hickory")]
+      Self::Https { .. } | Self::Tls { .. } => Ok(Box::new(hickory::Encrypted::new(self)?)),
+      #[cfg(not(feature = "hickory"))]
+      Self::Https { .. } | Self::Tls { .. } => {
+        anyhow::bail!("DNS over HTTPS and TLS need the client built with `--features hickory`")
+      }
+    }
+  }
+}
+
+/// Resolver of the operating system, through `getaddrinfo`.
+pub struct System;
+
+impl Resolver for System {
+  fn name(&self) -> &str {
+    "system"
+  }
+
+  fn lookup<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+    Box::pin(async move { Ok(lookup_host((host, 0)).await?.map(|addr| addr.ip()).collect()) })
+  }
+}
+
+pub struct StaticHosts {
+  hosts: BTreeMap<String, Vec<IpAddr>>,
+}
+
+impl StaticHosts {
+  /// Names are matched regardless of case and a trailing dot.
+  pub fn new(hosts: BTreeMap<String, Vec<IpAddr>>) -> Self {
+    Self { hosts: hosts.into_iter().map(|(name, addresses)| (normalize(&name), addresses)).collect() }
+  }
+}
+
+impl Resolver for StaticHosts {
+  fn name(&self) -> &str {
+    "static hosts"
+  }
+
+  fn lookup<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+    Box::pin(async move {
+      match self.hosts.get(&normalize(host)) {
+        Some(addresses) => Ok(addresses.clone()),
+        None => anyhow::bail!("{} isn't one of the static hosts", host),
+      }
+    })
+  }
+}
+
+fn normalize(name: &str) -> String {
+  name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(feature = "hickory")]
+mod hickory {
+  use std::time::Duration;
+
+  use hickory_resolver::config::NameServerConfigGroup;
+  use hickory_resolver::config::ResolverOpts;
+  use hickory_resolver::TokioAsyncResolver;
+
+  use super::ResolveFuture;
+  use super::Resolver;
+  use super::ResolverConfig;
+
+  /// DNS over HTTPS or TLS; nothing is sent in the clear, and answers only come from the configured servers.
+  pub struct Encrypted {
+    name: &'static str,
+    resolver: TokioAsyncResolver,
+  }
+
+  impl Encrypted {
+    pub fn new(config: &ResolverConfig) -> anyhow::Result<Self> {
+      let (name, servers, timeout_secs) = match config {
+        ResolverConfig::Https { servers, tls_name, port, timeout_secs } => {
+          let group = NameServerConfigGroup::from_ips_https(servers, *port, tls_name.clone(), true);
+          ("DNS over HTTPS", group, timeout_secs)
+        }
+        ResolverConfig::Tls { servers, tls_name, port, timeout_secs } => {
+          let group = NameServerConfigGroup::from_ips_tls(servers, *port, tls_name.clone(), true);
+          ("DNS over TLS", group, timeout_secs)
+        }
+        _ => anyhow::bail!("Not an encrypted resolver"),
+      };
+      if servers.is_empty() {
+        anyhow::bail!("{} needs at least one server", name);
+      }
+
+      let mut options = ResolverOpts::default();
+      options.timeout = Duration::from_secs(*timeout_secs);
+      let config = hickory_resolver::config::ResolverConfig::from_parts(None, Vec::new(), servers);
+      Ok(Self { name, resolver: TokioAsyncResolver::tokio(config, options) })
+    }
+  }
+
+  impl Resolver for Encrypted {
+    fn name(&self) -> &str {
+      self.name
+    }
+
+    fn lookup<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+      Box::pin(async move { Ok(self.resolver.lookup_ip(host).await?.iter().collect()) })
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_static_hosts() {
+    let config: ResolverConfig = serde_yml::from_str(
+      r#"
+        type: hosts
+        hosts:
+          vpn.example.com: ['2001:db8::1', '192.0.2.1']
+      "#,
+    )
+    .unwrap();
+    let resolver = config.build().unwrap();
+    let expected: Vec<IpAddr> = vec!["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()];
+    assert_eq!(resolver.lookup("VPN.example.com.").await.unwrap(), expected);
+    assert!(resolver.lookup("example.com").await.is_err());
+  }
+
+  #[test]
+  fn test_config() {
+    let config: ResolverConfig = serde_yml::from_str(
+      r#"
+        type: https
+        servers: ['1.1.1.1', '1.0.0.1']
+        tls-name: 'cloudflare-dns.com'
+      "#,
+    )
+    .unwrap();
+    let ResolverConfig::Https { servers, port, timeout_secs, .. } = config else {
+      panic!("Expected DNS over HTTPS");
+    };
+    assert_eq!((servers.len(), port, timeout_secs), (2, 443, 5));
+    assert_eq!(serde_yml::from_str::<ResolverConfig>("type: system").unwrap(), ResolverConfig::System);
+  }
+}
@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+use vpn_shared::handshake;
+use vpn_shared::packet::fill_random_bytes;
+use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::HANDSHAKE_SESSION;
+
+use crate::profile::write_private;
+
+/// Keeps the session ticket of servers issuing them across restarts of the client, so it can resume the
+/// session without the user logging in again. Setting it up is consenting to that: whoever can read both
+/// files can connect as the user until the ticket expires.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResumeConfig {
+  /// Where the encrypted ticket is kept.
+  pub path: PathBuf,
+
+  /// Hex-encoded key the ticket is encrypted with, created on first use; best kept apart from `path`, e.g.
+  /// out of backups.
+  pub key_file: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+  /// Server the ticket is from; others wouldn't take it.
+  server: SocketAddr,
+  /// Seconds since the Unix epoch.
+  expires_at: u64,
+  ticket: Vec<u8>,
+}
+
+pub struct TicketStore {
+  path: PathBuf,
+  key: Key,
+  server: SocketAddr,
+}
+
+impl TicketStore {
+  /// Store of the ticket of `server`, creating the key if there's none yet.
+  pub fn open(config: &ResumeConfig, server: SocketAddr) -> anyhow::Result<Self> {
+    let key = match std::fs::read_to_string(&config.key_file) {
+      Ok(contents) => handshake::parse_key(contents.trim())?,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => create_key(&config.key_file)?,
+      Err(e) => anyhow::bail!("Failed to read {}: {}", config.key_file.display(), e),
+    };
+    Ok(Self { path: config.path.clone(), key, server })
+  }
+
+  /// The stored ticket, unless there's none for this server or it expired.
+  pub fn load(&self) -> Option<Vec<u8>> {
+    let sealed = std::fs::read(&self.path).ok()?;
+    let stored = EncryptedPacket::from_bytes(&sealed).and_then(|packet| packet.decrypt::<Stored>(&self.key));
+    let stored = match stored {
+      Ok(stored) => stored,
+      Err(e) => {
+        warn!("Ignoring the session ticket in {}: {}", self.path.display(), e);
+        return None;
+      }
+    };
+    (stored.server == self.server && stored.expires_at > handshake::unix_time()).then_some(stored.ticket)
+  }
+
+  pub fn save(&self, ticket: Vec<u8>, lifetime: Duration) -> anyhow::Result<()> {
+    let stored =
+      Stored { server: self.server, expires_at: handshake::unix_time() + lifetime.as_secs(), ticket };
+    let sealed = EncryptedPacket::encrypt(&self.key, HANDSHAKE_SESSION, &stored)?.to_bytes();
+    write_private(&self.path, &sealed)
+      .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", self.path.display(), e))
+  }
+
+  /// Forgets the ticket once the server refused it or ended the session for good.
+  pub fn clear(&self) {
+    if let Err(e) = std::fs::remove_file(&self.path) {
+      if e.kind() != std::io::ErrorKind::NotFound {
+        warn!("Failed to remove {}: {}", self.path.display(), e);
+      }
+    }
+  }
+}
+
+fn create_key(path: &Path) -> anyhow::Result<Key> {
+  let mut key = [0u8; 32];
+  fill_random_bytes(&mut key);
+  write_private(path, handshake::encode_key(&key).as_bytes())
+    .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+  Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_store() {
+    let dir = std::env::temp_dir().join(format!("vpn-resume-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = ResumeConfig { path: dir.join("ticket"), key_file: dir.join("ticket.key") };
+    let server = "10.0.0.1:9696".parse().unwrap();
+
+    let store = TicketStore::open(&config, server).unwrap();
+    assert_eq!(store.load(), None);
+    store.save(vec![1, 2, 3], Duration::from_secs(60)).unwrap();
+    assert_eq!(TicketStore::open(&config, server).unwrap().load(), Some(vec![1, 2, 3]));
+
+    // Encrypted, and only handed to the server it's from.
+    assert!(!std::fs::read(&config.path).unwrap().windows(3).any(|w| w == [1, 2, 3]));
+    assert_eq!(TicketStore::open(&config, "10.0.0.2:9696".parse().unwrap()).unwrap().load(), None);
+    std::fs::write(&config.key_file, handshake::encode_key(&[7; 32])).unwrap();
+    assert_eq!(TicketStore::open(&config, server).unwrap().load(), None);
+
+    store.save(vec![1, 2, 3], Duration::ZERO).unwrap();
+    assert_eq!(store.load(), None);
+    store.clear();
+    assert!(!config.path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,176 @@
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::Ipv4Net;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
+
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::events::ClientEvent;
+use crate::lan;
+
+const RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub async fn install(route: &Ipv4Net, dev: &str) -> anyhow::Result<()> {
+  let output = Command::new("ip").args(["route", "replace", &route.to_string(), "dev", dev]).output().await?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "Failed to install route {} via {}: {}",
+      route,
+      dev,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(())
+}
+
+pub async fn remove(route: &Ipv4Net, dev: &str) -> anyhow::Result<()> {
+  let output = Command::new("ip").args(["route", "del", &route.to_string(), "dev", dev]).output().await?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "Failed to remove route {} via {}: {}",
+      route,
+      dev,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(())
+}
+
+/// Removes every route into `dev`.
+pub async fn flush(dev: &str) -> anyhow::Result<()> {
+  let output = Command::new("ip").args(["route", "flush", "dev", dev]).output().await?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "Failed to remove the routes via {}: {}",
+      dev,
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(())
+}
+
+pub async fn is_installed(route: &Ipv4Net, dev: &str) -> anyhow::Result<bool> {
+  let output = Command::new("ip").args(["route", "show", "exact", &route.to_string()]).output().await?;
+  Ok(routes_via(&String::from_utf8_lossy(&output.stdout), dev))
+}
+
+fn routes_via(output: &str, dev: &str) -> bool {
+  output.lines().any(|line| line.split_whitespace().collect::<Vec<_>>().windows(2).any(|w| w == ["dev", dev]))
+}
+
+/// Re-installs `routes` whenever the routing table changes under us, e.g. after a DHCP renewal or a
+/// NetworkManager reconfiguration. Changes are picked up from `ip monitor route` with a periodic recheck
+/// as a fallback. Routes sent through `updates` replace the current ones; `bypassed` ranges are cut out of
+/// all of them. While `paused` is set the routes are left down, and updates are only taken note of.
+pub async fn monitor(
+  mut updates: watch::Receiver<Vec<Ipv4Net>>,
+  bypassed: Vec<Ipv4Net>,
+  dev: String,
+  events: broadcast::Sender<ClientEvent>,
+  paused: Arc<AtomicBool>,
+) {
+  let mut routes = lan::exclude(&updates.borrow_and_update(), &bypassed);
+  let mut updates_open = true;
+
+  let mut monitor = match Command::new("ip")
+    .args(["monitor", "route"])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .kill_on_drop(true)
+    .spawn()
+  {
+    Ok(child) => Some(child),
+    Err(e) => {
+      warn!("Failed to watch routing table changes, falling back to polling: {}", e);
+      None
+    }
+  };
+
+  let mut lines =
+    monitor.as_mut().and_then(|child| child.stdout.take()).map(|out| BufReader::new(out).lines());
+  let mut interval = tokio::time::interval(RECHECK_INTERVAL);
+
+  loop {
+    tokio::select! {
+      line = async { lines.as_mut().unwrap().next_line().await }, if lines.is_some() => {
+        if !matches!(line, Ok(Some(_))) {
+          warn!("Routing table monitor exited, falling back to polling");
+          lines = None;
+        }
+      }
+      _ = interval.tick() => {}
+      changed = updates.changed(), if updates_open => {
+        if changed.is_err() {
+          updates_open = false;
+          continue;
+        }
+
+        let new = lan::exclude(&updates.borrow_and_update(), &bypassed);
+        if paused.load(Ordering::Relaxed) {
+          routes = new;
+          continue;
+        }
+        for route in routes.iter().filter(|route| !new.contains(route)) {
+          match remove(route, &dev).await {
+            Ok(()) => info!("Removed route {} via {}", route, dev),
+            Err(e) => error!("{}", e),
+          }
+        }
+        let added: Vec<_> = new.iter().filter(|route| !routes.contains(route)).copied().collect();
+        if let Err(e) = install_all(&added, &dev).await {
+          error!("{}", e);
+        }
+        routes = new;
+      }
+    }
+
+    if paused.load(Ordering::Relaxed) {
+      continue;
+    }
+    for route in &routes {
+      match is_installed(route, &dev).await {
+        Ok(true) => {}
+        Ok(false) => {
+          warn!("Route {} via {} was removed or overwritten by something else; re-installing", route, dev);
+          match install(route, &dev).await {
+            Ok(()) => _ = events.send(ClientEvent::RouteRepaired { route: *route }),
+            Err(e) => error!("{}", e),
+          }
+        }
+        Err(e) => error!("Failed to check route {}: {}", route, e),
+      }
+    }
+  }
+}
+
+pub async fn install_all(routes: &[Ipv4Net], dev: &str) -> anyhow::Result<()> {
+  for route in routes {
+    install(route, dev).await?;
+    info!("Installed route {} via {}", route, dev);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_routes_via() {
+    let output = "10.10.0.0/24 dev tun0 scope link \n";
+    assert!(routes_via(output, "tun0"));
+    assert!(!routes_via(output, "tun1"));
+    assert!(!routes_via("10.10.0.0/24 via 192.168.1.1 dev eth0 \n", "tun0"));
+    assert!(!routes_via("", "tun0"));
+  }
+}
@@ -0,0 +1,260 @@
+use std::path::Path;
+
+pub const SERVICE_NAME: &str = "sberlinux-vpn-client";
+pub const LAUNCHD_LABEL: &str = "com.sberlinux.vpn-client";
+
+/// Registers the client to start on boot with `config`, and starts it.
+pub fn install(config: &Path) -> anyhow::Result<()> {
+  let exe = std::env::current_exe()?;
+  let config = std::fs::canonicalize(config)
+    .map_err(|e| anyhow::anyhow!("Failed to find {}: {}", config.display(), e))?;
+  platform::install(&exe, &config)
+}
+
+pub fn uninstall() -> anyhow::Result<()> {
+  platform::uninstall()
+}
+
+/// Runs `client` under the Windows service control manager; only used as the entry point of the service.
+pub fn run(client: impl FnOnce() -> anyhow::Result<()> + Send + 'static) -> anyhow::Result<()> {
+  platform::run(Box::new(client))
+}
+
+type RunClient = Box<dyn FnOnce() -> anyhow::Result<()> + Send>;
+
+/// LaunchDaemon definition that keeps the client running as root.
+pub fn launchd_plist(exe: &Path, config: &Path) -> String {
+  let escape =
+    |path: &Path| path.display().to_string().replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>{exe}</string>
+    <string>--config</string>
+    <string>{config}</string>
+  </array>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <true/>
+  <key>StandardErrorPath</key>
+  <string>/var/log/{label}.log</string>
+</dict>
+</plist>
+"#,
+    label = LAUNCHD_LABEL,
+    exe = escape(exe),
+    config = escape(config),
+  )
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+  use std::path::Path;
+  use std::path::PathBuf;
+  use std::process::Command;
+
+  use super::RunClient;
+  use super::LAUNCHD_LABEL;
+
+  fn plist_path() -> PathBuf {
+    PathBuf::from(format!("/Library/LaunchDaemons/{}.plist", LAUNCHD_LABEL))
+  }
+
+  fn launchctl(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("launchctl").args(args).output()?;
+    if !output.status.success() {
+      anyhow::bail!(
+        "launchctl {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+      );
+    }
+    Ok(())
+  }
+
+  pub fn install(exe: &Path, config: &Path) -> anyhow::Result<()> {
+    let path = plist_path();
+    std::fs::write(&path, super::launchd_plist(exe, config))
+      .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+    launchctl(&["bootstrap", "system", &path.display().to_string()])
+  }
+
+  pub fn uninstall() -> anyhow::Result<()> {
+    let path = plist_path();
+    launchctl(&["bootout", &format!("system/{}", LAUNCHD_LABEL)])?;
+    std::fs::remove_file(&path).map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", path.display(), e))
+  }
+
+  pub fn run(_client: RunClient) -> anyhow::Result<()> {
+    anyhow::bail!(
+      "`service run` is only used by the Windows service manager; launchd runs the client directly"
+    )
+  }
+}
+
+#[cfg(windows)]
+mod platform {
+  use std::ffi::OsString;
+  use std::path::Path;
+  use std::sync::Mutex;
+  use std::sync::OnceLock;
+  use std::time::Duration;
+
+  use windows_service::define_windows_service;
+  use windows_service::service::ServiceAccess;
+  use windows_service::service::ServiceControl;
+  use windows_service::service::ServiceControlAccept;
+  use windows_service::service::ServiceErrorControl;
+  use windows_service::service::ServiceExitCode;
+  use windows_service::service::ServiceInfo;
+  use windows_service::service::ServiceStartType;
+  use windows_service::service::ServiceState;
+  use windows_service::service::ServiceStatus;
+  use windows_service::service::ServiceType;
+  use windows_service::service_control_handler;
+  use windows_service::service_control_handler::ServiceControlHandlerResult;
+  use windows_service::service_control_handler::ServiceStatusHandle;
+  use windows_service::service_dispatcher;
+  use windows_service::service_manager::ServiceManager;
+  use windows_service::service_manager::ServiceManagerAccess;
+
+  use super::RunClient;
+  use super::SERVICE_NAME;
+
+  static CLIENT: Mutex<Option<RunClient>> = Mutex::new(None);
+  static STATUS: OnceLock<ServiceStatusHandle> = OnceLock::new();
+
+  pub fn install(exe: &Path, config: &Path) -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(
+      None::<&str>,
+      ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let info = ServiceInfo {
+      name: OsString::from(SERVICE_NAME),
+      display_name: OsString::from("sberlinux VPN client"),
+      service_type: ServiceType::OWN_PROCESS,
+      start_type: ServiceStartType::AutoStart,
+      error_control: ServiceErrorControl::Normal,
+      executable_path: exe.to_path_buf(),
+      launch_arguments: vec!["--config".into(), config.into(), "service".into(), "run".into()],
+      dependencies: Vec::new(),
+      account_name: None,
+      account_password: None,
+    };
+
+    let service = manager.create_service(&info, ServiceAccess::START)?;
+    service.start::<&str>(&[])?;
+    Ok(())
+  }
+
+  pub fn uninstall() -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+      SERVICE_NAME,
+      ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+      service.stop()?;
+    }
+    service.delete()?;
+    Ok(())
+  }
+
+  pub fn run(client: RunClient) -> anyhow::Result<()> {
+    *CLIENT.lock().unwrap() = Some(client);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+    Ok(())
+  }
+
+  define_windows_service!(ffi_service_main, service_main);
+
+  fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+      service_type: ServiceType::OWN_PROCESS,
+      current_state: state,
+      controls_accepted: match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+      },
+      exit_code: ServiceExitCode::Win32(exit_code),
+      checkpoint: 0,
+      wait_hint: Duration::ZERO,
+      process_id: None,
+    }
+  }
+
+  fn service_main(_arguments: Vec<OsString>) {
+    let registered = service_control_handler::register(SERVICE_NAME, |control| match control {
+      // The client has no graceful shutdown beyond dropping its tun, which exiting the process does.
+      ServiceControl::Stop | ServiceControl::Shutdown => {
+        if let Some(handle) = STATUS.get() {
+          _ = handle.set_service_status(status(ServiceState::Stopped, 0));
+        }
+        std::process::exit(0);
+      }
+      ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+      _ => ServiceControlHandlerResult::NotImplemented,
+    });
+
+    let Ok(status_handle) = registered else {
+      return;
+    };
+    _ = STATUS.set(status_handle);
+    _ = status_handle.set_service_status(status(ServiceState::Running, 0));
+
+    let result = CLIENT.lock().unwrap().take().map(|client| client());
+    if let Some(Err(ref e)) = result {
+      tracing::error!("{}", e);
+    }
+
+    let exit_code = if matches!(result, Some(Ok(()))) { 0 } else { 1 };
+    _ = status_handle.set_service_status(status(ServiceState::Stopped, exit_code));
+  }
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+mod platform {
+  use std::path::Path;
+
+  use super::RunClient;
+
+  pub fn install(_exe: &Path, _config: &Path) -> anyhow::Result<()> {
+    anyhow::bail!(
+      "Installing as a service is only supported on Windows and macOS; use a systemd unit instead"
+    )
+  }
+
+  pub fn uninstall() -> anyhow::Result<()> {
+    anyhow::bail!(
+      "Installing as a service is only supported on Windows and macOS; use a systemd unit instead"
+    )
+  }
+
+  pub fn run(_client: RunClient) -> anyhow::Result<()> {
+    anyhow::bail!("`service run` is only used by the Windows service manager")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_launchd_plist() {
+    let plist = launchd_plist(Path::new("/usr/local/bin/vpn-client"), Path::new("/etc/vpn/R&D.yml"));
+
+    assert!(plist.contains("<string>com.sberlinux.vpn-client</string>"));
+    assert!(plist.contains("<string>/usr/local/bin/vpn-client</string>\n    <string>--config</string>"));
+    assert!(plist.contains("<string>/etc/vpn/R&amp;D.yml</string>"));
+  }
+}
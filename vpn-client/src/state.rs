@@ -0,0 +1,145 @@
+//! Record of the changes a running client made to the system, in a file next to its configuration, so that
+//! they can be undone after it crashed or was killed: by the next client started with that configuration,
+//! or by `vpn-client cleanup`. Routes and resolvers of the tun usually go away with it; the kill switch and
+//! gateway rules don't, and would keep blocking or forwarding traffic.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::dns;
+use crate::gateway;
+use crate::killswitch;
+use crate::profile::write_private;
+use crate::routes;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Change {
+  /// Routes into the tun.
+  Routes { dev: String },
+  /// Resolvers of the tun, set through systemd-resolved.
+  Dns { dev: String },
+  /// Rules of the kill switch, with what the firewall needs to lift them, see `Firewall::state`.
+  KillSwitch { firewall: String },
+  /// Gateway mode, with `ip_forward` as it was before.
+  Gateway { forwarding: String },
+}
+
+impl Change {
+  async fn undo(&self) -> anyhow::Result<()> {
+    match self {
+      // Gone along with the device, unless it outlived the client.
+      Self::Routes { dev } | Self::Dns { dev } if !Path::new("/sys/class/net").join(dev).exists() => Ok(()),
+      Self::Routes { dev } => routes::flush(dev).await,
+      Self::Dns { dev } => dns::revert(dev).await,
+      Self::KillSwitch { firewall } => killswitch::platform_from(firewall.clone())?.disable(),
+      Self::Gateway { forwarding } => gateway::disable(forwarding),
+    }
+  }
+}
+
+/// Path of the state file of the client with the configuration at `config`.
+pub fn path(config: &Path) -> PathBuf {
+  let mut path = config.as_os_str().to_owned();
+  path.push(".state");
+  PathBuf::from(path)
+}
+
+/// Changes of a running client, saved as they're made; the file is removed once the client stops and has
+/// undone them itself.
+pub struct SystemState {
+  path: PathBuf,
+  changes: Mutex<Vec<Change>>,
+}
+
+impl SystemState {
+  pub fn new(path: PathBuf) -> Self {
+    Self { path, changes: Mutex::default() }
+  }
+
+  pub fn record(&self, change: Change) {
+    let mut changes = self.changes.lock().unwrap();
+    if changes.contains(&change) {
+      return;
+    }
+    changes.push(change);
+    let contents = serde_json::to_vec_pretty(&*changes).expect("changes are serializable");
+    if let Err(e) = write_private(&self.path, &contents) {
+      warn!("Failed to save the system changes to {}: {}", self.path.display(), e);
+    }
+  }
+}
+
+impl Drop for SystemState {
+  fn drop(&mut self) {
+    _ = std::fs::remove_file(&self.path);
+  }
+}
+
+/// Undoes the changes a client that didn't stop cleanly left in the file at `path`, newest first, and
+/// removes it; how many there were.
+pub async fn restore(path: &Path) -> anyhow::Result<usize> {
+  let contents = match std::fs::read(path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+    Err(e) => return Err(e.into()),
+  };
+  let changes: Vec<Change> = serde_json::from_slice(&contents)
+    .map_err(|e| anyhow::anyhow!("Invalid state file {}: {}", path.display(), e))?;
+
+  let mut failed = 0;
+  for change in changes.iter().rev() {
+    match change.undo().await {
+      Ok(()) => info!("Undid {:?} left by a client that didn't stop cleanly", change),
+      Err(e) => {
+        warn!("Failed to undo {:?}: {}", change, e);
+        failed += 1;
+      }
+    }
+  }
+  if failed > 0 {
+    anyhow::bail!("Failed to undo {} of {} changes; see {}", failed, changes.len(), path.display());
+  }
+  std::fs::remove_file(path)?;
+  Ok(changes.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_state_file() {
+    let config = std::env::temp_dir().join(format!("vpn-client-state-{}.yml", std::process::id()));
+    let path = path(&config);
+    assert_eq!(
+      path.file_name().unwrap().to_string_lossy(),
+      format!("{}.state", config.file_name().unwrap().to_string_lossy())
+    );
+
+    let state = SystemState::new(path.clone());
+    let change = Change::Routes { dev: "vpn-state-test0".into() };
+    state.record(change.clone());
+    state.record(change.clone());
+    let saved: Vec<Change> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(saved, [change]);
+
+    // A client that stops cleanly leaves nothing behind.
+    drop(state);
+    assert!(!path.exists());
+    assert_eq!(restore(&path).await.unwrap(), 0);
+
+    // Changes to a device that's gone are already undone.
+    let state = SystemState::new(path.clone());
+    state.record(Change::Dns { dev: "vpn-state-test0".into() });
+    std::mem::forget(state);
+    assert_eq!(restore(&path).await.unwrap(), 1);
+    assert!(!path.exists());
+  }
+}
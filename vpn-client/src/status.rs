@@ -0,0 +1,474 @@
+//! Live status of a running client for `vpn-client status`. As with `profile::ProfileControl`, the client
+//! listens on loopback and leaves the port, with a token a watcher has to send first, in a file next to the
+//! configuration. Watchers get the status as a line of JSON right away and every second after: the state,
+//! the latency of the last pings, the throughput and the latest events.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::io::Lines;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::debug;
+use vpn_shared::handshake;
+use vpn_shared::packet;
+
+use crate::client::Client;
+use crate::events::ClientEvent;
+use crate::profile::write_private;
+
+/// Pings whose latency is kept for the sparkline.
+const RTT_HISTORY: usize = 30;
+const RECENT_EVENTS: usize = 8;
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a watcher has to send the token.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Counters of the tunnel that change with every packet, which would swamp `ClientEvent` subscribers.
+#[derive(Debug, Default)]
+pub struct TunnelStats {
+  /// Bytes of tunneled packets.
+  sent: AtomicU64,
+  received: AtomicU64,
+  /// Latency of the last answered ping; `pongs` counts the answers.
+  rtt_micros: AtomicU64,
+  pongs: AtomicU64,
+}
+
+impl TunnelStats {
+  pub fn record_sent(&self, bytes: usize) {
+    self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn record_received(&self, bytes: usize) {
+    self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn record_rtt(&self, rtt: Duration) {
+    self.rtt_micros.store(rtt.as_micros() as u64, Ordering::Relaxed);
+    self.pongs.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+  Connecting,
+  Connected,
+  Reconnecting,
+  /// Routes taken down while idle, see `ClientEvent::Suspended`.
+  Suspended,
+  Disconnected,
+  /// Refused for good by the server.
+  Stopped,
+}
+
+impl fmt::Display for State {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      State::Connecting => write!(f, "connecting"),
+      State::Connected => write!(f, "connected"),
+      State::Reconnecting => write!(f, "reconnecting"),
+      State::Suspended => write!(f, "suspended"),
+      State::Disconnected => write!(f, "disconnected"),
+      State::Stopped => write!(f, "stopped"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StatusEvent {
+  /// Unix time.
+  pub at: u64,
+  pub event: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Status {
+  pub state: State,
+  /// Unix time the client got into `state`.
+  pub since: u64,
+  /// Latency of the last pings in milliseconds, oldest first.
+  pub rtt_ms: Vec<f64>,
+  /// Tunneled bytes since the client started.
+  pub sent_bytes: u64,
+  pub received_bytes: u64,
+  /// Bytes per second over the last second.
+  pub sent_rate: u64,
+  pub received_rate: u64,
+  /// Latest events, oldest first.
+  pub events: Vec<StatusEvent>,
+  /// Mapping of the listen port on the local gateway, or why there's none; absent if none is kept.
+  #[serde(default)]
+  pub port_mapping: Option<String>,
+  /// Answered pings at the last sample.
+  #[serde(skip)]
+  pongs: u64,
+}
+
+impl Status {
+  pub fn new(now: u64) -> Self {
+    Self {
+      state: State::Connecting,
+      since: now,
+      rtt_ms: Vec::new(),
+      sent_bytes: 0,
+      received_bytes: 0,
+      sent_rate: 0,
+      received_rate: 0,
+      events: Vec::new(),
+      port_mapping: None,
+      pongs: 0,
+    }
+  }
+
+  pub fn apply(&mut self, event: &ClientEvent, now: u64) {
+    let state = match event {
+      ClientEvent::Connected | ClientEvent::Resumed => Some(State::Connected),
+      ClientEvent::Reconnecting { .. } => Some(State::Reconnecting),
+      ClientEvent::Suspended => Some(State::Suspended),
+      ClientEvent::Disconnected { .. } => Some(State::Disconnected),
+      ClientEvent::Stopped { .. } => Some(State::Stopped),
+      _ => None,
+    };
+    if let Some(state) = state.filter(|state| *state != self.state) {
+      self.state = state;
+      self.since = now;
+    }
+
+    match event {
+      ClientEvent::PortMapped { method, external } => {
+        self.port_mapping = Some(format!("{} with {}", external, method))
+      }
+      ClientEvent::PortMappingFailed { reason } => self.port_mapping = Some(format!("failed: {}", reason)),
+      _ => {}
+    }
+
+    if let Some(description) = describe(event) {
+      push_bounded(&mut self.events, StatusEvent { at: now, event: description }, RECENT_EVENTS);
+    }
+  }
+
+  /// Takes in the counters of `stats`, `elapsed` after the last sample.
+  pub fn sample(&mut self, stats: &TunnelStats, elapsed: Duration) {
+    let (sent, received) = (stats.sent.load(Ordering::Relaxed), stats.received.load(Ordering::Relaxed));
+    let rate = |bytes: u64| (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+    self.sent_rate = rate(sent.saturating_sub(self.sent_bytes));
+    self.received_rate = rate(received.saturating_sub(self.received_bytes));
+    (self.sent_bytes, self.received_bytes) = (sent, received);
+
+    let pongs = stats.pongs.load(Ordering::Relaxed);
+    if pongs != self.pongs {
+      self.pongs = pongs;
+      let rtt = stats.rtt_micros.load(Ordering::Relaxed) as f64 / 1000.0;
+      push_bounded(&mut self.rtt_ms, rtt, RTT_HISTORY);
+    }
+  }
+
+  pub fn render(&self, now: u64) -> String {
+    let mut text = format!("State:       {} for {}\n", self.state, duration(now.saturating_sub(self.since)));
+    text += &match self.rtt_ms.last() {
+      Some(rtt) => format!("Latency:     {} {:.1} ms\n", sparkline(&self.rtt_ms), rtt),
+      None => "Latency:     no pings answered yet\n".to_string(),
+    };
+    text += &format!("Throughput:  ↑ {}/s  ↓ {}/s\n", bytes(self.sent_rate), bytes(self.received_rate));
+    text += &format!("Transferred: ↑ {}  ↓ {}\n", bytes(self.sent_bytes), bytes(self.received_bytes));
+    if let Some(ref port_mapping) = self.port_mapping {
+      text += &format!("Port:        {}\n", port_mapping);
+    }
+    text += "Events:\n";
+    for event in self.events.iter().rev() {
+      text += &format!("  {:>8} ago  {}\n", duration(now.saturating_sub(event.at)), event.event);
+    }
+    text
+  }
+}
+
+fn push_bounded<T>(values: &mut Vec<T>, value: T, max: usize) {
+  if values.len() == max {
+    values.remove(0);
+  }
+  values.push(value);
+}
+
+/// A line about `event` for the status; `None` for ones too frequent to list.
+pub fn describe(event: &ClientEvent) -> Option<String> {
+  Some(match event {
+    ClientEvent::Connected => "connected".to_string(),
+    ClientEvent::Disconnected { reason, .. } => format!("disconnected: {}", reason),
+    ClientEvent::Reconnecting { attempt, delay, reason } => {
+      format!("reconnecting in {:?}, attempt {}: {}", delay, attempt, reason)
+    }
+    ClientEvent::PortMapped { method, external } => format!("port mapped to {} with {:?}", external, method),
+    ClientEvent::PortMappingFailed { reason } => format!("port mapping failed: {}", reason),
+    ClientEvent::Stopped { reason, .. } => format!("stopped: {}", reason),
+    ClientEvent::Notice(notice) => format!("notice from the server: {:?}", notice),
+    ClientEvent::ClockSkew { offset_secs } => format!("clock is {}s off the server's", offset_secs),
+    ClientEvent::Stats { .. } => return None,
+    ClientEvent::Renegotiated(params) => {
+      format!("renegotiated: mtu {}, keepalive {}s", params.mtu, params.keepalive_secs)
+    }
+    ClientEvent::MtuReduced { from, to, reason } => {
+      format!("mtu lowered from {} to {}: {}", from, to, reason)
+    }
+    ClientEvent::Subnets { accepted, rejected } => {
+      format!("subnets: {} routed, {} refused", accepted.len(), rejected.len())
+    }
+    ClientEvent::Forwards { accepted, rejected } => {
+      format!("forwards: {} accepted, {} refused", accepted.len(), rejected.len())
+    }
+    ClientEvent::Hostname { name, fqdn: Some(fqdn) } => format!("hostname {} registered as {}", name, fqdn),
+    ClientEvent::Hostname { name, fqdn: None } => format!("hostname {} refused", name),
+    ClientEvent::PeerKeyRefused { peer } => format!("refused a new pairwise key of {}", peer),
+    ClientEvent::Routes { routes } => format!("{} site routes", routes.len()),
+    ClientEvent::NetworkConfig { network, .. } => format!("leased {}", network),
+    ClientEvent::RouteRepaired { route } => format!("route {} repaired", route),
+    ClientEvent::TunRemoved { name } => format!("tun {} removed", name),
+    ClientEvent::TunRecreated { name } => format!("tun {} recreated", name),
+    ClientEvent::Suspended => "suspended while idle".to_string(),
+    ClientEvent::Resumed => "resumed".to_string(),
+  })
+}
+
+/// `values` as bars from the lowest to the highest of them.
+pub fn sparkline(values: &[f64]) -> String {
+  let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+  let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+  values
+    .iter()
+    .map(|value| match max - min {
+      range if range > 0.0 => SPARKS[((value - min) / range * (SPARKS.len() - 1) as f64).round() as usize],
+      _ => SPARKS[0],
+    })
+    .collect()
+}
+
+fn bytes(bytes: u64) -> String {
+  const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+  if bytes < 1024 {
+    return format!("{} B", bytes);
+  }
+  let mut value = bytes as f64 / 1024.0;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < UNITS.len() - 1 {
+    value /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn duration(secs: u64) -> String {
+  match secs {
+    0..60 => format!("{}s", secs),
+    60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+    _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+  }
+}
+
+/// Serves the status of the clients this process runs to `StatusWatch`es.
+pub struct StatusFeed {
+  path: PathBuf,
+  status: Arc<Mutex<Status>>,
+  server: JoinHandle<()>,
+  follower: Option<JoinHandle<()>>,
+}
+
+impl StatusFeed {
+  pub async fn bind(config: &Path) -> anyhow::Result<Self> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let mut token = [0; 16];
+    packet::fill_random_bytes(&mut token);
+    let token = handshake::encode_hex(&token);
+
+    let path = feed_path(config);
+    write_private(&path, format!("{} {}", listener.local_addr()?.port(), token).as_bytes())
+      .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+    let status = Arc::new(Mutex::new(Status::new(handshake::unix_time())));
+    let server = tokio::spawn(serve(listener, token, status.clone()));
+    Ok(Self { path, status, server, follower: None })
+  }
+
+  /// Follows the events and counters of `client` in place of the client followed before.
+  pub fn follow(&mut self, client: &Client) {
+    if let Some(follower) = self.follower.take() {
+      follower.abort();
+    }
+    {
+      let mut status = self.status.lock().unwrap();
+      *status = Status { events: std::mem::take(&mut status.events), ..Status::new(handshake::unix_time()) };
+    }
+
+    let (mut events, stats, status) = (client.subscribe(), client.stats(), self.status.clone());
+    self.follower = Some(tokio::spawn(async move {
+      let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+      let mut sampled = Instant::now();
+      loop {
+        tokio::select! {
+          event = events.recv() => match event {
+            Ok(event) => status.lock().unwrap().apply(&event, handshake::unix_time()),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+          },
+          _ = interval.tick() => {
+            status.lock().unwrap().sample(&stats, sampled.elapsed());
+            sampled = Instant::now();
+          }
+        }
+      }
+    }));
+  }
+}
+
+impl Drop for StatusFeed {
+  fn drop(&mut self) {
+    self.server.abort();
+    if let Some(ref follower) = self.follower {
+      follower.abort();
+    }
+    _ = std::fs::remove_file(&self.path);
+  }
+}
+
+async fn serve(listener: TcpListener, token: String, status: Arc<Mutex<Status>>) {
+  loop {
+    let Ok((stream, peer)) = listener.accept().await else {
+      continue;
+    };
+    let (token, status) = (token.clone(), status.clone());
+    tokio::spawn(async move {
+      if let Err(e) = watcher(stream, &token, &status).await {
+        debug!("Status watcher {} left: {}", peer, e);
+      }
+    });
+  }
+}
+
+async fn watcher(stream: TcpStream, token: &str, status: &Mutex<Status>) -> anyhow::Result<()> {
+  let mut stream = BufReader::new(stream);
+  let mut line = String::new();
+  tokio::time::timeout(TOKEN_TIMEOUT, stream.read_line(&mut line)).await??;
+  if line.trim() != token {
+    anyhow::bail!("no valid token");
+  }
+  loop {
+    let line = serde_json::to_string(&*status.lock().unwrap())? + "\n";
+    stream.get_mut().write_all(line.as_bytes()).await?;
+    tokio::time::sleep(UPDATE_INTERVAL).await;
+  }
+}
+
+/// Status updates of the client running with a configuration.
+pub struct StatusWatch {
+  lines: Lines<BufReader<TcpStream>>,
+}
+
+impl StatusWatch {
+  pub async fn connect(config: &Path) -> anyhow::Result<Self> {
+    let not_running = || anyhow::anyhow!("No client is running with {}", config.display());
+    let contents = std::fs::read_to_string(feed_path(config)).map_err(|_| not_running())?;
+    let (port, token) = contents.trim().split_once(' ').ok_or_else(not_running)?;
+    // Left behind by a client that didn't exit cleanly, if nothing listens.
+    let mut stream =
+      TcpStream::connect((Ipv4Addr::LOCALHOST, port.parse()?)).await.map_err(|_| not_running())?;
+    stream.write_all(format!("{}\n", token).as_bytes()).await?;
+    Ok(Self { lines: BufReader::new(stream).lines() })
+  }
+
+  /// The next update; `None` once the client stopped.
+  pub async fn next(&mut self) -> anyhow::Result<Option<Status>> {
+    match self.lines.next_line().await? {
+      Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+      None => Ok(None),
+    }
+  }
+}
+
+fn feed_path(config: &Path) -> PathBuf {
+  let mut path = config.as_os_str().to_owned();
+  path.push(".status");
+  PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::portmap::Method;
+
+  #[test]
+  fn test_status() {
+    let mut status = Status::new(1000);
+    status.apply(&ClientEvent::Connected, 1002);
+    status.apply(
+      &ClientEvent::Stats { sent: 0, received: 0, quota_remaining: None, clients: 1, max_clients: 10 },
+      1003,
+    );
+    assert_eq!((status.state, status.since), (State::Connected, 1002));
+    assert_eq!(status.events, vec![StatusEvent { at: 1002, event: "connected".to_string() }]);
+
+    let stats = TunnelStats::default();
+    stats.record_sent(1000);
+    stats.record_received(4000);
+    stats.record_rtt(Duration::from_millis(20));
+    status.sample(&stats, Duration::from_secs(2));
+    assert_eq!((status.sent_rate, status.received_rate), (500, 2000));
+    assert_eq!(status.rtt_ms, vec![20.0]);
+
+    // Latency is only taken in when a ping was answered since.
+    stats.record_sent(1000);
+    status.sample(&stats, Duration::from_secs(1));
+    assert_eq!((status.sent_bytes, status.sent_rate, status.received_rate), (2000, 1000, 0));
+    assert_eq!(status.rtt_ms.len(), 1);
+
+    let rendered = status.render(1062);
+    assert!(rendered.starts_with("State:       connected for 1m 00s\n"), "{}", rendered);
+    assert!(rendered.contains("Transferred: ↑ 2.0 KiB  ↓ 3.9 KiB\n"), "{}", rendered);
+    assert!(rendered.contains("    1m 00s ago  connected\n"), "{}", rendered);
+    assert!(!rendered.contains("Port:"), "{}", rendered);
+
+    status.apply(&ClientEvent::PortMappingFailed { reason: "no gateway".to_string() }, 1063);
+    assert_eq!(status.port_mapping.as_deref(), Some("failed: no gateway"));
+    let external = "203.0.113.5:6969".parse().unwrap();
+    status.apply(&ClientEvent::PortMapped { method: Method::NatPmp, external }, 1064);
+    assert!(status.render(1064).contains("Port:        203.0.113.5:6969 with NAT-PMP\n"));
+  }
+
+  #[test]
+  fn test_sparkline() {
+    assert_eq!(sparkline(&[10.0, 20.0, 30.0, 80.0]), "▁▂▃█");
+    assert_eq!(sparkline(&[5.0, 5.0]), "▁▁");
+    assert_eq!(sparkline(&[]), "");
+  }
+
+  #[tokio::test]
+  async fn test_watch() {
+    let dir = std::env::temp_dir().join(format!("vpn-client-status-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("client.yml");
+    assert!(StatusWatch::connect(&config).await.is_err());
+
+    let feed = StatusFeed::bind(&config).await.unwrap();
+    let mut watch = StatusWatch::connect(&config).await.unwrap();
+    assert_eq!(watch.next().await.unwrap().map(|status| status.state), Some(State::Connecting));
+
+    drop(feed);
+    assert!(!feed_path(&config).exists());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
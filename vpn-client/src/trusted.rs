@@ -0,0 +1,147 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+use tokio::io::Lines;
+use tokio::process::Child;
+use tokio::process::ChildStdout;
+use tokio::process::Command;
+use tracing::info;
+use tracing::warn;
+
+/// Keeps the tunnel up only while none of the active networks is trusted.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AutoConnectConfig {
+  /// Wi-Fi SSIDs and NetworkManager connection names, e.g. of the office or home network.
+  pub trusted: Vec<String>,
+
+  /// Fallback recheck when NetworkManager can't be followed for changes.
+  #[serde(default = "default_check_interval_secs")]
+  pub check_interval_secs: u64,
+}
+
+fn default_check_interval_secs() -> u64 {
+  10
+}
+
+/// Follows the networks the machine is connected to through NetworkManager, with a periodic recheck as a
+/// fallback. Without NetworkManager every network counts as untrusted, so the tunnel stays up.
+pub struct NetworkMonitor {
+  trusted: Vec<String>,
+  interval: tokio::time::Interval,
+  changes: Option<Lines<BufReader<ChildStdout>>>,
+  _monitor: Option<Child>,
+}
+
+impl NetworkMonitor {
+  pub fn new(config: &AutoConnectConfig) -> Self {
+    let mut monitor = match Command::new("nmcli")
+      .arg("monitor")
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .kill_on_drop(true)
+      .spawn()
+    {
+      Ok(child) => Some(child),
+      Err(e) => {
+        warn!("Failed to follow network changes, falling back to polling: {}", e);
+        None
+      }
+    };
+
+    let changes =
+      monitor.as_mut().and_then(|child| child.stdout.take()).map(|out| BufReader::new(out).lines());
+    Self {
+      trusted: config.trusted.clone(),
+      interval: tokio::time::interval(Duration::from_secs(config.check_interval_secs)),
+      changes,
+      _monitor: monitor,
+    }
+  }
+
+  /// Returns once the machine is on a trusted network, or off all of them for `trusted = false`.
+  pub async fn wait_for(&mut self, trusted: bool) {
+    loop {
+      let networks = match active_networks().await {
+        Ok(networks) => networks,
+        Err(e) => {
+          warn!("Failed to read active networks: {}", e);
+          Vec::new()
+        }
+      };
+
+      match networks.iter().find(|network| self.trusted.contains(network)) {
+        Some(network) if trusted => {
+          info!("Connected to trusted network {}", network);
+          return;
+        }
+        None if !trusted => {
+          info!("Connected to untrusted networks [{}]", networks.join(", "));
+          return;
+        }
+        _ => {}
+      }
+
+      tokio::select! {
+        line = async { self.changes.as_mut().unwrap().next_line().await }, if self.changes.is_some() => {
+          if !matches!(line, Ok(Some(_))) {
+            warn!("NetworkManager monitor exited, falling back to polling");
+            self.changes = None;
+          }
+        }
+        _ = self.interval.tick() => {}
+      }
+    }
+  }
+}
+
+/// Names of active NetworkManager connections and SSIDs of the Wi-Fi networks in use.
+pub(crate) async fn active_networks() -> anyhow::Result<Vec<String>> {
+  let connections = nmcli(&["-t", "-f", "NAME", "connection", "show", "--active"]).await?;
+  let mut networks: Vec<String> = connections.iter().map(|line| split_terse(line).concat()).collect();
+  for fields in nmcli(&["-t", "-f", "ACTIVE,SSID", "device", "wifi"]).await? {
+    if let ["yes", ssid] = split_terse(&fields).iter().map(String::as_str).collect::<Vec<_>>()[..] {
+      networks.push(ssid.to_string());
+    }
+  }
+  networks.sort();
+  networks.dedup();
+  Ok(networks)
+}
+
+async fn nmcli(args: &[&str]) -> anyhow::Result<Vec<String>> {
+  let output = Command::new("nmcli").args(args).output().await?;
+  if !output.status.success() {
+    anyhow::bail!("`nmcli {}` failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect())
+}
+
+/// Fields of a line of `nmcli -t` output, which are separated by `:` with `:` and `\` in values escaped.
+fn split_terse(line: &str) -> Vec<String> {
+  let mut fields = vec![String::new()];
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => fields.last_mut().unwrap().extend(chars.next()),
+      ':' => fields.push(String::new()),
+      c => fields.last_mut().unwrap().push(c),
+    }
+  }
+  fields
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_split_terse() {
+    assert_eq!(split_terse("yes:Office"), vec!["yes", "Office"]);
+    assert_eq!(split_terse(r"no:Cafe\: free\\wifi"), vec!["no", r"Cafe: free\wifi"]);
+    assert_eq!(split_terse("yes:"), vec!["yes", ""]);
+  }
+}
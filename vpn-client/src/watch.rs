@@ -0,0 +1,131 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use ipnet::Ipv4Net;
+use serde_yml::Value;
+use tokio::sync::watch;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::packet::SessionParams;
+
+use crate::config::ClientConfig;
+use crate::profile;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings a running client picks up without reconnecting, by path; changing anything else needs a new
+/// session.
+const LIVE_SETTINGS: &[&[&str]] = &[&["routes"], &["keepalive-secs"], &["transforms"], &["tun", "mtu"]];
+
+/// Follows the configuration file of a running client, as written by configuration management.
+pub struct ConfigWatcher {
+  path: PathBuf,
+  profile: Option<String>,
+  modified: Option<SystemTime>,
+  contents: Value,
+}
+
+impl ConfigWatcher {
+  /// With a `profile`, changes are compared and applied with its settings put over the top-level ones.
+  pub fn new(path: PathBuf, profile: Option<String>) -> anyhow::Result<Self> {
+    let modified = std::fs::metadata(&path)?.modified().ok();
+    let mut watcher = Self { path, profile, modified, contents: Value::Null };
+    watcher.contents = watcher.read()?.0;
+    Ok(watcher)
+  }
+
+  /// Sends route changes to `routes` and session settings to `session` as they're made, and returns the new
+  /// configuration once something changes that the client has to reconnect for. Files that fail to parse
+  /// are skipped until fixed.
+  pub async fn wait_for_reconnect(
+    &mut self,
+    routes: &watch::Sender<Vec<Ipv4Net>>,
+    session: &watch::Sender<SessionParams>,
+  ) -> ClientConfig {
+    loop {
+      tokio::time::sleep(POLL_INTERVAL).await;
+
+      let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+      if modified.is_none() || modified == self.modified {
+        continue;
+      }
+      self.modified = modified;
+
+      let (contents, config) = match self.read() {
+        Ok(read) => read,
+        Err(e) => {
+          warn!("Ignoring changed {}: {}", self.path.display(), e);
+          continue;
+        }
+      };
+
+      let reconnect = needs_reconnect(&self.contents, &contents);
+      self.contents = contents;
+
+      if reconnect {
+        info!("{} changed; reconnecting", self.path.display());
+        return config;
+      }
+
+      info!("{} changed; applying routes and session settings", self.path.display());
+      session.send_if_modified(|current| {
+        let mut params = config.session_params();
+        // Discovered transforms stay in use while none are configured.
+        if params.transforms.is_empty() && config.discovery.is_some() {
+          params.transforms = current.transforms.clone();
+        }
+        let modified = params != *current;
+        *current = params;
+        modified
+      });
+      routes.send_replace(config.routes);
+    }
+  }
+
+  fn read(&self) -> anyhow::Result<(Value, ClientConfig)> {
+    let mut contents = serde_yml::from_str(&std::fs::read_to_string(&self.path)?)?;
+    if let Some(ref name) = self.profile {
+      contents = profile::apply(contents, name)?;
+    }
+    Ok((contents.clone(), serde_yml::from_value(contents)?))
+  }
+}
+
+fn needs_reconnect(old: &Value, new: &Value) -> bool {
+  let without_live = |value: &Value| {
+    let mut value = value.clone();
+    for path in LIVE_SETTINGS {
+      let (key, parents) = path.split_last().unwrap();
+      let parent = parents.iter().try_fold(&mut value, |value, key| value.get_mut(*key));
+      if let Some(mapping) = parent.and_then(|parent| parent.as_mapping_mut()) {
+        mapping.remove(*key);
+      }
+    }
+    value
+  };
+
+  without_live(old) != without_live(new)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_needs_reconnect() {
+    let parse = |s: &str| serde_yml::from_str::<Value>(s).unwrap();
+    let old = parse("server-port: 8000\nroutes: ['10.8.0.0/16']");
+
+    assert!(!needs_reconnect(&old, &parse("server-port: 8000\nroutes: ['10.9.0.0/16']")));
+    assert!(!needs_reconnect(&old, &parse("server-port: 8000")));
+    assert!(needs_reconnect(&old, &parse("server-port: 8001\nroutes: ['10.8.0.0/16']")));
+
+    let old = parse("tun: {name: tun0, mtu: 1500}\nkeepalive-secs: 5");
+    assert!(!needs_reconnect(
+      &old,
+      &parse("tun: {name: tun0, mtu: 1280}\nkeepalive-secs: 25\ntransforms: [pad]")
+    ));
+    assert!(needs_reconnect(&old, &parse("tun: {name: tun1, mtu: 1500}\nkeepalive-secs: 5")));
+  }
+}
@@ -0,0 +1,59 @@
+//! Where sealing datagrams on the blocking pool starts to pay off, to pick `crypto-offload.threshold-bytes`
+//! with. Inline, the reactor thread seals one packet after another; offloaded, up to `offload::IN_FLIGHT` of
+//! them are sealed at once on other threads, which costs a handoff per packet that only large packets make up
+//! for, given spare cores; on a single core it never does. Set the threshold to the smallest size offloading is
+//! clearly faster at on the host.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
+
+use vpn_server::offload;
+use vpn_server::offload::OffloadConfig;
+use vpn_server::offload::Offloaded;
+use vpn_shared::packet::ServerPacket;
+use vpn_shared::packet::KEY_SIZE;
+use vpn_shared::transform::Pipeline;
+
+const BYTES_PER_SIZE: usize = 256 << 20;
+
+/// Packets per second sealing `packets` payloads of `size` bytes with `config`.
+async fn seal(config: OffloadConfig, size: usize, packets: usize) -> f64 {
+  let pipeline = Arc::new(Pipeline::default());
+  let mut in_flight: VecDeque<Offloaded<anyhow::Result<Vec<u8>>>> = VecDeque::new();
+
+  let start = Instant::now();
+  for _ in 0..packets {
+    if in_flight.len() == offload::IN_FLIGHT {
+      in_flight.pop_front().unwrap().await.unwrap().unwrap();
+    }
+    let pipeline = pipeline.clone();
+    let packet = ServerPacket::Data(vec![0x5a; size]);
+    in_flight.push_back(config.start(size, move || pipeline.seal(&[7; KEY_SIZE], 1, &packet)));
+  }
+  for sealing in in_flight {
+    sealing.await.unwrap().unwrap();
+  }
+  packets as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+  // `cargo test --all-targets` runs benches too, in a debug build and without `--bench`; the full run is
+  // only worth it under `cargo bench`.
+  if !std::env::args().any(|arg| arg == "--bench") {
+    return;
+  }
+  let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+  let offloaded = OffloadConfig { threshold_bytes: Some(0) };
+
+  println!("{:>6}  {:>14}  {:>14}  {:>7}", "BYTES", "INLINE PKT/S", "OFFLOAD PKT/S", "SPEEDUP");
+  for size in [512, 1400, 4096, 9000, 16384, 32768, 65000] {
+    let packets = BYTES_PER_SIZE / size;
+    let (inline, offload) = runtime.block_on(async {
+      // Warms up the allocator and the blocking pool before measuring.
+      seal(offloaded, size, packets / 4).await;
+      (seal(OffloadConfig::default(), size, packets).await, seal(offloaded, size, packets).await)
+    });
+    println!("{:>6}  {:>14.0}  {:>14.0}  {:>6.2}x", size, inline, offload, offload / inline);
+  }
+}
@@ -0,0 +1,93 @@
+//! Contention on the session table with thousands of sessions: every data packet notes when its session was
+//! last heard from and counts its bytes. Doing that under the write lock of the entry's shard, as the table
+//! used to, makes packets of unrelated sessions in the same shard wait on each other; with atomics they
+//! only take the read lock, which they share.
+
+use std::hint::black_box;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use vpn_server::timestamp::Timestamp;
+
+const SESSIONS: u64 = 4096;
+const PACKETS_PER_THREAD: u64 = 500_000;
+
+struct Locked {
+  last_seen: Instant,
+  bytes_in: u64,
+}
+
+#[derive(Default)]
+struct Atomic {
+  last_seen: Timestamp,
+  bytes_in: AtomicU64,
+}
+
+/// Packets per second over all threads, each sending to sessions spread over the table.
+fn run<V: Send + Sync>(
+  table: &DashMap<u64, V>,
+  threads: u64,
+  packet: impl Fn(&DashMap<u64, V>, u64) + Sync,
+) -> f64 {
+  let start = Instant::now();
+  thread::scope(|scope| {
+    for thread in 0..threads {
+      let packet = &packet;
+      scope.spawn(move || {
+        for i in 0..PACKETS_PER_THREAD {
+          // A cheap scramble, so threads don't walk the sessions in step.
+          packet(table, (i * 2_654_435_761 + thread * 40_503) % SESSIONS);
+        }
+      });
+    }
+  });
+  (threads * PACKETS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn locked(shards: usize, threads: u64) -> f64 {
+  let table = DashMap::with_shard_amount(shards);
+  for session in 0..SESSIONS {
+    table.insert(session, Locked { last_seen: Instant::now(), bytes_in: 0 });
+  }
+  run(&table, threads, |table, session| {
+    if let Some(mut entry) = table.get_mut(&session) {
+      entry.last_seen = Instant::now();
+      entry.bytes_in += black_box(1400);
+    }
+  })
+}
+
+fn atomic(shards: usize, threads: u64) -> f64 {
+  let table = DashMap::with_shard_amount(shards);
+  for session in 0..SESSIONS {
+    table.insert(session, Atomic::default());
+  }
+  run(&table, threads, |table, session| {
+    if let Some(entry) = table.get(&session) {
+      entry.last_seen.touch();
+      entry.bytes_in.fetch_add(black_box(1400), Ordering::Relaxed);
+    }
+  })
+}
+
+fn main() {
+  // Nothing to measure in the unoptimized run `cargo test --all-targets` makes, which doesn't pass `--bench`.
+  if !std::env::args().any(|arg| arg == "--bench") {
+    return;
+  }
+  // `cargo bench -p vpn-server --bench sessions -- 16` for another number of threads than one per core.
+  let threads = std::env::args()
+    .find_map(|arg| arg.parse().ok())
+    .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get() as u64).max(2));
+  println!("{} sessions, {} threads, {} packets each", SESSIONS, threads, PACKETS_PER_THREAD);
+  println!("{:>6}  {:>16}  {:>16}  {:>7}", "SHARDS", "GET_MUT PKT/S", "ATOMIC PKT/S", "SPEEDUP");
+  for shards in [4, 16, 64, 256] {
+    // Warms up the allocator and the caches before measuring.
+    locked(shards, threads);
+    let (locked, atomic) = (locked(shards, threads), atomic(shards, threads));
+    println!("{:>6}  {:>16.0}  {:>16.0}  {:>6.2}x", shards, locked, atomic, atomic / locked);
+  }
+}
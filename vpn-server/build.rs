@@ -0,0 +1,10 @@
+fn main() {
+  println!("cargo:rerun-if-changed=build.rs");
+  #[cfg(feature = "grpc")]
+  {
+    println!("cargo:rerun-if-changed=proto/admin.proto");
+    // A vendored protoc, so building doesn't depend on one being installed.
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("protoc for this platform"));
+    tonic_prost_build::configure().compile_protos(&["proto/admin.proto"], &["proto"]).expect("valid protos");
+  }
+}
@@ -0,0 +1,119 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use vpn_shared::packet::SessionId;
+
+use crate::server::ConnectedClient;
+use crate::server::Server;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+  /// From the client to the tun.
+  Inbound,
+  Outbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountingKind {
+  Start,
+  Interim,
+  Stop,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccountingEvent {
+  pub kind: AccountingKind,
+  pub session_id: SessionId,
+  pub username: String,
+  /// Device of the user the session is from, see `KeyCredentials::device`.
+  pub device: Option<String>,
+  /// Tenant network of the user; `None` for the default one.
+  pub network: Option<String>,
+  pub client_addr: SocketAddr,
+  pub virtual_ip: Option<Ipv4Addr>,
+  /// Bytes received from and sent to the client.
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+  pub duration: Duration,
+}
+
+/// Receiver of session accounting records; `record` must not block, so sinks talking to the network
+/// should spawn.
+pub trait Accounting: Send + Sync {
+  fn record(&self, event: AccountingEvent);
+
+  /// A client failed to authenticate, as `username` if its credentials name one.
+  fn auth_failed(&self, _username: Option<&str>, _client_addr: SocketAddr) {}
+
+  /// The session moved from `from` to `event.client_addr`, see `Server::roam`.
+  fn roamed(&self, _event: &AccountingEvent, _from: SocketAddr) {}
+
+  /// The session's key was replaced, see `Server::complete_rekey`.
+  fn rekeyed(&self, _event: &AccountingEvent) {}
+}
+
+impl ConnectedClient {
+  /// State of the session as of now; `None` until the client authenticates.
+  pub fn accounting_event(&self, kind: AccountingKind) -> Option<AccountingEvent> {
+    let (Some(username), Some(authenticated_at)) = (&self.username, self.authenticated_at) else {
+      return None;
+    };
+
+    Some(AccountingEvent {
+      kind,
+      session_id: self.session_id,
+      username: username.clone(),
+      device: self.device.clone(),
+      network: self.network.clone(),
+      client_addr: self.addr,
+      virtual_ip: self.virtual_ip,
+      bytes_in: self.bytes_in.load(Ordering::Relaxed),
+      bytes_out: self.bytes_out.load(Ordering::Relaxed),
+      duration: Instant::now().duration_since(authenticated_at),
+    })
+  }
+}
+
+impl Server {
+  pub fn record_accounting(&self, kind: AccountingKind, client: &ConnectedClient) {
+    let Some(event) = client.accounting_event(kind) else {
+      return;
+    };
+
+    match kind {
+      AccountingKind::Start => self.metrics.session_started(client.network.as_deref()),
+      AccountingKind::Stop => self.metrics.session_ended(client.network.as_deref()),
+      AccountingKind::Interim => {}
+    }
+
+    for sink in &self.accounting {
+      sink.record(event.clone());
+    }
+  }
+  pub fn record_auth_failure(&self, username: Option<&str>, client_addr: SocketAddr) {
+    for sink in &self.accounting {
+      sink.auth_failed(username, client_addr);
+    }
+  }
+
+  pub fn record_roam(&self, client: &ConnectedClient, from: SocketAddr) {
+    let Some(event) = client.accounting_event(AccountingKind::Interim) else {
+      return;
+    };
+    for sink in &self.accounting {
+      sink.roamed(&event, from);
+    }
+  }
+
+  pub fn record_rekey(&self, client: &ConnectedClient) {
+    let Some(event) = client.accounting_event(AccountingKind::Interim) else {
+      return;
+    };
+    for sink in &self.accounting {
+      sink.rekeyed(&event);
+    }
+  }
+}
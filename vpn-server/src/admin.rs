@@ -0,0 +1,318 @@
+//! Operations of the admin API, shared by its transports: the HTTP routes of the health endpoint and the
+//! admin service, and gRPC. Transports only translate requests and responses.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use vpn_shared::logging;
+use vpn_shared::packet::SessionId;
+use vpn_shared::recorder;
+
+use crate::bandwidth::BandwidthGraph;
+use crate::bandwidth::BandwidthGraphs;
+use crate::health::LiveClient;
+use crate::health::Scope;
+use crate::history::SessionRecord;
+use crate::listeners::Listener;
+use crate::listeners::ListenerStatus;
+use crate::reload::ConfigDiff;
+use crate::server::Server;
+use crate::table;
+use crate::table::SessionTable;
+use crate::trace;
+use crate::trace::TraceSnapshot;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminError {
+  /// The token isn't one of the admin tokens.
+  Unauthorized,
+  /// No token where one is needed, or one limited to a network for something server-wide.
+  Forbidden,
+  Invalid(String),
+  /// No such session or trace within the scope.
+  NotFound(String),
+  /// The change would cut off the administrator making it; made with `force`, it goes ahead anyway.
+  Lockout(String),
+}
+
+impl fmt::Display for AdminError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AdminError::Unauthorized => write!(f, "unauthorized"),
+      AdminError::Forbidden => write!(f, "forbidden"),
+      AdminError::Invalid(reason) | AdminError::NotFound(reason) | AdminError::Lockout(reason) => {
+        write!(f, "{}", reason)
+      }
+    }
+  }
+}
+
+impl std::error::Error for AdminError {}
+
+/// Scope of a request carrying `token`; requests without one may only do anything if they're `local`, since
+/// probes usually reach the endpoints from elsewhere.
+pub fn authorize(server: &Server, token: Option<&str>, local: bool) -> Result<Scope, AdminError> {
+  match token {
+    Some(token) => match server.admin_tokens.iter().find(|admin| admin.matches(token.trim())) {
+      Some(admin) => Ok(admin.scope()),
+      None => Err(AdminError::Unauthorized),
+    },
+    None if local => Ok(Scope::All),
+    None => Err(AdminError::Forbidden),
+  }
+}
+
+pub fn log_level(scope: &Scope) -> Result<LevelFilter, AdminError> {
+  server_wide(scope)?;
+  logging::level().ok_or(AdminError::Invalid("Logging isn't initialized".to_string()))
+}
+
+pub fn set_log_level(scope: &Scope, level: &str) -> Result<LevelFilter, AdminError> {
+  server_wide(scope)?;
+  let level = logging::parse_level(level).map_err(|e| AdminError::Invalid(e.to_string()))?;
+  logging::set_level(level).map_err(|e| AdminError::Invalid(e.to_string()))?;
+  Ok(level)
+}
+
+/// Writes the events of the flight recorder to disk; returns the path of the dump.
+pub fn dump_flight_recorder(scope: &Scope) -> Result<PathBuf, AdminError> {
+  server_wide(scope)?;
+  let path = recorder::dump("admin").map_err(|e| AdminError::Invalid(e.to_string()))?;
+  info!(target: logging::ADMIN, "Dumped the flight recorder to {}", path.display());
+  Ok(path)
+}
+
+/// Latest session of every user within `scope`.
+pub fn latest_sessions(server: &Server, scope: &Scope) -> HashMap<String, SessionRecord> {
+  let mut latest = server.history.latest();
+  latest.retain(|_, session| scope.includes(session.network.as_deref()));
+  latest
+}
+
+/// Recorded sessions of `username` within `scope`, newest first.
+pub fn sessions(server: &Server, scope: &Scope, username: &str) -> Vec<SessionRecord> {
+  let mut sessions = server.history.sessions(username);
+  sessions.retain(|session| scope.includes(session.network.as_deref()));
+  sessions
+}
+
+pub fn clients(server: &Server, scope: &Scope) -> Vec<LiveClient> {
+  server.live_clients(scope)
+}
+
+/// Disconnects the sessions of `username`, or of one device as `user/device`; returns how many there were.
+/// Unless `force`d, refuses when the request came through the tunnel from one of those sessions, whose
+/// administrator would lose the connection they're making it over.
+pub async fn kick(
+  server: &Server,
+  scope: &Scope,
+  username: &str,
+  peer: IpAddr,
+  force: bool,
+) -> Result<usize, AdminError> {
+  let own = match peer {
+    IpAddr::V4(address) => server.virtual_ips.get(&address).map(|addr| *addr),
+    IpAddr::V6(_) => None,
+  };
+  if !force && own.is_some_and(|own| server.sessions_of(username, scope).contains(&own)) {
+    return Err(AdminError::Lockout(format!(
+      "Kicking {} would disconnect the session this request came through; force it to go ahead",
+      username
+    )));
+  }
+  Ok(server.kick(username, scope).await)
+}
+
+/// Starts tracing every packet of a connected session, by its id as listed with the clients, for `duration`
+/// or `trace::DEFAULT_DURATION`; returns how long it's traced for.
+pub fn start_trace(
+  server: &Server,
+  scope: &Scope,
+  session_id: &str,
+  duration: Option<Duration>,
+) -> Result<Duration, AdminError> {
+  let session_id = parse_session_id(session_id)?;
+  let network = server
+    .sessions
+    .get(&session_id)
+    .and_then(|addr| server.clients.get(&*addr).map(|client| client.network.clone()))
+    .filter(|network| scope.includes(network.as_deref()))
+    .ok_or_else(|| AdminError::NotFound(format!("No session {:016x}", session_id)))?;
+
+  let duration = duration.unwrap_or(trace::DEFAULT_DURATION).min(trace::MAX_DURATION);
+  server.traces.start(session_id, network, duration).map_err(|e| AdminError::Invalid(e.to_string()))?;
+  info!(target: logging::ADMIN, "Tracing the packets of session {:016x} for {:?}", session_id, duration);
+  Ok(duration)
+}
+
+/// Has a connected session, by its id as listed with the clients, replace its key without disconnecting.
+pub async fn rekey(server: &Server, scope: &Scope, session_id: &str) -> Result<(), AdminError> {
+  let session_id = parse_session_id(session_id)?;
+  let addr = server
+    .sessions
+    .get(&session_id)
+    .map(|addr| *addr)
+    .filter(|addr| server.clients.get(addr).is_some_and(|client| scope.includes(client.network.as_deref())))
+    .ok_or_else(|| AdminError::NotFound(format!("No session {:016x}", session_id)))?;
+
+  match server.rekey(addr, "requested by an administrator").await {
+    Ok(true) => {
+      info!(target: logging::ADMIN, "Rekeying session {:016x}", session_id);
+      Ok(())
+    }
+    Ok(false) => Err(AdminError::Invalid(format!(
+      "Session {:016x} doesn't support rekeying; kick it to have it make a new handshake",
+      session_id
+    ))),
+    Err(e) => Err(AdminError::Invalid(e.to_string())),
+  }
+}
+
+/// Trace of a session, while it's recorded and after.
+pub fn trace(server: &Server, scope: &Scope, session_id: &str) -> Result<TraceSnapshot, AdminError> {
+  let session_id = traced(server, scope, session_id)?;
+  server.traces.snapshot(session_id).ok_or_else(|| no_trace(session_id))
+}
+
+pub fn delete_trace(server: &Server, scope: &Scope, session_id: &str) -> Result<(), AdminError> {
+  let session_id = traced(server, scope, session_id)?;
+  server.traces.delete(session_id).then_some(()).ok_or_else(|| no_trace(session_id))
+}
+
+/// Bandwidth graphs of the sessions within `scope`.
+pub fn bandwidth_graphs(server: &Server, scope: &Scope) -> Result<Vec<BandwidthGraph>, AdminError> {
+  let graphs = sampled(server)?;
+  Ok(graphs.graphs(|network| scope.includes(network)))
+}
+
+/// Bandwidth graph of a connected session, by its id as listed with the clients.
+pub fn bandwidth_graph(
+  server: &Server,
+  scope: &Scope,
+  session_id: &str,
+) -> Result<BandwidthGraph, AdminError> {
+  let graphs = sampled(server)?;
+  let session_id = parse_session_id(session_id)?;
+  let no_graph = || AdminError::NotFound(format!("No bandwidth graph of session {:016x}", session_id));
+  match graphs.network(session_id) {
+    Some(network) if scope.includes(network.as_deref()) => graphs.graph(session_id).ok_or_else(no_graph),
+    _ => Err(no_graph()),
+  }
+}
+
+/// Reloads the configuration file of the server as it is now, or only says what that would change if it's a
+/// `dry_run`, see `reload`.
+pub async fn reload(server: &Server, scope: &Scope, dry_run: bool) -> Result<ConfigDiff, AdminError> {
+  server_wide(scope)?;
+  let Some(ref file) = server.config_file else {
+    return Err(AdminError::Invalid("The server was started without a configuration file".to_string()));
+  };
+  let (diff, config) =
+    file.reload(&server.live_clients(scope), dry_run).map_err(|e| AdminError::Invalid(e.to_string()))?;
+  let kind = if dry_run { "Configuration preview" } else { "Configuration reload" };
+  for line in &diff.summary {
+    info!(target: logging::ADMIN, "{}: {}", kind, line);
+  }
+  if let Some(config) = config {
+    server.apply_reload(config, &diff.affected).await;
+  }
+  Ok(diff)
+}
+
+/// Page of the session table, see `table`; `limit` defaults to `table::DEFAULT_LIMIT`.
+pub fn session_table(
+  server: &Server,
+  scope: &Scope,
+  offset: usize,
+  limit: Option<usize>,
+) -> Result<SessionTable, AdminError> {
+  let limit = limit.unwrap_or(table::DEFAULT_LIMIT);
+  if limit == 0 || limit > table::MAX_LIMIT {
+    return Err(AdminError::Invalid(format!("The limit has to be between 1 and {}", table::MAX_LIMIT)));
+  }
+  Ok(SessionTable::page(server.session_rows(scope), offset, limit))
+}
+
+pub fn listeners(server: &Server, scope: &Scope) -> Result<Vec<ListenerStatus>, AdminError> {
+  server_wide(scope)?;
+  Ok(server.listener_statuses())
+}
+
+/// Drops key exchanges coming in on `listener` for `duration`, or until they're accepted again. Refusing
+/// them on the last listener taking them locks new clients out, so it needs `force`.
+pub fn refuse_handshakes(
+  server: &Server,
+  scope: &Scope,
+  listener: &str,
+  duration: Option<Duration>,
+  force: bool,
+) -> Result<(), AdminError> {
+  server_wide(scope)?;
+  let statuses = server.listener_statuses();
+  let listener = listed(&statuses, listener)?;
+  let others = statuses.iter().any(|status| status.listener != listener && status.accepting_handshakes);
+  if !force && !others {
+    return Err(AdminError::Lockout(format!(
+      "Refusing handshakes on {} would leave new clients no listener to connect through; force it to go ahead",
+      listener
+    )));
+  }
+  server.handshake_gates.refuse(listener, duration, Instant::now());
+  match duration {
+    Some(duration) => info!(target: logging::ADMIN, "Refusing handshakes on {} for {:?}", listener, duration),
+    None => info!(target: logging::ADMIN, "Refusing handshakes on {}", listener),
+  }
+  Ok(())
+}
+
+pub fn accept_handshakes(server: &Server, scope: &Scope, listener: &str) -> Result<(), AdminError> {
+  server_wide(scope)?;
+  let listener = listed(&server.listener_statuses(), listener)?;
+  if server.handshake_gates.accept(listener) {
+    info!(target: logging::ADMIN, "Accepting handshakes on {} again", listener);
+  }
+  Ok(())
+}
+
+fn listed(statuses: &[ListenerStatus], listener: &str) -> Result<Listener, AdminError> {
+  let listener = listener.parse().map_err(|e: anyhow::Error| AdminError::Invalid(e.to_string()))?;
+  match statuses.iter().any(|status| status.listener == listener) {
+    true => Ok(listener),
+    false => Err(AdminError::NotFound(format!("The server has no {} listener", listener))),
+  }
+}
+
+fn sampled(server: &Server) -> Result<&BandwidthGraphs, AdminError> {
+  server.bandwidth.as_ref().ok_or(AdminError::Invalid("Bandwidth graphs aren't configured".to_string()))
+}
+
+/// Session of a trace within `scope`.
+fn traced(server: &Server, scope: &Scope, session_id: &str) -> Result<SessionId, AdminError> {
+  let session_id = parse_session_id(session_id)?;
+  match server.traces.network(session_id) {
+    Some(network) if scope.includes(network.as_deref()) => Ok(session_id),
+    _ => Err(no_trace(session_id)),
+  }
+}
+
+fn no_trace(session_id: SessionId) -> AdminError {
+  AdminError::NotFound(format!("No trace of session {:016x}", session_id))
+}
+
+fn parse_session_id(session_id: &str) -> Result<SessionId, AdminError> {
+  SessionId::from_str_radix(session_id.trim_start_matches("0x"), 16)
+    .map_err(|_| AdminError::Invalid(format!("Invalid session id {}", session_id)))
+}
+
+fn server_wide(scope: &Scope) -> Result<(), AdminError> {
+  match scope {
+    Scope::All => Ok(()),
+    Scope::Network(_) => Err(AdminError::Forbidden),
+  }
+}
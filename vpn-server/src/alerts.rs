@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Thresholds of a client, see `AlertsConfig`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AlertThresholds {
+  /// Violations of one kind within a window that raise an alert.
+  #[serde(default)]
+  pub violations: Option<u32>,
+
+  /// Packets per second from the client above which a second counts as a `FlowRate` violation.
+  #[serde(default)]
+  pub max_pps: Option<u32>,
+}
+
+/// Alerts about clients violating policy in the data path, which is otherwise only seen in debug logs.
+/// Every kind alerts at most once per window and client.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AlertsConfig {
+  #[serde(default = "default_window_secs")]
+  pub window_secs: u64,
+
+  #[serde(flatten)]
+  pub thresholds: AlertThresholds,
+
+  /// Thresholds of particular users, falling back to the ones above for those not given.
+  #[serde(default)]
+  pub users: BTreeMap<String, AlertThresholds>,
+}
+
+fn default_window_secs() -> u64 {
+  60
+}
+
+const DEFAULT_VIOLATIONS: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Violation {
+  /// Packets from an address other than the client's virtual one.
+  SpoofedSource,
+  /// Packets to destinations the groups of the user don't allow.
+  Acl,
+  /// Packets to another tenant network.
+  NetworkIsolation,
+  /// Seconds in which the client sent more than `AlertThresholds::max_pps`.
+  FlowRate,
+}
+
+pub struct Alerts {
+  config: AlertsConfig,
+}
+
+impl Alerts {
+  pub fn new(config: AlertsConfig) -> anyhow::Result<Self> {
+    if config.window_secs == 0 {
+      anyhow::bail!("Alert window must be at least a second");
+    }
+    Ok(Self { config })
+  }
+
+  pub fn window(&self) -> Duration {
+    Duration::from_secs(self.config.window_secs)
+  }
+
+  pub fn thresholds(&self, username: Option<&str>) -> AlertThresholds {
+    let defaults = self.config.thresholds;
+    match username.and_then(|username| self.config.users.get(username)) {
+      Some(user) => AlertThresholds {
+        violations: user.violations.or(defaults.violations),
+        max_pps: user.max_pps.or(defaults.max_pps),
+      },
+      None => defaults,
+    }
+  }
+}
+
+/// Violations of a client in the current window.
+#[derive(Debug, Default)]
+pub struct AlertState {
+  window_start: Option<Instant>,
+  violations: BTreeMap<Violation, u32>,
+  second_start: Option<Instant>,
+  packets: u32,
+}
+
+impl AlertState {
+  /// Counts a violation at `now`, returning the count to alert with once it reaches the threshold.
+  pub fn violation(
+    &mut self,
+    violation: Violation,
+    thresholds: &AlertThresholds,
+    window: Duration,
+    now: Instant,
+  ) -> Option<u32> {
+    if self.window_start.is_none_or(|start| now.duration_since(start) >= window) {
+      self.window_start = Some(now);
+      self.violations.clear();
+    }
+    let count = self.violations.entry(violation).or_default();
+    *count += 1;
+    (*count == thresholds.violations.unwrap_or(DEFAULT_VIOLATIONS).max(1)).then_some(*count)
+  }
+
+  /// Counts a packet at `now`, returning whether it's the first over `max_pps` this second.
+  pub fn packet(&mut self, thresholds: &AlertThresholds, now: Instant) -> bool {
+    let Some(max_pps) = thresholds.max_pps else {
+      return false;
+    };
+    if self.second_start.is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1)) {
+      self.second_start = Some(now);
+      self.packets = 0;
+    }
+    self.packets += 1;
+    self.packets == max_pps.saturating_add(1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_thresholds() {
+    let config: AlertsConfig =
+      serde_yml::from_str("violations: 3\nmax-pps: 100\nusers:\n  alice:\n    max-pps: 1000\n").unwrap();
+    let alerts = Alerts::new(config).unwrap();
+    assert_eq!(
+      alerts.thresholds(Some("alice")),
+      AlertThresholds { violations: Some(3), max_pps: Some(1000) }
+    );
+    assert_eq!(alerts.thresholds(None), AlertThresholds { violations: Some(3), max_pps: Some(100) });
+    assert_eq!(alerts.window(), Duration::from_secs(60));
+  }
+
+  #[test]
+  fn test_alert_state() {
+    let mut state = AlertState::default();
+    let thresholds = AlertThresholds { violations: Some(2), max_pps: Some(2) };
+    let window = Duration::from_secs(60);
+    let now = Instant::now();
+
+    assert_eq!(state.violation(Violation::Acl, &thresholds, window, now), None);
+    assert_eq!(state.violation(Violation::SpoofedSource, &thresholds, window, now), None);
+    assert_eq!(state.violation(Violation::Acl, &thresholds, window, now), Some(2));
+    assert_eq!(state.violation(Violation::Acl, &thresholds, window, now), None);
+    // Counted anew in the next window.
+    let later = now + window;
+    assert_eq!(state.violation(Violation::Acl, &thresholds, window, later), None);
+    assert_eq!(state.violation(Violation::Acl, &thresholds, window, later), Some(2));
+
+    assert!(!state.packet(&thresholds, now));
+    assert!(!state.packet(&thresholds, now));
+    assert!(state.packet(&thresholds, now));
+    assert!(!state.packet(&thresholds, now));
+    assert!(!state.packet(&thresholds, now + Duration::from_secs(1)));
+    assert!(!state.packet(&AlertThresholds::default(), now));
+  }
+}
@@ -0,0 +1,551 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::accounting::Accounting;
+use crate::accounting::AccountingEvent;
+use crate::accounting::AccountingKind;
+
+const QUEUE_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AuditConfig {
+  /// Appends to a file, creating it if needed.
+  File { path: PathBuf },
+  /// Streams to a Unix socket, reconnecting whenever a write fails.
+  Socket { path: PathBuf },
+  /// Inserts into the `audit` table of a SQLite database, creating both if needed; needs the sqlite feature.
+  Sqlite { path: PathBuf },
+}
+
+/// One line of the audit log. Fields are only ever added, never renamed or removed, so consumers can rely
+/// on them; `version` changes if that ever has to be broken.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditRecord {
+  pub version: u32,
+  /// Seconds since the Unix epoch.
+  pub timestamp: u64,
+  /// `start`, `roam`, `rekey`, `end` or `auth-failure`; failures have no session, so their id is empty and
+  /// counters zero.
+  pub event: String,
+  pub session_id: String,
+  pub username: String,
+  /// Device of the user the session is from, absent for sessions not tied to one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub device: Option<String>,
+  pub client_addr: String,
+  /// Address a `roam` event's session moved from to `client_addr`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub previous_client_addr: Option<String>,
+  pub virtual_ip: Option<Ipv4Addr>,
+  /// Tenant network of the user, absent for the default one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub network: Option<String>,
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+  pub duration_secs: u64,
+}
+
+impl AuditRecord {
+  fn new(kind: &str, event: &AccountingEvent) -> Self {
+    Self {
+      version: 1,
+      timestamp: now(),
+      event: kind.to_string(),
+      session_id: format!("{:016x}", event.session_id),
+      username: event.username.clone(),
+      device: event.device.clone(),
+      client_addr: event.client_addr.to_string(),
+      previous_client_addr: None,
+      virtual_ip: event.virtual_ip,
+      network: event.network.clone(),
+      bytes_in: event.bytes_in,
+      bytes_out: event.bytes_out,
+      duration_secs: event.duration.as_secs(),
+    }
+  }
+
+  fn auth_failure(username: Option<&str>, client_addr: SocketAddr) -> Self {
+    Self {
+      version: 1,
+      timestamp: now(),
+      event: "auth-failure".to_string(),
+      session_id: String::new(),
+      username: username.unwrap_or_default().to_string(),
+      device: None,
+      client_addr: client_addr.to_string(),
+      previous_client_addr: None,
+      virtual_ip: None,
+      network: None,
+      bytes_in: 0,
+      bytes_out: 0,
+      duration_secs: 0,
+    }
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Writes session lifecycle events as JSON Lines for SIEMs; records are queued and dropped with a warning
+/// if the target can't keep up.
+pub struct AuditLog {
+  writes: mpsc::Sender<Write>,
+}
+
+/// What the task owning the target is asked to do with it.
+enum Write {
+  Record(Box<AuditRecord>),
+  /// Rotate a file, keeping this many old ones.
+  Rotate(u32),
+  /// Give the space of deleted database rows back.
+  Vacuum,
+}
+
+impl AuditLog {
+  pub fn spawn(config: AuditConfig) -> Self {
+    let (writes, rx) = mpsc::channel(QUEUE_DEPTH);
+    match config {
+      // SQLite blocks, so the database gets a thread of its own.
+      #[cfg(feature = "sqlite")]
+      AuditConfig::Sqlite { path } => {
+        std::thread::spawn(move || database::write_records(&path, rx));
+      }
+      config => {
+        tokio::spawn(write_lines(config, rx));
+      }
+    }
+    Self { writes }
+  }
+
+  fn write(&self, record: AuditRecord) {
+    if let Err(e) = self.writes.try_send(Write::Record(Box::new(record))) {
+      if let Write::Record(record) = e.into_inner() {
+        warn!("Audit log queue is full; dropping {} event of {}", record.event, record.username);
+      }
+    }
+  }
+
+  /// Renames a file log to `.1`, an earlier `.1` to `.2` and so on up to `keep`, deleting the oldest, and
+  /// starts a new one. A database keeps the records of as many rotation periods and is vacuumed; sockets
+  /// are left alone.
+  pub fn rotate(&self, keep: u32) {
+    if self.writes.try_send(Write::Rotate(keep)).is_err() {
+      warn!("Audit log queue is full; not rotating it");
+    }
+  }
+
+  /// Compacts a database log, e.g. after records were deleted outside the server; files and sockets are
+  /// left alone.
+  pub fn vacuum(&self) {
+    if self.writes.try_send(Write::Vacuum).is_err() {
+      warn!("Audit log queue is full; not vacuuming it");
+    }
+  }
+}
+
+impl Accounting for AuditLog {
+  fn record(&self, event: AccountingEvent) {
+    let kind = match event.kind {
+      AccountingKind::Start => "start",
+      AccountingKind::Stop => "end",
+      AccountingKind::Interim => return,
+    };
+    self.write(AuditRecord::new(kind, &event));
+  }
+
+  fn auth_failed(&self, username: Option<&str>, client_addr: SocketAddr) {
+    self.write(AuditRecord::auth_failure(username, client_addr));
+  }
+
+  fn roamed(&self, event: &AccountingEvent, from: SocketAddr) {
+    let previous_client_addr = Some(from.to_string());
+    self.write(AuditRecord { previous_client_addr, ..AuditRecord::new("roam", event) });
+  }
+
+  fn rekeyed(&self, event: &AccountingEvent) {
+    self.write(AuditRecord::new("rekey", event));
+  }
+}
+
+async fn write_lines(config: AuditConfig, mut writes: mpsc::Receiver<Write>) {
+  let mut target: Option<Box<dyn AsyncWrite + Send + Unpin>> = None;
+
+  while let Some(write) = writes.recv().await {
+    let line = match write {
+      Write::Record(record) => {
+        let mut line = serde_json::to_string(&record).expect("audit records are serializable");
+        line.push('\n');
+        line
+      }
+      Write::Rotate(keep) => {
+        if let AuditConfig::File { ref path } = config {
+          // Closed first, so the next line opens the new file.
+          target = None;
+          if let Err(e) = rotate(path, keep).await {
+            warn!("Failed to rotate audit log {}: {}", path.display(), e);
+          }
+        }
+        continue;
+      }
+      Write::Vacuum => continue,
+    };
+
+    if target.is_none() {
+      target = match open(&config).await {
+        Ok(opened) => Some(opened),
+        Err(e) => {
+          warn!("Failed to open audit log {:?}: {}", config, e);
+          continue;
+        }
+      };
+    }
+
+    if let Some(ref mut writer) = target {
+      if let Err(e) = async {
+        writer.write_all(line.as_bytes()).await?;
+        writer.flush().await
+      }
+      .await
+      {
+        warn!("Failed to write audit log {:?}: {}", config, e);
+        target = None;
+      }
+    }
+  }
+}
+
+async fn rotate(path: &Path, keep: u32) -> std::io::Result<()> {
+  let rotated = |n: u32| {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+  };
+
+  // Files not there yet, before the log was rotated `keep` times, are skipped.
+  let skip_missing = |result: std::io::Result<()>| match result {
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    result => result,
+  };
+
+  if keep == 0 {
+    return skip_missing(tokio::fs::remove_file(path).await);
+  }
+  skip_missing(tokio::fs::remove_file(rotated(keep)).await)?;
+  for n in (1..keep).rev() {
+    skip_missing(tokio::fs::rename(rotated(n), rotated(n + 1)).await)?;
+  }
+  skip_missing(tokio::fs::rename(path, rotated(1)).await)
+}
+
+async fn open(config: &AuditConfig) -> std::io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+  Ok(match config {
+    AuditConfig::File { path } => {
+      Box::new(tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?)
+    }
+    AuditConfig::Socket { path } => Box::new(UnixStream::connect(path).await?),
+    AuditConfig::Sqlite { .. } => {
+      return Err(std::io::Error::other("the server was built without the sqlite feature"));
+    }
+  })
+}
+
+#[cfg(feature = "sqlite")]
+pub use database::read_records;
+
+#[cfg(feature = "sqlite")]
+mod database {
+  use rusqlite::params;
+  use rusqlite::Connection;
+  use rusqlite::OptionalExtension;
+  use rusqlite::Row;
+
+  use super::*;
+
+  const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS audit (
+      version INTEGER NOT NULL,
+      timestamp INTEGER NOT NULL,
+      event TEXT NOT NULL,
+      session_id TEXT NOT NULL,
+      username TEXT NOT NULL,
+      device TEXT,
+      client_addr TEXT NOT NULL,
+      previous_client_addr TEXT,
+      virtual_ip TEXT,
+      network TEXT,
+      bytes_in INTEGER NOT NULL,
+      bytes_out INTEGER NOT NULL,
+      duration_secs INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS audit_event_timestamp ON audit (event, timestamp);
+    CREATE TABLE IF NOT EXISTS audit_rotations (timestamp INTEGER NOT NULL);
+  ";
+
+  fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    // Lets `report` read while the server writes.
+    connection.pragma_update(None, "journal_mode", "wal")?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+  }
+
+  /// Inserts records and rotates until the log is dropped, reopening the database after a failure.
+  pub fn write_records(path: &Path, mut writes: mpsc::Receiver<Write>) {
+    let mut connection: Option<Connection> = None;
+
+    while let Some(write) = writes.blocking_recv() {
+      if connection.is_none() {
+        connection = match open(path) {
+          Ok(opened) => Some(opened),
+          Err(e) => {
+            warn!("Failed to open audit database {}: {}", path.display(), e);
+            continue;
+          }
+        };
+      }
+
+      if let Some(ref db) = connection {
+        let (result, action) = match write {
+          Write::Record(ref record) => (insert(db, record), "write"),
+          Write::Rotate(keep) => (rotate(db, keep), "rotate"),
+          Write::Vacuum => (db.execute_batch("VACUUM"), "vacuum"),
+        };
+        if let Err(e) = result {
+          warn!("Failed to {} audit database {}: {}", action, path.display(), e);
+          connection = None;
+        }
+      }
+    }
+  }
+
+  /// Deletes the records from before the rotation `keep` rotations ago, which start the oldest period kept
+  /// as the oldest of `keep` rotated files would, and gives the space back. Nothing is deleted until there
+  /// were that many.
+  fn rotate(connection: &Connection, keep: u32) -> rusqlite::Result<()> {
+    connection.execute("INSERT INTO audit_rotations VALUES (?1)", [now() as i64])?;
+    let cutoff: Option<i64> = connection
+      .query_row(
+        "SELECT timestamp FROM audit_rotations ORDER BY timestamp DESC, rowid DESC LIMIT 1 OFFSET ?1",
+        [keep],
+        |row| row.get(0),
+      )
+      .optional()?;
+    if let Some(cutoff) = cutoff {
+      connection.execute("DELETE FROM audit WHERE timestamp < ?1", [cutoff])?;
+      connection.execute("DELETE FROM audit_rotations WHERE timestamp < ?1", [cutoff])?;
+    }
+    connection.execute_batch("VACUUM")
+  }
+
+  fn insert(connection: &Connection, record: &AuditRecord) -> rusqlite::Result<()> {
+    connection.execute(
+      "INSERT INTO audit VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+      params![
+        record.version,
+        record.timestamp as i64,
+        record.event,
+        record.session_id,
+        record.username,
+        record.device,
+        record.client_addr,
+        record.previous_client_addr,
+        record.virtual_ip.map(|ip| ip.to_string()),
+        record.network,
+        record.bytes_in as i64,
+        record.bytes_out as i64,
+        record.duration_secs as i64,
+      ],
+    )?;
+    Ok(())
+  }
+
+  /// Records of the given events at `since` or later, oldest first.
+  pub fn read_records(path: &Path, events: &[&str], since: u64) -> anyhow::Result<Vec<AuditRecord>> {
+    if !path.exists() {
+      anyhow::bail!("{} not found", path.display());
+    }
+
+    let connection = Connection::open(path)?;
+    let placeholders = vec!["?"; events.len()].join(", ");
+    let mut statement = connection.prepare(&format!(
+      "SELECT * FROM audit WHERE timestamp >= ? AND event IN ({}) ORDER BY timestamp, rowid",
+      placeholders
+    ))?;
+    let mut params: Vec<&dyn rusqlite::ToSql> = Vec::with_capacity(events.len() + 1);
+    let since = since as i64;
+    params.push(&since);
+    params.extend(events.iter().map(|event| event as &dyn rusqlite::ToSql));
+
+    let records = statement.query_map(params.as_slice(), record)?.collect::<rusqlite::Result<_>>()?;
+    Ok(records)
+  }
+
+  fn record(row: &Row) -> rusqlite::Result<AuditRecord> {
+    let virtual_ip: Option<String> = row.get("virtual_ip")?;
+    Ok(AuditRecord {
+      version: row.get("version")?,
+      timestamp: row.get::<_, i64>("timestamp")? as u64,
+      event: row.get("event")?,
+      session_id: row.get("session_id")?,
+      username: row.get("username")?,
+      device: row.get("device")?,
+      client_addr: row.get("client_addr")?,
+      previous_client_addr: row.get("previous_client_addr")?,
+      virtual_ip: virtual_ip.and_then(|ip| ip.parse().ok()),
+      network: row.get("network")?,
+      bytes_in: row.get::<_, i64>("bytes_in")? as u64,
+      bytes_out: row.get::<_, i64>("bytes_out")? as u64,
+      duration_secs: row.get::<_, i64>("duration_secs")? as u64,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  fn event(kind: AccountingKind) -> AccountingEvent {
+    AccountingEvent {
+      kind,
+      session_id: 0xabc,
+      username: "alice".into(),
+      device: Some("laptop".into()),
+      network: Some("acme".into()),
+      client_addr: "192.0.2.1:6969".parse().unwrap(),
+      virtual_ip: Some(Ipv4Addr::new(10, 0, 0, 2)),
+      bytes_in: 100,
+      bytes_out: 200,
+      duration: Duration::from_secs(5),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_file_audit_log() {
+    let path = std::env::temp_dir().join(format!("vpn-audit-{}.jsonl", std::process::id()));
+    _ = std::fs::remove_file(&path);
+
+    let log = AuditLog::spawn(AuditConfig::File { path: path.clone() });
+    log.record(event(AccountingKind::Start));
+    log.record(event(AccountingKind::Interim));
+    log.roamed(&event(AccountingKind::Interim), "192.0.2.9:7070".parse().unwrap());
+    log.rekeyed(&event(AccountingKind::Interim));
+    log.record(event(AccountingKind::Stop));
+    log.auth_failed(Some("mallory"), "192.0.2.7:6969".parse().unwrap());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let records: Vec<AuditRecord> =
+      contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(
+      records.iter().map(|r| r.event.as_str()).collect::<Vec<_>>(),
+      ["start", "roam", "rekey", "end", "auth-failure"]
+    );
+    assert_eq!(records[1].previous_client_addr.as_deref(), Some("192.0.2.9:7070"));
+    assert_eq!(records[2].previous_client_addr, None);
+    assert_eq!(records[3].session_id, "0000000000000abc");
+    assert_eq!(records[3].bytes_out, 200);
+    assert_eq!(records[3].network.as_deref(), Some("acme"));
+    assert_eq!(records[3].device.as_deref(), Some("laptop"));
+    assert_eq!((records[4].username.as_str(), records[4].bytes_in), ("mallory", 0));
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_rotation() {
+    let path = std::env::temp_dir().join(format!("vpn-audit-rotation-{}.jsonl", std::process::id()));
+    let rotated = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+    for file in [path.clone(), rotated(1), rotated(2)] {
+      _ = std::fs::remove_file(file);
+    }
+
+    let log = AuditLog::spawn(AuditConfig::File { path: path.clone() });
+    for _ in 0..3 {
+      log.record(event(AccountingKind::Start));
+      log.rotate(1);
+    }
+    log.record(event(AccountingKind::Stop));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let events = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+    assert_eq!((events(&path), events(&rotated(1))), (1, 1));
+    assert!(!rotated(2).exists());
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(rotated(1)).unwrap();
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[tokio::test]
+  async fn test_sqlite_audit_log() {
+    let path = std::env::temp_dir().join(format!("vpn-audit-{}.sqlite", std::process::id()));
+    _ = std::fs::remove_file(&path);
+
+    let log = AuditLog::spawn(AuditConfig::Sqlite { path: path.clone() });
+    log.record(event(AccountingKind::Start));
+    log.roamed(&event(AccountingKind::Interim), "192.0.2.9:7070".parse().unwrap());
+    log.rotate(1);
+    log.record(event(AccountingKind::Stop));
+    log.auth_failed(Some("mallory"), "192.0.2.7:6969".parse().unwrap());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let records = read_records(&path, &["start", "roam", "end", "auth-failure"], 0).unwrap();
+    assert_eq!(
+      records.iter().map(|r| r.event.as_str()).collect::<Vec<_>>(),
+      ["start", "roam", "end", "auth-failure"]
+    );
+    assert_eq!(records[1].previous_client_addr.as_deref(), Some("192.0.2.9:7070"));
+    assert_eq!(records[2].virtual_ip, Some(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!((records[2].bytes_out, records[2].device.as_deref()), (200, Some("laptop")));
+    assert_eq!(read_records(&path, &["end"], 0).unwrap().len(), 1);
+    assert!(read_records(&path, &["end"], records[2].timestamp + 1).unwrap().is_empty());
+
+    drop(log);
+    for suffix in ["", "-wal", "-shm"] {
+      _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[tokio::test]
+  async fn test_sqlite_rotation() {
+    let path = std::env::temp_dir().join(format!("vpn-audit-rotation-{}.sqlite", std::process::id()));
+    _ = std::fs::remove_file(&path);
+    let count = || read_records(&path, &["start"], 0).unwrap().len();
+
+    let log = AuditLog::spawn(AuditConfig::Sqlite { path: path.clone() });
+    for _ in 0..3 {
+      log.write(AuditRecord { timestamp: 1, ..AuditRecord::new("start", &event(AccountingKind::Start)) });
+    }
+    // The first rotation only starts the period kept.
+    log.rotate(1);
+    log.record(event(AccountingKind::Start));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(count(), 4);
+
+    log.rotate(1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(count(), 1);
+
+    drop(log);
+    for suffix in ["", "-wal", "-shm"] {
+      _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+  }
+}
@@ -0,0 +1,66 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use vpn_shared::creds::Credentials;
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Option<Identity>>> + Send + 'a>>;
+pub type PrepareFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+pub type LookupFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Lookup>> + Send + 'a>>;
+
+/// User authenticated by a credential store, along with the groups the store put them in.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Identity {
+  pub username: String,
+  pub groups: Vec<String>,
+}
+
+/// What a store knows of an account without its credentials, see `CredentialStore::lookup`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+  /// The store has the account and it's enabled, with the groups it's in now.
+  Active(Identity),
+  /// The store doesn't have the account, or has it disabled.
+  Absent,
+  /// The store can't look accounts up, e.g. RADIUS.
+  Unsupported,
+}
+
+/// External source of credentials, consulted when a user isn't listed in `client-credentials`. Resolves to
+/// `None` when the credentials are wrong or of a kind the store doesn't handle, and to an error when the
+/// store couldn't be asked.
+pub trait CredentialStore: Send + Sync {
+  fn name(&self) -> &str;
+  fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a>;
+
+  /// Loads or checks what the store needs while the server starts, before it takes clients; nothing by
+  /// default.
+  fn prepare(&self) -> PrepareFuture<'_> {
+    Box::pin(async { Ok(()) })
+  }
+
+  /// Looks `username` up for a session resumed with a ticket, which doesn't carry credentials to
+  /// authenticate with; `Lookup::Unsupported` by default.
+  fn lookup<'a>(&'a self, _username: &'a str) -> LookupFuture<'a> {
+    Box::pin(async { Ok(Lookup::Unsupported) })
+  }
+}
+
+/// Lets the server use a store it also needs for something else, e.g. `PasswordFile`.
+impl<T: CredentialStore + ?Sized> CredentialStore for Arc<T> {
+  fn name(&self) -> &str {
+    (**self).name()
+  }
+
+  fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a> {
+    (**self).authenticate(credentials)
+  }
+
+  fn prepare(&self) -> PrepareFuture<'_> {
+    (**self).prepare()
+  }
+
+  fn lookup<'a>(&'a self, username: &'a str) -> LookupFuture<'a> {
+    (**self).lookup(username)
+  }
+}
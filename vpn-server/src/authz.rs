@@ -0,0 +1,566 @@
+//! Auth policy: rules looked at in order once a user has authenticated, each with a `when` condition in a
+//! small subset of CEL over the attempt, that let the user in, turn them away or put them in a group.
+//!
+//! A condition sees `username`, `source` (the client's IP address), `network` (empty for the default one),
+//! `groups` (given by the directory), `sessions` (other sessions of the user already connected), and
+//! `hour` and `weekday` (UTC, Sunday being 0). It combines them with `==`, `!=`, `<`, `<=`, `>`, `>=`,
+//! `in` a list, `&&`, `||`, `!`, parentheses, string, integer and list literals, and the methods
+//! `startsWith`, `endsWith`, `contains` and `inSubnet("10.0.0.0/8")` of strings. Conditions are type
+//! checked when the configuration loads, so they can't fail later.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+
+use crate::report::DAY_SECS;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+  /// Lets the user in without looking at the rules after.
+  Allow,
+  Deny,
+  /// Puts the user in `group` and goes on with the rules after.
+  Group,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthRule {
+  pub when: Condition,
+  pub action: RuleAction,
+  /// One of `groups`, or of the groups of the user's network, for `action: group`.
+  #[serde(default)]
+  pub group: Option<String>,
+  /// Told to the users `action: deny` turns away.
+  #[serde(default)]
+  pub message: Option<String>,
+}
+
+/// Authentication the rules are looked at for.
+pub struct Attempt<'a> {
+  pub username: &'a str,
+  pub source: IpAddr,
+  pub network: Option<&'a str>,
+  pub groups: &'a [String],
+  pub sessions: usize,
+  /// Seconds since the Unix epoch.
+  pub time: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+  /// Let in, along with the groups the rules put the user in.
+  Allow(Vec<String>),
+  Deny(String),
+}
+
+/// What `rules` make of `attempt`; users no rule denies are let in.
+pub fn decide(rules: &[AuthRule], attempt: &Attempt) -> Decision {
+  let mut groups = Vec::new();
+  for rule in rules.iter().filter(|rule| rule.when.matches(attempt)) {
+    match rule.action {
+      RuleAction::Allow => break,
+      RuleAction::Deny => {
+        return Decision::Deny(
+          rule.message.clone().unwrap_or_else(|| "Denied by the auth policy".to_string()),
+        )
+      }
+      RuleAction::Group => groups.extend(rule.group.clone()),
+    }
+  }
+  Decision::Allow(groups)
+}
+
+/// Parsed and type checked `when` of a rule.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Condition {
+  expression: Expr,
+}
+
+impl TryFrom<String> for Condition {
+  type Error = anyhow::Error;
+
+  fn try_from(source: String) -> anyhow::Result<Self> {
+    Self::parse(&source)
+  }
+}
+
+impl Condition {
+  pub fn parse(source: &str) -> anyhow::Result<Self> {
+    let tokens = tokenize(source).map_err(|e| anyhow::anyhow!("Invalid condition {:?}: {}", source, e))?;
+    let mut parser = Parser { tokens, position: 0 };
+    let parsed = parser.or().and_then(|(expression, kind)| {
+      if let Some(token) = parser.tokens.get(parser.position) {
+        anyhow::bail!("unexpected {}", token);
+      }
+      expect(Type::Bool, kind, "the condition")?;
+      Ok(expression)
+    });
+    let expression = parsed.map_err(|e| anyhow::anyhow!("Invalid condition {:?}: {}", source, e))?;
+    Ok(Self { expression })
+  }
+
+  pub fn matches(&self, attempt: &Attempt) -> bool {
+    self.expression.eval(attempt) == Value::Bool(true)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+  Bool,
+  Int,
+  Str,
+  List,
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Type::Bool => write!(f, "a bool"),
+      Type::Int => write!(f, "an int"),
+      Type::Str => write!(f, "a string"),
+      Type::List => write!(f, "a list"),
+    }
+  }
+}
+
+fn expect(expected: Type, actual: Type, what: &str) -> anyhow::Result<()> {
+  match expected == actual {
+    true => Ok(()),
+    false => anyhow::bail!("{} must be {}, not {}", what, expected, actual),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+  Username,
+  Source,
+  Network,
+  Groups,
+  Sessions,
+  Hour,
+  Weekday,
+}
+
+impl Variable {
+  fn parse(name: &str) -> Option<(Self, Type)> {
+    Some(match name {
+      "username" => (Self::Username, Type::Str),
+      "source" => (Self::Source, Type::Str),
+      "network" => (Self::Network, Type::Str),
+      "groups" => (Self::Groups, Type::List),
+      "sessions" => (Self::Sessions, Type::Int),
+      "hour" => (Self::Hour, Type::Int),
+      "weekday" => (Self::Weekday, Type::Int),
+      _ => return None,
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  In,
+  And,
+  Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+  StartsWith,
+  EndsWith,
+  Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+  Literal(Value),
+  Variable(Variable),
+  List(Vec<Expr>),
+  Not(Box<Expr>),
+  Binary(Operator, Box<Expr>, Box<Expr>),
+  Method(Method, Box<Expr>, Box<Expr>),
+  InSubnet(Box<Expr>, Ipv4Net),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+  Bool(bool),
+  Int(i64),
+  Str(String),
+  List(Vec<String>),
+}
+
+impl Value {
+  fn int(&self) -> i64 {
+    match self {
+      Value::Int(value) => *value,
+      _ => 0,
+    }
+  }
+
+  fn str(&self) -> &str {
+    match self {
+      Value::Str(value) => value,
+      _ => "",
+    }
+  }
+}
+
+impl Expr {
+  /// Types were checked by the parser, so mismatches can't happen here.
+  fn eval(&self, attempt: &Attempt) -> Value {
+    match self {
+      Expr::Literal(value) => value.clone(),
+      Expr::Variable(variable) => match variable {
+        Variable::Username => Value::Str(attempt.username.to_string()),
+        Variable::Source => Value::Str(attempt.source.to_canonical().to_string()),
+        Variable::Network => Value::Str(attempt.network.unwrap_or_default().to_string()),
+        Variable::Groups => Value::List(attempt.groups.to_vec()),
+        Variable::Sessions => Value::Int(attempt.sessions as i64),
+        Variable::Hour => Value::Int((attempt.time % DAY_SECS / 3600) as i64),
+        Variable::Weekday => Value::Int(((attempt.time / DAY_SECS + 4) % 7) as i64),
+      },
+      Expr::List(items) => {
+        Value::List(items.iter().map(|item| item.eval(attempt).str().to_string()).collect())
+      }
+      Expr::Not(operand) => Value::Bool(operand.eval(attempt) == Value::Bool(false)),
+      Expr::Binary(operator, left, right) => {
+        let holds = |expr: &Expr| expr.eval(attempt) == Value::Bool(true);
+        Value::Bool(match operator {
+          Operator::And => holds(left) && holds(right),
+          Operator::Or => holds(left) || holds(right),
+          Operator::Eq => left.eval(attempt) == right.eval(attempt),
+          Operator::Ne => left.eval(attempt) != right.eval(attempt),
+          Operator::Lt => left.eval(attempt).int() < right.eval(attempt).int(),
+          Operator::Le => left.eval(attempt).int() <= right.eval(attempt).int(),
+          Operator::Gt => left.eval(attempt).int() > right.eval(attempt).int(),
+          Operator::Ge => left.eval(attempt).int() >= right.eval(attempt).int(),
+          Operator::In => match right.eval(attempt) {
+            Value::List(items) => items.iter().any(|item| item == left.eval(attempt).str()),
+            _ => false,
+          },
+        })
+      }
+      Expr::Method(method, target, argument) => {
+        let (target, argument) = (target.eval(attempt), argument.eval(attempt));
+        let (target, argument) = (target.str(), argument.str());
+        Value::Bool(match method {
+          Method::StartsWith => target.starts_with(argument),
+          Method::EndsWith => target.ends_with(argument),
+          Method::Contains => target.contains(argument),
+        })
+      }
+      Expr::InSubnet(target, subnet) => Value::Bool(
+        target.eval(attempt).str().parse::<Ipv4Addr>().is_ok_and(|address| subnet.contains(&address)),
+      ),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Identifier(String),
+  Str(String),
+  Int(i64),
+  Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Token::Identifier(name) => write!(f, "{}", name),
+      Token::Str(value) => write!(f, "{:?}", value),
+      Token::Int(value) => write!(f, "{}", value),
+      Token::Symbol(symbol) => write!(f, "{}", symbol),
+    }
+  }
+}
+
+const SYMBOLS: [&str; 15] = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")", "[", "]", ",", "."];
+
+fn tokenize(source: &str) -> anyhow::Result<Vec<Token>> {
+  let mut tokens = Vec::new();
+  let mut rest = source.trim_start();
+  while let Some(c) = rest.chars().next() {
+    if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+      tokens.push(Token::Symbol(symbol));
+      rest = &rest[symbol.len()..];
+    } else if c == '"' || c == '\'' {
+      let Some(end) = rest[1..].find(c) else {
+        anyhow::bail!("unterminated string");
+      };
+      tokens.push(Token::Str(rest[1..=end].to_string()));
+      rest = &rest[end + 2..];
+    } else if c.is_ascii_digit() {
+      let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+      tokens.push(Token::Int(rest[..end].parse()?));
+      rest = &rest[end..];
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+      tokens.push(Token::Identifier(rest[..end].to_string()));
+      rest = &rest[end..];
+    } else {
+      anyhow::bail!("unexpected {:?}", c);
+    }
+    rest = rest.trim_start();
+  }
+  Ok(tokens)
+}
+
+/// Recursive descent over the precedence of CEL: `||`, then `&&`, then the relations, then `!` and method
+/// calls.
+struct Parser {
+  tokens: Vec<Token>,
+  position: usize,
+}
+
+impl Parser {
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.position).cloned();
+    self.position += 1;
+    token
+  }
+
+  fn eat(&mut self, symbol: &str) -> bool {
+    let eaten = matches!(self.tokens.get(self.position), Some(Token::Symbol(next)) if *next == symbol);
+    if eaten {
+      self.position += 1;
+    }
+    eaten
+  }
+
+  fn expect(&mut self, symbol: &str) -> anyhow::Result<()> {
+    match self.eat(symbol) {
+      true => Ok(()),
+      false => anyhow::bail!("expected {}", symbol),
+    }
+  }
+
+  fn or(&mut self) -> anyhow::Result<(Expr, Type)> {
+    let (mut left, mut kind) = self.and()?;
+    while self.eat("||") {
+      expect(Type::Bool, kind, "an operand of ||")?;
+      let (right, right_kind) = self.and()?;
+      expect(Type::Bool, right_kind, "an operand of ||")?;
+      left = Expr::Binary(Operator::Or, Box::new(left), Box::new(right));
+      kind = Type::Bool;
+    }
+    Ok((left, kind))
+  }
+
+  fn and(&mut self) -> anyhow::Result<(Expr, Type)> {
+    let (mut left, mut kind) = self.relation()?;
+    while self.eat("&&") {
+      expect(Type::Bool, kind, "an operand of &&")?;
+      let (right, right_kind) = self.relation()?;
+      expect(Type::Bool, right_kind, "an operand of &&")?;
+      left = Expr::Binary(Operator::And, Box::new(left), Box::new(right));
+      kind = Type::Bool;
+    }
+    Ok((left, kind))
+  }
+
+  fn relation(&mut self) -> anyhow::Result<(Expr, Type)> {
+    let (left, left_kind) = self.unary()?;
+    let operator = match self.tokens.get(self.position) {
+      Some(Token::Symbol("==")) => Operator::Eq,
+      Some(Token::Symbol("!=")) => Operator::Ne,
+      Some(Token::Symbol("<")) => Operator::Lt,
+      Some(Token::Symbol("<=")) => Operator::Le,
+      Some(Token::Symbol(">")) => Operator::Gt,
+      Some(Token::Symbol(">=")) => Operator::Ge,
+      Some(Token::Identifier(name)) if name == "in" => Operator::In,
+      _ => return Ok((left, left_kind)),
+    };
+    self.position += 1;
+    let (right, right_kind) = self.unary()?;
+    match operator {
+      Operator::Eq | Operator::Ne if left_kind != right_kind => {
+        anyhow::bail!("can't compare {} with {}", left_kind, right_kind)
+      }
+      Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge => {
+        expect(Type::Int, left_kind, "an operand of an ordering")?;
+        expect(Type::Int, right_kind, "an operand of an ordering")?;
+      }
+      Operator::In => {
+        expect(Type::Str, left_kind, "the left side of in")?;
+        expect(Type::List, right_kind, "the right side of in")?;
+      }
+      _ => (),
+    }
+    Ok((Expr::Binary(operator, Box::new(left), Box::new(right)), Type::Bool))
+  }
+
+  fn unary(&mut self) -> anyhow::Result<(Expr, Type)> {
+    if self.eat("!") {
+      let (operand, kind) = self.unary()?;
+      expect(Type::Bool, kind, "the operand of !")?;
+      return Ok((Expr::Not(Box::new(operand)), Type::Bool));
+    }
+    let (mut target, mut kind) = self.primary()?;
+    while self.eat(".") {
+      let Some(Token::Identifier(name)) = self.next() else {
+        anyhow::bail!("expected a method after .");
+      };
+      self.expect("(")?;
+      let (argument, argument_kind) = self.or()?;
+      self.expect(")")?;
+      expect(Type::Str, kind, &format!("the target of {}", name))?;
+      expect(Type::Str, argument_kind, &format!("the argument of {}", name))?;
+      let method = match name.as_str() {
+        "startsWith" => Method::StartsWith,
+        "endsWith" => Method::EndsWith,
+        "contains" => Method::Contains,
+        "inSubnet" => {
+          let Expr::Literal(Value::Str(ref subnet)) = argument else {
+            anyhow::bail!("the argument of inSubnet must be a literal");
+          };
+          let subnet: Ipv4Net = subnet.parse().map_err(|_| anyhow::anyhow!("invalid subnet {:?}", subnet))?;
+          (target, kind) = (Expr::InSubnet(Box::new(target), subnet), Type::Bool);
+          continue;
+        }
+        _ => anyhow::bail!("unknown method {}", name),
+      };
+      (target, kind) = (Expr::Method(method, Box::new(target), Box::new(argument)), Type::Bool);
+    }
+    Ok((target, kind))
+  }
+
+  fn primary(&mut self) -> anyhow::Result<(Expr, Type)> {
+    match self.next() {
+      Some(Token::Symbol("(")) => {
+        let parsed = self.or()?;
+        self.expect(")")?;
+        Ok(parsed)
+      }
+      Some(Token::Symbol("[")) => {
+        let mut items = Vec::new();
+        while !self.eat("]") {
+          if !items.is_empty() {
+            self.expect(",")?;
+          }
+          let (item, kind) = self.or()?;
+          expect(Type::Str, kind, "a list item")?;
+          items.push(item);
+        }
+        Ok((Expr::List(items), Type::List))
+      }
+      Some(Token::Str(value)) => Ok((Expr::Literal(Value::Str(value)), Type::Str)),
+      Some(Token::Int(value)) => Ok((Expr::Literal(Value::Int(value)), Type::Int)),
+      Some(Token::Identifier(name)) => match name.as_str() {
+        "true" => Ok((Expr::Literal(Value::Bool(true)), Type::Bool)),
+        "false" => Ok((Expr::Literal(Value::Bool(false)), Type::Bool)),
+        _ => match Variable::parse(&name) {
+          Some((variable, kind)) => Ok((Expr::Variable(variable), kind)),
+          None => anyhow::bail!("unknown variable {}", name),
+        },
+      },
+      Some(token) => anyhow::bail!("unexpected {}", token),
+      None => anyhow::bail!("unexpected end"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Wednesday, 1 January 2025 at 14:30 UTC.
+  const WEDNESDAY_AFTERNOON: u64 = 1_735_741_800;
+
+  fn attempt<'a>(username: &'a str, source: &str, groups: &'a [String]) -> Attempt<'a> {
+    Attempt {
+      username,
+      source: source.parse().unwrap(),
+      network: None,
+      groups,
+      sessions: 1,
+      time: WEDNESDAY_AFTERNOON,
+    }
+  }
+
+  fn matches(condition: &str, attempt: &Attempt) -> bool {
+    Condition::parse(condition).unwrap().matches(attempt)
+  }
+
+  #[test]
+  fn test_conditions() {
+    let groups = vec!["staff".to_string()];
+    let alice = attempt("alice", "10.1.2.3", &groups);
+    assert!(matches("username == 'alice'", &alice));
+    assert!(matches("hour == 14 && weekday == 3", &alice));
+    assert!(matches("source.inSubnet(\"10.0.0.0/8\") && !source.inSubnet('192.168.0.0/16')", &alice));
+    assert!(matches("'staff' in groups && username in ['alice', 'bob']", &alice));
+    assert!(matches(
+      "username.startsWith('al') && username.endsWith('ce') && username.contains('lic')",
+      &alice
+    ));
+    assert!(matches("sessions >= 1 && !(sessions > 1) || false", &alice));
+    assert!(matches("network == ''", &alice));
+    assert!(!matches("hour < 8 || hour >= 20", &alice));
+    assert!(!matches("'admins' in groups", &alice));
+
+    let mapped = attempt("bob", "::ffff:10.1.2.3", &[]);
+    assert!(matches("source == '10.1.2.3' && source.inSubnet('10.0.0.0/8')", &mapped));
+  }
+
+  #[test]
+  fn test_invalid_conditions() {
+    for (condition, error) in [
+      ("usernme == 'alice'", "unknown variable usernme"),
+      ("username == 1", "can't compare a string with an int"),
+      ("hour", "the condition must be a bool, not an int"),
+      ("username < 'b'", "an operand of an ordering must be an int"),
+      ("source.inSubnet('10.0.0.0/33')", "invalid subnet"),
+      ("username.matches('a')", "unknown method matches"),
+      ("(hour == 1", "expected )"),
+      ("hour == 1 hour", "unexpected hour"),
+      ("username == 'alice", "unterminated string"),
+    ] {
+      let e = Condition::parse(condition).unwrap_err().to_string();
+      assert!(e.contains(error), "{}: {}", condition, e);
+    }
+  }
+
+  #[test]
+  fn test_decide() {
+    let rules: Vec<AuthRule> = serde_yml::from_str(
+      r#"
+        - when: "username == 'root'"
+          action: allow
+        - when: "source.inSubnet('10.0.0.0/8')"
+          action: group
+          group: office
+        - when: "sessions >= 3"
+          action: deny
+          message: "Too many sessions"
+        - when: "hour < 8 || hour >= 20"
+          action: deny
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+      decide(&rules, &attempt("alice", "10.1.2.3", &[])),
+      Decision::Allow(vec!["office".to_string()])
+    );
+    assert_eq!(decide(&rules, &attempt("alice", "203.0.113.1", &[])), Decision::Allow(Vec::new()));
+    let busy = Attempt { sessions: 3, ..attempt("alice", "10.1.2.3", &[]) };
+    assert_eq!(decide(&rules, &busy), Decision::Deny("Too many sessions".to_string()));
+    let night = Attempt { time: WEDNESDAY_AFTERNOON + 10 * 3600, ..attempt("alice", "203.0.113.1", &[]) };
+    assert_eq!(decide(&rules, &night), Decision::Deny("Denied by the auth policy".to_string()));
+    let root = Attempt { time: WEDNESDAY_AFTERNOON + 10 * 3600, ..attempt("root", "10.1.2.3", &[]) };
+    assert_eq!(decide(&rules, &root), Decision::Allow(Vec::new()));
+  }
+}
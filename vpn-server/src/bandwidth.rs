@@ -0,0 +1,233 @@
+//! Bandwidth graphs: the throughput of every session, sampled every `interval-secs` into a ring buffer
+//! covering `window-secs`, e.g. the last hour at 5 second resolution. The admin API serves them, so that
+//! dashboards can draw them without an external time-series database.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use vpn_shared::packet::SessionId;
+
+use crate::server::Server;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct BandwidthConfig {
+  /// Seconds between samples.
+  #[serde(default = "default_interval_secs")]
+  pub interval_secs: u64,
+
+  /// Seconds of samples kept of each session; older ones are overwritten.
+  #[serde(default = "default_window_secs")]
+  pub window_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+  5
+}
+
+fn default_window_secs() -> u64 {
+  3600
+}
+
+impl Default for BandwidthConfig {
+  fn default() -> Self {
+    Self { interval_secs: default_interval_secs(), window_secs: default_window_secs() }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Sample {
+  /// Seconds since the Unix epoch at the end of the interval.
+  pub at: u64,
+  /// Bytes received from the client during the interval.
+  pub bytes_in: u64,
+  /// Bytes sent to the client during the interval.
+  pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BandwidthGraph {
+  pub session_id: String,
+  pub username: Option<String>,
+  pub interval_secs: u64,
+  /// Oldest first.
+  pub samples: Vec<Sample>,
+}
+
+/// Byte counters of a session at the time of a sample.
+pub struct Reading {
+  pub session_id: SessionId,
+  pub username: Option<String>,
+  pub network: Option<String>,
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+}
+
+struct Graph {
+  username: Option<String>,
+  /// Tenant network of the session, for admin tokens limited to one.
+  network: Option<String>,
+  /// Counters at the previous sample, which the next one is the difference from.
+  bytes_in: u64,
+  bytes_out: u64,
+  samples: VecDeque<Sample>,
+}
+
+pub struct BandwidthGraphs {
+  interval: Duration,
+  capacity: usize,
+  graphs: Mutex<HashMap<SessionId, Graph>>,
+}
+
+impl BandwidthGraphs {
+  pub fn new(config: &BandwidthConfig) -> Self {
+    let interval_secs = config.interval_secs.max(1);
+    Self {
+      interval: Duration::from_secs(interval_secs),
+      capacity: (config.window_secs / interval_secs).max(1) as usize,
+      graphs: Mutex::default(),
+    }
+  }
+
+  pub fn interval(&self) -> Duration {
+    self.interval
+  }
+
+  /// Adds a sample at `at` to the graph of every session read; a session's first reading only sets where
+  /// its graph starts from, and graphs of sessions that weren't read, having ended, are dropped.
+  pub fn sample(&self, at: u64, readings: Vec<Reading>) {
+    let mut graphs = self.graphs.lock().unwrap();
+    let mut sampled = HashMap::with_capacity(readings.len());
+    for reading in readings {
+      let graph = match graphs.remove(&reading.session_id) {
+        Some(mut graph) => {
+          if graph.samples.len() >= self.capacity {
+            graph.samples.pop_front();
+          }
+          graph.samples.push_back(Sample {
+            at,
+            bytes_in: reading.bytes_in.saturating_sub(graph.bytes_in),
+            bytes_out: reading.bytes_out.saturating_sub(graph.bytes_out),
+          });
+          Graph { bytes_in: reading.bytes_in, bytes_out: reading.bytes_out, ..graph }
+        }
+        None => Graph {
+          username: reading.username,
+          network: reading.network,
+          bytes_in: reading.bytes_in,
+          bytes_out: reading.bytes_out,
+          samples: VecDeque::with_capacity(self.capacity),
+        },
+      };
+      sampled.insert(reading.session_id, graph);
+    }
+    *graphs = sampled;
+  }
+
+  /// Graphs of the sessions whose network `include` accepts.
+  pub fn graphs(&self, include: impl Fn(Option<&str>) -> bool) -> Vec<BandwidthGraph> {
+    let graphs = self.graphs.lock().unwrap();
+    let mut graphs: Vec<_> = graphs
+      .iter()
+      .filter(|(_, graph)| include(graph.network.as_deref()))
+      .map(|(&session_id, graph)| self.render(session_id, graph))
+      .collect();
+    graphs.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+    graphs
+  }
+
+  pub fn network(&self, session_id: SessionId) -> Option<Option<String>> {
+    self.graphs.lock().unwrap().get(&session_id).map(|graph| graph.network.clone())
+  }
+
+  pub fn graph(&self, session_id: SessionId) -> Option<BandwidthGraph> {
+    let graphs = self.graphs.lock().unwrap();
+    graphs.get(&session_id).map(|graph| self.render(session_id, graph))
+  }
+
+  fn render(&self, session_id: SessionId, graph: &Graph) -> BandwidthGraph {
+    BandwidthGraph {
+      session_id: format!("{:016x}", session_id),
+      username: graph.username.clone(),
+      interval_secs: self.interval.as_secs(),
+      samples: graph.samples.iter().copied().collect(),
+    }
+  }
+}
+
+impl Server {
+  /// Samples the throughput of the authenticated sessions every interval of the bandwidth graphs.
+  pub async fn sample_bandwidth(self: Arc<Self>) {
+    let Some(ref graphs) = self.bandwidth else {
+      return;
+    };
+    let mut interval = tokio::time::interval(graphs.interval());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+      interval.tick().await;
+      let readings = self
+        .clients
+        .iter()
+        .filter(|client| client.authenticated_at.is_some())
+        .map(|client| Reading {
+          session_id: client.session_id,
+          username: client.username.clone(),
+          network: client.network.clone(),
+          bytes_in: client.bytes_in.load(Ordering::Relaxed),
+          bytes_out: client.bytes_out.load(Ordering::Relaxed),
+        })
+        .collect();
+      let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+      graphs.sample(at, readings);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn reading(session_id: SessionId, bytes_in: u64, bytes_out: u64) -> Reading {
+    Reading { session_id, username: Some("alice".into()), network: None, bytes_in, bytes_out }
+  }
+
+  #[test]
+  fn test_ring() {
+    let graphs = BandwidthGraphs::new(&BandwidthConfig { interval_secs: 5, window_secs: 10 });
+    graphs.sample(0, vec![reading(1, 100, 1000)]);
+    assert!(graphs.graph(1).unwrap().samples.is_empty());
+
+    graphs.sample(5, vec![reading(1, 150, 3000)]);
+    graphs.sample(10, vec![reading(1, 150, 3500)]);
+    graphs.sample(15, vec![reading(1, 170, 3500)]);
+    let graph = graphs.graph(1).unwrap();
+    assert_eq!(graph.session_id, "0000000000000001");
+    assert_eq!(graph.interval_secs, 5);
+    assert_eq!(
+      graph.samples,
+      [Sample { at: 10, bytes_in: 0, bytes_out: 500 }, Sample { at: 15, bytes_in: 20, bytes_out: 0 }]
+    );
+  }
+
+  #[test]
+  fn test_ended_sessions_are_dropped() {
+    let graphs = BandwidthGraphs::new(&BandwidthConfig::default());
+    let tenant = Reading { network: Some("tenant".into()), ..reading(2, 0, 0) };
+    graphs.sample(0, vec![reading(1, 0, 0), tenant]);
+    assert_eq!(graphs.graphs(|_| true).len(), 2);
+    assert_eq!(graphs.graphs(|network| network == Some("tenant"))[0].session_id, "0000000000000002");
+    assert_eq!(graphs.network(2), Some(Some("tenant".into())));
+
+    graphs.sample(5, vec![reading(2, 10, 10)]);
+    assert!(graphs.graph(1).is_none());
+    assert_eq!(graphs.graph(2).unwrap().samples.len(), 1);
+  }
+}
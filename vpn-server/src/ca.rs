@@ -0,0 +1,106 @@
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing::warn;
+use vpn_shared::cert::SigningKey;
+use vpn_shared::cert::VerifyingKey;
+use vpn_shared::handshake;
+use vpn_shared::packet::fill_random_bytes;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CaConfig {
+  /// Hex-encoded signing key created by `ca init`, needed only to issue certificates; keep it off the
+  /// server that only verifies them, which takes `public-key` instead.
+  #[serde(default)]
+  pub key_file: Option<PathBuf>,
+
+  /// Hex-encoded verifying key `ca init` prints, which the server checks certificates against.
+  #[serde(default)]
+  pub public_key: Option<String>,
+
+  /// Lifetime of certificates issued without `--ttl`.
+  #[serde(default = "default_certificate_ttl_days")]
+  pub certificate_ttl_days: u64,
+}
+
+fn default_certificate_ttl_days() -> u64 {
+  365
+}
+
+impl CaConfig {
+  pub fn key_file(&self) -> anyhow::Result<&Path> {
+    self.key_file.as_deref().ok_or_else(|| anyhow::anyhow!("No ca.key-file configured"))
+  }
+
+  /// Key certificates are verified with: `public-key`, or the one of the signing key for configs that
+  /// predate it.
+  pub fn verifying_key(&self) -> anyhow::Result<VerifyingKey> {
+    if let Some(ref public_key) = self.public_key {
+      return Ok(VerifyingKey::from_bytes(&handshake::parse_key(public_key)?)?);
+    }
+    warn!("No ca.public-key configured; reading the CA signing key, which the server doesn't need");
+    Ok(load(self.key_file()?)?.verifying_key())
+  }
+
+  /// Lifetime of certificates issued without `--ttl`, refused if it doesn't fit in seconds.
+  pub fn certificate_ttl(&self) -> anyhow::Result<Duration> {
+    let secs = self.certificate_ttl_days.checked_mul(24 * 60 * 60);
+    secs.map(Duration::from_secs).ok_or_else(|| anyhow::anyhow!("ca.certificate-ttl-days is too large"))
+  }
+}
+
+/// Creates a new CA key at `path`, refusing to replace an existing one since that invalidates every issued
+/// certificate.
+pub fn init(path: &Path) -> anyhow::Result<SigningKey> {
+  let mut seed = [0u8; 32];
+  fill_random_bytes(&mut seed);
+
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+  let mut file =
+    options.open(path).map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+  writeln!(file, "{}", handshake::encode_key(&seed))?;
+  Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn load(path: &Path) -> anyhow::Result<SigningKey> {
+  let contents =
+    std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+  Ok(SigningKey::from_bytes(&handshake::parse_key(&contents)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_init_and_load() {
+    let path = std::env::temp_dir().join(format!("vpn-ca-{}", std::process::id()));
+    _ = std::fs::remove_file(&path);
+
+    let key = init(&path).unwrap();
+    assert_eq!(load(&path).unwrap(), key);
+    assert!(init(&path).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_verifying_key() {
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let public_key = handshake::encode_key(key.verifying_key().as_bytes());
+    let config = CaConfig { key_file: None, public_key: Some(public_key), certificate_ttl_days: 365 };
+    assert_eq!(config.verifying_key().unwrap(), key.verifying_key());
+    assert!(config.key_file().is_err());
+
+    assert_eq!(config.certificate_ttl().unwrap(), Duration::from_secs(365 * 24 * 60 * 60));
+    assert!(CaConfig { certificate_ttl_days: u64::MAX, ..config }.certificate_ttl().is_err());
+  }
+}
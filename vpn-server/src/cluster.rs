@@ -0,0 +1,613 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use dashmap::DashMap;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::handshake;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::SessionId;
+use vpn_shared::packet::HANDSHAKE_SESSION;
+use vpn_shared::packet::MAX_DATAGRAM_SIZE;
+use vpn_shared::transform::Pipeline;
+
+use crate::accounting::AccountingKind;
+use crate::policy::Policy;
+use crate::redis;
+use crate::redis::Value;
+use crate::server::ConnectedClient;
+use crate::server::Server;
+
+/// Announcements older than this are dropped, so a recorded one can't move sessions back later.
+const MAX_MESSAGE_AGE: Duration = Duration::from_secs(30);
+
+/// Sessions announced in one datagram.
+const ANNOUNCE_BATCH: usize = 32;
+
+/// Wait before subscribing again after the connection to Redis failed.
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClusterConfig {
+  /// Address other nodes reach this one on; it's also what they know it by in `peers`, or in announcements
+  /// passed on by `redis`, which needs nothing listening on it.
+  pub listen: SocketAddr,
+
+  #[serde(default)]
+  pub peers: Vec<SocketAddr>,
+
+  /// Exchange announcements through a Redis server instead of sending them to `peers`, so nodes can come and
+  /// go without the others being reconfigured.
+  #[serde(default)]
+  pub redis: Option<RedisConfig>,
+
+  /// Hex-encoded 32-byte key shared by all nodes, e.g. a `private-key` from `--generate-key`; session keys
+  /// travel between nodes encrypted with it.
+  pub secret: String,
+
+  /// Every node announces all of its sessions this often, so nodes that join later learn them.
+  #[serde(default = "default_announce_interval_secs")]
+  pub announce_interval_secs: u64,
+}
+
+fn default_announce_interval_secs() -> u64 {
+  10
+}
+
+/// Redis server the nodes publish their announcements on, encrypted with the cluster secret like datagrams
+/// between peers; Redis only passes them on and keeps nothing.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RedisConfig {
+  /// `host:port` of the server.
+  pub address: String,
+
+  #[serde(default)]
+  pub password: Option<String>,
+
+  /// Channel all nodes of the cluster publish and subscribe to.
+  #[serde(default = "default_channel")]
+  pub channel: String,
+}
+
+fn default_channel() -> String {
+  "vpn-cluster".to_string()
+}
+
+/// What another node needs to take a session over.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionEntry {
+  pub session_id: SessionId,
+  pub key: Key,
+  pub transforms: Vec<String>,
+  pub username: String,
+  pub device: Option<String>,
+  pub network: Option<String>,
+  pub policy: Policy,
+  pub virtual_ip: Option<Ipv4Addr>,
+  pub public_key: Option<Key>,
+  pub expires_at: Option<SystemTime>,
+  /// Times the session moved between nodes; the latest announcement of a session wins.
+  pub generation: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Message {
+  Announce(Vec<SessionEntry>),
+  /// The session ended on the node sending this.
+  Withdraw(SessionId),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+  sent_at: SystemTime,
+  message: Message,
+}
+
+/// An envelope published on Redis, with the node it's from, which datagrams get from their source.
+#[derive(Serialize, Deserialize)]
+struct Published {
+  node: SocketAddr,
+  envelope: Envelope,
+}
+
+/// How announcements get to the other nodes.
+enum Transport {
+  Peers { socket: UdpSocket, peers: Vec<SocketAddr> },
+  Redis(Box<Channel>),
+}
+
+/// Connections to the Redis server of a cluster, made on first use and again after failing.
+struct Channel {
+  config: RedisConfig,
+  /// This node, whose own messages come back to its subscription.
+  node: SocketAddr,
+  publisher: Mutex<Option<redis::Connection>>,
+  subscriber: Mutex<Option<redis::Connection>>,
+}
+
+struct Remote {
+  entry: SessionEntry,
+  node: SocketAddr,
+  seen: Instant,
+}
+
+/// Directory of the sessions of the other nodes behind the same address. A node receiving a packet of a
+/// session it doesn't have looks it up here and takes the session over, so clients can land on any node.
+pub struct Cluster {
+  transport: Transport,
+  key: Key,
+  pub announce_interval: Duration,
+  directory: DashMap<SessionId, Remote>,
+}
+
+impl Cluster {
+  pub async fn bind(config: ClusterConfig) -> anyhow::Result<Self> {
+    let transport = match config.redis {
+      Some(_) if !config.peers.is_empty() => anyhow::bail!("A cluster has either peers or redis"),
+      Some(redis) => Transport::Redis(Box::new(Channel {
+        config: redis,
+        node: config.listen,
+        publisher: Mutex::default(),
+        subscriber: Mutex::default(),
+      })),
+      None => Transport::Peers { socket: UdpSocket::bind(config.listen).await?, peers: config.peers },
+    };
+    Ok(Self {
+      transport,
+      key: handshake::parse_key(&config.secret)?,
+      announce_interval: Duration::from_secs(config.announce_interval_secs),
+      directory: DashMap::new(),
+    })
+  }
+
+  pub async fn announce(&self, entries: &[SessionEntry]) {
+    for batch in entries.chunks(ANNOUNCE_BATCH) {
+      self.broadcast(Message::Announce(batch.to_vec())).await;
+    }
+  }
+
+  pub async fn withdraw(&self, session_id: SessionId) {
+    self.broadcast(Message::Withdraw(session_id)).await;
+  }
+
+  async fn broadcast(&self, message: Message) {
+    let envelope = Envelope { sent_at: SystemTime::now(), message };
+    match self.transport {
+      Transport::Peers { ref socket, ref peers } => {
+        let datagram = match EncryptedPacket::encrypt(&self.key, HANDSHAKE_SESSION, &envelope) {
+          Ok(packet) => packet.to_bytes(),
+          Err(e) => return warn!("Failed to encrypt a cluster message: {}", e),
+        };
+        for peer in peers {
+          if let Err(e) = socket.send_to(&datagram, peer).await {
+            debug!("Failed to send a cluster message to {}: {}", peer, e);
+          }
+        }
+      }
+      Transport::Redis(ref channel) => {
+        let published = Published { node: channel.node, envelope };
+        match EncryptedPacket::encrypt(&self.key, HANDSHAKE_SESSION, &published) {
+          Ok(packet) => channel.publish(&packet.to_bytes()).await,
+          Err(e) => warn!("Failed to encrypt a cluster message: {}", e),
+        }
+      }
+    }
+  }
+
+  /// Next message of a peer; datagrams that aren't one are skipped.
+  pub async fn recv(&self) -> anyhow::Result<(SocketAddr, Message)> {
+    let (socket, peers) = match self.transport {
+      Transport::Peers { ref socket, ref peers } => (socket, peers),
+      Transport::Redis(ref channel) => return channel.recv(&self.key).await,
+    };
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+      let (len, node) = socket.recv_from(&mut buf).await?;
+      if !peers.contains(&node) {
+        debug!("Ignoring a cluster message from {}, which isn't a peer", node);
+        continue;
+      }
+
+      let envelope =
+        EncryptedPacket::from_bytes(&buf[..len]).and_then(|packet| packet.decrypt::<Envelope>(&self.key));
+      match envelope {
+        Ok(envelope) if is_fresh(envelope.sent_at) => return Ok((node, envelope.message)),
+        Ok(_) => debug!("Ignoring a stale cluster message from {}", node),
+        Err(e) => warn!("Invalid cluster message from {}: {}", node, e),
+      }
+    }
+  }
+
+  /// Records a message of `node` and returns the sessions it announced.
+  pub fn apply(&self, node: SocketAddr, message: Message) -> Vec<SessionEntry> {
+    match message {
+      Message::Announce(entries) => {
+        let mut announced = Vec::new();
+        for entry in entries {
+          let newer = self.directory.get(&entry.session_id).is_none_or(|remote| {
+            remote.entry.generation < entry.generation
+              || (remote.entry.generation == entry.generation && remote.node == node)
+          });
+          if newer {
+            let remote = Remote { entry: entry.clone(), node, seen: Instant::now() };
+            self.directory.insert(entry.session_id, remote);
+            announced.push(entry);
+          }
+        }
+        announced
+      }
+      Message::Withdraw(session_id) => {
+        self.directory.remove_if(&session_id, |_, remote| remote.node == node);
+        Vec::new()
+      }
+    }
+  }
+
+  pub fn lookup(&self, session_id: SessionId) -> Option<SessionEntry> {
+    self.directory.get(&session_id).map(|remote| remote.entry.clone())
+  }
+
+  /// Forgets a session taken over from another node.
+  pub fn forget(&self, session_id: SessionId) {
+    self.directory.remove(&session_id);
+  }
+
+  /// Forgets sessions of nodes that stopped announcing them, e.g. because they went down.
+  pub fn prune(&self) {
+    let max_silence = self.announce_interval * 3;
+    self.directory.retain(|_, remote| remote.seen.elapsed() <= max_silence);
+  }
+}
+
+impl Channel {
+  async fn publish(&self, payload: &[u8]) {
+    let config = &self.config;
+    let mut publisher = self.publisher.lock().await;
+    let published = async {
+      if publisher.is_none() {
+        *publisher = Some(redis::Connection::connect(&config.address, config.password.as_deref()).await?);
+      }
+      let connection = publisher.as_mut().expect("connected above");
+      connection.command(&[b"PUBLISH", config.channel.as_bytes(), payload]).await
+    };
+    if let Err(e) = published.await {
+      warn!("Failed to publish a cluster message on {}: {}", config.address, e);
+      *publisher = None;
+    }
+  }
+
+  /// Next message another node published; reconnects for as long as Redis is unreachable rather than fail.
+  async fn recv(&self, key: &Key) -> anyhow::Result<(SocketAddr, Message)> {
+    let config = &self.config;
+    let mut subscriber = self.subscriber.lock().await;
+    loop {
+      let payload = async {
+        if subscriber.is_none() {
+          let mut connection =
+            redis::Connection::connect(&config.address, config.password.as_deref()).await?;
+          connection.command(&[b"SUBSCRIBE", config.channel.as_bytes()]).await?;
+          info!("Subscribed to cluster messages on {}", config.address);
+          *subscriber = Some(connection);
+        }
+        let connection = subscriber.as_mut().expect("subscribed above");
+        match connection.read().await? {
+          Value::Array(reply) => match <[Value; 3]>::try_from(reply) {
+            Ok([Value::Bulk(Some(kind)), _, Value::Bulk(Some(payload))]) if kind == b"message" => {
+              Ok(Some(payload))
+            }
+            _ => Ok(None),
+          },
+          _ => anyhow::Ok(None),
+        }
+      };
+      let payload = match payload.await {
+        Ok(Some(payload)) => payload,
+        Ok(None) => continue,
+        Err(e) => {
+          warn!("Lost the subscription to cluster messages on {}: {}", config.address, e);
+          *subscriber = None;
+          tokio::time::sleep(REDIS_RETRY_INTERVAL).await;
+          continue;
+        }
+      };
+
+      let published =
+        EncryptedPacket::from_bytes(&payload).and_then(|packet| packet.decrypt::<Published>(key));
+      match published {
+        // Subscribers get what their own node publishes as well.
+        Ok(published) if published.node == self.node => {}
+        Ok(published) if is_fresh(published.envelope.sent_at) => {
+          return Ok((published.node, published.envelope.message))
+        }
+        Ok(published) => debug!("Ignoring a stale cluster message from {}", published.node),
+        Err(e) => warn!("Invalid cluster message published on {}: {}", config.address, e),
+      }
+    }
+  }
+}
+
+impl ConnectedClient {
+  /// Announcement of the session; `None` until the client authenticates.
+  pub fn cluster_entry(&self) -> Option<SessionEntry> {
+    self.authenticated_at?;
+    Some(SessionEntry {
+      session_id: self.session_id,
+      key: self.key,
+      transforms: self.pipeline.names().to_vec(),
+      username: self.username.clone()?,
+      device: self.device.clone(),
+      network: self.network.clone(),
+      policy: self.policy.clone(),
+      virtual_ip: self.virtual_ip,
+      public_key: self.public_key,
+      expires_at: self.expires_at,
+      generation: self.generation,
+    })
+  }
+}
+
+impl Server {
+  /// Tells the other nodes about a session that was authenticated or taken over here.
+  pub async fn announce_session(&self, addr: SocketAddr) {
+    let Some(ref cluster) = self.cluster else {
+      return;
+    };
+    if let Some(entry) = self.clients.get(&addr).and_then(|client| client.cluster_entry()) {
+      cluster.announce(&[entry]).await;
+    }
+  }
+
+  /// Takes over a session of another node whose client now sends its packets here.
+  pub async fn adopt(
+    &self,
+    entry: SessionEntry,
+    pipeline: Arc<Pipeline>,
+    addr: SocketAddr,
+    local: Option<Ipv4Addr>,
+  ) -> anyhow::Result<()> {
+    if self.clients.len() >= self.max_clients {
+      anyhow::bail!("Not taking over session {:#x} of {}: server is full", entry.session_id, entry.username);
+    }
+    if entry.public_key.is_some_and(|key| self.revocations.is_revoked(&key)) {
+      anyhow::bail!("Not taking over session {:#x} of {}: key revoked", entry.session_id, entry.username);
+    }
+
+    self.remove_client(addr).await;
+    if let Some(ref cluster) = self.cluster {
+      cluster.forget(entry.session_id);
+    }
+
+    let outbound = self.send_queue(addr, local, &entry.policy.bandwidth_classes);
+    let mut client = ConnectedClient::new(
+      entry.key,
+      entry.session_id,
+      addr,
+      self.client_timeout,
+      outbound,
+      KeyPair::generate(),
+    );
+    client.username = Some(entry.username.clone());
+    client.device = entry.device;
+    client.network = entry.network;
+    client.policy = entry.policy;
+    client.virtual_ip = entry.virtual_ip;
+    client.local = local;
+    client.public_key = entry.public_key;
+    client.expires_at = entry.expires_at;
+    client.pipeline = pipeline;
+    client.generation = entry.generation + 1;
+    client.authenticated_at = Some(Instant::now());
+
+    if let Some(virtual_ip) = entry.virtual_ip {
+      self.virtual_ips.insert(virtual_ip, addr);
+      self.nat.assign(&entry.username, &client.policy.groups, virtual_ip).await?;
+    }
+    self.record_accounting(AccountingKind::Start, &client);
+    self.sessions.insert(entry.session_id, addr);
+    self.clients.insert(addr, client);
+
+    info!("Took over session {:#x} of {} at {} from another node", entry.session_id, entry.username, addr);
+    self.announce_session(addr).await;
+    Ok(())
+  }
+
+  /// Announces the local sessions to the other nodes periodically.
+  pub async fn announce_sessions(self: Arc<Self>) {
+    let Some(ref cluster) = self.cluster else {
+      return;
+    };
+    loop {
+      tokio::time::sleep(cluster.announce_interval).await;
+      let entries: Vec<_> = self.clients.iter().filter_map(|client| client.cluster_entry()).collect();
+      cluster.announce(&entries).await;
+      cluster.prune();
+    }
+  }
+
+  /// Follows the announcements of the other nodes, dropping local sessions they took over.
+  pub async fn serve_cluster(self: Arc<Self>) -> anyhow::Result<()> {
+    let Some(ref cluster) = self.cluster else {
+      return Ok(());
+    };
+
+    loop {
+      let (node, message) =
+        cluster.recv().await.map_err(|e| anyhow::anyhow!("Failed to receive cluster messages: {}", e))?;
+
+      for entry in cluster.apply(node, message) {
+        let moved = self
+          .sessions
+          .get(&entry.session_id)
+          .map(|addr| *addr)
+          .filter(|addr| self.clients.get(addr).is_some_and(|client| client.generation < entry.generation));
+        if let Some(addr) = moved {
+          info!("Session {:#x} of {} moved to node {}", entry.session_id, entry.username, node);
+          self.remove_client(addr).await;
+        }
+      }
+    }
+  }
+}
+
+fn is_fresh(sent_at: SystemTime) -> bool {
+  match SystemTime::now().duration_since(sent_at) {
+    Ok(age) => age <= MAX_MESSAGE_AGE,
+    Err(e) => e.duration() <= MAX_MESSAGE_AGE,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::AsyncBufReadExt;
+  use tokio::io::AsyncReadExt;
+  use tokio::io::AsyncWriteExt;
+  use tokio::io::BufReader;
+  use tokio::net::tcp::OwnedReadHalf;
+  use tokio::net::tcp::OwnedWriteHalf;
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  fn entry(session_id: SessionId, generation: u32) -> SessionEntry {
+    SessionEntry {
+      session_id,
+      key: [1; 32],
+      transforms: Vec::new(),
+      username: "alice".into(),
+      device: None,
+      network: None,
+      policy: Policy::default(),
+      virtual_ip: None,
+      public_key: None,
+      expires_at: None,
+      generation,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_directory() {
+    let config = ClusterConfig {
+      listen: "127.0.0.1:0".parse().unwrap(),
+      peers: Vec::new(),
+      redis: None,
+      secret: handshake::encode_key(&[2; 32]),
+      announce_interval_secs: 10,
+    };
+    let cluster = Cluster::bind(config).await.unwrap();
+    let (a, b): (SocketAddr, SocketAddr) =
+      ("10.0.0.1:7000".parse().unwrap(), "10.0.0.2:7000".parse().unwrap());
+
+    assert_eq!(cluster.apply(a, Message::Announce(vec![entry(1, 0)])).len(), 1);
+    assert_eq!(cluster.lookup(1).unwrap().generation, 0);
+
+    // A session taken over by b stays there even if a's periodic announcement arrives later.
+    assert_eq!(cluster.apply(b, Message::Announce(vec![entry(1, 1)])).len(), 1);
+    assert!(cluster.apply(a, Message::Announce(vec![entry(1, 0)])).is_empty());
+    cluster.apply(a, Message::Withdraw(1));
+    assert_eq!(cluster.lookup(1).unwrap().generation, 1);
+
+    cluster.apply(b, Message::Withdraw(1));
+    assert!(cluster.lookup(1).is_none());
+  }
+
+  /// Reads a command sent by `redis::Connection`: an array of bulk strings.
+  async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let len: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::new();
+    for _ in 0..len {
+      line.clear();
+      reader.read_line(&mut line).await.ok()?;
+      let mut arg = vec![0; line.trim().strip_prefix('$')?.parse::<usize>().ok()? + 2];
+      reader.read_exact(&mut arg).await.ok()?;
+      arg.truncate(arg.len() - 2);
+      args.push(arg);
+    }
+    Some(args)
+  }
+
+  /// Address of a stand-in for Redis that only does SUBSCRIBE and PUBLISH, on a single channel.
+  async fn fake_redis() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let subscribers = Arc::new(Mutex::new(Vec::<OwnedWriteHalf>::new()));
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let subscribers = subscribers.clone();
+        tokio::spawn(async move {
+          let (reader, mut writer) = stream.into_split();
+          let mut reader = BufReader::new(reader);
+          while let Some(args) = read_command(&mut reader).await {
+            let bulk = |arg: &[u8]| [format!("${}\r\n", arg.len()).as_bytes(), arg, b"\r\n"].concat();
+            match args[0].as_slice() {
+              b"SUBSCRIBE" => {
+                writer
+                  .write_all(&[b"*3\r\n", &bulk(b"subscribe")[..], &bulk(&args[1]), b":1\r\n"].concat())
+                  .await
+                  .unwrap();
+                subscribers.lock().await.push(writer);
+                return;
+              }
+              b"PUBLISH" => {
+                let message = [b"*3\r\n", &bulk(b"message")[..], &bulk(&args[1]), &bulk(&args[2])].concat();
+                let mut subscribers = subscribers.lock().await;
+                for subscriber in subscribers.iter_mut() {
+                  subscriber.write_all(&message).await.unwrap();
+                }
+                writer.write_all(format!(":{}\r\n", subscribers.len()).as_bytes()).await.unwrap();
+              }
+              _ => writer.write_all(b"-ERR unknown command\r\n").await.unwrap(),
+            }
+          }
+        });
+      }
+    });
+    address
+  }
+
+  #[tokio::test]
+  async fn test_redis() {
+    let redis = RedisConfig { address: fake_redis().await, password: None, channel: default_channel() };
+    let config = |listen: &str| ClusterConfig {
+      listen: listen.parse().unwrap(),
+      peers: Vec::new(),
+      redis: Some(redis.clone()),
+      secret: handshake::encode_key(&[2; 32]),
+      announce_interval_secs: 10,
+    };
+    let a = Cluster::bind(config("10.0.0.1:7000")).await.unwrap();
+    let b = Arc::new(Cluster::bind(config("10.0.0.2:7000")).await.unwrap());
+
+    let received = tokio::spawn({
+      let b = b.clone();
+      async move { b.recv().await.unwrap() }
+    });
+    // Messages published before b subscribed aren't delivered to it, as with Redis.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    b.announce(&[entry(2, 0)]).await;
+    a.announce(&[entry(1, 0)]).await;
+    let (node, message) = tokio::time::timeout(Duration::from_secs(2), received).await.unwrap().unwrap();
+    assert_eq!(node, "10.0.0.1:7000".parse().unwrap());
+    assert_eq!(message, Message::Announce(vec![entry(1, 0)]));
+
+    let both = ClusterConfig { peers: vec!["10.0.0.3:7000".parse().unwrap()], ..config("127.0.0.1:0") };
+    assert!(Cluster::bind(both).await.is_err());
+  }
+}
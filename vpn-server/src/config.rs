@@ -1,9 +1,64 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::net::Ipv4Addr;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use ipnet::Ipv4Net;
 use serde::Deserialize;
 use vpn_shared::creds::Credentials;
+use vpn_shared::creds::KeyCredentials;
+use vpn_shared::handshake;
+pub use vpn_shared::iface::TunConfig;
+use vpn_shared::iface::MAX_MTU;
+use vpn_shared::iface::MIN_MTU;
+use vpn_shared::logging::LogConfig;
+use vpn_shared::outer::OuterConfig;
+use vpn_shared::packet::Features;
+use vpn_shared::packet::Key;
+
+use crate::alerts::AlertsConfig;
+use crate::audit::AuditConfig;
+use crate::authz::AuthRule;
+use crate::authz::RuleAction;
+use crate::bandwidth::BandwidthConfig;
+use crate::ca::CaConfig;
+use crate::cluster::ClusterConfig;
+use crate::dns::InternalDnsConfig;
+use crate::health::AdminToken;
+use crate::history::HistoryConfig;
+use crate::ldap::LdapConfig;
+use crate::mdns::MdnsConfig;
+use crate::mdns::MDNS_PORT;
+use crate::metrics::DEFAULT_NETWORK;
+use crate::mirror::MirrorConfig;
+use crate::nat::EgressRule;
+use crate::nat::PortForward;
+use crate::nat::Protocol;
+use crate::network::NetworkConfig;
+use crate::offload::OffloadConfig;
+use crate::oidc::OidcConfig;
+use crate::pacing::PacingConfig;
+use crate::policy::GroupPolicy;
+use crate::policy::PreemptionConfig;
+use crate::pool::AddressPoolConfig;
+use crate::pool::PoolExhaustionConfig;
+use crate::push::MetricsPushConfig;
+use crate::quarantine::QuarantineConfig;
+use crate::radius::RadiusConfig;
+use crate::rekey::RekeyConfig;
+use crate::rendezvous::RendezvousConfig;
+use crate::runtime::RuntimeConfig;
+use crate::schedule::ScheduleConfig;
+use crate::service::AdminServiceConfig;
+use crate::userspace::UserspaceNatConfig;
+use crate::wasm::WasmFilterConfig;
+use crate::webhook::WebhookConfig;
+use crate::workers::WorkerConfig;
+use crate::xdp::XdpConfig;
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -11,10 +66,269 @@ pub struct ServerConfig {
   pub listen_address: Ipv4Addr,
   pub listen_port: u16,
 
+  /// Also accept clients over TCP on this port of the listen address, for networks that block UDP.
+  #[serde(default)]
+  pub tcp_port: Option<u16>,
+
   pub max_clients: usize,
   pub client_timeout_secs: u64,
 
+  /// Push usage statistics to clients this often; off by default.
+  #[serde(default)]
+  pub stats_interval_secs: Option<u64>,
+
   pub client_credentials: Vec<Credentials>,
+
+  #[serde(default)]
+  pub client_keys: Vec<KeyCredentials>,
+
+  /// Authenticate users not listed in `client-credentials` against a directory; needs the `ldap` feature.
+  #[serde(default)]
+  pub ldap: Option<LdapConfig>,
+
+  /// Authenticate users not listed in `client-credentials` against a RADIUS server and report sessions to it.
+  #[serde(default)]
+  pub radius: Option<RadiusConfig>,
+
+  /// Accept access tokens of an OpenID Connect provider; needs the `oidc` feature.
+  #[serde(default)]
+  pub oidc: Option<OidcConfig>,
+
+  /// Passwords users change themselves with `vpn-client change-password`, set with `vpn-server set-password`.
+  #[serde(default)]
+  pub password_file: Option<PathBuf>,
+
+  /// CA client certificates are verified against; managed with `vpn-server ca`.
+  #[serde(default)]
+  pub ca: Option<CaConfig>,
+
+  /// Where to write session start and end events as JSON Lines.
+  #[serde(default)]
+  pub audit: Option<AuditConfig>,
+
+  #[serde(default)]
+  pub mirror: Option<MirrorConfig>,
+
+  /// Throughput of every session sampled for the admin API, see `vpn-server bandwidth`.
+  #[serde(default)]
+  pub bandwidth: Option<BandwidthConfig>,
+
+  /// Metrics pushed to statsd or InfluxDB, besides being served on `/metrics`.
+  #[serde(default)]
+  pub metrics_push: Option<MetricsPushConfig>,
+
+  /// Policy violations in the data path to alert about, through the log and the webhook.
+  #[serde(default)]
+  pub alerts: Option<AlertsConfig>,
+
+  /// Where to POST events administrators may want to act on, like users nearing their quota.
+  #[serde(default)]
+  pub webhook: Option<WebhookConfig>,
+
+  /// Recent sessions of every user, see `vpn-server sessions`.
+  #[serde(default)]
+  pub history: HistoryConfig,
+
+  /// Periodic jobs the server runs itself, like resetting quotas.
+  #[serde(default)]
+  pub schedule: ScheduleConfig,
+
+  #[serde(default)]
+  pub log: LogConfig,
+
+  /// File of revoked client public keys, see `--revoke`.
+  #[serde(default)]
+  pub revocation_list: Option<PathBuf>,
+
+  /// Hex-encoded X25519 key from `--generate-key`.
+  #[serde(default)]
+  pub private_key: Option<String>,
+
+  /// Sends clients tickets to resume their sessions with after restarting, valid this long; needs
+  /// `private_key`, which tickets are sealed with.
+  #[serde(default)]
+  pub session_ticket_lifetime_secs: Option<u64>,
+
+  #[serde(default)]
+  pub groups: BTreeMap<String, GroupPolicy>,
+
+  /// Rules over each authentication that let the user in, turn them away or put them in a group, with
+  /// conditions in a subset of CEL, see `authz`.
+  #[serde(default)]
+  pub auth_policy: Vec<AuthRule>,
+
+  #[serde(default)]
+  pub health_address: Option<SocketAddr>,
+
+  /// Tokens remote administrators use for the admin routes of `health-address`.
+  #[serde(default)]
+  pub admin_tokens: Vec<AdminToken>,
+
+  /// Serve the admin routes over gRPC here too, see `proto/admin.proto`; needs the `grpc` feature.
+  #[serde(default)]
+  pub grpc_address: Option<SocketAddr>,
+
+  /// Serve the routes of `health-address` at the gateway address to clients of the tunnel only; needs the
+  /// `userspace-nat` feature.
+  #[serde(default)]
+  pub admin_service: Option<AdminServiceConfig>,
+
+  /// Resolve hostnames clients register under a domain of the tunnel at the gateway address, which is
+  /// pushed to clients as their DNS server.
+  #[serde(default)]
+  pub internal_dns: Option<InternalDnsConfig>,
+
+  #[serde(default)]
+  pub quarantine: QuarantineConfig,
+
+  /// When sessions get a new key without a new handshake; see `vpn-server rekey` for doing it by hand.
+  #[serde(default)]
+  pub rekey: RekeyConfig,
+
+  /// How far the clock of a client may be off before its key exchanges are refused as replays; 30s by
+  /// default.
+  #[serde(default)]
+  pub handshake_skew_secs: Option<u64>,
+
+  /// How long after expiring tokens from `issue-token` and session tickets are still taken, for servers
+  /// whose clock is ahead of the one that issued them; 60s by default.
+  #[serde(default)]
+  pub token_skew_secs: Option<u64>,
+
+  /// How long the tun device, listeners, credential stores and admin APIs have to start in all before
+  /// the server gives up, naming the ones that didn't; 60s by default.
+  #[serde(default)]
+  pub startup_timeout_secs: Option<u64>,
+
+  #[serde(default)]
+  pub workers: WorkerConfig,
+
+  #[serde(default)]
+  pub pacing: PacingConfig,
+
+  #[serde(default)]
+  pub runtime: RuntimeConfig,
+
+  #[serde(default)]
+  pub crypto_offload: OffloadConfig,
+
+  /// Carry ECN marks between tunneled packets and the datagrams carrying them (Linux only).
+  #[serde(default)]
+  pub ecn: bool,
+
+  /// DF bit and TTL of the datagrams carrying the tunnel.
+  #[serde(default)]
+  pub outer: OuterConfig,
+
+  /// Answer client packets dropped by ACLs, network isolation or filters, or with nowhere to forward them,
+  /// with ICMP destination unreachable, so applications fail fast instead of timing out.
+  #[serde(default)]
+  pub icmp_unreachable: bool,
+
+  /// Pass pairwise keys between clients that ask for them, so their traffic to each other is sealed with
+  /// keys the server doesn't hold and relayed without the tun, filters or mirrors.
+  #[serde(default)]
+  pub peer_encryption: bool,
+
+  /// Features clients must support, by the names `vpn_shared::packet::Features` gives them; older clients
+  /// are refused with `ErrorCode::VersionTooOld` and don't retry.
+  #[serde(default)]
+  pub required_client_features: Vec<String>,
+
+  /// Clamp the MSS of TCP SYNs in both directions to what fits the tun MTU, so TCP flows through the tunnel
+  /// don't depend on path MTU discovery.
+  #[serde(default)]
+  pub mss_clamp: bool,
+
+  /// Tell clients with registered subnets about the subnets of the others their users may reach, so
+  /// sites reach each other without routes configured on every one.
+  #[serde(default)]
+  pub route_exchange: bool,
+
+  /// Transforms clients may negotiate, see `vpn_shared::transform`.
+  #[serde(default)]
+  pub transforms: Vec<String>,
+
+  /// WebAssembly modules every forwarded packet is run through, in order; needs the `wasm` feature.
+  #[serde(default)]
+  pub wasm_filters: Vec<WasmFilterConfig>,
+
+  /// XDP program dropping garbage and steering sessions to CPUs before the network stack; needs the `xdp`
+  /// feature and Linux.
+  #[serde(default)]
+  pub xdp: Option<XdpConfig>,
+
+  #[serde(default)]
+  pub tun: Option<TunConfig>,
+
+  /// Lease client addresses and push resolvers to clients instead of relying on their tun settings.
+  #[serde(default)]
+  pub address_pool: Option<AddressPoolConfig>,
+
+  /// Forward client traffic without a tun device or root, in place of `tun`; needs the `userspace-nat`
+  /// feature.
+  #[serde(default)]
+  pub userspace_nat: Option<UserspaceNatConfig>,
+
+  /// Nodes behind the same address sharing their sessions, so clients can move between them.
+  #[serde(default)]
+  pub cluster: Option<ClusterConfig>,
+
+  #[serde(default)]
+  pub mdns: Option<MdnsConfig>,
+
+  /// Register with a rendezvous, for a server behind NAT without port forwarding.
+  #[serde(default)]
+  pub rendezvous: Option<RendezvousConfig>,
+
+  /// Serve as a rendezvous for servers behind NAT, on the listen port.
+  #[serde(default)]
+  pub rendezvous_service: bool,
+
+  /// Let users of high-priority groups in when the server is full by ending the longest-idle normal
+  /// session.
+  #[serde(default)]
+  pub preemption: Option<PreemptionConfig>,
+
+  /// How to treat users authenticating while no address is free: when to tell them to retry, and whether
+  /// to take the address of a long-silent session instead.
+  #[serde(default)]
+  pub pool_exhaustion: Option<PoolExhaustionConfig>,
+
+  /// Tenant networks served next to the default one, each with its own users and subnet and unreachable
+  /// from the others.
+  #[serde(default)]
+  pub networks: BTreeMap<String, NetworkConfig>,
+
+  #[serde(default)]
+  pub gateway: Option<GatewayConfig>,
+
+  /// Server ports forwarded to connected users; needs `gateway`.
+  #[serde(default)]
+  pub port_forwards: Vec<PortForward>,
+}
+
+/// Base of `--simple` when no configuration file is given.
+const SIMPLE_CONFIG: &str = r#"
+listen-address: "0.0.0.0"
+listen-port: 6969
+max-clients: 64
+client-timeout-secs: 30
+client-credentials: []
+"#;
+
+const SIMPLE_SUBNET: &str = "10.8.0.0/24";
+const SIMPLE_DNS: [Ipv4Addr; 2] = [Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(1, 0, 0, 1)];
+const SIMPLE_HEALTH_ADDRESS: &str = "0.0.0.0:8080";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GatewayConfig {
+  #[serde(default)]
+  pub fix_sysctls: bool,
+
+  #[serde(default)]
+  pub egress: Vec<EgressRule>,
 }
 
 impl ServerConfig {
@@ -28,15 +342,429 @@ impl ServerConfig {
     Ok(config)
   }
 
+  /// Configuration `--simple` starts from without a file.
+  pub fn simple() -> Self {
+    serde_yml::from_str(SIMPLE_CONFIG).expect("the simple configuration is valid")
+  }
+
+  /// Fills in what a road-warrior server needs and isn't configured: a tun with NAT to the internet, unless
+  /// `userspace-nat` replaces it, leased addresses with public resolvers, and the health endpoint.
+  pub fn apply_simple(&mut self) {
+    let subnet: Ipv4Net = SIMPLE_SUBNET.parse().expect("valid subnet");
+
+    if self.tun.is_none() && self.userspace_nat.is_none() {
+      self.tun = Some(TunConfig {
+        name: "vpn%d".into(),
+        address: subnet.hosts().next().expect("subnet has hosts"),
+        netmask: subnet.netmask(),
+        mtu: None,
+        link_mtu: None,
+        up: true,
+        persist: false,
+        owner: None,
+        description: None,
+        gso: false,
+      });
+    }
+
+    if self.address_pool.is_none() {
+      let subnet = match self.tun {
+        Some(ref tun) => {
+          Ipv4Net::with_netmask(tun.address, tun.netmask).map(|net| net.trunc()).unwrap_or(subnet)
+        }
+        None => subnet,
+      };
+      self.address_pool = Some(AddressPoolConfig { subnet, dns: SIMPLE_DNS.to_vec() });
+    }
+
+    if self.gateway.is_none() && self.tun.is_some() {
+      self.gateway = Some(GatewayConfig { fix_sysctls: true, ..Default::default() });
+    }
+
+    if self.health_address.is_none() {
+      self.health_address = Some(SIMPLE_HEALTH_ADDRESS.parse().expect("valid address"));
+    }
+  }
+
+  /// Whether any way for users to authenticate is configured.
+  pub fn has_authentication(&self) -> bool {
+    !self.client_credentials.is_empty()
+      || !self.client_keys.is_empty()
+      || self.ldap.is_some()
+      || self.radius.is_some()
+      || self.oidc.is_some()
+      || self.password_file.is_some()
+      || self.ca.is_some()
+      || self.private_key.is_some()
+      || self
+        .networks
+        .values()
+        .any(|network| !network.client_credentials.is_empty() || !network.client_keys.is_empty())
+  }
+
+  /// Subnet of the users of the top-level configuration.
+  pub fn default_subnet(&self) -> Option<Ipv4Net> {
+    match (&self.address_pool, &self.tun) {
+      (Some(pool), _) => Some(pool.subnet),
+      (None, Some(tun)) => Ipv4Net::with_netmask(tun.address, tun.netmask).ok().map(|net| net.trunc()),
+      (None, None) => None,
+    }
+  }
+
+  /// Key entry of a device named as `user/device`, in the default network or any other.
+  pub fn device_key(&self, name: &str) -> Option<&KeyCredentials> {
+    let (username, device) = name.split_once('/')?;
+    let networks = self.networks.values().map(|network| &network.client_keys);
+    std::iter::once(&self.client_keys)
+      .chain(networks)
+      .flatten()
+      .find(|key| key.username == username && key.device.as_deref() == Some(device))
+  }
+
+  /// Users `key` is the last working key of: once it's revoked, no other key of theirs outside `revoked` is
+  /// left, nor a password. Directories and the password file might still let them in, so there are none
+  /// with one of those configured.
+  pub fn locked_out_by(&self, key: &Key, revoked: impl Fn(&Key) -> bool) -> Vec<String> {
+    if self.ldap.is_some() || self.radius.is_some() || self.oidc.is_some() || self.password_file.is_some() {
+      return Vec::new();
+    }
+
+    let networks = self.networks.values();
+    let keys: Vec<_> = std::iter::once(&self.client_keys)
+      .chain(networks.clone().map(|network| &network.client_keys))
+      .flatten()
+      .collect();
+    let passwords: Vec<_> = std::iter::once(&self.client_credentials)
+      .chain(networks.map(|network| &network.client_credentials))
+      .flatten()
+      .filter_map(Credentials::username)
+      .collect();
+
+    let mut locked_out: Vec<_> = keys
+      .iter()
+      .filter(|entry| entry.public_key == *key)
+      .map(|entry| entry.username.clone())
+      .filter(|username| {
+        !passwords.contains(&username.as_str())
+          && !keys.iter().any(|other| {
+            other.username == *username && other.public_key != *key && !revoked(&other.public_key)
+          })
+      })
+      .collect();
+    locked_out.sort();
+    locked_out.dedup();
+    locked_out
+  }
+
+  /// Address of the server itself in the default subnet, which the address pool doesn't lease.
+  pub fn default_gateway(&self) -> Option<Ipv4Addr> {
+    match (&self.tun, &self.address_pool) {
+      (Some(tun), _) => Some(tun.address),
+      (None, Some(pool)) => pool.subnet.hosts().next(),
+      (None, None) => None,
+    }
+  }
+
+  /// Address the admin service answers at: the configured one, or the gateway of the default subnet.
+  pub fn admin_service_address(&self) -> Option<Ipv4Addr> {
+    self.admin_service.as_ref()?.address.or(self.default_gateway())
+  }
+
+  /// Address the internal DNS answers at: the configured one, or the gateway of the default subnet.
+  pub fn internal_dns_address(&self) -> Option<Ipv4Addr> {
+    self.internal_dns.as_ref()?.address.or(self.default_gateway())
+  }
+
+  /// Resolvers the internal DNS forwards other names to: the configured ones, or those of the address pool.
+  pub fn internal_dns_upstream(&self) -> Vec<Ipv4Addr> {
+    match (&self.internal_dns, &self.address_pool) {
+      (Some(InternalDnsConfig { upstream: Some(upstream), .. }), _) => upstream.clone(),
+      (Some(_), Some(pool)) => pool.dns.clone(),
+      _ => Vec::new(),
+    }
+  }
+
   pub fn client_timeout(&self) -> Duration {
     Duration::from_secs(self.client_timeout_secs)
   }
+
+  /// Reports settings that can't work together, all at once.
+  pub fn required_client_features(&self) -> anyhow::Result<Features> {
+    self.required_client_features.iter().try_fold(Features::empty(), |features, name| {
+      let feature =
+        Features::from_name(name).ok_or_else(|| anyhow::anyhow!("unknown client feature {}", name))?;
+      Ok(features.union(feature))
+    })
+  }
+
+  pub fn check(&self) -> anyhow::Result<()> {
+    let problems = self.problems();
+    if !problems.is_empty() {
+      anyhow::bail!("Invalid configuration: {}", problems.join("; "));
+    }
+    Ok(())
+  }
+
+  /// Conflicting settings, described one by one.
+  pub fn problems(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Err(e) = self.required_client_features() {
+      problems.push(e.to_string());
+    }
+
+    if !self.port_forwards.is_empty() && self.gateway.is_none() {
+      problems.push("port-forwards require a gateway section".to_string());
+    }
+
+    if let (Some(pool), Some(tun)) = (&self.address_pool, &self.tun) {
+      if !pool.subnet.contains(&tun.address) {
+        problems.push(format!(
+          "the tun address {} is outside the address-pool subnet {}",
+          tun.address, pool.subnet
+        ));
+      }
+    }
+
+    if let Some(ref nat) = self.userspace_nat {
+      if !(MIN_MTU..=MAX_MTU).contains(&nat.mtu) {
+        problems.push(format!("the userspace-nat mtu {} is out of range {}..={}", nat.mtu, MIN_MTU, MAX_MTU));
+      }
+    }
+    if self.userspace_nat.is_some() && self.tun.is_some() {
+      problems.push("userspace-nat replaces the tun section; configure only one of them".to_string());
+    }
+    if self.userspace_nat.is_some() && self.gateway.is_some() {
+      problems.push("userspace-nat can't be combined with gateway mode, which needs a tun".to_string());
+    }
+
+    if self.ca.as_ref().is_some_and(|ca| ca.key_file.is_none() && ca.public_key.is_none()) {
+      problems.push("the ca section needs a public-key to verify certificates with".to_string());
+    }
+
+    if !self.networks.is_empty() && self.tun.is_none() && self.userspace_nat.is_none() {
+      problems.push("networks require a tun or userspace-nat section".to_string());
+    }
+
+    if let Some(ref mirror) = self.mirror {
+      for name in mirror.networks.keys() {
+        if name != DEFAULT_NETWORK && !self.networks.contains_key(name) {
+          problems.push(format!("mirror lists network {}, which isn't configured", name));
+        }
+      }
+    }
+
+    if let Some(shards) = self.workers.session_shards {
+      if shards < 2 || !shards.is_power_of_two() {
+        problems.push(format!("workers.session-shards must be a power of two above 1, not {}", shards));
+      }
+    }
+
+    if let Some(ref bandwidth) = self.bandwidth {
+      if bandwidth.interval_secs == 0 || bandwidth.window_secs < bandwidth.interval_secs {
+        problems.push("bandwidth needs an interval-secs of at least 1, within window-secs".to_string());
+      }
+    }
+
+    if self.networks.contains_key(DEFAULT_NETWORK) {
+      problems
+        .push(format!("the network name {} is reserved for users outside of networks", DEFAULT_NETWORK));
+    }
+    if !self.admin_tokens.is_empty()
+      && self.health_address.is_none()
+      && self.admin_service.is_none()
+      && self.grpc_address.is_none()
+    {
+      problems.push("admin-tokens require a health-address, admin-service or grpc-address".to_string());
+    }
+    if self.admin_service.is_some() && self.admin_service_address().is_none() {
+      problems.push("admin-service requires an address, an address-pool or a tun section".to_string());
+    }
+    if let (Some(address), Some(pool)) = (self.admin_service_address(), &self.address_pool) {
+      if pool.subnet.contains(&address) && Some(address) != self.default_gateway() {
+        problems.push(format!("the admin-service address {} would be leased to clients", address));
+      }
+    }
+    if let Some(ref dns) = self.internal_dns {
+      problems.extend(dns.problems());
+      match (self.internal_dns_address(), &self.address_pool) {
+        (None, _) => {
+          problems.push("internal-dns requires an address, an address-pool or a tun section".to_string())
+        }
+        (Some(address), Some(pool))
+          if pool.subnet.contains(&address) && Some(address) != self.default_gateway() =>
+        {
+          problems.push(format!("the internal-dns address {} would be leased to clients", address));
+        }
+        _ => {}
+      }
+    }
+    for network in self.admin_tokens.iter().filter_map(|admin| admin.network.as_ref()) {
+      if !self.networks.contains_key(network) {
+        problems.push(format!("an admin token is limited to network {}, which isn't configured", network));
+      }
+    }
+
+    let mut subnets: Vec<(String, Ipv4Net)> = Vec::new();
+    if let Some(subnet) = self.default_subnet() {
+      subnets.push(("the default network".to_string(), subnet));
+    }
+    for (name, network) in &self.networks {
+      let owner = format!("network {}", name);
+      for (other, subnet) in &subnets {
+        if subnet.contains(&network.subnet) || network.subnet.contains(subnet) {
+          problems
+            .push(format!("the subnet {} of {} overlaps {} of {}", network.subnet, owner, subnet, other));
+        }
+      }
+      subnets.push((owner, network.subnet));
+    }
+
+    // Users are put in a network by the credentials they log in with, so a name can't be in two of them.
+    let mut users = HashMap::new();
+    let default_users = self
+      .client_credentials
+      .iter()
+      .filter_map(Credentials::username)
+      .chain(self.client_keys.iter().map(|key| key.username.as_str()))
+      .map(|username| (username, "the default network".to_string()));
+    let network_users = self.networks.iter().flat_map(|(name, network)| {
+      let owner = format!("network {}", name);
+      network
+        .client_credentials
+        .iter()
+        .filter_map(Credentials::username)
+        .chain(network.client_keys.iter().map(|key| key.username.as_str()))
+        .map(move |username| (username, owner.clone()))
+    });
+    for (username, owner) in default_users.chain(network_users) {
+      match users.insert(username, owner.clone()) {
+        Some(other) if other != owner => {
+          problems.push(format!("user {} is in both {} and {}", username, other, owner))
+        }
+        _ => {}
+      }
+    }
+
+    // Devices are named as `user/device` in accounting, the admin API and `--revoke`.
+    let mut devices = HashSet::new();
+    let keys = self.client_keys.iter().chain(self.networks.values().flat_map(|network| &network.client_keys));
+    for key in keys {
+      let Some(ref device) = key.device else {
+        continue;
+      };
+      if device.is_empty() || device.contains('/') {
+        problems
+          .push(format!("device name {:?} of user {} must be non-empty and without /", device, key.username));
+      } else if !devices.insert((key.username.as_str(), device.as_str())) {
+        problems.push(format!("user {} has more than one key for device {}", key.username, device));
+      }
+    }
+
+    let mut taken = HashMap::new();
+    taken.insert((Protocol::Udp, self.listen_port), "the server's listen-port".to_string());
+    if let Some(address) = self.health_address {
+      taken.insert((Protocol::Tcp, address.port()), "health-address".to_string());
+    }
+    if let Some(ref cluster) = self.cluster {
+      if let Some(other) = taken.insert((Protocol::Udp, cluster.listen.port()), "cluster.listen".to_string())
+      {
+        problems.push(format!(
+          "udp port {} of cluster.listen is already used by {}",
+          cluster.listen.port(),
+          other
+        ));
+      }
+      if let Err(e) = handshake::parse_key(&cluster.secret) {
+        problems.push(format!("invalid cluster.secret: {}", e));
+      }
+    }
+
+    if self.mdns.is_some() {
+      if let Some(other) = taken.insert((Protocol::Udp, MDNS_PORT), "mdns".to_string()) {
+        problems.push(format!("udp port {} of mdns is already used by {}", MDNS_PORT, other));
+      }
+    }
+
+    if let Some(ref rendezvous) = self.rendezvous {
+      problems.extend(rendezvous.problems());
+    }
+
+    if let Some(ref push) = self.metrics_push {
+      problems.extend(push.problems());
+    }
+
+    let groups = self.groups.iter().chain(self.networks.values().flat_map(|network| &network.groups));
+    for (name, group) in groups {
+      match group.bandwidth_class {
+        Some(ref class) if !self.pacing.classes.contains_key(class) => problems
+          .push(format!("group {} is in bandwidth class {}, which isn't in pacing.classes", name, class)),
+        _ => (),
+      }
+    }
+
+    for (i, rule) in self.auth_policy.iter().enumerate() {
+      let known = |group: &String| {
+        self.groups.contains_key(group)
+          || self.networks.values().any(|network| network.groups.contains_key(group))
+      };
+      match (rule.action, &rule.group) {
+        (RuleAction::Group, None) => problems.push(format!("auth-policy rule {} needs a group", i + 1)),
+        (RuleAction::Group, Some(group)) if !known(group) => {
+          problems.push(format!("auth-policy rule {} puts users in unknown group {}", i + 1, group))
+        }
+        _ => (),
+      }
+    }
+
+    if self.alerts.as_ref().is_some_and(|alerts| alerts.window_secs == 0) {
+      problems.push("alerts.window-secs must be at least 1".to_string());
+    }
+
+    if self.schedule.history_compaction.is_some() && self.history.path.is_none() {
+      problems.push("schedule.history-compaction requires a history path".to_string());
+    }
+    let rotated = matches!(self.audit, Some(AuditConfig::File { .. } | AuditConfig::Sqlite { .. }));
+    if self.schedule.audit_rotation.is_some() && !rotated {
+      problems.push("schedule.audit-rotation requires an audit log of type file or sqlite".to_string());
+    }
+    if self.schedule.audit_vacuum.is_some() && !matches!(self.audit, Some(AuditConfig::Sqlite { .. })) {
+      problems.push("schedule.audit-vacuum requires an audit log of type sqlite".to_string());
+    }
+
+    if let Some(Err(e)) = self.webhook.as_ref().map(WebhookConfig::validate) {
+      problems.push(format!("invalid webhook.url: {}", e));
+    }
+
+    for forward in &self.port_forwards {
+      let owner = format!("the forward for {}", forward.user);
+      if let Some(other) = taken.insert((forward.proto, forward.public_port), owner.clone()) {
+        problems.push(format!(
+          "{} port {} of {} is already used by {}",
+          forward.proto.as_str(),
+          forward.public_port,
+          owner,
+          other
+        ));
+      }
+    }
+
+    problems
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::policy::Priority;
+  use crate::push::PushFormat;
+  use crate::radius::RadiusMethod;
+  use crate::workers::AdmissionConfig;
+  use crate::workers::OverflowPolicy;
+  use crate::workers::SheddingConfig;
   use std::str::FromStr;
+  use vpn_shared::logging::LogTarget;
+  use vpn_shared::logging::SyslogTransport;
 
   #[test]
   fn test_parse_full_config() {
@@ -69,16 +797,868 @@ mod tests {
   }
 
   #[test]
-  fn test_empty_credentials() {
+  fn test_client_keys() {
     let config_str = r#"
             listen-address: "0.0.0.0"
             listen-port: 8000
             max-clients: 10
             client-timeout-secs: 30
             client-credentials: []
+            client-keys:
+              - username: "alice"
+                public-key: "0101010101010101010101010101010101010101010101010101010101010101"
+            revocation-list: "/etc/vpn/revoked"
         "#;
 
     let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
-    assert!(config.client_credentials.is_empty());
+    assert_eq!(config.client_keys, vec![KeyCredentials::new("alice", [1; 32])]);
+    assert_eq!(config.revocation_list, Some(PathBuf::from("/etc/vpn/revoked")));
+  }
+
+  #[test]
+  fn test_devices() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            client-keys:
+              - username: "alice"
+                device: "laptop"
+                public-key: "0101010101010101010101010101010101010101010101010101010101010101"
+              - username: "alice"
+                device: "phone"
+                public-key: "0202020202020202020202020202020202020202020202020202020202020202"
+                quota-mb: 512
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    config.check().unwrap();
+    let phone = config.device_key("alice/phone").unwrap();
+    assert_eq!((phone.public_key, phone.quota_mb), ([2; 32], Some(512)));
+    assert!(config.device_key("alice").is_none());
+    assert!(config.device_key("bob/phone").is_none());
+
+    // Revoking one of alice's keys leaves her the other, unless that one is revoked too.
+    assert!(config.locked_out_by(&[1; 32], |_| false).is_empty());
+    assert_eq!(config.locked_out_by(&[1; 32], |key| *key == [2; 32]), vec!["alice"]);
+    config.client_credentials.push(Credentials::new("alice", "pass"));
+    assert!(config.locked_out_by(&[1; 32], |key| *key == [2; 32]).is_empty());
+    config.client_credentials.clear();
+
+    config.client_keys[1].device = Some("laptop".into());
+    assert!(config.check().unwrap_err().to_string().contains("more than one key for device laptop"));
+    config.client_keys[1].device = Some("a/b".into());
+    assert!(config.check().unwrap_err().to_string().contains("without /"));
+  }
+
+  #[test]
+  fn test_ldap_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            ldap:
+              url: "ldaps://dc.example.com"
+              bind-dn: "{username}@example.com"
+              user-dn: "cn={username},cn=Users,dc=example,dc=com"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let ldap = config.ldap.unwrap();
+    assert_eq!(ldap.bind_dn, "{username}@example.com");
+    assert_eq!(ldap.group_attribute, "memberOf");
+    assert!(!ldap.starttls);
+  }
+
+  #[test]
+  fn test_radius_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            radius:
+              server: "10.0.0.5:1812"
+              secret: "testing123"
+              method: chap
+              accounting: true
+              interim-interval-secs: 300
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let radius = config.radius.unwrap();
+    assert_eq!(radius.server, "10.0.0.5:1812".parse().unwrap());
+    assert_eq!(radius.method, RadiusMethod::Chap);
+    assert_eq!(radius.nas_identifier, "sberlinux-vpn");
+    assert_eq!(radius.interim_interval_secs, Some(300));
+  }
+
+  #[test]
+  fn test_oidc_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "token"
+                token: "static-token"
+            oidc:
+              issuer: "https://idp.example.com/realms/corp"
+              audience: "vpn"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.client_credentials, vec![Credentials::Token("static-token".into())]);
+    let oidc = config.oidc.unwrap();
+    assert_eq!(oidc.username_claim, "preferred_username");
+    assert_eq!(oidc.groups_claim, "groups");
+    assert!(oidc.jwks_url.is_none());
+  }
+
+  #[test]
+  fn test_ca_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            ca:
+              key-file: "/etc/vpn/ca.key"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let ca = config.ca.unwrap();
+    assert_eq!(ca.key_file, Some(PathBuf::from("/etc/vpn/ca.key")));
+    assert_eq!(ca.certificate_ttl_days, 365);
+  }
+
+  #[test]
+  fn test_audit_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            audit:
+              type: "socket"
+              path: "/run/siem.sock"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.audit, Some(AuditConfig::Socket { path: PathBuf::from("/run/siem.sock") }));
+  }
+
+  #[test]
+  fn test_required_client_features() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            required-client-features: ["rekey", "pinned-key"]
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.required_client_features().unwrap(), Features::REKEY.union(Features::PINNED_KEY));
+    config.required_client_features.push("teleport".to_string());
+    assert!(config.check().unwrap_err().to_string().contains("unknown client feature teleport"));
+  }
+
+  #[test]
+  fn test_syslog_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            log:
+              level: "debug"
+              target:
+                type: "syslog"
+                transport: "tcp"
+                address: "logs.example.com:601"
+                facility: "local3"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.log.level, "debug");
+    let LogTarget::Syslog(syslog) = config.log.target else {
+      panic!("Expected a syslog target");
+    };
+    assert_eq!(syslog.transport, SyslogTransport::Tcp);
+    assert_eq!(syslog.facility, "local3");
+  }
+
+  #[test]
+  fn test_empty_credentials() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert!(config.client_credentials.is_empty());
+    assert!(config.health_address.is_none());
+    assert_eq!(config.quarantine, QuarantineConfig::default());
+  }
+
+  #[test]
+  fn test_quarantine_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            quarantine:
+              max-failures: 5
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.quarantine, QuarantineConfig { max_failures: 5, ..Default::default() });
+  }
+
+  #[test]
+  fn test_workers_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            workers:
+              concurrency: 2
+              overflow: block
+              shedding:
+                retry-after-secs: 10
+              admission:
+                handshakes-per-sec: 20
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.workers.concurrency, 2);
+    assert_eq!(config.workers.queue_depth, 1024);
+    assert_eq!(config.workers.overflow, OverflowPolicy::Block);
+    assert_eq!(config.workers.shedding, Some(SheddingConfig { retry_after_secs: 10, ..Default::default() }));
+    assert_eq!(
+      config.workers.admission,
+      Some(AdmissionConfig { handshakes_per_sec: 20, ..Default::default() })
+    );
+  }
+
+  #[test]
+  fn test_pacing_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            pacing:
+              bytes-per-sec: 125000
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.pacing, PacingConfig { bytes_per_sec: Some(125000), ..Default::default() });
+  }
+
+  #[test]
+  fn test_runtime_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            runtime:
+              worker-threads: 2
+              cpu-affinity: [2, 3]
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(
+      config.runtime,
+      RuntimeConfig { worker_threads: Some(2), max_blocking_threads: None, cpu_affinity: vec![2, 3] }
+    );
+  }
+
+  #[test]
+  fn test_crypto_offload_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            crypto-offload:
+              threshold-bytes: 4096
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.crypto_offload.threshold_bytes, Some(4096));
+  }
+
+  #[test]
+  fn test_health_address() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            health-address: "127.0.0.1:8080"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.health_address, Some("127.0.0.1:8080".parse().unwrap()));
+  }
+
+  #[test]
+  fn test_admin_service() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            admin-service: {}
+            admin-tokens:
+              - token: "secret"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.admin_service, Some(AdminServiceConfig { address: None, port: 80 }));
+    assert!(config.check().unwrap_err().to_string().contains("admin-service requires an address"));
+
+    config.address_pool = Some(AddressPoolConfig { subnet: "10.8.0.0/24".parse().unwrap(), dns: Vec::new() });
+    assert_eq!(config.admin_service_address(), Some(Ipv4Addr::new(10, 8, 0, 1)));
+    config.check().unwrap();
+
+    config.admin_service.as_mut().unwrap().address = Some(Ipv4Addr::new(10, 8, 0, 2));
+    assert!(config.check().unwrap_err().to_string().contains("10.8.0.2 would be leased to clients"));
+  }
+
+  #[test]
+  fn test_internal_dns_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            internal-dns: {}
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.internal_dns.as_ref().unwrap().domain, "vpn.internal");
+    assert!(config.check().unwrap_err().to_string().contains("internal-dns requires an address"));
+
+    let dns = vec![Ipv4Addr::new(1, 1, 1, 1)];
+    config.address_pool =
+      Some(AddressPoolConfig { subnet: "10.8.0.0/24".parse().unwrap(), dns: dns.clone() });
+    assert_eq!(config.internal_dns_address(), Some(Ipv4Addr::new(10, 8, 0, 1)));
+    assert_eq!(config.internal_dns_upstream(), dns);
+    config.check().unwrap();
+
+    config.internal_dns.as_mut().unwrap().address = Some(Ipv4Addr::new(10, 8, 0, 2));
+    assert!(config.check().unwrap_err().to_string().contains("10.8.0.2 would be leased to clients"));
+  }
+
+  #[test]
+  fn test_gateway_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            tun:
+              name: "vpn%d"
+              address: "10.0.0.1"
+              netmask: "255.255.255.0"
+            gateway:
+              fix-sysctls: true
+              egress:
+                - users: ["user1"]
+                  interface: "eth1"
+                  table: 100
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.tun.unwrap().name, "vpn%d");
+
+    let gateway = config.gateway.unwrap();
+    assert!(gateway.fix_sysctls);
+    assert_eq!(
+      gateway.egress,
+      vec![EgressRule { users: vec!["user1".into()], groups: vec![], interface: "eth1".into(), table: 100 }]
+    );
+  }
+
+  #[test]
+  fn test_groups() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            groups:
+              staff:
+                members: ["user1", "user2"]
+                acl: ["10.10.0.0/16"]
+                routes: ["10.10.0.0/16"]
+                dns: ["10.10.0.53"]
+                bandwidth-class: standard
+                quota-mb: 1024
+              admins:
+                members: ["root"]
+                bandwidth-class: unknown
+            pacing:
+              classes:
+                standard:
+                  bytes-per-sec: 1250000
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+
+    assert_eq!(config.groups.len(), 2);
+    assert_eq!(config.groups["staff"].members, vec!["user1", "user2"]);
+    assert_eq!(config.groups["staff"].quota_mb, Some(1024));
+    assert_eq!(config.groups["staff"].routes, vec!["10.10.0.0/16".parse::<Ipv4Net>().unwrap()]);
+    assert_eq!(config.groups["staff"].dns, vec![Ipv4Addr::new(10, 10, 0, 53)]);
+    assert_eq!(config.pacing.classes["standard"].bytes_per_sec, Some(1250000));
+    assert!(config.groups["admins"].acl.is_empty());
+    assert_eq!(
+      config.problems(),
+      vec!["group admins is in bandwidth class unknown, which isn't in pacing.classes".to_string()]
+    );
+  }
+
+  #[test]
+  fn test_port_forwards() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            gateway: {}
+            port-forwards:
+              - user: "alice"
+                proto: "tcp"
+                public-port: 8080
+                client-port: 80
+              - user: "bob"
+                proto: "udp"
+                public-port: 8080
+                client-port: 53
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(
+      config.port_forwards[0],
+      PortForward { user: "alice".into(), proto: Protocol::Tcp, public_port: 8080, client_port: 80 }
+    );
+    config.check().unwrap();
+
+    config.port_forwards[1].public_port = 8000;
+    config.port_forwards.push(PortForward {
+      user: "carol".into(),
+      proto: Protocol::Tcp,
+      public_port: 8080,
+      client_port: 22,
+    });
+    let error = config.check().unwrap_err().to_string();
+    assert!(
+      error.contains("udp port 8000 of the forward for bob is already used by the server's listen-port")
+    );
+    assert!(error.contains("tcp port 8080 of the forward for carol is already used by the forward for alice"));
+
+    config.gateway = None;
+    assert!(config.check().unwrap_err().to_string().contains("require a gateway"));
+  }
+
+  #[test]
+  fn test_userspace_nat_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials: []
+            userspace-nat:
+              mtu: 1280
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.userspace_nat, Some(UserspaceNatConfig { mtu: 1280, ..Default::default() }));
+    config.check().unwrap();
+
+    config.gateway = Some(GatewayConfig::default());
+    assert!(config.check().unwrap_err().to_string().contains("can't be combined with gateway"));
+
+    config.gateway = None;
+    config.userspace_nat = Some(UserspaceNatConfig { mtu: 100, ..Default::default() });
+    assert!(config.check().unwrap_err().to_string().contains("userspace-nat mtu 100 is out of range"));
+  }
+
+  #[test]
+  fn test_networks() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            tun:
+              name: "vpn%d"
+              address: "10.0.0.1"
+              netmask: "255.255.255.0"
+            networks:
+              acme:
+                subnet: "10.1.0.0/24"
+                dns: ["10.1.0.53"]
+                client-credentials:
+                  - type: "password"
+                    username: "alice"
+                    password: "pass"
+                groups:
+                  staff:
+                    members: ["alice"]
+                    acl: ["10.1.0.0/24"]
+              globex:
+                subnet: "10.2.0.0/24"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let acme = &config.networks["acme"];
+    assert_eq!(acme.dns, vec![Ipv4Addr::new(10, 1, 0, 53)]);
+    assert_eq!(acme.groups["staff"].members, vec!["alice"]);
+    assert!(config.networks["globex"].client_credentials.is_empty());
+    config.check().unwrap();
+
+    config.networks.get_mut("globex").unwrap().subnet = "10.0.0.128/25".parse().unwrap();
+    config.networks.get_mut("globex").unwrap().client_credentials.push(Credentials::new("admin", "other"));
+    let error = config.check().unwrap_err().to_string();
+    assert!(error
+      .contains("the subnet 10.0.0.128/25 of network globex overlaps 10.0.0.0/24 of the default network"));
+    assert!(error.contains("user admin is in both the default network and network globex"));
+
+    config.tun = None;
+    assert!(config.check().unwrap_err().to_string().contains("networks require a tun"));
+
+    config.admin_tokens.push(AdminToken { token: "secret".into(), network: Some("initech".into()) });
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("admin-tokens require a health-address"));
+    assert!(error.contains("limited to network initech, which isn't configured"));
+  }
+
+  #[test]
+  fn test_simple_config() {
+    let mut config = ServerConfig::simple();
+    assert!(!config.has_authentication());
+    config.apply_simple();
+    config.check().unwrap();
+
+    let tun = config.tun.as_ref().unwrap();
+    assert_eq!(tun.address, Ipv4Addr::new(10, 8, 0, 1));
+    assert_eq!(config.address_pool.unwrap().subnet, "10.8.0.0/24".parse().unwrap());
+    assert!(config.gateway.unwrap().fix_sysctls);
+    assert_eq!(config.health_address, Some("0.0.0.0:8080".parse().unwrap()));
+
+    let mut config = ServerConfig::simple();
+    config.userspace_nat = Some(UserspaceNatConfig::default());
+    config.apply_simple();
+    config.check().unwrap();
+    assert!(config.tun.is_none() && config.gateway.is_none());
+  }
+
+  #[test]
+  fn test_cluster() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            cluster:
+              listen: "10.0.0.1:9697"
+              peers: ["10.0.0.2:9697"]
+              secret: "0000000000000000000000000000000000000000000000000000000000000000"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let cluster = config.cluster.as_mut().unwrap();
+    assert_eq!(cluster.peers, vec!["10.0.0.2:9697".parse().unwrap()]);
+    assert_eq!(cluster.announce_interval_secs, 10);
+    config.check().unwrap();
+
+    let cluster = config.cluster.as_mut().unwrap();
+    cluster.listen.set_port(8000);
+    cluster.secret = "nope".to_string();
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("udp port 8000 of cluster.listen"), "{}", error);
+    assert!(error.contains("invalid cluster.secret"), "{}", error);
+  }
+
+  #[test]
+  fn test_webhook_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            webhook:
+              url: "https://hooks.example.com/vpn"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.webhook.as_ref().unwrap().timeout_secs, 5);
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("invalid webhook.url"), "{}", error);
+
+    config.webhook.as_mut().unwrap().url = "http://127.0.0.1:9000/vpn".to_string();
+    config.check().unwrap();
+  }
+
+  #[test]
+  fn test_preemption_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "alice"
+                password: "pass"
+            groups:
+              oncall:
+                members: ["alice"]
+                priority: "high"
+            preemption:
+              min-idle-secs: 300
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.groups["oncall"].priority, Priority::High);
+    assert_eq!(config.preemption.unwrap().min_idle(), Duration::from_secs(300));
+  }
+
+  #[test]
+  fn test_mdns_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 5353
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            mdns:
+              name: "lab"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let mdns = config.mdns.as_ref().unwrap();
+    assert_eq!(mdns.name.as_deref(), Some("lab"));
+    assert_eq!(mdns.interface, None);
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("udp port 5353 of mdns"), "{}", error);
+
+    config.listen_port = 9696;
+    config.check().unwrap();
+  }
+
+  #[test]
+  fn test_rendezvous_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 9696
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            rendezvous:
+              address: "rendezvous.example.com:9696"
+              name: "home"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.rendezvous.as_ref().unwrap().interval_secs, 20);
+    assert!(!config.rendezvous_service);
+    config.check().unwrap();
+
+    config.rendezvous.as_mut().unwrap().interval_secs = 60;
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("rendezvous.interval-secs"), "{}", error);
+  }
+
+  #[test]
+  fn test_bandwidth_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            bandwidth:
+              window-secs: 600
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.bandwidth, Some(BandwidthConfig { interval_secs: 5, window_secs: 600 }));
+    config.check().unwrap();
+
+    config.bandwidth = Some(BandwidthConfig { interval_secs: 0, window_secs: 600 });
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("bandwidth needs an interval-secs"), "{}", error);
+  }
+
+  #[test]
+  fn test_metrics_push_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            metrics-push:
+              format: influx
+              address: "telegraf.example.com:8094"
+              tags:
+                server: vpn1
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    let push = config.metrics_push.clone().unwrap();
+    assert_eq!(push.format, PushFormat::Influx);
+    assert_eq!(push.interval_secs, 10);
+    assert_eq!(push.tags.get("server").map(String::as_str), Some("vpn1"));
+    config.check().unwrap();
+
+    config.metrics_push = Some(MetricsPushConfig { interval_secs: 0, address: String::new(), ..push });
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("metrics-push.interval-secs must be at least 1"), "{}", error);
+    assert!(error.contains("metrics-push.address is required"), "{}", error);
+  }
+
+  #[test]
+  fn test_auth_policy_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            groups:
+              office:
+                members: []
+            auth-policy:
+              - when: "source.inSubnet('10.0.0.0/8')"
+                action: group
+                group: office
+              - when: "hour < 8 && !('admins' in groups)"
+                action: deny
+                message: "Outside working hours"
+        "#;
+
+    let mut config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.auth_policy.len(), 2);
+    assert_eq!(config.auth_policy[1].action, RuleAction::Deny);
+    config.check().unwrap();
+
+    config.auth_policy[0].group = Some("lab".to_string());
+    let error = config.check().unwrap_err().to_string();
+    assert!(error.contains("auth-policy rule 1 puts users in unknown group lab"), "{}", error);
+
+    let invalid = config_str.replace("hour < 8", "hour < '8'");
+    let error = serde_yml::from_str::<ServerConfig>(&invalid).unwrap_err().to_string();
+    assert!(error.contains("an operand of an ordering must be an int"), "{}", error);
+  }
+
+  #[test]
+  fn test_rekey_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            rekey:
+              decrypt-failures: 0
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.rekey, RekeyConfig { on_roaming: true, decrypt_failures: 0 });
+  }
+
+  #[test]
+  fn test_schedule_config() {
+    let config_str = r#"
+            listen-address: "0.0.0.0"
+            listen-port: 8000
+            max-clients: 10
+            client-timeout-secs: 30
+            client-credentials:
+              - type: "password"
+                username: "admin"
+                password: "pass"
+            schedule:
+              quota-reset: "@monthly"
+              audit-rotation: "0 3 * * 1"
+              audit-vacuum: "@weekly"
+              lease-cleanup: "@hourly"
+        "#;
+
+    let config: ServerConfig = serde_yml::from_str(config_str).unwrap();
+    assert_eq!(config.schedule.quota_reset, Some(crate::schedule::Cron::parse("0 0 1 * *").unwrap()));
+    assert_eq!(config.schedule.audit_keep, 4);
+    assert_eq!(config.schedule.lease_cleanup, Some(crate::schedule::Cron::parse("0 * * * *").unwrap()));
+    let problems = config.check().unwrap_err().to_string();
+    assert!(problems.contains("audit-rotation requires an audit log"));
+    assert!(problems.contains("audit-vacuum requires an audit log of type sqlite"));
+
+    let invalid = config_str.replace("@monthly", "0 0 32 * *");
+    assert!(serde_yml::from_str::<ServerConfig>(&invalid).is_err());
   }
 }
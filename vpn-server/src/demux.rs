@@ -0,0 +1,95 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tracing::debug;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::SessionId;
+use vpn_shared::packet::HANDSHAKE_SESSION;
+use vpn_shared::packet::KEY_SIZE;
+use vpn_shared::transform::Pipeline;
+
+use crate::cluster::SessionEntry;
+use crate::server::Server;
+
+#[derive(Debug)]
+pub enum Demux {
+  Handshake,
+  Session(Key, Arc<Pipeline>),
+  /// Datagram of a session larger than agreed on with its client, dropped before it's opened.
+  Oversized {
+    max_packet_size: u32,
+  },
+  /// Packet of the session at this address, arriving from another one; see `Server::roam`.
+  Roaming {
+    key: Key,
+    pipeline: Arc<Pipeline>,
+    from: SocketAddr,
+  },
+  /// Session of another node of the cluster, taken over once a packet of it opens.
+  Cluster {
+    entry: Box<SessionEntry>,
+    pipeline: Arc<Pipeline>,
+  },
+  Unknown,
+}
+
+impl Demux {
+  /// Key and transforms to open the packet with; handshake packets are never transformed.
+  pub fn session(&self) -> Option<(Key, Arc<Pipeline>)> {
+    match self {
+      Demux::Handshake => Some(([0u8; KEY_SIZE], Arc::default())),
+      Demux::Session(key, pipeline) => Some((*key, pipeline.clone())),
+      Demux::Roaming { key, pipeline, .. } => Some((*key, pipeline.clone())),
+      Demux::Cluster { entry, pipeline } => Some((entry.key, pipeline.clone())),
+      Demux::Oversized { .. } | Demux::Unknown => None,
+    }
+  }
+}
+
+impl Server {
+  /// Picks the key for an incoming packet from its session id, so packets of unknown sessions are dropped
+  /// without attempting to decrypt them.
+  pub fn demux(&self, session_id: SessionId, src_addr: SocketAddr, len: usize) -> Demux {
+    if session_id == HANDSHAKE_SESSION {
+      return Demux::Handshake;
+    }
+
+    let Some(addr) = self.sessions.get(&session_id).map(|addr| *addr) else {
+      return self.demux_cluster(session_id);
+    };
+
+    let Some((key, pipeline, limits)) =
+      self.clients.get(&addr).map(|client| (client.key, client.pipeline.clone(), client.limits))
+    else {
+      return Demux::Unknown;
+    };
+    if limits.check_datagram(len).is_err() {
+      return Demux::Oversized { max_packet_size: limits.max_packet_size };
+    }
+
+    if addr != src_addr {
+      debug!("Session {:#x} belongs to {}, not {}", session_id, addr, src_addr);
+      return Demux::Roaming { key, pipeline, from: addr };
+    }
+
+    Demux::Session(key, pipeline)
+  }
+
+  fn demux_cluster(&self, session_id: SessionId) -> Demux {
+    let Some(entry) = self.cluster.as_ref().and_then(|cluster| cluster.lookup(session_id)) else {
+      return Demux::Unknown;
+    };
+
+    match self.transforms.pipeline(&entry.transforms, &entry.key) {
+      Ok(pipeline) => Demux::Cluster { entry: Box::new(entry), pipeline: Arc::new(pipeline) },
+      Err(e) => {
+        debug!("Can't take over session {:#x}: {}", session_id, e);
+        Demux::Unknown
+      }
+    }
+  }
+
+  pub fn record_decrypt_failure(&self, src_addr: SocketAddr, reason: &dyn std::fmt::Display) {
+    self.quarantine.record_failure(src_addr.ip(), reason);
+  }
+}
@@ -0,0 +1,455 @@
+//! Internal DNS: the server answers queries of clients sent to an address inside the tunnel, resolving the
+//! hostnames clients registered under the internal domain to their tunnel addresses and forwarding other
+//! names to upstream resolvers. Queries never reach a tun, so it works with userspace NAT as well.
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::debug;
+use tracing::info;
+use tracing::trace;
+use tracing::warn;
+use vpn_shared::dns;
+use vpn_shared::dns::Message;
+use vpn_shared::dns::Record;
+use vpn_shared::dns::RecordData;
+use vpn_shared::ip;
+use vpn_shared::logging;
+use vpn_shared::packet::fill_random_bytes;
+use vpn_shared::packet::ServerPacket;
+use vpn_shared::packet::SessionId;
+
+use crate::handle_packet::PacketHandler;
+use crate::server::Server;
+
+pub const DNS_PORT: u16 = 53;
+
+/// Forwarded queries an upstream resolver doesn't answer for this long go to the next one, or are forgotten
+/// after the last.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often queries are checked for upstream resolvers that didn't answer.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Forwarded queries waiting for an answer at most; more are refused.
+const MAX_PENDING: usize = 4096;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct InternalDnsConfig {
+  /// Domain hostnames of clients are served under.
+  #[serde(default = "default_domain")]
+  pub domain: String,
+  /// Defaults to the gateway address of the address pool, the tun address or else its first host.
+  #[serde(default)]
+  pub address: Option<Ipv4Addr>,
+  /// Resolvers other names are forwarded to; defaults to the DNS servers of the address pool.
+  #[serde(default)]
+  pub upstream: Option<Vec<Ipv4Addr>>,
+  #[serde(default = "default_ttl_secs")]
+  pub ttl_secs: u32,
+}
+
+fn default_domain() -> String {
+  "vpn.internal".to_string()
+}
+
+fn default_ttl_secs() -> u32 {
+  60
+}
+
+impl InternalDnsConfig {
+  pub fn problems(&self) -> Vec<String> {
+    let domain = self.domain.trim_end_matches('.');
+    if domain.is_empty() || !domain.split('.').all(dns::is_hostname_label) {
+      return vec![format!("internal-dns.domain {} isn't a valid domain name", self.domain)];
+    }
+    Vec::new()
+  }
+}
+
+/// Query forwarded upstream, answered to the client under its own id.
+struct Pending {
+  client: SocketAddrV4,
+  id: u16,
+  /// Query as sent upstream, under the id it's pending with.
+  query: Vec<u8>,
+  /// Resolvers the client may reach, asked in turn; `attempt` is the one asked last.
+  upstreams: Vec<SocketAddr>,
+  attempt: usize,
+  sent: Instant,
+}
+
+/// Session a hostname resolves to, and the account owning it, see `Server::register_hostname`.
+struct Holder {
+  session_id: SessionId,
+  owner: Option<String>,
+}
+
+pub struct InternalDns {
+  pub address: Ipv4Addr,
+  /// Lowercase, without the trailing dot.
+  domain: String,
+  ttl: u32,
+  upstream: Vec<SocketAddr>,
+  socket: UdpSocket,
+  /// Holder of each registered hostname by the network it's registered in, see `Server::register_hostname`.
+  names: DashMap<(Option<String>, String), Holder>,
+  /// Forwarded queries by the id they were sent upstream with.
+  pending: DashMap<u16, Pending>,
+}
+
+impl InternalDns {
+  /// Binds the socket queries are forwarded upstream from.
+  pub fn bind(config: InternalDnsConfig, address: Ipv4Addr, upstream: Vec<Ipv4Addr>) -> anyhow::Result<Self> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_nonblocking(true)?;
+    let domain = config.domain.trim_end_matches('.').to_ascii_lowercase();
+    info!("Serving hostnames of clients under {} at {}", domain, address);
+    Ok(Self {
+      address,
+      domain,
+      ttl: config.ttl_secs,
+      upstream: upstream.into_iter().map(|resolver| SocketAddr::from((resolver, DNS_PORT))).collect(),
+      socket: UdpSocket::from_std(socket)?,
+      names: DashMap::new(),
+      pending: DashMap::new(),
+    })
+  }
+
+  /// Whether `packet` is a query to this resolver.
+  pub fn is_query(&self, packet: &[u8]) -> bool {
+    ip::udp_datagram(packet).is_some_and(|(_, destination, _)| destination == self.local())
+  }
+
+  /// Full name `name` resolves as.
+  pub fn fqdn(&self, name: &str) -> String {
+    format!("{}.{}", name, self.domain)
+  }
+
+  fn local(&self) -> SocketAddrV4 {
+    SocketAddrV4::new(self.address, DNS_PORT)
+  }
+
+  /// Hostname `name` is of if it's under the domain, which may be none for the domain itself.
+  fn hostname_of(&self, name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if name == self.domain {
+      return Some(String::new());
+    }
+    Some(name.strip_suffix(&self.domain)?.strip_suffix('.')?.to_string())
+  }
+
+  /// Answer to `query` for a name under the domain, `address` being what the hostname resolves to for the
+  /// client asking; names of other clients and other domains are `None`.
+  fn answer(&self, query: &Message, address: Option<Ipv4Addr>) -> anyhow::Result<Vec<u8>> {
+    let Some(question) = query.questions.first() else {
+      return dns::answer(query, dns::RCODE_SERVFAIL, &[]);
+    };
+    match address {
+      Some(address) if matches!(question.record_type, dns::TYPE_A | dns::TYPE_ANY) => {
+        let record = Record { name: question.name.clone(), ttl: self.ttl, data: RecordData::A(address) };
+        dns::answer(query, dns::RCODE_NOERROR, &[record])
+      }
+      Some(_) => dns::answer(query, dns::RCODE_NOERROR, &[]),
+      None => dns::answer(query, dns::RCODE_NXDOMAIN, &[]),
+    }
+  }
+
+  /// Sends `query` of `client` to the first of `upstreams` under a random id of its own, so that answers
+  /// are hard to spoof, failing over to the next ones; false if it can't be sent.
+  async fn forward(
+    &self,
+    query: &[u8],
+    client: SocketAddrV4,
+    upstreams: Vec<SocketAddr>,
+    now: Instant,
+  ) -> anyhow::Result<bool> {
+    if upstreams.is_empty() || self.pending.len() >= MAX_PENDING || query.len() < 2 {
+      return Ok(false);
+    }
+    let mut id = [0u8; 2];
+    fill_random_bytes(&mut id);
+    let id = u16::from_be_bytes(id);
+    if self.pending.contains_key(&id) {
+      return Ok(false);
+    }
+    let mut sent = query.to_vec();
+    sent[..2].copy_from_slice(&id.to_be_bytes());
+    let original = u16::from_be_bytes([query[0], query[1]]);
+    self.pending.insert(id, Pending { client, id: original, query: sent, upstreams, attempt: 0, sent: now });
+    Ok(self.send_pending(id).await)
+  }
+
+  /// Sends the pending query `id` to the upstream of its attempt, moving on while sending fails; false once
+  /// none is left, which forgets the query.
+  async fn send_pending(&self, id: u16) -> bool {
+    loop {
+      let next = self
+        .pending
+        .get(&id)
+        .and_then(|pending| Some((pending.query.clone(), *pending.upstreams.get(pending.attempt)?)));
+      let Some((query, upstream)) = next else {
+        self.pending.remove(&id);
+        return false;
+      };
+      match self.socket.send_to(&query, upstream).await {
+        Ok(_) => return true,
+        Err(e) => {
+          debug!("Failed to forward a DNS query to {}: {}", upstream, e);
+          if let Some(mut pending) = self.pending.get_mut(&id) {
+            pending.attempt += 1;
+          }
+        }
+      }
+    }
+  }
+
+  /// Sends the pending query `id` to its next upstream, after the last one failed it or didn't answer.
+  async fn fail_over(&self, id: u16, now: Instant) -> bool {
+    match self.pending.get_mut(&id) {
+      Some(mut pending) => {
+        pending.attempt += 1;
+        pending.sent = now;
+      }
+      None => return false,
+    }
+    self.send_pending(id).await
+  }
+
+  /// Fails over queries whose upstream didn't answer in time, forgetting those no upstream answered.
+  pub async fn retry(&self, now: Instant) {
+    let late: Vec<u16> = self
+      .pending
+      .iter()
+      .filter(|pending| now.duration_since(pending.sent) >= UPSTREAM_TIMEOUT)
+      .map(|pending| *pending.key())
+      .collect();
+    for id in late {
+      self.fail_over(id, now).await;
+    }
+  }
+}
+
+impl Server {
+  /// Registers `name` for the client at `addr` in place of the one it had; returns the full name, or
+  /// `None` if it was refused. Names are scoped to the network of the client and belong to the account,
+  /// user and device, that registered them: another account's client is refused while the name is held,
+  /// and only a session of the same account, e.g. after a reconnect while the old one times out, takes it
+  /// over.
+  pub fn register_hostname(&self, addr: SocketAddr, name: &str) -> Option<String> {
+    let dns = self.internal_dns.as_ref()?;
+    let name = name.to_ascii_lowercase();
+    if !dns::is_hostname_label(&name) {
+      debug!("Refusing hostname {:?} of client {}: not a valid label", name, addr);
+      return None;
+    }
+    let (session_id, owner, network) = {
+      let client = self.clients.get(&addr)?;
+      (client.session_id, client.account(), client.network.clone())
+    };
+    let key = (network.clone(), name.clone());
+    let taken = dns
+      .names
+      .get(&key)
+      .is_some_and(|holder| holder.session_id != session_id && (owner.is_none() || holder.owner != owner));
+    if taken {
+      debug!("Refusing hostname {} of client {}: another account's client holds it", name, addr);
+      return None;
+    }
+
+    let previous = self.clients.get_mut(&addr)?.hostname.replace(name.clone());
+    if let Some(previous) = previous.filter(|previous| *previous != name) {
+      self.release_hostname(session_id, network.as_deref(), &previous);
+    }
+    dns.names.insert(key, Holder { session_id, owner });
+    let fqdn = dns.fqdn(&name);
+    info!("Client {} is reachable as {}", addr, fqdn);
+    Some(fqdn)
+  }
+
+  /// Stops resolving `name` in `network` to the session `session_id`, unless another session took it over.
+  pub fn release_hostname(&self, session_id: SessionId, network: Option<&str>, name: &str) {
+    if let Some(ref dns) = self.internal_dns {
+      let key = (network.map(str::to_string), name.to_string());
+      dns.names.remove_if(&key, |_, holder| holder.session_id == session_id);
+    }
+  }
+
+  /// Answers a query of the client at `addr` to the internal DNS, or forwards it upstream.
+  pub async fn answer_dns(&self, addr: SocketAddr, packet: &[u8]) -> anyhow::Result<()> {
+    let Some(ref dns) = self.internal_dns else {
+      return Ok(());
+    };
+    let Some((client, _, payload)) = ip::udp_datagram(packet) else {
+      return Ok(());
+    };
+    let query = Message::parse(payload)?;
+    if query.response {
+      return Ok(());
+    }
+
+    let hostname = query.questions.first().and_then(|question| dns.hostname_of(&question.name));
+    let answer = match hostname {
+      Some(hostname) => {
+        let address = self.resolve_hostname(addr, &hostname);
+        trace!(target: logging::DATAPATH, "Client {} resolved {}: {:?}", addr, hostname, address);
+        dns.answer(&query, address)?
+      }
+      None if dns.forward(payload, client, self.upstreams_of(addr), Instant::now()).await? => return Ok(()),
+      None => dns::answer(&query, dns::RCODE_REFUSED, &[])?,
+    };
+    self.send_packet(ServerPacket::Data(ip::udp_packet(dns.local(), client, &answer)), addr).await
+  }
+
+  /// Address `hostname` resolves to for the client at `addr`: only clients of its own network are found.
+  fn resolve_hostname(&self, addr: SocketAddr, hostname: &str) -> Option<Ipv4Addr> {
+    let network = self.clients.get(&addr)?.network.clone();
+    let session_id = self.internal_dns.as_ref()?.names.get(&(network, hostname.to_string()))?.session_id;
+    let holder = *self.sessions.get(&session_id)?;
+    self.clients.get(&holder)?.virtual_ip
+  }
+
+  /// Upstream resolvers the ACL of the client at `addr` lets its queries be forwarded to.
+  fn upstreams_of(&self, addr: SocketAddr) -> Vec<SocketAddr> {
+    let (Some(dns), Some(client)) = (self.internal_dns.as_ref(), self.clients.get(&addr)) else {
+      return Vec::new();
+    };
+    let allowed =
+      |upstream: &&SocketAddr| matches!(upstream.ip(), IpAddr::V4(ip) if client.policy.allows(ip));
+    dns.upstream.iter().filter(allowed).copied().collect()
+  }
+
+  /// Relays answers of upstream resolvers to the clients whose queries were forwarded.
+  pub async fn relay_dns(&self) {
+    let Some(ref dns) = self.internal_dns else {
+      return;
+    };
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut retries = tokio::time::interval(RETRY_INTERVAL);
+    loop {
+      let received = tokio::select! {
+        received = dns.socket.recv_from(&mut buf) => received,
+        _ = retries.tick() => {
+          dns.retry(Instant::now()).await;
+          continue;
+        }
+      };
+      let (len, from) = match received {
+        Ok(received) => received,
+        Err(e) => {
+          warn!("Failed to receive an answer of an upstream resolver: {}", e);
+          continue;
+        }
+      };
+      if len < 4 {
+        continue;
+      }
+      let id = u16::from_be_bytes([buf[0], buf[1]]);
+      // Only the resolvers asked so far answer, and one failing the query leaves it to the next.
+      let Some((asked, last)) = dns.pending.get(&id).map(|pending| {
+        (
+          pending.upstreams[..=pending.attempt].contains(&from),
+          pending.attempt + 1 == pending.upstreams.len(),
+        )
+      }) else {
+        continue;
+      };
+      let rcode = buf[3] & 0x0f;
+      if !asked {
+        continue;
+      }
+      if !last && matches!(rcode, dns::RCODE_SERVFAIL | dns::RCODE_REFUSED) {
+        debug!("Upstream resolver {} failed a query with rcode {}; asking the next", from, rcode);
+        dns.fail_over(id, Instant::now()).await;
+        continue;
+      }
+      let Some((_, pending)) = dns.pending.remove(&id) else {
+        continue;
+      };
+      buf[..2].copy_from_slice(&pending.id.to_be_bytes());
+      let Some(addr) = self.route_to(*pending.client.ip()) else {
+        continue;
+      };
+      let packet = ip::udp_packet(dns.local(), pending.client, &buf[..len]);
+      if let Err(e) = self.send_packet(ServerPacket::Data(packet), addr).await {
+        warn!("Failed to relay a DNS answer to {}: {}", addr, e);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn internal_dns() -> InternalDns {
+    let config: InternalDnsConfig = serde_yml::from_str("domain: VPN.internal.").unwrap();
+    assert!(config.problems().is_empty());
+    InternalDns::bind(config, Ipv4Addr::new(10, 8, 0, 1), Vec::new()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_answer() {
+    let dns = internal_dns();
+    assert_eq!(dns.fqdn("laptop"), "laptop.vpn.internal");
+    assert_eq!(dns.hostname_of("Laptop.vpn.internal.").as_deref(), Some("laptop"));
+    assert_eq!(dns.hostname_of("vpn.internal").as_deref(), Some(""));
+    assert_eq!(dns.hostname_of("laptopvpn.internal"), None);
+    assert_eq!(dns.hostname_of("example.com"), None);
+
+    let address = Ipv4Addr::new(10, 8, 0, 2);
+    let query =
+      |record_type| Message::parse(&dns::query(1, "laptop.vpn.internal", record_type).unwrap()).unwrap();
+    let answer = Message::parse(&dns.answer(&query(dns::TYPE_A), Some(address)).unwrap()).unwrap();
+    assert_eq!(answer.answers[0].data, RecordData::A(address));
+    let answer = Message::parse(&dns.answer(&query(dns::TYPE_TXT), Some(address)).unwrap()).unwrap();
+    assert_eq!((answer.rcode, answer.answers.len()), (dns::RCODE_NOERROR, 0));
+    let answer = Message::parse(&dns.answer(&query(dns::TYPE_A), None).unwrap()).unwrap();
+    assert_eq!(answer.rcode, dns::RCODE_NXDOMAIN);
+
+    // Without upstream resolvers other names aren't forwarded.
+    let client = SocketAddrV4::new(address, 40000);
+    assert!(!dns
+      .forward(&dns::query(2, "example.com", dns::TYPE_A).unwrap(), client, Vec::new(), Instant::now())
+      .await
+      .unwrap());
+
+    let config: InternalDnsConfig = serde_yml::from_str("domain: -vpn.internal").unwrap();
+    assert_eq!(config.problems().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_fail_over() {
+    let dns = internal_dns();
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let answering = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let upstreams = vec![silent.local_addr().unwrap(), answering.local_addr().unwrap()];
+
+    let now = Instant::now();
+    let client = SocketAddrV4::new(Ipv4Addr::new(10, 8, 0, 2), 40000);
+    let query = dns::query(7, "example.com", dns::TYPE_A).unwrap();
+    assert!(dns.forward(&query, client, upstreams, now).await.unwrap());
+    let mut buf = [0u8; 512];
+    let (len, _) = silent.recv_from(&mut buf).await.unwrap();
+    let id = [buf[0], buf[1]];
+    assert_eq!(buf[2..len], query[2..]);
+
+    // The first resolver doesn't answer in time, so the query goes to the second under the same id.
+    dns.retry(now + UPSTREAM_TIMEOUT / 2).await;
+    dns.retry(now + UPSTREAM_TIMEOUT).await;
+    let (len, _) = answering.recv_from(&mut buf).await.unwrap();
+    assert_eq!(([buf[0], buf[1]], &buf[2..len]), (id, &query[2..]));
+
+    // And none answering in time forgets it.
+    dns.retry(now + UPSTREAM_TIMEOUT * 2).await;
+    assert!(dns.pending.is_empty());
+  }
+}
@@ -0,0 +1,26 @@
+// Only the `wasm` feature implements filters in the binary; the library exposes the trait regardless.
+#![cfg_attr(not(feature = "wasm"), allow(dead_code))]
+
+use crate::accounting::Direction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  Accept,
+  Drop,
+}
+
+/// Packet passing through the server, as seen by a `PacketFilter`.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketContext<'a> {
+  pub direction: Direction,
+  pub username: &'a str,
+  /// IPv4 packet; from the client for inbound packets, to it for outbound ones.
+  pub packet: &'a [u8],
+}
+
+/// Per-packet policy consulted, in order, after the group ACLs. A packet is forwarded only if every filter
+/// accepts it; a filter that fails is treated as dropping the packet.
+pub trait PacketFilter: Send + Sync {
+  fn name(&self) -> &str;
+  fn filter(&self, context: &PacketContext) -> anyhow::Result<Action>;
+}
@@ -0,0 +1,115 @@
+//! Reverse port forwards: ports of the server a client asks to have forwarded to its virtual address, like
+//! `ssh -R`, so that a service behind it is reachable from outside without a forward in the configuration.
+//! They're installed the way configured port forwards are and last as long as the session.
+
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+
+use dashmap::mapref::entry::Entry;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::logging;
+use vpn_shared::packet::ReverseForward;
+
+use crate::nat::PortForward;
+use crate::nat::Protocol;
+use crate::server::Server;
+use crate::server::Tun;
+
+fn rule(username: &str, forward: ReverseForward) -> Option<PortForward> {
+  let proto = Protocol::from_number(forward.protocol)?;
+  Some(PortForward {
+    user: username.to_string(),
+    proto,
+    public_port: forward.port,
+    client_port: forward.port,
+  })
+}
+
+impl Server {
+  /// Forwards the ports of `requested` the client's policy allows and nothing else takes to it, in place of
+  /// the ones it registered before; returns those forwarded and those refused.
+  pub async fn register_forwards(
+    &self,
+    addr: SocketAddr,
+    requested: Vec<ReverseForward>,
+  ) -> (Vec<ReverseForward>, Vec<ReverseForward>) {
+    let (previous, username, virtual_ip, policy) = match self.clients.get_mut(&addr) {
+      Some(mut client) => (
+        std::mem::take(&mut client.reverse_forwards),
+        client.username.clone().unwrap_or_default(),
+        client.virtual_ip,
+        client.policy.clone(),
+      ),
+      None => return (Vec::new(), requested),
+    };
+    let Some(address) = virtual_ip else {
+      return (Vec::new(), requested);
+    };
+    self.withdraw_forwards(addr, &username, address, &previous).await;
+
+    let forwarding = self.nat.is_enabled() && matches!(self.tun, Some(Tun::Device { .. }));
+    let (mut accepted, mut rejected) = (Vec::new(), Vec::new());
+    for forward in requested {
+      let reason = match Protocol::from_number(forward.protocol) {
+        _ if !forwarding => Some("the server doesn't forward ports"),
+        None => Some("neither TCP nor UDP"),
+        Some(_) if !policy.may_forward(forward.port) => Some("not allowed by policy"),
+        Some(protocol) if self.nat.is_public_port_taken(protocol, forward.port) => {
+          Some("taken by a configured port forward")
+        }
+        Some(_) => match self.reverse_forwards.entry(forward) {
+          Entry::Occupied(owner) if *owner.get() == addr => continue,
+          Entry::Occupied(_) => Some("forwarded to another client"),
+          Entry::Vacant(entry) => {
+            entry.insert(addr);
+            None
+          }
+        },
+      };
+      if let Some(reason) = reason {
+        let port = forward.port;
+        warn!(target: logging::DATAPATH, "Refusing to forward port {} to client {}: {}", port, addr, reason);
+        rejected.push(forward);
+        continue;
+      }
+
+      let Some(rule) = rule(&username, forward) else { continue };
+      match rule.open(address).await {
+        Ok(()) => accepted.push(forward),
+        Err(e) => {
+          let port = forward.port;
+          warn!(target: logging::DATAPATH, "Failed to forward port {} to client {}: {}", port, addr, e);
+          self.reverse_forwards.remove(&forward);
+          rejected.push(forward);
+        }
+      }
+    }
+
+    match self.clients.get_mut(&addr) {
+      Some(mut client) => client.reverse_forwards = accepted.clone(),
+      // Gone while the rules were being added, after `remove_client` withdrew what it knew of.
+      None => self.withdraw_forwards(addr, &username, address, &accepted).await,
+    }
+    (accepted, rejected)
+  }
+
+  /// Stops forwarding `forwards` to the client at `addr`, e.g. once it's gone.
+  pub async fn withdraw_forwards(
+    &self,
+    addr: SocketAddr,
+    username: &str,
+    address: Ipv4Addr,
+    forwards: &[ReverseForward],
+  ) {
+    for &forward in forwards {
+      if self.reverse_forwards.remove_if(&forward, |_, owner| *owner == addr).is_none() {
+        continue;
+      }
+      if let Some(rule) = rule(username, forward) {
+        rule.close(address).await;
+        info!(target: logging::DATAPATH, "No longer forwarding port {} to client {}", forward.port, addr);
+      }
+    }
+  }
+}
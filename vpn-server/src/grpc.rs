@@ -0,0 +1,438 @@
+//! The admin API over gRPC, for orchestration that prefers typed RPCs to the JSON of the admin routes; the
+//! service is published as `proto/admin.proto`.
+
+#[cfg(feature = "grpc")]
+pub use service::serve;
+
+#[cfg(feature = "grpc")]
+pub mod proto {
+  tonic::include_proto!("sberlinux.vpn.admin.v1");
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+  use std::net::Ipv4Addr;
+  use std::net::SocketAddr;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use tokio::net::TcpListener;
+  use tonic::transport::server::TcpIncoming;
+  use tonic::Request;
+  use tonic::Response;
+  use tonic::Status;
+  use tracing::info;
+  use vpn_shared::logging;
+
+  use super::proto;
+  use super::proto::admin_server::AdminServer;
+  use crate::admin;
+  use crate::admin::AdminError;
+  use crate::health::Scope;
+  use crate::history::SessionRecord;
+  use crate::reload::ConfigDiff;
+  use crate::server::Server;
+  use crate::startup::Component;
+  use crate::trace::Flow;
+
+  pub async fn serve(address: SocketAddr, server: Arc<Server>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!(target: logging::ADMIN, "gRPC admin API listening on {}", address);
+    server.startup.ready(Component::Grpc);
+    serve_on(listener, server).await
+  }
+
+  pub(super) async fn serve_on(listener: TcpListener, server: Arc<Server>) -> anyhow::Result<()> {
+    tonic::transport::Server::builder()
+      .add_service(AdminServer::new(Admin { server }))
+      .serve_with_incoming(TcpIncoming::from(listener))
+      .await?;
+    Ok(())
+  }
+
+  struct Admin {
+    server: Arc<Server>,
+  }
+
+  impl Admin {
+    /// Scope of the token in the `authorization` metadata, as with the `Authorization` header of the admin
+    /// routes.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<Scope, Status> {
+      let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+      let local = request.remote_addr().is_some_and(|peer| peer.ip().is_loopback());
+      admin::authorize(&self.server, token, local).map_err(status)
+    }
+  }
+
+  #[tonic::async_trait]
+  impl proto::admin_server::Admin for Admin {
+    async fn get_log_level(
+      &self,
+      request: Request<proto::GetLogLevelRequest>,
+    ) -> Result<Response<proto::LogLevel>, Status> {
+      let scope = self.authorize(&request)?;
+      let level = admin::log_level(&scope).map_err(status)?;
+      Ok(Response::new(proto::LogLevel { level: level.to_string() }))
+    }
+
+    async fn dump_flight_recorder(
+      &self,
+      request: Request<proto::DumpFlightRecorderRequest>,
+    ) -> Result<Response<proto::DumpFlightRecorderResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let path = admin::dump_flight_recorder(&scope).map_err(status)?;
+      Ok(Response::new(proto::DumpFlightRecorderResponse { path: path.display().to_string() }))
+    }
+
+    async fn set_log_level(
+      &self,
+      request: Request<proto::LogLevel>,
+    ) -> Result<Response<proto::LogLevel>, Status> {
+      let scope = self.authorize(&request)?;
+      let level = admin::set_log_level(&scope, &request.into_inner().level).map_err(status)?;
+      Ok(Response::new(proto::LogLevel { level: level.to_string() }))
+    }
+
+    async fn list_sessions(
+      &self,
+      request: Request<proto::ListSessionsRequest>,
+    ) -> Result<Response<proto::ListSessionsResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let sessions = match request.into_inner().username {
+        Some(username) => admin::sessions(&self.server, &scope, &username)
+          .into_iter()
+          .map(|record| session(username.clone(), record))
+          .collect(),
+        None => admin::latest_sessions(&self.server, &scope)
+          .into_iter()
+          .map(|(username, record)| session(username, record))
+          .collect(),
+      };
+      Ok(Response::new(proto::ListSessionsResponse { sessions }))
+    }
+
+    async fn list_clients(
+      &self,
+      request: Request<proto::ListClientsRequest>,
+    ) -> Result<Response<proto::ListClientsResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let clients = admin::clients(&self.server, &scope)
+        .into_iter()
+        .map(|client| proto::Session {
+          clock_offset_secs: Some(client.clock_offset_secs),
+          ..session(client.username, client.session)
+        })
+        .collect();
+      Ok(Response::new(proto::ListClientsResponse { clients }))
+    }
+
+    async fn kick_client(
+      &self,
+      request: Request<proto::KickClientRequest>,
+    ) -> Result<Response<proto::KickClientResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let peer = request.remote_addr().map_or(Ipv4Addr::UNSPECIFIED.into(), |peer| peer.ip());
+      let request = request.into_inner();
+      let kicked =
+        admin::kick(&self.server, &scope, &request.username, peer, request.force).await.map_err(status)?;
+      Ok(Response::new(proto::KickClientResponse { kicked: kicked as u32 }))
+    }
+
+    async fn start_trace(
+      &self,
+      request: Request<proto::StartTraceRequest>,
+    ) -> Result<Response<proto::StartTraceResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let request = request.into_inner();
+      let duration = request.duration_secs.map(Duration::from_secs);
+      let duration =
+        admin::start_trace(&self.server, &scope, &request.session_id, duration).map_err(status)?;
+      Ok(Response::new(proto::StartTraceResponse { duration_secs: duration.as_secs() }))
+    }
+
+    async fn get_trace(
+      &self,
+      request: Request<proto::GetTraceRequest>,
+    ) -> Result<Response<proto::Trace>, Status> {
+      let scope = self.authorize(&request)?;
+      let trace = admin::trace(&self.server, &scope, &request.into_inner().session_id).map_err(status)?;
+      Ok(Response::new(proto::Trace {
+        session_id: trace.session_id,
+        started_at: trace.started_at,
+        recording: trace.recording,
+        remaining_secs: trace.remaining_secs,
+        overwritten: trace.overwritten,
+        entries: trace
+          .entries
+          .into_iter()
+          .map(|entry| proto::TraceEntry {
+            seq: entry.seq,
+            at_ms: entry.at_ms,
+            flow: match entry.flow {
+              Flow::Received => "received",
+              Flow::Sent => "sent",
+            }
+            .to_string(),
+            kind: entry.kind.to_string(),
+            size: entry.size as u64,
+            decision: entry.decision,
+          })
+          .collect(),
+      }))
+    }
+
+    async fn delete_trace(
+      &self,
+      request: Request<proto::DeleteTraceRequest>,
+    ) -> Result<Response<proto::DeleteTraceResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      admin::delete_trace(&self.server, &scope, &request.into_inner().session_id).map_err(status)?;
+      Ok(Response::new(proto::DeleteTraceResponse {}))
+    }
+
+    async fn get_bandwidth(
+      &self,
+      request: Request<proto::GetBandwidthRequest>,
+    ) -> Result<Response<proto::GetBandwidthResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let graphs = match request.into_inner().session_id {
+        Some(session_id) => vec![admin::bandwidth_graph(&self.server, &scope, &session_id).map_err(status)?],
+        None => admin::bandwidth_graphs(&self.server, &scope).map_err(status)?,
+      };
+      Ok(Response::new(proto::GetBandwidthResponse {
+        graphs: graphs
+          .into_iter()
+          .map(|graph| proto::BandwidthGraph {
+            session_id: graph.session_id,
+            username: graph.username,
+            interval_secs: graph.interval_secs,
+            samples: graph
+              .samples
+              .into_iter()
+              .map(|sample| proto::BandwidthSample {
+                at: sample.at,
+                bytes_in: sample.bytes_in,
+                bytes_out: sample.bytes_out,
+              })
+              .collect(),
+          })
+          .collect(),
+      }))
+    }
+
+    async fn rekey_session(
+      &self,
+      request: Request<proto::RekeySessionRequest>,
+    ) -> Result<Response<proto::RekeySessionResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      admin::rekey(&self.server, &scope, &request.into_inner().session_id).await.map_err(status)?;
+      Ok(Response::new(proto::RekeySessionResponse {}))
+    }
+
+    async fn diff_config(
+      &self,
+      request: Request<proto::DiffConfigRequest>,
+    ) -> Result<Response<proto::ConfigDiff>, Status> {
+      let scope = self.authorize(&request)?;
+      let diff = admin::reload(&self.server, &scope, true).await.map_err(status)?;
+      Ok(Response::new(config_diff(diff)))
+    }
+
+    async fn reload(
+      &self,
+      request: Request<proto::ReloadRequest>,
+    ) -> Result<Response<proto::ConfigDiff>, Status> {
+      let scope = self.authorize(&request)?;
+      let diff = admin::reload(&self.server, &scope, request.into_inner().dry_run).await.map_err(status)?;
+      Ok(Response::new(config_diff(diff)))
+    }
+
+    async fn list_listeners(
+      &self,
+      request: Request<proto::ListListenersRequest>,
+    ) -> Result<Response<proto::ListListenersResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let listeners = admin::listeners(&self.server, &scope).map_err(status)?;
+      Ok(Response::new(proto::ListListenersResponse {
+        listeners: listeners
+          .into_iter()
+          .map(|status| proto::ListenerStatus {
+            listener: status.listener.to_string(),
+            address: status.address,
+            accepting_handshakes: status.accepting_handshakes,
+            refused_for_secs: status.refused_for_secs,
+            handshake_packets: status.handshake_packets,
+            handshake_bytes: status.handshake_bytes,
+            data_packets: status.data_packets,
+            data_bytes: status.data_bytes,
+            refused_handshakes: status.refused_handshakes,
+          })
+          .collect(),
+      }))
+    }
+
+    async fn refuse_handshakes(
+      &self,
+      request: Request<proto::RefuseHandshakesRequest>,
+    ) -> Result<Response<proto::RefuseHandshakesResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      let request = request.into_inner();
+      let duration = request.duration_secs.map(Duration::from_secs);
+      admin::refuse_handshakes(&self.server, &scope, &request.listener, duration, request.force)
+        .map_err(status)?;
+      Ok(Response::new(proto::RefuseHandshakesResponse {}))
+    }
+
+    async fn accept_handshakes(
+      &self,
+      request: Request<proto::AcceptHandshakesRequest>,
+    ) -> Result<Response<proto::AcceptHandshakesResponse>, Status> {
+      let scope = self.authorize(&request)?;
+      admin::accept_handshakes(&self.server, &scope, &request.into_inner().listener).map_err(status)?;
+      Ok(Response::new(proto::AcceptHandshakesResponse {}))
+    }
+
+    async fn get_session_table(
+      &self,
+      request: Request<proto::GetSessionTableRequest>,
+    ) -> Result<Response<proto::SessionTable>, Status> {
+      let scope = self.authorize(&request)?;
+      let request = request.into_inner();
+      let limit = request.limit.map(|limit| limit as usize);
+      let table =
+        admin::session_table(&self.server, &scope, request.offset as usize, limit).map_err(status)?;
+      Ok(Response::new(proto::SessionTable {
+        schema_version: table.schema_version,
+        total: table.total as u64,
+        offset: table.offset as u64,
+        next_offset: table.next_offset.map(|offset| offset as u64),
+        sessions: table
+          .sessions
+          .into_iter()
+          .map(|row| proto::SessionRow {
+            session_id: row.session_id,
+            username: row.username,
+            device: row.device,
+            network: row.network,
+            virtual_ip: row.virtual_ip.map(|address| address.to_string()),
+            endpoint: row.endpoint,
+            rx_bytes: row.rx_bytes,
+            tx_bytes: row.tx_bytes,
+            connected_at: row.connected_at,
+            last_handshake: row.last_handshake,
+            idle_secs: row.idle_secs,
+            features: row.features.into_iter().map(str::to_string).collect(),
+          })
+          .collect(),
+      }))
+    }
+  }
+
+  fn session(username: String, record: SessionRecord) -> proto::Session {
+    proto::Session {
+      username,
+      session_id: record.session_id,
+      client_addr: record.client_addr,
+      virtual_ip: record.virtual_ip.map(|ip| ip.to_string()),
+      device: record.device,
+      network: record.network,
+      connected_at: record.connected_at,
+      duration_secs: record.duration_secs,
+      bytes_in: record.bytes_in,
+      bytes_out: record.bytes_out,
+      active: record.active,
+      clock_offset_secs: None,
+    }
+  }
+
+  fn config_diff(diff: ConfigDiff) -> proto::ConfigDiff {
+    proto::ConfigDiff {
+      summary: diff.summary,
+      changed: diff.changed,
+      affected: diff
+        .affected
+        .into_iter()
+        .map(|session| proto::AffectedSession {
+          session_id: session.session_id,
+          username: session.username,
+          reason: session.reason.to_string(),
+        })
+        .collect(),
+      pending: diff.pending,
+    }
+  }
+
+  fn status(error: AdminError) -> Status {
+    match error {
+      AdminError::Unauthorized => Status::unauthenticated(error.to_string()),
+      AdminError::Forbidden => Status::permission_denied(error.to_string()),
+      AdminError::Invalid(reason) => Status::invalid_argument(reason),
+      AdminError::NotFound(reason) => Status::not_found(reason),
+      AdminError::Lockout(reason) => Status::failed_precondition(reason),
+    }
+  }
+}
+
+#[cfg(all(test, feature = "grpc"))]
+mod tests {
+  use std::net::Ipv4Addr;
+  use std::sync::Arc;
+
+  use tokio::net::TcpListener;
+  use tonic::Code;
+  use tonic::Request;
+  use vpn_shared::socket::Network;
+
+  use super::proto;
+  use super::proto::admin_client::AdminClient;
+  use crate::health::AdminToken;
+  use crate::server::Server;
+
+  #[tokio::test]
+  async fn test_admin_api() {
+    let server = Server::builder(Ipv4Addr::new(192, 0, 2, 1), 6969)
+      .with_packet_pipe(1400)
+      .with_memory_transport(Network::new())
+      .with_admin_tokens(vec![AdminToken { token: "initech".into(), network: Some("initech".into()) }])
+      .build()
+      .await
+      .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(super::service::serve_on(listener, Arc::new(server)));
+
+    let mut client = AdminClient::connect(format!("http://{}", address)).await.unwrap();
+    // Local peers need no token.
+    let clients = client.list_clients(proto::ListClientsRequest {}).await.unwrap();
+    assert!(clients.into_inner().clients.is_empty());
+
+    let with_token = |token: &str| {
+      let mut request = Request::new(proto::GetLogLevelRequest {});
+      request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+      request
+    };
+    let error = client.get_log_level(with_token("wrong")).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unauthenticated);
+    // Tokens of a network can't touch server-wide settings.
+    let error = client.get_log_level(with_token("initech")).await.unwrap_err();
+    assert_eq!(error.code(), Code::PermissionDenied);
+
+    let kicked =
+      client.kick_client(proto::KickClientRequest { username: "alice".into(), force: false }).await.unwrap();
+    assert_eq!(kicked.into_inner().kicked, 0);
+
+    let error = client
+      .start_trace(proto::StartTraceRequest { session_id: "2a".into(), duration_secs: None })
+      .await
+      .unwrap_err();
+    assert_eq!(error.code(), Code::NotFound);
+
+    // Bandwidth graphs are off unless configured.
+    let error = client.get_bandwidth(proto::GetBandwidthRequest { session_id: None }).await.unwrap_err();
+    assert_eq!(error.code(), Code::InvalidArgument);
+  }
+}
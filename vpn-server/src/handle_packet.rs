@@ -1,39 +1,150 @@
 use anyhow::Result;
+use ipnet::Ipv4Net;
+use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::debug;
+use tracing::trace;
 use tracing::warn;
+use vpn_shared::cert::Certificate;
 use vpn_shared::creds::Credentials;
+use vpn_shared::creds::KeyCredentials;
+use vpn_shared::ecn;
+use vpn_shared::fragment::Fragment;
+use vpn_shared::handshake;
+use vpn_shared::iface::MIN_MTU;
+use vpn_shared::ip;
+use vpn_shared::logging;
 use vpn_shared::packet::fill_random_bytes;
-use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::packet::ErrorCode;
+use vpn_shared::packet::Features;
 use vpn_shared::packet::Key;
+use vpn_shared::packet::ReverseForward;
+use vpn_shared::packet::SessionId;
+use vpn_shared::packet::SessionParams;
+use vpn_shared::packet::HANDSHAKE_SESSION;
 use vpn_shared::packet::KEY_SIZE;
+use vpn_shared::packet::RENDEZVOUS_SESSION;
+use vpn_shared::packet::SESSION_ID_SIZE;
 
 use tracing::error;
 use tracing::info;
 
+use vpn_shared::limits::Limits;
 use vpn_shared::packet::{ClientPacket, ServerPacket};
+use vpn_shared::protocol::Accepted;
+use vpn_shared::protocol::Offer;
+use vpn_shared::protocol::ServerHandshake;
+use vpn_shared::protocol::MAX_KEEPALIVE;
 
+use crate::accounting::AccountingKind;
+use crate::accounting::Direction;
+use crate::alerts::Violation;
+use crate::auth::Identity;
+use crate::authz;
+use crate::authz::Attempt;
+use crate::authz::Decision;
+use crate::health::Scope;
+use crate::pacing::Queued;
+use crate::passwords;
+use crate::passwords::ChangeError;
+use crate::policy::Priority;
+use crate::replay;
 use crate::server::ConnectedClient;
 use crate::server::Server;
+use crate::tokens::Ticket;
+use crate::trace;
+use crate::trace::Flow;
+
+/// Offset of a client's clock from the server's worth pointing out when its token is refused: a client
+/// that far off may hold on to tokens its provider considers expired, or use them before they're valid.
+const NOTABLE_CLOCK_OFFSET: u64 = 5;
 
 #[allow(async_fn_in_trait)]
 pub trait PacketHandler {
   async fn send_packet(&self, packet: ServerPacket, addr: SocketAddr) -> Result<()>;
-  async fn send_unencrypted_packet(&self, packet: ServerPacket, addr: SocketAddr) -> Result<()>;
   async fn handle_auth(&self, credentials: Credentials, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_key_auth(
+    &self,
+    username: String,
+    public_key: Key,
+    proof: Key,
+    src_addr: SocketAddr,
+  ) -> Result<()>;
   async fn handle_data(&self, payload: Vec<u8>, src_addr: SocketAddr) -> Result<()>;
   async fn handle_ping(&self, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_probe(&self, padding: Vec<u8>, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_register_subnets(&self, subnets: Vec<Ipv4Net>, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_register_forwards(&self, forwards: Vec<ReverseForward>, src_addr: SocketAddr)
+    -> Result<()>;
+  async fn handle_request_routes(&self, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_register_hostname(&self, name: String, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_suspended(&self, suspended: bool, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_change_password(&self, old: String, new: String, src_addr: SocketAddr) -> Result<()>;
   async fn handle_disconnect(&self, src_addr: SocketAddr) -> Result<()>;
-  async fn handle_key_exchange(&self, client_key: Key, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_rehandshake(&self, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_peer_key(&self, peer: Ipv4Addr, key: Key, reply: bool, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_peer_data(&self, peer: Ipv4Addr, sealed: Vec<u8>, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_fragment(&self, fragment: Fragment, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_renegotiate(&self, params: SessionParams, src_addr: SocketAddr) -> Result<()>;
+  async fn handle_key_exchange(
+    &self,
+    client_key: Key,
+    transforms: Vec<String>,
+    offer: Offer,
+    timestamp: u64,
+    src_addr: SocketAddr,
+    local: Option<Ipv4Addr>,
+  ) -> Result<()>;
 }
 
 impl Server {
   pub async fn handle(&self, packet: ClientPacket, src_addr: SocketAddr) -> Result<()> {
     match packet {
+      ClientPacket::Ping
+      | ClientPacket::Probe(_)
+      | ClientPacket::Renegotiate(_)
+      | ClientPacket::RegisterSubnets(_)
+      | ClientPacket::RegisterForwards(_)
+      | ClientPacket::RequestRoutes
+      | ClientPacket::ChangePassword { .. }
+      | ClientPacket::Rehandshake
+      | ClientPacket::PeerKey { .. }
+      | ClientPacket::RegisterHostname(_)
+      | ClientPacket::Suspended(_)
+        if !self.allow_control(src_addr) => {}
       ClientPacket::Auth(credentials) => self.handle_auth(credentials, src_addr).await?,
+      ClientPacket::KeyAuth { username, public_key, proof } => {
+        self.handle_key_auth(username, public_key, proof, src_addr).await?
+      }
       ClientPacket::Data(payload) => self.handle_data(payload, src_addr).await?,
       ClientPacket::Ping => self.handle_ping(src_addr).await?,
+      ClientPacket::Probe(padding) => self.handle_probe(padding, src_addr).await?,
       ClientPacket::Disconnect => self.handle_disconnect(src_addr).await?,
-      ClientPacket::KeyExchange(client_key) => self.handle_key_exchange(client_key, src_addr).await?,
+      ClientPacket::KeyExchange { key, transforms, timestamp, features, limits } => {
+        let local = self.clients.get(&src_addr).and_then(|client| client.local);
+        self
+          .handle_key_exchange(key, transforms, Offer { features, limits }, timestamp, src_addr, local)
+          .await?
+      }
+      // Late answer to a challenge for the address the session already moved to.
+      ClientPacket::PathResponse(_) => {}
+      ClientPacket::Fragment(fragment) => self.handle_fragment(fragment, src_addr).await?,
+      ClientPacket::Renegotiate(params) => self.handle_renegotiate(params, src_addr).await?,
+      ClientPacket::RegisterSubnets(subnets) => self.handle_register_subnets(subnets, src_addr).await?,
+      ClientPacket::RegisterForwards(forwards) => self.handle_register_forwards(forwards, src_addr).await?,
+      ClientPacket::RequestRoutes => self.handle_request_routes(src_addr).await?,
+      ClientPacket::ChangePassword { old, new } => self.handle_change_password(old, new, src_addr).await?,
+      ClientPacket::Rehandshake => self.handle_rehandshake(src_addr).await?,
+      ClientPacket::PeerKey { peer, key, reply } => self.handle_peer_key(peer, key, reply, src_addr).await?,
+      ClientPacket::PeerData { peer, sealed } => self.handle_peer_data(peer, sealed, src_addr).await?,
+      ClientPacket::RegisterHostname(name) => self.handle_register_hostname(name, src_addr).await?,
+      ClientPacket::Suspended(suspended) => self.handle_suspended(suspended, src_addr).await?,
+      // Taken as it's received, see `Server::complete_rekey`.
+      ClientPacket::Rekey { .. } => {}
       _ => {
         error!("Unknown packet from client {}: {:?}", src_addr, packet);
       }
@@ -41,38 +152,521 @@ impl Server {
 
     Ok(())
   }
-}
 
-impl PacketHandler for Server {
-  async fn handle_auth(&self, credentials: Credentials, src_addr: SocketAddr) -> Result<()> {
-    if !self.client_credentials.contains(&credentials) {
-      info!("Authentication failed for {}", src_addr);
-      self.send_packet(ServerPacket::AuthError("Invalid credentials".into()), src_addr).await?;
+  async fn accept(
+    &self,
+    username: &str,
+    directory_groups: &[String],
+    public_key: Option<Key>,
+    device: Option<&KeyCredentials>,
+    network: Option<&str>,
+    src_addr: SocketAddr,
+  ) -> Result<()> {
+    let features = self.clients.get(&src_addr).map_or(Features::empty(), |client| client.features);
+    let missing = self.required_features.difference(features);
+    if missing != Features::empty() {
+      info!(target: logging::HANDSHAKE, "Refusing {} ({}): the client lacks {}", src_addr, username, missing);
+      let message = format!("Client is too old, it lacks {}; upgrade it", missing);
+      self.send_packet(ServerPacket::AuthError { code: ErrorCode::VersionTooOld, message }, src_addr).await?;
+      self.remove_client(src_addr).await;
+      return Ok(());
+    }
+
+    let attempt = Attempt {
+      username,
+      source: src_addr.ip(),
+      network,
+      groups: directory_groups,
+      sessions: self.sessions_of(username, &Scope::All).iter().filter(|addr| **addr != src_addr).count(),
+      time: handshake::unix_time(),
+    };
+    let assigned = match authz::decide(&self.auth_policy, &attempt) {
+      Decision::Allow(groups) => groups,
+      Decision::Deny(message) => {
+        info!(target: logging::HANDSHAKE, "Auth policy denied {} ({}): {}", src_addr, username, message);
+        self.record_auth_failure(Some(username), src_addr);
+        let error = ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message };
+        self.send_packet(error, src_addr).await?;
+        return Ok(());
+      }
+    };
+
+    let mut policy = match network.and_then(|name| self.networks.get(name)) {
+      Some(network) => network.policies.resolve(username, directory_groups, &assigned),
+      None => self.policies.resolve(username, directory_groups, &assigned),
+    };
+    if let Some(quota_mb) = device.and_then(|device| device.quota_mb) {
+      policy.quota_bytes = Some(quota_mb * 1024 * 1024);
+    }
+
+    let resumed = self.resumed_session(src_addr);
+    let full = self.clients.len() - usize::from(resumed.is_some()) >= self.max_clients;
+    if full && !(policy.priority == Priority::High && self.preempt_for(src_addr, username).await) {
+      self
+        .send_packet(
+          ServerPacket::AuthError { code: ErrorCode::ServerFull, message: "Server is full".into() },
+          src_addr,
+        )
+        .await?;
+      self.remove_client(src_addr).await;
       return Ok(());
     }
 
-    if self.clients.len() >= self.max_clients {
-      self.clients.remove(&src_addr);
-      self.send_packet(ServerPacket::AuthError("Server is full".into()), src_addr).await?;
+    // The session keeps the address it leased, so it can't move to the pool of another network.
+    let switched = self
+      .clients
+      .get(&src_addr)
+      .is_some_and(|client| client.authenticated_at.is_some() && client.network.as_deref() != network);
+    if switched {
+      self
+        .send_packet(
+          ServerPacket::AuthError {
+            code: ErrorCode::InvalidCredentials,
+            message: "Session belongs to another network".into(),
+          },
+          src_addr,
+        )
+        .await?;
+      self.remove_client(src_addr).await;
       return Ok(());
     }
 
-    info!("Client {} authenticated successfully", src_addr);
+    // Only now that nothing refuses the session does it replace the one it resumes, which holds the address
+    // it's leased again until then.
+    if let Some(previous) = resumed {
+      info!(target: logging::HANDSHAKE, "Client {} resumes the session of {}", src_addr, previous);
+      self.remove_client(previous).await;
+    }
+
+    if let Some(mut client) = self.clients.get_mut(&src_addr) {
+      if client.policy.bandwidth_classes != policy.bandwidth_classes {
+        client.outbound = self.send_queue(src_addr, client.local, &policy.bandwidth_classes);
+      }
+      client.policy = policy;
+      client.network = network.map(str::to_string);
+      client.username = Some(username.to_string());
+      client.device = device.and_then(|device| device.device.clone());
+      client.public_key = public_key;
+      if client.authenticated_at.is_none() {
+        client.authenticated_at = Some(std::time::Instant::now());
+        self.record_accounting(AccountingKind::Start, &client);
+      }
+    }
+
+    let config = match self.pool_of(network) {
+      Some(pool) => {
+        let mut config = self.lease_address(pool, src_addr).await?;
+        if config.is_none() && self.reclaim_address(network, src_addr).await {
+          config = self.lease_address(pool, src_addr).await?;
+        }
+        if config.is_none() {
+          self.pool_exhausted(username, network);
+          let retry_after_secs = self.pool_exhaustion.retry_after_secs;
+          self.send_packet(ServerPacket::PoolExhausted { retry_after_secs }, src_addr).await?;
+          self.remove_client(src_addr).await;
+          return Ok(());
+        }
+        config
+      }
+      None => None,
+    };
+
+    match network {
+      Some(network) => {
+        info!(target: logging::HANDSHAKE, "Client {} authenticated successfully in network {}", src_addr, network)
+      }
+      None => info!(target: logging::HANDSHAKE, "Client {} authenticated successfully", src_addr),
+    }
     self.send_packet(ServerPacket::AuthOk, src_addr).await?;
+    if let Some(config) = config {
+      self.send_packet(config, src_addr).await?;
+    }
+    self.issue_ticket(directory_groups, src_addr).await?;
+    self.announce_session(src_addr).await;
+    if self
+      .clients
+      .get(&src_addr)
+      .is_some_and(|client| !client.policy.routes.is_empty() || !client.routes.is_empty())
+    {
+      self.advertise_routes().await;
+    }
 
     Ok(())
   }
+}
+
+impl Server {
+  /// Address of the session that the one at `src_addr` resumes with a ticket, if it's still around: it
+  /// belongs to the client before it restarted.
+  fn resumed_session(&self, src_addr: SocketAddr) -> Option<SocketAddr> {
+    let session_id = self.clients.get(&src_addr)?.ticket.as_ref()?.session_id;
+    self.sessions.get(&session_id).map(|addr| *addr).filter(|addr| *addr != src_addr)
+  }
 
-  async fn handle_data(&self, payload: Vec<u8>, src_addr: SocketAddr) -> Result<()> {
+  /// Limits agreed on with the client at `src_addr`, see `vpn_shared::limits`.
+  fn limits_of(&self, src_addr: SocketAddr) -> Limits {
+    self.clients.get(&src_addr).map_or(Limits::LOCAL, |client| client.limits)
+  }
+
+  /// Answers credentials longer than the server takes with an `AuthError`; returns whether it did.
+  async fn refuse_oversized(&self, checked: anyhow::Result<()>, src_addr: SocketAddr) -> Result<bool> {
+    let Err(e) = checked else {
+      return Ok(false);
+    };
+    info!(target: logging::HANDSHAKE, "Refusing credentials from {}: {}", src_addr, e);
+    self.record_auth_failure(None, src_addr);
+    let error = ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message: e.to_string() };
+    self.send_packet(error, src_addr).await?;
+    Ok(true)
+  }
+
+  /// Virtual address of the client at `src_addr` and the address of the one at `peer`, if pairwise keys and
+  /// packets may pass between them; otherwise `src_addr` is told why not.
+  async fn peer_route(&self, peer: Ipv4Addr, src_addr: SocketAddr) -> Result<Option<(Ipv4Addr, SocketAddr)>> {
     self.assert_auth(src_addr).await?;
+    let Some((own, allowed)) = self.clients.get(&src_addr).and_then(|client| {
+      let allowed = client.features.contains(Features::PEER_KEYS)
+        && client.policy.allows(peer)
+        && self.networks.allows(client.network.as_deref(), self.subnet, peer);
+      Some((client.virtual_ip?, allowed))
+    }) else {
+      anyhow::bail!("Pairwise key from {} before it has an address", src_addr);
+    };
+
+    let target = self.virtual_ips.get(&peer).map(|addr| *addr);
+    let takes_keys = |addr: &SocketAddr| {
+      self.clients.get(addr).is_some_and(|client| {
+        client.authenticated_at.is_some() && client.features.contains(Features::PEER_KEYS)
+      })
+    };
+    match target {
+      Some(addr) if allowed && takes_keys(&addr) => Ok(Some((own, addr))),
+      target => {
+        let client = target.is_some();
+        debug!(target: logging::DATAPATH, "Not passing pairwise keys from {} to {} (a client: {})", src_addr, peer, client);
+        self.send_packet(ServerPacket::PeerUnavailable { peer, client }, src_addr).await?;
+        Ok(None)
+      }
+    }
+  }
+
+  async fn handle_certificate_auth(
+    &self,
+    certificate: Certificate,
+    proof: Key,
+    src_addr: SocketAddr,
+  ) -> Result<()> {
+    let expected = match self.clients.get(&src_addr) {
+      Some(client) => handshake::server_auth_proof(&client.ephemeral, &certificate.public_key, &client.key)?,
+      None => [0u8; KEY_SIZE],
+    };
+
+    let verified = match self.certificate_authority {
+      Some(ref ca) => certificate.verify(ca, std::time::SystemTime::now()),
+      None => Err(anyhow::anyhow!("Certificates aren't accepted")),
+    };
+
+    let error = match verified {
+      Err(e) if certificate.expires_at() <= std::time::SystemTime::now() => {
+        Some((ErrorCode::Expired, e.to_string()))
+      }
+      Err(e) => Some((ErrorCode::InvalidCredentials, e.to_string())),
+      Ok(()) if !handshake::keys_match(&proof, &expected) => {
+        Some((ErrorCode::InvalidCredentials, "Invalid credentials".to_string()))
+      }
+      Ok(()) if self.revocations.is_revoked(&certificate.public_key) => {
+        Some((ErrorCode::Revoked, "Certificate revoked".to_string()))
+      }
+      Ok(()) => None,
+    };
+
+    if let Some((code, message)) = error {
+      info!(target: logging::HANDSHAKE, "Certificate authentication failed for {} ({}): {}", src_addr, certificate.username, message);
+      self.record_auth_failure(Some(&certificate.username), src_addr);
+      self.send_packet(ServerPacket::AuthError { code, message }, src_addr).await?;
+      return Ok(());
+    }
+
+    if let Some(mut client) = self.clients.get_mut(&src_addr) {
+      client.expires_at = Some(certificate.expires_at());
+    }
+    self.accept(&certificate.username, &[], Some(certificate.public_key), None, None, src_addr).await
+  }
+
+  async fn handle_ticket_auth(&self, sealed: Vec<u8>, src_addr: SocketAddr) -> Result<()> {
+    let ticket =
+      self.tickets.as_ref().and_then(|tickets| tickets.open(&sealed, std::time::SystemTime::now()));
+    let Some(ticket) = ticket else {
+      info!(target: logging::HANDSHAKE, "Ticket authentication failed for {}", src_addr);
+      self.record_auth_failure(None, src_addr);
+      let error = ServerPacket::AuthError {
+        code: ErrorCode::InvalidCredentials,
+        message: "Invalid or expired ticket".into(),
+      };
+      self.send_packet(error, src_addr).await?;
+      return Ok(());
+    };
+
+    if ticket.public_key.is_some_and(|key| self.revocations.is_revoked(&key)) {
+      info!(target: logging::HANDSHAKE, "Client {} ({}) resumed with a ticket of a revoked key", src_addr, ticket.username);
+      self
+        .send_packet(
+          ServerPacket::AuthError { code: ErrorCode::Revoked, message: "Key revoked".into() },
+          src_addr,
+        )
+        .await?;
+      return Ok(());
+    }
+
+    if self.password_changed_since(&ticket).await {
+      info!(target: logging::HANDSHAKE, "Client {} ({}) resumed with a ticket issued before the password changed", src_addr, ticket.username);
+      let error = ServerPacket::AuthError {
+        code: ErrorCode::InvalidCredentials,
+        message: "Password changed, log in again".into(),
+      };
+      self.send_packet(error, src_addr).await?;
+      return Ok(());
+    }
+
+    let Some(groups) = self.current_groups(&ticket).await else {
+      info!(target: logging::HANDSHAKE, "Client {} ({}) resumed with a ticket of an account that's gone", src_addr, ticket.username);
+      self.record_auth_failure(Some(&ticket.username), src_addr);
+      let error =
+        ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message: "Account not found".into() };
+      self.send_packet(error, src_addr).await?;
+      return Ok(());
+    };
 
+    let device = ticket.public_key.and_then(|key| {
+      self.networks.by_key(&ticket.username, &key).map(|(_, entry)| entry.clone()).or_else(|| {
+        let keys = self.client_keys.read().unwrap();
+        keys.iter().find(|entry| entry.username == ticket.username && entry.public_key == key).cloned()
+      })
+    });
+    let (username, network) = (ticket.username.clone(), ticket.network.clone());
+    // The session the ticket was issued to is taken over in `accept`, see `Server::resumed_session`.
     if let Some(mut client) = self.clients.get_mut(&src_addr) {
-      client.last_seen = std::time::Instant::now();
+      client.ticket = Some(ticket.clone());
+    }
+    self.accept(&username, &groups, ticket.public_key, device.as_ref(), network.as_deref(), src_addr).await
+  }
+
+  /// Sends an authenticated client a ticket to resume its session with, if tickets are enabled. Tickets
+  /// issued to a resumed session expire with the one it was resumed with.
+  async fn issue_ticket(&self, directory_groups: &[String], src_addr: SocketAddr) -> Result<()> {
+    let Some(ref tickets) = self.tickets else {
+      return Ok(());
+    };
+    let Some(ticket) = self.clients.get(&src_addr).and_then(|client| {
+      let username = client.username.clone()?;
+      let expires_at = match client.ticket {
+        Some(ref resumed) => Ok(resumed.expires_at),
+        None => tickets.expiry(client.expires_at),
+      };
+      Some(expires_at.map(|expires_at| Ticket {
+        session_id: client.session_id,
+        username,
+        groups: directory_groups.to_vec(),
+        network: client.network.clone(),
+        public_key: client.public_key,
+        address: client.virtual_ip,
+        expires_at,
+        issued_at: match client.ticket {
+          Some(ref resumed) => resumed.issued_at,
+          None => crate::passwords::now_millis(),
+        },
+      }))
+    }) else {
+      return Ok(());
+    };
+    let ticket = ticket?;
+
+    let lifetime_secs = ticket.expires_at.saturating_sub(handshake::unix_time());
+    self.send_packet(ServerPacket::Ticket { ticket: tickets.seal(&ticket)?, lifetime_secs }, src_addr).await
+  }
+}
+
+impl PacketHandler for Server {
+  async fn handle_auth(&self, credentials: Credentials, src_addr: SocketAddr) -> Result<()> {
+    if self.refuse_oversized(self.limits_of(src_addr).check_credentials(&credentials), src_addr).await? {
+      return Ok(());
+    }
+    match credentials {
+      Credentials::Certificate { certificate, proof } => {
+        return self.handle_certificate_auth(certificate, proof, src_addr).await;
+      }
+      Credentials::Ticket(ticket) => return self.handle_ticket_auth(ticket, src_addr).await,
+      _ => {}
+    }
+
+    let tenant = self.networks.by_credentials(&credentials).map(|network| network.name.as_str());
+    let identity = match credentials.username() {
+      Some(username)
+        if tenant.is_some() || self.client_credentials.read().unwrap().contains(&credentials) =>
+      {
+        Some(Identity { username: username.to_string(), groups: Vec::new() })
+      }
+      _ => self.authenticate_with_stores(&credentials).await,
+    };
+
+    let Some(identity) = identity else {
+      let clock_offset = self.clients.get(&src_addr).map_or(0, |client| client.clock_offset);
+      let message = match credentials {
+        Credentials::Token(_) if clock_offset.unsigned_abs() >= NOTABLE_CLOCK_OFFSET => {
+          format!("Invalid credentials; the client's clock is {}", replay::clock_offset(clock_offset))
+        }
+        _ => "Invalid credentials".to_string(),
+      };
+      info!(target: logging::HANDSHAKE, "Authentication failed for {}: {}", src_addr, message);
+      self.record_auth_failure(credentials.username(), src_addr);
+      let error = ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message };
+      self.send_packet(error, src_addr).await?;
+      return Ok(());
+    };
+
+    self.accept(&identity.username, &identity.groups, None, None, tenant, src_addr).await
+  }
+
+  async fn handle_key_auth(
+    &self,
+    username: String,
+    public_key: Key,
+    proof: Key,
+    src_addr: SocketAddr,
+  ) -> Result<()> {
+    if self.refuse_oversized(self.limits_of(src_addr).check_credential(username.as_bytes()), src_addr).await?
+    {
+      return Ok(());
     }
+    let (tenant, entry) = match self.networks.by_key(&username, &public_key) {
+      Some((network, entry)) => (Some(network.name.as_str()), Some(entry.clone())),
+      None => {
+        let keys = self.client_keys.read().unwrap();
+        (None, keys.iter().find(|key| key.username == username && key.public_key == public_key).cloned())
+      }
+    };
+
+    let expected = match self.clients.get(&src_addr) {
+      Some(client) => handshake::server_auth_proof(&client.ephemeral, &public_key, &client.key)?,
+      None => [0u8; KEY_SIZE],
+    };
+
+    if entry.is_none() || !handshake::keys_match(&proof, &expected) {
+      info!(target: logging::HANDSHAKE, "Key authentication failed for {}", src_addr);
+      self.record_auth_failure(Some(&username), src_addr);
+      let error = ServerPacket::AuthError {
+        code: ErrorCode::InvalidCredentials,
+        message: "Invalid credentials".into(),
+      };
+      self.send_packet(error, src_addr).await?;
+      return Ok(());
+    }
+
+    if self.revocations.is_revoked(&public_key) {
+      info!(target: logging::HANDSHAKE, "Client {} ({}) used a revoked key", src_addr, username);
+      self
+        .send_packet(
+          ServerPacket::AuthError { code: ErrorCode::Revoked, message: "Key revoked".into() },
+          src_addr,
+        )
+        .await?;
+      return Ok(());
+    }
+
+    self.accept(&username, &[], Some(public_key), entry.as_ref(), tenant, src_addr).await
+  }
 
-    info!("Received data from client {}: {:?}", src_addr, payload);
+  async fn handle_data(&self, mut payload: Vec<u8>, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
 
-    // TODO: Implement actual data handling
+    if let Some(client) = self.clients.get(&src_addr) {
+      client.last_active.touch();
+    }
+    self.count_packet(src_addr, &payload);
+    let len = payload.len();
+    let record = |decision: &str| self.trace(src_addr, Flow::Received, "data", len, || decision.to_string());
+
+    let destination = ip::ipv4_destination(&payload);
+    // Answered before the admin service, which takes everything else sent to the same address.
+    if self.internal_dns.as_ref().is_some_and(|dns| dns.is_query(&payload)) {
+      self.learn_virtual_ip(src_addr, &payload).await?;
+      // Held to the ACL like any destination; upstream resolvers it's forwarded to are too, see `dns`.
+      let allowed =
+        self.clients.get(&src_addr).zip(destination).is_some_and(|(client, dst)| client.policy.allows(dst));
+      if !allowed {
+        record("rejected: denied by ACL");
+        debug!(target: logging::DATAPATH, "Dropping DNS query from {}: denied by ACL", src_addr);
+        self.alert(src_addr, Violation::Acl, &payload);
+        return self.reject(src_addr, &payload, ip::ICMP_ADMIN_PROHIBITED).await;
+      }
+      if let Err(e) = self.answer_dns(src_addr, &payload).await {
+        debug!(target: logging::DATAPATH, "Failed to answer a DNS query of {}: {}", src_addr, e);
+      }
+      record("answered by the internal DNS");
+      return Ok(());
+    }
+    // Reachable by every client, whatever its ACLs, network or the tun; admin routes still need a token.
+    let to_service = self.admin_service.as_ref().filter(|service| destination == Some(service.address));
+    if let Some(service) = to_service {
+      self.learn_virtual_ip(src_addr, &payload).await?;
+      if !service.send(&payload) {
+        record("dropped: admin service is behind");
+        trace!(target: logging::DATAPATH, "Admin service is behind; dropping packet from {}", src_addr);
+        return Ok(());
+      }
+      record("sent to the admin service");
+      return Ok(());
+    }
+
+    let Some(ref tun) = self.tun else {
+      record("rejected: no tun");
+      trace!(target: logging::DATAPATH, "Received data from client {} without a tun; len: {}", src_addr, payload.len());
+      return self.reject(src_addr, &payload, ip::ICMP_NET_UNREACHABLE).await;
+    };
+
+    self.learn_virtual_ip(src_addr, &payload).await?;
+
+    let (allowed, isolated) = match (self.clients.get(&src_addr), destination) {
+      (Some(client), Some(dst)) => {
+        (client.policy.allows(dst), !self.networks.allows(client.network.as_deref(), self.subnet, dst))
+      }
+      _ => (false, false),
+    };
+    if !allowed {
+      record("rejected: denied by ACL");
+      debug!(target: logging::DATAPATH, "Dropping packet from {} to {:?}: denied by ACL", src_addr, destination);
+      self.alert(src_addr, Violation::Acl, &payload);
+      return self.reject(src_addr, &payload, ip::ICMP_ADMIN_PROHIBITED).await;
+    }
+    if isolated {
+      record("rejected: outside of its network");
+      debug!(target: logging::DATAPATH, "Dropping packet from {} to {:?}: outside of its network", src_addr, destination);
+      self.alert(src_addr, Violation::NetworkIsolation, &payload);
+      return self.reject(src_addr, &payload, ip::ICMP_ADMIN_PROHIBITED).await;
+    }
+
+    if !self.filter_packet(Direction::Inbound, src_addr, &payload) {
+      record("rejected: denied by a filter");
+      return self.reject(src_addr, &payload, ip::ICMP_ADMIN_PROHIBITED).await;
+    }
+    if let Some(mss) = self.mss_clamp_for(src_addr) {
+      ip::clamp_tcp_mss(&mut payload, mss);
+    }
+
+    self.account(src_addr, Direction::Inbound, payload.len()).await?;
+    self.mirror_packet(src_addr, &payload);
+
+    let queued = Instant::now();
+    self.metrics.tun_queue.enqueued();
+    let written = tun.send(src_addr, &payload).await;
+    self.metrics.tun_queue.dequeued(queued);
+    if !written? {
+      self.metrics.tun_queue.dropped.inc();
+      record("dropped: userspace NAT is behind");
+      trace!(target: logging::DATAPATH, "Userspace NAT is behind; dropping packet from {}", src_addr);
+      return Ok(());
+    }
+    record("forwarded to the tun");
     Ok(())
   }
 
@@ -83,8 +677,109 @@ impl PacketHandler for Server {
     Ok(())
   }
 
+  async fn handle_probe(&self, padding: Vec<u8>, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    trace!(target: logging::DATAPATH, "Echoing a probe of {} bytes to client {}", padding.len(), src_addr);
+    self.send_packet(ServerPacket::Probe(padding), src_addr).await?;
+    Ok(())
+  }
+
+  async fn handle_register_subnets(&self, mut subnets: Vec<Ipv4Net>, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    // Ones past the limit are refused, along with the ones that may not be routed.
+    let max_routes = self.limits_of(src_addr).max_routes as usize;
+    let excess = subnets.split_off(subnets.len().min(max_routes));
+    let (accepted, mut rejected) = self.register_subnets(src_addr, subnets).await;
+    rejected.extend(excess);
+    self.send_packet(ServerPacket::Subnets { accepted, rejected }, src_addr).await?;
+    self.advertise_routes().await;
+    Ok(())
+  }
+
+  async fn handle_register_forwards(
+    &self,
+    forwards: Vec<ReverseForward>,
+    src_addr: SocketAddr,
+  ) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    let (accepted, rejected) = self.register_forwards(src_addr, forwards).await;
+    self.send_packet(ServerPacket::Forwards { accepted, rejected }, src_addr).await?;
+    Ok(())
+  }
+
+  async fn handle_request_routes(&self, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    let Some((revision, routes)) =
+      self.clients.get(&src_addr).map(|client| (client.routes_revision, client.routes.clone()))
+    else {
+      return Ok(());
+    };
+    debug!(target: logging::DATAPATH, "Client {} missed route updates; resending revision {}", src_addr, revision);
+    self.send_packet(ServerPacket::Routes { revision, routes }, src_addr).await?;
+    Ok(())
+  }
+
+  async fn handle_register_hostname(&self, name: String, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    let fqdn = self.register_hostname(src_addr, &name);
+    self.send_packet(ServerPacket::Hostname { name, fqdn }, src_addr).await?;
+    Ok(())
+  }
+
+  async fn handle_suspended(&self, suspended: bool, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    if let Some(client) = self.clients.get(&src_addr) {
+      debug!(target: logging::DATAPATH, "Client {} {} its routes", src_addr, match suspended {
+        true => "suspended",
+        false => "resumed",
+      });
+      client.suspended.store(suspended, Ordering::Relaxed);
+    }
+    Ok(())
+  }
+
+  async fn handle_change_password(&self, old: String, new: String, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    let Some(username) = self.clients.get(&src_addr).and_then(|client| client.username.clone()) else {
+      return Ok(());
+    };
+    let result = match (&self.passwords, self.limits_of(src_addr).check_credential(new.as_bytes())) {
+      (_, Err(e)) => Err(e.to_string()),
+      (Some(passwords), Ok(())) => match passwords.change(&username, &old, &new).await? {
+        Ok(()) => Ok(()),
+        Err(ChangeError::Denied) => Err("Wrong password, or it can't be changed here".to_string()),
+        Err(ChangeError::TooShort) => {
+          Err(format!("Passwords have to be at least {} characters long", passwords::MIN_LENGTH))
+        }
+      },
+      (None, Ok(())) => Err("Passwords can't be changed on this server".to_string()),
+    };
+
+    match result {
+      Ok(()) => {
+        info!(target: logging::HANDSHAKE, "Client {} ({}) changed their password", src_addr, username)
+      }
+      Err(ref e) => {
+        info!(target: logging::HANDSHAKE, "Client {} ({}) failed to change their password: {}", src_addr, username, e)
+      }
+    }
+    let changed = result.is_ok();
+    self.send_packet(ServerPacket::PasswordChanged { error: result.err() }, src_addr).await?;
+    if changed {
+      // Tickets issued before are refused from now on, so the session gets a fresh one.
+      let groups = self
+        .clients
+        .get_mut(&src_addr)
+        .and_then(|mut client| client.ticket.take())
+        .map(|ticket| ticket.groups)
+        .unwrap_or_default();
+      self.issue_ticket(&groups, src_addr).await?;
+    }
+    Ok(())
+  }
+
   async fn handle_disconnect(&self, src_addr: SocketAddr) -> Result<()> {
-    if self.clients.remove(&src_addr).is_some() {
+    if self.remove_client(src_addr).await.is_some() {
       info!("Client {} disconnected", src_addr);
     } else {
       warn!("Client {} wasn't connected; ignoring disconnect", src_addr);
@@ -93,41 +788,192 @@ impl PacketHandler for Server {
     Ok(())
   }
 
-  async fn send_packet(&self, packet: ServerPacket, addr: SocketAddr) -> Result<()> {
-    let encrypted_packet = EncryptedPacket::encrypt(&self.get_client_key(addr), &packet)?;
-    _ = tokio::time::timeout(self.client_timeout, self.socket.send_to(&encrypted_packet.to_bytes(), addr))
-      .await?;
-    Ok(())
+  async fn handle_peer_key(&self, peer: Ipv4Addr, key: Key, reply: bool, src_addr: SocketAddr) -> Result<()> {
+    let Some((own, addr)) = self.peer_route(peer, src_addr).await? else {
+      return Ok(());
+    };
+    debug!(target: logging::HANDSHAKE, "Passing a pairwise key from {} to {}", own, peer);
+    self.send_packet(ServerPacket::PeerKey { peer: own, key, reply }, addr).await
   }
 
-  async fn send_unencrypted_packet(&self, packet: ServerPacket, addr: SocketAddr) -> Result<()> {
-    let encrypted_packet = EncryptedPacket::encrypt(&[0u8; KEY_SIZE], &packet)?;
-    _ = tokio::time::timeout(self.client_timeout, self.socket.send_to(&encrypted_packet.to_bytes(), addr))
-      .await?;
+  async fn handle_peer_data(&self, peer: Ipv4Addr, sealed: Vec<u8>, src_addr: SocketAddr) -> Result<()> {
+    let Some((own, addr)) = self.peer_route(peer, src_addr).await? else {
+      return Ok(());
+    };
+    if let Some(client) = self.clients.get(&src_addr) {
+      client.last_active.touch();
+    }
+    let len = sealed.len();
+    self.trace(src_addr, Flow::Received, "peer-data", len, || format!("passed on to {}", peer));
+    self.account(src_addr, Direction::Inbound, len).await?;
+    self.account(addr, Direction::Outbound, len).await?;
+    self.send_packet(ServerPacket::PeerData { peer: own, sealed }, addr).await
+  }
+
+  async fn handle_rehandshake(&self, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    if let Some(mut client) = self.clients.get_mut(&src_addr) {
+      client.rehandshaking = true;
+    }
+    debug!(target: logging::HANDSHAKE, "Client {} is about to make a new key exchange", src_addr);
     Ok(())
   }
 
-  async fn handle_key_exchange(&self, client_key: Key, src_addr: SocketAddr) -> Result<()> {
-    let mut server_key = [0u8; KEY_SIZE];
-    fill_random_bytes(&mut server_key);
+  async fn handle_fragment(&self, fragment: Fragment, src_addr: SocketAddr) -> Result<()> {
+    let (packet, limits) = {
+      let Some(mut client) = self.clients.get_mut(&src_addr) else {
+        anyhow::bail!("Fragment from unknown client {}", src_addr);
+      };
+      if !client.features.contains(Features::FRAGMENTATION) {
+        anyhow::bail!("Fragment from {}, which didn't negotiate fragmentation", src_addr);
+      }
+      (client.fragments.add(fragment)?, client.limits)
+    };
+    let Some(packet) = packet else {
+      return Ok(());
+    };
 
-    let mut session_key = [0u8; KEY_SIZE];
-    for i in 0..KEY_SIZE {
-      session_key[i] = client_key[i] ^ server_key[i];
+    match limits.decode(&packet)? {
+      packet @ (ClientPacket::Auth(_) | ClientPacket::KeyAuth { .. }) => {
+        Box::pin(self.handle(packet, src_addr)).await
+      }
+      packet => anyhow::bail!("Unexpected fragmented packet from {}: {:?}", src_addr, packet),
     }
+  }
 
-    let client = ConnectedClient::new(session_key, src_addr, self.client_timeout);
+  async fn handle_renegotiate(&self, params: SessionParams, src_addr: SocketAddr) -> Result<()> {
+    self.assert_auth(src_addr).await?;
+    let keepalive =
+      Duration::from_secs(params.keepalive_secs.into()).clamp(Duration::from_secs(1), MAX_KEEPALIVE);
+    let transforms = self.transforms.negotiate(&params.transforms, &self.accepted_transforms);
+    let (key, _, current) = self.get_client_session(src_addr);
+    let pipeline = self.transforms.pipeline(&transforms, &key)?;
+    let pipeline = Arc::new(pipeline.with_raw_data(current.raw_data()));
+    // What the transforms add comes out of the MTU, so datagrams are no larger than they'd be without them.
+    let overhead = u16::try_from(pipeline.overhead()).unwrap_or(u16::MAX);
+    let agreed = SessionParams {
+      mtu: params.mtu.clamp(MIN_MTU, self.mtu.saturating_sub(overhead).max(MIN_MTU)),
+      keepalive_secs: keepalive.as_secs() as u32,
+      transforms,
+    };
 
-    self.clients.insert(src_addr, client);
+    // Sealed with the transforms the client still uses until it has the answer.
+    self.send_packet(ServerPacket::Renegotiated(agreed.clone()), src_addr).await?;
+    let transforms_changed = {
+      let Some(mut client) = self.clients.get_mut(&src_addr) else {
+        return Ok(());
+      };
+      client.mtu = (agreed.mtu < self.mtu).then_some(agreed.mtu);
+      client.timeout = self.client_timeout.max(keepalive * 3);
+      let changed = client.pipeline.names() != agreed.transforms;
+      if changed {
+        client.pipeline = pipeline;
+      }
+      changed
+    };
+    if transforms_changed {
+      self.announce_session(src_addr).await;
+    }
 
-    if let Some(mut client) = self.clients.get_mut(&src_addr) {
-      client.key = session_key;
-      client.last_seen = std::time::Instant::now();
+    info!(target: logging::HANDSHAKE, "Renegotiated the session of {}: {:?}", src_addr, agreed);
+    Ok(())
+  }
+
+  async fn send_packet(&self, packet: ServerPacket, addr: SocketAddr) -> Result<()> {
+    let (key, session_id, pipeline) = self.get_client_session(addr);
+    let kind = trace::server_kind(&packet);
+    let (len, outer_ecn) = match packet {
+      ServerPacket::Data(ref data) if self.ecn => (data.len(), ecn::encapsulate(data)),
+      ServerPacket::Data(ref data) => (data.len(), ip::ECN_NOT_ECT),
+      _ => (0, ip::ECN_NOT_ECT),
+    };
+    let datagram = self.offload.start(len, move || pipeline.seal(&key, session_id, &packet));
+
+    if let Some(outbound) = self.clients.get(&addr).map(|client| client.outbound.clone()) {
+      // Datagrams still being sealed are traced with the size of their payload.
+      let size = datagram.done().and_then(|datagram| datagram.as_ref().ok()).map_or(len, Vec::len);
+      let queued = Queued { datagram, payload_len: len, outer_ecn, at: Instant::now() };
+      match outbound.try_send(queued) {
+        Ok(()) => {
+          self.metrics.send_queue.enqueued();
+          self.traces.record(session_id, Flow::Sent, kind, size, || "queued".to_string());
+        }
+        Err(_) => {
+          self.metrics.send_queue.dropped.inc();
+          self.traces.record(session_id, Flow::Sent, kind, size, || "dropped: send queue full".to_string());
+          trace!(target: logging::DATAPATH, "Send queue of {} is full; dropping packet", addr);
+        }
+      }
+      return Ok(());
     }
 
-    self.send_unencrypted_packet(ServerPacket::KeyExchange(server_key), src_addr).await?;
+    let datagram = datagram.await??;
+    if len > 0 {
+      self.metrics.record_data(len, datagram.len());
+    }
+    self.traces.record(session_id, Flow::Sent, kind, datagram.len(), || "sent".to_string());
+    _ = tokio::time::timeout(self.client_timeout, self.socket.send_to(&datagram, addr, outer_ecn)).await?;
+    Ok(())
+  }
+
+  async fn handle_key_exchange(
+    &self,
+    client_key: Key,
+    transforms: Vec<String>,
+    offer: Offer,
+    timestamp: u64,
+    src_addr: SocketAddr,
+    local: Option<Ipv4Addr>,
+  ) -> Result<()> {
+    // Checked before the session at this address goes away, which a replayed exchange shouldn't cause.
+    let now = handshake::unix_time();
+    if let Err(e) = self.replays.check(&client_key, timestamp, now) {
+      self.metrics.replayed_handshakes.inc();
+      anyhow::bail!("Refusing key exchange from {}: {}", src_addr, e);
+    }
+    if self.clients.get(&src_addr).is_some_and(|client| client.refuses_handshakes()) {
+      self.metrics.refused_handshakes.inc();
+      anyhow::bail!("Refusing key exchange from {}: it has an established session", src_addr);
+    }
+    self.remove_client(src_addr).await;
+
+    let session_id = loop {
+      let mut session_id = [0u8; SESSION_ID_SIZE];
+      fill_random_bytes(&mut session_id);
+      let session_id = SessionId::from_be_bytes(session_id);
+      if session_id != HANDSHAKE_SESSION
+        && session_id != RENDEZVOUS_SESSION
+        && !self.sessions.contains_key(&session_id)
+      {
+        break session_id;
+      }
+    };
+
+    let handshake = ServerHandshake {
+      transforms: &self.transforms,
+      accepted_transforms: &self.accepted_transforms,
+      static_key: self.static_key.as_ref(),
+      features: self.features(),
+    };
+    let Accepted { session, features, limits, ephemeral, reply } =
+      handshake.accept(&client_key, &transforms, offer, src_addr, session_id)?;
+
+    let outbound = self.send_queue(src_addr, local, &[]);
+    let mut client =
+      ConnectedClient::new(session.key, session_id, src_addr, self.client_timeout, outbound, ephemeral);
+    client.pipeline = session.pipeline;
+    client.features = features;
+    client.limits = limits;
+    client.local = local;
+    client.clock_offset = timestamp as i64 - now as i64;
+    self.clients.insert(src_addr, client);
+    self.sessions.insert(session_id, src_addr);
+
+    let sent = self.socket.send_from(&reply, src_addr, ip::ECN_NOT_ECT, local);
+    _ = tokio::time::timeout(self.client_timeout, sent).await?;
 
-    info!("Key exchange completed for client {}", src_addr);
+    info!(target: logging::HANDSHAKE, "Key exchange completed for client {}", src_addr);
+    debug!(target: logging::HANDSHAKE, "Features of the session of {}: {}", src_addr, features);
     Ok(())
   }
 }
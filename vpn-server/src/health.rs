@@ -0,0 +1,457 @@
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+use tracing::error;
+use tracing::info;
+use vpn_shared::logging;
+
+use crate::admin;
+use crate::admin::AdminError;
+use crate::history::SessionRecord;
+use crate::server::Server;
+use crate::startup::Component;
+
+/// Token granting access to the admin routes from anywhere, sent as `Authorization: Bearer <token>`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdminToken {
+  pub token: String,
+
+  /// Tenant network the token is limited to; without it the token can do everything local requests can.
+  #[serde(default)]
+  pub network: Option<String>,
+}
+
+impl AdminToken {
+  pub fn matches(&self, token: &str) -> bool {
+    let (expected, token) = (self.token.as_bytes(), token.as_bytes());
+    expected.len() == token.len() && expected.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+  }
+
+  pub fn scope(&self) -> Scope {
+    match self.network {
+      Some(ref network) => Scope::Network(network.clone()),
+      None => Scope::All,
+    }
+  }
+}
+
+/// What an admin request may see and do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+  All,
+  /// Only sessions of this tenant network, and no server-wide settings.
+  Network(String),
+}
+
+impl Scope {
+  pub fn includes(&self, network: Option<&str>) -> bool {
+    match self {
+      Scope::All => true,
+      Scope::Network(name) => network == Some(name.as_str()),
+    }
+  }
+}
+
+/// Session of a connected user, as listed by `/clients`.
+#[derive(Debug, Serialize)]
+pub struct LiveClient {
+  pub username: String,
+  #[serde(flatten)]
+  pub session: SessionRecord,
+  /// How far the client's clock was ahead of the server's when it connected; negative when behind.
+  pub clock_offset_secs: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Health {
+  main_loop_running: AtomicBool,
+  tun_loop_running: AtomicBool,
+  cleanup_heartbeat: AtomicU64,
+}
+
+impl Health {
+  pub fn set_main_loop_running(&self, running: bool) {
+    self.main_loop_running.store(running, Ordering::Relaxed);
+  }
+
+  pub fn set_tun_loop_running(&self, running: bool) {
+    self.tun_loop_running.store(running, Ordering::Relaxed);
+  }
+
+  pub fn cleanup_beat(&self) {
+    self.cleanup_heartbeat.store(now_secs(), Ordering::Relaxed);
+  }
+
+  pub fn is_main_loop_alive(&self) -> bool {
+    self.main_loop_running.load(Ordering::Relaxed)
+  }
+
+  pub fn is_tun_loop_alive(&self) -> bool {
+    self.tun_loop_running.load(Ordering::Relaxed)
+  }
+
+  pub fn is_cleanup_alive(&self, max_silence: Duration) -> bool {
+    let last = self.cleanup_heartbeat.load(Ordering::Relaxed);
+    last != 0 && now_secs().saturating_sub(last) <= max_silence.as_secs()
+  }
+}
+
+/// Stops reporting the main loop as alive once `Server::run` returns or unwinds.
+pub struct MainLoopGuard(pub Arc<Health>);
+
+impl Drop for MainLoopGuard {
+  fn drop(&mut self) {
+    self.0.set_main_loop_running(false);
+  }
+}
+
+/// Stops reporting the tun loop as alive once `Server::serve_tun` returns, until the supervisor restarts it.
+pub struct TunLoopGuard(pub Arc<Health>);
+
+impl Drop for TunLoopGuard {
+  fn drop(&mut self) {
+    self.0.set_tun_loop_running(false);
+  }
+}
+
+pub struct HealthReport {
+  pub main_loop: bool,
+  pub cleanup: bool,
+  /// Whether the tun loop runs and the device is still there; `None` for a server without a tun.
+  pub tun: Option<bool>,
+  /// Whether everything the server starts with is up.
+  pub startup: bool,
+}
+
+impl HealthReport {
+  /// Current state of `server`, whose cleanup runs every `cleanup_interval`.
+  pub fn of(server: &Server, cleanup_interval: Duration) -> Self {
+    Self {
+      main_loop: server.health.is_main_loop_alive(),
+      cleanup: server.health.is_cleanup_alive(cleanup_interval * 2),
+      tun: server.tun.as_ref().map(|tun| server.health.is_tun_loop_alive() && tun.is_present()),
+      startup: server.startup.is_ready(),
+    }
+  }
+
+  pub fn is_live(&self) -> bool {
+    self.main_loop && self.cleanup && self.tun != Some(false)
+  }
+
+  pub fn is_ready(&self) -> bool {
+    self.main_loop && self.startup
+  }
+
+  fn render(&self) -> String {
+    let status = |alive: bool| if alive { "ok" } else { "down" };
+    let startup = if self.startup { "ok" } else { "pending" };
+    let tun = self.tun.map(|alive| format!("tun: {}\n", status(alive))).unwrap_or_default();
+    format!(
+      "main-loop: {}\ncleanup: {}\n{}startup: {}\n",
+      status(self.main_loop),
+      status(self.cleanup),
+      tun,
+      startup
+    )
+  }
+}
+
+pub async fn serve(
+  address: SocketAddr,
+  server: Arc<Server>,
+  cleanup_interval: Duration,
+) -> anyhow::Result<()> {
+  let listener = TcpListener::bind(address).await?;
+  info!(target: logging::ADMIN, "Health endpoint listening on {}", address);
+  server.startup.ready(Component::AdminApi);
+
+  loop {
+    let (stream, peer) = listener.accept().await?;
+    let report = HealthReport::of(&server, cleanup_interval);
+
+    let server = server.clone();
+    tokio::spawn(async move {
+      if let Err(e) = respond(stream, peer, report, &server).await {
+        error!(target: logging::ADMIN, "Failed to answer health probe from {}: {}", peer, e);
+      }
+    });
+  }
+}
+
+async fn respond(
+  mut stream: TcpStream,
+  peer: SocketAddr,
+  report: HealthReport,
+  server: &Server,
+) -> anyhow::Result<()> {
+  let mut buf = [0u8; 1024];
+  let len = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+  let request = String::from_utf8_lossy(&buf[..len]);
+
+  let response = answer(&request, peer.ip(), report, server).await?;
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
+/// Whole HTTP response to a request of the health endpoint from `peer`; requests without a token may only
+/// use the admin routes from the server itself.
+pub async fn answer(
+  request: &str,
+  peer: IpAddr,
+  report: HealthReport,
+  server: &Server,
+) -> anyhow::Result<String> {
+  let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+  let method = request_line.next().unwrap_or("GET");
+  let target = request_line.next().unwrap_or("/");
+  let (path, query) = target.split_once('?').unwrap_or((target, ""));
+  let body = request.split_once("\r\n\r\n").map(|(_, body)| body.trim()).unwrap_or_default();
+
+  let is_admin = [
+    "/log-level",
+    "/flight-recorder",
+    "/sessions",
+    "/clients",
+    "/traces",
+    "/bandwidth",
+    "/rekey",
+    "/config-diff",
+    "/reload",
+    "/listeners",
+    "/session-table",
+  ]
+  .iter()
+  .any(|route| path == *route || path.strip_prefix(route).is_some_and(|rest| rest.starts_with('/')));
+  let token = request.lines().find_map(|line| {
+    let (name, value) = line.split_once(':')?;
+    name.trim().eq_ignore_ascii_case("authorization").then(|| value.trim().strip_prefix("Bearer "))?
+  });
+
+  let (status, body) = match path {
+    "/healthz" if report.is_live() => ("200 OK", report.render()),
+    "/healthz" => ("503 Service Unavailable", report.render()),
+    "/readyz" if report.is_ready() => ("200 OK", report.render()),
+    "/readyz" => ("503 Service Unavailable", report.render()),
+    "/metrics" => ("200 OK", server.metrics.render()),
+    _ if is_admin => match admin::authorize(server, token, peer.is_loopback()) {
+      Ok(scope) => admin_route(server, &scope, method, path, query, body, peer).await?,
+      Err(e) => error_status(e),
+    },
+    _ => ("404 Not Found", "not found\n".to_string()),
+  };
+
+  Ok(format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body
+  ))
+}
+
+/// `GET /log-level` returns the current level, `PUT` with a level as the body changes it. `POST
+/// /flight-recorder` dumps the flight recorder and returns the path of the dump. `DELETE
+/// /clients/USER?force` kicks the user even from the session the request came through. `PUT
+/// /traces/SESSION` traces the packets of a session for the seconds in the body, or a minute; `GET` returns
+/// the trace and `DELETE` drops it. `GET /bandwidth` returns the bandwidth graphs of all sessions, `GET
+/// /bandwidth/SESSION` of one. `POST /rekey/SESSION` has a session replace its key. `POST /reload` reloads
+/// the configuration file, `POST /reload?dry-run` and `GET /config-diff` only preview it, see `reload`.
+/// `GET /listeners` returns the traffic of the listeners; `PUT /listeners/LISTENER?force` refuses
+/// handshakes on one for the seconds in the body, or until `DELETE` accepts them again. `GET /session-table?offset=N&limit=N&format=openmetrics` returns a
+/// page of the session table, as JSON without `format`, see `table`.
+async fn admin_route(
+  server: &Server,
+  scope: &Scope,
+  method: &str,
+  path: &str,
+  query: &str,
+  body: &str,
+  peer: IpAddr,
+) -> anyhow::Result<(&'static str, String)> {
+  let force = query.split('&').any(|parameter| parameter == "force" || parameter == "force=true");
+  let result = match path {
+    "/log-level" if matches!(method, "PUT" | "POST") => {
+      admin::set_log_level(scope, body).map(|level| format!("{}\n", level))
+    }
+    "/log-level" => admin::log_level(scope).map(|level| format!("{}\n", level)),
+    "/flight-recorder" if matches!(method, "PUT" | "POST") => {
+      admin::dump_flight_recorder(scope).map(|path| format!("{}\n", path.display()))
+    }
+    "/sessions" => Ok(serde_json::to_string_pretty(&admin::latest_sessions(server, scope))? + "\n"),
+    "/clients" => Ok(serde_json::to_string_pretty(&admin::clients(server, scope))? + "\n"),
+    "/bandwidth" => match admin::bandwidth_graphs(server, scope) {
+      Ok(graphs) => Ok(serde_json::to_string_pretty(&graphs)? + "\n"),
+      Err(e) => Err(e),
+    },
+    _ if path.starts_with("/bandwidth/") => {
+      match admin::bandwidth_graph(server, scope, path.trim_start_matches("/bandwidth/")) {
+        Ok(graph) => Ok(serde_json::to_string_pretty(&graph)? + "\n"),
+        Err(e) => Err(e),
+      }
+    }
+    "/reload" | "/config-diff" => {
+      let dry_run = path == "/config-diff"
+        || !matches!(method, "PUT" | "POST")
+        || query.split('&').any(|parameter| matches!(parameter, "dry-run" | "dry-run=true"));
+      match admin::reload(server, scope, dry_run).await {
+        Ok(diff) => Ok(serde_json::to_string_pretty(&diff)? + "\n"),
+        Err(e) => Err(e),
+      }
+    }
+    "/session-table" => {
+      let number = |name| match parameter(query, name) {
+        Some(value) => value.parse().map(Some).map_err(|_| AdminError::Invalid(format!("Invalid {}", name))),
+        None => Ok(None),
+      };
+      let page = number("offset").and_then(|offset| Ok((offset.unwrap_or_default(), number("limit")?)));
+      match page.and_then(|(offset, limit)| admin::session_table(server, scope, offset, limit)) {
+        Ok(table) if parameter(query, "format") == Some("openmetrics") => Ok(table.openmetrics()),
+        Ok(table) => Ok(serde_json::to_string_pretty(&table)? + "\n"),
+        Err(e) => Err(e),
+      }
+    }
+    "/listeners" => match admin::listeners(server, scope) {
+      Ok(listeners) => Ok(serde_json::to_string_pretty(&listeners)? + "\n"),
+      Err(e) => Err(e),
+    },
+    _ if path.starts_with("/listeners/") => {
+      let listener = path.trim_start_matches("/listeners/");
+      match method {
+        "PUT" | "POST" => match body {
+          "" => Ok(None),
+          secs => secs.parse().map(|secs| Some(Duration::from_secs(secs))).map_err(|_| {
+            AdminError::Invalid(format!("Expected the seconds to refuse handshakes for, got {}", secs))
+          }),
+        }
+        .and_then(|duration| admin::refuse_handshakes(server, scope, listener, duration, force))
+        .map(|()| "refusing\n".to_string()),
+        "DELETE" => admin::accept_handshakes(server, scope, listener).map(|()| "accepting\n".to_string()),
+        _ => return Ok(("404 Not Found", "not found\n".to_string())),
+      }
+    }
+    _ if path.starts_with("/rekey/") && matches!(method, "PUT" | "POST") => {
+      admin::rekey(server, scope, path.trim_start_matches("/rekey/")).await.map(|()| "rekeying\n".to_string())
+    }
+    _ => {
+      match (path.strip_prefix("/sessions/"), path.strip_prefix("/clients/"), path.strip_prefix("/traces/")) {
+        (Some(username), _, _) => {
+          Ok(serde_json::to_string_pretty(&admin::sessions(server, scope, username))? + "\n")
+        }
+        (_, Some(username), _) if method == "DELETE" => {
+          admin::kick(server, scope, username, peer, force).await.map(|kicked| format!("{}\n", kicked))
+        }
+        (_, _, Some(session_id)) => match method {
+          "PUT" | "POST" => match body {
+            "" => Ok(None),
+            secs => secs
+              .parse()
+              .map(|secs| Some(Duration::from_secs(secs)))
+              .map_err(|_| AdminError::Invalid(format!("Expected the seconds to trace for, got {}", secs))),
+          }
+          .and_then(|duration| admin::start_trace(server, scope, session_id, duration))
+          .map(|duration| format!("{}\n", duration.as_secs())),
+          "DELETE" => admin::delete_trace(server, scope, session_id).map(|()| "deleted\n".to_string()),
+          _ => match admin::trace(server, scope, session_id) {
+            Ok(trace) => Ok(serde_json::to_string_pretty(&trace)? + "\n"),
+            Err(e) => Err(e),
+          },
+        },
+        _ => return Ok(("404 Not Found", "not found\n".to_string())),
+      }
+    }
+  };
+  Ok(match result {
+    Ok(body) => ("200 OK", body),
+    Err(e) => error_status(e),
+  })
+}
+
+fn parameter<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+  query.split('&').find_map(|parameter| parameter.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn error_status(error: AdminError) -> (&'static str, String) {
+  let status = match error {
+    AdminError::Unauthorized => "401 Unauthorized",
+    AdminError::Forbidden => "403 Forbidden",
+    AdminError::Invalid(_) => "400 Bad Request",
+    AdminError::NotFound(_) => "404 Not Found",
+    AdminError::Lockout(_) => "409 Conflict",
+  };
+  (status, format!("{}\n", error))
+}
+
+/// Sends a request to the health endpoint of a running server on `address` and returns the response body.
+pub fn admin_request(
+  address: SocketAddr,
+  token: Option<&str>,
+  method: &str,
+  path: &str,
+  body: &str,
+) -> anyhow::Result<String> {
+  use std::io::Read as _;
+  use std::io::Write as _;
+
+  let address = match address.ip().is_unspecified() {
+    true => SocketAddr::new([127, 0, 0, 1].into(), address.port()),
+    false => address,
+  };
+
+  let mut stream = std::net::TcpStream::connect_timeout(&address, Duration::from_secs(5))?;
+  stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+  let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+  let request = format!(
+    "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+    method,
+    path,
+    authorization,
+    body.len(),
+    body
+  );
+  stream.write_all(request.as_bytes())?;
+
+  let mut response = String::new();
+  stream.read_to_string(&mut response)?;
+  let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+  if !head.starts_with("HTTP/1.1 200") {
+    anyhow::bail!("Server refused: {}", body.trim());
+  }
+  Ok(body.trim().to_string())
+}
+
+fn now_secs() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_tun_liveness() {
+    let report = |tun| HealthReport { main_loop: true, cleanup: true, tun, startup: true };
+
+    assert!(report(None).is_live());
+    assert!(!report(None).render().contains("tun"));
+    assert!(report(Some(true)).is_live());
+    assert!(report(Some(true)).render().contains("tun: ok\n"));
+    // A tun loop that stopped, or a device that's gone, fails the liveness probe.
+    assert!(!report(Some(false)).is_live());
+    assert!(report(Some(false)).render().contains("tun: down\n"));
+  }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::Write as _;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::accounting::Accounting;
+use crate::accounting::AccountingEvent;
+use crate::accounting::AccountingKind;
+
+const QUEUE_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HistoryConfig {
+  /// Recent sessions kept for every user; older ones are forgotten.
+  #[serde(default = "default_sessions_per_user")]
+  pub sessions_per_user: usize,
+
+  /// File finished sessions are appended to as JSON Lines, so the history survives restarts.
+  #[serde(default)]
+  pub path: Option<PathBuf>,
+}
+
+impl Default for HistoryConfig {
+  fn default() -> Self {
+    Self { sessions_per_user: default_sessions_per_user(), path: None }
+  }
+}
+
+fn default_sessions_per_user() -> usize {
+  10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionRecord {
+  pub session_id: String,
+  pub client_addr: String,
+  pub virtual_ip: Option<Ipv4Addr>,
+  /// Device of the user the session is from, absent for sessions not tied to one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub device: Option<String>,
+  /// Tenant network of the user, absent for the default one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub network: Option<String>,
+  /// Seconds since the Unix epoch.
+  pub connected_at: u64,
+  pub duration_secs: u64,
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+  /// Whether the session is still connected; duration and bytes are as of the last accounting record.
+  pub active: bool,
+}
+
+impl SessionRecord {
+  pub fn new(event: &AccountingEvent, now: SystemTime) -> Self {
+    let connected_at = now.checked_sub(event.duration).unwrap_or(now);
+    Self {
+      session_id: format!("{:016x}", event.session_id),
+      client_addr: event.client_addr.to_string(),
+      virtual_ip: event.virtual_ip,
+      device: event.device.clone(),
+      network: event.network.clone(),
+      connected_at: connected_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+      duration_secs: event.duration.as_secs(),
+      bytes_in: event.bytes_in,
+      bytes_out: event.bytes_out,
+      active: event.kind != AccountingKind::Stop,
+    }
+  }
+}
+
+/// Persisted form of a finished session.
+#[derive(Serialize, Deserialize)]
+pub struct Line {
+  pub username: String,
+  #[serde(flatten)]
+  pub session: SessionRecord,
+}
+
+/// Last sessions of every user, newest last, answering when and from where someone connected.
+pub struct SessionHistory {
+  sessions_per_user: usize,
+  users: Mutex<HashMap<String, VecDeque<SessionRecord>>>,
+  writes: Option<mpsc::Sender<Write>>,
+}
+
+/// What the task owning the history file is asked to do with it.
+enum Write {
+  Append(String),
+  Compact,
+}
+
+impl Default for SessionHistory {
+  fn default() -> Self {
+    Self { sessions_per_user: default_sessions_per_user(), users: Mutex::default(), writes: None }
+  }
+}
+
+impl SessionHistory {
+  pub fn new(config: HistoryConfig) -> anyhow::Result<Self> {
+    let mut history = Self { sessions_per_user: config.sessions_per_user, ..Self::default() };
+
+    if let Some(path) = config.path {
+      history.load(&path)?;
+      // Rewrite the file with only what's kept, so it doesn't grow without bound across restarts.
+      history.rewrite(&path)?;
+
+      let (writes, rx) = mpsc::channel(QUEUE_DEPTH);
+      tokio::spawn(write_file(path, config.sessions_per_user, rx));
+      history.writes = Some(writes);
+    }
+
+    Ok(history)
+  }
+
+  /// Replaces the file at `path` with the sessions kept; through a new file, so that a failure half way
+  /// doesn't lose the old one.
+  fn rewrite(&mut self, path: &Path) -> anyhow::Result<()> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    let mut file = std::fs::File::create(&name)?;
+    for (username, sessions) in self.users.get_mut().unwrap().iter() {
+      for session in sessions {
+        writeln!(file, "{}", line(username, session))?;
+      }
+    }
+    std::fs::rename(name, path)?;
+    Ok(())
+  }
+
+  /// Asks for the file to be rewritten with only the sessions kept, as on start.
+  pub fn compact(&self) {
+    if let Some(ref writes) = self.writes {
+      if writes.try_send(Write::Compact).is_err() {
+        warn!("Session history queue is full; not compacting it");
+      }
+    }
+  }
+
+  fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+    let file = match std::fs::File::open(path) {
+      Ok(file) => file,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+
+    for text in std::io::BufReader::new(file).lines() {
+      match serde_json::from_str::<Line>(&text?) {
+        Ok(line) => self.push(line.username, line.session),
+        Err(e) => warn!("Skipping a malformed line of {}: {}", path.display(), e),
+      }
+    }
+    Ok(())
+  }
+
+  fn push(&self, username: String, session: SessionRecord) {
+    let mut users = self.users.lock().unwrap();
+    let sessions = users.entry(username).or_default();
+    sessions.push_back(session);
+    while sessions.len() > self.sessions_per_user {
+      sessions.pop_front();
+    }
+  }
+
+  /// Sessions of `username`, newest first.
+  pub fn sessions(&self, username: &str) -> Vec<SessionRecord> {
+    let users = self.users.lock().unwrap();
+    users.get(username).map(|sessions| sessions.iter().rev().cloned().collect()).unwrap_or_default()
+  }
+
+  /// Latest session of every user.
+  pub fn latest(&self) -> HashMap<String, SessionRecord> {
+    let users = self.users.lock().unwrap();
+    users.iter().filter_map(|(user, sessions)| Some((user.clone(), sessions.back()?.clone()))).collect()
+  }
+}
+
+impl Accounting for SessionHistory {
+  fn record(&self, event: AccountingEvent) {
+    if self.sessions_per_user == 0 {
+      return;
+    }
+
+    let session = SessionRecord::new(&event, SystemTime::now());
+    {
+      let mut users = self.users.lock().unwrap();
+      let existing = users
+        .get_mut(&event.username)
+        .and_then(|sessions| sessions.iter_mut().rev().find(|s| s.session_id == session.session_id));
+      match existing {
+        Some(existing) => {
+          *existing = SessionRecord { connected_at: existing.connected_at, ..session.clone() }
+        }
+        None => {
+          drop(users);
+          self.push(event.username.clone(), session.clone());
+        }
+      }
+    }
+
+    if let (AccountingKind::Stop, Some(writes)) = (event.kind, &self.writes) {
+      if writes.try_send(Write::Append(line(&event.username, &session))).is_err() {
+        warn!("Session history queue is full; not persisting a session of {}", event.username);
+      }
+    }
+  }
+}
+
+fn line(username: &str, session: &SessionRecord) -> String {
+  let line = Line { username: username.to_string(), session: session.clone() };
+  serde_json::to_string(&line).expect("session records are serializable")
+}
+
+async fn write_file(path: PathBuf, sessions_per_user: usize, mut writes: mpsc::Receiver<Write>) {
+  while let Some(write) = writes.recv().await {
+    let result = match write {
+      Write::Append(line) => append(&path, &line).await,
+      // Reads back what was written, rather than taking the sessions in memory, whose lines may still be
+      // queued behind this.
+      Write::Compact => {
+        let mut kept = SessionHistory { sessions_per_user, ..SessionHistory::default() };
+        kept.load(&path).and_then(|()| kept.rewrite(&path))
+      }
+    };
+
+    if let Err(e) = result {
+      warn!("Failed to write session history {}: {}", path.display(), e);
+    }
+  }
+}
+
+async fn append(path: &Path, line: &str) -> anyhow::Result<()> {
+  let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+  file.write_all(format!("{}\n", line).as_bytes()).await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  fn event(kind: AccountingKind, session_id: u64, duration: u64) -> AccountingEvent {
+    AccountingEvent {
+      kind,
+      session_id,
+      username: "alice".into(),
+      device: None,
+      network: None,
+      client_addr: "192.0.2.1:6969".parse().unwrap(),
+      virtual_ip: None,
+      bytes_in: duration * 10,
+      bytes_out: 0,
+      duration: Duration::from_secs(duration),
+    }
+  }
+
+  #[test]
+  fn test_history_is_bounded() {
+    let history = SessionHistory::new(HistoryConfig { sessions_per_user: 2, path: None }).unwrap();
+    history.record(event(AccountingKind::Start, 1, 0));
+    history.record(event(AccountingKind::Stop, 1, 5));
+    history.record(event(AccountingKind::Start, 2, 0));
+    history.record(event(AccountingKind::Interim, 2, 3));
+    history.record(event(AccountingKind::Start, 3, 0));
+
+    let sessions = history.sessions("alice");
+    assert_eq!(
+      sessions.iter().map(|s| s.session_id.as_str()).collect::<Vec<_>>(),
+      ["0000000000000003", "0000000000000002"]
+    );
+    assert!(sessions[1].active);
+    assert_eq!(sessions[1].bytes_in, 30);
+    assert!(history.sessions("bob").is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_history_is_persisted() {
+    let path = std::env::temp_dir().join(format!("vpn-history-{}.jsonl", std::process::id()));
+    _ = std::fs::remove_file(&path);
+    let config = HistoryConfig { sessions_per_user: 10, path: Some(path.clone()) };
+
+    let history = SessionHistory::new(config.clone()).unwrap();
+    history.record(event(AccountingKind::Start, 1, 0));
+    history.record(event(AccountingKind::Stop, 1, 5));
+    history.record(event(AccountingKind::Start, 2, 0));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let restored = SessionHistory::new(config).unwrap();
+    let sessions = restored.sessions("alice");
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].duration_secs, 5);
+    assert!(!sessions[0].active);
+
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_compaction() {
+    let path = std::env::temp_dir().join(format!("vpn-history-compaction-{}.jsonl", std::process::id()));
+    _ = std::fs::remove_file(&path);
+
+    let history =
+      SessionHistory::new(HistoryConfig { sessions_per_user: 2, path: Some(path.clone()) }).unwrap();
+    for id in 1..=3 {
+      history.record(event(AccountingKind::Stop, id, id));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+    history.compact();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let lines: Vec<Line> = std::fs::read_to_string(&path)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    assert_eq!(lines.iter().map(|line| line.session.duration_secs).collect::<Vec<_>>(), [2, 3]);
+
+    std::fs::remove_file(&path).unwrap();
+  }
+}
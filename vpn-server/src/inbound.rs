@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use std::time::Instant;
+
+use vpn_shared::ip;
+use vpn_shared::ip::Flow;
+use vpn_shared::packet::Notice;
+use vpn_shared::rate::TokenBucket;
+
+/// UDP has no handshake, so a datagram from a source that was quiet this long counts as a new connection.
+const UDP_IDLE: Duration = Duration::from_secs(60);
+/// UDP sources remembered at most per session; older ones are forgotten past this.
+const MAX_UDP_SOURCES: usize = 1024;
+/// Notices per second a session gets at most, with bursts of `NOTICE_BURST`, so a port scan doesn't flood
+/// the tunnel with them.
+const NOTICE_RATE: f64 = 5.0;
+const NOTICE_BURST: f64 = 20.0;
+
+/// New connections reaching a client through port forwards, which it's told about with
+/// `Notice::InboundConnection`.
+#[derive(Debug)]
+pub struct InboundConnections {
+  udp: HashMap<SocketAddrV4, Instant>,
+  notices: TokenBucket,
+}
+
+impl Default for InboundConnections {
+  fn default() -> Self {
+    Self { udp: HashMap::new(), notices: TokenBucket::new(NOTICE_RATE, NOTICE_BURST) }
+  }
+}
+
+impl InboundConnections {
+  /// Notice for a forwarded packet of `flow` if it opens a connection and the client isn't being told about
+  /// too many already.
+  pub fn notice(&mut self, flow: Flow, packet: &[u8], now: Instant) -> Option<Notice> {
+    let opens = match flow.protocol {
+      ip::PROTO_TCP => ip::is_tcp_syn(packet),
+      _ => {
+        let last = self.udp.insert(flow.source, now);
+        if self.udp.len() > MAX_UDP_SOURCES {
+          self.udp.retain(|_, seen| now.duration_since(*seen) < UDP_IDLE);
+        }
+        last.is_none_or(|last| now.duration_since(last) >= UDP_IDLE)
+      }
+    };
+    if !opens || !self.notices.try_take(1.0) {
+      return None;
+    }
+    Some(Notice::InboundConnection {
+      protocol: flow.protocol,
+      source: flow.source,
+      port: flow.destination_port,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use super::*;
+
+  fn packet(protocol: u8, source_port: u16, flags: u8) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, protocol, 0, 0, 203, 0, 113, 7, 10, 8, 0, 2];
+    packet.extend(source_port.to_be_bytes());
+    packet.extend([0, 22, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet
+  }
+
+  #[test]
+  fn test_notice() {
+    let mut inbound = InboundConnections::default();
+    let now = Instant::now();
+    let syn = packet(ip::PROTO_TCP, 50000, 0x02);
+    let flow = ip::flow(&syn).unwrap();
+
+    let expected = Notice::InboundConnection {
+      protocol: ip::PROTO_TCP,
+      source: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 50000),
+      port: 22,
+    };
+    assert_eq!(inbound.notice(flow, &syn, now), Some(expected));
+    assert_eq!(inbound.notice(flow, &packet(ip::PROTO_TCP, 50000, 0x10), now), None);
+
+    let datagram = packet(ip::PROTO_UDP, 5353, 0);
+    let flow = ip::flow(&datagram).unwrap();
+    assert!(inbound.notice(flow, &datagram, now).is_some());
+    assert!(inbound.notice(flow, &datagram, now + Duration::from_secs(30)).is_none());
+    assert!(inbound.notice(flow, &datagram, now + Duration::from_secs(89)).is_none());
+    assert!(inbound.notice(flow, &datagram, now + Duration::from_secs(151)).is_some());
+
+    // A scan gets the burst through, and the rest is dropped.
+    let noticed = (0..100)
+      .filter_map(|port| {
+        let syn = packet(ip::PROTO_TCP, 40000 + port, 0x02);
+        inbound.notice(ip::flow(&syn).unwrap(), &syn, now)
+      })
+      .count();
+    assert!(noticed <= NOTICE_BURST as usize);
+  }
+}
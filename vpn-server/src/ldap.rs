@@ -0,0 +1,217 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct LdapConfig {
+  /// `ldap://` or `ldaps://` URL of the directory.
+  pub url: String,
+
+  #[serde(default)]
+  pub starttls: bool,
+
+  /// DN users bind as; `{username}` is replaced with the escaped username, e.g.
+  /// `uid={username},ou=people,dc=example,dc=com` or `{username}@example.com` for Active Directory.
+  pub bind_dn: String,
+
+  /// Attribute of the user entry listing their groups. Values that are DNs are reduced to their first
+  /// RDN's value, so `cn=vpn-admins,ou=groups,...` maps to `vpn-admins`.
+  #[serde(default = "default_group_attribute")]
+  pub group_attribute: String,
+
+  /// Entry to read the groups from, with the same placeholder as `bind-dn`; defaults to `bind-dn`, which
+  /// doesn't work for the UPN form Active Directory accepts.
+  #[serde(default)]
+  pub user_dn: Option<String>,
+
+  /// Account the server binds as to look users up when they resume a session with a ticket, which carries
+  /// no password to bind with; without one, resumed sessions keep the groups they were issued with.
+  #[serde(default)]
+  pub lookup_dn: Option<String>,
+
+  #[serde(default)]
+  pub lookup_password: Option<String>,
+
+  /// Limit on connecting and on each bind and search, after which the user is refused.
+  #[serde(default = "default_timeout_secs")]
+  pub timeout_secs: u64,
+}
+
+fn default_group_attribute() -> String {
+  "memberOf".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+  5
+}
+
+#[cfg(feature = "ldap")]
+pub use store::LdapStore;
+
+#[cfg(feature = "ldap")]
+mod store {
+  use std::future::Future;
+  use std::time::Duration;
+
+  use ldap3::Ldap;
+  use ldap3::LdapConnAsync;
+  use ldap3::LdapConnSettings;
+  use ldap3::Scope;
+  use ldap3::SearchEntry;
+
+  use tracing::warn;
+  use vpn_shared::creds::Credentials;
+
+  use super::LdapConfig;
+  use crate::auth::AuthFuture;
+  use crate::auth::CredentialStore;
+  use crate::auth::Identity;
+  use crate::auth::Lookup;
+  use crate::auth::LookupFuture;
+
+  const INVALID_CREDENTIALS: u32 = 49;
+  const NO_SUCH_OBJECT: u32 = 32;
+  /// `ACCOUNTDISABLE` flag of Active Directory's `userAccountControl`.
+  const ACCOUNT_DISABLED: u32 = 0x2;
+
+  /// Authenticates users with a simple bind as themselves, so no service account is needed.
+  pub struct LdapStore {
+    config: LdapConfig,
+  }
+
+  impl LdapStore {
+    pub fn new(config: LdapConfig) -> Self {
+      Self { config }
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> anyhow::Result<Option<Identity>> {
+      // An empty password makes an unauthenticated bind, which many servers accept for any DN.
+      if username.is_empty() || password.is_empty() {
+        return Ok(None);
+      }
+
+      let mut ldap = self.connect().await?;
+      let bind =
+        self.within("bind", ldap.simple_bind(&self.dn(&self.config.bind_dn, username), password)).await?;
+      if bind.rc == INVALID_CREDENTIALS {
+        return Ok(None);
+      }
+      bind.success()?;
+
+      let user_dn = self.dn(self.config.user_dn.as_deref().unwrap_or(&self.config.bind_dn), username);
+      let search = ldap.search(&user_dn, Scope::Base, "(objectClass=*)", vec![&self.config.group_attribute]);
+      let groups = match self.within("search", search).await?.success() {
+        Ok((entries, _)) => entries
+          .into_iter()
+          .flat_map(|entry| {
+            SearchEntry::construct(entry).attrs.remove(&self.config.group_attribute).unwrap_or_default()
+          })
+          .map(|group| group_name(&group).to_string())
+          .collect(),
+        Err(e) => {
+          warn!("Failed to read LDAP groups of {}: {}", username, e);
+          Vec::new()
+        }
+      };
+
+      _ = self.within("unbind", ldap.unbind()).await;
+      Ok(Some(Identity { username: username.to_string(), groups }))
+    }
+
+    /// Reads the entry of `username` as the lookup account; a missing or disabled entry is `Absent`.
+    async fn find(&self, username: &str) -> anyhow::Result<Lookup> {
+      let (Some(lookup_dn), Some(lookup_password)) = (&self.config.lookup_dn, &self.config.lookup_password)
+      else {
+        return Ok(Lookup::Unsupported);
+      };
+      if username.is_empty() {
+        return Ok(Lookup::Absent);
+      }
+
+      let mut ldap = self.connect().await?;
+      self.within("bind", ldap.simple_bind(lookup_dn, lookup_password)).await?.success()?;
+      let user_dn = self.dn(self.config.user_dn.as_deref().unwrap_or(&self.config.bind_dn), username);
+      let attributes = vec![self.config.group_attribute.as_str(), "userAccountControl"];
+      let search =
+        self.within("search", ldap.search(&user_dn, Scope::Base, "(objectClass=*)", attributes)).await?;
+      if search.1.rc == NO_SUCH_OBJECT {
+        _ = self.within("unbind", ldap.unbind()).await;
+        return Ok(Lookup::Absent);
+      }
+      let (entries, _) = search.success()?;
+      _ = self.within("unbind", ldap.unbind()).await;
+
+      let Some(mut entry) = entries.into_iter().next().map(SearchEntry::construct) else {
+        return Ok(Lookup::Absent);
+      };
+      let control = entry.attrs.remove("userAccountControl").unwrap_or_default();
+      if control.iter().any(|flags| flags.parse::<u32>().is_ok_and(|flags| flags & ACCOUNT_DISABLED != 0)) {
+        return Ok(Lookup::Absent);
+      }
+      let groups = entry.attrs.remove(&self.config.group_attribute).unwrap_or_default();
+      let groups = groups.iter().map(|group| group_name(group).to_string()).collect();
+      Ok(Lookup::Active(Identity { username: username.to_string(), groups }))
+    }
+
+    async fn connect(&self) -> anyhow::Result<Ldap> {
+      let settings = LdapConnSettings::new()
+        .set_starttls(self.config.starttls)
+        .set_conn_timeout(Duration::from_secs(self.config.timeout_secs));
+      let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+      ldap3::drive!(conn);
+      Ok(ldap)
+    }
+
+    /// Fails an operation the directory doesn't answer within the timeout, which otherwise holds up the
+    /// handshake of the user for as long as the connection stays open.
+    async fn within<T>(
+      &self,
+      operation: &str,
+      future: impl Future<Output = ldap3::result::Result<T>>,
+    ) -> anyhow::Result<T> {
+      let timeout = Duration::from_secs(self.config.timeout_secs);
+      match tokio::time::timeout(timeout, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => anyhow::bail!("LDAP {} timed out after {:?}", operation, timeout),
+      }
+    }
+
+    fn dn(&self, template: &str, username: &str) -> String {
+      template.replace("{username}", &ldap3::dn_escape(username))
+    }
+  }
+
+  impl CredentialStore for LdapStore {
+    fn name(&self) -> &str {
+      "ldap"
+    }
+
+    fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a> {
+      let Credentials::Password { username, password } = credentials else {
+        return Box::pin(async { Ok(None) });
+      };
+      Box::pin(self.bind(username, password))
+    }
+
+    fn lookup<'a>(&'a self, username: &'a str) -> LookupFuture<'a> {
+      Box::pin(self.find(username))
+    }
+  }
+
+  fn group_name(group: &str) -> &str {
+    match group.split_once('=') {
+      Some((_, rest)) if group.contains(',') => rest.split(',').next().unwrap_or(rest),
+      _ => group,
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_name() {
+      assert_eq!(group_name("cn=vpn-admins,ou=groups,dc=example,dc=com"), "vpn-admins");
+      assert_eq!(group_name("vpn-admins"), "vpn-admins");
+    }
+  }
+}
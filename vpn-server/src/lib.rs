@@ -1,6 +1,63 @@
+pub mod accounting;
+pub mod admin;
+pub mod alerts;
+pub mod audit;
+pub mod auth;
+pub mod authz;
+pub mod bandwidth;
+pub mod ca;
+pub mod cluster;
 pub mod config;
+pub mod demux;
+pub mod dns;
+pub mod filter;
+pub mod forwards;
+pub mod grpc;
 pub mod handle_packet;
+pub mod health;
+pub mod history;
+pub mod inbound;
+pub mod ldap;
+pub mod listeners;
+pub mod mdns;
+pub mod metrics;
+pub mod mirror;
+pub mod nat;
+pub mod network;
+pub mod offload;
+pub mod oidc;
+pub mod pacing;
+pub mod passwords;
+pub mod pipe;
+pub mod policy;
+pub mod pool;
+pub mod prereqs;
+pub mod push;
+pub mod quarantine;
+pub mod radius;
+pub mod redis;
+pub mod rekey;
+pub mod reload;
+pub mod rendezvous;
+pub mod replay;
+pub mod report;
+pub mod revocation;
+pub mod roaming;
+pub mod runtime;
+pub mod schedule;
 pub mod server;
+pub mod service;
+pub mod startup;
+pub mod subnets;
+pub mod table;
+pub mod timestamp;
+pub mod tokens;
+pub mod trace;
+pub mod userspace;
+pub mod wasm;
+pub mod webhook;
+pub mod workers;
+pub mod xdp;
 
 pub use config::ServerConfig;
 pub use server::Server;
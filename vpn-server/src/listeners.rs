@@ -0,0 +1,156 @@
+//! The listeners of the server: the UDP port, and the TCP one of `tcp-port` if set. Traffic is counted
+//! by listener with key exchanges apart from the packets of established sessions, and a listener can stop
+//! taking key exchanges for a while, e.g. while its port is flooded with them, with clients reconnecting
+//! through the other one. Established sessions keep going on both.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Serialize;
+use vpn_shared::socket::Socket;
+
+use crate::server::Server;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Listener {
+  Udp,
+  Tcp,
+}
+
+impl fmt::Display for Listener {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Listener::Udp => write!(f, "udp"),
+      Listener::Tcp => write!(f, "tcp"),
+    }
+  }
+}
+
+impl FromStr for Listener {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "udp" => Ok(Listener::Udp),
+      "tcp" => Ok(Listener::Tcp),
+      _ => anyhow::bail!("Unknown listener {}, expected udp or tcp", s),
+    }
+  }
+}
+
+/// Listeners refusing key exchanges, with when they take them again; `None` until told to.
+#[derive(Debug, Default)]
+pub struct HandshakeGates {
+  refused: Mutex<HashMap<Listener, Option<Instant>>>,
+}
+
+impl HandshakeGates {
+  pub fn refuse(&self, listener: Listener, duration: Option<Duration>, now: Instant) {
+    self.refused.lock().unwrap().insert(listener, duration.map(|duration| now + duration));
+  }
+
+  /// Takes key exchanges on `listener` again; returns whether it refused them.
+  pub fn accept(&self, listener: Listener) -> bool {
+    self.refused.lock().unwrap().remove(&listener).is_some()
+  }
+
+  pub fn is_refused(&self, listener: Listener, now: Instant) -> bool {
+    self.refused_for(listener, now).is_some()
+  }
+
+  /// How much longer `listener` refuses key exchanges, `Some(None)` until told otherwise.
+  pub fn refused_for(&self, listener: Listener, now: Instant) -> Option<Option<Duration>> {
+    let mut refused = self.refused.lock().unwrap();
+    match refused.get(&listener).copied()? {
+      Some(until) if until <= now => {
+        refused.remove(&listener);
+        None
+      }
+      until => Some(until.map(|until| until - now)),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ListenerStatus {
+  pub listener: Listener,
+  pub address: String,
+  pub accepting_handshakes: bool,
+  /// Seconds until key exchanges are taken again, if refused for a while.
+  pub refused_for_secs: Option<u64>,
+  pub handshake_packets: u64,
+  pub handshake_bytes: u64,
+  pub data_packets: u64,
+  pub data_bytes: u64,
+  pub refused_handshakes: u64,
+}
+
+impl Server {
+  /// Listener that datagrams of `peer` come in on.
+  pub fn listener_of(&self, peer: SocketAddr) -> Listener {
+    match &*self.socket {
+      Socket::UdpAndTcp(_, transport) if transport.has_peer(peer) => Listener::Tcp,
+      Socket::Tcp(_) => Listener::Tcp,
+      _ => Listener::Udp,
+    }
+  }
+
+  pub fn listener_statuses(&self) -> Vec<ListenerStatus> {
+    let now = Instant::now();
+    let udp = self.socket.local_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    let mut listeners = vec![(Listener::Udp, udp)];
+    if let Socket::UdpAndTcp(_, transport) = &*self.socket {
+      listeners.push((Listener::Tcp, transport.local_addr().to_string()));
+    }
+    listeners
+      .into_iter()
+      .map(|(listener, address)| {
+        let refused = self.handshake_gates.refused_for(listener, now);
+        let metrics = self.metrics.listener(listener);
+        ListenerStatus {
+          listener,
+          address,
+          accepting_handshakes: refused.is_none(),
+          refused_for_secs: refused.flatten().map(|remaining| remaining.as_secs()),
+          handshake_packets: metrics.handshake_packets.get(),
+          handshake_bytes: metrics.handshake_bytes.get(),
+          data_packets: metrics.data_packets.get(),
+          data_bytes: metrics.data_bytes.get(),
+          refused_handshakes: metrics.refused_handshakes.get(),
+        }
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_handshake_gates() {
+    let gates = HandshakeGates::default();
+    let now = Instant::now();
+    assert!(!gates.is_refused(Listener::Udp, now));
+
+    gates.refuse(Listener::Udp, Some(Duration::from_secs(60)), now);
+    gates.refuse(Listener::Tcp, None, now);
+    assert_eq!(
+      gates.refused_for(Listener::Udp, now + Duration::from_secs(20)),
+      Some(Some(Duration::from_secs(40)))
+    );
+    assert_eq!(gates.refused_for(Listener::Tcp, now + Duration::from_secs(600)), Some(None));
+
+    // Refusals for a while lapse on their own.
+    assert!(!gates.is_refused(Listener::Udp, now + Duration::from_secs(60)));
+    assert!(!gates.accept(Listener::Udp));
+    assert!(gates.accept(Listener::Tcp));
+    assert!(!gates.is_refused(Listener::Tcp, now));
+  }
+}
@@ -1,43 +1,895 @@
+mod accounting;
+mod admin;
+mod alerts;
+mod audit;
+mod auth;
+mod authz;
+mod bandwidth;
+mod ca;
+mod cluster;
 mod config;
+mod demux;
+mod dns;
+mod filter;
+mod forwards;
+mod grpc;
 mod handle_packet;
+mod health;
+mod history;
+mod inbound;
+mod ldap;
+mod listeners;
+mod mdns;
+mod metrics;
+mod mirror;
+mod nat;
+mod network;
+mod offload;
+mod oidc;
+mod pacing;
+mod passwords;
+mod pipe;
+mod policy;
+mod pool;
+mod prereqs;
+mod push;
+mod quarantine;
+mod radius;
+mod redis;
+mod rekey;
+mod reload;
+mod rendezvous;
+mod replay;
+mod report;
+mod revocation;
+mod roaming;
+mod runtime;
+mod schedule;
 mod server;
+mod service;
+mod startup;
+mod subnets;
+mod table;
+mod timestamp;
+mod tokens;
+mod trace;
+mod userspace;
+mod wasm;
+mod webhook;
+mod workers;
+mod xdp;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::*;
+use ipnet::Ipv4Net;
+use serde_json::json;
 use tracing::error;
+use tracing::info;
+use vpn_shared::cert::Certificate;
+use vpn_shared::creds::Credentials;
+use vpn_shared::handshake;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::logging;
+use vpn_shared::output;
+use vpn_shared::output::Format;
+use vpn_shared::packet::fill_random_bytes;
+use vpn_shared::reference;
+use vpn_shared::selftest;
+use vpn_shared::transform::Registry;
 
 #[derive(Debug, Parser)]
 #[command(version)]
 struct Args {
   /// Path to the configuration file; --config config.yaml
-  #[arg(short, long)]
-  config: String,
+  #[arg(short, long, required_unless_present_any = ["generate_key", "protocol_reference", "simple"])]
+  config: Option<String>,
+
+  /// Fill in a working road-warrior setup: tun with NAT, leased addresses, pushed DNS and the health
+  /// endpoint; without --config, users authenticate as $VPN_USERNAME with $VPN_PASSWORD, a printed random
+  /// one if unset
+  #[arg(long)]
+  simple: bool,
+
+  /// Print a new private key for the configuration and its public key for clients, then exit
+  #[arg(long, exclusive = true)]
+  generate_key: bool,
+
+  /// Print the reference of the wire protocol in Markdown, with sample encodings of every packet, then exit
+  #[arg(long, exclusive = true)]
+  protocol_reference: bool,
+
+  /// Add a client public key, or the key of a device configured as `USER/DEVICE`, to the configured
+  /// revocation list and exit; connected clients using it are disconnected by the running server
+  #[arg(long, value_name = "PUBLIC_KEY")]
+  revoke: Option<String>,
+
+  /// Load the configuration, report conflicting settings and exit, with status 1 if there are any
+  #[arg(long)]
+  check: bool,
+
+  /// Check the crypto primitives, permissions to create a tun device, the configuration and its sockets,
+  /// print a JSON report and exit, with status 1 if anything failed
+  #[arg(long)]
+  selftest: bool,
+
+  /// Revoke a key even if it's the last way its user can connect, or kick a user even from the session the
+  /// request goes through
+  #[arg(long, global = true)]
+  force: bool,
+
+  /// One of `admin-tokens`, for the commands that go through `health-address` when it isn't on this host
+  #[arg(long, global = true)]
+  admin_token: Option<String>,
+
+  /// How --check, `report` and the commands querying the running server print: `text` tables, or `json` for scripts
+  #[arg(long, global = true, default_value = "text", value_parser = Format::parse)]
+  output: Format,
+
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+  /// Manage the CA client certificates are issued by
+  #[command(subcommand)]
+  Ca(CaCommand),
+
+  /// Print the log level of the running server, or change it; goes through `health-address`
+  LogLevel {
+    /// `error`, `warn`, `info`, `debug` or `trace`
+    level: Option<String>,
+  },
+
+  /// Write the events of the flight recorder of the running server to disk and print where; goes through
+  /// `health-address`
+  DumpFlightRecorder,
+
+  /// Print the recent sessions of a user of the running server, or the latest session of every user; goes
+  /// through `health-address`
+  Sessions { user: Option<String> },
+
+  /// Print the connected users of the running server; goes through `health-address`
+  Clients,
+
+  /// Disconnect every session of a user of the running server, or of one device as `USER/DEVICE`; goes
+  /// through `health-address`
+  Kick { user: String },
+
+  /// Print the trace of a session of the running server, or start tracing every packet of it; goes through
+  /// `health-address`
+  Trace {
+    /// Id of the session, as printed by `clients`
+    session: String,
+
+    /// Start a trace for this many seconds, up to 600, instead of printing it
+    #[arg(long)]
+    start: Option<u64>,
+  },
+
+  /// Replace the key of a session of the running server without disconnecting it; goes through
+  /// `health-address`
+  Rekey {
+    /// Id of the session, as printed by `clients`
+    session: String,
+  },
+
+  /// Print the bandwidth graphs of the sessions of the running server, or of one session; goes through
+  /// `health-address`
+  Bandwidth {
+    /// Id of the session, as printed by `clients`
+    session: Option<String>,
+  },
+
+  /// Compare the configuration file of the running server with the one it loaded, listing the changed
+  /// settings and the sessions a reload would disconnect; applies nothing, as `reload --dry-run`. Goes through
+  /// `health-address`
+  ConfigDiff,
+
+  /// Apply the client credentials and keys of the configuration file to the running server, disconnecting
+  /// the sessions whose credentials were removed; other changes are listed as pending until a restart. Goes
+  /// through `health-address`
+  Reload {
+    /// Only list what the reload would change
+    #[arg(long)]
+    dry_run: bool,
+  },
+
+  /// Print a page of the session table of the running server: user, addresses, traffic, last key exchange
+  /// and negotiated features of every session; goes through `health-address`
+  SessionTable {
+    /// Sessions to skip, `next_offset` of the previous page
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+
+    /// Sessions on the page, 500 by default
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Print the page in the OpenMetrics text format
+    #[arg(long)]
+    openmetrics: bool,
+  },
+
+  /// Print the traffic of the listeners of the running server, or refuse or accept handshakes on one; goes
+  /// through `health-address`
+  Listeners {
+    #[command(subcommand)]
+    command: Option<ListenersCommand>,
+  },
+
+  /// Summarize the sessions of the last days from the audit log, if it's a file or a database, or else the
+  /// session history into a capacity report: peak concurrent clients, hourly throughput and authentication
+  /// failures
+  Report {
+    #[arg(long, default_value_t = 30)]
+    days: u64,
+  },
+
+  /// Set the password of a user in `password-file`, adding them if needed; read from the terminal
+  SetPassword { user: String },
+
+  /// Print a token the user can connect with instead of a password; signed with the configured private key
+  IssueToken {
+    #[arg(long)]
+    user: String,
+
+    /// Lifetime of the token, e.g. 30m, 24h or 7d
+    #[arg(long, default_value = "24h", value_parser = tokens::parse_ttl)]
+    ttl: Duration,
+  },
+}
+
+#[derive(Debug, Subcommand)]
+enum CaCommand {
+  /// Create the key configured in `ca.key-file`
+  Init,
+
+  /// Print a certificate binding a user to a client public key; issue a new one the same way to renew it
+  Issue {
+    #[arg(long)]
+    user: String,
+
+    /// Client public key, as printed by --generate-key
+    #[arg(long)]
+    public_key: String,
+
+    /// Defaults to `ca.certificate-ttl-days`
+    #[arg(long, value_parser = tokens::parse_ttl)]
+    ttl: Option<Duration>,
+  },
+
+  /// Add the key of a certificate to the revocation list; connected clients using it are disconnected
+  Revoke { certificate: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+enum ListenersCommand {
+  /// Drop key exchanges coming in on a listener, `udp` or `tcp`, keeping established sessions; --force
+  /// refuses them on the last listener taking them
+  Refuse {
+    listener: String,
+
+    /// Refuse them for this many seconds instead of until accepted again
+    #[arg(long)]
+    secs: Option<u64>,
+  },
+
+  /// Accept key exchanges on a listener again
+  Accept { listener: String },
+}
+
+fn real_main(args: Args) -> anyhow::Result<()> {
+  if args.generate_key {
+    let key = KeyPair::generate();
+    println!(
+      "private-key: {}\npublic key: {}",
+      handshake::encode_key(&key.secret()),
+      handshake::encode_key(&key.public())
+    );
+    return Ok(());
+  }
+
+  if args.protocol_reference {
+    print!("{}", reference::markdown());
+    return Ok(());
+  }
+
+  if args.selftest {
+    let report = selftest(args.config, args.simple)?;
+    println!("{}", report.to_json());
+    if !report.passed {
+      std::process::exit(1);
+    }
+    return Ok(());
+  }
+
+  let mut config = match args.config {
+    Some(ref path) => config::ServerConfig::from_file(path)?,
+    None if args.simple => config::ServerConfig::simple(),
+    None => anyhow::bail!("--config is required"),
+  };
+  if args.simple {
+    config.apply_simple();
+  }
+  logging::init(&config.log, "vpn-server")?;
+  if args.simple && !config.has_authentication() {
+    config.client_credentials.push(simple_credentials());
+  }
+  if args.check {
+    let problems = config.problems();
+    match args.output {
+      Format::Json => {
+        print!("{}", args.output.render(&json!({ "valid": problems.is_empty(), "problems": problems })))
+      }
+      Format::Text if problems.is_empty() => println!("Configuration is valid"),
+      Format::Text => problems.iter().for_each(|problem| println!("{}", problem)),
+    }
+    if !problems.is_empty() {
+      std::process::exit(1);
+    }
+    return Ok(());
+  }
+  config.check()?;
+
+  if let Some(ref key) = args.revoke {
+    let Some(ref list) = config.revocation_list else {
+      anyhow::bail!("No revocation-list configured");
+    };
+    let key = match config.device_key(key) {
+      Some(device) => device.public_key,
+      None => handshake::parse_key(key)?,
+    };
+    let revoked = revocation::RevocationList::load(list.clone())?;
+    let locked_out = config.locked_out_by(&key, |key| revoked.is_revoked(key));
+    if !locked_out.is_empty() && !args.force {
+      anyhow::bail!(
+        "Revoking the key would leave {} without a way to connect; use --force to revoke it anyway",
+        locked_out.join(", ")
+      );
+    }
+    revocation::append(list, &key)?;
+    println!("Revoked {}", handshake::encode_key(&key));
+    return Ok(());
+  }
+
+  let token = args.admin_token.as_deref();
+  // Responses of the admin API are JSON already, which is kept as is for `--output json`.
+  let print = |response: String| -> anyhow::Result<()> {
+    print!("{}", args.output.render(&serde_json::from_str(&response)?));
+    Ok(())
+  };
+  match args.command {
+    Some(Command::Ca(command)) => return run_ca(command, &config),
+    Some(Command::LogLevel { level }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Changing the log level requires a health-address");
+      };
+      let response = match level {
+        Some(level) => health::admin_request(address, token, "PUT", "/log-level", &level)?,
+        None => health::admin_request(address, token, "GET", "/log-level", "")?,
+      };
+      match args.output {
+        Format::Json => print!("{}", args.output.render(&json!({ "level": response.trim() }))),
+        Format::Text => println!("{}", response),
+      }
+      return Ok(());
+    }
+    Some(Command::DumpFlightRecorder) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Dumping the flight recorder requires a health-address");
+      };
+      println!("{}", health::admin_request(address, token, "POST", "/flight-recorder", "")?);
+      return Ok(());
+    }
+    Some(Command::Sessions { user }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Querying sessions requires a health-address");
+      };
+      let path = format!("/sessions/{}", user.as_deref().unwrap_or_default());
+      let response = health::admin_request(address, token, "GET", path.trim_end_matches('/'), "")?;
+      if user.is_some() || args.output == Format::Json {
+        return print(response);
+      }
+      // The latest session of every user, keyed by the user; a row each in the table.
+      let latest: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&response)?;
+      let rows = latest.into_iter().map(|(user, mut session)| {
+        session["username"] = user.into();
+        session
+      });
+      print!("{}", output::text(&rows.collect()));
+      return Ok(());
+    }
+    Some(Command::Clients) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Listing clients requires a health-address");
+      };
+      return print(health::admin_request(address, token, "GET", "/clients", "")?);
+    }
+    Some(Command::Kick { user }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Kicking users requires a health-address");
+      };
+      let path = format!("/clients/{}{}", user, if args.force { "?force" } else { "" });
+      let kicked = health::admin_request(address, token, "DELETE", &path, "")?;
+      println!("Disconnected {} session(s) of {}", kicked, user);
+      return Ok(());
+    }
+    Some(Command::Trace { session, start }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Tracing sessions requires a health-address");
+      };
+      let path = format!("/traces/{}", session);
+      match start {
+        Some(secs) => {
+          let secs = health::admin_request(address, token, "PUT", &path, &secs.to_string())?;
+          println!("Tracing session {} for {}s", session, secs);
+        }
+        None => print(health::admin_request(address, token, "GET", &path, "")?)?,
+      }
+      return Ok(());
+    }
+    Some(Command::Rekey { session }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Rekeying sessions requires a health-address");
+      };
+      health::admin_request(address, token, "POST", &format!("/rekey/{}", session), "")?;
+      println!("Asked session {} to replace its key", session);
+      return Ok(());
+    }
+    Some(Command::Bandwidth { session }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Querying bandwidth graphs requires a health-address");
+      };
+      let path = format!("/bandwidth/{}", session.unwrap_or_default());
+      return print(health::admin_request(address, token, "GET", path.trim_end_matches('/'), "")?);
+    }
+    Some(Command::ConfigDiff) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Previewing configuration changes requires a health-address");
+      };
+      return print(health::admin_request(address, token, "GET", "/config-diff", "")?);
+    }
+    Some(Command::Reload { dry_run }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Reloading the configuration requires a health-address");
+      };
+      let path = if dry_run { "/reload?dry-run" } else { "/reload" };
+      return print(health::admin_request(address, token, "POST", path, "")?);
+    }
+    Some(Command::SessionTable { offset, limit, openmetrics }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Querying the session table requires a health-address");
+      };
+      let mut path = format!("/session-table?offset={}", offset);
+      if let Some(limit) = limit {
+        path += &format!("&limit={}", limit);
+      }
+      if openmetrics {
+        path += "&format=openmetrics";
+        println!("{}", health::admin_request(address, token, "GET", &path, "")?);
+        return Ok(());
+      }
+      return print(health::admin_request(address, token, "GET", &path, "")?);
+    }
+    Some(Command::Listeners { command }) => {
+      let Some(address) = config.health_address else {
+        anyhow::bail!("Managing listeners requires a health-address");
+      };
+      match command {
+        Some(ListenersCommand::Refuse { listener, secs }) => {
+          let path = format!("/listeners/{}{}", listener, if args.force { "?force" } else { "" });
+          let body = secs.map(|secs| secs.to_string()).unwrap_or_default();
+          health::admin_request(address, token, "PUT", &path, &body)?;
+          println!("Refusing handshakes on {}", listener);
+        }
+        Some(ListenersCommand::Accept { listener }) => {
+          health::admin_request(address, token, "DELETE", &format!("/listeners/{}", listener), "")?;
+          println!("Accepting handshakes on {}", listener);
+        }
+        None => print(health::admin_request(address, token, "GET", "/listeners", "")?)?,
+      }
+      return Ok(());
+    }
+    Some(Command::Report { days }) => {
+      let until = handshake::unix_time();
+      let from = until.saturating_sub(days * report::DAY_SECS);
+      let records = report::Records::load(&config, from)?;
+      let report = report::report(&records, from, until);
+      match args.output {
+        Format::Json => print!("{}", args.output.render(&serde_json::to_value(&report)?)),
+        Format::Text => print!("{}", report.text()),
+      }
+      return Ok(());
+    }
+    Some(Command::SetPassword { user }) => {
+      let Some(path) = config.password_file else {
+        anyhow::bail!("No password-file configured");
+      };
+      let password = rpassword::prompt_password(format!("New password of {}: ", user))?;
+      if rpassword::prompt_password("Repeat it: ")? != password {
+        anyhow::bail!("Passwords don't match");
+      }
+      let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+      runtime.block_on(passwords::PasswordFile::new(path).set(&user, &password))?;
+      println!("Set the password of {}", user);
+      return Ok(());
+    }
+    Some(Command::IssueToken { user, ttl }) => {
+      let Some(ref key) = config.private_key else {
+        anyhow::bail!("Issuing tokens requires a private-key");
+      };
+      println!("{}", tokens::TokenIssuer::new(&handshake::parse_key(key)?).issue(&user, ttl)?);
+      return Ok(());
+    }
+    None => (),
+  }
+
+  let runtime = config.runtime.build()?;
+  let config_file =
+    args.config.as_deref().map(|path| reload::LoadedConfig::read(path.as_ref())).transpose()?;
+  runtime.block_on(serve(config, config_file))
+}
+
+fn run_ca(command: CaCommand, config: &config::ServerConfig) -> anyhow::Result<()> {
+  let Some(ref ca_config) = config.ca else {
+    anyhow::bail!("No ca section configured");
+  };
+
+  match command {
+    CaCommand::Init => {
+      let key = ca::init(ca_config.key_file()?)?;
+      println!("Created CA key {}", ca_config.key_file()?.display());
+      println!("CA public key (ca.public-key): {}", handshake::encode_key(key.verifying_key().as_bytes()));
+    }
+    CaCommand::Issue { user, public_key, ttl } => {
+      let key = ca::load(ca_config.key_file()?)?;
+      let ttl = match ttl {
+        Some(ttl) => ttl,
+        None => ca_config.certificate_ttl()?,
+      };
+      println!("{}", Certificate::issue(&key, &user, handshake::parse_key(&public_key)?, ttl)?.encode());
+    }
+    CaCommand::Revoke { certificate } => {
+      let Some(ref list) = config.revocation_list else {
+        anyhow::bail!("No revocation-list configured");
+      };
+      let certificate = Certificate::decode(&std::fs::read_to_string(certificate)?)?;
+      revocation::append(list, &certificate.public_key)?;
+      println!("Revoked certificate {:016x} of {}", certificate.serial, certificate.username);
+    }
+  }
+
+  Ok(())
 }
 
-#[tokio::main]
-async fn real_main(args: Args) -> anyhow::Result<()> {
-  let config = config::ServerConfig::from_file(&args.config)?;
+async fn serve(
+  config: config::ServerConfig,
+  config_file: Option<reload::LoadedConfig>,
+) -> anyhow::Result<()> {
+  #[cfg(unix)]
+  tokio::spawn(logging::cycle_on_sigusr1());
+
+  if let Some(ref gateway) = config.gateway {
+    prereqs::ensure(gateway)?;
+  }
 
-  let server = server::Server::builder(config.listen_address, config.listen_port)
+  let admin_service = match (&config.admin_service, config.admin_service_address()) {
+    (Some(service), Some(address)) => Some((address, service.port)),
+    (Some(_), None) => {
+      anyhow::bail!("The admin service requires an address, an address-pool or a tun section")
+    }
+    (None, _) => None,
+  };
+  let internal_dns = match (&config.internal_dns, config.internal_dns_address()) {
+    (Some(dns), Some(address)) => {
+      Some(dns::InternalDns::bind(dns.clone(), address, config.internal_dns_upstream())?)
+    }
+    (Some(_), None) => {
+      anyhow::bail!("The internal DNS requires an address, an address-pool or a tun section")
+    }
+    (None, _) => None,
+  };
+
+  let required_features = config.required_client_features()?;
+  let mut builder = server::Server::builder(config.listen_address, config.listen_port)
     .with_client_timeout(config.client_timeout())
     .with_max_clients(config.max_clients)
     .with_client_credentials(config.client_credentials)
-    .build()
-    .await?;
+    .with_client_keys(config.client_keys)
+    .with_policies(policy::Policies::new(config.groups))
+    .with_auth_policy(config.auth_policy)
+    .with_quarantine(config.quarantine)
+    .with_rekeying(config.rekey)
+    .with_schedule(config.schedule)
+    .with_workers(config.workers)
+    .with_pacing(config.pacing)
+    .with_offload(config.crypto_offload)
+    .with_ecn(config.ecn)
+    .with_outer(config.outer)
+    .with_icmp_unreachable(config.icmp_unreachable)
+    .with_peer_encryption(config.peer_encryption)
+    .with_required_features(required_features)
+    .with_mss_clamp(config.mss_clamp)
+    .with_route_exchange(config.route_exchange)
+    .with_transforms(config.transforms.clone())
+    .with_history(history::SessionHistory::new(config.history)?);
+
+  if let Some(path) = config.password_file {
+    builder = builder.with_password_file(path);
+  }
+
+  if let Some(ldap) = config.ldap {
+    #[cfg(feature = "ldap")]
+    {
+      builder = builder.with_credential_store(Box::new(ldap::LdapStore::new(ldap)));
+    }
+    #[cfg(not(feature = "ldap"))]
+    anyhow::bail!(
+      "LDAP server {} is configured, but the server was built without the ldap feature",
+      ldap.url
+    );
+  }
+
+  if let Some(oidc) = config.oidc {
+    #[cfg(feature = "oidc")]
+    {
+      builder = builder.with_credential_store(Box::new(oidc::OidcStore::new(oidc)));
+    }
+    #[cfg(not(feature = "oidc"))]
+    anyhow::bail!(
+      "OIDC issuer {} is configured, but the server was built without the oidc feature",
+      oidc.issuer
+    );
+  }
+
+  #[cfg(feature = "wasm")]
+  for filter in &config.wasm_filters {
+    builder = builder.with_packet_filter(Arc::new(wasm::WasmFilter::load(filter)?));
+  }
+  #[cfg(not(feature = "wasm"))]
+  if let Some(filter) = config.wasm_filters.first() {
+    anyhow::bail!(
+      "WASM filter {} is configured, but the server was built without the wasm feature",
+      filter.path.display()
+    );
+  }
+  #[cfg(not(feature = "sqlite"))]
+  if let Some(audit::AuditConfig::Sqlite { ref path }) = config.audit {
+    anyhow::bail!(
+      "The audit database {} is configured, but the server was built without the sqlite feature",
+      path.display()
+    );
+  }
+  #[cfg(not(all(feature = "xdp", target_os = "linux")))]
+  if let Some(ref xdp) = config.xdp {
+    anyhow::bail!(
+      "XDP on {} is configured, but the server was built without the xdp feature or not for Linux",
+      xdp.interface
+    );
+  }
+
+  if let Some(skew) = config.handshake_skew_secs {
+    builder = builder.with_handshake_skew(Duration::from_secs(skew));
+  }
+  let token_skew = config.token_skew_secs.map_or(tokens::DEFAULT_SKEW, Duration::from_secs);
+  builder = builder.with_token_skew(token_skew);
+  if let Some(timeout) = config.startup_timeout_secs {
+    builder = builder.with_startup_timeout(Duration::from_secs(timeout));
+  }
+
+  if let Some(interval) = config.stats_interval_secs {
+    builder = builder.with_stats_interval(Duration::from_secs(interval));
+  }
+
+  if let Some(radius) = config.radius {
+    let interim = radius.interim_interval_secs.map(Duration::from_secs);
+    let accounting = radius.accounting;
+    let client = radius::RadiusClient::new(radius);
+
+    builder = builder.with_credential_store(Box::new(client.clone()));
+    if accounting {
+      builder = builder.with_accounting(Arc::new(client));
+      if let Some(interval) = interim {
+        builder = builder.with_accounting_interval(interval);
+      }
+    }
+  }
+
+  if let Some(audit) = config.audit {
+    builder = builder.with_audit_log(Arc::new(audit::AuditLog::spawn(audit)));
+  }
+
+  if let Some(mirror) = config.mirror {
+    builder = builder.with_mirror(mirror);
+  }
+  if let Some(bandwidth) = config.bandwidth {
+    builder = builder.with_bandwidth_graphs(bandwidth);
+  }
+  if let Some(metrics_push) = config.metrics_push {
+    builder = builder.with_metrics_push(metrics_push);
+  }
+
+  if let Some(alerts) = config.alerts {
+    builder = builder.with_alerts(alerts::Alerts::new(alerts)?);
+  }
+
+  if let Some(webhook) = config.webhook {
+    builder = builder.with_webhook(webhook::Webhook::spawn(webhook)?);
+  }
+
+  if let Some(ref ca) = config.ca {
+    builder = builder.with_certificate_authority(ca.verifying_key()?);
+  }
+
+  if let Some(path) = config.revocation_list {
+    builder = builder.with_revocation_list(revocation::RevocationList::load(path)?);
+  }
+
+  if let Some(ref key) = config.private_key {
+    let key = handshake::parse_key(key)?;
+    info!("Server public key: {}", handshake::encode_key(&KeyPair::from_secret(key).public()));
+    let issuer = tokens::TokenIssuer::new(&key).with_skew(token_skew);
+    builder = builder.with_private_key(key).with_credential_store(Box::new(issuer));
+  }
+
+  if let Some(lifetime) = config.session_ticket_lifetime_secs {
+    builder = builder.with_session_tickets(Duration::from_secs(lifetime));
+  }
+
+  if let Some(preemption) = config.preemption {
+    builder = builder.with_preemption(preemption.min_idle());
+  }
+
+  if let Some(pool_exhaustion) = config.pool_exhaustion {
+    builder = builder.with_pool_exhaustion(pool_exhaustion);
+  }
+
+  if let Some(port) = config.tcp_port {
+    builder = builder.with_tcp_port(port);
+  }
+
+  if let Some(mdns) = config.mdns {
+    let public_key = config.private_key.as_deref().map(handshake::parse_key).transpose()?;
+    let public_key = public_key.map(|key| KeyPair::from_secret(key).public());
+    builder = builder.with_mdns(mdns::Responder::bind(mdns, config.listen_port, public_key)?);
+  }
+
+  if let Some(rendezvous) = config.rendezvous {
+    builder = builder.with_rendezvous(rendezvous);
+  }
+  builder = builder.with_rendezvous_service(config.rendezvous_service);
+
+  if let Some(address) = config.health_address {
+    builder = builder.with_health_address(address);
+  }
+  if let Some(address) = config.grpc_address {
+    builder = builder.with_grpc_address(address);
+  }
+  if let Some((address, port)) = admin_service {
+    builder = builder.with_admin_service(address, port);
+  }
+  if let Some(internal_dns) = internal_dns {
+    builder = builder.with_internal_dns(internal_dns);
+  }
+  builder = builder.with_admin_tokens(config.admin_tokens);
+  if let Some(config_file) = config_file {
+    builder = builder.with_config_file(config_file);
+  }
+
+  if let Some(ref tun) = config.tun {
+    let overhead = Registry::default().overhead(&config.transforms)?;
+    builder = builder.with_tun_config(tun.to_tun_config(overhead)?).with_tun_gso(tun.gso);
+  }
+
+  if let Some(pool) = config.address_pool {
+    let reserved = config.tun.as_ref().map(|tun| tun.address);
+    builder = builder.with_address_pool(pool::AddressPool::new(pool, reserved));
+  }
+
+  if let Some(userspace_nat) = config.userspace_nat {
+    builder = builder.with_userspace_nat(userspace_nat);
+  }
+
+  if let Some(gateway) = config.gateway {
+    let Some(ref tun) = config.tun else {
+      anyhow::bail!("Gateway mode requires a tun section");
+    };
+
+    let subnet = Ipv4Net::with_netmask(tun.address, tun.netmask)?.trunc();
+    let nat = nat::Nat::new(subnet, gateway.egress)
+      .with_subnets(config.networks.values().map(|network| network.subnet))
+      .with_port_forwards(config.port_forwards);
+    builder = builder.with_nat(nat);
+  }
+
+  if let Some(cluster) = config.cluster {
+    builder = builder.with_cluster(cluster::Cluster::bind(cluster).await?);
+  }
+
+  builder = builder.with_networks(network::Networks::new(config.networks));
+
+  let server = builder.build().await?;
+
+  // Detached when dropped, so it stays attached for as long as the server runs.
+  #[cfg(all(feature = "xdp", target_os = "linux"))]
+  let _xdp = config.xdp.as_ref().map(|xdp| xdp::attach(xdp, config.listen_port)).transpose()?;
 
   server.run().await?;
 
   Ok(())
 }
 
+/// Report of `--selftest`: the configuration is loaded and checked as on start, but nothing is started.
+fn selftest(path: Option<String>, simple: bool) -> anyhow::Result<selftest::Report> {
+  let config = match path {
+    Some(path) => config::ServerConfig::from_file(path),
+    None => Ok(config::ServerConfig::simple()),
+  };
+  let config = config.and_then(|mut config| {
+    if simple {
+      config.apply_simple();
+    }
+    // Stands in for the user `--simple` takes from the environment, which needs no checking.
+    if simple && !config.has_authentication() {
+      config
+        .client_credentials
+        .push(Credentials::Password { username: "vpn".into(), password: String::new() });
+    }
+    config.check()?;
+    Ok(config)
+  });
+
+  let mut checks = vec![
+    selftest::crypto(),
+    selftest::Check::new(
+      "config",
+      config.as_ref().map(|_| "Configuration is valid".to_string()).map_err(|e| anyhow::anyhow!("{}", e)),
+    ),
+  ];
+  checks.push(match config {
+    Ok(ref config) if config.tun.is_none() => selftest::Check::skip("tun", "No tun section configured"),
+    _ => selftest::tun(),
+  });
+
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+  match config {
+    Ok(ref config) => runtime.block_on(async {
+      let address = config.listen_address.into();
+      checks.push(selftest::udp_bind("socket", SocketAddr::new(address, config.listen_port)).await);
+      if let Some(port) = config.tcp_port {
+        checks.push(selftest::tcp_bind("tcp-socket", SocketAddr::new(address, port)).await);
+      }
+    }),
+    Err(_) => checks.push(selftest::Check::skip("socket", "Configuration is invalid")),
+  }
+  Ok(selftest::Report::new("vpn-server", env!("CARGO_PKG_VERSION"), checks))
+}
+
+/// The only user of `--simple` without a configuration, from the environment.
+fn simple_credentials() -> Credentials {
+  let username = std::env::var("VPN_USERNAME").unwrap_or_else(|_| "vpn".into());
+  let password = std::env::var("VPN_PASSWORD").unwrap_or_else(|_| {
+    let mut bytes = [0u8; 12];
+    fill_random_bytes(&mut bytes);
+    let password = handshake::encode_hex(&bytes);
+    println!("Generated password for {}: {}", username, password);
+    password
+  });
+  Credentials::Password { username, password }
+}
+
 fn main() {
-  setup_logging();
   let args = Args::parse();
 
   if let Err(e) = real_main(args) {
+    // Errors before the configured logging is up still need to be seen.
+    _ = tracing_subscriber::fmt().try_init();
     error!("{}", e);
+    std::process::exit(1);
   }
 }
-
-fn setup_logging() {
-  tracing_subscriber::fmt().init();
-}
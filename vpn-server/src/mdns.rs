@@ -0,0 +1,227 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+use tokio::net::UdpSocket;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::dns;
+use vpn_shared::dns::Message;
+use vpn_shared::dns::Record;
+use vpn_shared::dns::RecordData;
+use vpn_shared::handshake;
+use vpn_shared::packet::Key;
+
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+
+/// Service type browsed by `vpn-client discover`.
+pub const SERVICE: &str = "_sberlinux-vpn._udp.local";
+
+/// Records are meant to be re-queried while browsing, not cached for long.
+const TTL: u32 = 120;
+
+/// Answers `vpn-client discover` and other mDNS browsers on the local network, for setups without DNS.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MdnsConfig {
+  /// Instance name browsers show; defaults to the host name.
+  #[serde(default)]
+  pub name: Option<String>,
+
+  /// Address of the interface to answer on; defaults to the one multicast is routed through.
+  #[serde(default)]
+  pub interface: Option<Ipv4Addr>,
+}
+
+pub struct Responder {
+  socket: UdpSocket,
+  /// Full name of the instance, `<name>._sberlinux-vpn._udp.local`.
+  instance: String,
+  host: String,
+  interface: Option<Ipv4Addr>,
+  port: u16,
+  public_key: Option<Key>,
+}
+
+impl Responder {
+  /// Binds the mDNS port alongside other responders on the host, e.g. avahi.
+  pub fn bind(config: MdnsConfig, port: u16, public_key: Option<Key>) -> anyhow::Result<Self> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    let interface = config.interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+    socket.join_multicast_v4(&MDNS_GROUP, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+
+    let host = host_name();
+    // Labels can't hold dots, and the service name is what browsers group instances by.
+    let name = config.name.unwrap_or_else(|| host.clone()).replace('.', "-");
+    info!("Advertising {} on the local network through mDNS", name);
+
+    Ok(Self {
+      socket: UdpSocket::from_std(socket.into())?,
+      instance: format!("{}.{}", name, SERVICE),
+      host: format!("{}.local", host.replace('.', "-")),
+      interface: config.interface,
+      port,
+      public_key,
+    })
+  }
+
+  pub async fn serve(&self) {
+    let mut buf = vec![0; 9000];
+    loop {
+      let (len, src) = match self.socket.recv_from(&mut buf).await {
+        Ok(received) => received,
+        Err(e) => {
+          warn!("Failed to receive an mDNS query: {}", e);
+          continue;
+        }
+      };
+      let Ok(query) = Message::parse(&buf[..len]) else {
+        continue;
+      };
+
+      let Some(address) = self.interface.or_else(|| source_for(src)) else {
+        continue;
+      };
+      let Some((answers, additional)) = self.answer(&query, address) else {
+        continue;
+      };
+
+      // Queries from other ports are one-shot ones, which are answered directly and with their id.
+      let (id, destination) = match src.port() {
+        MDNS_PORT => (0, SocketAddr::from((MDNS_GROUP, MDNS_PORT))),
+        _ => (query.id, src),
+      };
+      let sent = match dns::response(id, &answers, &additional) {
+        Ok(response) => self.socket.send_to(&response, destination).await.map(drop).map_err(Into::into),
+        Err(e) => Err(e),
+      };
+      if let Err(e) = sent {
+        warn!("Failed to answer an mDNS query from {}: {}", src, e);
+      }
+    }
+  }
+
+  /// Records answering the questions of `query` about this server, reachable at `address`.
+  fn answer(&self, query: &Message, address: Ipv4Addr) -> Option<(Vec<Record>, Vec<Record>)> {
+    if query.response {
+      return None;
+    }
+
+    let record = |name: &str, data| Record { name: name.to_string(), ttl: TTL, data };
+    let srv = record(
+      &self.instance,
+      RecordData::Srv { priority: 0, weight: 0, port: self.port, target: self.host.clone() },
+    );
+    let txt = record(&self.instance, RecordData::Txt(self.txt()));
+    let a = record(&self.host, RecordData::A(address));
+
+    let mut answers = Vec::new();
+    let mut additional = Vec::new();
+    for question in &query.questions {
+      let wants = |record_type| question.record_type == record_type || question.record_type == dns::TYPE_ANY;
+      if question.name.eq_ignore_ascii_case(SERVICE) && wants(dns::TYPE_PTR) {
+        answers.push(record(SERVICE, RecordData::Ptr(self.instance.clone())));
+        additional.extend([srv.clone(), txt.clone(), a.clone()]);
+      } else if question.name.eq_ignore_ascii_case(&self.instance) {
+        if wants(dns::TYPE_SRV) {
+          answers.push(srv.clone());
+          additional.push(a.clone());
+        }
+        if wants(dns::TYPE_TXT) {
+          answers.push(txt.clone());
+        }
+      } else if question.name.eq_ignore_ascii_case(&self.host) && wants(dns::TYPE_A) {
+        answers.push(a.clone());
+      }
+    }
+
+    additional.retain(|record| !answers.contains(record));
+    additional.dedup();
+    (!answers.is_empty()).then_some((answers, additional))
+  }
+
+  fn txt(&self) -> Vec<String> {
+    match self.public_key {
+      Some(ref key) => vec![format!("key={}", handshake::encode_key(key))],
+      // An empty TXT record is a single empty string.
+      None => vec![String::new()],
+    }
+  }
+}
+
+/// Local address the kernel would send from to reach `peer`; connecting a UDP socket sends nothing.
+fn source_for(peer: SocketAddr) -> Option<Ipv4Addr> {
+  let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+  socket.connect(peer).ok()?;
+  match socket.local_addr().ok()? {
+    SocketAddr::V4(local) if !local.ip().is_unspecified() => Some(*local.ip()),
+    _ => None,
+  }
+}
+
+fn host_name() -> String {
+  std::fs::read_to_string("/proc/sys/kernel/hostname")
+    .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+    .map(|name| name.trim().to_string())
+    .ok()
+    .filter(|name| !name.is_empty())
+    .unwrap_or_else(|| "sberlinux-vpn".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use vpn_shared::dns::Question;
+
+  use super::*;
+
+  async fn responder(public_key: Option<Key>) -> Responder {
+    Responder {
+      socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+      instance: format!("office.{}", SERVICE),
+      host: "gw.local".to_string(),
+      interface: None,
+      port: 9696,
+      public_key,
+    }
+  }
+
+  fn query(name: &str, record_type: u16) -> Message {
+    Message { questions: vec![Question { name: name.into(), record_type }], ..Default::default() }
+  }
+
+  #[tokio::test]
+  async fn test_answer() {
+    let responder = responder(Some([1; 32])).await;
+    let address = Ipv4Addr::new(192, 168, 1, 10);
+
+    let (answers, additional) =
+      responder.answer(&query("_SBERLINUX-VPN._udp.local", dns::TYPE_PTR), address).unwrap();
+    assert_eq!(answers[0].data, RecordData::Ptr(format!("office.{}", SERVICE)));
+    assert_eq!(additional.len(), 3);
+    assert_eq!(additional[1].data, RecordData::Txt(vec![format!("key={}", "01".repeat(32))]));
+    assert_eq!(additional[2].data, RecordData::A(address));
+
+    let (answers, additional) =
+      responder.answer(&query(&responder.instance, dns::TYPE_ANY), address).unwrap();
+    assert_eq!(answers.len(), 2);
+    assert_eq!(additional, [Record { name: "gw.local".into(), ttl: TTL, data: RecordData::A(address) }]);
+
+    assert!(responder.answer(&query("_http._tcp.local", dns::TYPE_PTR), address).is_none());
+    let response = Message { response: true, ..query(SERVICE, dns::TYPE_PTR) };
+    assert!(responder.answer(&response, address).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_txt_without_key() {
+    assert_eq!(responder(None).await.txt(), vec![String::new()]);
+  }
+}
@@ -0,0 +1,566 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Write;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::accounting::Direction;
+use crate::listeners::Listener;
+
+/// Label of users outside of tenant networks.
+pub const DEFAULT_NETWORK: &str = "default";
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+  pub fn inc(&self) {
+    self.add(1);
+  }
+
+  pub fn add(&self, value: u64) {
+    self.0.fetch_add(value, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+  pub fn inc(&self) {
+    self.0.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn dec(&self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+
+  pub fn set(&self, value: i64) {
+    self.0.store(value, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> i64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// Type of a metric family, which sinks without Prometheus' types map to their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+  Counter,
+  Gauge,
+  /// Cumulative `_bucket`, `_sum` and `_count` samples, counters to all but Prometheus.
+  Histogram,
+}
+
+impl Display for Kind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Kind::Counter => "counter",
+      Kind::Gauge => "gauge",
+      Kind::Histogram => "histogram",
+    })
+  }
+}
+
+/// Where `Metrics::write` puts the metrics: the Prometheus text of `Metrics::render`, or the formats pushed
+/// by `push`.
+pub trait Sink {
+  /// Starts the family `name`, whose samples follow.
+  fn family(&mut self, name: &str, help: &str, kind: Kind);
+  /// Sample of the current family; `name` carries the suffix of histogram samples, e.g. `_bucket`.
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display);
+}
+
+/// Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Prometheus(pub String);
+
+impl Sink for Prometheus {
+  fn family(&mut self, name: &str, help: &str, kind: Kind) {
+    _ = writeln!(self.0, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+  }
+
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let labels: Vec<_> = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, value)).collect();
+    match labels.is_empty() {
+      true => _ = writeln!(self.0, "{} {}", name, value),
+      false => _ = writeln!(self.0, "{}{{{}}} {}", name, labels.join(","), value),
+    }
+  }
+}
+
+/// Upper bounds of the packet size buckets, in bytes; dense around common MTUs.
+const SIZE_BUCKETS: [u64; 10] = [64, 128, 256, 512, 1024, 1280, 1400, 1420, 1500, 9000];
+
+/// IPv4 and UDP headers carrying each datagram, counted in outer sizes.
+const IP_UDP_HEADERS: usize = 28;
+
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+  buckets: [AtomicU64; SIZE_BUCKETS.len()],
+  sum: AtomicU64,
+  count: AtomicU64,
+}
+
+impl SizeHistogram {
+  pub fn observe(&self, size: usize) {
+    let size = size as u64;
+    if let Some(bucket) = SIZE_BUCKETS.iter().position(|&bound| size <= bound) {
+      self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+    self.sum.fetch_add(size, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn sum(&self) -> u64 {
+    self.sum.load(Ordering::Relaxed)
+  }
+
+  fn write(&self, sink: &mut impl Sink, name: &str, help: &str) {
+    sink.family(name, help, Kind::Histogram);
+    let bucket = format!("{}_bucket", name);
+    let mut cumulative = 0;
+    for (bound, count) in SIZE_BUCKETS.iter().zip(&self.buckets) {
+      cumulative += count.load(Ordering::Relaxed);
+      sink.sample(&bucket, &[("le", &bound.to_string())], cumulative);
+    }
+    let count = self.count.load(Ordering::Relaxed);
+    sink.sample(&bucket, &[("le", "+Inf")], count);
+    sink.sample(&format!("{}_sum", name), &[], self.sum());
+    sink.sample(&format!("{}_count", name), &[], count);
+  }
+}
+
+/// Upper bounds of the dwell time buckets, in seconds.
+const DWELL_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5];
+
+#[derive(Debug, Default)]
+pub struct DwellHistogram {
+  buckets: [AtomicU64; DWELL_BUCKETS.len()],
+  sum_micros: AtomicU64,
+  count: AtomicU64,
+}
+
+impl DwellHistogram {
+  pub fn observe(&self, dwell: Duration) {
+    let seconds = dwell.as_secs_f64();
+    if let Some(bucket) = DWELL_BUCKETS.iter().position(|&bound| seconds <= bound) {
+      self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+    self.sum_micros.fetch_add(dwell.as_micros() as u64, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn write(&self, sink: &mut impl Sink, name: &str, queue: &str) {
+    let bucket = format!("{}_bucket", name);
+    let mut cumulative = 0;
+    for (bound, count) in DWELL_BUCKETS.iter().zip(&self.buckets) {
+      cumulative += count.load(Ordering::Relaxed);
+      sink.sample(&bucket, &[("queue", queue), ("le", &bound.to_string())], cumulative);
+    }
+    let count = self.count.load(Ordering::Relaxed);
+    let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    sink.sample(&bucket, &[("queue", queue), ("le", "+Inf")], count);
+    sink.sample(&format!("{}_sum", name), &[("queue", queue)], sum);
+    sink.sample(&format!("{}_count", name), &[("queue", queue)], count);
+  }
+}
+
+/// Packets waiting in one of the internal queues, so throughput problems can be pinned on a stage.
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+  pub depth: Gauge,
+  pub dropped: Counter,
+  /// Time from entering the queue to being picked up from it.
+  pub dwell: DwellHistogram,
+}
+
+impl QueueMetrics {
+  pub fn enqueued(&self) {
+    self.depth.inc();
+  }
+
+  /// Records a packet that entered the queue at `since` leaving it.
+  pub fn dequeued(&self, since: Instant) {
+    self.depth.dec();
+    self.dwell.observe(since.elapsed());
+  }
+}
+
+/// Datagrams a listener took in, key exchanges apart from the packets of sessions.
+#[derive(Debug, Default)]
+pub struct ListenerMetrics {
+  pub handshake_packets: Counter,
+  pub handshake_bytes: Counter,
+  pub data_packets: Counter,
+  pub data_bytes: Counter,
+  /// Key exchanges dropped while the listener refused them, see `listeners::HandshakeGates`.
+  pub refused_handshakes: Counter,
+}
+
+impl ListenerMetrics {
+  pub fn record(&self, handshake: bool, len: usize) {
+    let (packets, bytes) = match handshake {
+      true => (&self.handshake_packets, &self.handshake_bytes),
+      false => (&self.data_packets, &self.data_bytes),
+    };
+    packets.inc();
+    bytes.add(len as u64);
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkMetrics {
+  pub sessions: i64,
+  pub bytes_in: u64,
+  pub bytes_out: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+  pub decrypt_failures: Counter,
+  pub quarantined_peers: Counter,
+  pub quarantine_dropped_packets: Counter,
+  pub replayed_handshakes: Counter,
+  pub refused_handshakes: Counter,
+  pub rekeys: Counter,
+  /// Decrypted packets waiting for a worker.
+  pub worker_queue: QueueMetrics,
+  /// Datagrams waiting in the send queues of clients and cluster peers.
+  pub send_queue: QueueMetrics,
+  /// Packets of clients being written to the tun device, or waiting for the userspace stack.
+  pub tun_queue: QueueMetrics,
+  pub red_marked_packets: Counter,
+  pub red_dropped_packets: Counter,
+  /// Load being shed, see `workers::Shed`.
+  pub shed_level: Gauge,
+  pub shed_data_packets: Counter,
+  pub deferred_handshakes: Counter,
+  pub mirrored_packets: Counter,
+  /// Sampled packets that weren't mirrored, being over a cap or the sink being behind.
+  pub mirror_dropped_packets: Counter,
+  pub policy_alerts: Counter,
+  /// Pings and renegotiations over the rate of their session.
+  pub dropped_control_packets: Counter,
+  /// Users refused for want of a free address, and addresses taken from silent sessions to avoid that.
+  pub pool_exhausted: Counter,
+  pub reclaimed_leases: Counter,
+  pub inner_packet_bytes: SizeHistogram,
+  pub outer_packet_bytes: SizeHistogram,
+  pub udp_listener: ListenerMetrics,
+  pub tcp_listener: ListenerMetrics,
+  /// Sessions and traffic of each network, by name; see `network_label`.
+  pub networks: Mutex<BTreeMap<String, NetworkMetrics>>,
+}
+
+pub fn network_label(network: Option<&str>) -> &str {
+  network.unwrap_or(DEFAULT_NETWORK)
+}
+
+impl Metrics {
+  /// Records a tunneled packet of `inner` bytes carried in a datagram of `datagram` bytes.
+  pub fn record_data(&self, inner: usize, datagram: usize) {
+    self.inner_packet_bytes.observe(inner);
+    self.outer_packet_bytes.observe(datagram + IP_UDP_HEADERS);
+  }
+
+  pub fn listener(&self, listener: Listener) -> &ListenerMetrics {
+    match listener {
+      Listener::Udp => &self.udp_listener,
+      Listener::Tcp => &self.tcp_listener,
+    }
+  }
+
+  pub fn session_started(&self, network: Option<&str>) {
+    self.update_network(network, |metrics| metrics.sessions += 1);
+  }
+
+  pub fn session_ended(&self, network: Option<&str>) {
+    self.update_network(network, |metrics| metrics.sessions -= 1);
+  }
+
+  /// Records tunneled bytes of a user of `network`.
+  pub fn record_network_data(&self, network: Option<&str>, direction: Direction, bytes: usize) {
+    self.update_network(network, |metrics| match direction {
+      Direction::Inbound => metrics.bytes_in += bytes as u64,
+      Direction::Outbound => metrics.bytes_out += bytes as u64,
+    });
+  }
+
+  fn update_network(&self, network: Option<&str>, update: impl FnOnce(&mut NetworkMetrics)) {
+    let mut networks = self.networks.lock().unwrap();
+    match networks.get_mut(network_label(network)) {
+      Some(metrics) => update(metrics),
+      None => update(networks.entry(network_label(network).to_string()).or_default()),
+    }
+  }
+
+  /// Bytes added by the tunnel, as a percentage of the tunneled bytes.
+  pub fn overhead_percent(&self) -> f64 {
+    let (inner, outer) = (self.inner_packet_bytes.sum(), self.outer_packet_bytes.sum());
+    match inner {
+      0 => 0.0,
+      _ => outer.saturating_sub(inner) as f64 * 100.0 / inner as f64,
+    }
+  }
+
+  /// Renders all metrics in the Prometheus text exposition format.
+  pub fn render(&self) -> String {
+    let mut prometheus = Prometheus::default();
+    self.write(&mut prometheus);
+    prometheus.0
+  }
+
+  pub fn write(&self, sink: &mut impl Sink) {
+    let counters = [
+      ("vpn_decrypt_failures_total", "Packets that failed to parse or decrypt", &self.decrypt_failures),
+      ("vpn_quarantined_peers_total", "Times a source was quarantined", &self.quarantined_peers),
+      (
+        "vpn_quarantine_dropped_packets_total",
+        "Packets dropped from quarantined sources",
+        &self.quarantine_dropped_packets,
+      ),
+      (
+        "vpn_replayed_handshakes_total",
+        "Key exchanges refused as replayed or too far off the server clock",
+        &self.replayed_handshakes,
+      ),
+      (
+        "vpn_refused_handshakes_total",
+        "Key exchanges refused from the address of an established session with strict-handshakes",
+        &self.refused_handshakes,
+      ),
+      ("vpn_rekeys_total", "Session keys the server asked clients to replace", &self.rekeys),
+      (
+        "vpn_worker_dropped_packets_total",
+        "Packets dropped because the worker queues were full",
+        &self.worker_queue.dropped,
+      ),
+      (
+        "vpn_send_dropped_packets_total",
+        "Outbound packets dropped because a client's send queue was full",
+        &self.send_queue.dropped,
+      ),
+      (
+        "vpn_red_marked_packets_total",
+        "Tun packets marked as congested because a client's send queue was filling up",
+        &self.red_marked_packets,
+      ),
+      (
+        "vpn_red_dropped_packets_total",
+        "Tun packets dropped early because a client's send queue was filling up",
+        &self.red_dropped_packets,
+      ),
+      (
+        "vpn_shed_data_packets_total",
+        "Data packets of clients dropped to shed load",
+        &self.shed_data_packets,
+      ),
+      (
+        "vpn_deferred_handshakes_total",
+        "Key exchanges deferred with a retry-after to shed load or pace admissions",
+        &self.deferred_handshakes,
+      ),
+      ("vpn_mirrored_packets_total", "Packets of clients copied to the mirror sink", &self.mirrored_packets),
+      (
+        "vpn_mirror_dropped_packets_total",
+        "Sampled packets not mirrored because of a rate cap or a slow sink",
+        &self.mirror_dropped_packets,
+      ),
+      ("vpn_policy_alerts_total", "Alerts raised about clients violating policy", &self.policy_alerts),
+      (
+        "vpn_dropped_control_packets_total",
+        "Control packets dropped for exceeding the rate of their session",
+        &self.dropped_control_packets,
+      ),
+      (
+        "vpn_pool_exhausted_total",
+        "Users refused because no address was free to lease",
+        &self.pool_exhausted,
+      ),
+      (
+        "vpn_reclaimed_leases_total",
+        "Addresses taken from silent sessions to lease them to others",
+        &self.reclaimed_leases,
+      ),
+    ];
+
+    for (name, help, counter) in counters {
+      sink.family(name, help, Kind::Counter);
+      sink.sample(name, &[], counter.get());
+    }
+
+    let gauges = [
+      ("vpn_worker_queue_depth", "Packets waiting for a worker", &self.worker_queue.depth),
+      ("vpn_send_queue_depth", "Outbound packets waiting in client send queues", &self.send_queue.depth),
+      (
+        "vpn_shed_level",
+        "Load being shed: 0 nothing, 1 handshakes, 2 also normal-priority data, 3 all data",
+        &self.shed_level,
+      ),
+    ];
+
+    for (name, help, gauge) in gauges {
+      sink.family(name, help, Kind::Gauge);
+      sink.sample(name, &[], gauge.get());
+    }
+
+    let queues = [("workers", &self.worker_queue), ("send", &self.send_queue), ("tun", &self.tun_queue)];
+    sink.family("vpn_queue_depth", "Packets waiting by queue", Kind::Gauge);
+    for (queue, metrics) in queues {
+      sink.sample("vpn_queue_depth", &[("queue", queue)], metrics.depth.get());
+    }
+    sink.family("vpn_queue_dropped_packets_total", "Packets dropped by queue", Kind::Counter);
+    for (queue, metrics) in queues {
+      sink.sample("vpn_queue_dropped_packets_total", &[("queue", queue)], metrics.dropped.get());
+    }
+    sink.family("vpn_queue_dwell_seconds", "Time packets spend waiting by queue", Kind::Histogram);
+    for (queue, metrics) in queues {
+      metrics.dwell.write(sink, "vpn_queue_dwell_seconds", queue);
+    }
+
+    self.inner_packet_bytes.write(
+      sink,
+      "vpn_inner_packet_bytes",
+      "Sizes of tunneled IP packets, in both directions",
+    );
+    self.outer_packet_bytes.write(
+      sink,
+      "vpn_outer_packet_bytes",
+      "Sizes of the IP packets carrying tunneled packets, including IP and UDP headers",
+    );
+    let overhead = "vpn_protocol_overhead_percent";
+    sink.family(overhead, "Bytes added by the tunnel as a percentage of the tunneled bytes", Kind::Gauge);
+    sink.sample(overhead, &[], format!("{:.2}", self.overhead_percent()));
+
+    let listeners = [("udp", &self.udp_listener), ("tcp", &self.tcp_listener)];
+    let packets = "vpn_listener_packets_total";
+    sink.family(packets, "Datagrams taken in by listener and traffic", Kind::Counter);
+    for (listener, metrics) in listeners {
+      sink.sample(
+        packets,
+        &[("listener", listener), ("traffic", "handshake")],
+        metrics.handshake_packets.get(),
+      );
+      sink.sample(packets, &[("listener", listener), ("traffic", "data")], metrics.data_packets.get());
+    }
+    let bytes = "vpn_listener_bytes_total";
+    sink.family(bytes, "Bytes taken in by listener and traffic", Kind::Counter);
+    for (listener, metrics) in listeners {
+      sink.sample(bytes, &[("listener", listener), ("traffic", "handshake")], metrics.handshake_bytes.get());
+      sink.sample(bytes, &[("listener", listener), ("traffic", "data")], metrics.data_bytes.get());
+    }
+    let refused = "vpn_listener_refused_handshakes_total";
+    sink.family(refused, "Key exchanges dropped while their listener refused them", Kind::Counter);
+    for (listener, metrics) in listeners {
+      sink.sample(refused, &[("listener", listener)], metrics.refused_handshakes.get());
+    }
+
+    let networks = self.networks.lock().unwrap();
+    sink.family("vpn_network_sessions", "Authenticated sessions by network", Kind::Gauge);
+    for (network, metrics) in networks.iter() {
+      sink.sample("vpn_network_sessions", &[("network", network)], metrics.sessions);
+    }
+    sink.family("vpn_network_bytes_total", "Tunneled bytes by network and direction", Kind::Counter);
+    for (network, metrics) in networks.iter() {
+      for (direction, bytes) in [("in", metrics.bytes_in), ("out", metrics.bytes_out)] {
+        sink.sample("vpn_network_bytes_total", &[("network", network), ("direction", direction)], bytes);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let metrics = Metrics::default();
+    metrics.decrypt_failures.add(3);
+    metrics.worker_queue.enqueued();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("# TYPE vpn_decrypt_failures_total counter\nvpn_decrypt_failures_total 3\n"));
+    assert!(rendered.contains("vpn_quarantined_peers_total 0\n"));
+    assert!(rendered.contains("# TYPE vpn_worker_queue_depth gauge\nvpn_worker_queue_depth 1\n"));
+    assert!(rendered.contains("# TYPE vpn_shed_level gauge\nvpn_shed_level 0\n"));
+    assert!(rendered.contains("vpn_protocol_overhead_percent 0.00\n"));
+  }
+
+  #[test]
+  fn test_listeners() {
+    let metrics = Metrics::default();
+    metrics.listener(Listener::Udp).record(true, 120);
+    metrics.listener(Listener::Udp).record(false, 1400);
+    metrics.listener(Listener::Tcp).refused_handshakes.inc();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("vpn_listener_packets_total{listener=\"udp\",traffic=\"handshake\"} 1\n"));
+    assert!(rendered.contains("vpn_listener_bytes_total{listener=\"udp\",traffic=\"data\"} 1400\n"));
+    assert!(rendered.contains("vpn_listener_packets_total{listener=\"tcp\",traffic=\"data\"} 0\n"));
+    assert!(rendered.contains("vpn_listener_refused_handshakes_total{listener=\"tcp\"} 1\n"));
+  }
+
+  #[test]
+  fn test_packet_sizes() {
+    let metrics = Metrics::default();
+    metrics.record_data(100, 132);
+    metrics.record_data(1400, 1432);
+    metrics.record_data(10000, 10032);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("vpn_inner_packet_bytes_bucket{le=\"128\"} 1\n"));
+    assert!(rendered.contains("vpn_inner_packet_bytes_bucket{le=\"1400\"} 2\n"));
+    assert!(rendered.contains("vpn_inner_packet_bytes_bucket{le=\"9000\"} 2\n"));
+    assert!(
+      rendered.contains("vpn_inner_packet_bytes_bucket{le=\"+Inf\"} 3\nvpn_inner_packet_bytes_sum 11500\n")
+    );
+    assert!(rendered.contains("vpn_outer_packet_bytes_bucket{le=\"1500\"} 2\n"));
+    assert!(rendered.contains("vpn_protocol_overhead_percent 1.57\n"));
+  }
+
+  #[test]
+  fn test_queues() {
+    let metrics = Metrics::default();
+    metrics.tun_queue.enqueued();
+    metrics.tun_queue.dequeued(Instant::now() - Duration::from_millis(3));
+    metrics.send_queue.enqueued();
+    metrics.send_queue.dropped.inc();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("vpn_queue_depth{queue=\"send\"} 1\nvpn_queue_depth{queue=\"tun\"} 0\n"));
+    assert!(rendered.contains("vpn_queue_dropped_packets_total{queue=\"send\"} 1\n"));
+    assert!(rendered.contains("vpn_send_dropped_packets_total 1\n"));
+    assert!(rendered.contains("vpn_queue_dwell_seconds_bucket{queue=\"tun\",le=\"0.0025\"} 0\n"));
+    assert!(rendered.contains("vpn_queue_dwell_seconds_bucket{queue=\"tun\",le=\"0.005\"} 1\n"));
+    assert!(rendered.contains("vpn_queue_dwell_seconds_count{queue=\"tun\"} 1\n"));
+    assert!(rendered.contains("vpn_queue_dwell_seconds_count{queue=\"workers\"} 0\n"));
+  }
+
+  #[test]
+  fn test_network_labels() {
+    let metrics = Metrics::default();
+    metrics.session_started(Some("acme"));
+    metrics.session_started(None);
+    metrics.session_ended(None);
+    metrics.record_network_data(Some("acme"), Direction::Inbound, 100);
+    metrics.record_network_data(Some("acme"), Direction::Outbound, 50);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("vpn_network_sessions{network=\"acme\"} 1\n"));
+    assert!(rendered.contains("vpn_network_sessions{network=\"default\"} 0\n"));
+    assert!(rendered.contains("vpn_network_bytes_total{network=\"acme\",direction=\"in\"} 100\n"));
+    assert!(rendered.contains("vpn_network_bytes_total{network=\"acme\",direction=\"out\"} 50\n"));
+  }
+}
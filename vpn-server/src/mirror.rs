@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufWriter;
+use tokio::sync::mpsc;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::diagnose;
+use vpn_shared::rate::TokenBucket;
+
+use crate::metrics::network_label;
+use crate::metrics::Metrics;
+use crate::nat;
+
+const QUEUE_DEPTH: usize = 4096;
+/// Raw IPv4 packets without a link-layer header.
+const LINKTYPE_RAW: u32 = 101;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MirrorSink {
+  /// Writes a pcap stream to a file or a FIFO that an IDS reads from, e.g. `suricata -r` or `zeek -r`.
+  Pcap { path: PathBuf },
+  /// Writes to a tun device of this name for IDSes sniffing an interface; the kernel drops what's written
+  /// to it before routing, so mirrored packets go nowhere else.
+  Interface { name: String },
+}
+
+/// Copies of decrypted packets of clients in both directions, for intrusion detection.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MirrorConfig {
+  pub sink: MirrorSink,
+
+  /// Mirrors one packet out of this many.
+  #[serde(default = "default_sample")]
+  pub sample: u32,
+
+  /// Packets mirrored per second at most, across networks; the rest aren't.
+  #[serde(default)]
+  pub max_pps: Option<u32>,
+
+  /// Networks whose clients are mirrored, `default` being the one of users outside of networks, each with
+  /// its own cap; all of them when empty.
+  #[serde(default)]
+  pub networks: BTreeMap<String, NetworkMirrorConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkMirrorConfig {
+  #[serde(default)]
+  pub max_pps: Option<u32>,
+}
+
+fn default_sample() -> u32 {
+  1
+}
+
+fn rate_cap(max_pps: Option<u32>) -> Option<Mutex<TokenBucket>> {
+  max_pps.map(|pps| Mutex::new(TokenBucket::new(pps as f64, pps as f64)))
+}
+
+pub struct Mirror {
+  sample: u64,
+  seen: AtomicU64,
+  cap: Option<Mutex<TokenBucket>>,
+  networks: BTreeMap<String, Option<Mutex<TokenBucket>>>,
+  packets: mpsc::Sender<Vec<u8>>,
+  metrics: Arc<Metrics>,
+}
+
+impl Mirror {
+  /// Opens the sink; packets of tun devices up to `mtu` are mirrored to it.
+  pub async fn spawn(config: MirrorConfig, mtu: u16, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+    if config.sample == 0 {
+      anyhow::bail!("Mirror sample must be at least 1");
+    }
+
+    let (packets, rx) = mpsc::channel(QUEUE_DEPTH);
+    match config.sink {
+      MirrorSink::Pcap { path } => {
+        tokio::spawn(write_pcap(path, rx));
+      }
+      MirrorSink::Interface { name } => {
+        let mut tun_config = tun::Configuration::default();
+        tun_config.tun_name(&name).mtu(mtu).up();
+        let device = tun::create_as_async(&tun_config).map_err(diagnose::tun_error)?;
+        nat::ensure_iptables("raw", "PREROUTING", &["-i", &name, "-j", "DROP"]).await?;
+        info!("Mirroring packets to interface {}", name);
+        tokio::spawn(write_interface(device, rx));
+      }
+    }
+
+    let networks =
+      config.networks.into_iter().map(|(name, network)| (name, rate_cap(network.max_pps))).collect();
+    Ok(Self {
+      sample: config.sample as u64,
+      seen: AtomicU64::new(0),
+      cap: rate_cap(config.max_pps),
+      networks,
+      packets,
+      metrics,
+    })
+  }
+
+  /// Mirrors `packet` of a client of `network` if it's sampled and under the caps; never waits for the sink.
+  pub fn mirror(&self, network: Option<&str>, packet: &[u8]) {
+    let network_cap = match self.networks.get(network_label(network)) {
+      Some(cap) => cap.as_ref(),
+      None if self.networks.is_empty() => None,
+      None => return,
+    };
+    if !self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample) {
+      return;
+    }
+
+    let admitted =
+      [network_cap, self.cap.as_ref()].into_iter().flatten().all(|cap| cap.lock().unwrap().try_take(1.0));
+    if !admitted || self.packets.try_send(packet.to_vec()).is_err() {
+      self.metrics.mirror_dropped_packets.inc();
+      return;
+    }
+    self.metrics.mirrored_packets.inc();
+  }
+}
+
+async fn write_pcap(path: PathBuf, mut packets: mpsc::Receiver<Vec<u8>>) {
+  // Opening a FIFO waits until the IDS opens it for reading.
+  let file = match tokio::fs::File::create(&path).await {
+    Ok(file) => file,
+    Err(e) => {
+      warn!("Not mirroring packets: failed to open {}: {}", path.display(), e);
+      return;
+    }
+  };
+  info!("Mirroring packets to {}", path.display());
+
+  let mut out = BufWriter::new(file);
+  let mut result = out.write_all(&pcap_header()).await;
+  while result.is_ok() {
+    let Some(packet) = packets.recv().await else {
+      break;
+    };
+    result = out.write_all(&pcap_record(&packet, SystemTime::now())).await;
+    if result.is_ok() && packets.is_empty() {
+      result = out.flush().await;
+    }
+  }
+  if let Err(e) = result {
+    warn!("Stopped mirroring packets to {}: {}", path.display(), e);
+  }
+}
+
+async fn write_interface(device: tun::AsyncDevice, mut packets: mpsc::Receiver<Vec<u8>>) {
+  while let Some(packet) = packets.recv().await {
+    if let Err(e) = device.send(&packet).await {
+      debug!("Failed to mirror a packet: {}", e);
+    }
+  }
+}
+
+fn pcap_header() -> Vec<u8> {
+  let mut header = Vec::with_capacity(24);
+  header.extend(0xa1b2c3d4u32.to_le_bytes());
+  header.extend(2u16.to_le_bytes());
+  header.extend(4u16.to_le_bytes());
+  header.extend([0; 8]);
+  header.extend((u16::MAX as u32).to_le_bytes());
+  header.extend(LINKTYPE_RAW.to_le_bytes());
+  header
+}
+
+fn pcap_record(packet: &[u8], at: SystemTime) -> Vec<u8> {
+  let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let mut record = Vec::with_capacity(16 + packet.len());
+  record.extend((at.as_secs() as u32).to_le_bytes());
+  record.extend(at.subsec_micros().to_le_bytes());
+  record.extend((packet.len() as u32).to_le_bytes());
+  record.extend((packet.len() as u32).to_le_bytes());
+  record.extend_from_slice(packet);
+  record
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn test_pcap_record() {
+    assert_eq!(pcap_header()[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(pcap_header()[20..], [101, 0, 0, 0]);
+
+    let record = pcap_record(&[0x45, 0, 0, 20], UNIX_EPOCH + Duration::from_micros(1_500_000));
+    assert_eq!(record[..16], [1, 0, 0, 0, 0x20, 0xa1, 7, 0, 4, 0, 0, 0, 4, 0, 0, 0]);
+    assert_eq!(record[16..], [0x45, 0, 0, 20]);
+  }
+
+  #[tokio::test]
+  async fn test_mirror() {
+    let path = std::env::temp_dir().join(format!("vpn-mirror-{}.pcap", std::process::id()));
+    let config = MirrorConfig {
+      sink: MirrorSink::Pcap { path: path.clone() },
+      sample: 2,
+      max_pps: None,
+      networks: BTreeMap::from([("acme".to_string(), NetworkMirrorConfig { max_pps: Some(2) })]),
+    };
+    let metrics = Arc::new(Metrics::default());
+    let mirror = Mirror::spawn(config, 1500, metrics.clone()).await.unwrap();
+
+    // Only acme is mirrored, every other packet, and no more than its cap.
+    for _ in 0..4 {
+      mirror.mirror(None, &[0x45]);
+    }
+    for _ in 0..8 {
+      mirror.mirror(Some("acme"), &[0x45]);
+    }
+    assert_eq!(metrics.mirrored_packets.get(), 2);
+    assert_eq!(metrics.mirror_dropped_packets.get(), 2);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(std::fs::read(&path).unwrap().len(), 24 + 2 * 17);
+    std::fs::remove_file(&path).unwrap();
+  }
+}
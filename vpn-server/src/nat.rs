@@ -0,0 +1,284 @@
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use tracing::info;
+use tracing::warn;
+use vpn_shared::ip;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct EgressRule {
+  #[serde(default)]
+  pub users: Vec<String>,
+  #[serde(default)]
+  pub groups: Vec<String>,
+  pub interface: String,
+  pub table: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+  Tcp,
+  Udp,
+}
+
+impl Protocol {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Self::Tcp => "tcp",
+      Self::Udp => "udp",
+    }
+  }
+
+  pub fn number(self) -> u8 {
+    match self {
+      Self::Tcp => ip::PROTO_TCP,
+      Self::Udp => ip::PROTO_UDP,
+    }
+  }
+
+  pub fn from_number(number: u8) -> Option<Self> {
+    match number {
+      ip::PROTO_TCP => Some(Self::Tcp),
+      ip::PROTO_UDP => Some(Self::Udp),
+      _ => None,
+    }
+  }
+}
+
+/// Connections to `public-port` on the server forwarded to `client-port` on the user's virtual address
+/// while the user is connected.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PortForward {
+  pub user: String,
+  pub proto: Protocol,
+  pub public_port: u16,
+  pub client_port: u16,
+}
+
+impl PortForward {
+  fn dnat(&self, address: Ipv4Addr) -> Vec<String> {
+    let destination = format!("{}:{}", address, self.client_port);
+    let spec = ["-p", self.proto.as_str(), "--dport", &self.public_port.to_string(), "-j", "DNAT"];
+    spec.iter().map(|s| s.to_string()).chain(["--to-destination".into(), destination]).collect()
+  }
+
+  fn accept(&self, address: Ipv4Addr) -> Vec<String> {
+    let (address, port) = (format!("{}/32", address), self.client_port.to_string());
+    ["-p", self.proto.as_str(), "-d", &address, "--dport", &port, "-j", "ACCEPT"].map(String::from).to_vec()
+  }
+
+  /// Adds the rules forwarding the public port to `address`.
+  pub async fn open(&self, address: Ipv4Addr) -> anyhow::Result<()> {
+    iptables("nat", "-A", "PREROUTING", &self.dnat(address)).await?;
+    iptables("filter", "-I", "FORWARD", &self.accept(address)).await?;
+    info!(
+      "Forwarding {} port {} to {} ({}) port {}",
+      self.proto.as_str(),
+      self.public_port,
+      self.user,
+      address,
+      self.client_port
+    );
+    Ok(())
+  }
+
+  /// Removes the rules added by `open`.
+  pub async fn close(&self, address: Ipv4Addr) {
+    let removed = async {
+      iptables("nat", "-D", "PREROUTING", &self.dnat(address)).await?;
+      iptables("filter", "-D", "FORWARD", &self.accept(address)).await
+    };
+    if let Err(e) = removed.await {
+      warn!("Failed to remove port forward {} for {} ({}): {}", self.public_port, self.user, address, e);
+    }
+  }
+}
+
+/// Source NAT for the client subnets plus per-user egress selection. Users matched by an egress rule, either
+/// directly or through one of their groups, get an `ip rule` sending their traffic to the rule's routing
+/// table, whose default route leaves through the rule's interface; everyone else follows the main table.
+#[derive(Debug, Default)]
+pub struct Nat {
+  subnets: Vec<Ipv4Net>,
+  egress: Vec<EgressRule>,
+  forwards: Vec<PortForward>,
+}
+
+impl Nat {
+  pub fn new(subnet: Ipv4Net, egress: Vec<EgressRule>) -> Self {
+    Self { subnets: vec![subnet], egress, forwards: Vec::new() }
+  }
+
+  /// Also masquerades these subnets, e.g. of tenant networks.
+  pub fn with_subnets(mut self, subnets: impl IntoIterator<Item = Ipv4Net>) -> Self {
+    self.subnets.extend(subnets);
+    self
+  }
+
+  pub fn with_port_forwards(mut self, forwards: Vec<PortForward>) -> Self {
+    self.forwards = forwards;
+    self
+  }
+
+  fn forwards_for<'a>(&'a self, username: &'a str) -> impl Iterator<Item = &'a PortForward> {
+    self.forwards.iter().filter(move |forward| forward.user == username)
+  }
+
+  pub fn has_port_forwards(&self) -> bool {
+    !self.forwards.is_empty()
+  }
+
+  /// Whether connections to `port` of the user's address come in through a port forward; `protocol` is the
+  /// IP protocol number.
+  pub fn is_forwarded(&self, username: &str, protocol: u8, port: u16) -> bool {
+    self
+      .forwards_for(username)
+      .any(|forward| forward.proto.number() == protocol && forward.client_port == port)
+  }
+
+  /// Whether a configured port forward takes `port` of the server.
+  pub fn is_public_port_taken(&self, protocol: Protocol, port: u16) -> bool {
+    self.forwards.iter().any(|forward| forward.proto == protocol && forward.public_port == port)
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    !self.subnets.is_empty()
+  }
+
+  pub fn egress_for(&self, username: &str, groups: &[String]) -> Option<&EgressRule> {
+    self.egress.iter().find(|rule| {
+      rule.users.iter().any(|user| user == username) || rule.groups.iter().any(|group| groups.contains(group))
+    })
+  }
+
+  pub async fn setup(&self) -> anyhow::Result<()> {
+    for subnet in &self.subnets {
+      ensure_iptables("nat", "POSTROUTING", &["-s", &subnet.to_string(), "-j", "MASQUERADE"]).await?;
+    }
+
+    for rule in &self.egress {
+      let table = rule.table.to_string();
+      run("ip", &["route", "replace", "default", "dev", &rule.interface, "table", &table]).await?;
+      info!(
+        "Egress via {} (table {}) for users [{}] and groups [{}]",
+        rule.interface,
+        rule.table,
+        rule.users.join(", "),
+        rule.groups.join(", ")
+      );
+    }
+
+    Ok(())
+  }
+
+  pub async fn assign(&self, username: &str, groups: &[String], address: Ipv4Addr) -> anyhow::Result<()> {
+    if let Some(rule) = self.egress_for(username, groups) {
+      run("ip", &["rule", "add", "from", &format!("{}/32", address), "table", &rule.table.to_string()])
+        .await?;
+      info!("Routing {} ({}) via {}", username, address, rule.interface);
+    }
+
+    for forward in self.forwards_for(username) {
+      forward.open(address).await?;
+    }
+
+    Ok(())
+  }
+
+  pub async fn release(&self, username: &str, groups: &[String], address: Ipv4Addr) {
+    if let Some(rule) = self.egress_for(username, groups) {
+      if let Err(e) =
+        run("ip", &["rule", "del", "from", &format!("{}/32", address), "table", &rule.table.to_string()])
+          .await
+      {
+        warn!("Failed to remove egress rule for {} ({}): {}", username, address, e);
+      }
+    }
+
+    for forward in self.forwards_for(username) {
+      forward.close(address).await;
+    }
+  }
+}
+
+async fn iptables(table: &str, action: &str, chain: &str, spec: &[String]) -> anyhow::Result<()> {
+  let spec: Vec<&str> = spec.iter().map(String::as_str).collect();
+  run("iptables", &[&["-t", table, action, chain], spec.as_slice()].concat()).await
+}
+
+/// Appends the rule unless the chain already has it.
+pub async fn ensure_iptables(table: &str, chain: &str, spec: &[&str]) -> anyhow::Result<()> {
+  if run("iptables", &[&["-t", table, "-C", chain], spec].concat()).await.is_ok() {
+    return Ok(());
+  }
+
+  run("iptables", &[&["-t", table, "-A", chain], spec].concat()).await
+}
+
+pub async fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+  let output = Command::new(program).args(args).output().await?;
+  if !output.status.success() {
+    anyhow::bail!(
+      "`{} {}` failed: {}",
+      program,
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_egress_for() {
+    let nat = Nat::new(
+      "10.0.0.0/24".parse().unwrap(),
+      vec![
+        EgressRule { users: vec!["alice".into()], groups: vec![], interface: "eth0".into(), table: 100 },
+        EgressRule {
+          users: vec!["bob".into(), "carol".into()],
+          groups: vec!["ops".into()],
+          interface: "eth1".into(),
+          table: 101,
+        },
+      ],
+    );
+
+    assert_eq!(nat.egress_for("alice", &[]).unwrap().interface, "eth0");
+    assert_eq!(nat.egress_for("carol", &[]).unwrap().table, 101);
+    assert_eq!(nat.egress_for("dave", &["ops".into()]).unwrap().table, 101);
+    assert!(nat.egress_for("dave", &["staff".into()]).is_none());
+  }
+
+  #[test]
+  fn test_port_forward_rules() {
+    let forward =
+      PortForward { user: "alice".into(), proto: Protocol::Tcp, public_port: 8080, client_port: 80 };
+    let address = Ipv4Addr::new(10, 0, 0, 2);
+
+    assert_eq!(
+      forward.dnat(address),
+      ["-p", "tcp", "--dport", "8080", "-j", "DNAT", "--to-destination", "10.0.0.2:80"]
+    );
+    assert_eq!(forward.accept(address), ["-p", "tcp", "-d", "10.0.0.2/32", "--dport", "80", "-j", "ACCEPT"]);
+
+    let nat = Nat::default().with_port_forwards(vec![forward]);
+    assert!(nat.is_forwarded("alice", ip::PROTO_TCP, 80));
+    assert!(!nat.is_forwarded("alice", ip::PROTO_UDP, 80));
+    assert!(!nat.is_forwarded("alice", ip::PROTO_TCP, 8080));
+    assert!(!nat.is_forwarded("bob", ip::PROTO_TCP, 80));
+    assert!(nat.is_public_port_taken(Protocol::Tcp, 8080));
+    assert!(!nat.is_public_port_taken(Protocol::Udp, 8080));
+    assert_eq!(Protocol::from_number(ip::PROTO_UDP), Some(Protocol::Udp));
+    assert_eq!(Protocol::from_number(1), None);
+  }
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use vpn_shared::creds::Credentials;
+use vpn_shared::creds::KeyCredentials;
+use vpn_shared::packet::Key;
+
+use crate::policy::GroupPolicy;
+use crate::policy::Policies;
+use crate::pool::AddressPool;
+use crate::pool::AddressPoolConfig;
+
+/// Network of one tenant: users are authenticated against its own credentials and get addresses of its
+/// subnet, and can't reach other networks.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkConfig {
+  pub subnet: Ipv4Net,
+
+  #[serde(default)]
+  pub dns: Vec<Ipv4Addr>,
+
+  #[serde(default)]
+  pub client_credentials: Vec<Credentials>,
+
+  #[serde(default)]
+  pub client_keys: Vec<KeyCredentials>,
+
+  #[serde(default)]
+  pub groups: BTreeMap<String, GroupPolicy>,
+}
+
+pub struct Network {
+  pub name: String,
+  pub pool: AddressPool,
+  pub client_credentials: Vec<Credentials>,
+  pub client_keys: Vec<KeyCredentials>,
+  pub policies: Policies,
+}
+
+impl Network {
+  pub fn new(name: String, config: NetworkConfig) -> Self {
+    Self {
+      name,
+      pool: AddressPool::new(AddressPoolConfig { subnet: config.subnet, dns: config.dns }, None),
+      client_credentials: config.client_credentials,
+      client_keys: config.client_keys,
+      policies: Policies::new(config.groups),
+    }
+  }
+}
+
+/// Tenant networks next to the default one, which holds users of the top-level configuration.
+#[derive(Default)]
+pub struct Networks {
+  networks: Vec<Network>,
+}
+
+impl Networks {
+  pub fn new(configs: BTreeMap<String, NetworkConfig>) -> Self {
+    Self { networks: configs.into_iter().map(|(name, config)| Network::new(name, config)).collect() }
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Network> {
+    self.networks.iter()
+  }
+
+  pub fn get(&self, name: &str) -> Option<&Network> {
+    self.networks.iter().find(|network| network.name == name)
+  }
+
+  /// Network whose `client-credentials` list these credentials.
+  pub fn by_credentials(&self, credentials: &Credentials) -> Option<&Network> {
+    self.networks.iter().find(|network| network.client_credentials.contains(credentials))
+  }
+
+  /// Network with `public_key` among its keys of `username`, along with the entry of the key.
+  pub fn by_key(&self, username: &str, public_key: &Key) -> Option<(&Network, &KeyCredentials)> {
+    self.networks.iter().find_map(|network| {
+      let key =
+        network.client_keys.iter().find(|key| key.username == username && key.public_key == *public_key);
+      Some((network, key?))
+    })
+  }
+
+  /// Whether a client of network `from`, `None` being the default one with `default_subnet`, may send to
+  /// `destination`: subnets of other networks are unreachable, anything outside of them is.
+  pub fn allows(&self, from: Option<&str>, default_subnet: Option<Ipv4Net>, destination: Ipv4Addr) -> bool {
+    let owner = match self.networks.iter().find(|network| network.pool.subnet().contains(&destination)) {
+      Some(network) => Some(network.name.as_str()),
+      None if default_subnet.is_some_and(|subnet| subnet.contains(&destination)) => None,
+      None => return true,
+    };
+    owner == from
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+
+  fn networks() -> Networks {
+    let network = |subnet: &str, user: &str| NetworkConfig {
+      subnet: subnet.parse().unwrap(),
+      dns: Vec::new(),
+      client_credentials: vec![Credentials::from_str(user).unwrap()],
+      client_keys: Vec::new(),
+      groups: BTreeMap::new(),
+    };
+    Networks::new(BTreeMap::from([
+      ("acme".to_string(), network("10.1.0.0/24", "alice:pass")),
+      ("globex".to_string(), network("10.2.0.0/24", "bob:pass")),
+    ]))
+  }
+
+  #[test]
+  fn test_by_credentials() {
+    let networks = networks();
+
+    let network = networks.by_credentials(&Credentials::from_str("bob:pass").unwrap()).unwrap();
+    assert_eq!(network.name, "globex");
+    assert!(networks.by_credentials(&Credentials::from_str("bob:wrong").unwrap()).is_none());
+  }
+
+  #[test]
+  fn test_isolation() {
+    let networks = networks();
+    let default_subnet = Some("10.0.0.0/24".parse().unwrap());
+    let allows = |from, destination: [u8; 4]| networks.allows(from, default_subnet, destination.into());
+
+    assert!(allows(Some("acme"), [10, 1, 0, 5]));
+    assert!(!allows(Some("acme"), [10, 2, 0, 5]));
+    assert!(!allows(Some("acme"), [10, 0, 0, 1]));
+    assert!(allows(Some("acme"), [1, 1, 1, 1]));
+
+    assert!(allows(None, [10, 0, 0, 5]));
+    assert!(!allows(None, [10, 1, 0, 5]));
+    assert!(allows(None, [1, 1, 1, 1]));
+  }
+}
@@ -0,0 +1,105 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+/// Offloaded packets a receive loop or a send queue has in flight at most before it waits for the oldest.
+pub const IN_FLIGHT: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct OffloadConfig {
+  /// Packets of at least this many bytes are encrypted and decrypted on the blocking pool; unset keeps all
+  /// crypto inline, which is faster for typical packet sizes. `cargo bench -p vpn-server --bench offload`
+  /// shows where offloading starts to pay off on a host.
+  pub threshold_bytes: Option<usize>,
+}
+
+impl OffloadConfig {
+  /// Starts CPU-bound `work` for a packet of `len` bytes, on the blocking pool when the packet is large enough
+  /// for that to pay off and right away otherwise. Callers keep the returned job in flight while they go on
+  /// with other packets, so offloaded work overlaps instead of being waited for one packet at a time.
+  pub fn start<T, F>(&self, len: usize, work: F) -> Offloaded<T>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+  {
+    match self.threshold_bytes {
+      Some(threshold) if len >= threshold => Offloaded(Job::Running(tokio::task::spawn_blocking(work))),
+      _ => Offloaded(Job::Done(Some(work()))),
+    }
+  }
+}
+
+/// Work started by `OffloadConfig::start`.
+#[derive(Debug)]
+pub struct Offloaded<T>(Job<T>);
+
+#[derive(Debug)]
+enum Job<T> {
+  Done(Option<T>),
+  Running(JoinHandle<T>),
+}
+
+impl<T> Offloaded<T> {
+  /// Whether the work runs on the blocking pool rather than having been done already.
+  pub fn is_running(&self) -> bool {
+    matches!(self.0, Job::Running(_))
+  }
+
+  /// Result of work that was done right away.
+  pub fn done(&self) -> Option<&T> {
+    match self.0 {
+      Job::Done(ref result) => result.as_ref(),
+      Job::Running(_) => None,
+    }
+  }
+}
+
+impl<T: Unpin> Future for Offloaded<T> {
+  type Output = anyhow::Result<T>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    match self.get_mut().0 {
+      Job::Done(ref mut result) => {
+        Poll::Ready(Ok(result.take().expect("offloaded work polled after completion")))
+      }
+      Job::Running(ref mut handle) => Pin::new(handle).poll(cx).map_err(Into::into),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_threshold() {
+    let config = OffloadConfig { threshold_bytes: Some(1024) };
+    let current = std::thread::current().id();
+
+    assert_eq!(config.start(100, move || std::thread::current().id()).await.unwrap(), current);
+    assert_ne!(config.start(1024, move || std::thread::current().id()).await.unwrap(), current);
+    assert_eq!(
+      OffloadConfig::default().start(1 << 20, move || std::thread::current().id()).await.unwrap(),
+      current
+    );
+  }
+
+  #[tokio::test]
+  async fn test_start() {
+    let config = OffloadConfig { threshold_bytes: Some(1024) };
+
+    let inline = config.start(100, || 1);
+    assert!(!inline.is_running());
+    // Jobs started together run at the same time and still complete in the order they were waited for.
+    let (tx, rx) = std::sync::mpsc::channel();
+    let first = config.start(2048, move || rx.recv().unwrap());
+    let second = config.start(2048, move || tx.send(2).is_ok());
+    assert!(first.is_running());
+    assert_eq!((inline.await.unwrap(), first.await.unwrap(), second.await.unwrap()), (1, 2, true));
+  }
+}
@@ -0,0 +1,203 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct OidcConfig {
+  /// Issuer URL; tokens must carry it in `iss`, and the signing keys are found through its discovery
+  /// document unless `jwks-url` is set.
+  pub issuer: String,
+
+  #[serde(default)]
+  pub jwks_url: Option<String>,
+
+  /// Expected `aud` of access tokens, usually the client id the VPN client uses.
+  pub audience: String,
+
+  #[serde(default = "default_username_claim")]
+  pub username_claim: String,
+
+  #[serde(default = "default_groups_claim")]
+  pub groups_claim: String,
+
+  /// Signing keys are refetched at most this often, and whenever a token names an unknown key.
+  #[serde(default = "default_jwks_refresh_secs")]
+  pub jwks_refresh_secs: u64,
+
+  /// How far the clock of the provider may be off the server's: tokens are taken this long after `exp`
+  /// and before `nbf`.
+  #[serde(default = "default_clock_skew_secs")]
+  pub clock_skew_secs: u64,
+}
+
+fn default_username_claim() -> String {
+  "preferred_username".to_string()
+}
+
+fn default_groups_claim() -> String {
+  "groups".to_string()
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+  300
+}
+
+fn default_clock_skew_secs() -> u64 {
+  60
+}
+
+#[cfg(feature = "oidc")]
+pub use store::OidcStore;
+
+#[cfg(feature = "oidc")]
+mod store {
+  use std::time::Duration;
+  use std::time::Instant;
+
+  use jsonwebtoken::jwk::JwkSet;
+  use jsonwebtoken::Algorithm;
+  use jsonwebtoken::DecodingKey;
+  use jsonwebtoken::Validation;
+  use serde::Deserialize;
+  use serde_json::Map;
+  use serde_json::Value;
+  use tokio::sync::Mutex;
+  use tracing::debug;
+  use vpn_shared::creds::Credentials;
+
+  use super::OidcConfig;
+  use crate::auth::AuthFuture;
+  use crate::auth::CredentialStore;
+  use crate::auth::Identity;
+
+  const MIN_REFETCH: Duration = Duration::from_secs(10);
+
+  #[derive(Deserialize)]
+  struct Discovery {
+    jwks_uri: String,
+  }
+
+  /// Validates access tokens offline against the provider's published signing keys.
+  pub struct OidcStore {
+    config: OidcConfig,
+    http: reqwest::Client,
+    keys: Mutex<Option<(Instant, JwkSet)>>,
+  }
+
+  impl OidcStore {
+    pub fn new(config: OidcConfig) -> Self {
+      Self { config, http: reqwest::Client::new(), keys: Mutex::new(None) }
+    }
+
+    async fn fetch_keys(&self) -> anyhow::Result<JwkSet> {
+      let url = match self.config.jwks_url {
+        Some(ref url) => url.clone(),
+        None => {
+          let discovery =
+            format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+          self.http.get(discovery).send().await?.error_for_status()?.json::<Discovery>().await?.jwks_uri
+        }
+      };
+
+      Ok(self.http.get(url).send().await?.error_for_status()?.json().await?)
+    }
+
+    async fn decoding_key(&self, kid: Option<&str>) -> anyhow::Result<Option<DecodingKey>> {
+      let mut cache = self.keys.lock().await;
+      let find = |cache: &Option<(Instant, JwkSet)>| {
+        let (_, set) = cache.as_ref()?;
+        match kid {
+          Some(kid) => set.find(kid).cloned(),
+          None => set.keys.first().cloned(),
+        }
+      };
+
+      // Unknown keys trigger a refetch to pick up rotations, throttled so made up `kid`s can't hammer
+      // the provider.
+      let age = cache.as_ref().map(|(fetched, _)| fetched.elapsed());
+      let missing = find(&cache).is_none();
+      if age.is_none_or(|age| {
+        age >= Duration::from_secs(self.config.jwks_refresh_secs) || (missing && age >= MIN_REFETCH)
+      }) {
+        debug!("Fetching OIDC signing keys of {}", self.config.issuer);
+        *cache = Some((Instant::now(), self.fetch_keys().await?));
+      }
+
+      Ok(find(&cache).map(|jwk| DecodingKey::from_jwk(&jwk)).transpose()?)
+    }
+
+    async fn validate(&self, token: &str) -> anyhow::Result<Option<Identity>> {
+      let Ok(header) = jsonwebtoken::decode_header(token) else {
+        return Ok(None);
+      };
+
+      // Symmetric algorithms would let anyone holding the public key forge tokens.
+      if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Ok(None);
+      }
+
+      let Some(key) = self.decoding_key(header.kid.as_deref()).await? else {
+        return Ok(None);
+      };
+
+      let mut validation = Validation::new(header.alg);
+      validation.set_issuer(&[&self.config.issuer]);
+      validation.set_audience(&[&self.config.audience]);
+      validation.leeway = self.config.clock_skew_secs;
+      validation.validate_nbf = true;
+
+      match jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation) {
+        Ok(data) => Ok(identity(&self.config, &data.claims)),
+        Err(e) => {
+          debug!("Rejected OIDC token: {}", e);
+          Ok(None)
+        }
+      }
+    }
+  }
+
+  impl CredentialStore for OidcStore {
+    fn name(&self) -> &str {
+      "oidc"
+    }
+
+    fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a> {
+      let Credentials::Token(token) = credentials else {
+        return Box::pin(async { Ok(None) });
+      };
+      Box::pin(self.validate(token))
+    }
+  }
+
+  fn identity(config: &OidcConfig, claims: &Map<String, Value>) -> Option<Identity> {
+    let username = claims.get(&config.username_claim)?.as_str()?.to_string();
+    let groups = match claims.get(&config.groups_claim) {
+      Some(Value::Array(groups)) => groups.iter().filter_map(|g| g.as_str().map(str::to_string)).collect(),
+      Some(Value::String(group)) => vec![group.clone()],
+      _ => Vec::new(),
+    };
+
+    Some(Identity { username, groups })
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config() -> OidcConfig {
+      serde_yml::from_str("{ issuer: 'https://idp.example.com', audience: 'vpn' }").unwrap()
+    }
+
+    #[test]
+    fn test_identity_from_claims() {
+      let claims = json!({ "preferred_username": "alice", "groups": ["staff", "admins"] });
+      let alice = identity(&config(), claims.as_object().unwrap()).unwrap();
+      assert_eq!(alice.username, "alice");
+      assert_eq!(alice.groups, vec!["staff", "admins"]);
+
+      let claims = json!({ "sub": "1234", "groups": "staff" });
+      assert!(identity(&config(), claims.as_object().unwrap()).is_none());
+    }
+  }
+}
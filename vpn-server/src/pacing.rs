@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use tracing::error;
+use vpn_shared::rate::TokenBucket;
+use vpn_shared::socket::Socket;
+
+use crate::metrics::Metrics;
+use crate::offload::Offloaded;
+
+pub type SendQueue = mpsc::Sender<Queued>;
+
+/// Datagram waiting in the send queue of a client; it may still be being sealed on the blocking pool, which
+/// lets the tun reader go on to the next packet meanwhile.
+#[derive(Debug)]
+pub struct Queued {
+  pub datagram: Offloaded<anyhow::Result<Vec<u8>>>,
+  /// Bytes of the `Data` packet sealed into the datagram, zero for other packets.
+  pub payload_len: usize,
+  /// ECN field for the outer header of the datagram.
+  pub outer_ecn: u8,
+  pub at: Instant,
+}
+
+const BURST_WINDOW: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PacingConfig {
+  pub packets_per_sec: Option<u32>,
+  pub bytes_per_sec: Option<u64>,
+  pub queue_depth: usize,
+  pub red: Option<RedConfig>,
+  /// Rates groups put their members' sessions in with `bandwidth-class`, on top of the ones above.
+  pub classes: BTreeMap<String, BandwidthClass>,
+}
+
+impl Default for PacingConfig {
+  fn default() -> Self {
+    Self { packets_per_sec: None, bytes_per_sec: None, queue_depth: 256, red: None, classes: BTreeMap::new() }
+  }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct BandwidthClass {
+  pub packets_per_sec: Option<u32>,
+  pub bytes_per_sec: Option<u64>,
+}
+
+/// Random early detection on the send queue of each client: once a queue is filled past `min-fill-pct`,
+/// tun packets towards that client are marked as congested, or dropped when they aren't ECN-capable, with
+/// a probability rising to `max-probability-pct` at `max-fill-pct`. Beyond that every packet is dropped.
+/// Inner TCP flows back off this way instead of filling the queue until it overflows.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RedConfig {
+  pub min_fill_pct: u8,
+  pub max_fill_pct: u8,
+  pub max_probability_pct: u8,
+}
+
+impl Default for RedConfig {
+  fn default() -> Self {
+    Self { min_fill_pct: 50, max_fill_pct: 90, max_probability_pct: 10 }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+  Send,
+  Mark,
+  Drop,
+}
+
+impl RedConfig {
+  /// `fill` and `roll` are in `0.0..=1.0`; `roll` is a uniformly random number.
+  pub fn verdict(&self, fill: f64, ecn_capable: bool, roll: f64) -> Verdict {
+    let (min, max) = (self.min_fill_pct as f64 / 100.0, self.max_fill_pct as f64 / 100.0);
+    if fill < min {
+      return Verdict::Send;
+    }
+    if fill >= max {
+      return Verdict::Drop;
+    }
+
+    let probability = self.max_probability_pct as f64 / 100.0 * (fill - min) / (max - min);
+    match (roll < probability, ecn_capable) {
+      (false, _) => Verdict::Send,
+      (true, true) => Verdict::Mark,
+      (true, false) => Verdict::Drop,
+    }
+  }
+}
+
+impl PacingConfig {
+  fn bucket(rate: f64) -> TokenBucket {
+    TokenBucket::new(rate, (rate * BURST_WINDOW.as_secs_f64()).max(1.0))
+  }
+
+  /// Pacing of a session in the most generous of `classes`, see `Policy::bandwidth_classes`; unknown classes
+  /// are left out.
+  pub fn for_classes(&self, classes: &[String]) -> PacingConfig {
+    let class = classes.iter().filter_map(|name| self.classes.get(name)).max_by_key(|class| {
+      (class.bytes_per_sec.unwrap_or(u64::MAX), class.packets_per_sec.unwrap_or(u32::MAX))
+    });
+    let Some(class) = class else {
+      return self.clone();
+    };
+    let lower = |a: Option<u64>, b: Option<u64>| match (a, b) {
+      (Some(a), Some(b)) => Some(a.min(b)),
+      (a, b) => a.or(b),
+    };
+    PacingConfig {
+      packets_per_sec: lower(self.packets_per_sec.map(u64::from), class.packets_per_sec.map(u64::from))
+        .map(|rate| rate as u32),
+      bytes_per_sec: lower(self.bytes_per_sec, class.bytes_per_sec),
+      ..self.clone()
+    }
+  }
+}
+
+/// Spawns the outbound queue of a single client. Packets are sent in order, smoothed to the configured
+/// rates; the task ends once the returned sender is dropped and the queue is drained.
+pub fn spawn_send_queue(
+  socket: Arc<Socket>,
+  addr: SocketAddr,
+  local: Option<Ipv4Addr>,
+  config: &PacingConfig,
+  metrics: Arc<Metrics>,
+) -> SendQueue {
+  let (tx, mut rx): (SendQueue, _) = mpsc::channel(config.queue_depth.max(1));
+
+  let mut packets = config.packets_per_sec.map(|rate| PacingConfig::bucket(rate as f64));
+  let mut bytes = config.bytes_per_sec.map(|rate| PacingConfig::bucket(rate as f64));
+
+  tokio::spawn(async move {
+    while let Some(Queued { datagram, payload_len, outer_ecn, at }) = rx.recv().await {
+      metrics.send_queue.dequeued(at);
+      let packet = match datagram.await.and_then(|datagram| datagram) {
+        Ok(packet) => packet,
+        Err(e) => {
+          error!("Failed to seal packet to {}: {}", addr, e);
+          continue;
+        }
+      };
+      if payload_len > 0 {
+        metrics.record_data(payload_len, packet.len());
+      }
+
+      let delay = Duration::max(
+        packets.as_mut().map(|bucket| bucket.take(1.0)).unwrap_or_default(),
+        bytes.as_mut().map(|bucket| bucket.take(packet.len() as f64)).unwrap_or_default(),
+      );
+
+      if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+      }
+
+      if let Err(e) = socket.send_from(&packet, addr, outer_ecn, local).await {
+        error!("Failed to send packet to {}: {}", addr, e);
+      }
+    }
+  });
+
+  tx
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_red_verdict() {
+    let red = RedConfig::default();
+
+    assert_eq!(red.verdict(0.3, false, 0.0), Verdict::Send);
+    assert_eq!(red.verdict(0.95, true, 0.99), Verdict::Drop);
+
+    // Halfway between the thresholds half of the maximum probability applies.
+    assert_eq!(red.verdict(0.7, true, 0.04), Verdict::Mark);
+    assert_eq!(red.verdict(0.7, false, 0.04), Verdict::Drop);
+    assert_eq!(red.verdict(0.7, true, 0.06), Verdict::Send);
+  }
+
+  #[test]
+  fn test_classes() {
+    let config = PacingConfig {
+      bytes_per_sec: Some(1_000_000),
+      classes: BTreeMap::from([
+        ("slow".to_string(), BandwidthClass { bytes_per_sec: Some(100_000), packets_per_sec: Some(100) }),
+        ("fast".to_string(), BandwidthClass { bytes_per_sec: Some(10_000_000), packets_per_sec: None }),
+      ]),
+      ..Default::default()
+    };
+
+    let slow = config.for_classes(&["slow".into(), "unknown".into()]);
+    assert_eq!((slow.bytes_per_sec, slow.packets_per_sec), (Some(100_000), Some(100)));
+    // The server-wide rate still caps the faster class.
+    let fast = config.for_classes(&["slow".into(), "fast".into()]);
+    assert_eq!((fast.bytes_per_sec, fast.packets_per_sec), (Some(1_000_000), None));
+    assert_eq!(config.for_classes(&[]), config);
+  }
+}
@@ -0,0 +1,252 @@
+//! Passwords users change themselves with `vpn-client change-password`, kept hashed in a JSON file that's
+//! re-read on every use, so `vpn-server set-password` takes effect on a running server too.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use tracing::info;
+use vpn_shared::creds::Credentials;
+use vpn_shared::handshake;
+use vpn_shared::packet::fill_random_bytes;
+
+use crate::auth::AuthFuture;
+use crate::auth::CredentialStore;
+use crate::auth::Identity;
+use crate::auth::Lookup;
+use crate::auth::LookupFuture;
+use crate::auth::PrepareFuture;
+
+/// PBKDF2 rounds of newly set passwords; entries keep their own, so raising it only affects later changes.
+const ITERATIONS: u32 = 100_000;
+
+pub const MIN_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct Entry {
+  salt: String,
+  hash: String,
+  iterations: u32,
+  /// Milliseconds since the Unix epoch; tickets issued before are no longer accepted.
+  changed_at: u64,
+}
+
+impl Entry {
+  fn new(password: &str) -> Self {
+    let mut salt = [0u8; 32];
+    fill_random_bytes(&mut salt);
+    Self {
+      salt: handshake::encode_key(&salt),
+      hash: handshake::encode_key(&pbkdf2(password, &salt, ITERATIONS)),
+      iterations: ITERATIONS,
+      changed_at: now_millis(),
+    }
+  }
+
+  fn verify(&self, password: &str) -> bool {
+    let (Ok(salt), Ok(hash)) = (handshake::parse_hex(&self.salt), handshake::parse_key(&self.hash)) else {
+      return false;
+    };
+    handshake::keys_match(&pbkdf2(password, &salt, self.iterations), &hash)
+  }
+}
+
+/// Why a password wasn't changed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChangeError {
+  /// The user isn't in the file or the current password is wrong.
+  Denied,
+  TooShort,
+}
+
+pub struct PasswordFile {
+  path: PathBuf,
+  /// Serializes changes, so that concurrent ones don't overwrite each other.
+  writing: Mutex<()>,
+}
+
+impl PasswordFile {
+  pub fn new(path: PathBuf) -> Self {
+    Self { path, writing: Mutex::new(()) }
+  }
+
+  pub async fn verify(&self, username: &str, password: &str) -> anyhow::Result<bool> {
+    let Some(entry) = self.load().await?.remove(username) else {
+      return Ok(false);
+    };
+    let password = password.to_string();
+    Ok(tokio::task::spawn_blocking(move || entry.verify(&password)).await?)
+  }
+
+  /// When the password of `username` was last set, in milliseconds since the Unix epoch.
+  pub async fn changed_at(&self, username: &str) -> anyhow::Result<Option<u64>> {
+    Ok(self.load().await?.get(username).map(|entry| entry.changed_at))
+  }
+
+  /// Sets the password of `username`, adding the user if they aren't in the file yet.
+  pub async fn set(&self, username: &str, password: &str) -> anyhow::Result<()> {
+    if password.len() < MIN_LENGTH {
+      anyhow::bail!("Passwords have to be at least {} characters long", MIN_LENGTH);
+    }
+    let _writing = self.writing.lock().await;
+    let mut users = self.load().await?;
+    let password = password.to_string();
+    users.insert(username.to_string(), tokio::task::spawn_blocking(move || Entry::new(&password)).await?);
+    self.save(&users).await
+  }
+
+  /// Replaces the password of `username` if `old` is the current one.
+  pub async fn change(
+    &self,
+    username: &str,
+    old: &str,
+    new: &str,
+  ) -> anyhow::Result<Result<(), ChangeError>> {
+    if new.len() < MIN_LENGTH {
+      return Ok(Err(ChangeError::TooShort));
+    }
+    let _writing = self.writing.lock().await;
+    let mut users = self.load().await?;
+    let Some(entry) = users.get(username).cloned() else {
+      return Ok(Err(ChangeError::Denied));
+    };
+
+    let (old, new) = (old.to_string(), new.to_string());
+    let Some(changed) =
+      tokio::task::spawn_blocking(move || entry.verify(&old).then(|| Entry::new(&new))).await?
+    else {
+      return Ok(Err(ChangeError::Denied));
+    };
+    users.insert(username.to_string(), changed);
+    self.save(&users).await?;
+    Ok(Ok(()))
+  }
+
+  async fn load(&self) -> anyhow::Result<BTreeMap<String, Entry>> {
+    match tokio::fs::read_to_string(&self.path).await {
+      Ok(contents) => Ok(serde_json::from_str(&contents)?),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+      Err(e) => Err(anyhow::anyhow!("Failed to read {}: {}", self.path.display(), e)),
+    }
+  }
+
+  /// Writes a temporary file and renames it over the old one, so the file is either all old or all new.
+  async fn save(&self, users: &BTreeMap<String, Entry>) -> anyhow::Result<()> {
+    let temporary = self.path.with_extension("tmp");
+    let file = tokio::fs::File::create(&temporary).await?;
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt as _;
+      file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    let mut file = file;
+    tokio::io::AsyncWriteExt::write_all(&mut file, serde_json::to_string_pretty(users)?.as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temporary, &self.path).await?;
+    Ok(())
+  }
+}
+
+impl CredentialStore for PasswordFile {
+  fn name(&self) -> &str {
+    "password file"
+  }
+
+  fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a> {
+    Box::pin(async move {
+      let Credentials::Password { username, password } = credentials else {
+        return Ok(None);
+      };
+      let identity = Identity { username: username.clone(), groups: Vec::new() };
+      Ok(self.verify(username, password).await?.then_some(identity))
+    })
+  }
+
+  /// Reads the file once, so that one that doesn't parse stops the server from starting rather than
+  /// failing every login.
+  fn prepare(&self) -> PrepareFuture<'_> {
+    Box::pin(async move {
+      let users = self.load().await?;
+      info!("Loaded {} user(s) from {}", users.len(), self.path.display());
+      Ok(())
+    })
+  }
+
+  fn lookup<'a>(&'a self, username: &'a str) -> LookupFuture<'a> {
+    Box::pin(async move {
+      Ok(match self.load().await?.contains_key(username) {
+        true => Lookup::Active(Identity { username: username.to_string(), groups: Vec::new() }),
+        false => Lookup::Absent,
+      })
+    })
+  }
+}
+
+/// PBKDF2-HMAC-SHA256 with a single block of output.
+fn pbkdf2(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+  let mac = Hmac::<Sha256>::new_from_slice(password.as_bytes()).expect("HMAC accepts keys of any size");
+  let mut block = mac.clone();
+  block.update(salt);
+  block.update(&1u32.to_be_bytes());
+  let mut round: [u8; 32] = block.finalize().into_bytes().into();
+  let mut output = round;
+  for _ in 1..iterations {
+    let mut block = mac.clone();
+    block.update(&round);
+    round = block.finalize().into_bytes().into();
+    output.iter_mut().zip(round).for_each(|(output, byte)| *output ^= byte);
+  }
+  output
+}
+
+pub fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pbkdf2() {
+    // RFC 7914, section 11.
+    let expected = "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc";
+    assert_eq!(handshake::encode_key(&pbkdf2("passwd", b"salt", 1)), expected);
+  }
+
+  #[tokio::test]
+  async fn test_change() {
+    let path = std::env::temp_dir().join(format!("vpn-passwords-{}.json", std::process::id()));
+    let passwords = PasswordFile::new(path.clone());
+    assert!(!passwords.verify("alice", "hunter22").await.unwrap());
+    assert!(passwords.set("alice", "short").await.is_err());
+
+    passwords.set("alice", "hunter22").await.unwrap();
+    assert!(passwords.verify("alice", "hunter22").await.unwrap());
+    let set_at = passwords.changed_at("alice").await.unwrap().unwrap();
+
+    assert_eq!(passwords.change("alice", "wrong", "correct horse").await.unwrap(), Err(ChangeError::Denied));
+    assert_eq!(passwords.change("bob", "hunter22", "correct horse").await.unwrap(), Err(ChangeError::Denied));
+    assert_eq!(passwords.change("alice", "hunter22", "short").await.unwrap(), Err(ChangeError::TooShort));
+    assert_eq!(passwords.change("alice", "hunter22", "correct horse").await.unwrap(), Ok(()));
+
+    // Another instance, e.g. the running server after `set-password`, sees the change.
+    let reopened = PasswordFile::new(path.clone());
+    assert!(!reopened.verify("alice", "hunter22").await.unwrap());
+    assert!(reopened.verify("alice", "correct horse").await.unwrap());
+    assert!(reopened.changed_at("alice").await.unwrap().unwrap() >= set_at);
+    assert!(
+      matches!(reopened.lookup("alice").await.unwrap(), Lookup::Active(identity) if identity.username == "alice")
+    );
+    assert_eq!(reopened.lookup("bob").await.unwrap(), Lookup::Absent);
+    std::fs::remove_file(path).unwrap();
+  }
+}
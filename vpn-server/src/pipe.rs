@@ -0,0 +1,113 @@
+//! Packet pipe for applications embedding the server; the server binary itself has no use for it.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+
+use tracing::trace;
+use vpn_shared::logging;
+
+const QUEUE_DEPTH: usize = 1024;
+
+/// Packet a client sent through the tunnel, see `Server::client_packets`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionPacket {
+  /// Address of the session the packet came from, a key of `Server::clients`.
+  pub client: SocketAddr,
+  pub packet: Vec<u8>,
+}
+
+/// Sends packets to the clients of a server built `with_packet_pipe`, see `Server::packet_handle`.
+#[derive(Clone)]
+pub struct ServerHandle {
+  to_clients: mpsc::Sender<Vec<u8>>,
+}
+
+impl ServerHandle {
+  /// Sends an IPv4 packet to the client its destination is leased to, as if it came out of a tun device;
+  /// waits while the server is behind with earlier ones.
+  pub async fn send_ip_packet(&self, packet: Vec<u8>) -> anyhow::Result<()> {
+    self.to_clients.send(packet).await.map_err(|_| anyhow::anyhow!("Server stopped"))
+  }
+}
+
+/// Stands in for the tun device of servers embedded in applications that route packets of clients
+/// themselves, e.g. to serve something only reachable over the VPN.
+pub struct PacketPipe {
+  pub mtu: u16,
+  handle: ServerHandle,
+  from_clients: mpsc::Sender<SessionPacket>,
+  /// Taken by the application with `Server::client_packets`.
+  receiver: Option<mpsc::Receiver<SessionPacket>>,
+  to_clients: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl PacketPipe {
+  pub fn new(mtu: u16) -> Self {
+    let (handle, to_clients) = mpsc::channel(QUEUE_DEPTH);
+    let (from_clients, receiver) = mpsc::channel(QUEUE_DEPTH);
+    Self {
+      mtu,
+      handle: ServerHandle { to_clients: handle },
+      from_clients,
+      receiver: Some(receiver),
+      to_clients: Mutex::new(to_clients),
+    }
+  }
+
+  pub fn handle(&self) -> ServerHandle {
+    self.handle.clone()
+  }
+
+  pub fn take_receiver(&mut self) -> Option<mpsc::Receiver<SessionPacket>> {
+    self.receiver.take()
+  }
+
+  /// Passes a packet of the client at `addr` to the application; false if it was dropped because the
+  /// application is behind or no longer listening.
+  pub fn send(&self, addr: SocketAddr, packet: &[u8]) -> bool {
+    self.from_clients.try_send(SessionPacket { client: addr, packet: packet.to_vec() }).is_ok()
+  }
+
+  /// Waits for the next packet the application sends to a client.
+  pub async fn recv(&self, buf: &mut [u8]) -> usize {
+    loop {
+      // The pipe holds a handle itself, so the channel stays open.
+      let packet = self.to_clients.lock().await.recv().await.expect("handle is kept");
+      if packet.len() > buf.len() {
+        trace!(target: logging::TUN, "Dropping packet larger than the MTU from the application; len: {}", packet.len());
+        continue;
+      }
+      buf[..packet.len()].copy_from_slice(&packet);
+      return packet.len();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_pipe() {
+    let mut pipe = PacketPipe::new(4);
+    let mut receiver = pipe.take_receiver().unwrap();
+    assert!(pipe.take_receiver().is_none());
+
+    let addr = "127.0.0.1:6969".parse().unwrap();
+    assert!(pipe.send(addr, &[0x45, 1]));
+    assert_eq!(receiver.recv().await.unwrap(), SessionPacket { client: addr, packet: vec![0x45, 1] });
+
+    // Larger than the MTU, so skipped.
+    pipe.handle().send_ip_packet(vec![0x45; 5]).await.unwrap();
+    pipe.handle().send_ip_packet(vec![0x45, 2]).await.unwrap();
+    let mut buf = [0u8; 4];
+    let len = pipe.recv(&mut buf).await;
+    assert_eq!(&buf[..len], [0x45, 2]);
+
+    drop(receiver);
+    assert!(!pipe.send(addr, &[0x45, 3]));
+  }
+}
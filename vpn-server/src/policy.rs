@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct GroupPolicy {
+  #[serde(default)]
+  pub members: Vec<String>,
+
+  /// Destinations members may reach through the tunnel; empty means everything.
+  #[serde(default)]
+  pub acl: Vec<Ipv4Net>,
+
+  /// Routes pushed to members' clients, which send traffic to them through the tunnel.
+  #[serde(default)]
+  pub routes: Vec<Ipv4Net>,
+
+  /// Resolvers members are handed along with a leased address instead of the ones of the pool, unless the
+  /// internal DNS answers them.
+  #[serde(default)]
+  pub dns: Vec<Ipv4Addr>,
+
+  /// Class of `pacing.classes` whose rates members' sessions are sent at.
+  #[serde(default)]
+  pub bandwidth_class: Option<String>,
+
+  #[serde(default)]
+  pub quota_mb: Option<u64>,
+
+  /// Groups reported by a credential store, e.g. LDAP, whose users are members of this group too.
+  #[serde(default)]
+  pub directory_groups: Vec<String>,
+
+  #[serde(default)]
+  pub priority: Priority,
+
+  /// LANs behind their clients members may register to be routed to them, turning the client into a
+  /// gateway for another site; a registered subnet has to be within one of these.
+  #[serde(default)]
+  pub subnets: Vec<Ipv4Net>,
+
+  /// Ports of the server members may ask to have forwarded to their client, see
+  /// `ClientPacket::RegisterForwards`.
+  #[serde(default)]
+  pub forward_ports: Vec<u16>,
+
+  /// Refuse key exchanges from the address of a member's established session, which would replace it,
+  /// unless the client announced it's about to make one with `ClientPacket::Rehandshake`; the default.
+  /// Turning it off for any of a user's groups lets anyone able to send from that address end the session,
+  /// in exchange for clients that restarted on the same port not waiting for it to time out.
+  #[serde(default)]
+  pub strict_handshakes: Option<bool>,
+}
+
+/// With `preemption`, a high-priority user logging in to a full server ends a normal session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+  #[default]
+  Normal,
+  High,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PreemptionConfig {
+  /// Sessions that sent data more recently than this are never preempted.
+  #[serde(default)]
+  pub min_idle_secs: u64,
+}
+
+impl PreemptionConfig {
+  pub fn min_idle(&self) -> Duration {
+    Duration::from_secs(self.min_idle_secs)
+  }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Policies {
+  groups: BTreeMap<String, GroupPolicy>,
+}
+
+/// Shares of the data quota, in percent, users are warned about reaching.
+pub const QUOTA_WARNINGS: [u8; 2] = [80, 95];
+
+/// Effective policy of a single user, merged from all groups they're a member of.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+  pub groups: Vec<String>,
+  pub acl: Vec<Ipv4Net>,
+  pub routes: Vec<Ipv4Net>,
+  pub dns: Vec<Ipv4Addr>,
+  /// Bandwidth classes of the groups, the most generous of which paces the sessions of the user; empty when
+  /// one of the groups has none.
+  pub bandwidth_classes: Vec<String>,
+  pub quota_bytes: Option<u64>,
+  pub priority: Priority,
+  pub subnets: Vec<Ipv4Net>,
+  pub forward_ports: Vec<u16>,
+  pub strict_handshakes: bool,
+}
+
+impl Policies {
+  pub fn new(groups: BTreeMap<String, GroupPolicy>) -> Self {
+    Self { groups }
+  }
+
+  /// Policy of `username` as a member of `assigned` besides the groups listing them, see `authz`.
+  pub fn resolve(&self, username: &str, directory_groups: &[String], assigned: &[String]) -> Policy {
+    let groups: Vec<_> = self
+      .groups
+      .iter()
+      .filter(|(name, group)| {
+        assigned.contains(name)
+          || group.members.iter().any(|member| member == username)
+          || group.directory_groups.iter().any(|group| directory_groups.contains(group))
+      })
+      .collect();
+
+    let unrestricted = groups.iter().any(|(_, group)| group.acl.is_empty());
+    let unlimited = groups.iter().any(|(_, group)| group.quota_mb.is_none());
+    let unpaced = groups.iter().any(|(_, group)| group.bandwidth_class.is_none());
+    let mut routes: Vec<_> = groups.iter().flat_map(|(_, group)| group.routes.iter().copied()).collect();
+    routes.sort();
+    routes.dedup();
+    let mut dns = Vec::new();
+    for address in groups.iter().flat_map(|(_, group)| group.dns.iter()) {
+      if !dns.contains(address) {
+        dns.push(*address);
+      }
+    }
+
+    Policy {
+      groups: groups.iter().map(|(name, _)| name.to_string()).collect(),
+      acl: match unrestricted {
+        true => Vec::new(),
+        false => groups.iter().flat_map(|(_, group)| group.acl.iter().copied()).collect(),
+      },
+      routes,
+      dns,
+      bandwidth_classes: match unpaced {
+        true => Vec::new(),
+        false => groups.iter().filter_map(|(_, group)| group.bandwidth_class.clone()).collect(),
+      },
+      quota_bytes: match unlimited {
+        true => None,
+        false => groups.iter().filter_map(|(_, group)| group.quota_mb).max().map(|mb| mb * 1024 * 1024),
+      },
+      priority: groups.iter().map(|(_, group)| group.priority).max().unwrap_or_default(),
+      subnets: groups.iter().flat_map(|(_, group)| group.subnets.iter().copied()).collect(),
+      forward_ports: groups.iter().flat_map(|(_, group)| group.forward_ports.iter().copied()).collect(),
+      strict_handshakes: !groups.iter().any(|(_, group)| group.strict_handshakes == Some(false)),
+    }
+  }
+}
+
+impl Policy {
+  pub fn allows(&self, destination: Ipv4Addr) -> bool {
+    self.acl.is_empty() || self.acl.iter().any(|net| net.contains(&destination))
+  }
+
+  /// Whether the ACL lets the user reach all of `subnet`.
+  pub fn may_reach(&self, subnet: &Ipv4Net) -> bool {
+    self.acl.is_empty() || self.acl.iter().any(|net| net.contains(subnet))
+  }
+
+  /// Whether the user may have `subnet` routed to their client.
+  pub fn may_route(&self, subnet: &Ipv4Net) -> bool {
+    self.subnets.iter().any(|net| net.contains(subnet))
+  }
+
+  /// Whether the user may have `port` of the server forwarded to their client.
+  pub fn may_forward(&self, port: u16) -> bool {
+    self.forward_ports.contains(&port)
+  }
+
+  pub fn is_over_quota(&self, used_bytes: u64) -> bool {
+    self.quota_bytes.is_some_and(|quota| used_bytes >= quota)
+  }
+
+  /// Highest of `QUOTA_WARNINGS` that `used_bytes` reaches.
+  pub fn quota_warning(&self, used_bytes: u64) -> Option<u8> {
+    let quota = self.quota_bytes? as u128;
+    QUOTA_WARNINGS.into_iter().rev().find(|percent| used_bytes as u128 * 100 >= quota * *percent as u128)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policies() -> Policies {
+    Policies::new(BTreeMap::from([
+      (
+        "staff".to_string(),
+        GroupPolicy {
+          members: vec!["alice".into(), "bob".into()],
+          acl: vec!["10.10.0.0/16".parse().unwrap()],
+          routes: vec!["10.10.0.0/16".parse().unwrap()],
+          dns: vec![Ipv4Addr::new(10, 10, 0, 53)],
+          bandwidth_class: Some("standard".into()),
+          quota_mb: Some(100),
+          ..Default::default()
+        },
+      ),
+      (
+        "ops".to_string(),
+        GroupPolicy {
+          members: vec!["bob".into()],
+          acl: vec!["10.20.0.0/16".parse().unwrap()],
+          routes: vec!["10.20.0.0/16".parse().unwrap(), "10.10.0.0/16".parse().unwrap()],
+          dns: vec![Ipv4Addr::new(10, 20, 0, 53), Ipv4Addr::new(10, 10, 0, 53)],
+          bandwidth_class: Some("fast".into()),
+          quota_mb: Some(200),
+          directory_groups: vec!["ops-team".into()],
+          priority: Priority::High,
+          subnets: vec!["192.168.0.0/16".parse().unwrap()],
+          forward_ports: vec![8080],
+          strict_handshakes: Some(false),
+        },
+      ),
+      ("admins".to_string(), GroupPolicy { members: vec!["root".into()], ..Default::default() }),
+    ]))
+  }
+
+  #[test]
+  fn test_ungrouped_user_is_unrestricted() {
+    let policy = policies().resolve("dave", &[], &[]);
+
+    assert!(policy.groups.is_empty());
+    assert!(policy.allows(Ipv4Addr::new(8, 8, 8, 8)));
+    assert!(!policy.is_over_quota(u64::MAX));
+    assert_eq!(policy.quota_warning(u64::MAX), None);
+  }
+
+  #[test]
+  fn test_quota_warning() {
+    let policy = Policy { quota_bytes: Some(1000), ..Default::default() };
+
+    assert_eq!(policy.quota_warning(799), None);
+    assert_eq!(policy.quota_warning(800), Some(80));
+    assert_eq!(policy.quota_warning(950), Some(95));
+    assert_eq!(policy.quota_warning(2000), Some(95));
+  }
+
+  #[test]
+  fn test_single_group() {
+    let policy = policies().resolve("alice", &[], &[]);
+
+    assert_eq!(policy.groups, vec!["staff"]);
+    assert!(policy.allows(Ipv4Addr::new(10, 10, 1, 1)));
+    assert!(!policy.allows(Ipv4Addr::new(10, 20, 1, 1)));
+    assert!(policy.is_over_quota(100 * 1024 * 1024));
+  }
+
+  #[test]
+  fn test_groups_are_merged() {
+    let policy = policies().resolve("bob", &[], &[]);
+
+    assert_eq!(policy.groups, vec!["ops", "staff"]);
+    assert!(policy.allows(Ipv4Addr::new(10, 10, 1, 1)));
+    assert!(policy.allows(Ipv4Addr::new(10, 20, 1, 1)));
+    assert_eq!(policy.quota_bytes, Some(200 * 1024 * 1024));
+    assert_eq!(policy.priority, Priority::High);
+    assert_eq!(policies().resolve("alice", &[], &[]).priority, Priority::Normal);
+    assert!(policy.may_route(&"192.168.10.0/24".parse().unwrap()));
+    assert!(!policy.may_route(&"10.0.0.0/8".parse().unwrap()));
+    assert!(!policies().resolve("alice", &[], &[]).may_route(&"192.168.10.0/24".parse().unwrap()));
+    assert!(policy.may_forward(8080));
+    assert!(!policy.may_forward(8081));
+    assert!(!policies().resolve("alice", &[], &[]).may_forward(8080));
+    assert!(!policy.strict_handshakes);
+    assert!(policies().resolve("alice", &[], &[]).strict_handshakes);
+    assert_eq!(policy.routes, vec!["10.10.0.0/16".parse().unwrap(), "10.20.0.0/16".parse().unwrap()]);
+    assert_eq!(policy.dns, vec![Ipv4Addr::new(10, 20, 0, 53), Ipv4Addr::new(10, 10, 0, 53)]);
+    assert_eq!(policy.bandwidth_classes, vec!["fast", "standard"]);
+    // Root's group has no class, so root isn't paced.
+    assert!(policies().resolve("root", &[], &["staff".into()]).bandwidth_classes.is_empty());
+  }
+
+  #[test]
+  fn test_directory_groups() {
+    let policy = policies().resolve("erin", &["ops-team".into(), "unmapped".into()], &[]);
+
+    assert_eq!(policy.groups, vec!["ops"]);
+    assert!(policy.allows(Ipv4Addr::new(10, 20, 1, 1)));
+    assert!(!policy.allows(Ipv4Addr::new(10, 10, 1, 1)));
+  }
+
+  #[test]
+  fn test_unrestricted_group() {
+    let policy = policies().resolve("root", &[], &[]);
+
+    assert!(policy.allows(Ipv4Addr::new(8, 8, 8, 8)));
+    assert_eq!(policy.quota_bytes, None);
+  }
+
+  #[test]
+  fn test_assigned_groups() {
+    let policy = policies().resolve("dave", &[], &["ops".into()]);
+
+    assert_eq!(policy.groups, vec!["ops"]);
+    assert!(policy.allows(Ipv4Addr::new(10, 20, 1, 1)));
+  }
+}
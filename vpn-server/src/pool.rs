@@ -0,0 +1,126 @@
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AddressPoolConfig {
+  /// Addresses handed to clients; the server's own tun address is never handed out.
+  pub subnet: Ipv4Net,
+
+  /// Resolvers clients are told to use while connected.
+  #[serde(default)]
+  pub dns: Vec<Ipv4Addr>,
+}
+
+/// What happens to clients authenticating while every address of their pool is leased.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PoolExhaustionConfig {
+  /// Refused clients are told to come back after this long.
+  #[serde(default = "default_retry_after_secs")]
+  pub retry_after_secs: u32,
+
+  /// Take the address of the session that has been silent the longest, if for at least this long, rather
+  /// than refuse the client; its owner has likely gone without disconnecting.
+  #[serde(default)]
+  pub reclaim_idle_secs: Option<u64>,
+}
+
+fn default_retry_after_secs() -> u32 {
+  30
+}
+
+impl Default for PoolExhaustionConfig {
+  fn default() -> Self {
+    Self { retry_after_secs: default_retry_after_secs(), reclaim_idle_secs: None }
+  }
+}
+
+/// Addresses leased to connected clients, so they need no static tun configuration.
+pub struct AddressPool {
+  subnet: Ipv4Net,
+  pub dns: Vec<Ipv4Addr>,
+  reserved: Option<Ipv4Addr>,
+  leased: Mutex<BTreeSet<Ipv4Addr>>,
+}
+
+impl AddressPool {
+  /// Hands out the hosts of the subnet except `reserved`, or the first host without one.
+  pub fn new(config: AddressPoolConfig, reserved: Option<Ipv4Addr>) -> Self {
+    let reserved = reserved.or(config.subnet.hosts().next());
+    Self { subnet: config.subnet, dns: config.dns, reserved, leased: Mutex::default() }
+  }
+
+  pub fn subnet(&self) -> Ipv4Net {
+    self.subnet
+  }
+
+  /// Lowest free address, or `None` when every address is leased.
+  pub fn lease(&self) -> Option<Ipv4Addr> {
+    let mut leased = self.leased.lock().unwrap();
+    let address =
+      self.subnet.hosts().find(|address| Some(*address) != self.reserved && !leased.contains(address))?;
+    leased.insert(address);
+    Some(address)
+  }
+
+  /// Leases `address` itself if it's free, for a session resuming with the address it had.
+  pub fn lease_at(&self, address: Ipv4Addr) -> bool {
+    let is_host = self.subnet.hosts().any(|host| host == address);
+    is_host && Some(address) != self.reserved && self.leased.lock().unwrap().insert(address)
+  }
+
+  pub fn release(&self, address: Ipv4Addr) {
+    self.leased.lock().unwrap().remove(&address);
+  }
+
+  /// Addresses leased at the moment, lowest first.
+  pub fn leased(&self) -> Vec<Ipv4Addr> {
+    self.leased.lock().unwrap().iter().copied().collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_lease() {
+    let config = AddressPoolConfig { subnet: "10.8.0.0/30".parse().unwrap(), dns: Vec::new() };
+    let pool = AddressPool::new(config, None);
+
+    assert_eq!(pool.lease(), Some(Ipv4Addr::new(10, 8, 0, 2)));
+    assert_eq!(pool.lease(), None);
+    assert_eq!(pool.leased(), [Ipv4Addr::new(10, 8, 0, 2)]);
+
+    pool.release(Ipv4Addr::new(10, 8, 0, 2));
+    assert!(pool.leased().is_empty());
+    assert_eq!(pool.lease(), Some(Ipv4Addr::new(10, 8, 0, 2)));
+  }
+
+  #[test]
+  fn test_reserved_address() {
+    let config = AddressPoolConfig { subnet: "10.8.0.0/29".parse().unwrap(), dns: Vec::new() };
+    let pool = AddressPool::new(config, Some(Ipv4Addr::new(10, 8, 0, 2)));
+
+    assert_eq!(pool.lease(), Some(Ipv4Addr::new(10, 8, 0, 1)));
+    assert_eq!(pool.lease(), Some(Ipv4Addr::new(10, 8, 0, 3)));
+  }
+
+  #[test]
+  fn test_lease_at() {
+    let config = AddressPoolConfig { subnet: "10.8.0.0/29".parse().unwrap(), dns: Vec::new() };
+    let pool = AddressPool::new(config, None);
+
+    assert!(pool.lease_at(Ipv4Addr::new(10, 8, 0, 4)));
+    assert!(!pool.lease_at(Ipv4Addr::new(10, 8, 0, 4)));
+    assert!(!pool.lease_at(Ipv4Addr::new(10, 8, 0, 1)));
+    assert!(!pool.lease_at(Ipv4Addr::new(10, 8, 0, 7)));
+    assert!(!pool.lease_at(Ipv4Addr::new(10, 9, 0, 2)));
+    assert_eq!(pool.lease(), Some(Ipv4Addr::new(10, 8, 0, 2)));
+  }
+}
@@ -0,0 +1,172 @@
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::GatewayConfig;
+
+const PROC_SYS: &str = "/proc/sys";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Issue {
+  pub problem: String,
+  pub remediation: String,
+}
+
+impl fmt::Display for Issue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}; fix with `{}`", self.problem, self.remediation)
+  }
+}
+
+struct Sysctl {
+  key: &'static str,
+  accepted: &'static [&'static str],
+  fix: &'static str,
+  why: &'static str,
+}
+
+const SYSCTLS: &[Sysctl] = &[
+  Sysctl {
+    key: "net.ipv4.ip_forward",
+    accepted: &["1"],
+    fix: "1",
+    why: "packet forwarding is disabled, tunneled traffic won't leave the server",
+  },
+  Sysctl {
+    key: "net.ipv4.conf.all.rp_filter",
+    accepted: &["0", "2"],
+    fix: "2",
+    why: "strict reverse path filtering drops tunneled packets with client source addresses",
+  },
+];
+
+/// Verifies the host is able to forward client traffic, fixing sysctls if the config allows it.
+pub fn ensure(config: &GatewayConfig) -> anyhow::Result<()> {
+  let root = Path::new(PROC_SYS);
+
+  let mut issues = Vec::new();
+  for sysctl in SYSCTLS {
+    if let Some(issue) = check_sysctl(root, sysctl, config.fix_sysctls)? {
+      issues.push(issue);
+    }
+  }
+
+  issues.extend(check_firewall());
+
+  if issues.is_empty() {
+    info!("Gateway prerequisites satisfied");
+    return Ok(());
+  }
+
+  for issue in &issues {
+    warn!("Gateway prerequisite failed: {}", issue);
+  }
+
+  anyhow::bail!(
+    "{} gateway prerequisite(s) failed: {}",
+    issues.len(),
+    issues.iter().map(|issue| issue.to_string()).collect::<Vec<_>>().join("; ")
+  )
+}
+
+fn sysctl_path(root: &Path, key: &str) -> PathBuf {
+  root.join(key.replace('.', "/"))
+}
+
+fn check_sysctl(root: &Path, sysctl: &Sysctl, fix: bool) -> anyhow::Result<Option<Issue>> {
+  let path = sysctl_path(root, sysctl.key);
+  let value = std::fs::read_to_string(&path)
+    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+  let value = value.trim();
+
+  if sysctl.accepted.contains(&value) {
+    return Ok(None);
+  }
+
+  if fix {
+    info!("Setting {} = {} (was {})", sysctl.key, sysctl.fix, value);
+    std::fs::write(&path, sysctl.fix).map_err(|e| anyhow::anyhow!("Failed to set {}: {}", sysctl.key, e))?;
+    return Ok(None);
+  }
+
+  Ok(Some(Issue {
+    problem: format!("{} = {}: {}", sysctl.key, value, sysctl.why),
+    remediation: format!("sysctl -w {}={}", sysctl.key, sysctl.fix),
+  }))
+}
+
+fn check_firewall() -> Option<Issue> {
+  match Command::new("iptables").args(["-S", "FORWARD"]).output() {
+    Ok(output) if output.status.success() => check_forward_rules(&String::from_utf8_lossy(&output.stdout)),
+    Ok(output) => {
+      debug!("Skipping firewall check: {}", String::from_utf8_lossy(&output.stderr).trim());
+      None
+    }
+    Err(e) => {
+      debug!("Skipping firewall check, iptables is unavailable: {}", e);
+      None
+    }
+  }
+}
+
+fn check_forward_rules(rules: &str) -> Option<Issue> {
+  let drops_by_default = rules.lines().any(|line| line.trim() == "-P FORWARD DROP");
+  let accepts_anything =
+    rules.lines().any(|line| line.starts_with("-A FORWARD") && line.trim_end().ends_with("-j ACCEPT"));
+
+  if drops_by_default && !accepts_anything {
+    return Some(Issue {
+      problem: "iptables FORWARD chain drops everything by default".into(),
+      remediation: "iptables -A FORWARD -i <tun> -j ACCEPT && iptables -A FORWARD -o <tun> -j ACCEPT".into(),
+    });
+  }
+
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fake_proc(name: &str, ip_forward: &str, rp_filter: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("vpn-prereqs-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(root.join("net/ipv4/conf/all")).unwrap();
+    std::fs::write(root.join("net/ipv4/ip_forward"), ip_forward).unwrap();
+    std::fs::write(root.join("net/ipv4/conf/all/rp_filter"), rp_filter).unwrap();
+    root
+  }
+
+  #[test]
+  fn test_sysctl_issue_reported() {
+    let root = fake_proc("report", "0\n", "1\n");
+
+    let issue = check_sysctl(&root, &SYSCTLS[0], false).unwrap().unwrap();
+    assert_eq!(issue.remediation, "sysctl -w net.ipv4.ip_forward=1");
+    assert!(check_sysctl(&root, &SYSCTLS[1], false).unwrap().is_some());
+
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn test_sysctl_fixed_on_opt_in() {
+    let root = fake_proc("fix", "0\n", "2\n");
+
+    assert!(check_sysctl(&root, &SYSCTLS[0], true).unwrap().is_none());
+    assert_eq!(std::fs::read_to_string(root.join("net/ipv4/ip_forward")).unwrap(), "1");
+    assert!(check_sysctl(&root, &SYSCTLS[1], false).unwrap().is_none());
+
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn test_forward_rules() {
+    assert!(check_forward_rules("-P FORWARD ACCEPT\n").is_none());
+    assert!(check_forward_rules("-P FORWARD DROP\n").is_some());
+    assert!(check_forward_rules("-P FORWARD DROP\n-A FORWARD -i tun0 -j ACCEPT\n").is_none());
+  }
+}
@@ -0,0 +1,278 @@
+//! Metrics pushed to a collector every `interval-secs`, for environments without a Prometheus stack to
+//! scrape `/metrics`: statsd, or InfluxDB line protocol for InfluxDB's UDP listener or Telegraf's
+//! `socket_listener`. Both go over UDP, in datagrams that fit the usual MTU.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::trace;
+use tracing::warn;
+
+use crate::metrics::Kind;
+use crate::metrics::Sink;
+use crate::server::Server;
+
+/// Largest datagram sent, which statsd recommends for paths with a 1500 byte MTU.
+const MAX_DATAGRAM: usize = 1432;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PushFormat {
+  Statsd,
+  Influx,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsPushConfig {
+  pub format: PushFormat,
+  /// `host:port` of the collector, looked up again before every push.
+  pub address: String,
+  #[serde(default = "default_interval_secs")]
+  pub interval_secs: u64,
+  /// Labels added to every metric, e.g. `server: vpn1` to tell servers apart.
+  #[serde(default)]
+  pub tags: BTreeMap<String, String>,
+}
+
+fn default_interval_secs() -> u64 {
+  10
+}
+
+impl MetricsPushConfig {
+  pub fn problems(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+    if self.interval_secs == 0 {
+      problems.push("metrics-push.interval-secs must be at least 1".to_string());
+    }
+    if self.address.is_empty() {
+      problems.push("metrics-push.address is required".to_string());
+    }
+    problems
+  }
+}
+
+/// Statsd lines. Labels become segments of the name, as plain statsd has no tags; counters and histograms
+/// are sent as their increase since the last push, which statsd sums up.
+pub struct Statsd<'a> {
+  tags: Vec<String>,
+  /// Values of the counters at the last push, by the name sent.
+  last: &'a mut HashMap<String, f64>,
+  kind: Kind,
+  pub lines: Vec<String>,
+}
+
+impl<'a> Statsd<'a> {
+  pub fn new(tags: &BTreeMap<String, String>, last: &'a mut HashMap<String, f64>) -> Self {
+    Self {
+      tags: tags.values().map(|value| statsd_segment(value)).collect(),
+      last,
+      kind: Kind::Gauge,
+      lines: Vec::new(),
+    }
+  }
+}
+
+fn statsd_segment(value: &str) -> String {
+  value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+impl Sink for Statsd<'_> {
+  fn family(&mut self, _: &str, _: &str, kind: Kind) {
+    self.kind = kind;
+  }
+
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let Ok(value) = value.to_string().parse::<f64>() else {
+      return;
+    };
+    let mut segments = vec![statsd_segment(name)];
+    segments.extend(self.tags.iter().cloned());
+    segments.extend(labels.iter().map(|(_, value)| statsd_segment(value)));
+    let name = segments.join(".");
+    match self.kind {
+      Kind::Gauge if value < 0.0 => {
+        // A signed gauge would change the value by that much rather than set it.
+        self.lines.push(format!("{}:0|g", name));
+        self.lines.push(format!("{}:{}|g", name, value));
+      }
+      Kind::Gauge => self.lines.push(format!("{}:{}|g", name, value)),
+      Kind::Counter | Kind::Histogram => {
+        let last = self.last.insert(name.clone(), value).unwrap_or_default();
+        self.lines.push(format!("{}:{}|c", name, (value - last).max(0.0)));
+      }
+    }
+  }
+}
+
+/// InfluxDB line protocol: a measurement per sample with its labels as tags and a single `value` field,
+/// all stamped with the time of the push.
+pub struct Influx {
+  tags: String,
+  timestamp_nanos: u128,
+  pub lines: Vec<String>,
+}
+
+impl Influx {
+  pub fn new(tags: &BTreeMap<String, String>, timestamp_nanos: u128) -> Self {
+    let tags =
+      tags.iter().map(|(key, value)| format!(",{}={}", influx_escape(key), influx_escape(value))).collect();
+    Self { tags, timestamp_nanos, lines: Vec::new() }
+  }
+}
+
+fn influx_escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    if matches!(c, ',' | '=' | ' ' | '\\') {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+impl Sink for Influx {
+  fn family(&mut self, _: &str, _: &str, _: Kind) {}
+
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let labels: String = labels
+      .iter()
+      .map(|(label, value)| format!(",{}={}", influx_escape(label), influx_escape(value)))
+      .collect();
+    self.lines.push(format!(
+      "{}{}{} value={} {}",
+      influx_escape(name),
+      self.tags,
+      labels,
+      value,
+      self.timestamp_nanos
+    ));
+  }
+}
+
+/// Packs `lines` into as few datagrams as possible, never splitting one.
+fn datagrams(lines: &[String]) -> Vec<String> {
+  let mut datagrams: Vec<String> = Vec::new();
+  for line in lines {
+    match datagrams.last_mut() {
+      Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM => {
+        datagram.push('\n');
+        datagram.push_str(line);
+      }
+      _ => datagrams.push(line.clone()),
+    }
+  }
+  datagrams
+}
+
+impl Server {
+  /// Pushes the metrics every `metrics-push.interval-secs`.
+  pub async fn push_metrics(self: Arc<Self>) -> anyhow::Result<()> {
+    let Some(ref config) = self.metrics_push else {
+      return Ok(());
+    };
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut counters = HashMap::new();
+    loop {
+      interval.tick().await;
+      let lines = match config.format {
+        PushFormat::Statsd => {
+          let mut statsd = Statsd::new(&config.tags, &mut counters);
+          self.metrics.write(&mut statsd);
+          statsd.lines
+        }
+        PushFormat::Influx => {
+          let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+          let mut influx = Influx::new(&config.tags, now.as_nanos());
+          self.metrics.write(&mut influx);
+          influx.lines
+        }
+      };
+      match send(&socket, &config.address, &lines).await {
+        Ok(collector) => trace!("Pushed {} metrics to {}", lines.len(), collector),
+        Err(e) => warn!("Failed to push the metrics to {}: {}", config.address, e),
+      }
+    }
+  }
+}
+
+async fn send(socket: &UdpSocket, address: &str, lines: &[String]) -> anyhow::Result<SocketAddr> {
+  let collector = tokio::net::lookup_host(address)
+    .await?
+    .find(SocketAddr::is_ipv4)
+    .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", address))?;
+  for datagram in datagrams(lines) {
+    socket.send_to(datagram.as_bytes(), collector).await?;
+  }
+  Ok(collector)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::metrics::Metrics;
+
+  #[test]
+  fn test_statsd() {
+    let metrics = Metrics::default();
+    metrics.decrypt_failures.add(3);
+    metrics.session_started(Some("acme"));
+    metrics.session_ended(None);
+    let tags = BTreeMap::from([("server".to_string(), "vpn1".to_string())]);
+    let mut counters = HashMap::new();
+
+    let mut statsd = Statsd::new(&tags, &mut counters);
+    metrics.write(&mut statsd);
+    let lines = statsd.lines;
+    assert!(lines.contains(&"vpn_decrypt_failures_total.vpn1:3|c".to_string()));
+    assert!(lines.contains(&"vpn_queue_depth.vpn1.send:0|g".to_string()));
+    assert!(lines.contains(&"vpn_network_sessions.vpn1.acme:1|g".to_string()));
+    assert!(lines.contains(&"vpn_inner_packet_bytes_bucket.vpn1._Inf:0|c".to_string()));
+    let negative = lines.iter().position(|line| line == "vpn_network_sessions.vpn1.default:0|g").unwrap();
+    assert_eq!(lines[negative + 1], "vpn_network_sessions.vpn1.default:-1|g");
+
+    // Counters go as their increase.
+    metrics.decrypt_failures.add(2);
+    let mut statsd = Statsd::new(&tags, &mut counters);
+    metrics.write(&mut statsd);
+    assert!(statsd.lines.contains(&"vpn_decrypt_failures_total.vpn1:2|c".to_string()));
+  }
+
+  #[test]
+  fn test_influx() {
+    let metrics = Metrics::default();
+    metrics.decrypt_failures.add(3);
+    metrics.session_started(Some("acme corp"));
+    let tags = BTreeMap::from([("server".to_string(), "vpn1".to_string())]);
+
+    let mut influx = Influx::new(&tags, 1_700_000_000_000_000_000);
+    metrics.write(&mut influx);
+    let lines = influx.lines;
+    assert!(lines.contains(&"vpn_decrypt_failures_total,server=vpn1 value=3 1700000000000000000".to_string()));
+    assert!(lines.contains(&"vpn_queue_depth,server=vpn1,queue=send value=0 1700000000000000000".to_string()));
+    assert!(lines.contains(
+      &"vpn_network_sessions,server=vpn1,network=acme\\ corp value=1 1700000000000000000".to_string()
+    ));
+  }
+
+  #[test]
+  fn test_datagrams() {
+    let lines: Vec<String> = (0..100).map(|i| format!("metric_{:03}:{}|c", i, "1".repeat(20))).collect();
+    let datagrams = datagrams(&lines);
+    assert!(datagrams.len() > 1);
+    assert!(datagrams.iter().all(|datagram| datagram.len() <= MAX_DATAGRAM));
+    assert_eq!(datagrams.join("\n"), lines.join("\n"));
+  }
+}
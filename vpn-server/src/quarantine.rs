@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use serde::Deserialize;
+
+use tracing::debug;
+use tracing::warn;
+use vpn_shared::logging;
+
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct QuarantineConfig {
+  pub max_failures: u32,
+  pub window_secs: u64,
+  pub duration_secs: u64,
+}
+
+impl Default for QuarantineConfig {
+  fn default() -> Self {
+    Self { max_failures: 50, window_secs: 10, duration_secs: 60 }
+  }
+}
+
+#[derive(Debug)]
+struct PeerScore {
+  window_start: Instant,
+  failures: u32,
+  quarantined_until: Option<Instant>,
+}
+
+/// Scores sources by their recent parse/decrypt failures and ignores the ones exceeding the threshold for
+/// a while, so garbage floods don't cost an AEAD attempt per packet.
+#[derive(Debug)]
+pub struct Quarantine {
+  config: QuarantineConfig,
+  peers: DashMap<IpAddr, PeerScore>,
+  metrics: Arc<Metrics>,
+}
+
+impl Quarantine {
+  pub fn new(config: QuarantineConfig, metrics: Arc<Metrics>) -> Self {
+    Self { config, peers: DashMap::new(), metrics }
+  }
+
+  pub fn is_quarantined(&self, ip: IpAddr) -> bool {
+    let Some(peer) = self.peers.get(&ip) else {
+      return false;
+    };
+
+    let quarantined = peer.quarantined_until.is_some_and(|until| Instant::now() < until);
+    if quarantined {
+      self.metrics.quarantine_dropped_packets.inc();
+    }
+    quarantined
+  }
+
+  pub fn record_failure(&self, ip: IpAddr, reason: &dyn std::fmt::Display) {
+    self.metrics.decrypt_failures.inc();
+
+    let now = Instant::now();
+    let window = Duration::from_secs(self.config.window_secs);
+
+    let mut peer = self.peers.entry(ip).or_insert_with(|| PeerScore {
+      window_start: now,
+      failures: 0,
+      quarantined_until: None,
+    });
+
+    if now.duration_since(peer.window_start) > window {
+      peer.window_start = now;
+      peer.failures = 0;
+    }
+    peer.failures += 1;
+
+    if peer.failures.is_power_of_two() {
+      debug!(target: logging::CRYPTO, "Bad packet from {} ({} failures in {:?}): {}", ip, peer.failures, window, reason);
+    }
+
+    if peer.failures >= self.config.max_failures && self.config.max_failures > 0 {
+      let duration = Duration::from_secs(self.config.duration_secs);
+      warn!("Quarantining {} for {:?} after {} bad packets", ip, duration, peer.failures);
+
+      peer.quarantined_until = Some(now + duration);
+      peer.window_start = now + duration;
+      peer.failures = 0;
+      self.metrics.quarantined_peers.inc();
+    }
+  }
+
+  /// Forgets peers without recent failures.
+  pub fn prune(&self) {
+    let now = Instant::now();
+    let window = Duration::from_secs(self.config.window_secs);
+    self.peers.retain(|_, peer| {
+      peer.quarantined_until.is_some_and(|until| now < until)
+        || now.duration_since(peer.window_start) <= window
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn quarantine(max_failures: u32) -> Quarantine {
+    Quarantine::new(
+      QuarantineConfig { max_failures, window_secs: 10, duration_secs: 60 },
+      Arc::new(Metrics::default()),
+    )
+  }
+
+  #[test]
+  fn test_quarantined_after_threshold() {
+    let quarantine = quarantine(3);
+    let ip = IpAddr::from([192, 0, 2, 1]);
+    let other = IpAddr::from([192, 0, 2, 2]);
+
+    quarantine.record_failure(ip, &"bad");
+    quarantine.record_failure(ip, &"bad");
+    assert!(!quarantine.is_quarantined(ip));
+
+    quarantine.record_failure(ip, &"bad");
+    assert!(quarantine.is_quarantined(ip));
+    assert!(!quarantine.is_quarantined(other));
+
+    assert_eq!(quarantine.metrics.decrypt_failures.get(), 3);
+    assert_eq!(quarantine.metrics.quarantined_peers.get(), 1);
+    assert_eq!(quarantine.metrics.quarantine_dropped_packets.get(), 1);
+  }
+
+  #[test]
+  fn test_disabled_with_zero_threshold() {
+    let quarantine = quarantine(0);
+    let ip = IpAddr::from([192, 0, 2, 1]);
+
+    for _ in 0..100 {
+      quarantine.record_failure(ip, &"bad");
+    }
+    assert!(!quarantine.is_quarantined(ip));
+  }
+}
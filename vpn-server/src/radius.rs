@@ -0,0 +1,508 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::Hmac;
+use hmac::Mac;
+use md5::Digest;
+use md5::Md5;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+use tracing::debug;
+use tracing::warn;
+use vpn_shared::creds::Credentials;
+use vpn_shared::packet::fill_random_bytes;
+
+use crate::accounting::Accounting;
+use crate::accounting::AccountingEvent;
+use crate::accounting::AccountingKind;
+use crate::auth::AuthFuture;
+use crate::auth::CredentialStore;
+use crate::auth::Identity;
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCOUNTING_REQUEST: u8 = 4;
+const ACCOUNTING_RESPONSE: u8 = 5;
+
+const USER_NAME: u8 = 1;
+const USER_PASSWORD: u8 = 2;
+const CHAP_PASSWORD: u8 = 3;
+const FRAMED_IP_ADDRESS: u8 = 8;
+const FILTER_ID: u8 = 11;
+const CLASS: u8 = 25;
+const CALLING_STATION_ID: u8 = 31;
+const NAS_IDENTIFIER: u8 = 32;
+const ACCT_STATUS_TYPE: u8 = 40;
+const ACCT_INPUT_OCTETS: u8 = 42;
+const ACCT_OUTPUT_OCTETS: u8 = 43;
+const ACCT_SESSION_ID: u8 = 44;
+const ACCT_SESSION_TIME: u8 = 46;
+const ACCT_INPUT_GIGAWORDS: u8 = 52;
+const ACCT_OUTPUT_GIGAWORDS: u8 = 53;
+const CHAP_CHALLENGE: u8 = 60;
+const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const HEADER_SIZE: usize = 20;
+const MAX_PACKET_SIZE: usize = 4096;
+const MAX_PASSWORD_SIZE: usize = 128;
+/// Longest value of an attribute, whose length including the type and length bytes has to fit a byte.
+const MAX_ATTRIBUTE_SIZE: usize = 253;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RadiusMethod {
+  #[default]
+  Pap,
+  Chap,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RadiusConfig {
+  pub server: SocketAddr,
+
+  /// Defaults to the next port of `server`, e.g. 1813 for 1812; has to be set when `server` uses port 65535.
+  #[serde(default)]
+  pub accounting_server: Option<SocketAddr>,
+
+  pub secret: String,
+
+  #[serde(default)]
+  pub method: RadiusMethod,
+
+  #[serde(default = "default_nas_identifier")]
+  pub nas_identifier: String,
+
+  #[serde(default = "default_timeout_secs")]
+  pub timeout_secs: u64,
+
+  #[serde(default = "default_retries")]
+  pub retries: u32,
+
+  /// Whether to send accounting records at all.
+  #[serde(default)]
+  pub accounting: bool,
+
+  #[serde(default)]
+  pub interim_interval_secs: Option<u64>,
+}
+
+fn default_nas_identifier() -> String {
+  "sberlinux-vpn".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+  3
+}
+
+fn default_retries() -> u32 {
+  2
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+  code: u8,
+  id: u8,
+  authenticator: [u8; 16],
+  attributes: Vec<(u8, Vec<u8>)>,
+}
+
+impl Packet {
+  fn new(code: u8) -> Self {
+    let mut id = [0u8; 1];
+    fill_random_bytes(&mut id);
+    Self { code, id: id[0], authenticator: [0u8; 16], attributes: Vec::new() }
+  }
+
+  fn with(mut self, kind: u8, value: impl Into<Vec<u8>>) -> Self {
+    self.attributes.push((kind, value.into()));
+    self
+  }
+
+  fn attributes(&self, kind: u8) -> impl Iterator<Item = &[u8]> {
+    self.attributes.iter().filter(move |(k, _)| *k == kind).map(|(_, value)| value.as_slice())
+  }
+
+  /// Fails for attributes too long for RADIUS, e.g. a username of more than `MAX_ATTRIBUTE_SIZE` bytes.
+  fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![self.code, self.id, 0, 0];
+    bytes.extend_from_slice(&self.authenticator);
+    for (kind, value) in &self.attributes {
+      if value.len() > MAX_ATTRIBUTE_SIZE {
+        anyhow::bail!(
+          "RADIUS attribute {} of {} bytes is longer than {}",
+          kind,
+          value.len(),
+          MAX_ATTRIBUTE_SIZE
+        );
+      }
+      bytes.push(*kind);
+      bytes.push(value.len() as u8 + 2);
+      bytes.extend_from_slice(value);
+    }
+
+    if bytes.len() > MAX_PACKET_SIZE {
+      anyhow::bail!("RADIUS packet of {} bytes is longer than {}", bytes.len(), MAX_PACKET_SIZE);
+    }
+    let len = bytes.len() as u16;
+    bytes[2..4].copy_from_slice(&len.to_be_bytes());
+    Ok(bytes)
+  }
+
+  fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+    if bytes.len() < HEADER_SIZE {
+      anyhow::bail!("RADIUS packet too short");
+    }
+
+    let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    if len < HEADER_SIZE || len > bytes.len() {
+      anyhow::bail!("Invalid RADIUS packet length {}", len);
+    }
+
+    let mut attributes = Vec::new();
+    let mut rest = &bytes[HEADER_SIZE..len];
+    while !rest.is_empty() {
+      let (kind, attr_len) = match rest {
+        [kind, attr_len, ..] if *attr_len >= 2 && *attr_len as usize <= rest.len() => {
+          (*kind, *attr_len as usize)
+        }
+        _ => anyhow::bail!("Malformed RADIUS attribute"),
+      };
+      attributes.push((kind, rest[2..attr_len].to_vec()));
+      rest = &rest[attr_len..];
+    }
+
+    Ok(Self { code: bytes[0], id: bytes[1], authenticator: bytes[4..20].try_into()?, attributes })
+  }
+}
+
+fn md5(parts: &[&[u8]]) -> [u8; 16] {
+  let mut hasher = Md5::new();
+  for part in parts {
+    hasher.update(part);
+  }
+  hasher.finalize().into()
+}
+
+fn hmac_md5(secret: &[u8], data: &[u8]) -> [u8; 16] {
+  let mut mac = Hmac::<Md5>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+  mac.update(data);
+  mac.finalize().into_bytes().into()
+}
+
+/// User-Password hiding from RFC 2865, section 5.2.
+fn hide_password(password: &[u8], secret: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+  let mut padded = password.to_vec();
+  padded.resize(password.len().div_ceil(16).max(1) * 16, 0);
+
+  let mut hidden: Vec<u8> = Vec::with_capacity(padded.len());
+  for chunk in padded.chunks(16) {
+    let previous = match hidden.len() {
+      0 => authenticator.as_slice(),
+      len => &hidden[len - 16..],
+    };
+    let pad = md5(&[secret, previous]);
+    let block: Vec<u8> = chunk.iter().zip(pad).map(|(a, b)| a ^ b).collect();
+    hidden.extend(block);
+  }
+  hidden
+}
+
+/// Client of a RADIUS server, used both to authenticate passwords and to send accounting records.
+#[derive(Clone)]
+pub struct RadiusClient {
+  config: Arc<RadiusConfig>,
+}
+
+impl RadiusClient {
+  pub fn new(config: RadiusConfig) -> Self {
+    Self { config: Arc::new(config) }
+  }
+
+  fn accounting_server(&self) -> anyhow::Result<SocketAddr> {
+    if let Some(server) = self.config.accounting_server {
+      return Ok(server);
+    }
+    let mut server = self.config.server;
+    let Some(port) = server.port().checked_add(1) else {
+      anyhow::bail!("RADIUS server {} has no next port for accounting; set accounting-server", server);
+    };
+    server.set_port(port);
+    Ok(server)
+  }
+
+  async fn authenticate(&self, username: &str, password: &str) -> anyhow::Result<Option<Identity>> {
+    if password.len() > MAX_PASSWORD_SIZE || username.len() > MAX_ATTRIBUTE_SIZE {
+      return Ok(None);
+    }
+
+    let secret = self.config.secret.as_bytes();
+    let mut request = Packet::new(ACCESS_REQUEST)
+      .with(USER_NAME, username)
+      .with(NAS_IDENTIFIER, self.config.nas_identifier.as_str());
+    fill_random_bytes(&mut request.authenticator);
+
+    request = match self.config.method {
+      RadiusMethod::Pap => {
+        let hidden = hide_password(password.as_bytes(), secret, &request.authenticator);
+        request.with(USER_PASSWORD, hidden)
+      }
+      RadiusMethod::Chap => {
+        let mut challenge = [0u8; 17];
+        fill_random_bytes(&mut challenge);
+        let (chap_id, challenge) = (challenge[0], &challenge[1..]);
+        let response = md5(&[&[chap_id], password.as_bytes(), challenge]);
+        request
+          .with(CHAP_PASSWORD, [&[chap_id], response.as_slice()].concat())
+          .with(CHAP_CHALLENGE, challenge)
+      }
+    };
+
+    // Message-Authenticator (RFC 3579) is computed over the packet with the attribute zeroed.
+    request = request.with(MESSAGE_AUTHENTICATOR, [0u8; 16]);
+    let signature = hmac_md5(secret, &request.to_bytes()?);
+    request.attributes.last_mut().unwrap().1 = signature.to_vec();
+
+    let response = self.exchange(self.config.server, &request).await?;
+    match response.code {
+      ACCESS_ACCEPT => {
+        let groups = response
+          .attributes(FILTER_ID)
+          .chain(response.attributes(CLASS))
+          .map(|group| String::from_utf8_lossy(group).into_owned())
+          .collect();
+        Ok(Some(Identity { username: username.to_string(), groups }))
+      }
+      ACCESS_REJECT => Ok(None),
+      code => anyhow::bail!("Unexpected RADIUS response code {}", code),
+    }
+  }
+
+  async fn send_accounting(&self, event: AccountingEvent) -> anyhow::Result<()> {
+    let status: u32 = match event.kind {
+      AccountingKind::Start => 1,
+      AccountingKind::Stop => 2,
+      AccountingKind::Interim => 3,
+    };
+
+    let mut request = Packet::new(ACCOUNTING_REQUEST)
+      .with(ACCT_STATUS_TYPE, status.to_be_bytes())
+      .with(ACCT_SESSION_ID, format!("{:016x}", event.session_id))
+      .with(USER_NAME, event.username.as_str())
+      .with(CALLING_STATION_ID, event.client_addr.to_string())
+      .with(NAS_IDENTIFIER, self.config.nas_identifier.as_str());
+
+    if let Some(ip) = event.virtual_ip {
+      request = request.with(FRAMED_IP_ADDRESS, ip.octets());
+    }
+
+    if event.kind != AccountingKind::Start {
+      // Octet counts past 4 GiB carry on in the gigawords, RFC 2869 section 5.1.
+      let session_time = u32::try_from(event.duration.as_secs()).unwrap_or(u32::MAX);
+      request = request
+        .with(ACCT_INPUT_OCTETS, (event.bytes_in as u32).to_be_bytes())
+        .with(ACCT_OUTPUT_OCTETS, (event.bytes_out as u32).to_be_bytes())
+        .with(ACCT_INPUT_GIGAWORDS, ((event.bytes_in >> 32) as u32).to_be_bytes())
+        .with(ACCT_OUTPUT_GIGAWORDS, ((event.bytes_out >> 32) as u32).to_be_bytes())
+        .with(ACCT_SESSION_TIME, session_time.to_be_bytes());
+    }
+
+    request.authenticator = md5(&[&request.to_bytes()?, self.config.secret.as_bytes()]);
+
+    let response = self.exchange(self.accounting_server()?, &request).await?;
+    if response.code != ACCOUNTING_RESPONSE {
+      anyhow::bail!("Unexpected RADIUS accounting response code {}", response.code);
+    }
+    Ok(())
+  }
+
+  async fn exchange(&self, server: SocketAddr, request: &Packet) -> anyhow::Result<Packet> {
+    let socket = UdpSocket::bind(match server {
+      SocketAddr::V4(_) => "0.0.0.0:0",
+      SocketAddr::V6(_) => "[::]:0",
+    })
+    .await?;
+    socket.connect(server).await?;
+
+    let bytes = request.to_bytes()?;
+    let timeout = Duration::from_secs(self.config.timeout_secs);
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+    for attempt in 0..=self.config.retries {
+      socket.send(&bytes).await?;
+
+      let deadline = tokio::time::Instant::now() + timeout;
+      while let Ok(len) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+        let response = &buf[..len?];
+        match Packet::from_bytes(response) {
+          Ok(packet) if packet.id == request.id && self.verify(&packet, response, request) => {
+            return Ok(packet);
+          }
+          Ok(_) => debug!("Ignoring unmatched RADIUS response from {}", server),
+          Err(e) => debug!("Ignoring invalid RADIUS response from {}: {}", server, e),
+        }
+      }
+
+      debug!("RADIUS server {} didn't respond, attempt {}", server, attempt + 1);
+    }
+
+    anyhow::bail!("RADIUS server {} didn't respond", server)
+  }
+
+  fn verify(&self, response: &Packet, bytes: &[u8], request: &Packet) -> bool {
+    let secret = self.config.secret.as_bytes();
+    let mut signed = bytes[..u16::from_be_bytes([bytes[2], bytes[3]]) as usize].to_vec();
+    signed[4..20].copy_from_slice(&request.authenticator);
+
+    if md5(&[&signed, secret]) != response.authenticator {
+      return false;
+    }
+
+    match response.attributes(MESSAGE_AUTHENTICATOR).next() {
+      Some(signature) => {
+        let mut zeroed = Packet { authenticator: request.authenticator, ..response.clone() };
+        zeroed
+          .attributes
+          .iter_mut()
+          .filter(|(kind, _)| *kind == MESSAGE_AUTHENTICATOR)
+          .for_each(|(_, value)| value.fill(0));
+        zeroed.to_bytes().is_ok_and(|bytes| hmac_md5(secret, &bytes) == signature)
+      }
+      None => true,
+    }
+  }
+}
+
+impl CredentialStore for RadiusClient {
+  fn name(&self) -> &str {
+    "radius"
+  }
+
+  fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a> {
+    let Credentials::Password { username, password } = credentials else {
+      return Box::pin(async { Ok(None) });
+    };
+    Box::pin(RadiusClient::authenticate(self, username, password))
+  }
+}
+
+impl Accounting for RadiusClient {
+  fn record(&self, event: AccountingEvent) {
+    let client = self.clone();
+    tokio::spawn(async move {
+      let username = event.username.clone();
+      if let Err(e) = client.send_accounting(event).await {
+        warn!("Failed to send RADIUS accounting for {}: {}", username, e);
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECRET: &str = "testing123";
+
+  fn config(server: SocketAddr) -> RadiusConfig {
+    RadiusConfig {
+      server,
+      accounting_server: None,
+      secret: SECRET.into(),
+      method: RadiusMethod::Pap,
+      nas_identifier: default_nas_identifier(),
+      timeout_secs: 1,
+      retries: 0,
+      accounting: true,
+      interim_interval_secs: None,
+    }
+  }
+
+  fn reply(request: &Packet, code: u8, attributes: Vec<(u8, Vec<u8>)>) -> Vec<u8> {
+    let mut response = Packet { code, id: request.id, authenticator: request.authenticator, attributes };
+    let bytes = response.to_bytes().unwrap();
+    response.authenticator = md5(&[&bytes, SECRET.as_bytes()]);
+    response.to_bytes().unwrap()
+  }
+
+  #[test]
+  fn test_packet_roundtrip() {
+    let packet = Packet::new(ACCESS_REQUEST).with(USER_NAME, "alice").with(FILTER_ID, "staff");
+    let bytes = packet.to_bytes().unwrap();
+    assert_eq!(Packet::from_bytes(&bytes).unwrap(), packet);
+    assert!(Packet::from_bytes(&bytes[..22]).is_err());
+
+    let long = Packet::new(ACCESS_REQUEST).with(USER_NAME, "a".repeat(MAX_ATTRIBUTE_SIZE + 1));
+    assert!(long.to_bytes().is_err());
+  }
+
+  #[test]
+  fn test_accounting_server() {
+    let mut config = config("127.0.0.1:1812".parse().unwrap());
+    assert_eq!(
+      RadiusClient::new(config.clone()).accounting_server().unwrap(),
+      "127.0.0.1:1813".parse().unwrap()
+    );
+    config.server.set_port(u16::MAX);
+    assert!(RadiusClient::new(config.clone()).accounting_server().is_err());
+    config.accounting_server = Some("127.0.0.1:1646".parse().unwrap());
+    assert_eq!(RadiusClient::new(config).accounting_server().unwrap(), "127.0.0.1:1646".parse().unwrap());
+  }
+
+  #[test]
+  fn test_hide_password() {
+    let authenticator = [7u8; 16];
+    let hidden = hide_password(b"a password longer than sixteen", SECRET.as_bytes(), &authenticator);
+    assert_eq!(hidden.len(), 32);
+
+    let first = md5(&[SECRET.as_bytes(), &authenticator]);
+    let revealed: Vec<u8> = hidden[..16].iter().zip(first).map(|(a, b)| a ^ b).collect();
+    assert_eq!(&revealed, b"a password longe");
+  }
+
+  #[tokio::test]
+  async fn test_pap_authentication() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = RadiusClient::new(config(server.local_addr().unwrap()));
+
+    tokio::spawn(async move {
+      let mut buf = [0u8; MAX_PACKET_SIZE];
+      loop {
+        let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+        let request = Packet::from_bytes(&buf[..len]).unwrap();
+        let hidden = request.attributes(USER_PASSWORD).next().unwrap();
+        let expected = hide_password(b"secret", SECRET.as_bytes(), &request.authenticator);
+
+        let response = match hidden == expected.as_slice() {
+          true => reply(&request, ACCESS_ACCEPT, vec![(FILTER_ID, b"staff".to_vec())]),
+          false => reply(&request, ACCESS_REJECT, vec![]),
+        };
+        server.send_to(&response, peer).await.unwrap();
+      }
+    });
+
+    let identity = client.authenticate("alice", "secret").await.unwrap().unwrap();
+    assert_eq!(identity.groups, vec!["staff"]);
+    assert!(client.authenticate("alice", "wrong").await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn test_forged_response_is_ignored() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = RadiusClient::new(config(server.local_addr().unwrap()));
+
+    tokio::spawn(async move {
+      let mut buf = [0u8; MAX_PACKET_SIZE];
+      let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+      let request = Packet::from_bytes(&buf[..len]).unwrap();
+      let forged =
+        Packet { code: ACCESS_ACCEPT, id: request.id, authenticator: [0u8; 16], attributes: vec![] };
+      server.send_to(&forged.to_bytes().unwrap(), peer).await.unwrap();
+    });
+
+    assert!(client.authenticate("alice", "secret").await.is_err());
+  }
+}
@@ -0,0 +1,138 @@
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+/// Longest bulk string read, to bound what a misbehaving server can make the node allocate.
+const MAX_BULK_LEN: usize = 1 << 20;
+
+/// Reply of a Redis server, as far as publishing and subscribing need; errors are returned as `Err`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+  Simple(String),
+  Integer(i64),
+  Bulk(Option<Vec<u8>>),
+  /// Elements are never arrays themselves.
+  Array(Vec<Value>),
+}
+
+/// A value, or the header of an array with its length.
+enum Item {
+  Value(Value),
+  Array(i64),
+}
+
+/// Connection speaking just enough of RESP, the Redis protocol, for `cluster` to exchange messages over
+/// pub/sub.
+pub struct Connection {
+  stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+  /// Connects to `address` (`host:port`), authenticating with `password` if there's one.
+  pub async fn connect(address: &str, password: Option<&str>) -> anyhow::Result<Self> {
+    let mut connection = Self { stream: BufReader::new(TcpStream::connect(address).await?) };
+    if let Some(password) = password {
+      connection.command(&[b"AUTH", password.as_bytes()]).await?;
+    }
+    Ok(connection)
+  }
+
+  /// Sends a command and reads its reply.
+  pub async fn command(&mut self, args: &[&[u8]]) -> anyhow::Result<Value> {
+    self.send(args).await?;
+    self.read().await
+  }
+
+  pub async fn send(&mut self, args: &[&[u8]]) -> anyhow::Result<()> {
+    self.stream.get_mut().write_all(&encode(args)).await?;
+    Ok(())
+  }
+
+  /// Reads the next reply, or the next message pushed to a subscribed connection.
+  pub async fn read(&mut self) -> anyhow::Result<Value> {
+    let len = match self.read_item().await? {
+      Item::Value(value) => return Ok(value),
+      Item::Array(len) => len,
+    };
+    let mut elements = Vec::new();
+    for _ in 0..len {
+      match self.read_item().await? {
+        Item::Value(value) => elements.push(value),
+        Item::Array(_) => anyhow::bail!("Nested arrays aren't supported"),
+      }
+    }
+    Ok(Value::Array(elements))
+  }
+
+  async fn read_item(&mut self) -> anyhow::Result<Item> {
+    let mut line = Vec::new();
+    self.stream.read_until(b'\n', &mut line).await?;
+    let Some(line) = line.strip_suffix(b"\r\n") else {
+      anyhow::bail!("Connection closed or reply not terminated by CRLF");
+    };
+    let (kind, rest) = line.split_first().ok_or_else(|| anyhow::anyhow!("Empty reply"))?;
+    let text = String::from_utf8_lossy(rest);
+    let number = || text.parse::<i64>().map_err(|_| anyhow::anyhow!("Invalid length or integer {}", text));
+
+    Ok(Item::Value(match kind {
+      b'+' => Value::Simple(text.to_string()),
+      b'-' => anyhow::bail!("Redis error: {}", text),
+      b':' => Value::Integer(number()?),
+      b'*' => return Ok(Item::Array(number()?)),
+      b'$' => match number()? {
+        len if len < 0 => Value::Bulk(None),
+        len if len as usize > MAX_BULK_LEN => anyhow::bail!("Bulk string of {} bytes is too long", len),
+        len => {
+          let mut bulk = vec![0; len as usize + 2];
+          self.stream.read_exact(&mut bulk).await?;
+          bulk.truncate(len as usize);
+          Value::Bulk(Some(bulk))
+        }
+      },
+      other => anyhow::bail!("Unknown reply type {:?}", *other as char),
+    }))
+  }
+}
+
+/// A command as an array of bulk strings.
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+  let mut encoded = format!("*{}\r\n", args.len()).into_bytes();
+  for arg in args {
+    encoded.extend(format!("${}\r\n", arg.len()).as_bytes());
+    encoded.extend_from_slice(arg);
+    encoded.extend(b"\r\n");
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn test_resp() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let server = tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = vec![0; 64];
+      let len = stream.read(&mut request).await.unwrap();
+      request.truncate(len);
+      stream.write_all(b"+OK\r\n*3\r\n$7\r\nmessage\r\n$-1\r\n:42\r\n-ERR nope\r\n").await.unwrap();
+      request
+    });
+
+    let mut connection = Connection::connect(&address, Some("secret")).await.unwrap();
+    assert_eq!(server.await.unwrap(), b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n");
+    assert_eq!(
+      connection.read().await.unwrap(),
+      Value::Array(vec![Value::Bulk(Some(b"message".to_vec())), Value::Bulk(None), Value::Integer(42)])
+    );
+    assert!(connection.read().await.unwrap_err().to_string().contains("ERR nope"));
+    assert!(connection.read().await.is_err());
+  }
+}
@@ -0,0 +1,156 @@
+//! Rekeying: replacing the key of an established session in place, without a new handshake, when there's
+//! reason to think someone else may hold it: the session moved to another address, packets of it that don't
+//! open keep arriving, or an administrator asked. Key material captured before is worth nothing for the
+//! traffic after, and the user stays connected.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::handshake;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::logging;
+use vpn_shared::packet::Features;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::ServerPacket;
+
+use crate::handle_packet::PacketHandler;
+use crate::server::Server;
+
+/// Window the decrypt failures of a session are counted over.
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RekeyConfig {
+  /// Rekey sessions once they moved to a new address.
+  #[serde(default = "default_on_roaming")]
+  pub on_roaming: bool,
+
+  /// Packets of a session failing to open within a minute that get it rekeyed; 0 to never.
+  #[serde(default = "default_decrypt_failures")]
+  pub decrypt_failures: u32,
+}
+
+fn default_on_roaming() -> bool {
+  true
+}
+
+fn default_decrypt_failures() -> u32 {
+  20
+}
+
+impl Default for RekeyConfig {
+  fn default() -> Self {
+    Self { on_roaming: default_on_roaming(), decrypt_failures: default_decrypt_failures() }
+  }
+}
+
+/// Packets of a session that failed to open in the current window.
+#[derive(Debug, Default)]
+pub struct DecryptFailures {
+  started: Option<Instant>,
+  count: u32,
+}
+
+impl DecryptFailures {
+  /// Counts a failure at `now`; true once they make `limit` within the window, at most once per window.
+  pub fn record(&mut self, now: Instant, limit: u32) -> bool {
+    if self.started.is_none_or(|started| now.duration_since(started) >= FAILURE_WINDOW) {
+      *self = Self { started: Some(now), count: 0 };
+    }
+    self.count += 1;
+    limit > 0 && self.count == limit
+  }
+}
+
+impl Server {
+  /// Asks the client at `addr` to replace its session key, see `ServerPacket::Rekey`; one it hasn't answered
+  /// yet is asked for again with the same key. False if the session didn't negotiate rekeying.
+  pub async fn rekey(&self, addr: SocketAddr, reason: &str) -> anyhow::Result<bool> {
+    let key = {
+      let Some(mut client) = self.clients.get_mut(&addr) else {
+        return Ok(false);
+      };
+      if client.authenticated_at.is_none() || !client.features.contains(Features::REKEY) {
+        return Ok(false);
+      }
+      client.rekey.get_or_insert_with(KeyPair::generate).public()
+    };
+
+    self.send_packet(ServerPacket::Rekey { key }, addr).await?;
+    self.metrics.rekeys.inc();
+    info!(target: logging::HANDSHAKE, "Rekeying the session of {}: {}", addr, reason);
+    Ok(true)
+  }
+
+  /// Switches the session at `addr` to the key derived from the client's answer to `rekey`. Called as the
+  /// answer is received rather than by a worker, so that the packets after it, sealed with the new key, open.
+  pub async fn complete_rekey(&self, addr: SocketAddr, client_key: Key) -> anyhow::Result<()> {
+    {
+      let Some(mut client) = self.clients.get_mut(&addr) else {
+        return Ok(());
+      };
+      let Some(ephemeral) = client.rekey.take() else {
+        anyhow::bail!("Rekey from {} that wasn't asked for", addr);
+      };
+      let key = handshake::server_rekey(&ephemeral, &client_key, &client.key)?;
+      let pipeline = self.transforms.pipeline(client.pipeline.names(), &key)?;
+      let pipeline = Arc::new(pipeline.with_raw_data(client.pipeline.raw_data()));
+      client.key = key;
+      client.pipeline = pipeline;
+      client.keyed_at = SystemTime::now();
+      self.record_rekey(&client);
+    }
+    self.announce_session(addr).await;
+
+    info!(target: logging::HANDSHAKE, "Replaced the session key of {}", addr);
+    Ok(())
+  }
+
+  /// Counts a packet for the session at `addr` that didn't open, and rekeys it once they come in bursts.
+  pub async fn record_session_decrypt_failure(&self, addr: SocketAddr) {
+    let limit = self.rekeying.decrypt_failures;
+    let burst = self
+      .clients
+      .get_mut(&addr)
+      .is_some_and(|mut client| client.decrypt_failures.record(Instant::now(), limit));
+    if !burst {
+      return;
+    }
+
+    let reason = format!("{} packets of it failed to open within {:?}", limit, FAILURE_WINDOW);
+    if let Err(e) = self.rekey(addr, &reason).await {
+      warn!(target: logging::HANDSHAKE, "Failed to rekey the session of {}: {}", addr, e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_decrypt_failures() {
+    let mut failures = DecryptFailures::default();
+    let now = Instant::now();
+    assert!(!failures.record(now, 3));
+    assert!(!failures.record(now, 3));
+    assert!(failures.record(now, 3));
+    // Only the failure making the limit counts, not the ones after it.
+    assert!(!failures.record(now, 3));
+
+    let later = now + FAILURE_WINDOW;
+    assert!(!failures.record(later, 3));
+    assert!(!failures.record(later, 3));
+    assert!(failures.record(later + Duration::from_secs(1), 3));
+
+    let mut disabled = DecryptFailures::default();
+    assert!(!(0..100).any(|_| disabled.record(now, 0)));
+  }
+}
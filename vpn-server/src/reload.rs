@@ -0,0 +1,385 @@
+//! Reload of the configuration file of a running server, and its dry run: the sections that change, and the
+//! sessions the new configuration cuts off. Only `RELOADABLE` settings are applied in place; the server
+//! takes in other changes when it restarts, so the preview lists them as pending.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_yml::Mapping;
+use serde_yml::Value;
+
+use crate::config::ServerConfig;
+use crate::health::LiveClient;
+
+/// Top-level settings a reload applies to the running server.
+pub const RELOADABLE: [&str; 2] = ["client-credentials", "client-keys"];
+
+/// Configuration file as the server runs it: as loaded at start, with the settings of later reloads.
+pub struct LoadedConfig {
+  path: PathBuf,
+  loaded: Mutex<Value>,
+}
+
+impl LoadedConfig {
+  pub fn read(path: &Path) -> anyhow::Result<Self> {
+    let loaded = serde_yml::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(Self { path: path.to_path_buf(), loaded: Mutex::new(loaded) })
+  }
+
+  /// What reloading the file as it is now changes, for the sessions in `clients`. Unless it's a `dry_run`,
+  /// the reloaded settings count as loaded from now on, and the configuration to apply them from is
+  /// returned.
+  pub fn reload(
+    &self,
+    clients: &[LiveClient],
+    dry_run: bool,
+  ) -> anyhow::Result<(ConfigDiff, Option<ServerConfig>)> {
+    let current = serde_yml::from_str(&std::fs::read_to_string(&self.path)?)?;
+    let mut loaded = self.loaded.lock().unwrap();
+    let (diff, applied) = reload(&loaded, &current, clients)?;
+    if dry_run {
+      return Ok((diff, None));
+    }
+    let config = serde_yml::from_value(applied.clone())?;
+    *loaded = applied;
+    Ok((diff, Some(config)))
+  }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+  NetworkRemoval,
+  CredentialRemoval,
+  /// The subnet of the session's network no longer holds its address.
+  SubnetChange,
+}
+
+impl fmt::Display for Reason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Reason::NetworkRemoval => write!(f, "network-removal"),
+      Reason::CredentialRemoval => write!(f, "credential-removal"),
+      Reason::SubnetChange => write!(f, "subnet-change"),
+    }
+  }
+}
+
+impl Reason {
+  fn describe(self) -> &'static str {
+    match self {
+      Reason::NetworkRemoval => "network removal",
+      Reason::CredentialRemoval => "credential removal",
+      Reason::SubnetChange => "a subnet change",
+    }
+  }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct AffectedSession {
+  pub session_id: String,
+  pub username: String,
+  pub reason: Reason,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ConfigDiff {
+  /// Lines like "3 sessions will be disconnected due to credential removal".
+  pub summary: Vec<String>,
+  /// Top-level keys whose values change, and `networks.NAME` for tenant networks.
+  pub changed: Vec<String>,
+  /// Keys of `changed` a reload leaves alone, which take effect when the server restarts.
+  pub pending: Vec<String>,
+  pub affected: Vec<AffectedSession>,
+}
+
+/// Compares two parsed configuration files; fails if the new one doesn't load or has problems.
+pub fn diff(old: &Value, new: &Value, clients: &[LiveClient]) -> anyhow::Result<ConfigDiff> {
+  let old_config: ServerConfig = serde_yml::from_value(old.clone())?;
+  let new_config: ServerConfig = serde_yml::from_value(new.clone())?;
+  let problems = new_config.problems();
+  if !problems.is_empty() {
+    anyhow::bail!("The new configuration has problems: {}", problems.join("; "));
+  }
+
+  let changed = changed_keys(old, new);
+  let affected: Vec<_> = clients
+    .iter()
+    .filter_map(|client| {
+      Some(AffectedSession {
+        session_id: client.session.session_id.clone(),
+        username: client.username.clone(),
+        reason: reason(&old_config, &new_config, client)?,
+      })
+    })
+    .collect();
+
+  let summary = summary(&changed, &[], &affected);
+  Ok(ConfigDiff { summary, changed, pending: Vec::new(), affected })
+}
+
+/// What reloading `new` changes against `old`: the `RELOADABLE` settings, and the sessions they cut off,
+/// with the other changes pending. Returns the configuration after the reload too.
+pub fn reload(old: &Value, new: &Value, clients: &[LiveClient]) -> anyhow::Result<(ConfigDiff, Value)> {
+  let restart = diff(old, new, clients)?;
+  let mut applied = old.clone();
+  if let (Some(applied), Some(new)) = (applied.as_mapping_mut(), new.as_mapping()) {
+    for key in RELOADABLE {
+      match new.get(key) {
+        Some(value) => applied.insert(key.into(), value.clone()),
+        None => applied.remove(key),
+      };
+    }
+  }
+
+  let mut diff = diff(old, &applied, clients)?;
+  diff.pending = restart.changed.into_iter().filter(|key| !diff.changed.contains(key)).collect();
+  diff.summary = summary(&diff.changed, &diff.pending, &diff.affected);
+  Ok((diff, applied))
+}
+
+fn summary(changed: &[String], pending: &[String], affected: &[AffectedSession]) -> Vec<String> {
+  let mut counts = BTreeMap::new();
+  for session in affected {
+    *counts.entry(session.reason).or_insert(0) += 1;
+  }
+  let mut summary: Vec<_> = counts
+    .into_iter()
+    .map(|(reason, count)| {
+      let sessions = if count == 1 { "session" } else { "sessions" };
+      format!("{} {} will be disconnected due to {}", count, sessions, reason.describe())
+    })
+    .collect();
+  if changed.is_empty() && pending.is_empty() {
+    summary.push("The configuration is unchanged".to_string());
+  } else if !changed.is_empty() && affected.is_empty() {
+    summary.push(format!("{} setting(s) change, no session is cut off", changed.len()));
+  }
+  if !pending.is_empty() {
+    summary.push(format!("{} setting(s) take effect on restart: {}", pending.len(), pending.join(", ")));
+  }
+  summary
+}
+
+fn changed_keys(old: &Value, new: &Value) -> Vec<String> {
+  let mut changed = Vec::new();
+  diff_mappings(old, new, "", &mut changed);
+  changed
+}
+
+fn diff_mappings(old: &Value, new: &Value, prefix: &str, changed: &mut Vec<String>) {
+  let empty = Mapping::new();
+  let old = old.as_mapping().unwrap_or(&empty);
+  let new = new.as_mapping().unwrap_or(&empty);
+  let keys: BTreeSet<_> = old.keys().chain(new.keys()).filter_map(Value::as_str).collect();
+  for key in keys {
+    let (old, new) = (old.get(key), new.get(key));
+    match key {
+      "networks" if prefix.is_empty() => {
+        diff_mappings(old.unwrap_or(&Value::Null), new.unwrap_or(&Value::Null), "networks.", changed)
+      }
+      _ if old != new => changed.push(format!("{}{}", prefix, key)),
+      _ => (),
+    }
+  }
+}
+
+/// Why `new` would cut off the session of `client`, which `old` let in.
+fn reason(old: &ServerConfig, new: &ServerConfig, client: &LiveClient) -> Option<Reason> {
+  let network = client.session.network.as_deref();
+  let subnet = match network {
+    Some(name) => match new.networks.get(name) {
+      Some(network) => Some(network.subnet),
+      None => return Some(Reason::NetworkRemoval),
+    },
+    None => new.default_subnet(),
+  };
+  if let (Some(address), Some(subnet)) = (client.session.virtual_ip, subnet) {
+    if !subnet.contains(&address) {
+      return Some(Reason::SubnetChange);
+    }
+  }
+
+  // Directories and the password file might know users the configuration doesn't list.
+  let directory =
+    new.ldap.is_some() || new.radius.is_some() || new.oidc.is_some() || new.password_file.is_some();
+  let device = client.session.device.as_deref();
+  let removed =
+    listed(old, network, &client.username, device) && !listed(new, network, &client.username, device);
+  (removed && !directory).then_some(Reason::CredentialRemoval)
+}
+
+/// Whether `config` lists a password or a key of `username` in `network`, or the key of `device`.
+fn listed(config: &ServerConfig, network: Option<&str>, username: &str, device: Option<&str>) -> bool {
+  let (credentials, keys) = match network {
+    Some(name) => match config.networks.get(name) {
+      Some(network) => (&network.client_credentials, &network.client_keys),
+      None => return false,
+    },
+    None => (&config.client_credentials, &config.client_keys),
+  };
+  let key =
+    keys.iter().any(|key| key.username == username && (device.is_none() || key.device.as_deref() == device));
+  key || (device.is_none() && credentials.iter().any(|credentials| credentials.username() == Some(username)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::history::SessionRecord;
+
+  const CONFIG: &str = r#"
+    listen-address: "0.0.0.0"
+    listen-port: 8000
+    max-clients: 10
+    client-timeout-secs: 30
+    tun:
+      name: "vpn%d"
+      address: "10.0.0.1"
+      netmask: "255.255.255.0"
+    address-pool:
+      subnet: "10.0.0.0/24"
+    client-credentials:
+      - type: "password"
+        username: "alice"
+        password: "pass"
+      - type: "password"
+        username: "bob"
+        password: "pass"
+    networks:
+      acme:
+        subnet: "10.1.0.0/24"
+        client-credentials:
+          - type: "password"
+            username: "carol"
+            password: "pass"
+  "#;
+
+  fn client(username: &str, network: Option<&str>, virtual_ip: &str) -> LiveClient {
+    LiveClient {
+      username: username.to_string(),
+      session: SessionRecord {
+        session_id: format!("{}-session", username),
+        client_addr: "198.51.100.1:40000".to_string(),
+        virtual_ip: Some(virtual_ip.parse().unwrap()),
+        device: None,
+        network: network.map(str::to_string),
+        connected_at: 0,
+        duration_secs: 0,
+        bytes_in: 0,
+        bytes_out: 0,
+        active: true,
+      },
+      clock_offset_secs: 0,
+    }
+  }
+
+  fn edit(edit: impl FnOnce(&mut Mapping)) -> Value {
+    let mut config: Value = serde_yml::from_str(CONFIG).unwrap();
+    edit(config.as_mapping_mut().unwrap());
+    config
+  }
+
+  #[test]
+  fn test_unchanged() {
+    let config: Value = serde_yml::from_str(CONFIG).unwrap();
+    let diff = diff(&config, &config, &[client("alice", None, "10.0.0.2")]).unwrap();
+    assert!(diff.changed.is_empty());
+    assert!(diff.affected.is_empty());
+    assert_eq!(diff.summary, vec!["The configuration is unchanged"]);
+  }
+
+  #[test]
+  fn test_affected_sessions() {
+    let old: Value = serde_yml::from_str(CONFIG).unwrap();
+    let new = edit(|config| {
+      config.insert("listen-port".into(), 8001.into());
+      config.insert(
+        "client-credentials".into(),
+        serde_yml::from_str("[{type: password, username: alice, password: pass}]").unwrap(),
+      );
+      config.insert("networks".into(), Value::Mapping(Mapping::new()));
+    });
+    let clients = [
+      client("alice", None, "10.0.0.2"),
+      client("bob", None, "10.0.0.3"),
+      client("carol", Some("acme"), "10.1.0.2"),
+    ];
+
+    let diff = diff(&old, &new, &clients).unwrap();
+    assert_eq!(diff.changed, vec!["client-credentials", "listen-port", "networks.acme"]);
+    let affected: Vec<_> =
+      diff.affected.iter().map(|session| (session.username.as_str(), session.reason)).collect();
+    assert_eq!(affected, vec![("bob", Reason::CredentialRemoval), ("carol", Reason::NetworkRemoval)]);
+    assert_eq!(
+      diff.summary,
+      vec![
+        "1 session will be disconnected due to network removal",
+        "1 session will be disconnected due to credential removal"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_subnet_change() {
+    let old: Value = serde_yml::from_str(CONFIG).unwrap();
+    let new = edit(|config| {
+      config.insert("address-pool".into(), serde_yml::from_str("{subnet: 10.0.0.0/29}").unwrap());
+    });
+    let clients = [client("alice", None, "10.0.0.2"), client("bob", None, "10.0.0.20")];
+    let diff = diff(&old, &new, &clients).unwrap();
+    assert_eq!(diff.affected.len(), 1);
+    assert_eq!((diff.affected[0].username.as_str(), diff.affected[0].reason), ("bob", Reason::SubnetChange));
+  }
+
+  #[test]
+  fn test_reload() {
+    let old: Value = serde_yml::from_str(CONFIG).unwrap();
+    let new = edit(|config| {
+      config.insert("listen-port".into(), 8001.into());
+      config.insert(
+        "client-credentials".into(),
+        serde_yml::from_str("[{type: password, username: alice, password: pass}]").unwrap(),
+      );
+      config.insert("networks".into(), Value::Mapping(Mapping::new()));
+    });
+    let clients = [client("bob", None, "10.0.0.3"), client("carol", Some("acme"), "10.1.0.2")];
+
+    let (diff, applied) = reload(&old, &new, &clients).unwrap();
+    assert_eq!(diff.changed, vec!["client-credentials"]);
+    assert_eq!(diff.pending, vec!["listen-port", "networks.acme"]);
+    let affected: Vec<_> =
+      diff.affected.iter().map(|session| (session.username.as_str(), session.reason)).collect();
+    assert_eq!(affected, vec![("bob", Reason::CredentialRemoval)]);
+    assert_eq!(
+      diff.summary,
+      vec![
+        "1 session will be disconnected due to credential removal",
+        "2 setting(s) take effect on restart: listen-port, networks.acme"
+      ]
+    );
+
+    // Reloading again applies nothing more, but the pending settings still change.
+    let (diff, _) = reload(&applied, &new, &clients).unwrap();
+    assert!(diff.changed.is_empty() && diff.affected.is_empty());
+    assert_eq!(diff.summary, vec!["2 setting(s) take effect on restart: listen-port, networks.acme"]);
+
+    let applied: ServerConfig = serde_yml::from_value(applied).unwrap();
+    assert_eq!((applied.client_credentials.len(), applied.listen_port), (1, 8000));
+    assert!(applied.networks.contains_key("acme"));
+  }
+
+  #[test]
+  fn test_invalid_configuration() {
+    let old: Value = serde_yml::from_str(CONFIG).unwrap();
+    let new = edit(|config| {
+      config.remove("listen-port");
+    });
+    assert!(diff(&old, &new, &[]).is_err());
+  }
+}
@@ -0,0 +1,255 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use serde::Deserialize;
+use tracing::debug;
+use tracing::info;
+use tracing::trace;
+use tracing::warn;
+use vpn_shared::ip;
+use vpn_shared::rendezvous::RendezvousPacket;
+use vpn_shared::rendezvous::MAX_NAME_LEN;
+
+use crate::server::Server;
+
+/// Registrations not refreshed for this long are forgotten, letting another server take the name.
+pub const REGISTRATION_LIFETIME: Duration = Duration::from_secs(120);
+
+/// Names a server serving as a rendezvous keeps at most.
+const MAX_REGISTRATIONS: usize = 4096;
+
+/// Punches to the same client are sent at most this often, however many lookups introduce it.
+const PUNCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Registers the server with a rendezvous from its listen socket, so clients can reach a server behind NAT
+/// without port forwarding, see `vpn_shared::rendezvous`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RendezvousConfig {
+  /// `host:port` of the rendezvous, looked up again before every registration.
+  pub address: String,
+  /// Name clients look the server up by.
+  pub name: String,
+  /// Has to be shorter than the NAT's UDP timeout, and than `REGISTRATION_LIFETIME`.
+  #[serde(default = "default_interval_secs")]
+  pub interval_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+  20
+}
+
+impl RendezvousConfig {
+  pub fn problems(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+    if self.name.is_empty() || self.name.len() > MAX_NAME_LEN {
+      problems.push(format!("rendezvous.name must be 1 to {} bytes long", MAX_NAME_LEN));
+    }
+    if self.interval_secs == 0 || Duration::from_secs(self.interval_secs) >= REGISTRATION_LIFETIME / 2 {
+      problems.push(format!(
+        "rendezvous.interval-secs must be at least 1 and below {}",
+        REGISTRATION_LIFETIME.as_secs() / 2
+      ));
+    }
+    problems
+  }
+}
+
+/// Registration of this server with a rendezvous.
+pub struct Registration {
+  pub config: RendezvousConfig,
+  /// Address the rendezvous was last looked up to; introductions are only taken from there.
+  rendezvous: Mutex<Option<SocketAddr>>,
+  /// Address the rendezvous sees the server at, once it answered.
+  observed: Mutex<Option<SocketAddr>>,
+  /// When each introduced client was last sent a punch.
+  punched: DashMap<SocketAddr, Instant>,
+}
+
+impl Registration {
+  pub fn new(config: RendezvousConfig) -> Self {
+    Self { config, rendezvous: Mutex::default(), observed: Mutex::default(), punched: DashMap::new() }
+  }
+
+  fn is_rendezvous(&self, addr: SocketAddr) -> bool {
+    *self.rendezvous.lock().unwrap() == Some(addr)
+  }
+
+  /// Whether `client` is due a punch; punches aren't authenticated, so they're paced per address.
+  fn punch(&self, client: SocketAddr, now: Instant) -> bool {
+    let due = self.punched.get(&client).is_none_or(|at| now.duration_since(*at) >= PUNCH_INTERVAL);
+    if due {
+      self.punched.insert(client, now);
+    }
+    due
+  }
+}
+
+/// Names registered with this server by servers behind NAT, for `rendezvous-service`.
+#[derive(Default)]
+pub struct NameRegistry {
+  names: DashMap<String, (SocketAddr, Instant)>,
+}
+
+impl NameRegistry {
+  /// Registers `name` at `addr` unless another address holds it; returns whether it did.
+  pub fn register(&self, name: &str, addr: SocketAddr, now: Instant) -> bool {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+      return false;
+    }
+    if let Some(mut entry) = self.names.get_mut(name) {
+      let (holder, seen) = *entry;
+      if holder != addr && now.duration_since(seen) < REGISTRATION_LIFETIME {
+        return false;
+      }
+      *entry = (addr, now);
+      return true;
+    }
+    if self.names.len() >= MAX_REGISTRATIONS {
+      return false;
+    }
+    self.names.insert(name.to_string(), (addr, now));
+    true
+  }
+
+  pub fn lookup(&self, name: &str, now: Instant) -> Option<SocketAddr> {
+    let (addr, seen) = *self.names.get(name)?;
+    (now.duration_since(seen) < REGISTRATION_LIFETIME).then_some(addr)
+  }
+
+  pub fn prune(&self, now: Instant) {
+    self.names.retain(|_, (_, seen)| now.duration_since(*seen) < REGISTRATION_LIFETIME);
+  }
+}
+
+impl Server {
+  /// Registers with the rendezvous every `interval-secs`, which also keeps the NAT mapping of the listen
+  /// socket open.
+  pub async fn register_rendezvous(self: Arc<Self>) {
+    let Some(ref registration) = self.rendezvous else {
+      return;
+    };
+    let config = &registration.config;
+    let interval = Duration::from_secs(config.interval_secs);
+    loop {
+      match self.send_registration(registration).await {
+        Ok(rendezvous) => trace!("Registered as {} with the rendezvous {}", config.name, rendezvous),
+        Err(e) => warn!("Failed to register with the rendezvous {}: {}", config.address, e),
+      }
+      let now = Instant::now();
+      registration.punched.retain(|_, at| now.duration_since(*at) < PUNCH_INTERVAL);
+      tokio::time::sleep(interval).await;
+    }
+  }
+
+  async fn send_registration(&self, registration: &Registration) -> anyhow::Result<SocketAddr> {
+    let config = &registration.config;
+    let rendezvous = tokio::net::lookup_host(&config.address)
+      .await?
+      .find(SocketAddr::is_ipv4)
+      .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", config.address))?;
+    *registration.rendezvous.lock().unwrap() = Some(rendezvous);
+    self.send_rendezvous(RendezvousPacket::Register { name: config.name.clone() }, rendezvous).await?;
+    Ok(rendezvous)
+  }
+
+  /// Handles a datagram of `RENDEZVOUS_SESSION`: registrations and lookups with `rendezvous-service`, and
+  /// answers of the rendezvous this server registers with.
+  pub async fn handle_rendezvous(&self, src_addr: SocketAddr, datagram: &[u8]) -> anyhow::Result<()> {
+    let packet = RendezvousPacket::decode(datagram)?;
+    match (packet, &self.rendezvous_service, &self.rendezvous) {
+      (RendezvousPacket::Register { name }, Some(registry), _) => {
+        if !registry.register(&name, src_addr, Instant::now()) {
+          debug!("Refusing the registration of {} as {}: the name is taken or too many are", src_addr, name);
+          return Ok(());
+        }
+        self.send_rendezvous(RendezvousPacket::Registered { observed: src_addr }, src_addr).await
+      }
+      (RendezvousPacket::Lookup { name }, Some(registry), _) => {
+        let addr = registry.lookup(&name, Instant::now());
+        debug!("Client {} looked up {}: {:?}", src_addr, name, addr);
+        if let Some(server) = addr {
+          self.send_rendezvous(RendezvousPacket::Introduce { client: src_addr }, server).await?;
+        }
+        self.send_rendezvous(RendezvousPacket::Located { name, addr }, src_addr).await
+      }
+      (RendezvousPacket::Registered { observed }, _, Some(registration))
+        if registration.is_rendezvous(src_addr) =>
+      {
+        let previous = registration.observed.lock().unwrap().replace(observed);
+        if previous != Some(observed) {
+          info!(
+            "Reachable as {} through the rendezvous {} at {}",
+            registration.config.name, src_addr, observed
+          );
+        }
+        Ok(())
+      }
+      (RendezvousPacket::Introduce { client }, _, Some(registration))
+        if registration.is_rendezvous(src_addr) =>
+      {
+        if registration.punch(client, Instant::now()) {
+          debug!("Opening the NAT to {}, introduced by the rendezvous", client);
+          self.send_rendezvous(RendezvousPacket::Punch, client).await?;
+        }
+        Ok(())
+      }
+      (packet, _, _) => {
+        trace!("Ignoring rendezvous packet from {}: {:?}", src_addr, packet);
+        Ok(())
+      }
+    }
+  }
+
+  async fn send_rendezvous(&self, packet: RendezvousPacket, addr: SocketAddr) -> anyhow::Result<()> {
+    self.socket.send_to(&packet.encode()?, addr, ip::ECN_NOT_ECT).await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_registry() {
+    let registry = NameRegistry::default();
+    let now = Instant::now();
+    let home: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+    let other: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+
+    assert!(registry.register("home", home, now));
+    assert!(!registry.register("home", other, now));
+    assert!(!registry.register(&"x".repeat(MAX_NAME_LEN + 1), other, now));
+    assert_eq!(registry.lookup("home", now), Some(home));
+    assert_eq!(registry.lookup("away", now), None);
+
+    // The name frees up once its registrations stop.
+    let later = now + REGISTRATION_LIFETIME;
+    assert_eq!(registry.lookup("home", later), None);
+    assert!(registry.register("home", other, later));
+    assert_eq!(registry.lookup("home", later), Some(other));
+
+    registry.prune(later + REGISTRATION_LIFETIME);
+    assert!(registry.names.is_empty());
+  }
+
+  #[test]
+  fn test_punches_are_paced() {
+    let registration = Registration::new(RendezvousConfig {
+      address: "rendezvous.example.com:9696".to_string(),
+      name: "home".to_string(),
+      interval_secs: default_interval_secs(),
+    });
+    assert!(registration.config.problems().is_empty());
+    let now = Instant::now();
+    let client: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+    assert!(registration.punch(client, now));
+    assert!(!registration.punch(client, now + PUNCH_INTERVAL / 2));
+    assert!(registration.punch(client, now + PUNCH_INTERVAL));
+  }
+}
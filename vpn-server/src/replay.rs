@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use vpn_shared::packet::Key;
+
+/// Rejects key exchanges that were captured and sent again, which would otherwise open ghost sessions or
+/// replace the session of the client they were captured from. Exchanges carry the time they were sent at:
+/// those outside the allowed clock skew are refused, and the ephemeral keys of the rest are remembered for
+/// as long as their timestamp stays acceptable.
+#[derive(Debug)]
+pub struct ReplayCache {
+  skew: Duration,
+  seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+  keys: HashSet<Key>,
+  /// Keys in the order they were seen, with the time they can be forgotten at.
+  expiry: VecDeque<(u64, Key)>,
+}
+
+impl ReplayCache {
+  pub fn new(skew: Duration) -> Self {
+    Self { skew, seen: Mutex::new(Seen::default()) }
+  }
+
+  /// Checks a key exchange with ephemeral `key`, sent at `timestamp` and received at `now` in seconds since
+  /// the Unix epoch.
+  pub fn check(&self, key: &Key, timestamp: u64, now: u64) -> anyhow::Result<()> {
+    let skew = self.skew.as_secs();
+    if timestamp.abs_diff(now) > skew {
+      anyhow::bail!(
+        "the client's clock is {}, more than the {}s allowed",
+        clock_offset(timestamp as i64 - now as i64),
+        skew
+      );
+    }
+
+    let mut seen = self.seen.lock().unwrap();
+    while seen.expiry.front().is_some_and(|(expires, _)| *expires < now) {
+      let (_, key) = seen.expiry.pop_front().unwrap();
+      seen.keys.remove(&key);
+    }
+    if !seen.keys.insert(*key) {
+      anyhow::bail!("Replayed key exchange");
+    }
+    // The timestamp is at most `skew` ahead of now and stops being accepted `skew` after itself.
+    seen.expiry.push_back((now + 2 * skew, *key));
+    Ok(())
+  }
+}
+
+/// Describes `offset` seconds of a client's clock from the server's, e.g. "95s behind the server's".
+pub fn clock_offset(offset: i64) -> String {
+  match offset {
+    0 => "in step with the server's".to_string(),
+    offset if offset > 0 => format!("{}s ahead of the server's", offset),
+    offset => format!("{}s behind the server's", -offset),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check() {
+    let cache = ReplayCache::new(Duration::from_secs(30));
+    let now = 1_700_000_000;
+
+    assert!(cache.check(&[1; 32], now - 10, now).is_ok());
+    assert!(cache.check(&[1; 32], now - 10, now + 1).is_err());
+    assert!(cache.check(&[2; 32], now + 31, now).is_err());
+    assert!(cache.check(&[2; 32], now - 31, now).is_err());
+    assert!(cache.check(&[2; 32], now + 30, now).is_ok());
+
+    // Forgotten once the timestamps they came with would be refused anyway.
+    assert!(cache.check(&[1; 32], now + 61, now + 61).is_ok());
+    assert!(cache.check(&[2; 32], now + 30, now + 61).is_err());
+    assert_eq!(cache.seen.lock().unwrap().keys.len(), 1);
+
+    let error = cache.check(&[3; 32], now - 95, now).unwrap_err().to_string();
+    assert_eq!(error, "the client's clock is 95s behind the server's, more than the 30s allowed");
+  }
+}
@@ -0,0 +1,346 @@
+//! `vpn-server report`: a capacity report for operators from what the server persists about sessions, the
+//! audit log when it's written to a file or a SQLite database, or else the session history. It gives the
+//! peak of concurrent clients, the 95th percentile of hourly throughput and authentication failures, in
+//! total and day by day.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::warn;
+
+#[cfg(feature = "sqlite")]
+use crate::audit;
+use crate::audit::AuditConfig;
+use crate::audit::AuditRecord;
+use crate::config::ServerConfig;
+use crate::history;
+use crate::schedule;
+
+const HOUR_SECS: u64 = 60 * 60;
+pub const DAY_SECS: u64 = 24 * HOUR_SECS;
+
+/// A finished session; times are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+  pub username: String,
+  pub start: u64,
+  pub end: u64,
+  /// Received and sent.
+  pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Records {
+  pub sessions: Vec<Session>,
+  /// When authentications failed; `None` if the records don't keep them.
+  pub auth_failures: Option<Vec<u64>>,
+}
+
+impl Records {
+  /// Reads the records of the audit log, if it's a file or a database, or else of the session history. A
+  /// database is only read from `since` on, the rest in full.
+  #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+  pub fn load(config: &ServerConfig, since: u64) -> anyhow::Result<Self> {
+    match (&config.audit, &config.history.path) {
+      (Some(AuditConfig::File { path }), _) => Self::from_audit_log(path),
+      #[cfg(feature = "sqlite")]
+      (Some(AuditConfig::Sqlite { path }), _) => {
+        Ok(Self::from_audit_records(audit::read_records(path, &["end", "auth-failure"], since)?))
+      }
+      #[cfg(not(feature = "sqlite"))]
+      (Some(AuditConfig::Sqlite { path }), _) => anyhow::bail!(
+        "The audit database {} is configured, but the server was built without the sqlite feature",
+        path.display()
+      ),
+      (_, Some(path)) => Self::from_history(path),
+      _ => anyhow::bail!("The report requires an audit log of type file or sqlite, or a history path"),
+    }
+  }
+
+  pub fn from_audit_log(path: &Path) -> anyhow::Result<Self> {
+    Ok(Self::from_audit_records(read_lines::<AuditRecord>(path)?))
+  }
+
+  /// The `end` and `auth-failure` events of an audit log.
+  pub fn from_audit_records(audit: impl IntoIterator<Item = AuditRecord>) -> Self {
+    let mut records = Self { sessions: Vec::new(), auth_failures: Some(Vec::new()) };
+    for record in audit {
+      match record.event.as_str() {
+        "end" => records.sessions.push(Session {
+          start: record.timestamp.saturating_sub(record.duration_secs),
+          end: record.timestamp,
+          bytes: record.bytes_in + record.bytes_out,
+          username: record.username,
+        }),
+        "auth-failure" => records.auth_failures.get_or_insert_default().push(record.timestamp),
+        _ => {}
+      }
+    }
+    records
+  }
+
+  /// The sessions of a session history file, which doesn't keep authentication failures.
+  pub fn from_history(path: &Path) -> anyhow::Result<Self> {
+    let sessions = read_lines::<history::Line>(path)?.into_iter().map(|line| Session {
+      start: line.session.connected_at,
+      end: line.session.connected_at + line.session.duration_secs,
+      bytes: line.session.bytes_in + line.session.bytes_out,
+      username: line.username,
+    });
+    Ok(Self { sessions: sessions.collect(), auth_failures: None })
+  }
+}
+
+fn read_lines<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<T>> {
+  if !path.exists() {
+    anyhow::bail!("{} not found", path.display());
+  }
+
+  let mut lines = Vec::new();
+  for text in std::io::BufReader::new(std::fs::File::open(path)?).lines() {
+    match serde_json::from_str(&text?) {
+      Ok(line) => lines.push(line),
+      Err(e) => warn!("Skipping a malformed line of {}: {}", path.display(), e),
+    }
+  }
+  Ok(lines)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CapacityReport {
+  /// Seconds since the Unix epoch the report covers, from and until.
+  pub from: u64,
+  pub until: u64,
+  pub sessions: usize,
+  pub users: usize,
+  pub peak_clients: usize,
+  /// When the peak was first reached.
+  pub peak_clients_at: Option<u64>,
+  /// 95th percentile and maximum of the throughput of every hour, both directions, in bits per second.
+  pub p95_throughput_bps: u64,
+  pub peak_throughput_bps: u64,
+  /// `None` if the records don't keep them.
+  pub auth_failures: Option<usize>,
+  pub days: Vec<Day>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Day {
+  /// `YYYY-MM-DD`, in UTC.
+  pub date: String,
+  /// Sessions started that day, or carried into the report from before it.
+  pub sessions: usize,
+  pub peak_clients: usize,
+  pub bytes: u64,
+  pub auth_failures: Option<usize>,
+}
+
+/// Report of the sessions overlapping `from..until`; a session's bytes are taken as spread evenly over it.
+pub fn report(records: &Records, from: u64, until: u64) -> CapacityReport {
+  let until = until.max(from + 1);
+  let sessions: Vec<_> = records
+    .sessions
+    .iter()
+    .filter(|session| session.end.max(session.start + 1) > from && session.start < until)
+    .collect();
+
+  let mut days: BTreeMap<u64, Day> = (from / DAY_SECS..=(until - 1) / DAY_SECS)
+    .map(|day| {
+      let auth_failures = records.auth_failures.as_ref().map(|_| 0);
+      (day, Day { date: date(day * DAY_SECS), sessions: 0, peak_clients: 0, bytes: 0, auth_failures })
+    })
+    .collect();
+
+  let first_hour = from / HOUR_SECS;
+  let mut hourly = vec![0u64; ((until - 1) / HOUR_SECS - first_hour + 1) as usize];
+  let mut changes = Vec::with_capacity(sessions.len() * 2);
+  for session in &sessions {
+    let end = session.end.max(session.start + 1);
+    let (start, clamped_end) = (session.start.max(from), end.min(until));
+    if let Some(day) = days.get_mut(&(start / DAY_SECS)) {
+      day.sessions += 1;
+    }
+    changes.extend([(start, 1), (clamped_end, -1)]);
+
+    for hour in start / HOUR_SECS..=(clamped_end - 1) / HOUR_SECS {
+      let overlap = clamped_end.min((hour + 1) * HOUR_SECS) - start.max(hour * HOUR_SECS);
+      let share = u128::from(session.bytes) * u128::from(overlap) / u128::from(end - session.start);
+      hourly[(hour - first_hour) as usize] += share as u64;
+    }
+  }
+
+  for (i, bytes) in hourly.iter().enumerate() {
+    if let Some(day) = days.get_mut(&((first_hour + i as u64) * HOUR_SECS / DAY_SECS)) {
+      day.bytes += bytes;
+    }
+  }
+
+  // Ends sort before starts at the same second, so back-to-back sessions don't count as concurrent.
+  changes.sort();
+  let (mut clients, mut peak, mut peak_at) = (0i64, 0, None);
+  let mut current_day = from / DAY_SECS;
+  for (at, change) in changes {
+    // Clients connected across midnight count towards the days they were connected on.
+    for day in current_day + 1..=at / DAY_SECS {
+      if let Some(day) = days.get_mut(&day) {
+        day.peak_clients = day.peak_clients.max(clients as usize);
+      }
+    }
+    current_day = current_day.max(at / DAY_SECS);
+
+    clients += change;
+    if change > 0 {
+      if let Some(day) = days.get_mut(&(at / DAY_SECS)) {
+        day.peak_clients = day.peak_clients.max(clients as usize);
+      }
+      if clients as usize > peak {
+        (peak, peak_at) = (clients as usize, Some(at));
+      }
+    }
+  }
+
+  let failures: Option<Vec<_>> = records
+    .auth_failures
+    .as_ref()
+    .map(|failures| failures.iter().filter(|&&at| at >= from && at < until).collect());
+  for at in failures.iter().flatten() {
+    if let Some(count) = days.get_mut(&(**at / DAY_SECS)).and_then(|day| day.auth_failures.as_mut()) {
+      *count += 1;
+    }
+  }
+
+  let mut rates: Vec<_> = hourly.iter().map(|bytes| bytes * 8 / HOUR_SECS).collect();
+  rates.sort_unstable();
+  CapacityReport {
+    from,
+    until,
+    sessions: sessions.len(),
+    users: sessions.iter().map(|session| session.username.as_str()).collect::<HashSet<_>>().len(),
+    peak_clients: peak,
+    peak_clients_at: peak_at,
+    p95_throughput_bps: rates[(rates.len() * 95).div_ceil(100) - 1],
+    peak_throughput_bps: rates[rates.len() - 1],
+    auth_failures: failures.map(|failures| failures.len()),
+    days: days.into_values().collect(),
+  }
+}
+
+impl CapacityReport {
+  pub fn text(&self) -> String {
+    let peak_at = self
+      .peak_clients_at
+      .map(|at| format!(" at {} {:02}:{:02}", date(at), at % DAY_SECS / HOUR_SECS, at % HOUR_SECS / 60));
+    let failures =
+      |failures: Option<usize>| failures.map_or("-".to_string(), |failures| failures.to_string());
+
+    let mut text = format!("Capacity report from {} to {} (UTC)\n\n", date(self.from), date(self.until - 1));
+    text += &format!("sessions        {} of {} users\n", self.sessions, self.users);
+    text += &format!("peak clients    {}{}\n", self.peak_clients, peak_at.unwrap_or_default());
+    text += &format!(
+      "throughput      {} at the 95th percentile of hours, {} at the peak\n",
+      si(self.p95_throughput_bps, "bit/s"),
+      si(self.peak_throughput_bps, "bit/s")
+    );
+    text += &format!("auth failures   {}\n\n", failures(self.auth_failures));
+
+    text += "DATE        SESSIONS  PEAK_CLIENTS  AUTH_FAILURES  BYTES\n";
+    for day in &self.days {
+      text += &format!(
+        "{}  {:>8}  {:>12}  {:>13}  {}\n",
+        day.date,
+        day.sessions,
+        day.peak_clients,
+        failures(day.auth_failures),
+        si(day.bytes, "B")
+      );
+    }
+    text
+  }
+}
+
+/// `value` with a decimal prefix, e.g. 12.3 Mbit/s.
+fn si(value: u64, unit: &str) -> String {
+  let prefixes = ["", "k", "M", "G", "T", "P"];
+  let power = (0..prefixes.len()).rev().find(|&power| value >= 1000u64.pow(power as u32)).unwrap_or_default();
+  match power {
+    0 => format!("{} {}", value, unit),
+    _ => format!("{:.1} {}{}", value as f64 / 1000f64.powi(power as i32), prefixes[power], unit),
+  }
+}
+
+/// `YYYY-MM-DD` of a time in UTC.
+fn date(secs: u64) -> String {
+  let (year, month, day) = schedule::civil_from_days((secs / DAY_SECS) as i64);
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn session(username: &str, start: u64, end: u64, bytes: u64) -> Session {
+    Session { username: username.into(), start, end, bytes }
+  }
+
+  #[test]
+  fn test_report() {
+    // 2024-03-01 and the day after.
+    let from = 1_709_251_200;
+    let records = Records {
+      sessions: vec![
+        session("alice", from, from + 2 * HOUR_SECS, 7200 * 1000),
+        session("bob", from + HOUR_SECS, from + HOUR_SECS + 1800, 900 * 1000),
+        // Back to back with alice's, not concurrent with it.
+        session("alice", from + 2 * HOUR_SECS, from + 3 * HOUR_SECS, 0),
+        session("carol", from + DAY_SECS - HOUR_SECS, from + DAY_SECS + HOUR_SECS, 0),
+        session("dave", from - DAY_SECS, from - 1, 1000),
+      ],
+      auth_failures: Some(vec![from + 10, from + DAY_SECS + 10, from + 2 * DAY_SECS]),
+    };
+
+    let report = report(&records, from, from + 2 * DAY_SECS);
+    assert_eq!((report.sessions, report.users), (4, 3));
+    assert_eq!((report.peak_clients, report.peak_clients_at), (2, Some(from + HOUR_SECS)));
+    // alice at 1000 B/s for two hours, with bob's 500 B/s over half of the second; the rest is idle.
+    assert_eq!(report.peak_throughput_bps, 10_000);
+    assert_eq!(report.p95_throughput_bps, 0);
+    assert_eq!(report.auth_failures, Some(2));
+
+    let days: Vec<_> = report
+      .days
+      .iter()
+      .map(|day| (day.date.as_str(), day.sessions, day.peak_clients, day.bytes, day.auth_failures))
+      .collect();
+    assert_eq!(days, [("2024-03-01", 4, 2, 8_100_000, Some(1)), ("2024-03-02", 0, 1, 0, Some(1))]);
+  }
+
+  #[test]
+  fn test_p95_throughput() {
+    let sessions =
+      (0..20).map(|hour| session("alice", hour * HOUR_SECS, (hour + 1) * HOUR_SECS, (hour + 1) * 450));
+    let records = Records { sessions: sessions.collect(), auth_failures: None };
+    let report = report(&records, 0, 20 * HOUR_SECS);
+    assert_eq!((report.p95_throughput_bps, report.peak_throughput_bps), (19, 20));
+  }
+
+  #[test]
+  fn test_without_auth_failures() {
+    let records = Records { sessions: vec![session("alice", 0, 10, 100)], auth_failures: None };
+    let report = report(&records, 0, DAY_SECS);
+    assert_eq!(report.auth_failures, None);
+    assert_eq!(report.days[0].auth_failures, None);
+    assert!(report.text().contains("auth failures   -\n"));
+  }
+
+  #[test]
+  fn test_formatting() {
+    assert_eq!(date(0), "1970-01-01");
+    assert_eq!(date(1_709_251_200 - 1), "2024-02-29");
+    assert_eq!(si(999, "B"), "999 B");
+    assert_eq!(si(12_345_678, "bit/s"), "12.3 Mbit/s");
+  }
+}
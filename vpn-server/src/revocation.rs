@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use tracing::info;
+use vpn_shared::handshake;
+use vpn_shared::packet::Key;
+
+/// Client public keys that may no longer authenticate, one hex key per line; `#` starts a comment. The file
+/// is re-read whenever it changes, so keys revoked with `--revoke` take effect on a running server.
+#[derive(Debug, Default)]
+pub struct RevocationList {
+  path: Option<PathBuf>,
+  revoked: RwLock<HashSet<Key>>,
+  modified: Mutex<Option<SystemTime>>,
+}
+
+impl RevocationList {
+  pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+    let list = Self { path: Some(path), ..Default::default() };
+    list.reload()?;
+    Ok(list)
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.path.is_some()
+  }
+
+  pub fn is_revoked(&self, key: &Key) -> bool {
+    self.revoked.read().unwrap().contains(key)
+  }
+
+  /// Re-reads the file if it was modified since the last load; returns whether it was.
+  pub fn reload(&self) -> anyhow::Result<bool> {
+    let Some(ref path) = self.path else {
+      return Ok(false);
+    };
+
+    let modified = match std::fs::metadata(path) {
+      Ok(metadata) => Some(metadata.modified()?),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e.into()),
+    };
+
+    let mut last_modified = self.modified.lock().unwrap();
+    if modified.is_some() && *last_modified == modified {
+      return Ok(false);
+    }
+
+    let revoked = match modified {
+      Some(_) => parse(&std::fs::read_to_string(path)?)?,
+      None => HashSet::new(),
+    };
+
+    info!("Loaded {} revoked keys from {}", revoked.len(), path.display());
+    *self.revoked.write().unwrap() = revoked;
+    *last_modified = modified;
+    Ok(true)
+  }
+}
+
+fn parse(contents: &str) -> anyhow::Result<HashSet<Key>> {
+  contents
+    .lines()
+    .map(|line| line.split('#').next().unwrap_or_default().trim())
+    .filter(|line| !line.is_empty())
+    .map(handshake::parse_key)
+    .collect()
+}
+
+pub fn append(path: &Path, key: &Key) -> anyhow::Result<()> {
+  let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+  writeln!(file, "{}", handshake::encode_key(key))?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let key = [0xab; 32];
+    let revoked = parse(&format!("# lost laptop\n{}  # alice\n\n", handshake::encode_key(&key))).unwrap();
+
+    assert_eq!(revoked, HashSet::from([key]));
+    assert!(parse("not a key").is_err());
+  }
+}
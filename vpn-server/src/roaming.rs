@@ -0,0 +1,117 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::ip;
+use vpn_shared::packet::fill_random_bytes;
+use vpn_shared::packet::ClientPacket;
+use vpn_shared::packet::Features;
+use vpn_shared::packet::ServerPacket;
+
+use crate::server::Server;
+
+/// Challenges to the same address are sent at most this often, however many packets arrive from it.
+const CHALLENGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Challenge sent to the new address of a session; the session moves there once the client answers it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathChallenge {
+  pub addr: SocketAddr,
+  pub nonce: u64,
+  pub sent_at: Instant,
+}
+
+impl Server {
+  /// Handles a packet of the session at `from` that arrived from `to`. Decrypting proves it was sealed with
+  /// the session key, but not that it wasn't replayed from a spoofed address, so the session only moves
+  /// after the client answers a challenge sent to `to`. `local` is the address it arrived on.
+  pub async fn roam(
+    &self,
+    from: SocketAddr,
+    to: SocketAddr,
+    local: Option<Ipv4Addr>,
+    packet: ClientPacket,
+  ) -> anyhow::Result<()> {
+    let ClientPacket::PathResponse(nonce) = packet else {
+      return self.challenge(from, to, local).await;
+    };
+
+    let valid = self
+      .clients
+      .get(&from)
+      .and_then(|client| client.path_challenge)
+      .is_some_and(|challenge| challenge.addr == to && challenge.nonce == nonce);
+    if !valid {
+      debug!("Ignoring a path response of {} from {} without a matching challenge", from, to);
+      return Ok(());
+    }
+
+    if self.migrate(from, to, local) && self.rekeying.on_roaming {
+      self.rekey(to, "the session moved to a new address").await?;
+    }
+    Ok(())
+  }
+
+  async fn challenge(&self, from: SocketAddr, to: SocketAddr, local: Option<Ipv4Addr>) -> anyhow::Result<()> {
+    let (key, session_id, pipeline, nonce) = {
+      let Some(mut client) = self.clients.get_mut(&from) else {
+        return Ok(());
+      };
+      if !client.features.contains(Features::ROAMING) {
+        debug!("Session of {} is used from {}, but the client didn't negotiate roaming", from, to);
+        return Ok(());
+      }
+
+      if client.path_challenge.is_some_and(|c| c.addr == to && c.sent_at.elapsed() < CHALLENGE_INTERVAL) {
+        return Ok(());
+      }
+
+      let mut nonce = [0u8; 8];
+      fill_random_bytes(&mut nonce);
+      let nonce = u64::from_be_bytes(nonce);
+      client.path_challenge = Some(PathChallenge { addr: to, nonce, sent_at: Instant::now() });
+      (client.key, client.session_id, client.pipeline.clone(), nonce)
+    };
+
+    debug!("Session of {} is used from {}; validating the new path", from, to);
+    let datagram = pipeline.seal(&key, session_id, &ServerPacket::PathChallenge(nonce))?;
+    self.socket.send_from(&datagram, to, ip::ECN_NOT_ECT, local).await?;
+    Ok(())
+  }
+
+  /// Moves the session at `from` to `to`; false if it's gone or `to` is taken.
+  fn migrate(&self, from: SocketAddr, to: SocketAddr, local: Option<Ipv4Addr>) -> bool {
+    if self.clients.contains_key(&to) {
+      warn!("Not moving the session of {} to {}: another session uses it", from, to);
+      return false;
+    }
+
+    let Some((_, mut client)) = self.clients.remove(&from) else {
+      return false;
+    };
+
+    client.addr = to;
+    client.path_challenge = None;
+    client.last_seen.touch();
+    client.local = local;
+    client.outbound = self.send_queue(to, local, &client.policy.bandwidth_classes);
+
+    self.sessions.insert(client.session_id, to);
+    if let Some(virtual_ip) = client.virtual_ip {
+      self.virtual_ips.insert(virtual_ip, to);
+    }
+    for subnet in &client.subnets {
+      self.subnet_routes.insert(*subnet, to);
+    }
+    self.record_roam(&client, from);
+    // Hostnames follow the session id, so they need no update.
+    self.clients.insert(to, client);
+
+    info!("Client {} moved to {}", from, to);
+    true
+  }
+}
@@ -0,0 +1,64 @@
+use std::cell::Cell;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use core_affinity::CoreId;
+use serde::Deserialize;
+use tokio::runtime::Runtime;
+
+use tracing::warn;
+
+thread_local! {
+  static PINNED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RuntimeConfig {
+  /// Defaults to the number of cores, like `#[tokio::main]`.
+  pub worker_threads: Option<usize>,
+  pub max_blocking_threads: Option<usize>,
+
+  /// CPUs the runtime's worker threads, which run the packet workers, are pinned to, round-robin. Threads of
+  /// the blocking pool, e.g. for offloaded crypto, stay free to run on any CPU.
+  pub cpu_affinity: Vec<usize>,
+}
+
+impl RuntimeConfig {
+  pub fn build(&self) -> anyhow::Result<Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(threads) = self.worker_threads {
+      builder.worker_threads(threads);
+    }
+
+    if let Some(threads) = self.max_blocking_threads {
+      builder.max_blocking_threads(threads);
+    }
+
+    if !self.cpu_affinity.is_empty() {
+      let available = core_affinity::get_core_ids().unwrap_or_default();
+      if let Some(cpu) = self.cpu_affinity.iter().find(|cpu| !available.iter().any(|core| core.id == **cpu)) {
+        anyhow::bail!("CPU {} from cpu-affinity is not available", cpu);
+      }
+
+      let cores: Arc<[CoreId]> = self.cpu_affinity.iter().map(|&id| CoreId { id }).collect();
+      let next = AtomicUsize::new(0);
+      // Worker threads are started by the blocking pool too, so `on_thread_start` can't tell them apart;
+      // only worker threads park, though, and each pins itself the first time it runs out of tasks.
+      builder.on_thread_park(move || {
+        if PINNED.replace(true) {
+          return;
+        }
+        let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+        if !core_affinity::set_for_current(core) {
+          warn!("Failed to pin runtime thread to CPU {}", core.id);
+        }
+      });
+    }
+
+    Ok(builder.build()?)
+  }
+}
@@ -0,0 +1,331 @@
+//! Periodic jobs the server runs itself on cron schedules, instead of an external cron touching its state:
+//! resetting the data usage quotas are counted against, compacting the session history file, rotating and
+//! vacuuming the audit log and expiring stale address leases. Schedules are in UTC.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing::info;
+use tracing::warn;
+use vpn_shared::handshake;
+
+use crate::server::Server;
+
+const MINUTE_SECS: u64 = 60;
+const DAY_SECS: u64 = 24 * 60 * MINUTE_SECS;
+/// How far ahead a schedule is searched for its next time; every valid one has one within four years.
+const HORIZON_DAYS: u64 = 4 * 366;
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScheduleConfig {
+  /// Forget the data usage counted towards quotas, e.g. `@monthly`.
+  #[serde(default)]
+  pub quota_reset: Option<Cron>,
+
+  /// Rewrite `history.path` with only the sessions kept.
+  #[serde(default)]
+  pub history_compaction: Option<Cron>,
+
+  /// Rename the audit log file to `.1`, an earlier `.1` to `.2` and so on, and start a new one.
+  #[serde(default)]
+  pub audit_rotation: Option<Cron>,
+
+  /// Rotated audit logs kept; older ones are deleted.
+  #[serde(default = "default_audit_keep")]
+  pub audit_keep: u32,
+
+  /// Compact the audit database, giving the space of deleted records back.
+  #[serde(default)]
+  pub audit_vacuum: Option<Cron>,
+
+  /// Release the pool addresses no connected client holds any more.
+  #[serde(default)]
+  pub lease_cleanup: Option<Cron>,
+}
+
+fn default_audit_keep() -> u32 {
+  4
+}
+
+impl ScheduleConfig {
+  fn jobs(&self) -> Vec<(Job, &Cron)> {
+    [
+      (Job::QuotaReset, &self.quota_reset),
+      (Job::HistoryCompaction, &self.history_compaction),
+      (Job::AuditRotation, &self.audit_rotation),
+      (Job::AuditVacuum, &self.audit_vacuum),
+      (Job::LeaseCleanup, &self.lease_cleanup),
+    ]
+    .into_iter()
+    .filter_map(|(job, cron)| Some((job, cron.as_ref()?)))
+    .collect()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.jobs().is_empty()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Job {
+  QuotaReset,
+  HistoryCompaction,
+  AuditRotation,
+  AuditVacuum,
+  LeaseCleanup,
+}
+
+/// Schedule in the five fields of crontab, `minute hour day-of-month month day-of-week`, each `*`, a number,
+/// a range `a-b` or a list of them, optionally with a step, `*/15`; or `@hourly`, `@daily`, `@weekly`,
+/// `@monthly` or `@yearly`. As in cron, a day matches either day field when both are restricted.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cron {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl TryFrom<String> for Cron {
+  type Error = anyhow::Error;
+
+  fn try_from(expression: String) -> anyhow::Result<Self> {
+    Self::parse(&expression)
+  }
+}
+
+impl Cron {
+  pub fn parse(expression: &str) -> anyhow::Result<Self> {
+    let expression = match expression.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      "@yearly" => "0 0 1 1 *",
+      expression => expression,
+    };
+    let fields: Vec<_> = expression.split_whitespace().collect();
+    let [minutes, hours, days, months, weekdays] = fields[..] else {
+      anyhow::bail!("Invalid schedule {:?}: expected 5 fields, got {}", expression, fields.len());
+    };
+
+    // Sunday is both 0 and 7.
+    let sundays = field(weekdays, 0, 7)?;
+    Ok(Self {
+      minutes: field(minutes, 0, 59)?,
+      hours: field(hours, 0, 23)?,
+      days: field(days, 1, 31)?,
+      months: field(months, 1, 12)?,
+      weekdays: (sundays | sundays >> 7) & 0x7f,
+      any_day: days == "*",
+      any_weekday: weekdays == "*",
+    })
+  }
+
+  /// First minute after `secs`, both seconds since the Unix epoch, the schedule matches.
+  pub fn next_after(&self, secs: u64) -> Option<u64> {
+    let mut at = (secs / MINUTE_SECS + 1) * MINUTE_SECS;
+    let horizon = at + HORIZON_DAYS * DAY_SECS;
+    while at < horizon {
+      if !self.matches_day(at / DAY_SECS) {
+        at = (at / DAY_SECS + 1) * DAY_SECS;
+        continue;
+      }
+      let (hour, minute) = (at % DAY_SECS / 3600, at % 3600 / MINUTE_SECS);
+      if self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0 {
+        return Some(at);
+      }
+      at += MINUTE_SECS;
+    }
+    None
+  }
+
+  fn matches_day(&self, days: u64) -> bool {
+    let (_, month, day) = civil_from_days(days as i64);
+    // 1970-01-01 was a Thursday.
+    let weekday = (days + 4) % 7;
+    let by_day = self.days & (1 << day) != 0;
+    let by_weekday = self.weekdays & (1 << weekday) != 0;
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (false, false) => by_day || by_weekday,
+      _ => by_day && by_weekday,
+    };
+    day_matches && self.months & (1 << month) != 0
+  }
+}
+
+/// Bits of the values of a crontab field, within `min..=max`.
+fn field(text: &str, min: u64, max: u64) -> anyhow::Result<u64> {
+  let mut bits = 0;
+  for part in text.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse()?),
+      None => (part, 1),
+    };
+    let (first, last) = match range.split_once('-') {
+      _ if range == "*" => (min, max),
+      Some((first, last)) => (first.parse()?, last.parse()?),
+      None if step > 1 => (range.parse()?, max),
+      None => (range.parse()?, range.parse()?),
+    };
+    if first < min || last > max || first > last || step == 0 {
+      anyhow::bail!("Invalid schedule field {:?}: values are {}-{}", text, min, max);
+    }
+    bits |= (first..=last).step_by(step as usize).fold(0, |bits, value| bits | (1 << value));
+  }
+  Ok(bits)
+}
+
+/// Year, month and day of days since the Unix epoch, after Howard Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, u64, u64) {
+  let days = days + 719_468;
+  let (era, day_of_era) = (days.div_euclid(146_097), days.rem_euclid(146_097));
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_index = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+  let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+  let year = year_of_era + era * 400 + i64::from(month <= 2);
+  (year, month as u64, day as u64)
+}
+
+impl Server {
+  /// Runs the scheduled jobs as they come due.
+  pub async fn run_schedule(self: Arc<Self>) {
+    let jobs = self.schedule.jobs();
+    loop {
+      let now = handshake::unix_time();
+      let Some(next) = jobs.iter().filter_map(|(_, cron)| cron.next_after(now)).min() else {
+        return std::future::pending().await;
+      };
+      tokio::time::sleep(Duration::from_secs(next - now)).await;
+
+      for (job, _) in jobs.iter().filter(|(_, cron)| cron.next_after(next - 1) == Some(next)) {
+        self.run_job(*job);
+      }
+    }
+  }
+
+  fn run_job(&self, job: Job) {
+    match job {
+      Job::QuotaReset => self.reset_quotas(),
+      Job::HistoryCompaction => self.history.compact(),
+      Job::AuditRotation => match self.audit_log {
+        Some(ref audit) => audit.rotate(self.schedule.audit_keep),
+        None => warn!("Not rotating the audit log: it isn't configured"),
+      },
+      Job::AuditVacuum => match self.audit_log {
+        Some(ref audit) => audit.vacuum(),
+        None => warn!("Not vacuuming the audit log: it isn't configured"),
+      },
+      Job::LeaseCleanup => {
+        self.expire_leases();
+      }
+    }
+  }
+
+  /// Forgets the data usage of every account, so quotas count from zero again.
+  pub fn reset_quotas(&self) {
+    self.usage.clear();
+    self.quota_warnings.clear();
+    info!("Reset the data usage of every user");
+  }
+
+  /// Releases the addresses of every pool that no connected client holds, leaked by sessions that went away
+  /// without giving theirs back, and returns how many.
+  pub fn expire_leases(&self) -> usize {
+    let pools = self.address_pool.iter().map(|pool| (None, pool));
+    let pools: Vec<_> =
+      pools.chain(self.networks.iter().map(|network| (Some(network.name.as_str()), &network.pool))).collect();
+    // Taken before the clients, so an address leased meanwhile is in neither and stays leased.
+    let leased: Vec<_> = pools.iter().map(|(_, pool)| pool.leased()).collect();
+    let held: HashSet<_> =
+      self.clients.iter().filter_map(|client| Some((client.network.clone(), client.virtual_ip?))).collect();
+
+    let mut expired = 0;
+    for ((network, pool), leased) in pools.iter().zip(leased) {
+      for address in leased {
+        if !held.contains(&(network.map(str::to_string), address)) {
+          pool.release(address);
+          expired += 1;
+        }
+      }
+    }
+    if expired > 0 {
+      info!("Released {} stale address leases", expired);
+    }
+    expired
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use vpn_shared::socket::Network;
+
+  use super::*;
+  use crate::pool::AddressPool;
+  use crate::pool::AddressPoolConfig;
+
+  // 2024-03-01 00:00, a Friday.
+  const MARCH: u64 = 1_709_251_200;
+
+  #[test]
+  fn test_parse() {
+    let cron = Cron::parse("*/15 9-17 * * 1-5").unwrap();
+    assert_eq!(cron.minutes, 1 | (1 << 15) | (1 << 30) | (1 << 45));
+    assert_eq!(cron.weekdays, 0b111110);
+    assert_eq!(Cron::parse("0 0 * * 7").unwrap().weekdays, 1);
+    assert_eq!(Cron::parse("@monthly").unwrap(), Cron::parse("0 0 1 * *").unwrap());
+
+    assert!(Cron::parse("0 0 * *").is_err());
+    assert!(Cron::parse("60 0 * * *").is_err());
+    assert!(Cron::parse("0 0 0 * *").is_err());
+    assert!(Cron::parse("*/0 * * * *").is_err());
+  }
+
+  #[test]
+  fn test_next_after() {
+    let monthly = Cron::parse("@monthly").unwrap();
+    assert_eq!(monthly.next_after(MARCH - 1), Some(MARCH));
+    assert_eq!(monthly.next_after(MARCH), Some(MARCH + 31 * DAY_SECS));
+
+    // Fridays at 09:30, or the 4th.
+    let cron = Cron::parse("30 9 4 * 5").unwrap();
+    assert_eq!(cron.next_after(MARCH), Some(MARCH + 9 * 3600 + 30 * 60));
+    assert_eq!(cron.next_after(MARCH + DAY_SECS), Some(MARCH + 3 * DAY_SECS + 9 * 3600 + 30 * 60));
+
+    // Only leap years have a 29th of February.
+    let leap = Cron::parse("0 0 29 2 *").unwrap();
+    assert_eq!(leap.next_after(MARCH).map(|at| civil_from_days((at / DAY_SECS) as i64)), Some((2028, 2, 29)));
+    assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(MARCH), None);
+  }
+
+  #[tokio::test]
+  async fn test_expire_leases() {
+    let pool = AddressPoolConfig { subnet: "10.8.0.0/29".parse().unwrap(), dns: Vec::new() };
+    let server = Server::builder(Ipv4Addr::new(192, 0, 2, 1), 6969)
+      .with_packet_pipe(1400)
+      .with_memory_transport(Network::new())
+      .with_address_pool(AddressPool::new(pool, None))
+      .build()
+      .await
+      .unwrap();
+    let pool = server.address_pool.as_ref().unwrap();
+    // Leases no client holds, as a session gone without releasing its address leaves behind.
+    pool.lease();
+    pool.lease();
+
+    assert_eq!(server.expire_leases(), 2);
+    assert!(pool.leased().is_empty());
+    assert_eq!(server.expire_leases(), 0);
+  }
+}
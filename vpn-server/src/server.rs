@@ -1,60 +1,630 @@
 use dashmap::DashMap;
+use ipnet::Ipv4Net;
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 use tokio::net::UdpSocket;
-use vpn_shared::packet::EncryptedPacket;
+use tun::AbstractDevice;
+use tun::AsyncDevice;
+use vpn_shared::cert::VerifyingKey;
+use vpn_shared::diagnose;
+use vpn_shared::diagnose::Protocol;
+use vpn_shared::ecn;
+use vpn_shared::fragment::Reassembly;
+use vpn_shared::gso;
+use vpn_shared::gso::GsoReader;
+use vpn_shared::handshake::KeyPair;
+use vpn_shared::iface;
+use vpn_shared::iface::InterfaceState;
+use vpn_shared::iface::MAX_MTU;
+use vpn_shared::iface::MIN_MTU;
+use vpn_shared::ip;
+use vpn_shared::limits::Limits;
+use vpn_shared::logging;
+use vpn_shared::outer::OuterConfig;
+use vpn_shared::packet;
+use vpn_shared::packet::fill_random_bytes;
+use vpn_shared::packet::ClientPacket;
+use vpn_shared::packet::ErrorCode;
+use vpn_shared::packet::Features;
 use vpn_shared::packet::Key;
+use vpn_shared::packet::Notice;
+use vpn_shared::packet::ReverseForward;
 use vpn_shared::packet::ServerPacket;
+use vpn_shared::packet::SessionId;
+use vpn_shared::packet::HANDSHAKE_SESSION;
 use vpn_shared::packet::KEY_SIZE;
+use vpn_shared::packet::MAX_DATAGRAM_SIZE;
+use vpn_shared::protocol;
+use vpn_shared::protocol::Offer;
+use vpn_shared::rate::TokenBucket;
+use vpn_shared::rendezvous;
+use vpn_shared::socket::Network;
+use vpn_shared::socket::Socket;
+use vpn_shared::stream::TcpTransport;
+use vpn_shared::supervisor::Fatal;
+use vpn_shared::supervisor::Restart;
+use vpn_shared::supervisor::Supervisor;
+use vpn_shared::transform::Pipeline;
+use vpn_shared::transform::Registry;
 
+use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::trace;
+use tracing::warn;
 
 use vpn_shared::creds::Credentials;
+use vpn_shared::creds::KeyCredentials;
 
+use crate::accounting::Accounting;
+use crate::accounting::AccountingKind;
+use crate::accounting::Direction;
+use crate::alerts::AlertState;
+use crate::alerts::Alerts;
+use crate::alerts::Violation;
+use crate::audit::AuditLog;
+use crate::auth::CredentialStore;
+use crate::auth::Identity;
+use crate::auth::Lookup;
+use crate::authz::AuthRule;
+use crate::bandwidth::BandwidthConfig;
+use crate::bandwidth::BandwidthGraphs;
+use crate::cluster::Cluster;
+use crate::config::ServerConfig;
+use crate::demux::Demux;
+use crate::dns::InternalDns;
+use crate::filter::Action;
+use crate::filter::PacketContext;
+use crate::filter::PacketFilter;
 use crate::handle_packet::PacketHandler;
+use crate::health;
+use crate::health::AdminToken;
+use crate::health::Health;
+use crate::health::HealthReport;
+use crate::health::LiveClient;
+use crate::health::MainLoopGuard;
+use crate::health::Scope;
+use crate::health::TunLoopGuard;
+use crate::history::SessionHistory;
+use crate::history::SessionRecord;
+use crate::inbound::InboundConnections;
+use crate::listeners::HandshakeGates;
+use crate::mdns::Responder;
+use crate::metrics::network_label;
+use crate::metrics::Metrics;
+use crate::mirror::Mirror;
+use crate::mirror::MirrorConfig;
+use crate::nat;
+use crate::nat::Nat;
+use crate::network::Networks;
+use crate::offload;
+use crate::offload::OffloadConfig;
+use crate::offload::Offloaded;
+use crate::pacing;
+use crate::pacing::PacingConfig;
+use crate::pacing::SendQueue;
+use crate::pacing::Verdict;
+use crate::passwords::PasswordFile;
+use crate::pipe::PacketPipe;
+use crate::pipe::ServerHandle;
+use crate::pipe::SessionPacket;
+use crate::policy::Policies;
+use crate::policy::Policy;
+use crate::policy::Priority;
+use crate::pool::AddressPool;
+use crate::pool::PoolExhaustionConfig;
+use crate::push::MetricsPushConfig;
+use crate::quarantine::Quarantine;
+use crate::quarantine::QuarantineConfig;
+use crate::rekey::DecryptFailures;
+use crate::rekey::RekeyConfig;
+use crate::reload::AffectedSession;
+use crate::reload::LoadedConfig;
+use crate::rendezvous::NameRegistry;
+use crate::rendezvous::Registration;
+use crate::rendezvous::RendezvousConfig;
+use crate::replay::ReplayCache;
+use crate::revocation::RevocationList;
+use crate::roaming::PathChallenge;
+use crate::schedule::ScheduleConfig;
+#[cfg(feature = "userspace-nat")]
+use crate::service;
+use crate::service::AdminService;
+use crate::startup;
+use crate::startup::Component;
+use crate::startup::Readiness;
+use crate::timestamp::Timestamp;
+use crate::tokens;
+use crate::tokens::Ticket;
+use crate::tokens::TicketIssuer;
+use crate::trace;
+use crate::trace::Flow;
+use crate::trace::Traces;
+#[cfg(feature = "userspace-nat")]
+use crate::userspace;
+use crate::userspace::UserspaceNat;
+use crate::userspace::UserspaceNatConfig;
+use crate::webhook::AdminEvent;
+use crate::webhook::Webhook;
+use crate::workers::Job;
+use crate::workers::Shed;
+use crate::workers::WorkerConfig;
+use crate::workers::WorkerPool;
+
+/// Pings, probes and renegotiations a session may send per second, in bursts of up to `CONTROL_BURST`. Each costs
+/// the server an encryption and a send, so the excess is dropped unanswered.
+const CONTROL_RATE: f64 = 2.0;
+const CONTROL_BURST: f64 = 10.0;
 
 pub struct ConnectedClient {
   pub addr: SocketAddr,
-  pub last_seen: Instant,
+  pub last_seen: Timestamp,
+  /// Last data packet from the client; pings don't count.
+  pub last_active: Timestamp,
   pub timeout: Duration,
   pub key: Key,
+  pub session_id: SessionId,
+  pub username: Option<String>,
+  /// Device of the user the session is from, see `KeyCredentials::device`.
+  pub device: Option<String>,
+  pub policy: Policy,
+  /// Tenant network the user belongs to; `None` for the default one.
+  pub network: Option<String>,
+  pub virtual_ip: Option<Ipv4Addr>,
+  /// LANs behind the client routed to it, see `Server::register_subnets`.
+  pub subnets: Vec<Ipv4Net>,
+  /// Ports of the server forwarded to the client on its request, see `Server::register_forwards`.
+  pub reverse_forwards: Vec<ReverseForward>,
+  /// Name the client registered with the internal DNS, see `Server::register_hostname`.
+  pub hostname: Option<String>,
+  /// Routes of the user's groups and subnets of other sites last advertised to the client, see
+  /// `Server::advertise_routes`.
+  pub routes: Vec<Ipv4Net>,
+  /// Changes made to `routes`, see `ServerPacket::RouteUpdate`.
+  pub routes_revision: u32,
+  /// Server address the client sends to, which replies have to leave from; only known when listening on
+  /// all addresses, where the kernel may otherwise pick another one.
+  pub local: Option<Ipv4Addr>,
+  pub outbound: SendQueue,
+  /// Server half of the handshake, kept to verify key-based authentication.
+  pub ephemeral: KeyPair,
+  pub public_key: Option<Key>,
+  pub authenticated_at: Option<Instant>,
+  /// End of the validity of the certificate the client authenticated with.
+  pub expires_at: Option<SystemTime>,
+  /// Counted through shared references like `last_seen`.
+  pub bytes_in: AtomicU64,
+  pub bytes_out: AtomicU64,
+  /// Set while the client has its routes down after idleness, see `ClientPacket::Suspended`; cleared by
+  /// the `Notice::Wake` sent ahead of the next packet to it.
+  pub suspended: AtomicBool,
+  pub path_challenge: Option<PathChallenge>,
+  /// MTU the client renegotiated below the server's, which the MSS of its TCP connections is clamped to.
+  pub mtu: Option<u16>,
+  /// Times the session moved between cluster nodes, see `Cluster`.
+  pub generation: u32,
+  /// Transforms negotiated in the handshake.
+  pub pipeline: Arc<Pipeline>,
+  /// Features negotiated in the handshake; `Features::LEGACY` for sessions taken over from other cluster
+  /// nodes, whose announcements don't carry them.
+  pub features: Features,
+  /// Limits agreed on in the key exchange; see `vpn_shared::limits`.
+  pub limits: Limits,
+  /// Control packet being received in fragments.
+  pub fragments: Reassembly,
+  /// Ticket the session was resumed with; resumed sessions aren't issued another one.
+  pub ticket: Option<Ticket>,
+  pub inbound: InboundConnections,
+  pub alerts: AlertState,
+  /// Locked on its own rather than with the entry, so that pings only take the read lock of its shard.
+  pub control: Mutex<TokenBucket>,
+  /// Set by `ClientPacket::Rehandshake`: the client gave up on the session and its next key exchange is
+  /// taken even with `strict-handshakes`.
+  pub rehandshaking: bool,
+  /// Server half of a rekey the client hasn't answered yet, see `Server::rekey`.
+  pub rekey: Option<KeyPair>,
+  pub decrypt_failures: DecryptFailures,
+  /// Seconds the client's clock was ahead of the server's at the key exchange; negative when behind.
+  pub clock_offset: i64,
+  /// Key exchange or rekey the session key comes from.
+  pub keyed_at: SystemTime,
 }
 
 impl ConnectedClient {
-  pub fn new(key: Key, addr: SocketAddr, timeout: Duration) -> Self {
-    Self { addr, last_seen: Instant::now(), timeout, key }
+  pub fn new(
+    key: Key,
+    session_id: SessionId,
+    addr: SocketAddr,
+    timeout: Duration,
+    outbound: SendQueue,
+    ephemeral: KeyPair,
+  ) -> Self {
+    Self {
+      addr,
+      last_seen: Timestamp::now(),
+      last_active: Timestamp::now(),
+      timeout,
+      key,
+      session_id,
+      username: None,
+      device: None,
+      policy: Policy::default(),
+      network: None,
+      virtual_ip: None,
+      subnets: Vec::new(),
+      reverse_forwards: Vec::new(),
+      hostname: None,
+      routes: Vec::new(),
+      routes_revision: 0,
+      local: None,
+      outbound,
+      ephemeral,
+      public_key: None,
+      authenticated_at: None,
+      expires_at: None,
+      bytes_in: AtomicU64::new(0),
+      bytes_out: AtomicU64::new(0),
+      suspended: AtomicBool::new(false),
+      path_challenge: None,
+      mtu: None,
+      generation: 0,
+      pipeline: Arc::default(),
+      features: Features::LEGACY,
+      limits: Limits::LOCAL,
+      fragments: Reassembly::default(),
+      ticket: None,
+      inbound: InboundConnections::default(),
+      alerts: AlertState::default(),
+      control: Mutex::new(TokenBucket::new(CONTROL_RATE, CONTROL_BURST)),
+      rehandshaking: false,
+      rekey: None,
+      decrypt_failures: DecryptFailures::default(),
+      clock_offset: 0,
+      keyed_at: SystemTime::now(),
+    }
+  }
+
+  /// Name the data usage of the session is counted under: the user, or `user/device` for a device of theirs.
+  pub fn account(&self) -> Option<String> {
+    let username = self.username.as_ref()?;
+    Some(match self.device {
+      Some(ref device) => format!("{}/{}", username, device),
+      None => username.clone(),
+    })
   }
 
   pub fn is_expired(&self) -> bool {
-    Instant::now().duration_since(self.last_seen) > self.timeout
+    self.last_seen.elapsed() > self.timeout
+  }
+
+  /// Whether a key exchange from the client's address, sealed with the all-zero key and so from anyone able
+  /// to send from it, is refused instead of replacing the session; see `GroupPolicy::strict_handshakes`.
+  pub fn refuses_handshakes(&self) -> bool {
+    self.authenticated_at.is_some() && self.policy.strict_handshakes && !self.rehandshaking
+  }
+}
+
+/// Where client packets leave the tunnel.
+pub enum Tun {
+  Device {
+    device: AsyncDevice,
+    /// Splits what the device reads when it was made for segmentation offload, see `TunConfig::gso`.
+    gso: Option<tokio::sync::Mutex<GsoReader>>,
+  },
+  #[cfg_attr(not(feature = "userspace-nat"), allow(dead_code))]
+  Userspace(UserspaceNat),
+  /// Packets exchanged with the application embedding the server.
+  Pipe(PacketPipe),
+}
+
+impl Tun {
+  /// Returns false if the packet was dropped because the userspace stack is behind.
+  pub async fn send(&self, addr: SocketAddr, packet: &[u8]) -> anyhow::Result<bool> {
+    match self {
+      Tun::Device { device, gso: None } => {
+        device.send(packet).await?;
+        Ok(true)
+      }
+      Tun::Device { device, gso: Some(_) } => {
+        gso::send(device, packet).await?;
+        Ok(true)
+      }
+      Tun::Userspace(nat) => nat.send(packet),
+      Tun::Pipe(pipe) => Ok(pipe.send(addr, packet)),
+    }
+  }
+
+  pub async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+    match self {
+      Tun::Device { device, gso: None } => Ok(device.recv(buf).await?),
+      Tun::Device { device, gso: Some(reader) } => Ok(reader.lock().await.recv(device, buf).await?),
+      Tun::Userspace(nat) => nat.recv(buf).await,
+      Tun::Pipe(pipe) => Ok(pipe.recv(buf).await),
+    }
+  }
+
+  /// Whether the device is still there; packet pipes and the userspace stack always are.
+  pub fn is_present(&self) -> bool {
+    match self {
+      Tun::Device { device, .. } => {
+        device.tun_name().is_ok_and(|name| iface::interface_state(&name) != InterfaceState::Missing)
+      }
+      Tun::Userspace(_) | Tun::Pipe(_) => true,
+    }
+  }
+
+  /// Name of the device if `error` of `send` or `recv` came from it being removed.
+  pub fn removed(&self, error: &anyhow::Error) -> Option<String> {
+    let Tun::Device { device, .. } = self else {
+      return None;
+    };
+    let name = device.tun_name().ok()?;
+    error.downcast_ref::<io::Error>().is_some_and(|e| iface::is_removed(&name, e)).then_some(name)
   }
 }
 
+/// Datagram `Server::receive` has read from a session, with what it needs to handle it once opened.
+struct Received {
+  demux: Demux,
+  datagram: Arc<[u8]>,
+  session_id: SessionId,
+  src_addr: SocketAddr,
+  outer_ecn: u8,
+  local: Option<Ipv4Addr>,
+  shed: Shed,
+}
+
 pub struct ServerBuilder {
   listen_address: Ipv4Addr,
   listen_port: u16,
   max_clients: Option<usize>,
   client_timeout: Option<Duration>,
   client_credentials: Option<Vec<Credentials>>,
+  client_keys: Vec<KeyCredentials>,
+  credential_stores: Vec<Box<dyn CredentialStore>>,
+  passwords: Option<Arc<PasswordFile>>,
+  revocations: RevocationList,
+  accounting: Vec<Arc<dyn Accounting>>,
+  accounting_interval: Option<Duration>,
+  stats_interval: Option<Duration>,
+  webhook: Option<Webhook>,
+  health_address: Option<SocketAddr>,
+  grpc_address: Option<SocketAddr>,
+  admin_tokens: Vec<AdminToken>,
+  config_file: Option<LoadedConfig>,
+  tun_config: Option<tun::Configuration>,
+  tun_gso: bool,
+  userspace_nat: Option<UserspaceNatConfig>,
+  /// MTU of the packet pipe used instead of a tun device.
+  packet_pipe: Option<u16>,
+  /// Address and port of the admin service.
+  admin_service: Option<(Ipv4Addr, u16)>,
+  address_pool: Option<AddressPool>,
+  networks: Networks,
+  nat: Nat,
+  policies: Policies,
+  auth_policy: Vec<AuthRule>,
+  quarantine: QuarantineConfig,
+  handshake_skew: Option<Duration>,
+  token_skew: Option<Duration>,
+  workers: WorkerConfig,
+  pacing: PacingConfig,
+  offload: OffloadConfig,
+  static_key: Option<KeyPair>,
+  certificate_authority: Option<VerifyingKey>,
+  ecn: bool,
+  outer: OuterConfig,
+  /// Network to bind on instead of UDP.
+  memory: Option<Network>,
+  tcp_port: Option<u16>,
+  icmp_unreachable: bool,
+  peer_encryption: bool,
+  required_features: Features,
+  mss_clamp: bool,
+  route_exchange: bool,
+  filters: Vec<Arc<dyn PacketFilter>>,
+  history: SessionHistory,
+  transforms: Registry,
+  accepted_transforms: Vec<String>,
+  cluster: Option<Cluster>,
+  mdns: Option<Responder>,
+  rendezvous: Option<RendezvousConfig>,
+  rendezvous_service: bool,
+  internal_dns: Option<InternalDns>,
+  preemption: Option<Duration>,
+  pool_exhaustion: PoolExhaustionConfig,
+  ticket_lifetime: Option<Duration>,
+  mirror: Option<MirrorConfig>,
+  bandwidth: Option<BandwidthConfig>,
+  metrics_push: Option<MetricsPushConfig>,
+  alerts: Option<Alerts>,
+  rekeying: RekeyConfig,
+  schedule: ScheduleConfig,
+  audit_log: Option<Arc<AuditLog>>,
+  startup_timeout: Option<Duration>,
 }
 
 pub struct Server {
-  pub socket: UdpSocket,
+  pub socket: Arc<Socket>,
   pub listen_address: Ipv4Addr,
   pub listen_port: u16,
   pub max_clients: usize,
   pub client_timeout: Duration,
-  pub client_credentials: Vec<Credentials>,
+  /// Both replaced by reloads of the configuration file, see `reload`.
+  pub client_credentials: RwLock<Vec<Credentials>>,
+  pub client_keys: RwLock<Vec<KeyCredentials>>,
+  pub credential_stores: Vec<Box<dyn CredentialStore>>,
+  /// Passwords users may change themselves; also one of `credential_stores`.
+  pub passwords: Option<Arc<PasswordFile>>,
+  pub revocations: RevocationList,
+  pub accounting: Vec<Arc<dyn Accounting>>,
+  pub accounting_interval: Option<Duration>,
+  pub stats_interval: Option<Duration>,
+  pub history: Arc<SessionHistory>,
   pub clients: Arc<DashMap<SocketAddr, ConnectedClient>>,
+  pub sessions: DashMap<SessionId, SocketAddr>,
+  pub quarantine: Quarantine,
+  pub replays: ReplayCache,
+  pub metrics: Arc<Metrics>,
+  pub workers: WorkerConfig,
+  pub pacing: PacingConfig,
+  pub offload: OffloadConfig,
+  pub static_key: Option<KeyPair>,
+  pub certificate_authority: Option<VerifyingKey>,
+  pub ecn: bool,
+  pub icmp_unreachable: bool,
+  /// Whether clients may agree on pairwise keys through the server, see `vpn_shared::peer`.
+  pub peer_encryption: bool,
+  /// Features a client must have negotiated to authenticate.
+  pub required_features: Features,
+  /// Whether sites, clients with registered subnets, are told about each other's subnets.
+  pub route_exchange: bool,
+  /// MSS that TCP SYNs in both directions are clamped to, leaving room for headers in the tun MTU.
+  pub mss_clamp: Option<u16>,
+  pub filters: Vec<Arc<dyn PacketFilter>>,
+  pub transforms: Registry,
+  pub accepted_transforms: Vec<String>,
+  pub cluster: Option<Cluster>,
+  pub mdns: Option<Responder>,
+  /// Registration with a rendezvous of a server behind NAT, see `rendezvous`.
+  pub rendezvous: Option<Registration>,
+  /// Names of servers behind NAT, when this server serves as their rendezvous.
+  pub rendezvous_service: Option<NameRegistry>,
+  /// Resolver serving hostnames of clients inside the tunnel, see `dns`.
+  pub internal_dns: Option<InternalDns>,
+  /// Minimum idle time of sessions high-priority users may preempt; `None` disables preemption.
+  pub preemption: Option<Duration>,
+  pub pool_exhaustion: PoolExhaustionConfig,
+  /// When sessions are rekeyed without being asked to by an administrator.
+  pub rekeying: RekeyConfig,
+  /// Periodic jobs, see `run_schedule`.
+  pub schedule: ScheduleConfig,
+  /// The audit log among `accounting`, for rotating it.
+  pub audit_log: Option<Arc<AuditLog>>,
+  /// Issues session tickets to authenticated clients; `None` unless enabled.
+  pub tickets: Option<TicketIssuer>,
+  pub mirror: Option<Mirror>,
+  pub traces: Traces,
+  /// Listeners refusing key exchanges, see `listeners`.
+  pub handshake_gates: HandshakeGates,
+  /// Throughput of every session over the last while; `None` unless enabled.
+  pub bandwidth: Option<BandwidthGraphs>,
+  /// Collector the metrics are pushed to besides `/metrics`, see `push`.
+  pub metrics_push: Option<MetricsPushConfig>,
+  pub alerts: Option<Alerts>,
+  pub health_address: Option<SocketAddr>,
+  #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+  pub grpc_address: Option<SocketAddr>,
+  pub admin_tokens: Vec<AdminToken>,
+  /// Configuration file the server started with, for previews of changes to it, see `reload`.
+  pub config_file: Option<LoadedConfig>,
+  pub admin_service: Option<AdminService>,
+  pub health: Arc<Health>,
+  /// Readiness of the tun device, listeners, credential stores and admin APIs.
+  pub startup: Readiness,
+  pub tun: Option<Tun>,
+  pub mtu: u16,
+  pub virtual_ips: DashMap<Ipv4Addr, SocketAddr>,
+  /// Client each LAN registered by a client is behind.
+  pub subnet_routes: DashMap<Ipv4Net, SocketAddr>,
+  /// Client each port of the server is forwarded to on its request.
+  pub reverse_forwards: DashMap<ReverseForward, SocketAddr>,
+  pub address_pool: Option<AddressPool>,
+  /// Subnet of the default network, from the address pool or the tun device.
+  pub subnet: Option<Ipv4Net>,
+  pub networks: Networks,
+  pub nat: Nat,
+  pub policies: Policies,
+  /// Rules looked at once a user authenticated, see `authz`.
+  pub auth_policy: Vec<AuthRule>,
+  /// Data usage by `ConnectedClient::account`.
+  pub usage: DashMap<String, u64>,
+  /// Highest of `QUOTA_WARNINGS` each account has been warned about.
+  pub quota_warnings: DashMap<String, u8>,
+  pub webhook: Option<Webhook>,
 }
 
 impl ServerBuilder {
   pub fn new(listen_address: Ipv4Addr, listen_port: u16) -> Self {
-    Self { listen_address, listen_port, max_clients: None, client_timeout: None, client_credentials: None }
+    Self {
+      listen_address,
+      listen_port,
+      max_clients: None,
+      client_timeout: None,
+      client_credentials: None,
+      client_keys: Vec::new(),
+      credential_stores: Vec::new(),
+      passwords: None,
+      revocations: RevocationList::default(),
+      accounting: Vec::new(),
+      accounting_interval: None,
+      stats_interval: None,
+      webhook: None,
+      health_address: None,
+      grpc_address: None,
+      admin_tokens: Vec::new(),
+      config_file: None,
+      tun_config: None,
+      tun_gso: false,
+      userspace_nat: None,
+      packet_pipe: None,
+      admin_service: None,
+      address_pool: None,
+      networks: Networks::default(),
+      nat: Nat::default(),
+      policies: Policies::default(),
+      auth_policy: Vec::new(),
+      quarantine: QuarantineConfig::default(),
+      handshake_skew: None,
+      token_skew: None,
+      workers: WorkerConfig::default(),
+      pacing: PacingConfig::default(),
+      offload: OffloadConfig::default(),
+      static_key: None,
+      certificate_authority: None,
+      ecn: false,
+      outer: OuterConfig::default(),
+      memory: None,
+      tcp_port: None,
+      icmp_unreachable: false,
+      peer_encryption: false,
+      required_features: Features::empty(),
+      mss_clamp: false,
+      route_exchange: false,
+      filters: Vec::new(),
+      history: SessionHistory::default(),
+      transforms: Registry::default(),
+      accepted_transforms: Vec::new(),
+      cluster: None,
+      mdns: None,
+      rendezvous: None,
+      rendezvous_service: false,
+      internal_dns: None,
+      preemption: None,
+      pool_exhaustion: PoolExhaustionConfig::default(),
+      ticket_lifetime: None,
+      mirror: None,
+      bandwidth: None,
+      metrics_push: None,
+      rekeying: RekeyConfig::default(),
+      schedule: ScheduleConfig::default(),
+      audit_log: None,
+      alerts: None,
+      startup_timeout: None,
+    }
   }
 
   pub fn with_max_clients(mut self, max_clients: usize) -> Self {
@@ -72,16 +642,565 @@ impl ServerBuilder {
     self
   }
 
+  pub fn with_client_keys(mut self, keys: Vec<KeyCredentials>) -> Self {
+    self.client_keys = keys;
+    self
+  }
+
+  /// Adds a store consulted, in order, for password credentials not found in `client_credentials`.
+  pub fn with_credential_store(mut self, store: Box<dyn CredentialStore>) -> Self {
+    self.credential_stores.push(store);
+    self
+  }
+
+  /// Lets users change their passwords, kept in the file at `path`; consulted like the other credential
+  /// stores.
+  pub fn with_password_file(mut self, path: PathBuf) -> Self {
+    let passwords = Arc::new(PasswordFile::new(path));
+    self.credential_stores.push(Box::new(passwords.clone()));
+    self.passwords = Some(passwords);
+    self
+  }
+
+  pub fn with_accounting(mut self, accounting: Arc<dyn Accounting>) -> Self {
+    self.accounting.push(accounting);
+    self
+  }
+
+  /// Interval of interim accounting records; only start and stop are recorded without it.
+  pub fn with_accounting_interval(mut self, interval: Duration) -> Self {
+    self.accounting_interval = Some(interval);
+    self
+  }
+
+  /// Send `ServerPacket::Stats` to every authenticated client this often.
+  pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+    self.stats_interval = Some(interval);
+    self
+  }
+
+  /// Where to report events like users nearing their quota.
+  pub fn with_webhook(mut self, webhook: Webhook) -> Self {
+    self.webhook = Some(webhook);
+    self
+  }
+
+  pub fn with_revocation_list(mut self, revocations: RevocationList) -> Self {
+    self.revocations = revocations;
+    self
+  }
+
+  pub fn with_health_address(mut self, address: SocketAddr) -> Self {
+    self.health_address = Some(address);
+    self
+  }
+
+  /// Serves the admin API over gRPC on `address`, see `grpc`.
+  pub fn with_grpc_address(mut self, address: SocketAddr) -> Self {
+    self.grpc_address = Some(address);
+    self
+  }
+
+  /// Tokens that open the admin routes of the health endpoint to remote administrators, see `AdminToken`.
+  pub fn with_admin_tokens(mut self, tokens: Vec<AdminToken>) -> Self {
+    self.admin_tokens = tokens;
+    self
+  }
+
+  /// Configuration file the server was started with, compared with its later edits by the admin API.
+  pub fn with_config_file(mut self, config: LoadedConfig) -> Self {
+    self.config_file = Some(config);
+    self
+  }
+
+  /// Serves the routes of the health endpoint to clients of the tunnel at `address`, see `AdminService`.
+  pub fn with_admin_service(mut self, address: Ipv4Addr, port: u16) -> Self {
+    self.admin_service = Some((address, port));
+    self
+  }
+
+  pub fn with_tun_config(mut self, tun_config: tun::Configuration) -> Self {
+    self.tun_config = Some(tun_config);
+    self
+  }
+
+  /// Reads the tun device as one made with `TunConfig::gso`, splitting the super-packets of TCP flows.
+  pub fn with_tun_gso(mut self, gso: bool) -> Self {
+    self.tun_gso = gso;
+    self
+  }
+
+  /// Hands packets of clients to the application embedding the server, which sends packets back with
+  /// `Server::packet_handle`, instead of a tun device; see `Server::client_packets`.
+  #[allow(dead_code)]
+  pub fn with_packet_pipe(mut self, mtu: u16) -> Self {
+    self.packet_pipe = Some(mtu);
+    self
+  }
+
+  /// Forwards client traffic through the server's own sockets instead of a tun device, see `UserspaceNat`.
+  pub fn with_userspace_nat(mut self, config: UserspaceNatConfig) -> Self {
+    self.userspace_nat = Some(config);
+    self
+  }
+
+  /// Leases client addresses from the pool instead of learning them from their traffic.
+  pub fn with_address_pool(mut self, pool: AddressPool) -> Self {
+    self.address_pool = Some(pool);
+    self
+  }
+
+  /// Serves tenant networks isolated from each other and the default one, see `Networks`.
+  pub fn with_networks(mut self, networks: Networks) -> Self {
+    self.networks = networks;
+    self
+  }
+
+  pub fn with_nat(mut self, nat: Nat) -> Self {
+    self.nat = nat;
+    self
+  }
+
+  pub fn with_policies(mut self, policies: Policies) -> Self {
+    self.policies = policies;
+    self
+  }
+
+  /// Lets users in, turns them away or puts them in groups by rules over their authentication, see `authz`.
+  pub fn with_auth_policy(mut self, rules: Vec<AuthRule>) -> Self {
+    self.auth_policy = rules;
+    self
+  }
+
+  pub fn with_quarantine(mut self, quarantine: QuarantineConfig) -> Self {
+    self.quarantine = quarantine;
+    self
+  }
+
+  /// Clock skew tolerated in the timestamps of key exchanges, see `ReplayCache`.
+  pub fn with_handshake_skew(mut self, skew: Duration) -> Self {
+    self.handshake_skew = Some(skew);
+    self
+  }
+
+  /// How long after expiring session tickets are still taken, see `TicketIssuer::with_skew`.
+  pub fn with_token_skew(mut self, skew: Duration) -> Self {
+    self.token_skew = Some(skew);
+    self
+  }
+
+  /// Gives up starting, naming what didn't start, when the tun device, listeners, credential stores and
+  /// admin APIs aren't all up after `timeout`; 60s by default.
+  pub fn with_startup_timeout(mut self, timeout: Duration) -> Self {
+    self.startup_timeout = Some(timeout);
+    self
+  }
+
+  pub fn with_workers(mut self, workers: WorkerConfig) -> Self {
+    self.workers = workers;
+    self
+  }
+
+  pub fn with_pacing(mut self, pacing: PacingConfig) -> Self {
+    self.pacing = pacing;
+    self
+  }
+
+  pub fn with_offload(mut self, offload: OffloadConfig) -> Self {
+    self.offload = offload;
+    self
+  }
+
+  /// Static X25519 key clients pin to authenticate the server before sending their credentials.
+  pub fn with_private_key(mut self, key: Key) -> Self {
+    self.static_key = Some(KeyPair::from_secret(key));
+    self
+  }
+
+  /// Accepts client certificates signed by this CA.
+  pub fn with_certificate_authority(mut self, ca: VerifyingKey) -> Self {
+    self.certificate_authority = Some(ca);
+    self
+  }
+
+  /// Copy ECN fields between tunneled packets and the datagrams carrying them, see `vpn_shared::ecn`.
+  pub fn with_ecn(mut self, ecn: bool) -> Self {
+    self.ecn = ecn;
+    self
+  }
+
+  /// DF bit and TTL of the datagrams carrying the tunnel.
+  pub fn with_outer(mut self, outer: OuterConfig) -> Self {
+    self.outer = outer;
+    self
+  }
+
+  /// Listens on `network` rather than a UDP socket, for clients in the same process bound on it; ECN and
+  /// the outer settings don't apply.
+  #[allow(dead_code)]
+  pub fn with_memory_transport(mut self, network: Network) -> Self {
+    self.memory = Some(network);
+    self
+  }
+
+  /// Also accepts clients over TCP on `port` of the listen address, for networks that block UDP, see
+  /// `vpn_shared::stream`.
+  pub fn with_tcp_port(mut self, port: u16) -> Self {
+    self.tcp_port = Some(port);
+    self
+  }
+
+  pub fn with_icmp_unreachable(mut self, icmp_unreachable: bool) -> Self {
+    self.icmp_unreachable = icmp_unreachable;
+    self
+  }
+
+  pub fn with_peer_encryption(mut self, peer_encryption: bool) -> Self {
+    self.peer_encryption = peer_encryption;
+    self
+  }
+
+  /// Refuses clients that didn't negotiate all of `features` with `ErrorCode::VersionTooOld`.
+  pub fn with_required_features(mut self, features: Features) -> Self {
+    self.required_features = features;
+    self
+  }
+
+  pub fn with_mss_clamp(mut self, mss_clamp: bool) -> Self {
+    self.mss_clamp = mss_clamp;
+    self
+  }
+
+  /// Advertises the subnets registered by each client to the other clients with subnets, which the
+  /// policy of their user lets reach them, so sites reach each other through the server.
+  pub fn with_route_exchange(mut self, route_exchange: bool) -> Self {
+    self.route_exchange = route_exchange;
+    self
+  }
+
+  pub fn with_history(mut self, history: SessionHistory) -> Self {
+    self.history = history;
+    self
+  }
+
+  /// Adds a filter every forwarded packet must pass, see `PacketFilter`.
+  #[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+  pub fn with_packet_filter(mut self, filter: Arc<dyn PacketFilter>) -> Self {
+    self.filters.push(filter);
+    self
+  }
+
+  /// Transforms clients may use, see `vpn_shared::transform`; none by default.
+  pub fn with_transforms(mut self, accepted: Vec<String>) -> Self {
+    self.accepted_transforms = accepted;
+    self
+  }
+
+  /// Shares sessions with other nodes behind the same address, see `Cluster`.
+  pub fn with_cluster(mut self, cluster: Cluster) -> Self {
+    self.cluster = Some(cluster);
+    self
+  }
+
+  pub fn with_mdns(mut self, responder: Responder) -> Self {
+    self.mdns = Some(responder);
+    self
+  }
+
+  /// Registers with a rendezvous so that clients reach the server through its NAT, see `rendezvous`.
+  pub fn with_rendezvous(mut self, config: RendezvousConfig) -> Self {
+    self.rendezvous = Some(config);
+    self
+  }
+
+  /// Serves as a rendezvous for servers behind NAT on the listen socket.
+  pub fn with_rendezvous_service(mut self, enabled: bool) -> Self {
+    self.rendezvous_service = enabled;
+    self
+  }
+
+  /// Serves hostnames of clients and forwards other queries upstream inside the tunnel, see `dns`.
+  pub fn with_internal_dns(mut self, dns: InternalDns) -> Self {
+    self.internal_dns = Some(dns);
+    self
+  }
+
+  pub fn with_preemption(mut self, min_idle: Duration) -> Self {
+    self.preemption = Some(min_idle);
+    self
+  }
+
+  pub fn with_pool_exhaustion(mut self, config: PoolExhaustionConfig) -> Self {
+    self.pool_exhaustion = config;
+    self
+  }
+
+  /// Issues clients tickets to resume their sessions with for `lifetime`; needs the private key.
+  pub fn with_session_tickets(mut self, lifetime: Duration) -> Self {
+    self.ticket_lifetime = Some(lifetime);
+    self
+  }
+
+  /// Copies decrypted packets of clients to a sink for intrusion detection.
+  pub fn with_mirror(mut self, config: MirrorConfig) -> Self {
+    self.mirror = Some(config);
+    self
+  }
+
+  /// Samples the throughput of every session for the admin API, see `bandwidth`.
+  pub fn with_bandwidth_graphs(mut self, config: BandwidthConfig) -> Self {
+    self.bandwidth = Some(config);
+    self
+  }
+
+  /// Pushes the metrics to statsd or InfluxDB besides serving them for scraping, see `push`.
+  pub fn with_metrics_push(mut self, config: MetricsPushConfig) -> Self {
+    self.metrics_push = Some(config);
+    self
+  }
+
+  pub fn with_alerts(mut self, alerts: Alerts) -> Self {
+    self.alerts = Some(alerts);
+    self
+  }
+
+  /// When sessions are rekeyed, see `rekey`.
+  pub fn with_rekeying(mut self, config: RekeyConfig) -> Self {
+    self.rekeying = config;
+    self
+  }
+
+  pub fn with_schedule(mut self, config: ScheduleConfig) -> Self {
+    self.schedule = config;
+    self
+  }
+
+  /// Records sessions to the audit log, which scheduled jobs may rotate.
+  pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+    self.accounting.push(audit_log.clone());
+    self.audit_log = Some(audit_log);
+    self
+  }
+
   pub async fn build(self) -> anyhow::Result<Server> {
-    let bind_addr = format!("{}:{}", self.listen_address, self.listen_port);
+    self.transforms.check(&self.accepted_transforms)?;
+    let tickets = match (self.ticket_lifetime, &self.static_key) {
+      (Some(lifetime), Some(key)) => Some(
+        TicketIssuer::new(&key.secret(), lifetime).with_skew(self.token_skew.unwrap_or(tokens::DEFAULT_SKEW)),
+      ),
+      (Some(_), None) => anyhow::bail!("Session tickets require a private key"),
+      (None, _) => None,
+    };
+    let bind_addr = SocketAddr::new(self.listen_address.into(), self.listen_port);
+    if self.packet_pipe.is_some() && (self.tun_config.is_some() || self.userspace_nat.is_some()) {
+      anyhow::bail!("A packet pipe can't be used with a tun device or userspace NAT");
+    }
+    // The tun device, the listeners and the credential stores don't depend on each other, so they start
+    // side by side; loading a large store then doesn't hold the others up.
+    let startup = Readiness::new(self.startup_timeout.unwrap_or(startup::DEFAULT_TIMEOUT));
+    let (tun_config, tun_gso, userspace_nat, packet_pipe, networks) =
+      (self.tun_config, self.tun_gso, self.userspace_nat, self.packet_pipe, &self.networks);
+    let tun = async {
+      let (tun, mtu) = match (tun_config, userspace_nat) {
+        (Some(_), Some(_)) => anyhow::bail!("A tun device and userspace NAT can't be used together"),
+        (Some(config), None) => {
+          let device = tun::create_as_async(&config).map_err(diagnose::tun_error)?;
+          let gso = match tun_gso {
+            true => {
+              gso::enable(&device)?;
+              Some(tokio::sync::Mutex::new(GsoReader::default()))
+            }
+            false => None,
+          };
+          let mtu = device.mtu()?;
+          let name = device.tun_name()?;
+          for network in networks.iter() {
+            nat::run("ip", &["route", "replace", &network.pool.subnet().to_string(), "dev", &name]).await?;
+          }
+          (Some(Tun::Device { device, gso }), mtu)
+        }
+        #[cfg(feature = "userspace-nat")]
+        (None, Some(config)) => {
+          let nat = userspace::spawn(config);
+          let mtu = nat.mtu;
+          (Some(Tun::Userspace(nat)), mtu)
+        }
+        #[cfg(not(feature = "userspace-nat"))]
+        (None, Some(_)) => {
+          anyhow::bail!("Userspace NAT requires the server to be built with the userspace-nat feature")
+        }
+        (None, None) => match packet_pipe {
+          Some(mtu) => (Some(Tun::Pipe(PacketPipe::new(mtu))), mtu),
+          None => (None, MAX_MTU),
+        },
+      };
+      // Clients are never asked to go below the minimum, which they'd have to for packets to fit.
+      if mtu < MIN_MTU {
+        anyhow::bail!("MTU {} is below the minimum of {}", mtu, MIN_MTU);
+      }
+      Ok((tun, mtu))
+    };
+
+    let (memory, ecn, outer, tcp_port) = (self.memory, self.ecn, &self.outer, self.tcp_port);
+    let max_clients = self.max_clients.unwrap_or(10);
+    let client_timeout = self.client_timeout.unwrap_or(Duration::from_secs(30));
+    let listen_address = self.listen_address;
+    let listeners = async {
+      Ok(match memory {
+        Some(network) => Socket::Memory(network.bind(bind_addr)?),
+        None => {
+          let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|e| diagnose::bind_error(Protocol::Udp, bind_addr, e))?;
+          if ecn {
+            ecn::enable(&socket)?;
+          }
+          outer.apply(&socket)?;
+          if listen_address.is_unspecified() {
+            ecn::enable_pktinfo(&socket)?;
+          }
+          match tcp_port {
+            Some(port) => {
+              let addr = SocketAddr::new(listen_address.into(), port);
+              // Room for clients reconnecting before their old connection is given up on.
+              let transport = TcpTransport::listen(addr, max_clients * 2, client_timeout)
+                .await
+                .map_err(|e| diagnose::bind_error(Protocol::Tcp, addr, e))?;
+              info!("Accepting clients over TCP on {}", transport.local_addr());
+              Socket::UdpAndTcp(socket, transport)
+            }
+            None => Socket::Udp(socket),
+          }
+        }
+      })
+    };
+
+    let credential_stores = &self.credential_stores;
+    let stores = async {
+      for store in credential_stores {
+        store.prepare().await.map_err(|e| e.context(format!("The {} isn't usable", store.name())))?;
+      }
+      Ok(())
+    };
+
+    let ((tun, mtu), socket, ()) = startup
+      .within(async {
+        tokio::try_join!(
+          startup.start(Component::Tun, tun),
+          startup.start(Component::Listeners, listeners),
+          startup.start(Component::CredentialStores, stores),
+        )
+      })
+      .await?;
+    let metrics = Arc::new(Metrics::default());
+    let mirror = match self.mirror {
+      Some(config) => Some(Mirror::spawn(config, mtu, metrics.clone()).await?),
+      None => None,
+    };
+    let subnet = match (&self.address_pool, &tun) {
+      (Some(pool), _) => Some(pool.subnet()),
+      (None, Some(Tun::Device { device, .. })) => match (device.address()?, device.netmask()?) {
+        (IpAddr::V4(address), IpAddr::V4(netmask)) => Some(Ipv4Net::with_netmask(address, netmask)?.trunc()),
+        _ => None,
+      },
+      (None, _) => None,
+    };
+
+    let admin_service = match self.admin_service {
+      #[cfg(feature = "userspace-nat")]
+      Some((address, port)) => Some(service::spawn(address, port, mtu)),
+      #[cfg(not(feature = "userspace-nat"))]
+      Some(_) => {
+        anyhow::bail!("The admin service requires the server to be built with the userspace-nat feature")
+      }
+      None => None,
+    };
+
+    if cfg!(not(feature = "grpc")) && self.grpc_address.is_some() {
+      anyhow::bail!("The gRPC admin API requires the server to be built with the grpc feature");
+    }
+
+    if self.nat.is_enabled() && matches!(tun, None | Some(Tun::Pipe(_))) {
+      anyhow::bail!("NAT requires a tun device");
+    }
+    self.nat.setup().await?;
+
+    let history = Arc::new(self.history);
+    let mut accounting = self.accounting;
+    accounting.push(history.clone());
+
     let server = Server {
-      socket: UdpSocket::bind(bind_addr).await?,
+      socket: Arc::new(socket),
       listen_address: self.listen_address,
       listen_port: self.listen_port,
-      max_clients: self.max_clients.unwrap_or(10),
-      client_timeout: self.client_timeout.unwrap_or(Duration::from_secs(30)),
-      client_credentials: self.client_credentials.unwrap_or_default(),
-      clients: Arc::new(DashMap::new()),
+      max_clients,
+      client_timeout,
+      client_credentials: RwLock::new(self.client_credentials.unwrap_or_default()),
+      client_keys: RwLock::new(self.client_keys),
+      credential_stores: self.credential_stores,
+      passwords: self.passwords,
+      revocations: self.revocations,
+      accounting,
+      accounting_interval: self.accounting_interval,
+      stats_interval: self.stats_interval,
+      history,
+      clients: Arc::new(self.workers.session_table()),
+      sessions: self.workers.session_table(),
+      quarantine: Quarantine::new(self.quarantine, metrics.clone()),
+      replays: ReplayCache::new(self.handshake_skew.unwrap_or(Duration::from_secs(30))),
+      metrics,
+      workers: self.workers,
+      pacing: self.pacing,
+      offload: self.offload,
+      static_key: self.static_key,
+      certificate_authority: self.certificate_authority,
+      ecn: self.ecn,
+      icmp_unreachable: self.icmp_unreachable,
+      peer_encryption: self.peer_encryption,
+      required_features: self.required_features,
+      route_exchange: self.route_exchange,
+      mss_clamp: self.mss_clamp.then(|| mtu.saturating_sub(ip::TCP_IPV4_OVERHEAD)),
+      filters: self.filters,
+      transforms: self.transforms,
+      accepted_transforms: self.accepted_transforms,
+      cluster: self.cluster,
+      mdns: self.mdns,
+      rendezvous: self.rendezvous.map(Registration::new),
+      rendezvous_service: self.rendezvous_service.then(NameRegistry::default),
+      internal_dns: self.internal_dns,
+      preemption: self.preemption,
+      pool_exhaustion: self.pool_exhaustion,
+      rekeying: self.rekeying,
+      schedule: self.schedule,
+      audit_log: self.audit_log,
+      tickets,
+      mirror,
+      bandwidth: self.bandwidth.as_ref().map(BandwidthGraphs::new),
+      metrics_push: self.metrics_push,
+      traces: Traces::default(),
+      handshake_gates: HandshakeGates::default(),
+      alerts: self.alerts,
+      health_address: self.health_address,
+      grpc_address: self.grpc_address,
+      admin_tokens: self.admin_tokens,
+      config_file: self.config_file,
+      admin_service,
+      health: Arc::new(Health::default()),
+      startup,
+      tun,
+      mtu,
+      virtual_ips: DashMap::new(),
+      subnet_routes: DashMap::new(),
+      reverse_forwards: DashMap::new(),
+      address_pool: self.address_pool,
+      subnet,
+      networks: self.networks,
+      nat: self.nat,
+      policies: self.policies,
+      auth_policy: self.auth_policy,
+      usage: DashMap::new(),
+      quota_warnings: DashMap::new(),
+      webhook: self.webhook,
     };
 
     Ok(server)
@@ -93,72 +1212,1235 @@ impl Server {
     ServerBuilder::new(listen_address, listen_port)
   }
 
+  /// Sends packets to clients of a server built `with_packet_pipe`.
+  #[allow(dead_code)]
+  pub fn packet_handle(&self) -> Option<ServerHandle> {
+    match self.tun {
+      Some(Tun::Pipe(ref pipe)) => Some(pipe.handle()),
+      _ => None,
+    }
+  }
+
+  /// Packets clients send through the tunnel of a server built `with_packet_pipe`, once they passed the
+  /// ACLs and filters; given out once. Packets the application doesn't take in time are dropped.
+  #[allow(dead_code)]
+  pub fn client_packets(&mut self) -> Option<tokio::sync::mpsc::Receiver<SessionPacket>> {
+    match self.tun {
+      Some(Tun::Pipe(ref mut pipe)) => pipe.take_receiver(),
+      _ => None,
+    }
+  }
+
   pub async fn run(self) -> anyhow::Result<()> {
     info!("Starting server on {}:{}", self.listen_address, self.listen_port);
 
     let server = Arc::new(self);
+    let supervisor = Supervisor::default();
 
-    let cleanup_server = server.clone();
     let cleanup_interval = server.client_timeout / 2;
-    tokio::spawn(async move {
-      loop {
-        cleanup_server.cleanup_inactive_clients().await;
-        tokio::time::sleep(cleanup_interval).await;
-      }
-    });
+    let cleanup_server = server.clone();
+    supervisor.spawn("cleanup", Restart::Always, move || cleanup_server.clone().clean_up(cleanup_interval));
 
-    let mut buf = vec![0u8; 65536];
+    if let Some(address) = server.health_address {
+      server.startup.expect(Component::AdminApi);
+      let health_server = server.clone();
+      supervisor.spawn("health", Restart::Always, move || {
+        health::serve(address, health_server.clone(), cleanup_interval)
+      });
+    }
 
-    loop {
-      let (len, src_addr) = server.socket.recv_from(&mut buf).await?;
+    #[cfg(feature = "grpc")]
+    if let Some(address) = server.grpc_address {
+      server.startup.expect(Component::Grpc);
+      let grpc_server = server.clone();
+      supervisor.spawn("grpc", Restart::Always, move || crate::grpc::serve(address, grpc_server.clone()));
+    }
 
-      let packet = EncryptedPacket::from_bytes(&buf[..len])?;
+    if server.admin_service.is_some() {
+      let service_server = server.clone();
+      supervisor
+        .spawn("admin-service", Restart::Always, move || service_server.clone().serve_admin_service());
+      let answering_server = server.clone();
+      supervisor.spawn("admin-service-requests", Restart::Always, move || {
+        answering_server.clone().answer_admin_service(cleanup_interval)
+      });
+    }
 
-      match packet.decrypt(&server.get_client_key(src_addr)) {
-        Ok(packet) => {
-          let server = server.clone();
-          tokio::spawn(async move {
-            if let Err(e) = server.handle(packet, src_addr).await {
-              error!("Error handling packet from {}: {}", src_addr, e);
-            }
-          });
-        }
-        Err(e) => {
-          error!("Error decrypting/deserializing packet from {}: {}", src_addr, e);
-        }
-      }
+    if server.cluster.is_some() {
+      let cluster_server = server.clone();
+      supervisor.spawn("cluster", Restart::Always, move || cluster_server.clone().serve_cluster());
+      let announcing_server = server.clone();
+      supervisor
+        .spawn("cluster-announcer", Restart::Always, move || announcing_server.clone().announce_sessions());
     }
-  }
 
-  pub async fn assert_auth(&self, src_addr: SocketAddr) -> anyhow::Result<()> {
-    if !self.clients.contains_key(&src_addr) {
-      self.send_packet(ServerPacket::AuthError("Invalid credentials".into()), src_addr).await?;
-      anyhow::bail!("Invalid credentials for {}", src_addr);
+    if server.mdns.is_some() {
+      let mdns_server = server.clone();
+      supervisor.spawn("mdns", Restart::Always, move || {
+        let server = mdns_server.clone();
+        async move { server.mdns.as_ref().unwrap().serve().await }
+      });
     }
 
-    let mut client = self.clients.get_mut(&src_addr).unwrap();
-    client.last_seen = Instant::now();
+    if server.rendezvous.is_some() {
+      let rendezvous_server = server.clone();
+      supervisor
+        .spawn("rendezvous", Restart::Always, move || rendezvous_server.clone().register_rendezvous());
+    }
 
-    Ok(())
-  }
+    if server.internal_dns.is_some() {
+      let dns_server = server.clone();
+      supervisor.spawn("internal-dns", Restart::Always, move || {
+        let server = dns_server.clone();
+        async move { server.relay_dns().await }
+      });
+    }
+
+    if server.tun.is_some() {
+      let tun_server = server.clone();
+      supervisor.spawn("tun", Restart::Always, move || {
+        let server = tun_server.clone();
+        async move { server.serve_tun().await }
+      });
+    }
+
+    if server.revocations.is_enabled() {
+      let revocation_server = server.clone();
+      supervisor
+        .spawn("revocations", Restart::Always, move || revocation_server.clone().follow_revocations());
+    }
+
+    if let Some(interval) = server.accounting_interval.filter(|_| !server.accounting.is_empty()) {
+      let accounting_server = server.clone();
+      supervisor
+        .spawn("accounting", Restart::Always, move || accounting_server.clone().account_interim(interval));
+    }
+
+    if let Some(interval) = server.stats_interval {
+      let stats_server = server.clone();
+      supervisor.spawn("stats", Restart::Always, move || stats_server.clone().push_stats(interval));
+    }
+
+    if server.bandwidth.is_some() {
+      let bandwidth_server = server.clone();
+      supervisor.spawn("bandwidth", Restart::Always, move || bandwidth_server.clone().sample_bandwidth());
+    }
 
-  pub fn get_client_key(&self, src_addr: SocketAddr) -> Key {
-    self.clients.get(&src_addr).map(|c| c.key).unwrap_or([0u8; KEY_SIZE])
+    if server.metrics_push.is_some() {
+      let push_server = server.clone();
+      supervisor.spawn("metrics-push", Restart::Always, move || push_server.clone().push_metrics());
+    }
+
+    if !server.schedule.is_empty() {
+      let schedule_server = server.clone();
+      supervisor.spawn("schedule", Restart::Always, move || schedule_server.clone().run_schedule());
+    }
+
+    server.health.set_main_loop_running(true);
+    let _guard = MainLoopGuard(server.health.clone());
+
+    let workers = WorkerPool::spawn(server.clone(), &server.workers, &supervisor);
+    let result = tokio::select! {
+      result = server.receive(&workers) => result,
+      error = supervisor.failed() => Err(error),
+      error = server.watch_startup() => Err(error),
+    };
+    // Tasks stop before the workers' queues close, so that workers don't report ending.
+    supervisor.stop();
+    result
   }
 
-  async fn cleanup_inactive_clients(&self) {
-    let clients_to_remove: Vec<_> =
-      self.clients.iter().filter(|client| client.is_expired()).map(|client| client.addr).collect();
+  /// Fails once startup takes too long, naming what didn't start; never resolves otherwise.
+  async fn watch_startup(&self) -> anyhow::Error {
+    match self.startup.wait().await {
+      Ok(()) => {
+        info!("Server ready in {}ms", self.startup.elapsed().as_millis());
+        std::future::pending().await
+      }
+      Err(e) => e,
+    }
+  }
+
+  /// Drops inactive clients and expired state every `interval`, beating the health check's heart.
+  async fn clean_up(self: Arc<Self>, interval: Duration) {
+    loop {
+      self.health.cleanup_beat();
+      self.cleanup_inactive_clients().await;
+      self.quarantine.prune();
+      self.traces.expire();
+      if let Some(ref registry) = self.rendezvous_service {
+        registry.prune(Instant::now());
+      }
+      tokio::time::sleep(interval).await;
+    }
+  }
+
+  /// Reloads the revocation list every second, disconnecting clients whose keys were revoked.
+  async fn follow_revocations(self: Arc<Self>) {
+    loop {
+      tokio::time::sleep(Duration::from_secs(1)).await;
+      match self.revocations.reload() {
+        Ok(true) => self.disconnect_revoked().await,
+        Ok(false) => {}
+        Err(e) => error!("Failed to reload the revocation list: {}", e),
+      }
+    }
+  }
+
+  async fn account_interim(self: Arc<Self>, interval: Duration) {
+    loop {
+      tokio::time::sleep(interval).await;
+      for client in self.clients.iter() {
+        self.record_accounting(AccountingKind::Interim, &client);
+      }
+    }
+  }
+
+  async fn push_stats(self: Arc<Self>, interval: Duration) {
+    loop {
+      tokio::time::sleep(interval).await;
+      self.send_stats().await;
+    }
+  }
+
+  /// Reads datagrams from the socket and hands them to the workers until reading fails. Packets of sessions
+  /// opened on the blocking pool, see `OffloadConfig`, stay in flight while the next ones are read, up to
+  /// `offload::IN_FLIGHT` of them; they're handled in the order they arrived in all the same.
+  async fn receive(self: &Arc<Self>, workers: &WorkerPool) -> anyhow::Result<()> {
+    let server = self;
+    // Clients may use a larger MTU than the server's tun and transforms grow datagrams, so accept any size.
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut in_flight: VecDeque<(Received, Offloaded<anyhow::Result<ClientPacket>>)> = VecDeque::new();
 
-    for addr in clients_to_remove {
-      info!("Disconnecting stale client {}", addr);
-      self.clients.remove(&addr);
+    loop {
+      let received = tokio::select! {
+        biased;
+        opened = async {
+          match in_flight.front_mut() {
+            Some((_, opening)) => opening.await,
+            None => std::future::pending().await,
+          }
+        }, if !in_flight.is_empty() => {
+          if let Some((received, _)) = in_flight.pop_front() {
+            server.dispatch(workers, received, opened.and_then(|result| result)).await;
+          }
+          continue;
+        }
+        received = server.socket.recv_from_to(&mut buf), if in_flight.len() < offload::IN_FLIGHT => received?,
+      };
+      let (len, src_addr, outer_ecn, local) = received;
+      let shed = workers.shed();
+
+      if server.quarantine.is_quarantined(src_addr.ip()) {
+        continue;
+      }
 
-      if let Err(e) =
-        self.send_packet(ServerPacket::Disconnect { reason: "Stale connection".into() }, addr).await
+      if rendezvous::is_rendezvous(&buf[..len]) {
+        if let Err(e) = server.handle_rendezvous(src_addr, &buf[..len]).await {
+          server.record_decrypt_failure(src_addr, &e);
+        }
+        continue;
+      }
+
+      let Some(session_id) = packet::peek_session_id(&buf[..len]) else {
+        server.record_decrypt_failure(src_addr, &"packet too short");
+        continue;
+      };
+
+      let listener = server.listener_of(src_addr);
+      let handshake = session_id == HANDSHAKE_SESSION;
+      server.metrics.listener(listener).record(handshake, len);
+      if handshake && server.handshake_gates.is_refused(listener, Instant::now()) {
+        server.metrics.listener(listener).refused_handshakes.inc();
+        continue;
+      }
+
+      let demux = server.demux(session_id, src_addr, len);
+      if let Demux::Oversized { max_packet_size } = demux {
+        server.record_decrypt_failure(
+          src_addr,
+          &format_args!("datagram of {} bytes, more than {}", len, max_packet_size),
+        );
+        continue;
+      }
+      let Some((key, pipeline)) = demux.session() else {
+        server.record_decrypt_failure(src_addr, &format_args!("unknown session {:#x}", session_id));
+        continue;
+      };
+
+      let datagram: Arc<[u8]> = buf[..len].into();
+      let opening = {
+        let datagram = datagram.clone();
+        server.offload.start(len, move || pipeline.open::<ClientPacket>(&key, &datagram))
+      };
+      let received = Received { demux, datagram, session_id, src_addr, outer_ecn, local, shed };
+
+      // Packets of established sessions don't change how the ones after them are demultiplexed, so they queue
+      // up behind those being opened; anything else waits for the queue to be handled first.
+      if matches!(received.demux, Demux::Session(..)) && (opening.is_running() || !in_flight.is_empty()) {
+        in_flight.push_back((received, opening));
+        continue;
+      }
+      while let Some((earlier, opening)) = in_flight.pop_front() {
+        let opened = opening.await.and_then(|result| result);
+        server.dispatch(workers, earlier, opened).await;
+      }
+      let opened = opening.await.and_then(|result| result);
+      server.dispatch(workers, received, opened).await;
+    }
+  }
+
+  /// Handles a datagram `receive` has opened, or failed to.
+  async fn dispatch(
+    &self,
+    workers: &WorkerPool,
+    received: Received,
+    decrypted: anyhow::Result<ClientPacket>,
+  ) {
+    let server = self;
+    let Received { demux, datagram, session_id, src_addr, outer_ecn, local, shed } = received;
+    let len = datagram.len();
+
+    // A packet opened while one before it was rekeying its session gets another go with the new key.
+    let decrypted = match (decrypted, &demux) {
+      (Err(e), Demux::Session(key, _)) => match server.demux(session_id, src_addr, len) {
+        Demux::Session(current, pipeline) if &current != key => {
+          pipeline.open::<ClientPacket>(&current, &datagram)
+        }
+        _ => Err(e),
+      },
+      (decrypted, _) => decrypted,
+    };
+
+    // Only a packet sealed with the session key moves a session of another node here.
+    if let (Demux::Cluster { entry, pipeline }, Ok(_)) = (&demux, &decrypted) {
+      if let Err(e) = server.adopt(entry.as_ref().clone(), pipeline.clone(), src_addr, local).await {
+        warn!("{}", e);
+        return;
+      }
+    }
+
+    if let Demux::Roaming { from, .. } = demux {
+      match decrypted {
+        Ok(packet) => {
+          server.traces.record(session_id, Flow::Received, trace::client_kind(&packet), len, || {
+            format!("from {}, a new address of the session", src_addr)
+          });
+          if let Err(e) = server.roam(from, src_addr, local, packet).await {
+            error!("Failed to validate the path of {} from {}: {}", from, src_addr, e);
+          }
+        }
+        Err(e) => server.record_decrypt_failure(src_addr, &e),
+      }
+      return;
+    }
+
+    match decrypted {
+      Ok(ClientPacket::KeyExchange { key, transforms, timestamp, features, limits })
+        if matches!(demux, Demux::Handshake) =>
+      {
+        match workers.deferral(shed) {
+          Some(retry_after) => server.defer_handshake(src_addr, local, retry_after).await,
+          None => {
+            workers
+              .submit(
+                Job::KeyExchange(key, transforms, Offer { features, limits }, timestamp, local),
+                src_addr,
+              )
+              .await
+          }
+        }
+      }
+      Ok(packet) if matches!(demux, Demux::Handshake) => {
+        server.record_decrypt_failure(
+          src_addr,
+          &format_args!("unexpected packet outside of a session: {:?}", packet),
+        );
+      }
+      Ok(ClientPacket::Rekey { key }) if matches!(demux, Demux::Session(..)) => {
+        server.traces.record(session_id, Flow::Received, "rekey", len, || "session key replaced".to_string());
+        if let Err(e) = server.complete_rekey(src_addr, key).await {
+          warn!(target: logging::HANDSHAKE, "Failed to rekey the session of {}: {}", src_addr, e);
+        }
+      }
+      Ok(ClientPacket::Data(_)) if shed >= Shed::NormalData && shed.drops_data(server.priority(src_addr)) => {
+        server.metrics.shed_data_packets.inc();
+        server.traces.record(session_id, Flow::Received, "data", len, || "dropped to shed load".to_string());
+        trace!(target: logging::DATAPATH, "Dropping packet from {} to shed load", src_addr);
+      }
+      Ok(ClientPacket::Data(mut payload)) => {
+        server.metrics.record_data(payload.len(), len);
+        if !ecn::decapsulate(&mut payload, outer_ecn) {
+          server.traces.record(session_id, Flow::Received, "data", len, || {
+            "dropped: congestion mark on a packet that isn't ECN-capable".to_string()
+          });
+          trace!(
+            target: logging::DATAPATH,
+            "Dropping packet from {}: congestion mark on a packet that isn't ECN-capable",
+            src_addr
+          );
+          return;
+        }
+        workers.submit(Job::Packet(ClientPacket::Data(payload)), src_addr).await;
+      }
+      Ok(packet) => {
+        server.traces.record(session_id, Flow::Received, trace::client_kind(&packet), len, || {
+          "handed to a worker".to_string()
+        });
+        workers.submit(Job::Packet(packet), src_addr).await
+      }
+      Err(e) => {
+        server.traces.record(session_id, Flow::Received, "unknown", len, || format!("dropped: {}", e));
+        server.record_decrypt_failure(src_addr, &e);
+        if matches!(demux, Demux::Session(..)) {
+          server.record_session_decrypt_failure(src_addr).await;
+        }
+      }
+    }
+  }
+
+  /// Asks a client to retry its key exchange after about `retry_after` instead of taking it on now, see
+  /// `WorkerPool::deferral`.
+  async fn defer_handshake(&self, addr: SocketAddr, local: Option<Ipv4Addr>, retry_after: Duration) {
+    self.metrics.deferred_handshakes.inc();
+    let sent = match protocol::deferral(retry_after) {
+      Ok(reply) => self.socket.send_from(&reply, addr, ip::ECN_NOT_ECT, local).await.map_err(Into::into),
+      Err(e) => Err(e),
+    };
+    match sent {
+      Ok(_) => debug!(target: logging::HANDSHAKE, "Deferred the key exchange of {}", addr),
+      Err(e) => error!(target: logging::HANDSHAKE, "Failed to defer the key exchange of {}: {}", addr, e),
+    }
+  }
+
+  /// Priority of the session at `addr`; normal for unknown ones.
+  fn priority(&self, addr: SocketAddr) -> Priority {
+    self.clients.get(&addr).map(|client| client.policy.priority).unwrap_or_default()
+  }
+
+  /// Features offered in key exchanges: pairwise keys only with `peer-encryption`, hostnames only with
+  /// the internal DNS.
+  pub fn features(&self) -> Features {
+    let mut features = Features::SUPPORTED;
+    if !self.peer_encryption {
+      features = features.difference(Features::PEER_KEYS);
+    }
+    if self.internal_dns.is_none() {
+      features = features.difference(Features::HOSTNAMES);
+    }
+    if self.static_key.is_none() {
+      features = features.difference(Features::PINNED_KEY);
+    }
+    features
+  }
+
+  /// Whether a control packet of the session at `addr` is within its rate; unknown clients are left to
+  /// `assert_auth`.
+  pub fn allow_control(&self, addr: SocketAddr) -> bool {
+    let allowed = self.clients.get(&addr).is_none_or(|client| client.control.lock().unwrap().try_take(1.0));
+    if !allowed {
+      self.metrics.dropped_control_packets.inc();
+      trace!(target: logging::DATAPATH, "Client {} sends control packets too often; dropping one", addr);
+    }
+    allowed
+  }
+
+  pub async fn assert_auth(&self, src_addr: SocketAddr) -> anyhow::Result<()> {
+    if !self.clients.contains_key(&src_addr) {
+      self
+        .send_packet(
+          ServerPacket::AuthError { code: ErrorCode::SessionLost, message: "Not authenticated".into() },
+          src_addr,
+        )
+        .await?;
+      anyhow::bail!("Invalid credentials for {}", src_addr);
+    }
+
+    if let Some(client) = self.clients.get(&src_addr) {
+      client.last_seen.touch();
+    }
+
+    Ok(())
+  }
+
+  pub async fn authenticate_with_stores(&self, credentials: &Credentials) -> Option<Identity> {
+    for store in &self.credential_stores {
+      match store.authenticate(credentials).await {
+        Ok(Some(identity)) => return Some(identity),
+        Ok(None) => {}
+        Err(e) => error!(target: logging::HANDSHAKE, "Credential store {} failed: {}", store.name(), e),
+      }
+    }
+
+    None
+  }
+
+  /// Groups the account a ticket was issued to is in now, or `None` if it's gone or disabled: resuming
+  /// skips authentication, so it mustn't outlive the account. Accounts of stores that can't look them up
+  /// keep the groups of the ticket.
+  pub async fn current_groups(&self, ticket: &Ticket) -> Option<Vec<String>> {
+    let listed = |credentials: &[Credentials], keys: &[KeyCredentials]| {
+      let key = |entry: &KeyCredentials| {
+        entry.username == ticket.username && Some(entry.public_key) == ticket.public_key
+      };
+      match ticket.public_key {
+        Some(_) => keys.iter().any(key),
+        None => credentials.iter().any(|credentials| credentials.username() == Some(&ticket.username)),
+      }
+    };
+    if let Some(ref name) = ticket.network {
+      let network = self.networks.get(name)?;
+      return listed(&network.client_credentials, &network.client_keys).then(Vec::new);
+    }
+    if listed(&self.client_credentials.read().unwrap(), &self.client_keys.read().unwrap()) {
+      return Some(Vec::new());
+    }
+    // Keys not listed are of certificates, which carry the account themselves and are checked against
+    // revocations instead.
+    if ticket.public_key.is_some() {
+      return self.certificate_authority.is_some().then(Vec::new);
+    }
+
+    let mut unsupported = false;
+    for store in &self.credential_stores {
+      match store.lookup(&ticket.username).await {
+        Ok(Lookup::Active(identity)) => return Some(identity.groups),
+        Ok(Lookup::Absent) => {}
+        Ok(Lookup::Unsupported) => unsupported = true,
+        Err(e) => {
+          error!(target: logging::HANDSHAKE, "Credential store {} failed: {}", store.name(), e);
+          return None;
+        }
+      }
+    }
+    unsupported.then(|| ticket.groups.clone())
+  }
+
+  /// Whether the password of the ticket's user changed after it was issued. Tickets aren't trusted when
+  /// that can't be told.
+  pub async fn password_changed_since(&self, ticket: &Ticket) -> bool {
+    let Some(ref passwords) = self.passwords else {
+      return false;
+    };
+    match passwords.changed_at(&ticket.username).await {
+      Ok(changed_at) => changed_at.is_some_and(|changed_at| changed_at > ticket.issued_at),
+      Err(e) => {
+        error!(target: logging::HANDSHAKE, "Failed to read the password file: {}", e);
+        true
+      }
+    }
+  }
+
+  pub fn get_client_session(&self, src_addr: SocketAddr) -> (Key, SessionId, Arc<Pipeline>) {
+    self.clients.get(&src_addr).map(|c| (c.key, c.session_id, c.pipeline.clone())).unwrap_or((
+      [0u8; KEY_SIZE],
+      HANDSHAKE_SESSION,
+      Arc::default(),
+    ))
+  }
+
+  /// MSS to clamp TCP connections of `addr` to, if clamping is on; lower for clients with a smaller MTU.
+  pub fn mss_clamp_for(&self, addr: SocketAddr) -> Option<u16> {
+    let mss = self.mss_clamp?;
+    match self.clients.get(&addr).and_then(|client| client.mtu) {
+      Some(mtu) => Some(mss.min(mtu.saturating_sub(ip::TCP_IPV4_OVERHEAD))),
+      None => Some(mss),
+    }
+  }
+
+  /// Returns whether a tun packet for `addr` should be sent, having marked it if the client's queue is
+  /// congested, see `RedConfig`.
+  fn apply_red(&self, addr: SocketAddr, packet: &mut [u8]) -> bool {
+    let Some(ref red) = self.pacing.red else {
+      return true;
+    };
+    let Some(fill) = self
+      .clients
+      .get(&addr)
+      .map(|client| 1.0 - client.outbound.capacity() as f64 / client.outbound.max_capacity() as f64)
+    else {
+      return true;
+    };
+
+    let mut roll = [0u8; 4];
+    fill_random_bytes(&mut roll);
+    let roll = u32::from_be_bytes(roll) as f64 / u32::MAX as f64;
+
+    let ecn_capable = !matches!(ip::ipv4_ecn(packet), None | Some(ip::ECN_NOT_ECT));
+    match red.verdict(fill, ecn_capable, roll) {
+      Verdict::Send => true,
+      Verdict::Mark => {
+        ip::mark_congestion(packet);
+        self.metrics.red_marked_packets.inc();
+        true
+      }
+      Verdict::Drop => {
+        self.metrics.red_dropped_packets.inc();
+        false
+      }
+    }
+  }
+
+  async fn serve_tun(&self) -> anyhow::Result<()> {
+    let Some(ref tun) = self.tun else {
+      return Ok(());
+    };
+    self.health.set_tun_loop_running(true);
+    let _guard = TunLoopGuard(self.health.clone());
+
+    let mut buf = vec![0u8; self.mtu as usize];
+    loop {
+      let len = match tun.recv(&mut buf).await {
+        Ok(len) => len,
+        Err(e) => match tun.removed(&e) {
+          Some(name) => {
+            error!(target: logging::TUN, "Tun device {} was removed; shutting down", name);
+            self
+              .disconnect_all(ErrorCode::SessionLost, "Server is shutting down: its tun device was removed")
+              .await;
+            return Err(Fatal(format!("Tun device {} was removed", name)).into());
+          }
+          None => return Err(e),
+        },
+      };
+      let packet = &buf[..len];
+
+      let Some(addr) = ip::ipv4_destination(packet).and_then(|dst| self.route_to(dst)) else {
+        trace!(target: logging::TUN, "Dropping tun packet without a client destination; len: {}", len);
+        continue;
+      };
+
+      let mut packet = packet.to_vec();
+      if !self.apply_red(addr, &mut packet) {
+        self.trace(addr, Flow::Sent, "data", len, || "dropped: send queue congested".to_string());
+        trace!(target: logging::TUN, "Dropping tun packet to {}: send queue congested", addr);
+        continue;
+      }
+
+      if !self.filter_packet(Direction::Outbound, addr, &packet) {
+        self.trace(addr, Flow::Sent, "data", len, || "dropped: denied by a filter".to_string());
+        continue;
+      }
+      if let Some(mss) = self.mss_clamp_for(addr) {
+        ip::clamp_tcp_mss(&mut packet, mss);
+      }
+
+      if let Err(e) = self.account(addr, Direction::Outbound, len).await {
+        error!("{}", e);
+        continue;
+      }
+
+      self.mirror_packet(addr, &packet);
+      if self.clients.get(&addr).is_some_and(|client| client.suspended.swap(false, Ordering::Relaxed)) {
+        debug!(target: logging::TUN, "Waking {} up for a packet to it", addr);
+        if let Err(e) = self.send_packet(ServerPacket::Notice(Notice::Wake), addr).await {
+          error!("Failed to wake {} up: {}", addr, e);
+        }
+      }
+      if let Some(notice) = self.inbound_notice(addr, &packet) {
+        if let Err(e) = self.send_packet(ServerPacket::Notice(notice), addr).await {
+          error!("Failed to notify {} of an inbound connection: {}", addr, e);
+        }
+      }
+
+      if let Err(e) = self.send_packet(ServerPacket::Data(packet), addr).await {
+        error!(target: logging::TUN, "Failed to forward tun packet to {}: {}", addr, e);
+      }
+    }
+  }
+
+  /// Answers the requests of the admin service.
+  async fn answer_admin_service(self: Arc<Self>, cleanup_interval: Duration) {
+    let Some(ref service) = self.admin_service else {
+      return;
+    };
+    while let Some(request) = service.request().await {
+      let server = self.clone();
+      tokio::spawn(async move {
+        let report = HealthReport::of(&server, cleanup_interval);
+        match health::answer(&request.text, request.peer.into(), report, &server).await {
+          Ok(response) => request.respond(response),
+          Err(e) => error!(target: logging::ADMIN, "Failed to answer an admin service request: {}", e),
+        }
+      });
+    }
+  }
+
+  /// Routes the packets of the admin service to clients.
+  async fn serve_admin_service(self: Arc<Self>) {
+    let Some(ref service) = self.admin_service else {
+      return;
+    };
+
+    while let Some(packet) = service.recv().await {
+      let Some(addr) = ip::ipv4_destination(&packet).and_then(|dst| self.route_to(dst)) else {
+        trace!(target: logging::ADMIN, "Dropping admin service packet without a client destination; len: {}", packet.len());
+        continue;
+      };
+      if let Err(e) = self.send_packet(ServerPacket::Data(packet), addr).await {
+        error!(target: logging::ADMIN, "Failed to send admin service packet to {}: {}", addr, e);
+      }
+    }
+  }
+
+  /// Counts a violation of the client at `addr` caught in `packet`, raising an alert once it reaches the
+  /// client's threshold.
+  pub fn alert(&self, addr: SocketAddr, violation: Violation, packet: &[u8]) {
+    let Some(ref alerts) = self.alerts else {
+      return;
+    };
+    let event = {
+      let Some(mut client) = self.clients.get_mut(&addr) else {
+        return;
+      };
+      let thresholds = alerts.thresholds(client.username.as_deref());
+      let Some(count) = client.alerts.violation(violation, &thresholds, alerts.window(), Instant::now())
+      else {
+        return;
+      };
+      AdminEvent::PolicyViolation {
+        username: client.username.clone().unwrap_or_default(),
+        device: client.device.clone(),
+        network: client.network.clone(),
+        client_addr: addr.to_string(),
+        violation,
+        source: ip::ipv4_source(packet),
+        destination: ip::ipv4_destination(packet),
+        count,
+      }
+    };
+
+    warn!("Policy alert: {:?}", event);
+    self.metrics.policy_alerts.inc();
+    if let Some(ref webhook) = self.webhook {
+      webhook.send(event);
+    }
+  }
+
+  /// Counts a data packet of the client at `addr` towards its flow rate threshold.
+  pub fn count_packet(&self, addr: SocketAddr, packet: &[u8]) {
+    let Some(ref alerts) = self.alerts else {
+      return;
+    };
+    let over = self.clients.get_mut(&addr).is_some_and(|mut client| {
+      let thresholds = alerts.thresholds(client.username.as_deref());
+      client.alerts.packet(&thresholds, Instant::now())
+    });
+    if over {
+      self.alert(addr, Violation::FlowRate, packet);
+    }
+  }
+
+  pub fn mirror_packet(&self, addr: SocketAddr, packet: &[u8]) {
+    if let Some(ref mirror) = self.mirror {
+      mirror.mirror(self.clients.get(&addr).and_then(|client| client.network.clone()).as_deref(), packet);
+    }
+  }
+
+  /// Notice for the client if `packet` opens a connection through a port forward of its user.
+  fn inbound_notice(&self, addr: SocketAddr, packet: &[u8]) -> Option<Notice> {
+    if !self.nat.has_port_forwards() && self.reverse_forwards.is_empty() {
+      return None;
+    }
+    let flow = ip::flow(packet)?;
+    let mut client = self.clients.get_mut(&addr)?;
+    let username = client.username.as_deref()?;
+    let reverse = ReverseForward { protocol: flow.protocol, port: flow.destination_port };
+    if !self.nat.is_forwarded(username, flow.protocol, flow.destination_port)
+      && !client.reverse_forwards.contains(&reverse)
+    {
+      return None;
+    }
+    client.inbound.notice(flow, packet, Instant::now())
+  }
+
+  /// Runs the packet through the configured filters; false if any of them drops it.
+  /// Answers a packet of `addr` that's being dropped with ICMP destination unreachable, if enabled.
+  pub async fn reject(&self, addr: SocketAddr, packet: &[u8], code: u8) -> anyhow::Result<()> {
+    if !self.icmp_unreachable {
+      return Ok(());
+    }
+
+    // From the destination itself, as a host refusing the connection would answer.
+    let reply =
+      ip::ipv4_destination(packet).and_then(|destination| ip::icmp_unreachable(packet, destination, code));
+    if let Some(reply) = reply {
+      self.send_packet(ServerPacket::Data(reply), addr).await?;
+    }
+    Ok(())
+  }
+
+  pub fn filter_packet(&self, direction: Direction, addr: SocketAddr, packet: &[u8]) -> bool {
+    if self.filters.is_empty() {
+      return true;
+    }
+
+    let username = self.clients.get(&addr).and_then(|c| c.username.clone()).unwrap_or_default();
+    let context = PacketContext { direction, username: &username, packet };
+    self.filters.iter().all(|filter| match filter.filter(&context) {
+      Ok(Action::Accept) => true,
+      Ok(Action::Drop) => {
+        trace!(target: logging::DATAPATH, "Dropping {:?} packet of {}: denied by filter {}", direction, addr, filter.name());
+        false
+      }
+      Err(e) => {
+        warn!("Filter {} failed on a packet of {}: {}", filter.name(), addr, e);
+        false
+      }
+    })
+  }
+
+  pub async fn learn_virtual_ip(&self, src_addr: SocketAddr, packet: &[u8]) -> anyhow::Result<()> {
+    let Some(source) = ip::ipv4_source(packet) else {
+      anyhow::bail!("Non-IPv4 packet from {}", src_addr);
+    };
+
+    let (username, groups) = {
+      let Some(mut client) = self.clients.get_mut(&src_addr) else {
+        anyhow::bail!("Unknown client {}", src_addr);
+      };
+
+      let spoofed = match client.virtual_ip {
+        Some(ip) if ip == source => return Ok(()),
+        // Hosts of a LAN behind the client, which routes for them.
+        _ if client.subnets.iter().any(|subnet| subnet.contains(&source)) => return Ok(()),
+        Some(ip) => Some(format!("sent a packet from {} instead of {}", source, ip)),
+        None if self.virtual_ips.contains_key(&source) => {
+          Some(format!("claims {} which is used by another client", source))
+        }
+        None if self.subnet_of(client.network.as_deref()).is_some_and(|subnet| !subnet.contains(&source)) => {
+          Some(format!("claims {} outside of its network's subnet", source))
+        }
+        None => {
+          client.virtual_ip = Some(source);
+          None
+        }
+      };
+      if let Some(reason) = spoofed {
+        drop(client);
+        self.alert(src_addr, Violation::SpoofedSource, packet);
+        anyhow::bail!("Client {} {}", src_addr, reason);
+      }
+
+      (client.username.clone().unwrap_or_default(), client.policy.groups.clone())
+    };
+
+    self.virtual_ips.insert(source, src_addr);
+    info!("Client {} uses virtual address {}", src_addr, source);
+    self.nat.assign(&username, &groups, source).await
+  }
+
+  /// Queue of datagrams to the client at `addr`, paced at the server's rates and the most generous of the
+  /// bandwidth classes of the client's groups.
+  pub fn send_queue(
+    &self,
+    addr: SocketAddr,
+    local: Option<Ipv4Addr>,
+    bandwidth_classes: &[String],
+  ) -> SendQueue {
+    let pacing = self.pacing.for_classes(bandwidth_classes);
+    pacing::spawn_send_queue(self.socket.clone(), addr, local, &pacing, self.metrics.clone())
+  }
+
+  /// Subnet of the network's clients, `None` when the server can't tell, e.g. with no tun and no pool.
+  pub fn subnet_of(&self, network: Option<&str>) -> Option<Ipv4Net> {
+    match network {
+      Some(name) => self.networks.get(name).map(|network| network.pool.subnet()),
+      None => self.subnet,
+    }
+  }
+
+  /// Pool clients of the network get their addresses from.
+  pub fn pool_of(&self, network: Option<&str>) -> Option<&AddressPool> {
+    match network {
+      Some(name) => self.networks.get(name).map(|network| &network.pool),
+      None => self.address_pool.as_ref(),
+    }
+  }
+
+  /// Leases an address to the client, or reuses the one it holds, and returns the configuration to send it;
+  /// `None` once the pool is exhausted.
+  pub async fn lease_address(
+    &self,
+    pool: &AddressPool,
+    addr: SocketAddr,
+  ) -> anyhow::Result<Option<ServerPacket>> {
+    let (address, leased, username, groups, dns) = {
+      let Some(mut client) = self.clients.get_mut(&addr) else {
+        anyhow::bail!("Unknown client {}", addr);
+      };
+
+      let resumed = client.ticket.as_ref().and_then(|ticket| ticket.address);
+      let (address, leased) = match client.virtual_ip {
+        Some(address) => (address, false),
+        None => match resumed.filter(|address| pool.lease_at(*address)).or_else(|| pool.lease()) {
+          Some(address) => (address, true),
+          None => return Ok(None),
+        },
+      };
+      client.virtual_ip = Some(address);
+      let username = client.username.clone().unwrap_or_default();
+      (address, leased, username, client.policy.groups.clone(), client.policy.dns.clone())
+    };
+
+    if leased {
+      self.virtual_ips.insert(address, addr);
+      info!("Leased {} to client {}", address, addr);
+      self.nat.assign(&username, &groups, address).await?;
+    }
+
+    Ok(Some(ServerPacket::NetworkConfig {
+      address,
+      prefix_len: pool.subnet().prefix_len(),
+      dns: match self.internal_dns {
+        Some(ref internal) => vec![internal.address],
+        None if !dns.is_empty() => dns,
+        None => pool.dns.clone(),
+      },
+    }))
+  }
+
+  async fn send_stats(&self) {
+    let stats: Vec<_> = self
+      .clients
+      .iter()
+      .filter(|client| client.authenticated_at.is_some() && client.features.contains(Features::STATS_PUSH))
+      .map(|client| {
+        let used = client.account().and_then(|account| self.usage.get(&account).map(|used| *used));
+        let quota_remaining = client.policy.quota_bytes.map(|quota| quota.saturating_sub(used.unwrap_or(0)));
+        let stats = ServerPacket::Stats {
+          sent: client.bytes_in.load(Ordering::Relaxed),
+          received: client.bytes_out.load(Ordering::Relaxed),
+          quota_remaining,
+          clients: self.clients.len() as u32,
+          max_clients: self.max_clients as u32,
+        };
+        (client.addr, stats)
+      })
+      .collect();
+
+    for (addr, stats) in stats {
+      if let Err(e) = self.send_packet(stats, addr).await {
+        trace!("Failed to send stats to {}: {}", addr, e);
+      }
+    }
+  }
+
+  /// Adds `bytes` to the data usage of the client's user, warning it as it nears the quota and disconnecting
+  /// it once the quota is exhausted.
+  pub async fn account(&self, addr: SocketAddr, direction: Direction, bytes: usize) -> anyhow::Result<()> {
+    let (over_quota, warning) = {
+      let Some(client) = self.clients.get(&addr) else {
+        anyhow::bail!("Unknown client {}", addr);
+      };
+      match direction {
+        Direction::Inbound => client.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed),
+        Direction::Outbound => client.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed),
+      };
+      self.metrics.record_network_data(client.network.as_deref(), direction, bytes);
+      let (Some(account), Some(username)) = (client.account(), client.username.clone()) else {
+        return Ok(());
+      };
+
+      let mut used = self.usage.entry(account.clone()).or_default();
+      *used += bytes as u64;
+
+      let warning = match client.policy.quota_warning(*used) {
+        Some(percent) if self.quota_warnings.get(&account).is_none_or(|warned| *warned < percent) => {
+          self.quota_warnings.insert(account, percent);
+          Some(AdminEvent::QuotaWarning {
+            username,
+            device: client.device.clone(),
+            network: client.network.clone(),
+            percent,
+            used_bytes: *used,
+            quota_bytes: client.policy.quota_bytes.unwrap_or_default(),
+          })
+        }
+        _ => None,
+      };
+      (client.policy.is_over_quota(*used), warning)
+    };
+
+    if let Some(AdminEvent::QuotaWarning { ref username, percent, used_bytes, quota_bytes, .. }) = warning {
+      info!("{} has used {}% of their data quota", username, percent);
+      let remaining = quota_bytes.saturating_sub(used_bytes);
+      self.send_packet(ServerPacket::Notice(Notice::QuotaWarning { percent, remaining }), addr).await?;
+    }
+    if let (Some(event), Some(webhook)) = (warning, &self.webhook) {
+      webhook.send(event);
+    }
+
+    if over_quota {
+      self
+        .send_packet(
+          ServerPacket::Disconnect { code: ErrorCode::QuotaExceeded, reason: "Data quota exceeded".into() },
+          addr,
+        )
+        .await?;
+      self.remove_client(addr).await;
+      anyhow::bail!("Client {} exceeded its data quota", addr);
+    }
+
+    Ok(())
+  }
+
+  pub async fn remove_client(&self, addr: SocketAddr) -> Option<ConnectedClient> {
+    let (_, client) = self.clients.remove(&addr)?;
+    self.sessions.remove(&client.session_id);
+    if let Some(ref cluster) = self.cluster {
+      cluster.withdraw(client.session_id).await;
+    }
+
+    if let Some(virtual_ip) = client.virtual_ip {
+      self.virtual_ips.remove(&virtual_ip);
+      if let Some(pool) = self.pool_of(client.network.as_deref()) {
+        pool.release(virtual_ip);
+      }
+      self
+        .nat
+        .release(client.username.as_deref().unwrap_or_default(), &client.policy.groups, virtual_ip)
+        .await;
+    }
+
+    if let (Some(virtual_ip), false) = (client.virtual_ip, client.reverse_forwards.is_empty()) {
+      let username = client.username.as_deref().unwrap_or_default();
+      self.withdraw_forwards(addr, username, virtual_ip, &client.reverse_forwards).await;
+    }
+
+    if !client.subnets.is_empty() {
+      self.withdraw_subnets(addr, &client.subnets).await;
+      self.advertise_routes().await;
+    }
+
+    if let Some(ref hostname) = client.hostname {
+      self.release_hostname(client.session_id, client.network.as_deref(), hostname);
+    }
+
+    self.record_accounting(AccountingKind::Stop, &client);
+    Some(client)
+  }
+
+  /// Authenticated sessions within `scope`.
+  pub fn live_clients(&self, scope: &Scope) -> Vec<LiveClient> {
+    let now = SystemTime::now();
+    self
+      .clients
+      .iter()
+      .filter(|client| scope.includes(client.network.as_deref()))
+      .filter_map(|client| {
+        let event = client.accounting_event(AccountingKind::Interim)?;
+        Some(LiveClient {
+          username: event.username.clone(),
+          session: SessionRecord::new(&event, now),
+          clock_offset_secs: client.clock_offset,
+        })
+      })
+      .collect()
+  }
+
+  /// Sessions of `username`, or of one device as `user/device`, within `scope`.
+  pub fn sessions_of(&self, username: &str, scope: &Scope) -> Vec<SocketAddr> {
+    self
+      .clients
+      .iter()
+      .filter(|client| {
+        client.username.as_deref() == Some(username) || client.account().as_deref() == Some(username)
+      })
+      .filter(|client| scope.includes(client.network.as_deref()))
+      .map(|client| client.addr)
+      .collect()
+  }
+
+  /// Disconnects the sessions of `username`, or of one device as `user/device`, within `scope` and returns
+  /// how many there were.
+  pub async fn kick(&self, username: &str, scope: &Scope) -> usize {
+    let kicked = self.sessions_of(username, scope);
+
+    for &addr in &kicked {
+      info!(target: logging::ADMIN, "Disconnecting client {} ({}): kicked by an administrator", addr, username);
+
+      if let Err(e) = self
+        .send_packet(
+          ServerPacket::Disconnect {
+            code: ErrorCode::Kicked,
+            reason: "Disconnected by an administrator".into(),
+          },
+          addr,
+        )
+        .await
       {
         error!("Failed to send disconnect packet to {}: {}", addr, e);
       }
+
+      self.remove_client(addr).await;
+    }
+
+    kicked.len()
+  }
+
+  /// Ends the longest-idle normal-priority session to make room for `src_addr`, if preemption is on and
+  /// one has been idle long enough. Returns whether there's room now.
+  pub async fn preempt_for(&self, src_addr: SocketAddr, username: &str) -> bool {
+    let Some(min_idle) = self.preemption else {
+      return false;
+    };
+
+    let victim = self
+      .clients
+      .iter()
+      .filter(|client| client.addr != src_addr && client.authenticated_at.is_some())
+      .filter(|client| client.policy.priority == Priority::Normal && client.last_active.elapsed() >= min_idle)
+      .min_by_key(|client| client.last_active.get())
+      .map(|client| (client.addr, client.username.clone().unwrap_or_default()));
+    let Some((addr, victim)) = victim else {
+      return false;
+    };
+
+    info!("Disconnecting client {} ({}): preempted by {}", addr, victim, username);
+    let reason = "Server is full; the session was ended for a higher-priority user".into();
+    if let Err(e) =
+      self.send_packet(ServerPacket::Disconnect { code: ErrorCode::Preempted, reason }, addr).await
+    {
+      error!("Failed to send disconnect packet to {}: {}", addr, e);
+    }
+    self.remove_client(addr).await;
+    true
+  }
+
+  /// Ends the session of `network` that has been silent the longest to free its address for `src_addr`, if
+  /// reclaiming is on and it has been silent long enough. Returns whether an address was freed.
+  pub async fn reclaim_address(&self, network: Option<&str>, src_addr: SocketAddr) -> bool {
+    let Some(min_idle) = self.pool_exhaustion.reclaim_idle_secs.map(Duration::from_secs) else {
+      return false;
+    };
+
+    let victim = self
+      .clients
+      .iter()
+      .filter(|client| client.addr != src_addr && client.virtual_ip.is_some())
+      .filter(|client| client.network.as_deref() == network && client.last_seen.elapsed() >= min_idle)
+      .min_by_key(|client| client.last_seen.get())
+      .map(|client| (client.addr, client.username.clone().unwrap_or_default(), client.virtual_ip));
+    let Some((addr, victim, Some(address))) = victim else {
+      return false;
+    };
+
+    info!("Reclaiming {} from client {} ({}), silent for over {:?}", address, addr, victim, min_idle);
+    self.metrics.reclaimed_leases.inc();
+    let reason = "Address reclaimed after the session went silent".into();
+    if let Err(e) =
+      self.send_packet(ServerPacket::Disconnect { code: ErrorCode::SessionLost, reason }, addr).await
+    {
+      error!("Failed to send disconnect packet to {}: {}", addr, e);
+    }
+    self.remove_client(addr).await;
+    true
+  }
+
+  /// Records that `username` was refused for want of a free address in the pool of `network`.
+  pub fn pool_exhausted(&self, username: &str, network: Option<&str>) {
+    let retry_after_secs = self.pool_exhaustion.retry_after_secs;
+    warn!("Address pool of network {} is exhausted, refusing {}", network_label(network), username);
+    self.metrics.pool_exhausted.inc();
+    if let Some(ref webhook) = self.webhook {
+      webhook.send(AdminEvent::PoolExhausted {
+        username: username.to_string(),
+        network: network.map(str::to_string),
+        retry_after_secs,
+      });
+    }
+  }
+
+  async fn disconnect_revoked(&self) {
+    let revoked: Vec<_> = self
+      .clients
+      .iter()
+      .filter(|client| client.public_key.is_some_and(|key| self.revocations.is_revoked(&key)))
+      .map(|client| client.addr)
+      .collect();
+
+    for addr in revoked {
+      info!("Disconnecting client {}: key revoked", addr);
+
+      if let Err(e) = self
+        .send_packet(
+          ServerPacket::Disconnect { code: ErrorCode::Revoked, reason: "Key revoked".into() },
+          addr,
+        )
+        .await
+      {
+        error!("Failed to send disconnect packet to {}: {}", addr, e);
+      }
+
+      self.remove_client(addr).await;
+    }
+  }
+
+  /// Replaces the credentials and keys of the default network with those of a reloaded configuration file,
+  /// disconnecting the sessions `affected` by the change, see `reload`.
+  pub async fn apply_reload(&self, config: ServerConfig, affected: &[AffectedSession]) {
+    *self.client_credentials.write().unwrap() = config.client_credentials;
+    *self.client_keys.write().unwrap() = config.client_keys;
+
+    for session in affected {
+      let addr = SessionId::from_str_radix(&session.session_id, 16)
+        .ok()
+        .and_then(|session_id| self.sessions.get(&session_id).map(|addr| *addr));
+      let Some(addr) = addr else {
+        continue;
+      };
+      info!(
+        target: logging::ADMIN,
+        "Disconnecting client {} ({}): {} by a reload",
+        addr,
+        session.username,
+        session.reason
+      );
+
+      let reason = "Credentials removed from the server".into();
+      let packet = ServerPacket::Disconnect { code: ErrorCode::InvalidCredentials, reason };
+      if let Err(e) = self.send_packet(packet, addr).await {
+        error!("Failed to send disconnect packet to {}: {}", addr, e);
+      }
+      self.remove_client(addr).await;
+    }
+  }
+
+  /// Tells every client why its session ends, before the server stops.
+  async fn disconnect_all(&self, code: ErrorCode, reason: &str) {
+    let addrs: Vec<_> = self.clients.iter().map(|client| client.addr).collect();
+    for addr in addrs {
+      let packet = ServerPacket::Disconnect { code, reason: reason.into() };
+      if let Err(e) = self.send_packet(packet, addr).await {
+        error!("Failed to send disconnect packet to {}: {}", addr, e);
+      }
+      self.remove_client(addr).await;
+    }
+  }
+
+  async fn cleanup_inactive_clients(&self) {
+    let now = SystemTime::now();
+    let clients_to_remove: Vec<_> = self
+      .clients
+      .iter()
+      .filter_map(|client| match client.expires_at {
+        _ if client.is_expired() => Some((client.addr, ErrorCode::SessionLost, "Stale connection")),
+        Some(expires_at) if expires_at <= now => {
+          Some((client.addr, ErrorCode::Expired, "Certificate expired"))
+        }
+        _ => None,
+      })
+      .collect();
+
+    for (addr, code, reason) in clients_to_remove {
+      info!("Disconnecting client {}: {}", addr, reason);
+
+      if let Err(e) = self.send_packet(ServerPacket::Disconnect { code, reason: reason.into() }, addr).await {
+        error!("Failed to send disconnect packet to {}: {}", addr, e);
+      }
+
+      self.remove_client(addr).await;
     }
   }
 }
@@ -0,0 +1,353 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdminServiceConfig {
+  /// Defaults to the gateway address of the address pool, the tun address or else its first host.
+  #[serde(default)]
+  pub address: Option<Ipv4Addr>,
+
+  #[serde(default = "default_port")]
+  pub port: u16,
+}
+
+fn default_port() -> u16 {
+  80
+}
+
+/// HTTP request to the admin service, answered by the server with `Request::respond`.
+pub struct Request {
+  pub text: String,
+  /// Tunnel address of the client that sent it.
+  pub peer: Ipv4Addr,
+  reply: oneshot::Sender<String>,
+  wake: Arc<Notify>,
+}
+
+impl Request {
+  /// Sends the whole HTTP response, after which the connection is closed.
+  pub fn respond(self, response: String) {
+    _ = self.reply.send(response);
+    self.wake.notify_one();
+  }
+}
+
+/// Serves the routes of the health endpoint at an address only clients of the tunnel reach: packets of
+/// clients to it end in a userspace stack instead of leaving the tunnel, so management needn't be exposed
+/// on a public interface.
+pub struct AdminService {
+  pub address: Ipv4Addr,
+  to_stack: mpsc::Sender<Vec<u8>>,
+  from_stack: Mutex<mpsc::Receiver<Vec<u8>>>,
+  requests: Mutex<mpsc::Receiver<Request>>,
+}
+
+impl AdminService {
+  /// Passes an IPv4 packet of a client to the service; false if it was dropped because the service is behind.
+  pub fn send(&self, packet: &[u8]) -> bool {
+    self.to_stack.try_send(packet.to_vec()).is_ok()
+  }
+
+  /// Waits for the next IPv4 packet to a client.
+  pub async fn recv(&self) -> Option<Vec<u8>> {
+    self.from_stack.lock().await.recv().await
+  }
+
+  /// Waits for the next complete request of a client.
+  pub async fn request(&self) -> Option<Request> {
+    self.requests.lock().await.recv().await
+  }
+}
+
+#[cfg(feature = "userspace-nat")]
+pub use stack::spawn;
+
+#[cfg(feature = "userspace-nat")]
+mod stack {
+  use std::collections::HashMap;
+  use std::net::Ipv4Addr;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use smoltcp::iface::Config;
+  use smoltcp::iface::Interface;
+  use smoltcp::iface::SocketHandle;
+  use smoltcp::iface::SocketSet;
+  use smoltcp::socket::tcp;
+  use smoltcp::time::Instant;
+  use smoltcp::wire::HardwareAddress;
+  use smoltcp::wire::IpAddress;
+  use smoltcp::wire::IpCidr;
+  use tokio::sync::mpsc;
+  use tokio::sync::oneshot;
+  use tokio::sync::Mutex;
+  use tokio::sync::Notify;
+  use tracing::debug;
+  use tracing::trace;
+  use vpn_shared::logging;
+
+  use super::AdminService;
+  use super::Request;
+  use crate::userspace::Queue;
+
+  const QUEUE_DEPTH: usize = 256;
+  const SOCKET_BUFFER: usize = 16 * 1024;
+  /// Requests are small; anything larger is cut off.
+  const MAX_REQUEST: usize = 8 * 1024;
+  const MAX_CONNECTIONS: usize = 16;
+  const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+  /// Starts the service on the current runtime.
+  pub fn spawn(address: Ipv4Addr, port: u16, mtu: u16) -> AdminService {
+    let (to_stack, from_clients) = mpsc::channel(QUEUE_DEPTH);
+    let (to_clients, from_stack) = mpsc::channel(QUEUE_DEPTH);
+    let (requests_tx, requests) = mpsc::channel(QUEUE_DEPTH);
+    tokio::spawn(Stack::new(address, port, mtu, to_clients, requests_tx).run(from_clients));
+    AdminService { address, to_stack, from_stack: Mutex::new(from_stack), requests: Mutex::new(requests) }
+  }
+
+  enum Connection {
+    Reading(Vec<u8>),
+    Waiting(oneshot::Receiver<String>),
+    Writing(Vec<u8>),
+  }
+
+  struct Stack {
+    port: u16,
+    iface: Interface,
+    device: Queue,
+    sockets: SocketSet<'static>,
+    connections: HashMap<SocketHandle, Connection>,
+    to_clients: mpsc::Sender<Vec<u8>>,
+    requests: mpsc::Sender<Request>,
+    wake: Arc<Notify>,
+  }
+
+  impl Stack {
+    fn new(
+      address: Ipv4Addr,
+      port: u16,
+      mtu: u16,
+      to_clients: mpsc::Sender<Vec<u8>>,
+      requests: mpsc::Sender<Request>,
+    ) -> Self {
+      let mut device = Queue::new(mtu as usize);
+      let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, Instant::now());
+      iface.update_ip_addrs(|addrs| _ = addrs.push(IpCidr::new(IpAddress::Ipv4(address), 32)));
+      _ = iface.routes_mut().add_default_ipv4_route(address);
+
+      Self {
+        port,
+        iface,
+        device,
+        sockets: SocketSet::new(Vec::new()),
+        connections: HashMap::new(),
+        to_clients,
+        requests,
+        wake: Arc::new(Notify::new()),
+      }
+    }
+
+    async fn run(mut self, mut from_clients: mpsc::Receiver<Vec<u8>>) {
+      let wake = self.wake.clone();
+      self.listen();
+      loop {
+        let delay = self
+          .iface
+          .poll_delay(Instant::now(), &self.sockets)
+          .map(Duration::from)
+          .unwrap_or(Duration::from_secs(1));
+
+        tokio::select! {
+          packet = from_clients.recv() => match packet {
+            Some(packet) => self.device.rx.push_back(packet),
+            None => break,
+          },
+          _ = wake.notified() => {}
+          _ = tokio::time::sleep(delay) => {}
+        }
+
+        self.poll();
+      }
+    }
+
+    /// Keeps a socket listening for the next connection while there's room for one.
+    fn listen(&mut self) {
+      let listening = self
+        .connections
+        .keys()
+        .any(|handle| self.sockets.get::<tcp::Socket>(*handle).state() == tcp::State::Listen);
+      if listening || self.connections.len() >= MAX_CONNECTIONS {
+        return;
+      }
+
+      let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER]),
+        tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER]),
+      );
+      socket.set_timeout(Some(IDLE_TIMEOUT.into()));
+      if socket.listen(self.port).is_err() {
+        return;
+      }
+      let handle = self.sockets.add(socket);
+      self.connections.insert(handle, Connection::Reading(Vec::new()));
+    }
+
+    fn poll(&mut self) {
+      self.iface.poll(Instant::now(), &mut self.device, &mut self.sockets);
+      self.serve();
+      self.listen();
+      self.iface.poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+      while let Some(packet) = self.device.tx.pop_front() {
+        if self.to_clients.try_send(packet).is_err() {
+          trace!(target: logging::ADMIN, "Admin service queue to clients is full; dropping packet");
+        }
+      }
+    }
+
+    fn serve(&mut self) {
+      let mut closed = Vec::new();
+
+      for (handle, connection) in self.connections.iter_mut() {
+        let socket = self.sockets.get_mut::<tcp::Socket>(*handle);
+
+        match connection {
+          Connection::Reading(request) => {
+            while socket.can_recv() {
+              _ = socket.recv(|buf| {
+                let len = buf.len().min(MAX_REQUEST - request.len());
+                request.extend_from_slice(&buf[..len]);
+                (buf.len(), ())
+              });
+            }
+            let ended = !request.is_empty() && !socket.may_recv();
+            if is_complete(request) || request.len() >= MAX_REQUEST || ended {
+              let (reply, response) = oneshot::channel();
+              let text = String::from_utf8_lossy(request).into_owned();
+              let wake = self.wake.clone();
+              let requests = self.requests.clone();
+              let peer = match socket.remote_endpoint().map(|endpoint| endpoint.addr) {
+                Some(IpAddress::Ipv4(address)) => address,
+                None => Ipv4Addr::UNSPECIFIED,
+              };
+              tokio::spawn(async move { _ = requests.send(Request { text, peer, reply, wake }).await });
+              *connection = Connection::Waiting(response);
+            }
+          }
+          Connection::Waiting(response) => match response.try_recv() {
+            Ok(response) => *connection = Connection::Writing(response.into_bytes()),
+            Err(oneshot::error::TryRecvError::Empty) => {}
+            Err(oneshot::error::TryRecvError::Closed) => socket.abort(),
+          },
+          Connection::Writing(pending) => {
+            if let Ok(sent) = socket.send_slice(pending) {
+              pending.drain(..sent);
+            }
+            if pending.is_empty() {
+              socket.close();
+            }
+          }
+        }
+
+        if socket.state() == tcp::State::Closed || socket.state() == tcp::State::TimeWait {
+          closed.push(*handle);
+        }
+      }
+
+      for handle in closed {
+        debug!(target: logging::ADMIN, "Admin service connection closed");
+        self.connections.remove(&handle);
+        self.sockets.remove(handle);
+      }
+    }
+  }
+
+  /// Whether the headers and as much of the body as `Content-Length` announces have arrived.
+  fn is_complete(request: &[u8]) -> bool {
+    let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+      return false;
+    };
+    let headers = String::from_utf8_lossy(&request[..end]);
+    let length = headers
+      .lines()
+      .filter_map(|line| line.split_once(':'))
+      .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+      .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+      .unwrap_or(0);
+    request.len() >= end + 4 + length
+  }
+}
+
+#[cfg(all(test, feature = "userspace-nat"))]
+mod tests {
+  use std::time::Duration;
+
+  use smoltcp::iface::Config;
+  use smoltcp::iface::Interface;
+  use smoltcp::iface::SocketSet;
+  use smoltcp::socket::tcp;
+  use smoltcp::time::Instant;
+  use smoltcp::wire::HardwareAddress;
+  use smoltcp::wire::IpAddress;
+  use smoltcp::wire::IpCidr;
+
+  use super::*;
+  use crate::userspace::Queue;
+
+  #[tokio::test]
+  async fn test_request() {
+    let gateway = Ipv4Addr::new(10, 8, 0, 1);
+    let service = std::sync::Arc::new(spawn(gateway, 80, 1400));
+    let answering = service.clone();
+    tokio::spawn(async move {
+      let request = answering.request().await.unwrap();
+      assert!(request.text.starts_with("GET /healthz HTTP/1.1\r\n"));
+      request.respond("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".into());
+    });
+
+    // A client of the tunnel, speaking TCP to the gateway address.
+    let mut device = Queue::new(1400);
+    let client = Ipv4Addr::new(10, 8, 0, 2);
+    let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, Instant::now());
+    iface.update_ip_addrs(|addrs| _ = addrs.push(IpCidr::new(IpAddress::Ipv4(client), 24)));
+
+    let mut sockets = SocketSet::new(Vec::new());
+    let socket =
+      tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 1024]), tcp::SocketBuffer::new(vec![0; 1024]));
+    let handle = sockets.add(socket);
+    sockets.get_mut::<tcp::Socket>(handle).connect(iface.context(), (gateway, 80), 49152).unwrap();
+
+    let mut response = Vec::new();
+    let mut sent = false;
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !response.ends_with(b"\r\n\r\n") {
+      assert!(std::time::Instant::now() < deadline, "no response from the admin service");
+      iface.poll(Instant::now(), &mut device, &mut sockets);
+      while let Some(packet) = device.tx.pop_front() {
+        assert!(service.send(&packet));
+      }
+
+      let socket = sockets.get_mut::<tcp::Socket>(handle);
+      if socket.can_send() && !sent {
+        socket.send_slice(b"GET /healthz HTTP/1.1\r\nHost: 10.8.0.1\r\n\r\n").unwrap();
+        sent = true;
+      }
+      if socket.can_recv() {
+        socket.recv(|buf| (buf.len(), response.extend_from_slice(buf))).unwrap();
+      }
+
+      if let Ok(Some(packet)) = tokio::time::timeout(Duration::from_millis(10), service.recv()).await {
+        device.rx.push_back(packet);
+      }
+    }
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+  }
+}
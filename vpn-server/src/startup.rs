@@ -0,0 +1,164 @@
+//! Readiness of the parts the server starts with: the tun device, the listeners and the credential stores
+//! are set up side by side while the server is built, and the admin APIs once it runs. The server is only
+//! reported ready on `/readyz` once all of them are, and gives up naming the ones still starting when
+//! startup takes longer than `startup-timeout-secs` in all.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::Notify;
+use tracing::debug;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Component {
+  Tun,
+  Listeners,
+  CredentialStores,
+  /// The routes of `health-address`.
+  AdminApi,
+  #[cfg(feature = "grpc")]
+  Grpc,
+}
+
+impl fmt::Display for Component {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Component::Tun => write!(f, "tun device"),
+      Component::Listeners => write!(f, "listeners"),
+      Component::CredentialStores => write!(f, "credential stores"),
+      Component::AdminApi => write!(f, "admin API"),
+      #[cfg(feature = "grpc")]
+      Component::Grpc => write!(f, "gRPC admin API"),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Readiness {
+  started: Instant,
+  timeout: Duration,
+  /// Components the server starts with, and how long each took once ready.
+  components: Mutex<BTreeMap<Component, Option<Duration>>>,
+  changed: Notify,
+}
+
+impl Readiness {
+  pub fn new(timeout: Duration) -> Self {
+    Self { started: Instant::now(), timeout, components: Mutex::default(), changed: Notify::new() }
+  }
+
+  /// Holds readiness back until `component` is ready.
+  pub fn expect(&self, component: Component) {
+    self.components.lock().unwrap().entry(component).or_insert(None);
+  }
+
+  pub fn ready(&self, component: Component) {
+    let elapsed = self.started.elapsed();
+    let mut components = self.components.lock().unwrap();
+    if components.insert(component, Some(elapsed)).flatten().is_none() {
+      debug!("The {} started {}ms into startup", component, elapsed.as_millis());
+    }
+    drop(components);
+    self.changed.notify_waiters();
+  }
+
+  /// Components not ready yet.
+  pub fn pending(&self) -> Vec<Component> {
+    let components = self.components.lock().unwrap();
+    components.iter().filter(|(_, ready)| ready.is_none()).map(|(component, _)| *component).collect()
+  }
+
+  pub fn is_ready(&self) -> bool {
+    self.pending().is_empty()
+  }
+
+  /// Starts `component` with `start`; a failure names the component.
+  pub async fn start<T>(
+    &self,
+    component: Component,
+    start: impl Future<Output = anyhow::Result<T>>,
+  ) -> anyhow::Result<T> {
+    self.expect(component);
+    let started = start.await.map_err(|e| e.context(format!("Failed to start the {}", component)))?;
+    self.ready(component);
+    Ok(started)
+  }
+
+  /// Waits for `future`, unless startup runs out of time first.
+  pub async fn within<T>(&self, future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    let deadline = tokio::time::Instant::from_std(self.started + self.timeout);
+    tokio::time::timeout_at(deadline, future).await.map_err(|_| self.blocked())?
+  }
+
+  /// Waits until every expected component is ready.
+  pub async fn wait(&self) -> anyhow::Result<()> {
+    self
+      .within(async {
+        loop {
+          // Registered before looking, so that components getting ready in between aren't missed.
+          let changed = self.changed.notified();
+          if self.is_ready() {
+            return Ok(());
+          }
+          changed.await;
+        }
+      })
+      .await
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    self.started.elapsed()
+  }
+
+  fn blocked(&self) -> anyhow::Error {
+    let pending: Vec<_> = self.pending().iter().map(Component::to_string).collect();
+    anyhow::anyhow!(
+      "Startup didn't finish within {:?}, still waiting for the {}",
+      self.timeout,
+      pending.join(", ")
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_readiness() {
+    let readiness = Readiness::new(DEFAULT_TIMEOUT);
+    assert!(readiness.is_ready());
+
+    readiness.expect(Component::AdminApi);
+    let tun = readiness.start(Component::Tun, async { Ok(1500) });
+    let (mtu, ready) = tokio::join!(tun, async {
+      readiness.ready(Component::AdminApi);
+      readiness.wait().await
+    });
+    assert_eq!(mtu.unwrap(), 1500);
+    ready.unwrap();
+
+    let failed: anyhow::Result<()> =
+      readiness.start(Component::Listeners, async { anyhow::bail!("Address in use") }).await;
+    assert_eq!(failed.unwrap_err().to_string(), "Failed to start the listeners");
+    assert_eq!(readiness.pending(), vec![Component::Listeners]);
+  }
+
+  #[tokio::test]
+  async fn test_blocked() {
+    let readiness = Readiness::new(Duration::from_millis(50));
+    readiness.ready(Component::Tun);
+    let stores = readiness.start(Component::CredentialStores, std::future::pending::<anyhow::Result<()>>());
+    let error = readiness.within(stores).await.unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Startup didn't finish within 50ms, still waiting for the credential stores"
+    );
+  }
+}
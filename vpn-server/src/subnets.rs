@@ -0,0 +1,173 @@
+//! Site-to-site: LANs behind clients, which the server routes to the client that registered them, so a
+//! client can be the gateway of a branch office rather than a single host.
+
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+
+use ipnet::Ipv4Net;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use tun::AbstractDevice;
+use vpn_shared::logging;
+use vpn_shared::packet::ServerPacket;
+
+use crate::handle_packet::PacketHandler;
+use crate::nat;
+use crate::server::Server;
+use crate::server::Tun;
+
+fn overlaps(a: &Ipv4Net, b: &Ipv4Net) -> bool {
+  a.contains(&b.network()) || b.contains(&a.network())
+}
+
+impl Server {
+  /// Client a packet to `destination` goes to: the one leased it, or else the one behind which the most
+  /// specific registered subnet containing it is.
+  pub fn route_to(&self, destination: Ipv4Addr) -> Option<SocketAddr> {
+    if let Some(addr) = self.virtual_ips.get(&destination) {
+      return Some(*addr);
+    }
+    self
+      .subnet_routes
+      .iter()
+      .filter(|route| route.key().contains(&destination))
+      .max_by_key(|route| route.key().prefix_len())
+      .map(|route| *route.value())
+  }
+
+  /// Routes the subnets of `requested` the client's policy allows and no one else has to it, in place of
+  /// the ones it registered before; returns those routed and those refused.
+  pub async fn register_subnets(
+    &self,
+    addr: SocketAddr,
+    requested: Vec<Ipv4Net>,
+  ) -> (Vec<Ipv4Net>, Vec<Ipv4Net>) {
+    let previous = match self.clients.get_mut(&addr) {
+      Some(mut client) => std::mem::take(&mut client.subnets),
+      None => return (Vec::new(), requested),
+    };
+    self.withdraw_subnets(addr, &previous).await;
+
+    let Some(policy) = self.clients.get(&addr).map(|client| client.policy.clone()) else {
+      return (Vec::new(), requested);
+    };
+    let (mut accepted, mut rejected) = (Vec::new(), Vec::new());
+    for subnet in requested.into_iter().map(|subnet| subnet.trunc()) {
+      let reason = if !policy.may_route(&subnet) {
+        Some("not allowed by policy")
+      } else if self.pools().any(|pool| overlaps(&pool, &subnet)) {
+        Some("overlaps an address pool")
+      } else if self.subnet_routes.iter().any(|route| overlaps(route.key(), &subnet))
+        || accepted.iter().any(|other| overlaps(other, &subnet))
+      {
+        Some("overlaps a subnet of another client")
+      } else {
+        None
+      };
+      match reason {
+        Some(reason) => {
+          warn!(target: logging::DATAPATH, "Refusing to route {} to client {}: {}", subnet, addr, reason);
+          rejected.push(subnet);
+        }
+        None => accepted.push(subnet),
+      }
+    }
+
+    for subnet in &accepted {
+      self.subnet_routes.insert(*subnet, addr);
+      self.route("replace", subnet).await;
+      info!(target: logging::DATAPATH, "Routing {} to client {}", subnet, addr);
+    }
+    if let Some(mut client) = self.clients.get_mut(&addr) {
+      client.subnets = accepted.clone();
+    }
+    (accepted, rejected)
+  }
+
+  /// Stops routing `subnets` to the client at `addr`, e.g. once it's gone.
+  pub async fn withdraw_subnets(&self, addr: SocketAddr, subnets: &[Ipv4Net]) {
+    for subnet in subnets {
+      if self.subnet_routes.remove_if(subnet, |_, owner| *owner == addr).is_some() {
+        self.route("del", subnet).await;
+        info!(target: logging::DATAPATH, "No longer routing {} to client {}", subnet, addr);
+      }
+    }
+  }
+
+  /// Sends every client whose view changed the routes of its user's groups and, with route exchange, the
+  /// subnets of the other sites its user may reach if it's a site itself, as a change to the ones sent
+  /// before once there are some; clients that withdrew their subnets are told to drop those.
+  pub async fn advertise_routes(&self) {
+    let changed: Vec<_> = self
+      .clients
+      .iter()
+      .filter_map(|client| {
+        let mut routes: Vec<_> = match !self.route_exchange || client.subnets.is_empty() {
+          true => Vec::new(),
+          false => self
+            .subnet_routes
+            .iter()
+            .filter(|route| *route.value() != client.addr && client.policy.may_reach(route.key()))
+            .map(|route| *route.key())
+            .collect(),
+        };
+        routes.extend(&client.policy.routes);
+        routes.sort();
+        routes.dedup();
+        if routes.len() > client.limits.max_routes as usize {
+          warn!(
+            "Only advertising {} of {} routes to client {}",
+            client.limits.max_routes,
+            routes.len(),
+            client.addr
+          );
+          routes.truncate(client.limits.max_routes as usize);
+        }
+        (routes != client.routes).then_some((client.addr, routes))
+      })
+      .collect();
+
+    for (addr, routes) in changed {
+      let packet = {
+        let Some(mut client) = self.clients.get_mut(&addr) else {
+          continue;
+        };
+        client.routes_revision += 1;
+        let revision = client.routes_revision;
+        let previous = std::mem::replace(&mut client.routes, routes.clone());
+        debug!(target: logging::DATAPATH, "Advertising {:?} to client {} at revision {}", routes, addr, revision);
+        match revision {
+          1 => ServerPacket::Routes { revision, routes },
+          _ => ServerPacket::RouteUpdate {
+            revision,
+            added: routes.iter().filter(|route| !previous.contains(route)).copied().collect(),
+            removed: previous.into_iter().filter(|route| !routes.contains(route)).collect(),
+          },
+        }
+      };
+      if let Err(e) = self.send_packet(packet, addr).await {
+        warn!(target: logging::DATAPATH, "Failed to advertise routes to client {}: {}", addr, e);
+      }
+    }
+  }
+
+  fn pools(&self) -> impl Iterator<Item = Ipv4Net> + '_ {
+    self.subnet.into_iter().chain(self.networks.iter().map(|network| network.pool.subnet()))
+  }
+
+  /// Adds or deletes the return route into the tun device, through which the system sends traffic for the
+  /// subnet to the server.
+  async fn route(&self, action: &str, subnet: &Ipv4Net) {
+    let Some(Tun::Device { ref device, .. }) = self.tun else {
+      return;
+    };
+    let result = match device.tun_name() {
+      Ok(name) => nat::run("ip", &["route", action, &subnet.to_string(), "dev", &name]).await,
+      Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+      warn!(target: logging::TUN, "Failed to {} the route to {}: {}", action, subnet, e);
+    }
+  }
+}
@@ -0,0 +1,187 @@
+//! The session table: every connected session with its user, addresses, traffic, last key exchange and
+//! negotiated features, for scripts. Pages of it are served as JSON, or OpenMetrics for collectors that
+//! would rather scrape it, ordered by session id so that paging through it doesn't skip or repeat sessions
+//! that stay connected meanwhile.
+
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+use crate::health::Scope;
+use crate::server::Server;
+
+/// Bumped when a field of `SessionTable` or `SessionRow` is renamed, removed or changes meaning. Fields are
+/// only added otherwise, so scripts written against a version keep working as long as it stays the same.
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub const DEFAULT_LIMIT: usize = 500;
+pub const MAX_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct SessionRow {
+  pub session_id: String,
+  /// User, also of sessions tied to a device.
+  pub username: String,
+  pub device: Option<String>,
+  /// Tenant network, `None` for the default one.
+  pub network: Option<String>,
+  pub virtual_ip: Option<Ipv4Addr>,
+  /// Address the client sends from.
+  pub endpoint: String,
+  /// Tunneled bytes received from the client and sent to it.
+  pub rx_bytes: u64,
+  pub tx_bytes: u64,
+  /// Seconds since the Unix epoch of the key exchange, and of the last key exchange or rekey.
+  pub connected_at: u64,
+  pub last_handshake: u64,
+  /// Seconds since the last packet from the client, pings included.
+  pub idle_secs: u64,
+  /// Protocol features negotiated in the key exchange, which tell client versions apart; clients don't
+  /// send their version otherwise.
+  pub features: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct SessionTable {
+  pub schema_version: u32,
+  /// Sessions in the table, on all pages.
+  pub total: usize,
+  pub offset: usize,
+  /// Offset of the next page; `None` on the last one.
+  pub next_offset: Option<usize>,
+  pub sessions: Vec<SessionRow>,
+}
+
+impl SessionTable {
+  /// Page of `rows` starting at `offset`, with at most `limit` of them.
+  pub fn page(mut rows: Vec<SessionRow>, offset: usize, limit: usize) -> Self {
+    rows.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+    let total = rows.len();
+    let sessions: Vec<_> = rows.into_iter().skip(offset).take(limit).collect();
+    let next_offset = Some(offset + sessions.len()).filter(|next| *next < total);
+    Self { schema_version: SCHEMA_VERSION, total, offset, next_offset, sessions }
+  }
+
+  /// The page in the OpenMetrics text format: a sample per session and figure, labeled with the session.
+  pub fn openmetrics(&self) -> String {
+    let mut text = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, value: fn(&SessionRow) -> u64| {
+      _ = writeln!(text, "# TYPE {} {}\n# HELP {} {}", name, kind, name, help);
+      let suffix = if kind == "counter" { "_total" } else { "" };
+      for row in &self.sessions {
+        _ = writeln!(text, "{}{}{{{}}} {}", name, suffix, labels(row), value(row));
+      }
+    };
+    family("vpn_session_rx_bytes", "counter", "Tunneled bytes received from the client", |row| row.rx_bytes);
+    family("vpn_session_tx_bytes", "counter", "Tunneled bytes sent to the client", |row| row.tx_bytes);
+    family("vpn_session_last_handshake_seconds", "gauge", "Time of the last key exchange or rekey", |row| {
+      row.last_handshake
+    });
+    family("vpn_session_idle_seconds", "gauge", "Time since the last packet from the client", |row| {
+      row.idle_secs
+    });
+    text + "# EOF\n"
+  }
+}
+
+fn labels(row: &SessionRow) -> String {
+  let virtual_ip = row.virtual_ip.map(|address| address.to_string());
+  [
+    ("session_id", Some(row.session_id.as_str())),
+    ("username", Some(row.username.as_str())),
+    ("device", row.device.as_deref()),
+    ("network", row.network.as_deref()),
+    ("virtual_ip", virtual_ip.as_deref()),
+    ("endpoint", Some(row.endpoint.as_str())),
+  ]
+  .into_iter()
+  .filter_map(|(label, value)| Some(format!("{}=\"{}\"", label, escape(value?))))
+  .collect::<Vec<_>>()
+  .join(",")
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Server {
+  /// Sessions within `scope` of authenticated users.
+  pub fn session_rows(&self, scope: &Scope) -> Vec<SessionRow> {
+    let now = SystemTime::now();
+    let unix = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    self
+      .clients
+      .iter()
+      .filter(|client| scope.includes(client.network.as_deref()))
+      .filter_map(|client| {
+        let connected = client.authenticated_at?.elapsed();
+        Some(SessionRow {
+          session_id: format!("{:016x}", client.session_id),
+          username: client.username.clone()?,
+          device: client.device.clone(),
+          network: client.network.clone(),
+          virtual_ip: client.virtual_ip,
+          endpoint: client.addr.to_string(),
+          rx_bytes: client.bytes_in.load(Ordering::Relaxed),
+          tx_bytes: client.bytes_out.load(Ordering::Relaxed),
+          connected_at: unix(now.checked_sub(connected).unwrap_or(now)),
+          last_handshake: unix(client.keyed_at),
+          idle_secs: client.last_seen.elapsed().as_secs(),
+          features: client.features.names(),
+        })
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn row(session_id: &str, username: &str) -> SessionRow {
+    SessionRow {
+      session_id: session_id.to_string(),
+      username: username.to_string(),
+      device: None,
+      network: None,
+      virtual_ip: Some(Ipv4Addr::new(10, 0, 0, 2)),
+      endpoint: "198.51.100.1:40000".to_string(),
+      rx_bytes: 100,
+      tx_bytes: 200,
+      connected_at: 1_700_000_000,
+      last_handshake: 1_700_000_600,
+      idle_secs: 3,
+      features: vec!["roaming"],
+    }
+  }
+
+  #[test]
+  fn test_pages() {
+    let rows: Vec<_> = ["03", "01", "02"].into_iter().map(|id| row(id, "alice")).collect();
+
+    let first = SessionTable::page(rows.clone(), 0, 2);
+    let ids: Vec<_> = first.sessions.iter().map(|row| row.session_id.as_str()).collect();
+    assert_eq!((first.total, first.next_offset, ids), (3, Some(2), vec!["01", "02"]));
+
+    let last = SessionTable::page(rows.clone(), 2, 2);
+    assert_eq!((last.sessions.len(), last.next_offset), (1, None));
+    assert!(SessionTable::page(rows, 5, 2).sessions.is_empty());
+  }
+
+  #[test]
+  fn test_openmetrics() {
+    let table = SessionTable::page(vec![row("01", "al\"ice")], 0, DEFAULT_LIMIT);
+    let text = table.openmetrics();
+    assert!(text.contains("# TYPE vpn_session_rx_bytes counter\n"), "{}", text);
+    assert!(text.contains(
+      "vpn_session_rx_bytes_total{session_id=\"01\",username=\"al\\\"ice\",virtual_ip=\"10.0.0.2\",\
+       endpoint=\"198.51.100.1:40000\"} 100\n"
+    ));
+    assert!(text.contains("vpn_session_last_handshake_seconds{session_id=\"01\","), "{}", text);
+    assert!(text.ends_with("# EOF\n"));
+  }
+}
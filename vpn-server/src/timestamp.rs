@@ -0,0 +1,69 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Instant every `Timestamp` counts from.
+fn epoch() -> Instant {
+  static EPOCH: OnceLock<Instant> = OnceLock::new();
+  *EPOCH.get_or_init(Instant::now)
+}
+
+/// Coarse `Instant`, in milliseconds, that can be updated through a shared reference, so that packets of a
+/// session only need to read its entry in the session table, which the other packets of its shard can do at
+/// the same time, instead of locking the shard to note when the session was last heard from. Within a
+/// millisecond `touch` only reads, so a busy session doesn't keep writing to memory every core reads.
+#[derive(Debug)]
+pub struct Timestamp(AtomicU64);
+
+impl Timestamp {
+  pub fn now() -> Self {
+    Self(AtomicU64::new(Self::since_epoch(Instant::now())))
+  }
+
+  fn since_epoch(instant: Instant) -> u64 {
+    instant.saturating_duration_since(epoch()).as_millis() as u64
+  }
+
+  pub fn get(&self) -> Instant {
+    epoch() + Duration::from_millis(self.0.load(Ordering::Relaxed))
+  }
+
+  pub fn touch(&self) {
+    let now = Self::since_epoch(Instant::now());
+    if self.0.load(Ordering::Relaxed) < now {
+      self.0.fetch_max(now, Ordering::Relaxed);
+    }
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    self.get().elapsed()
+  }
+}
+
+impl Default for Timestamp {
+  fn default() -> Self {
+    Self::now()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_timestamp() {
+    let timestamp = Timestamp::now();
+    let earlier = timestamp.get();
+    assert!(earlier <= Instant::now());
+
+    let later = Instant::now() + Duration::from_secs(5);
+    let timestamp = Timestamp(AtomicU64::new(Timestamp::since_epoch(later)));
+    assert!(later - timestamp.get() < Duration::from_millis(1));
+
+    // Never moves back, e.g. when packets of a session are handled out of order on two workers.
+    timestamp.touch();
+    assert!(timestamp.get() > earlier + Duration::from_secs(4));
+  }
+}
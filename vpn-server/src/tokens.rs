@@ -0,0 +1,256 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use hkdf::Hkdf;
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use vpn_shared::creds::Credentials;
+use vpn_shared::handshake;
+use vpn_shared::packet::EncryptedPacket;
+use vpn_shared::packet::Key;
+use vpn_shared::packet::SessionId;
+use vpn_shared::packet::HANDSHAKE_SESSION;
+
+use crate::auth::AuthFuture;
+use crate::auth::CredentialStore;
+use crate::auth::Identity;
+
+const TOKEN_INFO: &[u8] = b"sberlinux-vpn token v1";
+const TICKET_INFO: &[u8] = b"sberlinux-vpn ticket v1";
+
+/// How long after expiring tokens and tickets are still taken by default, see `TokenIssuer::with_skew`.
+pub const DEFAULT_SKEW: Duration = Duration::from_secs(60);
+
+fn derive_key(private_key: &Key, info: &[u8]) -> Key {
+  let mut key = [0u8; 32];
+  Hkdf::<Sha256>::new(None, private_key).expand(info, &mut key).expect("32 bytes is a valid length");
+  key
+}
+
+/// Issues and checks `<username>.<expiry>.<mac>` tokens, signed with a key derived from the server's private
+/// key so they can be verified without any state; rotating the private key invalidates all of them.
+pub struct TokenIssuer {
+  key: Key,
+  skew: Duration,
+}
+
+impl TokenIssuer {
+  pub fn new(private_key: &Key) -> Self {
+    Self { key: derive_key(private_key, TOKEN_INFO), skew: Duration::ZERO }
+  }
+
+  /// Keeps taking tokens for `skew` after they expire, for servers whose clock is ahead of the one of the
+  /// host that issued them.
+  pub fn with_skew(mut self, skew: Duration) -> Self {
+    self.skew = skew;
+    self
+  }
+
+  pub fn issue(&self, username: &str, ttl: Duration) -> anyhow::Result<String> {
+    let Some(expiry) = SystemTime::now().checked_add(ttl) else {
+      anyhow::bail!("Token lifetime of {:?} is out of range", ttl);
+    };
+    let expires_at = expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let payload = format!("{}.{}", username, expires_at);
+    Ok(format!("{}.{}", payload, handshake::encode_key(&self.sign(&payload))))
+  }
+
+  /// Returns the username of a valid, unexpired token.
+  pub fn verify(&self, token: &str) -> Option<String> {
+    let (payload, mac) = token.rsplit_once('.')?;
+    let (username, expires_at) = payload.rsplit_once('.')?;
+
+    if !handshake::keys_match(&handshake::parse_key(mac).ok()?, &self.sign(payload)) {
+      return None;
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let expires_at = expires_at.parse::<u64>().ok()?.saturating_add(self.skew.as_secs());
+    (expires_at > now).then(|| username.to_string())
+  }
+
+  fn sign(&self, payload: &str) -> Key {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac.finalize().into_bytes().into()
+  }
+}
+
+impl CredentialStore for TokenIssuer {
+  fn name(&self) -> &str {
+    "issued tokens"
+  }
+
+  fn authenticate<'a>(&'a self, credentials: &'a Credentials) -> AuthFuture<'a> {
+    let identity = match credentials {
+      Credentials::Token(token) => {
+        self.verify(token).map(|username| Identity { username, groups: Vec::new() })
+      }
+      _ => None,
+    };
+    Box::pin(async { Ok(identity) })
+  }
+}
+
+/// What a session was authenticated as, handed to its client sealed so that it can resume the session after
+/// restarting without the server keeping any state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ticket {
+  /// Session the ticket was issued to, which a resumption replaces if it's still around.
+  pub session_id: SessionId,
+  pub username: String,
+  pub groups: Vec<String>,
+  pub network: Option<String>,
+  /// Key or certificate key the session authenticated with, checked against revocations on resumption.
+  pub public_key: Option<Key>,
+  /// Address the session leased, handed out again if it's free.
+  pub address: Option<Ipv4Addr>,
+  /// Seconds since the Unix epoch; never after the certificate the session authenticated with expires.
+  pub expires_at: u64,
+  /// Milliseconds since the Unix epoch; tickets issued before the user changed their password are refused.
+  pub issued_at: u64,
+}
+
+/// Seals and opens tickets with a key derived from the server's private key, like `TokenIssuer`.
+pub struct TicketIssuer {
+  key: Key,
+  lifetime: Duration,
+  skew: Duration,
+}
+
+impl TicketIssuer {
+  pub fn new(private_key: &Key, lifetime: Duration) -> Self {
+    Self { key: derive_key(private_key, TICKET_INFO), lifetime, skew: Duration::ZERO }
+  }
+
+  /// Keeps taking tickets for `skew` after they expire, for cluster nodes whose clock is ahead of the one
+  /// of the node that issued them.
+  pub fn with_skew(mut self, skew: Duration) -> Self {
+    self.skew = skew;
+    self
+  }
+
+  /// When a ticket issued now expires, or the certificate of the session if that's sooner.
+  pub fn expiry(&self, certificate_expiry: Option<SystemTime>) -> anyhow::Result<u64> {
+    let Some(expiry) = SystemTime::now().checked_add(self.lifetime) else {
+      anyhow::bail!("Ticket lifetime of {:?} is out of range", self.lifetime);
+    };
+    let expiry = certificate_expiry.map_or(expiry, |certificate| expiry.min(certificate));
+    Ok(expiry.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+  }
+
+  pub fn seal(&self, ticket: &Ticket) -> anyhow::Result<Vec<u8>> {
+    Ok(EncryptedPacket::encrypt(&self.key, HANDSHAKE_SESSION, ticket)?.to_bytes())
+  }
+
+  /// Returns the ticket if it was sealed by this server and hasn't expired by `now`, give or take the skew.
+  pub fn open(&self, sealed: &[u8], now: SystemTime) -> Option<Ticket> {
+    let ticket: Ticket = EncryptedPacket::from_bytes(sealed).ok()?.decrypt(&self.key).ok()?;
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    (ticket.expires_at.saturating_add(self.skew.as_secs()) > now).then_some(ticket)
+  }
+}
+
+/// Parses durations such as `90s`, `30m`, `24h` or `7d`.
+pub fn parse_ttl(s: &str) -> anyhow::Result<Duration> {
+  let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+  let value: u64 = value.parse().map_err(|_| anyhow::anyhow!("Invalid duration: {}", s))?;
+  let seconds = match unit {
+    "s" | "" => 1,
+    "m" => 60,
+    "h" => 60 * 60,
+    "d" => 24 * 60 * 60,
+    _ => anyhow::bail!("Invalid duration unit in {}; expected s, m, h or d", s),
+  };
+  let Some(secs) = value.checked_mul(seconds) else {
+    anyhow::bail!("Duration is too long: {}", s);
+  };
+  Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_issue_and_verify() {
+    let issuer = TokenIssuer::new(&[1u8; 32]);
+    let token = issuer.issue("ci.runner", Duration::from_secs(60)).unwrap();
+
+    assert_eq!(issuer.verify(&token).as_deref(), Some("ci.runner"));
+    assert!(TokenIssuer::new(&[2u8; 32]).verify(&token).is_none());
+    assert!(issuer.verify(&token.replacen("ci.runner", "admin", 1)).is_none());
+    assert!(issuer.verify(&issuer.issue("alice", Duration::ZERO).unwrap()).is_none());
+    assert!(issuer.issue("alice", Duration::MAX).is_err());
+    assert!(issuer.verify("garbage").is_none());
+  }
+
+  #[test]
+  fn test_skew() {
+    let issuer = TokenIssuer::new(&[1u8; 32]).with_skew(DEFAULT_SKEW);
+    assert_eq!(issuer.verify(&issuer.issue("alice", Duration::ZERO).unwrap()).as_deref(), Some("alice"));
+    let expired = format!("alice.{}", handshake::unix_time() - DEFAULT_SKEW.as_secs());
+    let expired = format!("{}.{}", expired, handshake::encode_key(&issuer.sign(&expired)));
+    assert!(issuer.verify(&expired).is_none());
+
+    let tickets = TicketIssuer::new(&[1u8; 32], Duration::from_secs(3600)).with_skew(DEFAULT_SKEW);
+    let ticket = Ticket {
+      session_id: 42,
+      username: "alice".into(),
+      groups: Vec::new(),
+      network: None,
+      public_key: None,
+      address: None,
+      expires_at: tickets.expiry(None).unwrap(),
+      issued_at: 0,
+    };
+    let sealed = tickets.seal(&ticket).unwrap();
+    let now = SystemTime::now();
+    assert!(tickets.open(&sealed, now + Duration::from_secs(3630)).is_some());
+    assert!(tickets.open(&sealed, now + Duration::from_secs(3661)).is_none());
+  }
+
+  #[test]
+  fn test_tickets() {
+    let issuer = TicketIssuer::new(&[1u8; 32], Duration::from_secs(3600));
+    let now = SystemTime::now();
+    let ticket = Ticket {
+      session_id: 42,
+      username: "alice".into(),
+      groups: vec!["staff".into()],
+      network: None,
+      public_key: None,
+      address: Some(Ipv4Addr::new(10, 8, 0, 2)),
+      expires_at: issuer.expiry(None).unwrap(),
+      issued_at: 0,
+    };
+    let sealed = issuer.seal(&ticket).unwrap();
+
+    assert_eq!(issuer.open(&sealed, now), Some(ticket.clone()));
+    assert!(issuer.open(&sealed, now + Duration::from_secs(3601)).is_none());
+    assert!(TicketIssuer::new(&[2u8; 32], Duration::from_secs(3600)).open(&sealed, now).is_none());
+    assert!(issuer.open(&sealed[1..], now).is_none());
+
+    // Tokens and tickets use different keys, so neither passes for the other.
+    assert_ne!(issuer.key, TokenIssuer::new(&[1u8; 32]).key);
+
+    let certificate_expiry = now + Duration::from_secs(60);
+    let expiry = issuer.expiry(Some(certificate_expiry)).unwrap();
+    assert_eq!(expiry, certificate_expiry.duration_since(UNIX_EPOCH).unwrap().as_secs());
+    assert!(TicketIssuer::new(&[1u8; 32], Duration::MAX).expiry(None).is_err());
+  }
+
+  #[test]
+  fn test_parse_ttl() {
+    assert_eq!(parse_ttl("24h").unwrap(), Duration::from_secs(86400));
+    assert_eq!(parse_ttl("90").unwrap(), Duration::from_secs(90));
+    assert!(parse_ttl("1w").is_err());
+    assert!(parse_ttl("h").is_err());
+    assert!(parse_ttl("999999999999999999d").is_err());
+  }
+}
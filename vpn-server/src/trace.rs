@@ -0,0 +1,322 @@
+//! Tracing of every packet of one session for a while, to debug a single client without raising the log
+//! level for all of them. Entries go to a ring buffer per session, which the admin API reads; traces are
+//! kept after they finish until they're replaced or deleted.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+use vpn_shared::packet::ClientPacket;
+use vpn_shared::packet::ServerPacket;
+use vpn_shared::packet::SessionId;
+
+use crate::server::Server;
+
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+pub const MAX_DURATION: Duration = Duration::from_secs(600);
+/// Entries kept of each trace; the oldest ones are overwritten.
+pub const CAPACITY: usize = 1024;
+/// Traces kept at once, finished ones included.
+pub const MAX_TRACES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flow {
+  Received,
+  Sent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceEntry {
+  /// Counts the packets of the trace, overwritten ones included.
+  pub seq: u64,
+  /// Since the trace started.
+  pub at_ms: u64,
+  pub flow: Flow,
+  /// Kind of packet, e.g. `data` or `ping`.
+  pub kind: &'static str,
+  /// Bytes of the datagram, or of the IP packet for data.
+  pub size: usize,
+  /// What the server did with it.
+  pub decision: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceSnapshot {
+  pub session_id: String,
+  /// Seconds since the Unix epoch.
+  pub started_at: u64,
+  pub recording: bool,
+  pub remaining_secs: u64,
+  /// Entries lost to the ring buffer being full.
+  pub overwritten: u64,
+  pub entries: Vec<TraceEntry>,
+}
+
+struct Trace {
+  /// Tenant network of the session, for admin tokens limited to one.
+  network: Option<String>,
+  started: Instant,
+  started_at: SystemTime,
+  until: Instant,
+  recording: bool,
+  next_seq: u64,
+  entries: VecDeque<TraceEntry>,
+}
+
+#[derive(Default)]
+pub struct Traces {
+  /// Traces still recording, checked first so that packets of untraced sessions cost a single load.
+  recording: AtomicUsize,
+  traces: Mutex<HashMap<SessionId, Trace>>,
+}
+
+impl Traces {
+  pub fn is_recording(&self) -> bool {
+    self.recording.load(Ordering::Relaxed) > 0
+  }
+
+  /// Starts tracing `session_id` for `duration`, replacing an earlier trace of it; the oldest finished trace
+  /// makes room if there are too many.
+  pub fn start(
+    &self,
+    session_id: SessionId,
+    network: Option<String>,
+    duration: Duration,
+  ) -> anyhow::Result<()> {
+    let mut traces = self.traces.lock().unwrap();
+    if let Some(trace) = traces.remove(&session_id) {
+      self.finish(trace);
+    } else if traces.len() >= MAX_TRACES {
+      let oldest = traces.iter().filter(|(_, trace)| !trace.recording).min_by_key(|(_, trace)| trace.started);
+      let Some((&oldest, _)) = oldest else {
+        anyhow::bail!("{} sessions are already being traced", MAX_TRACES);
+      };
+      traces.remove(&oldest);
+    }
+
+    let now = Instant::now();
+    traces.insert(
+      session_id,
+      Trace {
+        network,
+        started: now,
+        started_at: SystemTime::now(),
+        until: now + duration.min(MAX_DURATION),
+        recording: true,
+        next_seq: 0,
+        entries: VecDeque::new(),
+      },
+    );
+    self.recording.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Drops the trace of `session_id`; returns whether there was one.
+  pub fn delete(&self, session_id: SessionId) -> bool {
+    let trace = self.traces.lock().unwrap().remove(&session_id);
+    trace.map(|trace| self.finish(trace)).is_some()
+  }
+
+  fn finish(&self, trace: Trace) {
+    if trace.recording {
+      self.recording.fetch_sub(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn network(&self, session_id: SessionId) -> Option<Option<String>> {
+    self.traces.lock().unwrap().get(&session_id).map(|trace| trace.network.clone())
+  }
+
+  pub fn snapshot(&self, session_id: SessionId) -> Option<TraceSnapshot> {
+    self.expire();
+    let traces = self.traces.lock().unwrap();
+    let trace = traces.get(&session_id)?;
+    Some(TraceSnapshot {
+      session_id: format!("{:016x}", session_id),
+      started_at: trace.started_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+      recording: trace.recording,
+      remaining_secs: trace.until.saturating_duration_since(Instant::now()).as_secs(),
+      overwritten: trace.next_seq - trace.entries.len() as u64,
+      entries: trace.entries.iter().cloned().collect(),
+    })
+  }
+
+  /// Stops the traces whose time is up.
+  pub fn expire(&self) {
+    let now = Instant::now();
+    for trace in self.traces.lock().unwrap().values_mut() {
+      if trace.recording && now >= trace.until {
+        trace.recording = false;
+        self.recording.fetch_sub(1, Ordering::Relaxed);
+      }
+    }
+  }
+
+  /// Records a packet of `session_id` if it's being traced; `decision` is only made then.
+  pub fn record(
+    &self,
+    session_id: SessionId,
+    flow: Flow,
+    kind: &'static str,
+    size: usize,
+    decision: impl FnOnce() -> String,
+  ) {
+    if !self.is_recording() {
+      return;
+    }
+    let now = Instant::now();
+    let mut traces = self.traces.lock().unwrap();
+    let Some(trace) = traces.get_mut(&session_id).filter(|trace| trace.recording) else {
+      return;
+    };
+    if now >= trace.until {
+      trace.recording = false;
+      self.recording.fetch_sub(1, Ordering::Relaxed);
+      return;
+    }
+
+    if trace.entries.len() >= CAPACITY {
+      trace.entries.pop_front();
+    }
+    let at_ms = now.duration_since(trace.started).as_millis() as u64;
+    trace.entries.push_back(TraceEntry {
+      seq: trace.next_seq,
+      at_ms,
+      flow,
+      kind,
+      size,
+      decision: decision(),
+    });
+    trace.next_seq += 1;
+  }
+}
+
+pub fn client_kind(packet: &ClientPacket) -> &'static str {
+  match packet {
+    ClientPacket::Auth(_) => "auth",
+    ClientPacket::KeyExchange { .. } => "key-exchange",
+    ClientPacket::Data(_) => "data",
+    ClientPacket::Ping => "ping",
+    ClientPacket::Disconnect => "disconnect",
+    ClientPacket::KeyAuth { .. } => "key-auth",
+    ClientPacket::PathResponse(_) => "path-response",
+    ClientPacket::Fragment(_) => "fragment",
+    ClientPacket::Renegotiate(_) => "renegotiate",
+    ClientPacket::Probe(_) => "probe",
+    ClientPacket::RegisterSubnets(_) => "register-subnets",
+    ClientPacket::RequestRoutes => "request-routes",
+    ClientPacket::ChangePassword { .. } => "change-password",
+    ClientPacket::RegisterForwards(_) => "register-forwards",
+    ClientPacket::Rehandshake => "rehandshake",
+    ClientPacket::Rekey { .. } => "rekey",
+    ClientPacket::PeerKey { .. } => "peer-key",
+    ClientPacket::PeerData { .. } => "peer-data",
+    ClientPacket::RegisterHostname(_) => "register-hostname",
+    ClientPacket::Suspended(_) => "suspended",
+    _ => "other",
+  }
+}
+
+pub fn server_kind(packet: &ServerPacket) -> &'static str {
+  match packet {
+    ServerPacket::AuthOk => "auth-ok",
+    ServerPacket::AuthError { .. } => "auth-error",
+    ServerPacket::KeyExchange { .. } => "key-exchange",
+    ServerPacket::Data(_) => "data",
+    ServerPacket::Error(_) => "error",
+    ServerPacket::Pong => "pong",
+    ServerPacket::Disconnect { .. } => "disconnect",
+    ServerPacket::PathChallenge(_) => "path-challenge",
+    ServerPacket::NetworkConfig { .. } => "network-config",
+    ServerPacket::Stats { .. } => "stats",
+    ServerPacket::Notice(_) => "notice",
+    ServerPacket::Renegotiated(_) => "renegotiated",
+    ServerPacket::Deferred { .. } => "deferred",
+    ServerPacket::Ticket { .. } => "ticket",
+    ServerPacket::Probe(_) => "probe",
+    ServerPacket::Subnets { .. } => "subnets",
+    ServerPacket::Routes { .. } => "routes",
+    ServerPacket::RouteUpdate { .. } => "route-update",
+    ServerPacket::PasswordChanged { .. } => "password-changed",
+    ServerPacket::PoolExhausted { .. } => "pool-exhausted",
+    ServerPacket::Forwards { .. } => "forwards",
+    ServerPacket::Rekey { .. } => "rekey",
+    ServerPacket::PeerKey { .. } => "peer-key",
+    ServerPacket::PeerData { .. } => "peer-data",
+    ServerPacket::PeerUnavailable { .. } => "peer-unavailable",
+    ServerPacket::Hostname { .. } => "hostname",
+    _ => "other",
+  }
+}
+
+impl Server {
+  /// Records a packet of the session at `addr` if it's being traced, see `Traces::record`.
+  pub fn trace(
+    &self,
+    addr: SocketAddr,
+    flow: Flow,
+    kind: &'static str,
+    size: usize,
+    decision: impl FnOnce() -> String,
+  ) {
+    if !self.traces.is_recording() {
+      return;
+    }
+    let Some(session_id) = self.clients.get(&addr).map(|client| client.session_id) else {
+      return;
+    };
+    self.traces.record(session_id, flow, kind, size, decision);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ring_buffer() {
+    let traces = Traces::default();
+    traces.record(1, Flow::Received, "data", 100, || unreachable!("Nothing is traced"));
+    traces.start(1, None, DEFAULT_DURATION).unwrap();
+    assert!(traces.is_recording());
+
+    for size in 0..CAPACITY + 10 {
+      traces.record(1, Flow::Received, "data", size, || "forwarded".to_string());
+    }
+    traces.record(2, Flow::Sent, "pong", 1, || unreachable!("Session 2 isn't traced"));
+
+    let snapshot = traces.snapshot(1).unwrap();
+    assert_eq!((snapshot.entries.len(), snapshot.overwritten), (CAPACITY, 10));
+    assert_eq!((snapshot.entries[0].seq, snapshot.entries[0].size), (10, 10));
+    assert_eq!(snapshot.session_id, "0000000000000001");
+
+    assert!(traces.delete(1));
+    assert!(!traces.is_recording());
+    assert!(traces.snapshot(1).is_none());
+  }
+
+  #[test]
+  fn test_expiry() {
+    let traces = Traces::default();
+    traces.start(1, None, Duration::ZERO).unwrap();
+    traces.record(1, Flow::Received, "ping", 40, || unreachable!("The trace is over"));
+    assert!(!traces.is_recording());
+    // Finished traces stay readable, and make room for new ones once there are too many.
+    assert!(!traces.snapshot(1).unwrap().recording);
+    for session_id in 2..=MAX_TRACES as u64 {
+      traces.start(session_id, None, DEFAULT_DURATION).unwrap();
+    }
+    traces.start(100, None, DEFAULT_DURATION).unwrap();
+    assert!(traces.snapshot(1).is_none());
+    assert!(traces.start(101, None, DEFAULT_DURATION).is_err());
+  }
+}
@@ -0,0 +1,750 @@
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct UserspaceNatConfig {
+  /// Address of the stack; clients route through the server as usual and never see it.
+  #[serde(default = "default_address")]
+  pub address: Ipv4Addr,
+
+  #[serde(default = "default_mtu")]
+  pub mtu: u16,
+
+  /// UDP flows without packets in either direction for this long are closed.
+  #[serde(default = "default_udp_timeout_secs")]
+  pub udp_timeout_secs: u64,
+
+  /// Connections and UDP flows open at once, over all clients; new ones are dropped beyond it.
+  #[serde(default = "default_max_flows")]
+  pub max_flows: usize,
+
+  /// Destinations clients may reach even though they're refused by default, see `may_reach`; e.g.
+  /// `127.0.0.1/32` for a service on the server itself.
+  #[serde(default)]
+  pub allowed_destinations: Vec<Ipv4Net>,
+}
+
+impl Default for UserspaceNatConfig {
+  fn default() -> Self {
+    Self {
+      address: default_address(),
+      mtu: default_mtu(),
+      udp_timeout_secs: default_udp_timeout_secs(),
+      max_flows: default_max_flows(),
+      allowed_destinations: Vec::new(),
+    }
+  }
+}
+
+impl UserspaceNatConfig {
+  /// Whether clients may reach `ip` through the stack. Addresses of the server itself, loopback and
+  /// link-local ones such as cloud metadata services, and ones that aren't a single host are refused unless
+  /// `allowed-destinations` lists them.
+  #[cfg_attr(not(feature = "userspace-nat"), allow(dead_code))]
+  pub fn may_reach(&self, ip: Ipv4Addr) -> bool {
+    if self.allowed_destinations.iter().any(|net| net.contains(&ip)) {
+      return true;
+    }
+    let special = ip.is_loopback()
+      || ip.is_link_local()
+      || ip.octets()[0] == 0
+      || ip.is_broadcast()
+      || ip.is_multicast()
+      || ip == self.address;
+    // Only addresses of the server's own interfaces can be bound to.
+    !special && std::net::UdpSocket::bind((ip, 0)).is_err()
+  }
+}
+
+fn default_address() -> Ipv4Addr {
+  Ipv4Addr::new(169, 254, 0, 1)
+}
+
+fn default_mtu() -> u16 {
+  1400
+}
+
+fn default_udp_timeout_secs() -> u64 {
+  60
+}
+
+fn default_max_flows() -> usize {
+  4096
+}
+
+/// Stands in for the tun device without needing privileges: TCP connections of clients end in a userspace
+/// stack and UDP datagrams are relayed, each through an ordinary socket of the server. Other protocols are
+/// dropped.
+pub struct UserspaceNat {
+  #[cfg_attr(not(feature = "userspace-nat"), allow(dead_code))]
+  pub mtu: u16,
+  to_stack: mpsc::Sender<Vec<u8>>,
+  from_stack: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl UserspaceNat {
+  /// Passes an IPv4 packet of a client to the stack; false if it was dropped because the stack is behind.
+  pub fn send(&self, packet: &[u8]) -> anyhow::Result<bool> {
+    match self.to_stack.try_send(packet.to_vec()) {
+      Ok(()) => Ok(true),
+      Err(mpsc::error::TrySendError::Full(_)) => Ok(false),
+      Err(mpsc::error::TrySendError::Closed(_)) => anyhow::bail!("Userspace NAT stopped"),
+    }
+  }
+
+  /// Waits for the next IPv4 packet to a client.
+  pub async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+    let Some(packet) = self.from_stack.lock().await.recv().await else {
+      anyhow::bail!("Userspace NAT stopped");
+    };
+    let len = packet.len().min(buf.len());
+    buf[..len].copy_from_slice(&packet[..len]);
+    Ok(len)
+  }
+}
+
+#[cfg(feature = "userspace-nat")]
+pub use stack::spawn;
+#[cfg(feature = "userspace-nat")]
+pub(crate) use stack::Queue;
+
+#[cfg(feature = "userspace-nat")]
+mod stack {
+  use std::collections::HashMap;
+  use std::collections::VecDeque;
+  use std::net::SocketAddr;
+  use std::net::SocketAddrV4;
+  use std::sync::atomic::AtomicU64;
+  use std::sync::atomic::Ordering;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use smoltcp::iface::Config;
+  use smoltcp::iface::Interface;
+  use smoltcp::iface::SocketHandle;
+  use smoltcp::iface::SocketSet;
+  use smoltcp::phy;
+  use smoltcp::phy::ChecksumCapabilities;
+  use smoltcp::phy::Device;
+  use smoltcp::phy::DeviceCapabilities;
+  use smoltcp::phy::Medium;
+  use smoltcp::socket::tcp;
+  use smoltcp::time::Instant;
+  use smoltcp::wire::HardwareAddress;
+  use smoltcp::wire::IpAddress;
+  use smoltcp::wire::IpCidr;
+  use smoltcp::wire::IpProtocol;
+  use smoltcp::wire::Ipv4Packet;
+  use smoltcp::wire::Ipv4Repr;
+  use smoltcp::wire::TcpPacket;
+  use smoltcp::wire::UdpPacket;
+  use smoltcp::wire::UdpRepr;
+  use tokio::io::AsyncReadExt;
+  use tokio::io::AsyncWriteExt;
+  use tokio::net::TcpStream;
+  use tokio::net::UdpSocket;
+  use tokio::sync::mpsc;
+  use tokio::sync::Mutex;
+  use tokio::sync::Notify;
+  use tokio::task::JoinHandle;
+  use tracing::debug;
+  use tracing::trace;
+  use vpn_shared::logging;
+
+  use super::UserspaceNat;
+  use super::UserspaceNatConfig;
+
+  const QUEUE_DEPTH: usize = 1024;
+  const SOCKET_BUFFER: usize = 64 * 1024;
+  const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+  type Flow = (SocketAddrV4, SocketAddrV4);
+
+  /// Starts the stack on the current runtime.
+  pub fn spawn(config: UserspaceNatConfig) -> UserspaceNat {
+    let (to_stack, from_clients) = mpsc::channel(QUEUE_DEPTH);
+    let (to_clients, from_stack) = mpsc::channel(QUEUE_DEPTH);
+    let mtu = config.mtu;
+    tokio::spawn(Stack::new(config, to_clients).run(from_clients));
+    UserspaceNat { mtu, to_stack, from_stack: Mutex::new(from_stack) }
+  }
+
+  /// Packets exchanged with smoltcp; what it transmits goes to the clients.
+  pub(crate) struct Queue {
+    pub(crate) rx: VecDeque<Vec<u8>>,
+    pub(crate) tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+  }
+
+  impl Queue {
+    pub(crate) fn new(mtu: usize) -> Self {
+      Self { rx: VecDeque::new(), tx: VecDeque::new(), mtu }
+    }
+  }
+
+  impl Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+      let packet = self.rx.pop_front()?;
+      Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+      Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+      let mut capabilities = DeviceCapabilities::default();
+      capabilities.medium = Medium::Ip;
+      capabilities.max_transmission_unit = self.mtu;
+      capabilities
+    }
+  }
+
+  pub(crate) struct RxToken(Vec<u8>);
+
+  impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+      f(&self.0)
+    }
+  }
+
+  pub(crate) struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+  impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+      let mut packet = vec![0u8; len];
+      let result = f(&mut packet);
+      self.0.push_back(packet);
+      result
+    }
+  }
+
+  enum Remote {
+    Connected,
+    Data(Vec<u8>),
+  }
+
+  struct TcpConnection {
+    flow: Flow,
+    /// Set once the client's handshake completes and the outbound connection is started.
+    relay: Option<Relay>,
+    connected: bool,
+    pending: Vec<u8>,
+  }
+
+  struct Relay {
+    /// Dropped once the client stops sending, which shuts down the remote's write half.
+    to_remote: Option<mpsc::Sender<Vec<u8>>>,
+    from_remote: mpsc::Receiver<Remote>,
+  }
+
+  struct UdpFlow {
+    socket: Arc<UdpSocket>,
+    last_used: Arc<AtomicU64>,
+    reader: JoinHandle<()>,
+  }
+
+  impl Drop for UdpFlow {
+    fn drop(&mut self) {
+      self.reader.abort();
+    }
+  }
+
+  struct Stack {
+    config: UserspaceNatConfig,
+    iface: Interface,
+    device: Queue,
+    sockets: SocketSet<'static>,
+    tcp: HashMap<SocketHandle, TcpConnection>,
+    udp: HashMap<Flow, UdpFlow>,
+    to_clients: mpsc::Sender<Vec<u8>>,
+    wake: Arc<Notify>,
+    started: std::time::Instant,
+  }
+
+  impl Stack {
+    fn new(config: UserspaceNatConfig, to_clients: mpsc::Sender<Vec<u8>>) -> Self {
+      let mut device = Queue::new(config.mtu as usize);
+      let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, Instant::now());
+      iface.update_ip_addrs(|addrs| _ = addrs.push(IpCidr::new(IpAddress::Ipv4(config.address), 32)));
+      // Accept packets to any address, as if every destination were local.
+      iface.set_any_ip(true);
+      _ = iface.routes_mut().add_default_ipv4_route(config.address);
+
+      Self {
+        config,
+        iface,
+        device,
+        sockets: SocketSet::new(Vec::new()),
+        tcp: HashMap::new(),
+        udp: HashMap::new(),
+        to_clients,
+        wake: Arc::new(Notify::new()),
+        started: std::time::Instant::now(),
+      }
+    }
+
+    async fn run(mut self, mut from_clients: mpsc::Receiver<Vec<u8>>) {
+      let wake = self.wake.clone();
+      loop {
+        let delay = self
+          .iface
+          .poll_delay(Instant::now(), &self.sockets)
+          .map(Duration::from)
+          .unwrap_or(Duration::from_secs(1));
+
+        tokio::select! {
+          packet = from_clients.recv() => match packet {
+            Some(packet) => self.ingress(packet).await,
+            None => break,
+          },
+          _ = wake.notified() => {}
+          _ = tokio::time::sleep(delay) => self.expire_udp(),
+        }
+
+        self.poll();
+      }
+    }
+
+    fn elapsed_secs(&self) -> u64 {
+      self.started.elapsed().as_secs()
+    }
+
+    fn flows(&self) -> usize {
+      self.tcp.len() + self.udp.len()
+    }
+
+    async fn ingress(&mut self, packet: Vec<u8>) {
+      let Ok(ip) = Ipv4Packet::new_checked(packet.as_slice()) else {
+        return;
+      };
+
+      match ip.next_header() {
+        IpProtocol::Tcp => {
+          if let Ok(tcp) = TcpPacket::new_checked(ip.payload()) {
+            let flow = (
+              SocketAddrV4::new(ip.src_addr(), tcp.src_port()),
+              SocketAddrV4::new(ip.dst_addr(), tcp.dst_port()),
+            );
+            if tcp.syn() && !tcp.ack() {
+              self.accept(flow);
+            }
+          }
+          self.device.rx.push_back(packet);
+        }
+        IpProtocol::Udp => {
+          let Ok(udp) = UdpPacket::new_checked(ip.payload()) else {
+            return;
+          };
+          let flow = (
+            SocketAddrV4::new(ip.src_addr(), udp.src_port()),
+            SocketAddrV4::new(ip.dst_addr(), udp.dst_port()),
+          );
+          self.relay_udp(flow, udp.payload()).await;
+        }
+        protocol => {
+          trace!(target: logging::TUN, "Dropping {} packet from {}: not supported in userspace", protocol, ip.src_addr())
+        }
+      }
+    }
+
+    /// Makes sure a socket listens for the client's SYN; several clients may connect to the same
+    /// destination, so every pending connection gets its own listener.
+    fn accept(&mut self, flow: Flow) {
+      let listening = self.tcp.iter().any(|(handle, connection)| {
+        let socket = self.sockets.get::<tcp::Socket>(*handle);
+        match socket.state() {
+          tcp::State::Listen => connection.flow.1 == flow.1,
+          _ => flow_of(socket) == Some(flow),
+        }
+      });
+      if listening {
+        return;
+      }
+
+      if !self.config.may_reach(*flow.1.ip()) {
+        debug!(target: logging::TUN, "Refusing connection {} -> {}: destination not allowed", flow.0, flow.1);
+        return;
+      }
+
+      if self.flows() >= self.config.max_flows {
+        debug!(target: logging::TUN, "Dropping connection {} -> {}: too many flows", flow.0, flow.1);
+        return;
+      }
+
+      let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER]),
+        tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER]),
+      );
+      if socket.listen(flow.1).is_err() {
+        return;
+      }
+      let handle = self.sockets.add(socket);
+      self.tcp.insert(handle, TcpConnection { flow, relay: None, connected: false, pending: Vec::new() });
+    }
+
+    async fn relay_udp(&mut self, flow: Flow, payload: &[u8]) {
+      if !self.udp.contains_key(&flow) {
+        if !self.config.may_reach(*flow.1.ip()) {
+          debug!(target: logging::TUN, "Dropping datagram {} -> {}: destination not allowed", flow.0, flow.1);
+          return;
+        }
+        if self.flows() >= self.config.max_flows {
+          debug!(target: logging::TUN, "Dropping datagram {} -> {}: too many flows", flow.0, flow.1);
+          return;
+        }
+
+        let socket = match UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).await {
+          Ok(socket) => Arc::new(socket),
+          Err(e) => {
+            debug!(target: logging::TUN, "Failed to open a socket for {} -> {}: {}", flow.0, flow.1, e);
+            return;
+          }
+        };
+        let last_used = Arc::new(AtomicU64::new(self.elapsed_secs()));
+        let reader = tokio::spawn(read_udp(
+          flow,
+          socket.clone(),
+          self.to_clients.clone(),
+          last_used.clone(),
+          self.started,
+        ));
+        self.udp.insert(flow, UdpFlow { socket, last_used, reader });
+      }
+
+      let udp = &self.udp[&flow];
+      udp.last_used.store(self.elapsed_secs(), Ordering::Relaxed);
+      if let Err(e) = udp.socket.send_to(payload, flow.1).await {
+        trace!(target: logging::TUN, "Failed to send a datagram {} -> {}: {}", flow.0, flow.1, e);
+      }
+    }
+
+    fn expire_udp(&mut self) {
+      let now = self.elapsed_secs();
+      let timeout = self.config.udp_timeout_secs;
+      self.udp.retain(|_, flow| now.saturating_sub(flow.last_used.load(Ordering::Relaxed)) < timeout);
+    }
+
+    fn poll(&mut self) {
+      self.iface.poll(Instant::now(), &mut self.device, &mut self.sockets);
+      self.service_tcp();
+      self.iface.poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+      while let Some(packet) = self.device.tx.pop_front() {
+        if self.to_clients.try_send(packet).is_err() {
+          trace!(target: logging::TUN, "Userspace NAT queue to clients is full; dropping packet");
+        }
+      }
+    }
+
+    fn service_tcp(&mut self) {
+      let mut closed = Vec::new();
+
+      for (handle, connection) in self.tcp.iter_mut() {
+        let socket = self.sockets.get_mut::<tcp::Socket>(*handle);
+
+        if connection.relay.is_none() && socket.state() == tcp::State::Established {
+          // Several listeners may share a destination, so the flow is whatever this one accepted.
+          if let Some(flow) = flow_of(socket) {
+            connection.flow = flow;
+          }
+          let (to_remote, to_remote_rx) = mpsc::channel(16);
+          let (from_remote_tx, from_remote) = mpsc::channel(16);
+          tokio::spawn(relay_tcp(connection.flow.1, to_remote_rx, from_remote_tx, self.wake.clone()));
+          connection.relay = Some(Relay { to_remote: Some(to_remote), from_remote });
+        }
+
+        let Some(Relay { ref mut to_remote, ref mut from_remote }) = connection.relay else {
+          if socket.state() == tcp::State::Closed {
+            closed.push(*handle);
+          }
+          continue;
+        };
+
+        // Client to remote, leaving data in the socket while the remote is slower.
+        if let Some(sender) = to_remote {
+          while socket.can_recv() {
+            let Ok(permit) = sender.try_reserve() else {
+              break;
+            };
+            if let Ok(data) = socket.recv(|buf| (buf.len(), buf.to_vec())) {
+              permit.send(data);
+            }
+          }
+          if !socket.may_recv() {
+            *to_remote = None;
+          }
+        }
+
+        // Remote to client.
+        loop {
+          if !connection.pending.is_empty() {
+            match socket.send_slice(&connection.pending) {
+              Ok(sent) => _ = connection.pending.drain(..sent),
+              Err(_) => break,
+            }
+            if !connection.pending.is_empty() {
+              break;
+            }
+          }
+
+          match from_remote.try_recv() {
+            Ok(Remote::Connected) => connection.connected = true,
+            Ok(Remote::Data(data)) => connection.pending = data,
+            Err(mpsc::error::TryRecvError::Empty) => break,
+            Err(mpsc::error::TryRecvError::Disconnected) => {
+              match connection.connected {
+                true => socket.close(),
+                false => socket.abort(),
+              }
+              break;
+            }
+          }
+        }
+
+        if socket.state() == tcp::State::Closed || socket.state() == tcp::State::TimeWait {
+          closed.push(*handle);
+        }
+      }
+
+      for handle in closed {
+        self.tcp.remove(&handle);
+        self.sockets.remove(handle);
+      }
+    }
+  }
+
+  fn flow_of(socket: &tcp::Socket) -> Option<Flow> {
+    let (remote, local) = (socket.remote_endpoint()?, socket.local_endpoint()?);
+    let (IpAddress::Ipv4(remote_ip), IpAddress::Ipv4(local_ip)) = (remote.addr, local.addr);
+    Some((SocketAddrV4::new(remote_ip, remote.port), SocketAddrV4::new(local_ip, local.port)))
+  }
+
+  async fn relay_tcp(
+    remote: SocketAddrV4,
+    mut to_remote: mpsc::Receiver<Vec<u8>>,
+    from_remote: mpsc::Sender<Remote>,
+    wake: Arc<Notify>,
+  ) {
+    let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(SocketAddr::V4(remote))).await
+    {
+      Ok(Ok(stream)) => stream,
+      Ok(Err(e)) => {
+        debug!(target: logging::TUN, "Failed to connect to {}: {}", remote, e);
+        wake.notify_one();
+        return;
+      }
+      Err(_) => {
+        debug!(target: logging::TUN, "Timed out connecting to {}", remote);
+        wake.notify_one();
+        return;
+      }
+    };
+
+    let (mut reader, mut writer) = stream.into_split();
+    if from_remote.send(Remote::Connected).await.is_err() {
+      return;
+    }
+    wake.notify_one();
+
+    let upload = async move {
+      while let Some(data) = to_remote.recv().await {
+        if writer.write_all(&data).await.is_err() {
+          break;
+        }
+      }
+      _ = writer.shutdown().await;
+    };
+
+    let download = async {
+      let mut buf = vec![0u8; 16 * 1024];
+      while let Ok(len @ 1..) = reader.read(&mut buf).await {
+        if from_remote.send(Remote::Data(buf[..len].to_vec())).await.is_err() {
+          break;
+        }
+        wake.notify_one();
+      }
+    };
+
+    tokio::join!(upload, download);
+    drop(from_remote);
+    wake.notify_one();
+  }
+
+  async fn read_udp(
+    flow: Flow,
+    socket: Arc<UdpSocket>,
+    to_clients: mpsc::Sender<Vec<u8>>,
+    last_used: Arc<AtomicU64>,
+    started: std::time::Instant,
+  ) {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+      let (len, from) = match socket.recv_from(&mut buf).await {
+        Ok(received) => received,
+        Err(e) => {
+          debug!(target: logging::TUN, "Failed to receive for {} -> {}: {}", flow.0, flow.1, e);
+          return;
+        }
+      };
+      let SocketAddr::V4(from) = from else {
+        continue;
+      };
+
+      last_used.store(started.elapsed().as_secs(), Ordering::Relaxed);
+      if to_clients.try_send(udp_packet(from, flow.0, &buf[..len])).is_err() {
+        trace!(target: logging::TUN, "Userspace NAT queue to clients is full; dropping datagram");
+      }
+    }
+  }
+
+  pub(super) fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let checksums = ChecksumCapabilities::default();
+    let udp = UdpRepr { src_port: src.port(), dst_port: dst.port() };
+    let ip = Ipv4Repr {
+      src_addr: *src.ip(),
+      dst_addr: *dst.ip(),
+      next_header: IpProtocol::Udp,
+      payload_len: udp.header_len() + payload.len(),
+      hop_limit: 64,
+    };
+
+    let mut packet = vec![0u8; ip.buffer_len() + ip.payload_len];
+    let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet);
+    ip.emit(&mut ip_packet, &checksums);
+    udp.emit(
+      &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+      &IpAddress::Ipv4(*src.ip()),
+      &IpAddress::Ipv4(*dst.ip()),
+      payload.len(),
+      |buf| buf.copy_from_slice(payload),
+      &checksums,
+    );
+    packet
+  }
+}
+
+#[cfg(all(test, feature = "userspace-nat"))]
+mod tests {
+  use std::net::SocketAddrV4;
+  use std::time::Duration;
+
+  use super::*;
+
+  /// The echo servers of the tests listen on loopback, which clients only reach when allowed.
+  fn loopback_allowed() -> UserspaceNatConfig {
+    UserspaceNatConfig { allowed_destinations: vec!["127.0.0.0/8".parse().unwrap()], ..Default::default() }
+  }
+
+  #[test]
+  fn test_may_reach() {
+    let config = UserspaceNatConfig::default();
+    for refused in ["127.0.0.1", "169.254.169.254", "0.0.0.0", "255.255.255.255", "224.0.0.1", "169.254.0.1"]
+    {
+      assert!(!config.may_reach(refused.parse().unwrap()), "{}", refused);
+    }
+    assert!(config.may_reach(Ipv4Addr::new(192, 0, 2, 1)));
+    assert!(loopback_allowed().may_reach(Ipv4Addr::LOCALHOST));
+  }
+
+  #[tokio::test]
+  async fn test_udp_relay() {
+    let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = match echo.local_addr().unwrap() {
+      std::net::SocketAddr::V4(addr) => addr,
+      _ => unreachable!(),
+    };
+    tokio::spawn(async move {
+      let mut buf = [0u8; 64];
+      let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+      echo.send_to(&buf[..len], from).await.unwrap();
+    });
+
+    let nat = spawn(loopback_allowed());
+    let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5000);
+    nat.send(&stack::udp_packet(client, echo_addr, b"ping")).unwrap();
+
+    let mut buf = [0u8; 1500];
+    let len = tokio::time::timeout(Duration::from_secs(2), nat.recv(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(&buf[..len], stack::udp_packet(echo_addr, client, b"ping"));
+
+    // Without the allow-list, the server's loopback is out of reach.
+    let nat = spawn(UserspaceNatConfig::default());
+    nat.send(&stack::udp_packet(client, echo_addr, b"ping")).unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(200), nat.recv(&mut buf)).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_tcp_relay() {
+    use smoltcp::iface::Config;
+    use smoltcp::iface::Interface;
+    use smoltcp::iface::SocketSet;
+    use smoltcp::socket::tcp;
+    use smoltcp::time::Instant;
+    use smoltcp::wire::HardwareAddress;
+    use smoltcp::wire::IpAddress;
+    use smoltcp::wire::IpCidr;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buf = [0u8; 4];
+      stream.read_exact(&mut buf).await.unwrap();
+      stream.write_all(&buf).await.unwrap();
+    });
+
+    // A client of the tunnel, speaking TCP through the NAT.
+    let nat = spawn(loopback_allowed());
+    let mut device = stack::Queue::new(1400);
+    let client = Ipv4Addr::new(10, 0, 0, 2);
+    let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, Instant::now());
+    iface.update_ip_addrs(|addrs| _ = addrs.push(IpCidr::new(IpAddress::Ipv4(client), 24)));
+    iface.routes_mut().add_default_ipv4_route(Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+
+    let mut sockets = SocketSet::new(Vec::new());
+    let socket =
+      tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 1024]), tcp::SocketBuffer::new(vec![0; 1024]));
+    let handle = sockets.add(socket);
+    sockets
+      .get_mut::<tcp::Socket>(handle)
+      .connect(iface.context(), (Ipv4Addr::LOCALHOST, port), 49152)
+      .unwrap();
+
+    let mut echoed = Vec::new();
+    let mut sent = false;
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while echoed.len() < 4 {
+      assert!(std::time::Instant::now() < deadline, "no echo through the userspace NAT");
+      iface.poll(Instant::now(), &mut device, &mut sockets);
+      while let Some(packet) = device.tx.pop_front() {
+        nat.send(&packet).unwrap();
+      }
+
+      let socket = sockets.get_mut::<tcp::Socket>(handle);
+      if socket.can_send() && !sent {
+        socket.send_slice(b"ping").unwrap();
+        sent = true;
+      }
+      if socket.can_recv() {
+        socket.recv(|buf| (buf.len(), echoed.extend_from_slice(buf))).unwrap();
+      }
+
+      let mut buf = [0u8; 1500];
+      if let Ok(Ok(len)) = tokio::time::timeout(Duration::from_millis(10), nat.recv(&mut buf)).await {
+        device.rx.push_back(buf[..len].to_vec());
+      }
+    }
+    assert_eq!(echoed, b"ping");
+  }
+}
@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// WebAssembly module loaded as a `PacketFilter`. It must not import anything and must export:
+///
+/// - `memory`;
+/// - `alloc(len: i32) -> i32`, returning the offset of `len` writable bytes; called for the username and
+///   the packet before every `filter` call, so a module may hand out the same buffer each time;
+/// - `filter(direction: i32, packet: i32, packet_len: i32, username: i32, username_len: i32) -> i32`,
+///   where `direction` is 0 for packets from the client and 1 for packets to it, returning 0 to accept the
+///   packet and anything else to drop it.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct WasmFilterConfig {
+  /// Compiled module, or its text format.
+  pub path: PathBuf,
+
+  /// Instructions a single `filter` call may run before it's aborted and the packet dropped.
+  #[serde(default = "default_fuel")]
+  pub fuel: u64,
+}
+
+fn default_fuel() -> u64 {
+  1_000_000
+}
+
+#[cfg(feature = "wasm")]
+pub use module::WasmFilter;
+
+#[cfg(feature = "wasm")]
+mod module {
+  use std::sync::Mutex;
+
+  use wasmtime::Config;
+  use wasmtime::Engine;
+  use wasmtime::Linker;
+  use wasmtime::Memory;
+  use wasmtime::Module;
+  use wasmtime::Store;
+  use wasmtime::TypedFunc;
+
+  use super::WasmFilterConfig;
+  use crate::accounting::Direction;
+  use crate::filter::Action;
+  use crate::filter::PacketContext;
+  use crate::filter::PacketFilter;
+
+  type FilterFunc = TypedFunc<(i32, i32, i32, i32, i32), i32>;
+
+  pub struct WasmFilter {
+    name: String,
+    fuel: u64,
+    // A store runs one call at a time, so packets of all workers pass through this lock.
+    instance: Mutex<Instance>,
+  }
+
+  struct Instance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    filter: FilterFunc,
+  }
+
+  impl WasmFilter {
+    pub fn load(config: &WasmFilterConfig) -> anyhow::Result<Self> {
+      let engine = Engine::new(Config::new().consume_fuel(true))?;
+      let module = Module::from_file(&engine, &config.path)
+        .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", config.path.display(), e))?;
+      Self::new(&engine, &module, config.path.display().to_string(), config.fuel)
+    }
+
+    fn new(engine: &Engine, module: &Module, name: String, fuel: u64) -> anyhow::Result<Self> {
+      let mut store = Store::new(engine, ());
+      store.set_fuel(fuel)?;
+      let instance = Linker::new(engine).instantiate(&mut store, module)?;
+
+      let Some(memory) = instance.get_memory(&mut store, "memory") else {
+        anyhow::bail!("{} doesn't export memory", name);
+      };
+      let alloc = instance.get_typed_func(&mut store, "alloc")?;
+      let filter = instance.get_typed_func(&mut store, "filter")?;
+
+      Ok(Self { name, fuel, instance: Mutex::new(Instance { store, memory, alloc, filter }) })
+    }
+  }
+
+  impl Instance {
+    fn copy(&mut self, bytes: &[u8]) -> anyhow::Result<i32> {
+      let offset = self.alloc.call(&mut self.store, bytes.len() as i32)?;
+      self.memory.write(&mut self.store, offset as u32 as usize, bytes)?;
+      Ok(offset)
+    }
+  }
+
+  impl PacketFilter for WasmFilter {
+    fn name(&self) -> &str {
+      &self.name
+    }
+
+    fn filter(&self, context: &PacketContext) -> anyhow::Result<Action> {
+      let mut instance = self.instance.lock().unwrap();
+      instance.store.set_fuel(self.fuel)?;
+
+      let username = instance.copy(context.username.as_bytes())?;
+      let packet = instance.copy(context.packet)?;
+      let direction = match context.direction {
+        Direction::Inbound => 0,
+        Direction::Outbound => 1,
+      };
+
+      let Instance { store, filter, .. } = &mut *instance;
+      let verdict = filter.call(
+        store,
+        (direction, packet, context.packet.len() as i32, username, context.username.len() as i32),
+      )?;
+
+      Ok(if verdict == 0 { Action::Accept } else { Action::Drop })
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    // Drops outbound packets and inbound ones whose first byte isn't 0x45.
+    const MODULE: &str = r#"
+      (module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) i32.const 1024)
+        (func (export "filter") (param i32 i32 i32 i32 i32) (result i32)
+          local.get 0
+          if (result i32)
+            i32.const 1
+          else
+            local.get 1
+            i32.load8_u
+            i32.const 0x45
+            i32.ne
+          end))
+    "#;
+
+    const SPIN: &str = r#"
+      (module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) i32.const 0)
+        (func (export "filter") (param i32 i32 i32 i32 i32) (result i32)
+          (loop br 0)
+          i32.const 0))
+    "#;
+
+    fn load(wat: &str, fuel: u64) -> WasmFilter {
+      let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+      let module = Module::new(&engine, wat).unwrap();
+      WasmFilter::new(&engine, &module, "test".to_string(), fuel).unwrap()
+    }
+
+    fn context(direction: Direction, packet: &[u8]) -> PacketContext<'_> {
+      PacketContext { direction, username: "alice", packet }
+    }
+
+    #[test]
+    fn test_filter() {
+      let filter = load(MODULE, 10_000);
+      assert_eq!(filter.filter(&context(Direction::Inbound, &[0x45, 0])).unwrap(), Action::Accept);
+      assert_eq!(filter.filter(&context(Direction::Inbound, &[0x60, 0])).unwrap(), Action::Drop);
+      assert_eq!(filter.filter(&context(Direction::Outbound, &[0x45, 0])).unwrap(), Action::Drop);
+    }
+
+    #[test]
+    fn test_out_of_fuel() {
+      let filter = load(SPIN, 10_000);
+      assert!(filter.filter(&context(Direction::Inbound, &[0x45])).is_err());
+      assert!(filter.filter(&context(Direction::Inbound, &[0x45])).is_err());
+    }
+  }
+}
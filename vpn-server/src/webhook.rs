@@ -0,0 +1,211 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::alerts::Violation;
+
+const QUEUE_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebhookConfig {
+  /// Plain `http://` endpoint events are POSTed to as JSON, e.g. a local relay to chat or ticketing.
+  pub url: String,
+
+  #[serde(default = "default_timeout_secs")]
+  pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+  5
+}
+
+impl WebhookConfig {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    Target::parse(&self.url).map(drop)
+  }
+}
+
+/// Something about a user administrators may want to act on.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum AdminEvent {
+  QuotaWarning {
+    username: String,
+    /// Device whose usage reached the share, see `KeyCredentials::device`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    percent: u8,
+    used_bytes: u64,
+    quota_bytes: u64,
+  },
+  /// Enough violations of one kind by a session to reach its alert threshold, see `AlertsConfig`.
+  PolicyViolation {
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    client_addr: String,
+    violation: Violation,
+    /// Addresses of the packet that raised the alert.
+    source: Option<Ipv4Addr>,
+    destination: Option<Ipv4Addr>,
+    /// Violations of this kind in the current window.
+    count: u32,
+  },
+  /// A user was refused because every address of their network's pool is leased.
+  PoolExhausted {
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    retry_after_secs: u32,
+  },
+}
+
+/// Delivers admin events one at a time; events are queued and dropped with a warning if the endpoint
+/// can't keep up, failed deliveries aren't retried.
+pub struct Webhook {
+  events: mpsc::Sender<AdminEvent>,
+}
+
+impl Webhook {
+  pub fn spawn(config: WebhookConfig) -> anyhow::Result<Self> {
+    let target = Target::parse(&config.url)?;
+    let (events, rx) = mpsc::channel(QUEUE_DEPTH);
+    tokio::spawn(deliver(target, Duration::from_secs(config.timeout_secs), rx));
+    Ok(Self { events })
+  }
+
+  pub fn send(&self, event: AdminEvent) {
+    if let Err(e) = self.events.try_send(event) {
+      warn!("Webhook queue is full; dropping {:?}", e.into_inner());
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Target {
+  /// `host:port`
+  authority: String,
+  path: String,
+}
+
+impl Target {
+  fn parse(url: &str) -> anyhow::Result<Self> {
+    let Some(rest) = url.strip_prefix("http://") else {
+      anyhow::bail!("Webhook URL {} must start with http://", url);
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if authority.is_empty() {
+      anyhow::bail!("Webhook URL {} has no host", url);
+    }
+
+    Ok(Self {
+      authority: match authority.contains(':') {
+        true => authority.to_string(),
+        false => format!("{}:80", authority),
+      },
+      path: match path {
+        "" => "/".to_string(),
+        path => path.to_string(),
+      },
+    })
+  }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+  /// Seconds since the Unix epoch.
+  timestamp: u64,
+  #[serde(flatten)]
+  event: &'a AdminEvent,
+}
+
+async fn deliver(target: Target, timeout: Duration, mut events: mpsc::Receiver<AdminEvent>) {
+  while let Some(event) = events.recv().await {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let body = serde_json::to_string(&Payload { timestamp, event: &event }).expect("events are serializable");
+    match tokio::time::timeout(timeout, post(&target, &body)).await {
+      Ok(Ok(())) => {}
+      Ok(Err(e)) => warn!("Failed to deliver {:?} to the webhook: {}", event, e),
+      Err(_) => warn!("Webhook timed out delivering {:?}", event),
+    }
+  }
+}
+
+async fn post(target: &Target, body: &str) -> anyhow::Result<()> {
+  let mut stream = TcpStream::connect(&target.authority).await?;
+  let request = format!(
+    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    target.path,
+    target.authority,
+    body.len(),
+    body
+  );
+  stream.write_all(request.as_bytes()).await?;
+
+  let mut response = Vec::new();
+  stream.read_to_end(&mut response).await?;
+  let response = String::from_utf8_lossy(&response);
+  let status = response.split_whitespace().nth(1).unwrap_or_default();
+  if !status.starts_with('2') {
+    anyhow::bail!("Endpoint answered {}", response.lines().next().unwrap_or_default());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  #[test]
+  fn test_parse_target() {
+    let target = Target::parse("http://hooks.example.com/vpn/events").unwrap();
+    assert_eq!(target, Target { authority: "hooks.example.com:80".into(), path: "/vpn/events".into() });
+
+    let target = Target::parse("http://127.0.0.1:9000").unwrap();
+    assert_eq!(target, Target { authority: "127.0.0.1:9000".into(), path: "/".into() });
+
+    assert!(Target::parse("https://hooks.example.com").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_delivery() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/events", listener.local_addr().unwrap());
+    let webhook = Webhook::spawn(WebhookConfig { url, timeout_secs: 5 }).unwrap();
+
+    webhook.send(AdminEvent::QuotaWarning {
+      username: "alice".into(),
+      device: None,
+      network: None,
+      percent: 80,
+      used_bytes: 80,
+      quota_bytes: 100,
+    });
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = vec![0; 4096];
+    let len = stream.read(&mut request).await.unwrap();
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+
+    let request = String::from_utf8_lossy(&request[..len]);
+    assert!(request.starts_with("POST /events HTTP/1.1\r\n"));
+    let body = request.split_once("\r\n\r\n").unwrap().1;
+    assert!(body.contains(r#""event":"quota-warning","username":"alice","percent":80"#), "{}", body);
+  }
+}
@@ -0,0 +1,272 @@
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::Mutex;
+
+use tracing::error;
+use vpn_shared::packet::ClientPacket;
+use vpn_shared::packet::Key;
+use vpn_shared::protocol::Offer;
+use vpn_shared::rate;
+use vpn_shared::rate::TokenBucket;
+use vpn_shared::supervisor::Restart;
+use vpn_shared::supervisor::Supervisor;
+
+use crate::handle_packet::PacketHandler;
+use crate::policy::Priority;
+use crate::server::Server;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+  /// Drop packets that don't fit into a full queue.
+  #[default]
+  Drop,
+  /// Stop reading from the socket until the queue has room.
+  Block,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct WorkerConfig {
+  pub concurrency: usize,
+  pub queue_depth: usize,
+  pub overflow: OverflowPolicy,
+  pub shedding: Option<SheddingConfig>,
+  pub admission: Option<AdmissionConfig>,
+  /// Shards of the session tables, a power of two; every shard has a lock of its own. Four per core by
+  /// default; more keep thousands of sessions from waiting on each other when their entries change.
+  pub session_shards: Option<usize>,
+}
+
+impl Default for WorkerConfig {
+  fn default() -> Self {
+    Self {
+      concurrency: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+      queue_depth: 1024,
+      overflow: OverflowPolicy::default(),
+      shedding: None,
+      admission: None,
+      session_shards: None,
+    }
+  }
+}
+
+/// Sheds load once the worker queues back up, as they do when the server runs out of CPU: past
+/// `handshake-fill-pct` new key exchanges are deferred with a retry-after, past `normal-fill-pct` data of
+/// normal-priority users is dropped, and past `high-fill-pct` data of everyone. Control packets like pings
+/// always go through, so established sessions aren't lost to the overload.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct SheddingConfig {
+  pub handshake_fill_pct: u8,
+  pub normal_fill_pct: u8,
+  pub high_fill_pct: u8,
+  pub retry_after_secs: u64,
+}
+
+impl Default for SheddingConfig {
+  fn default() -> Self {
+    Self { handshake_fill_pct: 50, normal_fill_pct: 70, high_fill_pct: 90, retry_after_secs: 5 }
+  }
+}
+
+/// Paces new key exchanges to `handshakes-per-sec`, in bursts of up to `burst`, so that clients reconnecting
+/// together after an outage or a restart are let in over a while instead of swamping the workers at once.
+/// The rest are deferred with a retry-after stretched by a random share of up to its length, which spreads
+/// them out further.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AdmissionConfig {
+  pub handshakes_per_sec: u32,
+  pub burst: u32,
+  pub retry_after_secs: u64,
+}
+
+impl Default for AdmissionConfig {
+  fn default() -> Self {
+    Self { handshakes_per_sec: 50, burst: 100, retry_after_secs: 2 }
+  }
+}
+
+/// What's being shed, each level on top of the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Shed {
+  Nothing,
+  Handshakes,
+  NormalData,
+  AllData,
+}
+
+impl SheddingConfig {
+  /// `fill` is in `0.0..=1.0`.
+  pub fn level(&self, fill: f64) -> Shed {
+    let percent = fill * 100.0;
+    if percent >= self.high_fill_pct as f64 {
+      Shed::AllData
+    } else if percent >= self.normal_fill_pct as f64 {
+      Shed::NormalData
+    } else if percent >= self.handshake_fill_pct as f64 {
+      Shed::Handshakes
+    } else {
+      Shed::Nothing
+    }
+  }
+
+  pub fn retry_after(&self) -> Duration {
+    Duration::from_secs(self.retry_after_secs)
+  }
+}
+
+impl Shed {
+  pub fn drops_data(self, priority: Priority) -> bool {
+    match self {
+      Self::AllData => true,
+      Self::NormalData => priority == Priority::Normal,
+      Self::Nothing | Self::Handshakes => false,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum Job {
+  /// Carries the timestamp of the exchange and the local address it arrived on, see `ConnectedClient::local`.
+  KeyExchange(Key, Vec<String>, Offer, u64, Option<Ipv4Addr>),
+  Packet(ClientPacket),
+}
+
+/// Fixed set of workers handling decrypted packets. Packets from the same address always land on the same
+/// worker, so they're handled in the order they were received. A worker that panics is restarted on the
+/// same queue.
+pub struct WorkerPool {
+  queues: Vec<mpsc::Sender<(Job, SocketAddr, Instant)>>,
+  overflow: OverflowPolicy,
+  shedding: Option<SheddingConfig>,
+  admission: Option<(Duration, std::sync::Mutex<TokenBucket>)>,
+  server: Arc<Server>,
+}
+
+impl WorkerConfig {
+  /// Empty session table with `session_shards` shards.
+  pub fn session_table<K: Eq + Hash, V>(&self) -> DashMap<K, V> {
+    match self.session_shards {
+      Some(shards) => DashMap::with_shard_amount(shards),
+      None => DashMap::new(),
+    }
+  }
+}
+
+impl WorkerPool {
+  pub fn spawn(server: Arc<Server>, config: &WorkerConfig, supervisor: &Supervisor) -> Self {
+    let queues = (0..config.concurrency.max(1))
+      .map(|_| {
+        let (tx, rx) = mpsc::channel(config.queue_depth.max(1));
+        let (server, rx) = (server.clone(), Arc::new(Mutex::new(rx)));
+        supervisor.spawn("worker", Restart::Always, move || work(server.clone(), rx.clone()));
+        tx
+      })
+      .collect();
+
+    let admission = config.admission.as_ref().map(|config| {
+      let bucket = TokenBucket::new(config.handshakes_per_sec as f64, config.burst.max(1) as f64);
+      (Duration::from_secs(config.retry_after_secs), std::sync::Mutex::new(bucket))
+    });
+    Self { queues, overflow: config.overflow, shedding: config.shedding.clone(), admission, server }
+  }
+
+  /// Share of the room in the queues that's taken, in `0.0..=1.0`.
+  pub fn fill(&self) -> f64 {
+    let (queued, room) = self.queues.iter().fold((0, 0), |(queued, room), queue| {
+      (queued + queue.max_capacity() - queue.capacity(), room + queue.max_capacity())
+    });
+    queued as f64 / room as f64
+  }
+
+  /// What to shed at the current fill of the queues, which is also recorded in the metrics.
+  pub fn shed(&self) -> Shed {
+    let shed = self.shedding.as_ref().map_or(Shed::Nothing, |config| config.level(self.fill()));
+    self.server.metrics.shed_level.set(shed as i64);
+    shed
+  }
+
+  /// How long the client of a new key exchange should wait before retrying, if it isn't taken on now
+  /// because load is being shed at `shed` or over the admission rate.
+  pub fn deferral(&self, shed: Shed) -> Option<Duration> {
+    if shed >= Shed::Handshakes {
+      return self.shedding.as_ref().map(SheddingConfig::retry_after);
+    }
+    let (retry_after, bucket) = self.admission.as_ref()?;
+    (!bucket.lock().unwrap().try_take(1.0)).then(|| rate::jitter(*retry_after, 1.0))
+  }
+
+  pub async fn submit(&self, job: Job, src_addr: SocketAddr) {
+    let mut hasher = std::hash::DefaultHasher::new();
+    src_addr.hash(&mut hasher);
+    let queue = &self.queues[hasher.finish() as usize % self.queues.len()];
+
+    let metrics = &self.server.metrics;
+    metrics.worker_queue.enqueued();
+
+    let sent = match self.overflow {
+      OverflowPolicy::Drop => match queue.try_send((job, src_addr, Instant::now())) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+          metrics.worker_queue.dropped.inc();
+          false
+        }
+        Err(TrySendError::Closed(_)) => false,
+      },
+      OverflowPolicy::Block => queue.send((job, src_addr, Instant::now())).await.is_ok(),
+    };
+
+    if !sent {
+      metrics.worker_queue.depth.dec();
+    }
+  }
+}
+
+async fn work(server: Arc<Server>, rx: Arc<Mutex<mpsc::Receiver<(Job, SocketAddr, Instant)>>>) {
+  let mut rx = rx.lock().await;
+  while let Some((job, src_addr, queued)) = rx.recv().await {
+    server.metrics.worker_queue.dequeued(queued);
+
+    let result = match job {
+      Job::KeyExchange(client_key, transforms, offer, timestamp, local) => {
+        server.handle_key_exchange(client_key, transforms, offer, timestamp, src_addr, local).await
+      }
+      Job::Packet(packet) => server.handle(packet, src_addr).await,
+    };
+
+    if let Err(e) = result {
+      error!("Error handling packet from {}: {}", src_addr, e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_shedding_level() {
+    let config = SheddingConfig::default();
+    assert_eq!(config.level(0.3), Shed::Nothing);
+    assert_eq!(config.level(0.5), Shed::Handshakes);
+    assert_eq!(config.level(0.75), Shed::NormalData);
+    assert_eq!(config.level(1.0), Shed::AllData);
+
+    assert!(!Shed::Handshakes.drops_data(Priority::Normal));
+    assert!(Shed::NormalData.drops_data(Priority::Normal));
+    assert!(!Shed::NormalData.drops_data(Priority::High));
+    assert!(Shed::AllData.drops_data(Priority::High));
+  }
+}
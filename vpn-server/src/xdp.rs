@@ -0,0 +1,392 @@
+use serde::Deserialize;
+
+/// XDP program on the interface clients' datagrams arrive on (Linux, `xdp` feature), run by the driver before
+/// the kernel allocates anything for a frame. UDP datagrams to the listen port too short to carry a packet are
+/// dropped there, and those of established sessions are spread over `cpus` by session id through a `CPUMAP`,
+/// so the network stack's share of the work isn't done on whichever CPU took the interrupt. Handshake and
+/// rendezvous datagrams, other traffic, IPv4 fragments and frames with VLAN tags or IP options pass as they
+/// would without it. Nothing is verified here: a datagram with a made-up session id is steered like any other
+/// and dropped by the server after failing to open.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct XdpConfig {
+  pub interface: String,
+
+  /// CPUs sessions are spread over; all available ones if empty.
+  #[serde(default)]
+  pub cpus: Vec<usize>,
+
+  /// Frames queued for each CPU before the program's redirects start failing and frames pass on the CPU they
+  /// arrived on instead.
+  #[serde(default = "default_queue_size")]
+  pub queue_size: u32,
+}
+
+fn default_queue_size() -> u32 {
+  2048
+}
+
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+pub use program::attach;
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+#[allow(unused_imports)]
+pub use program::{Attachment, Program};
+
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+// The server binary never runs the program without attaching it.
+#[allow(dead_code)]
+mod program {
+  use std::ffi::CString;
+  use std::os::fd::AsRawFd;
+  use std::os::fd::FromRawFd;
+  use std::os::fd::OwnedFd;
+  use std::time::Duration;
+
+  use anyhow::Context;
+  use vpn_shared::packet::HANDSHAKE_SESSION;
+  use vpn_shared::packet::NONCE_SIZE;
+  use vpn_shared::packet::RENDEZVOUS_SESSION;
+  use vpn_shared::packet::SESSION_ID_SIZE;
+  use vpn_shared::packet::TAG_SIZE;
+
+  use super::XdpConfig;
+
+  const XDP_DROP: u32 = 1;
+  const XDP_PASS: u32 = 2;
+
+  const BPF_MAP_CREATE: libc::c_long = 0;
+  const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+  const BPF_PROG_LOAD: libc::c_long = 5;
+  const BPF_PROG_TEST_RUN: libc::c_long = 10;
+  const BPF_LINK_CREATE: libc::c_long = 28;
+  const BPF_MAP_TYPE_CPUMAP: u32 = 16;
+  const BPF_PROG_TYPE_XDP: u32 = 6;
+  const BPF_XDP: u32 = 37;
+  const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+
+  /// Ethernet, IPv4 without options and UDP headers.
+  const HEADERS: i16 = 14 + 20 + 8;
+  /// Smallest datagram of a session: its id, the nonce and the tag around an empty packet.
+  const MIN_DATAGRAM: i16 = (SESSION_ID_SIZE + NONCE_SIZE + TAG_SIZE) as i16;
+  const VERIFIER_LOG_SIZE: usize = 64 * 1024;
+
+  /// The program, loaded but not attached yet; see `attach` and `test_run`.
+  pub struct Program {
+    program: OwnedFd,
+    _cpumap: OwnedFd,
+  }
+
+  /// The program attached to an interface until dropped.
+  pub struct Attachment {
+    _program: Program,
+    _link: OwnedFd,
+  }
+
+  /// Loads the program for the server listening on `port` and attaches it as `config` says.
+  pub fn attach(config: &XdpConfig, port: u16) -> anyhow::Result<Attachment> {
+    let cpus = match config.cpus.is_empty() {
+      true => core_affinity::get_core_ids()
+        .context("Failed to list CPUs")?
+        .into_iter()
+        .map(|core| core.id)
+        .collect(),
+      false => config.cpus.clone(),
+    };
+    Program::load(port, &cpus, config.queue_size)?.attach(&config.interface)
+  }
+
+  impl Program {
+    pub fn load(port: u16, cpus: &[usize], queue_size: u32) -> anyhow::Result<Self> {
+      anyhow::ensure!(!cpus.is_empty(), "No CPUs to steer sessions to");
+      let max_entries = cpus.iter().max().map_or(0, |cpu| cpu + 1) as u32;
+      let mut attr = Attr::default();
+      attr.set_u32(0, BPF_MAP_TYPE_CPUMAP).set_u32(4, 4).set_u32(8, 4).set_u32(12, max_entries);
+      let cpumap = bpf_fd(BPF_MAP_CREATE, &mut attr).context("Failed to create the CPU map")?;
+      for &cpu in cpus {
+        let key = cpu as u32;
+        let mut attr = Attr::default();
+        attr.set_u32(0, cpumap.as_raw_fd() as u32);
+        attr.set_u64(8, &key as *const u32 as u64).set_u64(16, &queue_size as *const u32 as u64);
+        bpf(BPF_MAP_UPDATE_ELEM, &mut attr)
+          .with_context(|| format!("Failed to add CPU {} to the CPU map", cpu))?;
+      }
+
+      let instructions = assemble(port, cpus, cpumap.as_raw_fd());
+      let license = c"GPL";
+      let mut log = vec![0u8; VERIFIER_LOG_SIZE];
+      let mut attr = Attr::default();
+      attr
+        .set_u32(0, BPF_PROG_TYPE_XDP)
+        .set_u32(4, instructions.len() as u32)
+        .set_u64(8, instructions.as_ptr() as u64)
+        .set_u64(16, license.as_ptr() as u64)
+        .set_u32(24, 1)
+        .set_u32(28, log.len() as u32)
+        .set_u64(32, log.as_mut_ptr() as u64)
+        .set_u32(68, BPF_XDP);
+      attr.0[48..48 + 7].copy_from_slice(b"vpn_xdp");
+      let program = bpf_fd(BPF_PROG_LOAD, &mut attr).map_err(|e| {
+        let log = String::from_utf8_lossy(&log[..log.iter().position(|&b| b == 0).unwrap_or(log.len())]);
+        anyhow::anyhow!("Failed to load the XDP program: {}\n{}", e, log.trim_end())
+      })?;
+      Ok(Self { program, _cpumap: cpumap })
+    }
+
+    pub fn attach(self, interface: &str) -> anyhow::Result<Attachment> {
+      let name = CString::new(interface)?;
+      let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+      if ifindex == 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!("No interface {}", interface));
+      }
+      let mut attr = Attr::default();
+      attr.set_u32(0, self.program.as_raw_fd() as u32).set_u32(4, ifindex).set_u32(8, BPF_XDP);
+      let link = bpf_fd(BPF_LINK_CREATE, &mut attr)
+        .with_context(|| format!("Failed to attach the XDP program to {}", interface))?;
+      Ok(Attachment { _program: self, _link: link })
+    }
+
+    /// Runs the program over `frame` `repeat` times without attaching it, returning its verdict and the mean
+    /// time a run took; redirects are reported as `XDP_REDIRECT`, not carried out.
+    pub fn test_run(&self, frame: &[u8], repeat: u32) -> anyhow::Result<(u32, Duration)> {
+      let mut attr = Attr::default();
+      attr
+        .set_u32(0, self.program.as_raw_fd() as u32)
+        .set_u32(8, frame.len() as u32)
+        .set_u64(16, frame.as_ptr() as u64)
+        .set_u32(32, repeat);
+      bpf(BPF_PROG_TEST_RUN, &mut attr).context("Failed to run the XDP program")?;
+      Ok((attr.u32(4), Duration::from_nanos(attr.u32(36) as u64)))
+    }
+  }
+
+  /// `union bpf_attr`, zero-filled past the fields a command takes as the kernel requires.
+  #[repr(C, align(8))]
+  struct Attr([u8; 128]);
+
+  impl Default for Attr {
+    fn default() -> Self {
+      Self([0; 128])
+    }
+  }
+
+  impl Attr {
+    fn set_u32(&mut self, at: usize, value: u32) -> &mut Self {
+      self.0[at..at + 4].copy_from_slice(&value.to_ne_bytes());
+      self
+    }
+
+    fn set_u64(&mut self, at: usize, value: u64) -> &mut Self {
+      self.0[at..at + 8].copy_from_slice(&value.to_ne_bytes());
+      self
+    }
+
+    fn u32(&self, at: usize) -> u32 {
+      u32::from_ne_bytes(self.0[at..at + 4].try_into().unwrap())
+    }
+  }
+
+  fn bpf(command: libc::c_long, attr: &mut Attr) -> std::io::Result<i32> {
+    let result = unsafe { libc::syscall(libc::SYS_bpf, command, attr as *mut Attr, size_of::<Attr>()) };
+    if result < 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+    Ok(result as i32)
+  }
+
+  /// Runs a command that creates a map, program or link, returning its descriptor.
+  fn bpf_fd(command: libc::c_long, attr: &mut Attr) -> std::io::Result<OwnedFd> {
+    bpf(command, attr).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+  }
+
+  #[derive(Clone, Copy)]
+  enum Label {
+    Pass,
+    Drop,
+  }
+
+  /// Just enough of an eBPF assembler for one program; jumps only go forward to `Label`s, which `finish`
+  /// resolves.
+  #[derive(Default)]
+  struct Assembler {
+    code: Vec<u64>,
+    jumps: Vec<(usize, Label)>,
+  }
+
+  const R0: u8 = 0;
+  const R1: u8 = 1;
+  const R2: u8 = 2;
+  const R3: u8 = 3;
+  const R4: u8 = 4;
+  const R5: u8 = 5;
+
+  const LDX_B: u8 = 0x71;
+  const LDX_H: u8 = 0x69;
+  const LDX_W: u8 = 0x61;
+  const LDX_DW: u8 = 0x79;
+  const MOV_IMM: u8 = 0xb7;
+  const MOV_REG: u8 = 0xbf;
+  const ADD_IMM: u8 = 0x07;
+  const AND_IMM: u8 = 0x57;
+  const RSH_IMM: u8 = 0x77;
+  const XOR_REG: u8 = 0xaf;
+  const MOD_IMM: u8 = 0x97;
+  const JEQ_IMM: u8 = 0x15;
+  const JNE_IMM: u8 = 0x55;
+  const JGT_REG: u8 = 0x2d;
+  const LD_IMM64: u8 = 0x18;
+  const CALL: u8 = 0x85;
+  const EXIT: u8 = 0x95;
+  const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+  impl Assembler {
+    fn emit(&mut self, code: u8, dst: u8, src: u8, offset: i16, imm: i32) -> &mut Self {
+      #[cfg(target_endian = "little")]
+      let registers = dst | src << 4;
+      #[cfg(target_endian = "big")]
+      let registers = dst << 4 | src;
+      let mut instruction = [0u8; 8];
+      instruction[0] = code;
+      instruction[1] = registers;
+      instruction[2..4].copy_from_slice(&offset.to_ne_bytes());
+      instruction[4..8].copy_from_slice(&imm.to_ne_bytes());
+      self.code.push(u64::from_ne_bytes(instruction));
+      self
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, src: u8, imm: i32, to: Label) -> &mut Self {
+      self.jumps.push((self.code.len(), to));
+      self.emit(code, dst, src, 0, imm)
+    }
+
+    /// Loads `width` bytes at `offset` into the packet as the CPU would, so they compare against the same
+    /// bytes taken with `from_ne_bytes`.
+    fn load(&mut self, code: u8, dst: u8, offset: i16) -> &mut Self {
+      self.emit(code, dst, R2, offset, 0)
+    }
+
+    /// Jumps to `to` unless the packet, starting at R2 and ending at R3, has `len` bytes.
+    fn ensure_len(&mut self, len: i16, to: Label) -> &mut Self {
+      self.emit(MOV_REG, R4, R2, 0, 0).emit(ADD_IMM, R4, 0, 0, len as i32).jump(JGT_REG, R4, R3, 0, to)
+    }
+
+    fn finish(mut self) -> Vec<u64> {
+      let pass = self.code.len();
+      self.emit(MOV_IMM, R0, 0, 0, XDP_PASS as i32).emit(EXIT, 0, 0, 0, 0);
+      let drop = self.code.len();
+      self.emit(MOV_IMM, R0, 0, 0, XDP_DROP as i32).emit(EXIT, 0, 0, 0, 0);
+      for (at, label) in std::mem::take(&mut self.jumps) {
+        let target = match label {
+          Label::Pass => pass,
+          Label::Drop => drop,
+        };
+        let mut instruction = self.code[at].to_ne_bytes();
+        instruction[2..4].copy_from_slice(&((target - at - 1) as i16).to_ne_bytes());
+        self.code[at] = u64::from_ne_bytes(instruction);
+      }
+      self.code
+    }
+  }
+
+  fn ne16(bytes: [u8; 2]) -> i32 {
+    u16::from_ne_bytes(bytes) as i32
+  }
+
+  fn assemble(port: u16, cpus: &[usize], cpumap: i32) -> Vec<u64> {
+    let mut asm = Assembler::default();
+    // R2 and R3: the frame's start and end, as the verifier only lets packets be read after such checks.
+    asm.emit(LDX_W, R2, R1, 0, 0).emit(LDX_W, R3, R1, 4, 0).ensure_len(HEADERS, Label::Pass);
+    asm.load(LDX_H, R5, 12).jump(JNE_IMM, R5, 0, ne16([0x08, 0x00]), Label::Pass);
+    asm.load(LDX_B, R5, 14).jump(JNE_IMM, R5, 0, 0x45, Label::Pass);
+    asm.load(LDX_B, R5, 23).jump(JNE_IMM, R5, 0, libc::IPPROTO_UDP, Label::Pass);
+    // More fragments or a fragment offset: only the first fragment would have the UDP header.
+    asm.load(LDX_H, R5, 20).emit(AND_IMM, R5, 0, 0, ne16([0x3f, 0xff])).jump(JNE_IMM, R5, 0, 0, Label::Pass);
+    asm.load(LDX_H, R5, 36).jump(JNE_IMM, R5, 0, ne16(port.to_be_bytes()), Label::Pass);
+
+    asm.ensure_len(HEADERS + SESSION_ID_SIZE as i16, Label::Drop);
+    // Both ids read the same in either byte order, and jumps sign-extend the -1 to all ones.
+    asm.load(LDX_DW, R5, HEADERS).jump(JEQ_IMM, R5, 0, HANDSHAKE_SESSION as i32, Label::Pass);
+    asm.jump(JEQ_IMM, R5, 0, RENDEZVOUS_SESSION as i64 as i32, Label::Pass);
+    asm.ensure_len(HEADERS + MIN_DATAGRAM, Label::Drop);
+
+    asm.emit(MOV_REG, R0, R5, 0, 0).emit(RSH_IMM, R0, 0, 0, 32).emit(XOR_REG, R0, R5, 0, 0);
+    asm.emit(MOD_IMM, R0, 0, 0, cpus.len() as i32).emit(MOV_IMM, R2, 0, 0, cpus[0] as i32);
+    for (i, &cpu) in cpus.iter().enumerate().skip(1) {
+      asm.emit(JNE_IMM, R0, 0, 1, i as i32).emit(MOV_IMM, R2, 0, 0, cpu as i32);
+    }
+    asm.emit(LD_IMM64, R1, BPF_PSEUDO_MAP_FD, 0, cpumap).emit(0, 0, 0, 0, 0);
+    // Frames the CPU's queue has no room for pass where they are.
+    asm
+      .emit(MOV_IMM, R3, 0, 0, XDP_PASS as i32)
+      .emit(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP)
+      .emit(EXIT, 0, 0, 0, 0);
+    asm.finish()
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    const XDP_REDIRECT: u32 = 4;
+
+    /// Ethernet frame with a UDP datagram to `port` carrying `payload`.
+    fn frame(port: u16, payload: &[u8]) -> Vec<u8> {
+      let mut frame = vec![0; 12];
+      frame.extend([0x08, 0x00, 0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+      frame.extend([0x30, 0x39]);
+      frame.extend(port.to_be_bytes());
+      frame.extend(((8 + payload.len()) as u16).to_be_bytes());
+      frame.extend([0, 0]);
+      frame.extend_from_slice(payload);
+      frame
+    }
+
+    fn session(id: u64, len: usize) -> Vec<u8> {
+      let mut datagram = id.to_be_bytes().to_vec();
+      datagram.resize(len, 0x5a);
+      datagram
+    }
+
+    /// Loads the program, or returns `None` where this process may not load BPF programs.
+    fn load(cpus: &[usize]) -> Option<Program> {
+      match Program::load(8050, cpus, 64) {
+        Ok(program) => Some(program),
+        Err(e) if e.chain().any(|e| e.to_string().contains("Operation not permitted")) => None,
+        Err(e) => panic!("{:?}", e),
+      }
+    }
+
+    #[test]
+    fn test_verdicts() {
+      let Some(program) = load(&[0]) else {
+        return;
+      };
+      let verdict = |frame: &[u8]| program.test_run(frame, 1).unwrap().0;
+
+      assert_eq!(verdict(&frame(8050, &session(0x1234, 64))), XDP_REDIRECT);
+      assert_eq!(verdict(&frame(8050, &session(0x1234, 36))), XDP_REDIRECT);
+      assert_eq!(verdict(&frame(8050, &session(0x1234, 35))), XDP_DROP);
+      assert_eq!(verdict(&frame(8050, b"short")), XDP_DROP);
+      assert_eq!(verdict(&frame(8050, &session(HANDSHAKE_SESSION, 12))), XDP_PASS);
+      assert_eq!(verdict(&frame(8050, &session(RENDEZVOUS_SESSION, 12))), XDP_PASS);
+      assert_eq!(verdict(&frame(8051, b"short")), XDP_PASS);
+
+      let mut fragment = frame(8050, b"short");
+      fragment[20] = 0x20;
+      assert_eq!(verdict(&fragment), XDP_PASS);
+      let mut ipv6 = frame(8050, b"short");
+      ipv6[12..14].copy_from_slice(&[0x86, 0xdd]);
+      assert_eq!(verdict(&ipv6), XDP_PASS);
+      assert_eq!(verdict(&frame(8050, b"short")[..30]), XDP_PASS);
+    }
+
+    #[test]
+    fn test_attach() {
+      let Some(program) = load(&[0]) else {
+        return;
+      };
+      let attachment = program.attach("lo").unwrap();
+      drop(attachment);
+      assert!(load(&[0]).unwrap().attach("no-such-interface").is_err());
+    }
+  }
+}
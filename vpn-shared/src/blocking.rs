@@ -0,0 +1,245 @@
+//! Driver of `protocol::Connection` on plain threads, for embedders whose applications don't run Tokio.
+//! Those running smol or async-std have `nonblocking` instead.
+
+use std::io;
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use tracing::error;
+use tracing::trace;
+
+use crate::packet::ErrorCode;
+use crate::packet::MAX_DATAGRAM_SIZE;
+use crate::protocol::Connection;
+use crate::protocol::ConnectionConfig;
+use crate::protocol::Event;
+
+/// Datagrams to and from the server.
+pub trait Transport: Send + Sync {
+  fn send(&self, datagram: &[u8]) -> io::Result<()>;
+  fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// A socket connected to the server.
+impl Transport for UdpSocket {
+  fn send(&self, datagram: &[u8]) -> io::Result<()> {
+    UdpSocket::send(self, datagram).map(drop)
+  }
+
+  fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    UdpSocket::recv(self, buf)
+  }
+}
+
+/// IP packets in and out of the tunnel, e.g. a tun device or the embedder's own network stack.
+pub trait TunDevice: Send + Sync {
+  fn send(&self, packet: &[u8]) -> io::Result<()>;
+  fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+enum Input {
+  Datagram(Vec<u8>),
+  Packet(Vec<u8>),
+}
+
+/// Connects and forwards packets until the session ends, handing every event but `Data` to `on_event`.
+/// Returns the code and reason the session ended with, see `Event::Closed`. Reader threads are left to
+/// end on the next read or error after that.
+pub fn run<S: Transport + 'static, T: TunDevice + 'static>(
+  transport: Arc<S>,
+  tun: Arc<T>,
+  mtu: u16,
+  config: ConnectionConfig,
+  mut on_event: impl FnMut(&Event),
+) -> anyhow::Result<(Option<ErrorCode>, String)> {
+  let mut connection = Connection::new(config, Instant::now())?;
+  let (tx, rx) = mpsc::channel();
+
+  let reader = transport.clone();
+  let datagrams = tx.clone();
+  thread::spawn(move || {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    while let Ok(len) = reader.recv(&mut buf) {
+      if datagrams.send(Input::Datagram(buf[..len].to_vec())).is_err() {
+        break;
+      }
+    }
+  });
+
+  let reader = tun.clone();
+  thread::spawn(move || {
+    let mut buf = vec![0u8; mtu as usize];
+    while let Ok(len) = reader.recv(&mut buf) {
+      if tx.send(Input::Packet(buf[..len].to_vec())).is_err() {
+        break;
+      }
+    }
+  });
+
+  loop {
+    while let Some(datagram) = connection.poll_transmit() {
+      transport.send(&datagram)?;
+    }
+
+    while let Some(event) = connection.poll_event() {
+      match event {
+        Event::Data(packet) => {
+          if let Err(e) = tun.send(&packet) {
+            error!("Failed to write to tun: {}", e);
+          }
+        }
+        Event::Closed { code, reason } => {
+          on_event(&Event::Closed { code, reason: reason.clone() });
+          return Ok((code, reason));
+        }
+        event => on_event(&event),
+      }
+    }
+
+    let Some(timeout) = connection.poll_timeout() else {
+      anyhow::bail!("Session closed");
+    };
+    match rx.recv_timeout(timeout.saturating_duration_since(Instant::now())) {
+      Ok(Input::Datagram(datagram)) => {
+        let established = connection.is_established();
+        match connection.handle_datagram(Instant::now(), &datagram) {
+          Err(e) if !established => return Err(e),
+          Err(e) => trace!("Dropping datagram from server: {}", e),
+          Ok(()) => {}
+        }
+      }
+      // Packets read before the session is established have nowhere to go yet.
+      Ok(Input::Packet(packet)) if connection.is_established() => {
+        if let Some(datagram) = connection.seal_data(Instant::now(), packet)? {
+          transport.send(&datagram)?;
+        }
+      }
+      Ok(Input::Packet(_)) => {}
+      Err(mpsc::RecvTimeoutError::Timeout) => connection.handle_timeout(Instant::now()),
+      Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("Transport and tun device both stopped"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+  use std::time::Duration;
+
+  use super::*;
+  use crate::creds::Credentials;
+  use crate::packet::ClientPacket;
+  use crate::packet::EncryptedPacket;
+  use crate::packet::Features;
+  use crate::packet::ServerPacket;
+  use crate::packet::KEY_SIZE;
+  use crate::peer::PeerEncryption;
+  use crate::protocol::Accepted;
+  use crate::protocol::ClientAuth;
+  use crate::protocol::Offer;
+  use crate::protocol::ServerHandshake;
+  use crate::transform::Registry;
+
+  /// One end of an in-memory link.
+  struct Pipe {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+  }
+
+  fn pipe() -> (Pipe, Pipe) {
+    let (a_tx, a_rx) = mpsc::channel();
+    let (b_tx, b_rx) = mpsc::channel();
+    (Pipe { tx: a_tx, rx: Mutex::new(b_rx) }, Pipe { tx: b_tx, rx: Mutex::new(a_rx) })
+  }
+
+  impl Pipe {
+    fn next(&self) -> Vec<u8> {
+      self.rx.lock().unwrap().recv_timeout(Duration::from_secs(2)).unwrap()
+    }
+  }
+
+  impl Transport for Pipe {
+    fn send(&self, datagram: &[u8]) -> io::Result<()> {
+      self.tx.send(datagram.to_vec()).map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+      let datagram =
+        self.rx.lock().unwrap().recv().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+      buf[..datagram.len()].copy_from_slice(&datagram);
+      Ok(datagram.len())
+    }
+  }
+
+  impl TunDevice for Pipe {
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+      Transport::send(self, packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+      Transport::recv(self, buf)
+    }
+  }
+
+  #[test]
+  fn test_run() {
+    let (client, server) = pipe();
+    let (tun, host) = pipe();
+    let config = ConnectionConfig {
+      auth: ClientAuth::Credentials(Credentials::new("alice", "secret")),
+      server_public_key: None,
+      transforms: Registry::default(),
+      offered_transforms: Vec::new(),
+      handshake_timeout: Duration::from_secs(2),
+      mtu_probe: None,
+      subnets: Vec::new(),
+      reverse_forwards: Vec::new(),
+      peer_encryption: PeerEncryption::Off,
+      hostname: None,
+    };
+    let driver = thread::spawn(move || {
+      let mut events = Vec::new();
+      let closed =
+        run(Arc::new(client), Arc::new(tun), 1500, config, |event| events.push(format!("{:?}", event)));
+      (closed.unwrap(), events)
+    });
+
+    let Ok(ClientPacket::KeyExchange { key, transforms, features, limits, .. }) =
+      EncryptedPacket::from_bytes(&server.next()).unwrap().decrypt(&[0u8; KEY_SIZE])
+    else {
+      panic!("Expected a key exchange");
+    };
+    let registry = Registry::default();
+    let handshake = ServerHandshake {
+      transforms: &registry,
+      accepted_transforms: &[],
+      static_key: None,
+      features: Features::SUPPORTED,
+    };
+    let Accepted { session, reply, .. } = handshake
+      .accept(&key, &transforms, Offer { features, limits }, "127.0.0.1:6969".parse().unwrap(), 42)
+      .unwrap();
+    Transport::send(&server, &reply).unwrap();
+    assert!(matches!(session.pipeline.open(&session.key, &server.next()), Ok(ClientPacket::Auth(_))));
+
+    let seal = |packet: &ServerPacket| session.pipeline.seal(&session.key, session.id, packet).unwrap();
+    Transport::send(&server, &seal(&ServerPacket::AuthOk)).unwrap();
+    assert!(matches!(session.pipeline.open(&session.key, &server.next()), Ok(ClientPacket::Ping)));
+
+    Transport::send(&server, &seal(&ServerPacket::Data(vec![0x45, 1]))).unwrap();
+    assert_eq!(host.next(), [0x45, 1]);
+    Transport::send(&host, &[0x45, 2]).unwrap();
+    assert!(
+      matches!(session.pipeline.open(&session.key, &server.next()), Ok(ClientPacket::Data(d)) if d == [0x45, 2])
+    );
+
+    let disconnect = ServerPacket::Disconnect { code: ErrorCode::Kicked, reason: "bye".into() };
+    Transport::send(&server, &seal(&disconnect)).unwrap();
+    let ((code, reason), events) = driver.join().unwrap();
+    assert_eq!((code, reason.as_str()), (Some(ErrorCode::Kicked), "bye"));
+    assert_eq!(events[0], "Established");
+  }
+}
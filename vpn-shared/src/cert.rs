@@ -0,0 +1,124 @@
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub use ed25519_dalek::SigningKey;
+pub use ed25519_dalek::VerifyingKey;
+
+use ed25519_dalek::Signature;
+use ed25519_dalek::Signer;
+use ed25519_dalek::Verifier;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::handshake;
+use crate::limits;
+use crate::packet::fill_random_bytes;
+use crate::packet::Key;
+
+/// Binds a username to a client's static key, signed by the server's CA. The client proves it holds the
+/// private half the same way as with key authentication.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Certificate {
+  pub serial: u64,
+  pub username: String,
+  pub public_key: Key,
+  /// Validity window in seconds since the Unix epoch.
+  pub not_before: u64,
+  pub not_after: u64,
+  signature: Vec<u8>,
+}
+
+impl Certificate {
+  /// Fails for a `ttl` that would put the expiry past what a timestamp holds.
+  pub fn issue(ca: &SigningKey, username: &str, public_key: Key, ttl: Duration) -> anyhow::Result<Self> {
+    let mut serial = [0u8; 8];
+    fill_random_bytes(&mut serial);
+
+    let now = unix_time(SystemTime::now());
+    let mut certificate = Self {
+      serial: u64::from_be_bytes(serial),
+      username: username.to_string(),
+      public_key,
+      not_before: now,
+      not_after: now
+        .checked_add(ttl.as_secs())
+        .ok_or_else(|| anyhow::anyhow!("Certificate TTL too large"))?,
+      signature: Vec::new(),
+    };
+    certificate.signature = ca.sign(&certificate.signed_bytes()).to_vec();
+    Ok(certificate)
+  }
+
+  pub fn verify(&self, ca: &VerifyingKey, now: SystemTime) -> anyhow::Result<()> {
+    let signature = Signature::from_slice(&self.signature)?;
+    if ca.verify(&self.signed_bytes(), &signature).is_err() {
+      anyhow::bail!("Certificate not signed by the CA");
+    }
+
+    let now = unix_time(now);
+    if now < self.not_before {
+      anyhow::bail!("Certificate not yet valid");
+    }
+    if now >= self.not_after {
+      anyhow::bail!("Certificate expired");
+    }
+    Ok(())
+  }
+
+  /// Expiry as a `SystemTime`; certificates signed with a `not_after` past what the platform holds expire
+  /// in 2106.
+  pub fn expires_at(&self) -> SystemTime {
+    let cap = || UNIX_EPOCH + Duration::from_secs(u32::MAX.into());
+    UNIX_EPOCH.checked_add(Duration::from_secs(self.not_after)).unwrap_or_else(cap)
+  }
+
+  pub fn encode(&self) -> String {
+    handshake::encode_hex(&bincode::serialize(self).expect("certificates are serializable"))
+  }
+
+  pub fn decode(s: &str) -> anyhow::Result<Self> {
+    limits::decode(&handshake::parse_hex(s)?)
+  }
+
+  fn signed_bytes(&self) -> Vec<u8> {
+    let fields = (self.serial, &self.username, &self.public_key, self.not_before, self.not_after);
+    bincode::serialize(&fields).expect("certificates are serializable")
+  }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_issue_and_verify() {
+    let ca = SigningKey::from_bytes(&[1u8; 32]);
+    let certificate = Certificate::issue(&ca, "alice", [2u8; 32], Duration::from_secs(3600)).unwrap();
+    let now = SystemTime::now();
+
+    let decoded = Certificate::decode(&certificate.encode()).unwrap();
+    assert_eq!(decoded, certificate);
+    decoded.verify(&ca.verifying_key(), now).unwrap();
+
+    let other_ca = SigningKey::from_bytes(&[3u8; 32]);
+    assert!(certificate.verify(&other_ca.verifying_key(), now).is_err());
+
+    let later = now + Duration::from_secs(7200);
+    assert_eq!(
+      certificate.verify(&ca.verifying_key(), later).unwrap_err().to_string(),
+      "Certificate expired"
+    );
+
+    let forged = Certificate { username: "admin".into(), ..certificate };
+    assert!(forged.verify(&ca.verifying_key(), now).is_err());
+
+    assert!(Certificate::issue(&ca, "alice", [2u8; 32], Duration::MAX).is_err());
+    let lasting = Certificate { not_after: u64::MAX, ..decoded };
+    assert!(lasting.expires_at() > later);
+  }
+}
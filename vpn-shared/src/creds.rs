@@ -1,8 +1,13 @@
 use std::str::FromStr;
 
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
 
+use crate::cert::Certificate;
+use crate::handshake;
+use crate::packet::Key;
+
 impl FromStr for Credentials {
   type Err = anyhow::Error;
 
@@ -13,19 +18,94 @@ impl FromStr for Credentials {
   }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
-#[serde(rename_all = "kebab-case")]
-pub struct Credentials {
-  username: String,
-  password: String,
+#[derive(Clone, Serialize, Debug, PartialEq, Eq)]
+pub enum Credentials {
+  Password {
+    username: String,
+    password: String,
+  },
+  /// Access token issued by an OpenID Connect provider.
+  Token(String),
+  /// Certificate issued by the server's CA; `proof` is `handshake::client_auth_proof` with the certificate's
+  /// key for this session, so it's never read from a config.
+  Certificate {
+    certificate: Certificate,
+    proof: Key,
+  },
+  /// Resumption ticket the server sent with `ServerPacket::Ticket`; opaque to the client.
+  Ticket(Vec<u8>),
 }
 
 impl Credentials {
   pub fn new<S: AsRef<str>>(username: S, password: S) -> Self {
-    Self { username: username.as_ref().to_string(), password: password.as_ref().to_string() }
+    Self::Password { username: username.as_ref().to_string(), password: password.as_ref().to_string() }
+  }
+
+  pub fn username(&self) -> Option<&str> {
+    match self {
+      Self::Password { username, .. } => Some(username),
+      Self::Certificate { certificate, .. } => Some(&certificate.username),
+      Self::Token(_) | Self::Ticket(_) => None,
+    }
+  }
+}
+
+/// Configs name the kind of credentials in a `type` field, but bincode can't decode internally tagged
+/// enums, so packets use the default representation.
+impl<'de> Deserialize<'de> for Credentials {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "kebab-case")]
+    enum Tagged {
+      Password { username: String, password: String },
+      Token { token: String },
+    }
+
+    #[derive(Deserialize)]
+    enum Packed {
+      Password { username: String, password: String },
+      Token(String),
+      Certificate { certificate: Certificate, proof: Key },
+      Ticket(Vec<u8>),
+    }
+
+    if !deserializer.is_human_readable() {
+      return Ok(match Packed::deserialize(deserializer)? {
+        Packed::Password { username, password } => Self::Password { username, password },
+        Packed::Token(token) => Self::Token(token),
+        Packed::Certificate { certificate, proof } => Self::Certificate { certificate, proof },
+        Packed::Ticket(ticket) => Self::Ticket(ticket),
+      });
+    }
+
+    Ok(match Tagged::deserialize(deserializer)? {
+      Tagged::Password { username, password } => Self::Password { username, password },
+      Tagged::Token { token } => Self::Token(token),
+    })
   }
+}
+
+/// Static key a client may authenticate with instead of a password.
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct KeyCredentials {
+  pub username: String,
+  #[serde(deserialize_with = "handshake::deserialize_key")]
+  pub public_key: Key,
+
+  /// Device of the user the key belongs to, e.g. `laptop`, for users connecting from several. Devices share
+  /// the policy of the user, but their data usage is counted separately and their keys are revoked one by
+  /// one.
+  #[serde(default)]
+  pub device: Option<String>,
+
+  /// Data quota of sessions with this key, instead of the one of the user's groups.
+  #[serde(default)]
+  pub quota_mb: Option<u64>,
+}
 
-  pub fn username(&self) -> &str {
-    &self.username
+impl KeyCredentials {
+  pub fn new(username: impl Into<String>, public_key: Key) -> Self {
+    Self { username: username.into(), public_key, device: None, quota_mb: None }
   }
 }
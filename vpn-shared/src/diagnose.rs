@@ -0,0 +1,169 @@
+//! Explanations of the usual startup failures, binding a socket and creating the tun device, in place of
+//! the bare OS error: which process holds the port, which capability is missing, what to change.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+  Udp,
+  Tcp,
+}
+
+impl fmt::Display for Protocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Protocol::Udp => "UDP",
+      Protocol::Tcp => "TCP",
+    })
+  }
+}
+
+/// Explains why binding `addr` failed with `error`.
+pub fn bind_error(protocol: Protocol, addr: SocketAddr, error: io::Error) -> anyhow::Error {
+  match error.kind() {
+    io::ErrorKind::AddrInUse => {
+      let holder = match port_holder(protocol, addr.port()) {
+        Some((pid, name)) => format!("PID {} ({})", pid, name),
+        None => "another process".to_string(),
+      };
+      anyhow::anyhow!(
+        "{} {} is already in use by {}; stop it or configure another port",
+        protocol,
+        addr,
+        holder
+      )
+    }
+    io::ErrorKind::PermissionDenied if addr.port() < 1024 => anyhow::anyhow!(
+      "Binding {} {} needs root or the CAP_NET_BIND_SERVICE capability, e.g. `setcap cap_net_bind_service+ep` \
+       on the binary; or configure a port from 1024 up",
+      protocol,
+      addr
+    ),
+    io::ErrorKind::AddrNotAvailable => anyhow::anyhow!(
+      "Can't bind {} {}: the address isn't assigned to any interface of this machine",
+      protocol,
+      addr
+    ),
+    _ => anyhow::anyhow!("Failed to bind {} {}: {}", protocol, addr, error),
+  }
+}
+
+/// Explains why creating a tun device failed with `error`.
+pub fn tun_error(error: tun::Error) -> anyhow::Error {
+  let tun::Error::Io(error) = error else {
+    return match error {
+      tun::Error::NameTooLong => anyhow::anyhow!("Tun device name is too long; use at most 15 characters"),
+      error => anyhow::anyhow!("Failed to create the tun device: {}", error),
+    };
+  };
+
+  match (error.kind(), error.raw_os_error()) {
+    (io::ErrorKind::PermissionDenied, _) if cfg!(target_os = "linux") => anyhow::anyhow!(
+      "Creating a tun device needs root or the CAP_NET_ADMIN capability, e.g. `setcap cap_net_admin+ep` on \
+       the binary or `AmbientCapabilities=CAP_NET_ADMIN` in its systemd unit"
+    ),
+    (io::ErrorKind::PermissionDenied, _) => {
+      anyhow::anyhow!("Creating a tun device needs administrator privileges: {}", error)
+    }
+    (io::ErrorKind::NotFound, _) if cfg!(target_os = "linux") => anyhow::anyhow!(
+      "/dev/net/tun is missing; load the module with `modprobe tun`, or pass the device into the container"
+    ),
+    #[cfg(target_os = "linux")]
+    (_, Some(libc::EBUSY)) => anyhow::anyhow!(
+      "The tun device is already in use by another process; configure another tun name, or a template such \
+       as vpn%d"
+    ),
+    _ => anyhow::anyhow!("Failed to create the tun device: {}", error),
+  }
+}
+
+/// Process listening on `port`, if it can be found out, which needs root for the processes of other users.
+#[cfg(target_os = "linux")]
+fn port_holder(protocol: Protocol, port: u16) -> Option<(u32, String)> {
+  let tables: &[&str] = match protocol {
+    Protocol::Udp => &["/proc/net/udp", "/proc/net/udp6"],
+    Protocol::Tcp => &["/proc/net/tcp", "/proc/net/tcp6"],
+  };
+  let inodes: Vec<u64> = tables
+    .iter()
+    .filter_map(|path| std::fs::read_to_string(path).ok())
+    .flat_map(|table| socket_inodes(&table, protocol, port))
+    .collect();
+  if inodes.is_empty() {
+    return None;
+  }
+
+  for process in std::fs::read_dir("/proc").ok()?.flatten() {
+    let Some(pid) = process.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()) else {
+      continue;
+    };
+    let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+      continue;
+    };
+    let holds = fds.flatten().filter_map(|fd| std::fs::read_link(fd.path()).ok()).any(|target| {
+      let target = target.to_string_lossy();
+      let inode = target.strip_prefix("socket:[").and_then(|inode| inode.strip_suffix(']'));
+      inode.and_then(|inode| inode.parse().ok()).is_some_and(|inode| inodes.contains(&inode))
+    });
+    if holds {
+      let name = std::fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+      return Some((pid, name.trim().to_string()));
+    }
+  }
+  None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_holder(_protocol: Protocol, _port: u16) -> Option<(u32, String)> {
+  None
+}
+
+/// Inodes of the sockets bound to `port` in a `/proc/net` table; only listening ones for TCP.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn socket_inodes(table: &str, protocol: Protocol, port: u16) -> Vec<u64> {
+  const TCP_LISTEN: &str = "0A";
+  table
+    .lines()
+    .skip(1)
+    .filter_map(|line| {
+      let fields: Vec<_> = line.split_whitespace().collect();
+      let local_port = fields.get(1)?.rsplit_once(':')?.1;
+      let listening = protocol == Protocol::Udp || *fields.get(3)? == TCP_LISTEN;
+      if u16::from_str_radix(local_port, 16).ok()? != port || !listening {
+        return None;
+      }
+      fields.get(9)?.parse().ok()
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_socket_inodes() {
+    let table = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:25D0 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 4242 1
+   1: 0100007F:25D0 0100007F:D431 01 00000000:00000000 00:00000000 00000000     0        0 4343 1
+   2: 00000000:1B39 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 4444 1
+";
+    assert_eq!(socket_inodes(table, Protocol::Tcp, 9680), vec![4242]);
+    assert_eq!(socket_inodes(table, Protocol::Udp, 9680), vec![4242, 4343]);
+    assert!(socket_inodes(table, Protocol::Tcp, 9696).is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_port_in_use() {
+    let taken = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = taken.local_addr().unwrap();
+    let error = tokio::net::UdpSocket::bind(addr).await.unwrap_err();
+    let message = bind_error(Protocol::Udp, addr, error).to_string();
+    assert!(message.starts_with(&format!("UDP {} is already in use by ", addr)), "{}", message);
+    #[cfg(target_os = "linux")]
+    assert!(message.contains(&format!("PID {} ", std::process::id())), "{}", message);
+  }
+}
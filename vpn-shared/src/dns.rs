@@ -0,0 +1,388 @@
+use std::net::Ipv4Addr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const FLAG_TRUNCATED: u16 = 0x0200;
+
+/// Compression pointers followed while reading one name, more than any real message needs.
+const MAX_POINTERS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+  A(Ipv4Addr),
+  Ptr(String),
+  Srv { priority: u16, weight: u16, port: u16, target: String },
+  Txt(Vec<String>),
+  Other(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+  /// Without the trailing dot; compare case-insensitively.
+  pub name: String,
+  pub ttl: u32,
+  pub data: RecordData,
+}
+
+impl RecordData {
+  fn record_type(&self) -> u16 {
+    match self {
+      Self::A(_) => TYPE_A,
+      Self::Ptr(_) => TYPE_PTR,
+      Self::Srv { .. } => TYPE_SRV,
+      Self::Txt(_) => TYPE_TXT,
+      Self::Other(record_type) => *record_type,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+  pub name: String,
+  pub record_type: u16,
+}
+
+/// Parsed message; only what resolving a name and answering mDNS queries need.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+  pub id: u16,
+  pub response: bool,
+  pub truncated: bool,
+  pub rcode: u8,
+  pub questions: Vec<Question>,
+  pub answers: Vec<Record>,
+  pub additional: Vec<Record>,
+}
+
+/// Recursive query for `name` records of `record_type`.
+pub fn query(id: u16, name: &str, record_type: u16) -> anyhow::Result<Vec<u8>> {
+  let mut message = Vec::with_capacity(12 + name.len() + 6);
+  for field in [id, FLAG_RECURSION_DESIRED, 1, 0, 0, 0] {
+    message.extend(field.to_be_bytes());
+  }
+  write_name(&mut message, name)?;
+  message.extend(record_type.to_be_bytes());
+  message.extend(CLASS_IN.to_be_bytes());
+  Ok(message)
+}
+
+/// Authoritative response, as mDNS responders send; names aren't compressed.
+pub fn response(id: u16, answers: &[Record], additional: &[Record]) -> anyhow::Result<Vec<u8>> {
+  let mut message = Vec::new();
+  for field in [id, FLAG_RESPONSE | FLAG_AUTHORITATIVE, 0, answers.len() as u16, 0, additional.len() as u16] {
+    message.extend(field.to_be_bytes());
+  }
+  for record in answers.iter().chain(additional) {
+    write_record(&mut message, record)?;
+  }
+  Ok(message)
+}
+
+/// Answer to `query` as a unicast server sends it, with the questions repeated and `rcode`; names aren't
+/// compressed.
+pub fn answer(query: &Message, rcode: u8, answers: &[Record]) -> anyhow::Result<Vec<u8>> {
+  let flags = FLAG_RESPONSE
+    | FLAG_AUTHORITATIVE
+    | FLAG_RECURSION_DESIRED
+    | FLAG_RECURSION_AVAILABLE
+    | (rcode & 0xf) as u16;
+  let mut message = Vec::new();
+  for field in [query.id, flags, query.questions.len() as u16, answers.len() as u16, 0, 0] {
+    message.extend(field.to_be_bytes());
+  }
+  for question in &query.questions {
+    write_name(&mut message, &question.name)?;
+    message.extend(question.record_type.to_be_bytes());
+    message.extend(CLASS_IN.to_be_bytes());
+  }
+  for record in answers {
+    write_record(&mut message, record)?;
+  }
+  Ok(message)
+}
+
+fn write_record(message: &mut Vec<u8>, record: &Record) -> anyhow::Result<()> {
+  write_name(message, &record.name)?;
+  message.extend(record.data.record_type().to_be_bytes());
+  message.extend(CLASS_IN.to_be_bytes());
+  message.extend(record.ttl.to_be_bytes());
+
+  let mut data = Vec::new();
+  match &record.data {
+    RecordData::A(address) => data.extend(address.octets()),
+    RecordData::Ptr(name) => write_name(&mut data, name)?,
+    RecordData::Srv { priority, weight, port, target } => {
+      for field in [priority, weight, port] {
+        data.extend(field.to_be_bytes());
+      }
+      write_name(&mut data, target)?;
+    }
+    RecordData::Txt(strings) => {
+      for string in strings {
+        let len =
+          u8::try_from(string.len()).map_err(|_| anyhow::anyhow!("TXT string {} is too long", string))?;
+        data.push(len);
+        data.extend(string.as_bytes());
+      }
+    }
+    RecordData::Other(record_type) => anyhow::bail!("Can't write records of type {}", record_type),
+  }
+  message.extend((data.len() as u16).to_be_bytes());
+  message.extend(data);
+  Ok(())
+}
+
+/// Whether `label` can be a single label of a hostname: letters, digits and inner hyphens.
+pub fn is_hostname_label(label: &str) -> bool {
+  (1..=63).contains(&label.len())
+    && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    && !label.starts_with('-')
+    && !label.ends_with('-')
+}
+
+fn write_name(message: &mut Vec<u8>, name: &str) -> anyhow::Result<()> {
+  for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+    if label.len() > 63 {
+      anyhow::bail!("Label {} of {} is longer than 63 bytes", label, name);
+    }
+    message.push(label.len() as u8);
+    message.extend(label.as_bytes());
+  }
+  message.push(0);
+  Ok(())
+}
+
+impl Message {
+  pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+    let mut questions = Vec::new();
+    for _ in 0..counts[0] {
+      questions.push(Question { name: reader.name()?, record_type: reader.u16()? });
+      reader.u16()?;
+    }
+    let answers = (0..counts[1]).map(|_| reader.record()).collect::<anyhow::Result<_>>()?;
+    for _ in 0..counts[2] {
+      reader.record()?;
+    }
+    let additional = (0..counts[3]).map(|_| reader.record()).collect::<anyhow::Result<_>>()?;
+
+    Ok(Self {
+      id,
+      response: flags & FLAG_RESPONSE != 0,
+      truncated: flags & FLAG_TRUNCATED != 0,
+      rcode: (flags & 0xf) as u8,
+      questions,
+      answers,
+      additional,
+    })
+  }
+
+  /// Answers and additional records, which servers use to send the addresses of SRV targets along.
+  pub fn records(&self) -> impl Iterator<Item = &Record> {
+    self.answers.iter().chain(&self.additional)
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+    let bytes = self.bytes.get(self.pos..self.pos + len).ok_or(anyhow::anyhow!("Truncated DNS message"))?;
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  fn u16(&mut self) -> anyhow::Result<u16> {
+    Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+  }
+
+  fn u32(&mut self) -> anyhow::Result<u32> {
+    Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+  }
+
+  fn name(&mut self) -> anyhow::Result<String> {
+    let mut labels = Vec::new();
+    let mut pos = self.pos;
+    let mut end = None;
+
+    for _ in 0..MAX_POINTERS {
+      loop {
+        let len = *self.bytes.get(pos).ok_or(anyhow::anyhow!("Truncated DNS name"))? as usize;
+        match len {
+          0 => {
+            self.pos = end.unwrap_or(pos + 1);
+            return Ok(labels.join("."));
+          }
+          len if len & 0xc0 == 0xc0 => {
+            let low = *self.bytes.get(pos + 1).ok_or(anyhow::anyhow!("Truncated DNS name"))? as usize;
+            end.get_or_insert(pos + 2);
+            pos = (len & 0x3f) << 8 | low;
+            break;
+          }
+          len => {
+            let label =
+              self.bytes.get(pos + 1..pos + 1 + len).ok_or(anyhow::anyhow!("Truncated DNS name"))?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+          }
+        }
+      }
+    }
+    anyhow::bail!("Too many compression pointers in a DNS name")
+  }
+
+  fn record(&mut self) -> anyhow::Result<Record> {
+    let name = self.name()?;
+    let record_type = self.u16()?;
+    self.u16()?;
+    let ttl = self.u32()?;
+    let len = self.u16()? as usize;
+    let end = self.pos + len;
+
+    let data = match record_type {
+      TYPE_A => RecordData::A(<[u8; 4]>::try_from(self.take(len)?)?.into()),
+      TYPE_PTR => RecordData::Ptr(self.name()?),
+      TYPE_SRV => RecordData::Srv {
+        priority: self.u16()?,
+        weight: self.u16()?,
+        port: self.u16()?,
+        target: self.name()?,
+      },
+      TYPE_TXT => {
+        let mut strings = Vec::new();
+        while self.pos < end {
+          let len = self.take(1)?[0] as usize;
+          strings.push(String::from_utf8_lossy(self.take(len)?).into_owned());
+        }
+        RecordData::Txt(strings)
+      }
+      other => RecordData::Other(other),
+    };
+    if self.pos > end {
+      anyhow::bail!("Malformed {} record for {}", record_type, name);
+    }
+    self.pos = end;
+
+    Ok(Record { name, ttl, data })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_query() {
+    let query = query(0x1234, "_vpn._udp.Example.com.", TYPE_SRV).unwrap();
+    assert_eq!(&query[..4], [0x12, 0x34, 0x01, 0x00]);
+    assert_eq!(&query[12..], b"\x04_vpn\x04_udp\x07Example\x03com\x00\x00\x21\x00\x01");
+    assert!(super::query(1, &format!("{}.com", "a".repeat(64)), TYPE_A).is_err());
+  }
+
+  #[test]
+  fn test_parse() {
+    let mut response = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 1];
+    response.extend(b"\x04_vpn\x04_udp\x07example\x03com\x00\x00\x21\x00\x01");
+    // SRV 10 5 9696 vpn1.example.com, the name pointing at the question.
+    response.extend([0xc0, 12, 0, 33, 0, 1, 0, 0, 0x0e, 0x10, 0, 13, 0, 10, 0, 5, 0x25, 0xe0]);
+    response.extend(b"\x04vpn1\xc0\x16");
+    response.extend([0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 11]);
+    response.extend(b"\x04key=\x05a b c");
+    response.extend([0xc0, 57, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 5]);
+
+    let message = Message::parse(&response).unwrap();
+    assert_eq!(message.id, 0x1234);
+    assert!(message.response && !message.truncated);
+    assert_eq!(message.questions, [Question { name: "_vpn._udp.example.com".into(), record_type: TYPE_SRV }]);
+    assert_eq!(
+      message.answers,
+      [
+        Record {
+          name: "_vpn._udp.example.com".into(),
+          ttl: 3600,
+          data: RecordData::Srv { priority: 10, weight: 5, port: 9696, target: "vpn1.example.com".into() },
+        },
+        Record {
+          name: "_vpn._udp.example.com".into(),
+          ttl: 60,
+          data: RecordData::Txt(vec!["key=".into(), "a b c".into()]),
+        },
+      ]
+    );
+    assert_eq!(message.additional[0].name, "vpn1.example.com");
+    assert_eq!(message.additional[0].data, RecordData::A(Ipv4Addr::new(203, 0, 113, 5)));
+
+    assert!(Message::parse(&response[..response.len() - 2]).is_err());
+  }
+
+  #[test]
+  fn test_response() {
+    let record = |name: &str, data| Record { name: name.into(), ttl: 120, data };
+    let answers = [record("_vpn._udp.local", RecordData::Ptr("Office VPN._vpn._udp.local".into()))];
+    let additional = [
+      record(
+        "office vpn._vpn._udp.local",
+        RecordData::Srv { priority: 0, weight: 0, port: 9696, target: "gw.local".into() },
+      ),
+      record("office vpn._vpn._udp.local", RecordData::Txt(vec!["key=01".into()])),
+      record("gw.local", RecordData::A(Ipv4Addr::new(192, 168, 1, 10))),
+    ];
+
+    let message = Message::parse(&response(7, &answers, &additional).unwrap()).unwrap();
+    assert!(message.response);
+    assert_eq!(message.id, 7);
+    assert_eq!(message.answers[0].data, RecordData::Ptr("Office VPN._vpn._udp.local".into()));
+    assert_eq!(message.additional, additional);
+    assert!(response(0, &[record("x.local", RecordData::Other(99))], &[]).is_err());
+  }
+
+  #[test]
+  fn test_answer() {
+    let query = Message::parse(&query(9, "laptop.vpn.internal", TYPE_A).unwrap()).unwrap();
+    let record =
+      Record { name: "laptop.vpn.internal".into(), ttl: 60, data: RecordData::A(Ipv4Addr::new(10, 8, 0, 2)) };
+    let message =
+      Message::parse(&answer(&query, RCODE_NOERROR, std::slice::from_ref(&record)).unwrap()).unwrap();
+    assert!(message.response);
+    assert_eq!((message.id, message.rcode), (9, RCODE_NOERROR));
+    assert_eq!(message.questions, query.questions);
+    assert_eq!(message.answers, [record]);
+
+    let message = Message::parse(&answer(&query, RCODE_NXDOMAIN, &[]).unwrap()).unwrap();
+    assert_eq!(message.rcode, RCODE_NXDOMAIN);
+    assert!(message.answers.is_empty());
+
+    assert!(is_hostname_label("laptop-2"));
+    for label in ["", "-laptop", "laptop.home", "ноутбук", &"a".repeat(64)] {
+      assert!(!is_hostname_label(label), "{}", label);
+    }
+  }
+
+  #[test]
+  fn test_pointer_loop() {
+    let mut response = vec![0, 0, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+    response.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 1, 1, 1]);
+    assert!(Message::parse(&response).is_err());
+  }
+}
@@ -0,0 +1,323 @@
+//! ECN across the tunnel after RFC 6040: the sender copies the inner packet's ECN field into the outer IP
+//! header of the datagram carrying it, and the receiver carries congestion marks set on the way back into
+//! the inner packet. Outer headers are only read and written on Linux; elsewhere datagrams go out as
+//! Not-ECT and arrive unmarked.
+//!
+//! The same control messages carry the local address datagrams arrive on, see `enable_pktinfo`.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+
+use crate::ip;
+
+/// Asks the kernel to report the ECN field of received datagrams.
+pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+  sys::enable(socket)
+}
+
+/// Asks the kernel to report the local address received datagrams were sent to. On a socket bound to the
+/// unspecified address of a multi-homed host, replies have to leave from that address: one picked by the
+/// routing table may not be the one the peer expects, and its firewall or NAT drops them.
+pub fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+  sys::enable_pktinfo(socket)
+}
+
+/// Sends `buf` with `ecn` in the outer header.
+pub async fn send_to(socket: &UdpSocket, buf: &[u8], addr: SocketAddr, ecn: u8) -> io::Result<usize> {
+  send_from(socket, buf, addr, ecn, None).await
+}
+
+/// Like `send_to`, with `source` as the source address if given, see `enable_pktinfo`.
+pub async fn send_from(
+  socket: &UdpSocket,
+  buf: &[u8],
+  addr: SocketAddr,
+  ecn: u8,
+  source: Option<Ipv4Addr>,
+) -> io::Result<usize> {
+  if ecn == ip::ECN_NOT_ECT && source.is_none() {
+    return socket.send_to(buf, addr).await;
+  }
+  sys::send_to(socket, buf, addr, ecn, source).await
+}
+
+/// Like `UdpSocket::recv_from`, also returning the ECN field of the outer header.
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u8)> {
+  let (len, addr, ecn, _) = sys::recv_from(socket, buf).await?;
+  Ok((len, addr, ecn))
+}
+
+/// Like `recv_from`, also returning the local address the datagram was sent to if `enable_pktinfo` was
+/// called on the socket.
+pub async fn recv_from_to(
+  socket: &UdpSocket,
+  buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, u8, Option<Ipv4Addr>)> {
+  sys::recv_from(socket, buf).await
+}
+
+/// ECN field for the outer header of a datagram carrying `inner`.
+pub fn encapsulate(inner: &[u8]) -> u8 {
+  ip::ipv4_ecn(inner).unwrap_or(ip::ECN_NOT_ECT)
+}
+
+/// Applies the outer header's ECN field to a received inner packet; returns false if the packet has to be
+/// dropped because it was marked on the way but can't carry the mark.
+pub fn decapsulate(inner: &mut [u8], outer: u8) -> bool {
+  if outer != ip::ECN_CE {
+    return true;
+  }
+  match ip::ipv4_ecn(inner) {
+    Some(ip::ECN_NOT_ECT) => false,
+    Some(_) => ip::mark_congestion(inner),
+    None => true,
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+  use std::io;
+  use std::mem::size_of;
+  use std::net::Ipv4Addr;
+  use std::net::SocketAddr;
+  use std::os::fd::AsRawFd;
+
+  use tokio::io::Interest;
+  use tokio::net::UdpSocket;
+
+  // Room for the TOS and packet info control messages, 8-byte aligned as cmsghdr requires.
+  type ControlBuf = [u64; 8];
+
+  fn check(result: isize) -> io::Result<usize> {
+    if result < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(result as usize)
+  }
+
+  pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+    set_option(socket, libc::IP_RECVTOS)
+  }
+
+  pub fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+    set_option(socket, libc::IP_PKTINFO)
+  }
+
+  fn set_option(socket: &UdpSocket, option: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let result = unsafe {
+      libc::setsockopt(
+        socket.as_raw_fd(),
+        libc::IPPROTO_IP,
+        option,
+        &on as *const _ as *const libc::c_void,
+        size_of::<libc::c_int>() as libc::socklen_t,
+      )
+    };
+    check(result as isize).map(drop)
+  }
+
+  pub async fn send_to(
+    socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    ecn: u8,
+    source: Option<Ipv4Addr>,
+  ) -> io::Result<usize> {
+    let SocketAddr::V4(v4) = addr else {
+      return socket.send_to(buf, addr).await;
+    };
+
+    socket
+      .async_io(Interest::WRITABLE, || {
+        let mut name = libc::sockaddr_in {
+          sin_family: libc::AF_INET as libc::sa_family_t,
+          sin_port: v4.port().to_be(),
+          sin_addr: libc::in_addr { s_addr: u32::from(*v4.ip()).to_be() },
+          sin_zero: [0; 8],
+        };
+        let mut iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut control: ControlBuf = [0; 8];
+
+        unsafe {
+          let mut msg: libc::msghdr = std::mem::zeroed();
+          msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+          msg.msg_namelen = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+          msg.msg_iov = &mut iov;
+          msg.msg_iovlen = 1;
+          msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+          msg.msg_controllen = size_of::<ControlBuf>() as _;
+
+          // Control messages are only added when needed: an IP_TOS one replaces the socket's TOS byte.
+          let mut len = 0;
+          let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+          if ecn != crate::ip::ECN_NOT_ECT {
+            (*cmsg).cmsg_level = libc::IPPROTO_IP;
+            (*cmsg).cmsg_type = libc::IP_TOS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<libc::c_int>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, ecn as libc::c_int);
+            len += libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) as usize;
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+          }
+          if let Some(source) = source {
+            let info = libc::in_pktinfo {
+              ipi_ifindex: 0,
+              ipi_spec_dst: libc::in_addr { s_addr: u32::from(source).to_be() },
+              ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            (*cmsg).cmsg_level = libc::IPPROTO_IP;
+            (*cmsg).cmsg_type = libc::IP_PKTINFO;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<libc::in_pktinfo>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+            len += libc::CMSG_SPACE(size_of::<libc::in_pktinfo>() as u32) as usize;
+          }
+          msg.msg_controllen = len as _;
+
+          check(libc::sendmsg(socket.as_raw_fd(), &msg, 0))
+        }
+      })
+      .await
+  }
+
+  pub async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+  ) -> io::Result<(usize, SocketAddr, u8, Option<Ipv4Addr>)> {
+    socket
+      .async_io(Interest::READABLE, || unsafe {
+        let mut name: libc::sockaddr_in = std::mem::zeroed();
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut control: ControlBuf = [0; 8];
+
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = size_of::<ControlBuf>() as _;
+
+        let len = check(libc::recvmsg(socket.as_raw_fd(), &mut msg, 0))?;
+        if name.sin_family != libc::AF_INET as libc::sa_family_t {
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "Datagram from a non-IPv4 address"));
+        }
+        let addr =
+          SocketAddr::from((Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr)), u16::from_be(name.sin_port)));
+
+        let mut ecn = 0;
+        let mut local = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+          match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_TOS) => ecn = *libc::CMSG_DATA(cmsg) & crate::ip::ECN_CE,
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+              let info = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo);
+              local = Some(Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr)));
+            }
+            _ => {}
+          }
+          cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        Ok((len, addr, ecn, local))
+      })
+      .await
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+  use std::io;
+  use std::net::Ipv4Addr;
+  use std::net::SocketAddr;
+
+  use tokio::net::UdpSocket;
+
+  pub fn enable(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+  }
+
+  pub fn enable_pktinfo(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+  }
+
+  pub async fn send_to(
+    socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    _ecn: u8,
+    _source: Option<Ipv4Addr>,
+  ) -> io::Result<usize> {
+    socket.send_to(buf, addr).await
+  }
+
+  pub async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+  ) -> io::Result<(usize, SocketAddr, u8, Option<Ipv4Addr>)> {
+    let (len, addr) = socket.recv_from(buf).await?;
+    Ok((len, addr, crate::ip::ECN_NOT_ECT, None))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ip::tests::ipv4_packet;
+
+  #[test]
+  fn test_decapsulate() {
+    let mut not_ect = ipv4_packet(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+    let mut ect = not_ect.clone();
+    ip::set_ipv4_ecn(&mut ect, 0b10);
+
+    assert!(decapsulate(&mut not_ect, 0b10));
+    assert!(!decapsulate(&mut not_ect, ip::ECN_CE));
+
+    assert_eq!(encapsulate(&ect), 0b10);
+    assert!(decapsulate(&mut ect, ip::ECN_CE));
+    assert_eq!(ip::ipv4_ecn(&ect), Some(ip::ECN_CE));
+  }
+
+  #[tokio::test]
+  async fn test_outer_ecn_roundtrip() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    enable(&receiver).unwrap();
+
+    send_to(&sender, b"marked", receiver.local_addr().unwrap(), 0b01).await.unwrap();
+    let mut buf = [0u8; 16];
+    let (len, from, ecn) = recv_from(&receiver, &mut buf).await.unwrap();
+
+    assert_eq!(&buf[..len], b"marked");
+    assert_eq!(from, sender.local_addr().unwrap());
+    if cfg!(target_os = "linux") {
+      assert_eq!(ecn, 0b01);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_reply_from_local_address() {
+    if !cfg!(target_os = "linux") {
+      return;
+    }
+    // All of 127.0.0.0/8 is local on Linux, which makes the wildcard socket multi-homed.
+    let server = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    enable_pktinfo(&server).unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local = Ipv4Addr::new(127, 0, 0, 2);
+
+    client.send_to(b"ping", (local, server.local_addr().unwrap().port())).await.unwrap();
+    let mut buf = [0u8; 16];
+    let (_, from, _, to) = recv_from_to(&server, &mut buf).await.unwrap();
+    assert_eq!(to, Some(local));
+
+    send_from(&server, b"pong", from, ip::ECN_NOT_ECT, to).await.unwrap();
+    let (len, from) = client.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"pong");
+    assert_eq!(from, SocketAddr::from((local, server.local_addr().unwrap().port())));
+  }
+}
@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::packet::fill_random_bytes;
+
+/// Largest datagram a handshake or control packet may take. Fragmented UDP is dropped on many paths, and
+/// this passes unfragmented over the minimum IPv6 MTU and most tunnels.
+pub const MAX_CONTROL_DATAGRAM: usize = 1200;
+
+/// Bytes of a serialized packet carried by one fragment, leaving room for the header, the tag and padding
+/// transforms within `MAX_CONTROL_DATAGRAM`.
+pub const FRAGMENT_SIZE: usize = 1024;
+
+pub const MAX_FRAGMENTS: usize = 16;
+
+/// Piece of a serialized control packet too large for one datagram, e.g. `Auth` with a long OIDC token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+  /// Same for all fragments of a packet.
+  pub id: u32,
+  pub index: u8,
+  pub count: u8,
+  pub bytes: Vec<u8>,
+}
+
+/// Splits a serialized packet into fragments of up to `FRAGMENT_SIZE` bytes.
+pub fn split(packet: &[u8]) -> anyhow::Result<Vec<Fragment>> {
+  let count = packet.len().div_ceil(FRAGMENT_SIZE);
+  if count > MAX_FRAGMENTS {
+    anyhow::bail!("Packet of {} bytes is too large to send even in fragments", packet.len());
+  }
+
+  let mut id = [0; 4];
+  fill_random_bytes(&mut id);
+  let id = u32::from_be_bytes(id);
+
+  Ok(
+    packet
+      .chunks(FRAGMENT_SIZE)
+      .enumerate()
+      .map(|(index, bytes)| Fragment { id, index: index as u8, count: count as u8, bytes: bytes.to_vec() })
+      .collect(),
+  )
+}
+
+/// Collects the fragments of a packet in any order; a fragment of another packet starts over, so only one
+/// packet per peer is held at a time.
+#[derive(Debug, Default)]
+pub struct Reassembly {
+  id: u32,
+  pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl Reassembly {
+  /// Returns the serialized packet once all of its fragments are in.
+  pub fn add(&mut self, fragment: Fragment) -> anyhow::Result<Option<Vec<u8>>> {
+    let count = fragment.count as usize;
+    if count == 0
+      || count > MAX_FRAGMENTS
+      || fragment.index >= fragment.count
+      || fragment.bytes.len() > FRAGMENT_SIZE
+    {
+      anyhow::bail!("Invalid fragment {} of {}", fragment.index, fragment.count);
+    }
+
+    if self.id != fragment.id || self.pieces.len() != count {
+      *self = Self { id: fragment.id, pieces: vec![None; count] };
+    }
+    self.pieces[fragment.index as usize] = Some(fragment.bytes);
+
+    if self.pieces.iter().any(Option::is_none) {
+      return Ok(None);
+    }
+    Ok(Some(std::mem::take(&mut self.pieces).into_iter().flatten().flatten().collect()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+  use std::net::SocketAddr;
+
+  use super::*;
+  use crate::cert::Certificate;
+  use crate::cert::SigningKey;
+  use crate::creds::Credentials;
+  use crate::limits::Limits;
+  use crate::packet::ClientPacket;
+  use crate::packet::ErrorCode;
+  use crate::packet::Features;
+  use crate::packet::ServerPacket;
+  use crate::packet::KEY_SIZE;
+  use crate::transform::Registry;
+  use crate::transform::PAD;
+
+  #[test]
+  fn test_reassembly() {
+    let packet: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let mut fragments = split(&packet).unwrap();
+    assert_eq!(fragments.len(), 3);
+    fragments.reverse();
+
+    let mut reassembly = Reassembly::default();
+    assert_eq!(reassembly.add(fragments[0].clone()).unwrap(), None);
+    assert_eq!(reassembly.add(fragments[1].clone()).unwrap(), None);
+    assert_eq!(reassembly.add(fragments[2].clone()).unwrap(), Some(packet));
+
+    assert!(split(&vec![0; FRAGMENT_SIZE * MAX_FRAGMENTS + 1]).is_err());
+    assert!(reassembly.add(Fragment { id: 1, index: 2, count: 2, bytes: Vec::new() }).is_err());
+  }
+
+  /// Every handshake and control packet, at the largest it gets in practice, fits `MAX_CONTROL_DATAGRAM`
+  /// with the padding transform on; fragments included.
+  #[test]
+  fn test_control_packets_fit() {
+    let key = [1; KEY_SIZE];
+    let pipeline = Registry::default().pipeline(&[PAD.to_string()], &key).unwrap();
+    let username = "a".repeat(64);
+    let transforms = vec!["a".repeat(32); 4];
+    let certificate = Certificate::issue(
+      &SigningKey::from_bytes(&key),
+      &username,
+      key,
+      std::time::Duration::from_secs(86400),
+    )
+    .unwrap();
+    let observed: SocketAddr = "[2001:db8::1]:65535".parse().unwrap();
+
+    let client = [
+      ClientPacket::KeyExchange {
+        key,
+        transforms: transforms.clone(),
+        timestamp: u64::MAX,
+        features: Some(Features::SUPPORTED),
+        limits: Some(Limits::LOCAL),
+      },
+      ClientPacket::Auth(Credentials::new(username.clone(), "p".repeat(256))),
+      ClientPacket::Auth(Credentials::Certificate { certificate, proof: key }),
+      ClientPacket::KeyAuth { username: username.clone(), public_key: key, proof: key },
+      ClientPacket::Fragment(Fragment { id: u32::MAX, index: 15, count: 16, bytes: vec![0; FRAGMENT_SIZE] }),
+    ];
+    for packet in &client {
+      let size = pipeline.seal(&key, u64::MAX, packet).unwrap().len();
+      assert!(size <= MAX_CONTROL_DATAGRAM, "{} bytes: {:?}", size, packet);
+    }
+
+    let server = [
+      ServerPacket::KeyExchange {
+        key,
+        session_id: u64::MAX,
+        observed,
+        transforms,
+        features: Some(Features::SUPPORTED),
+        time: Some(u64::MAX),
+        limits: Some(Limits::LOCAL),
+        address_tag: Some(key),
+      },
+      ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message: "m".repeat(256) },
+      ServerPacket::NetworkConfig {
+        address: Ipv4Addr::BROADCAST,
+        prefix_len: 32,
+        dns: vec![Ipv4Addr::BROADCAST; 16],
+      },
+    ];
+    for packet in &server {
+      let size = pipeline.seal(&key, u64::MAX, packet).unwrap().len();
+      assert!(size <= MAX_CONTROL_DATAGRAM, "{} bytes: {:?}", size, packet);
+    }
+  }
+}
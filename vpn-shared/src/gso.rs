@@ -0,0 +1,255 @@
+//! Generic segmentation offload on tun devices (Linux only). With `IFF_VNET_HDR` and TSO enabled on the
+//! device, the kernel hands a TCP flow to the tun in super-packets of up to 64 KiB behind a `virtio_net_hdr`,
+//! one read for what would otherwise take dozens; `GsoReader` splits them back into packets of the MTU.
+//! Every write to such a device carries the header as well, left empty.
+
+use std::collections::VecDeque;
+use std::io;
+
+use tracing::trace;
+use tun::AsyncDevice;
+
+use crate::ip;
+use crate::logging;
+
+/// Length of the `virtio_net_hdr` in front of every packet read from or written to the device.
+pub const VNET_HDR_LEN: usize = 10;
+/// Largest super-packet the kernel hands over in one read.
+const MAX_GSO_SIZE: usize = u16::MAX as usize;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_CWR: u8 = 0x80;
+
+/// Turns on checksum and TCP segmentation offload for a device created with `IFF_VNET_HDR`, see
+/// `TunConfig::gso`.
+pub fn enable(device: &AsyncDevice) -> io::Result<()> {
+  sys::enable(device)
+}
+
+/// Reads packets from a device with offload enabled, one at a time like a plain tun device hands them.
+#[derive(Debug)]
+pub struct GsoReader {
+  buf: Vec<u8>,
+  pending: VecDeque<Vec<u8>>,
+}
+
+impl Default for GsoReader {
+  fn default() -> Self {
+    Self { buf: vec![0; VNET_HDR_LEN + MAX_GSO_SIZE], pending: VecDeque::new() }
+  }
+}
+
+impl GsoReader {
+  /// Reads the next packet into `buf` and returns its length. Packets longer than `buf` and super-packets
+  /// that can't be split are dropped, as the kernel drops packets over the MTU.
+  pub async fn recv(&mut self, device: &AsyncDevice, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+      while let Some(packet) = self.pending.pop_front() {
+        if packet.len() > buf.len() {
+          trace!(target: logging::TUN, "Dropping segment larger than the MTU; len: {}", packet.len());
+          continue;
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        return Ok(packet.len());
+      }
+
+      let len = device.recv(&mut self.buf).await?;
+      if let Err(e) = segment(&self.buf[..len], &mut self.pending) {
+        trace!(target: logging::TUN, "Dropping tun packet: {}; len: {}", e, len);
+      }
+    }
+  }
+}
+
+/// Writes `packet` to a device with offload enabled, behind a header that asks for no offload.
+pub async fn send(device: &AsyncDevice, packet: &[u8]) -> io::Result<usize> {
+  let mut framed = Vec::with_capacity(VNET_HDR_LEN + packet.len());
+  framed.extend([0; VNET_HDR_LEN]);
+  framed.extend_from_slice(packet);
+  Ok(device.send(&framed).await?.saturating_sub(VNET_HDR_LEN))
+}
+
+/// Splits a read behind a `virtio_net_hdr` into the packets it stands for, with their checksums filled in.
+fn segment(read: &[u8], out: &mut VecDeque<Vec<u8>>) -> anyhow::Result<()> {
+  let Some((header, packet)) = read.split_first_chunk::<VNET_HDR_LEN>() else {
+    anyhow::bail!("read shorter than the virtio header");
+  };
+  // The header is in the host's byte order, since the device isn't switched to little-endian ones.
+  let field = |at: usize| u16::from_ne_bytes([header[at], header[at + 1]]) as usize;
+  let (flags, gso_type, gso_size, csum_start, csum_offset) =
+    (header[0], header[1], field(4), field(6), field(8));
+
+  match gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+    VIRTIO_NET_HDR_GSO_NONE => {
+      let mut packet = packet.to_vec();
+      if flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+        // The checksum field holds the sum of the pseudo-header, so summing on from there completes it.
+        let at = csum_start + csum_offset;
+        if at + 2 > packet.len() {
+          anyhow::bail!("checksum at {} past the end of the packet", at);
+        }
+        let sum = ip::checksum(&packet[csum_start..]);
+        packet[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+      }
+      out.push_back(packet);
+      Ok(())
+    }
+    VIRTIO_NET_HDR_GSO_TCPV4 => segment_tcp(packet, gso_size, out),
+    other => anyhow::bail!("unsupported GSO type {}", other),
+  }
+}
+
+/// Cuts a TCP super-packet into segments of `mss` bytes of payload each, like the kernel would have.
+fn segment_tcp(packet: &[u8], mss: usize, out: &mut VecDeque<Vec<u8>>) -> anyhow::Result<()> {
+  let ip_len = (packet.first().copied().unwrap_or_default() & 0x0f) as usize * 4;
+  let tcp_len = packet.get(ip_len + 12).map_or(0, |offset| (offset >> 4) as usize * 4);
+  let headers = ip_len + tcp_len;
+  if ip_len < 20 || tcp_len < 20 || headers > packet.len() || mss == 0 {
+    anyhow::bail!("malformed TCP super-packet");
+  }
+
+  let id = u16::from_be_bytes([packet[4], packet[5]]);
+  let seq = u32::from_be_bytes(packet[ip_len + 4..ip_len + 8].try_into()?);
+  let chunks = packet[headers..].chunks(mss);
+  let last = chunks.len().saturating_sub(1);
+  for (i, payload) in chunks.enumerate() {
+    let mut segment = Vec::with_capacity(headers + payload.len());
+    segment.extend_from_slice(&packet[..headers]);
+    segment.extend_from_slice(payload);
+
+    let total_len = segment.len() as u16;
+    segment[2..4].copy_from_slice(&total_len.to_be_bytes());
+    segment[4..6].copy_from_slice(&id.wrapping_add(i as u16).to_be_bytes());
+    segment[10..12].fill(0);
+    let sum = ip::checksum(&segment[..ip_len]);
+    segment[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let tcp = &mut segment[ip_len..];
+    tcp[4..8].copy_from_slice(&seq.wrapping_add((i * mss) as u32).to_be_bytes());
+    // CWR goes out with the first segment only, FIN and PSH with the last one.
+    if i > 0 {
+      tcp[13] &= !TCP_CWR;
+    }
+    if i < last {
+      tcp[13] &= !(TCP_FIN | TCP_PSH);
+    }
+    tcp[16..18].fill(0);
+    let mut pseudo = Vec::with_capacity(12 + tcp.len());
+    pseudo.extend_from_slice(&packet[12..20]);
+    pseudo.extend([0, ip::PROTO_TCP]);
+    pseudo.extend((tcp.len() as u16).to_be_bytes());
+    pseudo.extend_from_slice(tcp);
+    let sum = ip::checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+    out.push_back(segment);
+  }
+  Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+  use std::io;
+  use std::os::fd::AsRawFd;
+
+  use tun::AsyncDevice;
+
+  pub fn enable(device: &AsyncDevice) -> io::Result<()> {
+    let offload = libc::TUN_F_CSUM | libc::TUN_F_TSO4;
+    let result = unsafe { libc::ioctl(device.as_raw_fd(), libc::TUNSETOFFLOAD, offload as libc::c_ulong) };
+    if result < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+  use std::io;
+
+  use tun::AsyncDevice;
+
+  pub fn enable(_device: &AsyncDevice) -> io::Result<()> {
+    Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "Segmentation offload on tun devices is only supported on Linux",
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// TCP packet from 10.0.0.1:1000 to 10.0.0.2:2000 with `payload`, its checksums left empty.
+  fn tcp_packet(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet =
+      vec![0x45, 0, 0, 0, 0x12, 0x34, 0x40, 0, 64, ip::PROTO_TCP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+    packet.extend([0x03, 0xe8, 0x07, 0xd0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+    let len = packet.len() as u16;
+    packet[2..4].copy_from_slice(&len.to_be_bytes());
+    packet
+  }
+
+  fn header(flags: u8, gso_type: u8, gso_size: u16, csum_start: u16, csum_offset: u16) -> Vec<u8> {
+    let mut header = vec![flags, gso_type];
+    for field in [40, gso_size, csum_start, csum_offset] {
+      header.extend(field.to_ne_bytes());
+    }
+    header
+  }
+
+  /// Whether the IPv4 and TCP checksums of `packet` are right: summing over them gives zero.
+  fn checksums_valid(packet: &[u8]) -> bool {
+    let mut pseudo = packet[12..20].to_vec();
+    pseudo.extend([0, ip::PROTO_TCP]);
+    pseudo.extend(((packet.len() - 20) as u16).to_be_bytes());
+    pseudo.extend_from_slice(&packet[20..]);
+    ip::checksum(&packet[..20]) == 0 && ip::checksum(&pseudo) == 0
+  }
+
+  #[test]
+  fn test_segment_tcp() {
+    let payload: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+    let mut read = header(VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_GSO_TCPV4, 1000, 20, 16);
+    read.extend(tcp_packet(0x18 | TCP_FIN, &payload));
+
+    let mut out = VecDeque::new();
+    segment(&read, &mut out).unwrap();
+    let segments: Vec<_> = out.into_iter().collect();
+    assert_eq!(segments.iter().map(Vec::len).collect::<Vec<_>>(), [1040, 1040, 540]);
+    assert_eq!(segments.iter().flat_map(|segment| &segment[40..]).copied().collect::<Vec<_>>(), payload);
+    for (i, segment) in segments.iter().enumerate() {
+      assert!(checksums_valid(segment));
+      assert_eq!(u16::from_be_bytes([segment[4], segment[5]]), 0x1234 + i as u16);
+      assert_eq!(u32::from_be_bytes(segment[24..28].try_into().unwrap()), 0x1000 + 1000 * i as u32);
+    }
+    assert_eq!(segments.iter().map(|segment| segment[33]).collect::<Vec<_>>(), [0x10, 0x10, 0x19]);
+  }
+
+  #[test]
+  fn test_partial_checksum() {
+    let mut packet = tcp_packet(0x18, b"hello");
+    // What the kernel leaves in the checksum field: the pseudo-header's sum, not inverted.
+    let mut pseudo = packet[12..20].to_vec();
+    pseudo.extend([0, ip::PROTO_TCP, 0, 25]);
+    packet[36..38].copy_from_slice(&(!ip::checksum(&pseudo)).to_be_bytes());
+    let sum = ip::checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    let mut read = header(VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_GSO_NONE, 0, 20, 16);
+    read.extend(&packet);
+
+    let mut out = VecDeque::new();
+    segment(&read, &mut out).unwrap();
+    assert!(checksums_valid(&out[0]));
+    assert!(segment(&read[..4], &mut out).is_err());
+  }
+}
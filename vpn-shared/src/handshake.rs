@@ -0,0 +1,357 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::PublicKey;
+use x25519_dalek::StaticSecret;
+
+use crate::packet::Key;
+use crate::packet::KEY_SIZE;
+
+const SALT: &[u8] = b"sberlinux-vpn handshake v2";
+const AUTH_SALT: &[u8] = b"sberlinux-vpn key auth v1";
+const REKEY_SALT: &[u8] = b"sberlinux-vpn rekey v1";
+const PEER_SALT: &[u8] = b"sberlinux-vpn peer key v1";
+const FINGERPRINT_SALT: &[u8] = b"sberlinux-vpn peer fingerprint v1";
+const TIME_SALT: &[u8] = b"sberlinux-vpn server time v1";
+const ADDRESS_SALT: &[u8] = b"sberlinux-vpn observed address v1";
+
+/// X25519 key pair; used for both the per-handshake ephemeral keys and the server's static key.
+#[derive(Clone)]
+pub struct KeyPair {
+  secret: StaticSecret,
+}
+
+impl KeyPair {
+  pub fn generate() -> Self {
+    Self { secret: StaticSecret::random_from_rng(OsRng) }
+  }
+
+  pub fn from_secret(secret: Key) -> Self {
+    Self { secret: StaticSecret::from(secret) }
+  }
+
+  pub fn secret(&self) -> Key {
+    self.secret.to_bytes()
+  }
+
+  pub fn public(&self) -> Key {
+    PublicKey::from(&self.secret).to_bytes()
+  }
+
+  fn agree(&self, public: &Key) -> anyhow::Result<[u8; 32]> {
+    let shared = self.secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+      anyhow::bail!("Peer sent a low-order public key");
+    }
+    Ok(shared.to_bytes())
+  }
+}
+
+/// Session key of the client: ephemeral-ephemeral DH, plus ephemeral-static DH against the server's pinned
+/// static key if there is one. Credentials are only ever sent under this key, so recorded handshakes don't
+/// reveal who connected, and an impostor without the static key can't read them either.
+///
+/// `observed` is the client's address as the server saw the handshake, echoed back in its reply. Mixing it
+/// in means a handshake relayed from another address yields a different key unless the client is told
+/// about the relay, so a man-on-the-side can't splice it onto a different 5-tuple.
+pub fn client_session_key(
+  ephemeral: &KeyPair,
+  server_ephemeral: &Key,
+  server_static: Option<&Key>,
+  observed: SocketAddr,
+) -> anyhow::Result<Key> {
+  let ee = ephemeral.agree(server_ephemeral)?;
+  let es = server_static.map(|key| ephemeral.agree(key)).transpose()?;
+  Ok(derive(&ee, es.as_ref(), &ephemeral.public(), server_ephemeral, observed))
+}
+
+/// Session key of the server; `server_static` is only to be passed when the client pinned it, see
+/// `Features::PINNED_KEY`, as clients that didn't can't mix it in.
+pub fn server_session_key(
+  ephemeral: &KeyPair,
+  client_ephemeral: &Key,
+  server_static: Option<&KeyPair>,
+  observed: SocketAddr,
+) -> anyhow::Result<Key> {
+  let ee = ephemeral.agree(client_ephemeral)?;
+  let es = server_static.map(|key| key.agree(client_ephemeral)).transpose()?;
+  Ok(derive(&ee, es.as_ref(), client_ephemeral, &ephemeral.public(), observed))
+}
+
+/// Session key with the server's clock `time` from its reply mixed in, like `observed`: unlike the rest of
+/// the reply it can't be changed on the way without the session failing, so the client can trust it.
+pub fn bind_time(session_key: &Key, time: u64) -> Key {
+  expand(TIME_SALT, session_key, &time.to_be_bytes())
+}
+
+/// Tag over the `observed` address of the server's reply, under its static key: mixing the address into the
+/// session key only tells the client the reply is its server's once it authenticates, while this lets a
+/// client that pinned the key check the address before it sends anything.
+pub fn server_address_tag(
+  server_static: &KeyPair,
+  client_ephemeral: &Key,
+  server_ephemeral: &Key,
+  observed: SocketAddr,
+) -> anyhow::Result<Key> {
+  let es = server_static.agree(client_ephemeral)?;
+  Ok(address_tag(&es, client_ephemeral, server_ephemeral, observed))
+}
+
+/// Checks the tag of `server_address_tag` against the pinned static key of the server.
+pub fn verify_address_tag(
+  ephemeral: &KeyPair,
+  server_static: &Key,
+  server_ephemeral: &Key,
+  observed: SocketAddr,
+  tag: &Key,
+) -> anyhow::Result<bool> {
+  let es = ephemeral.agree(server_static)?;
+  Ok(keys_match(&address_tag(&es, &ephemeral.public(), server_ephemeral, observed), tag))
+}
+
+/// Key replacing `previous` after a rekey: a fresh ephemeral-ephemeral DH, chained to the key it replaces
+/// so that a rekey only ever authenticates peers that already shared the session.
+pub fn client_rekey(ephemeral: &KeyPair, server_ephemeral: &Key, previous: &Key) -> anyhow::Result<Key> {
+  let ee = ephemeral.agree(server_ephemeral)?;
+  Ok(rekey(&ee, previous, &ephemeral.public(), server_ephemeral))
+}
+
+pub fn server_rekey(ephemeral: &KeyPair, client_ephemeral: &Key, previous: &Key) -> anyhow::Result<Key> {
+  let ee = ephemeral.agree(client_ephemeral)?;
+  Ok(rekey(&ee, previous, client_ephemeral, &ephemeral.public()))
+}
+
+/// Pairwise key of the clients at `own` and `peer` from their ephemeral keys; the same on both sides, as
+/// the keys are mixed in by the order of the addresses rather than by who offered first.
+pub fn peer_key(
+  ephemeral: &KeyPair,
+  peer_ephemeral: &Key,
+  own: Ipv4Addr,
+  peer: Ipv4Addr,
+) -> anyhow::Result<Key> {
+  let ee = ephemeral.agree(peer_ephemeral)?;
+  let (own, peer) = ((own, ephemeral.public()), (peer, *peer_ephemeral));
+  let (first, second) = if own.0 < peer.0 { (own, peer) } else { (peer, own) };
+  let info = [&first.0.octets()[..], &first.1, &second.0.octets(), &second.1].concat();
+  Ok(expand(PEER_SALT, &ee, &info))
+}
+
+/// Short digest of a pairwise key for the users of both clients to compare, e.g. over the phone: the server
+/// passes the ephemeral keys on, and one that swapped them for its own would leave the two with different
+/// keys.
+pub fn fingerprint(key: &Key) -> String {
+  let digest = expand(FINGERPRINT_SALT, key, &[]);
+  digest[..6].chunks(2).map(encode_hex).collect::<Vec<_>>().join("-")
+}
+
+/// Proof that the client holds the private half of its static key, bound to the session it's sent in.
+pub fn client_auth_proof(
+  client_static: &KeyPair,
+  server_ephemeral: &Key,
+  session_key: &Key,
+) -> anyhow::Result<Key> {
+  Ok(expand(AUTH_SALT, &client_static.agree(server_ephemeral)?, session_key))
+}
+
+pub fn server_auth_proof(
+  server_ephemeral: &KeyPair,
+  client_static: &Key,
+  session_key: &Key,
+) -> anyhow::Result<Key> {
+  Ok(expand(AUTH_SALT, &server_ephemeral.agree(client_static)?, session_key))
+}
+
+/// Compares keys in constant time.
+pub fn keys_match(a: &Key, b: &Key) -> bool {
+  a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn derive(ee: &[u8; 32], es: Option<&[u8; 32]>, client: &Key, server: &Key, observed: SocketAddr) -> Key {
+  let mut ikm = ee.to_vec();
+  ikm.extend_from_slice(es.map(|es| es.as_slice()).unwrap_or_default());
+
+  let observed = observed.to_string();
+  expand(SALT, &ikm, &[client.as_slice(), server.as_slice(), observed.as_bytes()].concat())
+}
+
+fn address_tag(es: &[u8; 32], client: &Key, server: &Key, observed: SocketAddr) -> Key {
+  let observed = observed.to_string();
+  expand(ADDRESS_SALT, es, &[client.as_slice(), server.as_slice(), observed.as_bytes()].concat())
+}
+
+fn rekey(ee: &[u8; 32], previous: &Key, client: &Key, server: &Key) -> Key {
+  let ikm = [ee.as_slice(), previous.as_slice()].concat();
+  expand(REKEY_SALT, &ikm, &[client.as_slice(), server.as_slice()].concat())
+}
+
+fn expand(salt: &[u8], ikm: &[u8], info: &[u8]) -> Key {
+  let mut key = [0u8; KEY_SIZE];
+  Hkdf::<Sha256>::new(Some(salt), ikm)
+    .expand(info, &mut key)
+    .expect("key size is a valid HKDF output length");
+  key
+}
+
+pub fn encode_key(key: &Key) -> String {
+  encode_hex(key)
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn parse_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+  let hex = hex.trim();
+  if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+    anyhow::bail!("Invalid hex string");
+  }
+
+  (0..hex.len()).step_by(2).map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?)).collect()
+}
+
+pub fn deserialize_key<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+  let hex = <String as serde::Deserialize>::deserialize(deserializer)?;
+  parse_key(&hex).map_err(serde::de::Error::custom)
+}
+
+pub fn parse_key(hex: &str) -> anyhow::Result<Key> {
+  let hex = hex.trim();
+  if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+    anyhow::bail!("Key must be {} hex characters", KEY_SIZE * 2);
+  }
+
+  Ok(parse_hex(hex)?.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addr() -> SocketAddr {
+    "192.0.2.1:6969".parse().unwrap()
+  }
+
+  #[test]
+  fn test_both_sides_agree() {
+    let server_static = KeyPair::generate();
+    let client = KeyPair::generate();
+    let server = KeyPair::generate();
+
+    let server_key = server_session_key(&server, &client.public(), Some(&server_static), addr()).unwrap();
+    let client_key =
+      client_session_key(&client, &server.public(), Some(&server_static.public()), addr()).unwrap();
+    assert_eq!(server_key, client_key);
+
+    let unpinned = client_session_key(&client, &server.public(), None, addr()).unwrap();
+    assert_ne!(unpinned, server_key);
+    assert_eq!(unpinned, server_session_key(&server, &client.public(), None, addr()).unwrap());
+  }
+
+  #[test]
+  fn test_wrong_static_key() {
+    let client = KeyPair::generate();
+    let server = KeyPair::generate();
+
+    let server_key =
+      server_session_key(&server, &client.public(), Some(&KeyPair::generate()), addr()).unwrap();
+    let client_key =
+      client_session_key(&client, &server.public(), Some(&KeyPair::generate().public()), addr()).unwrap();
+    assert_ne!(server_key, client_key);
+  }
+
+  #[test]
+  fn test_observed_address_is_bound() {
+    let client = KeyPair::generate();
+    let server = KeyPair::generate();
+
+    let server_key = server_session_key(&server, &client.public(), None, addr()).unwrap();
+    let relayed = "198.51.100.7:6969".parse().unwrap();
+    assert_ne!(client_session_key(&client, &server.public(), None, relayed).unwrap(), server_key);
+  }
+
+  #[test]
+  fn test_address_tag() {
+    let client = KeyPair::generate();
+    let server = KeyPair::generate();
+    let server_static = KeyPair::generate();
+
+    let tag = server_address_tag(&server_static, &client.public(), &server.public(), addr()).unwrap();
+    let verify =
+      |observed, key: &Key| verify_address_tag(&client, key, &server.public(), observed, &tag).unwrap();
+    assert!(verify(addr(), &server_static.public()));
+    assert!(!verify("198.51.100.7:6969".parse().unwrap(), &server_static.public()));
+    assert!(!verify(addr(), &KeyPair::generate().public()));
+  }
+
+  #[test]
+  fn test_time_is_bound() {
+    let key = [7u8; KEY_SIZE];
+    assert_eq!(bind_time(&key, 1_700_000_000), bind_time(&key, 1_700_000_000));
+    assert_ne!(bind_time(&key, 1_700_000_000), bind_time(&key, 1_700_000_001));
+    assert_ne!(bind_time(&key, 1_700_000_000), key);
+  }
+
+  #[test]
+  fn test_auth_proof() {
+    let client_static = KeyPair::generate();
+    let server = KeyPair::generate();
+    let session_key = [1u8; KEY_SIZE];
+
+    let proof = client_auth_proof(&client_static, &server.public(), &session_key).unwrap();
+    let expected = server_auth_proof(&server, &client_static.public(), &session_key).unwrap();
+    assert!(keys_match(&proof, &expected));
+
+    let other = server_auth_proof(&server, &KeyPair::generate().public(), &session_key).unwrap();
+    assert!(!keys_match(&proof, &other));
+  }
+
+  #[test]
+  fn test_rekey() {
+    let client = KeyPair::generate();
+    let server = KeyPair::generate();
+    let previous = [1u8; KEY_SIZE];
+
+    let key = client_rekey(&client, &server.public(), &previous).unwrap();
+    assert_eq!(key, server_rekey(&server, &client.public(), &previous).unwrap());
+    assert_ne!(key, previous);
+    // Without the key being replaced, the ephemeral keys alone don't give the new one.
+    assert_ne!(key, server_rekey(&server, &client.public(), &[2u8; KEY_SIZE]).unwrap());
+  }
+
+  #[test]
+  fn test_peer_key() {
+    let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+    let (a, b) = (Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(10, 8, 0, 3));
+
+    let key = peer_key(&alice, &bob.public(), a, b).unwrap();
+    assert_eq!(key, peer_key(&bob, &alice.public(), b, a).unwrap());
+    // Bound to the addresses, so it isn't taken for the key with another peer.
+    assert_ne!(key, peer_key(&alice, &bob.public(), a, Ipv4Addr::new(10, 8, 0, 4)).unwrap());
+
+    let fingerprint = fingerprint(&key);
+    assert_eq!(fingerprint.len(), 14);
+    assert_ne!(fingerprint, super::fingerprint(&[0u8; KEY_SIZE]));
+  }
+
+  #[test]
+  fn test_low_order_key_is_rejected() {
+    assert!(client_session_key(&KeyPair::generate(), &[0u8; KEY_SIZE], None, addr()).is_err());
+  }
+
+  #[test]
+  fn test_key_encoding() {
+    let key = KeyPair::generate().public();
+    assert_eq!(parse_key(&encode_key(&key)).unwrap(), key);
+    assert!(parse_key("abcd").is_err());
+  }
+}
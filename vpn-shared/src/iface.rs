@@ -0,0 +1,406 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::Command;
+
+use serde::Deserialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::logging;
+use crate::packet::DATA_OVERHEAD;
+
+pub const NAME_INDEX_PLACEHOLDER: &str = "%d";
+pub const MAX_NAME_INDEX: u32 = 255;
+
+pub const DEFAULT_MTU: u16 = 1500;
+pub const MIN_MTU: u16 = 576;
+pub const MAX_MTU: u16 = 9000;
+
+/// Outer IPv4 and UDP headers of every tunnel datagram.
+const UDP_IPV4_OVERHEAD: usize = 28;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TunConfig {
+  pub name: String,
+  pub address: Ipv4Addr,
+  pub netmask: Ipv4Addr,
+  pub mtu: Option<u16>,
+
+  /// MTU of the underlying network; when set, the tun MTU must leave room for the tunnel overhead.
+  #[serde(default)]
+  pub link_mtu: Option<u16>,
+
+  #[serde(default = "default_tun_up")]
+  pub up: bool,
+
+  /// Keep the device after exit so later runs, e.g. as `owner`, can attach to it without root. A device left
+  /// with another owner, other flags or another MTU is recreated, which needs root again.
+  #[serde(default)]
+  pub persist: bool,
+
+  /// User allowed to attach to a persistent device.
+  #[serde(default)]
+  pub owner: Option<String>,
+
+  /// Alias shown by `ip link`.
+  #[serde(default)]
+  pub description: Option<String>,
+
+  /// Let the kernel hand TCP flows to the device in super-packets of up to 64 KiB and split them here, see
+  /// `gso::GsoReader`; fewer reads for bulk traffic, Linux only.
+  #[serde(default)]
+  pub gso: bool,
+}
+
+fn default_tun_up() -> bool {
+  true
+}
+
+impl TunConfig {
+  pub fn mtu(&self) -> u16 {
+    self.mtu.unwrap_or(DEFAULT_MTU)
+  }
+
+  /// Checks the MTU, leaving room in the link MTU for `transform_overhead` bytes on top of the tunnel's own,
+  /// see `transform::Pipeline::overhead`.
+  pub fn validate_mtu(&self, transform_overhead: usize) -> anyhow::Result<()> {
+    let mtu = self.mtu();
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+      anyhow::bail!("Tun MTU {} is out of range {}..={}", mtu, MIN_MTU, MAX_MTU);
+    }
+
+    if let Some(link_mtu) = self.link_mtu {
+      let overhead = DATA_OVERHEAD + UDP_IPV4_OVERHEAD + transform_overhead;
+      if mtu as usize + overhead > link_mtu as usize {
+        anyhow::bail!(
+          "Tun MTU {} doesn't fit into link MTU {} with {} bytes of tunnel overhead; use at most {}",
+          mtu,
+          link_mtu,
+          overhead,
+          (link_mtu as usize).saturating_sub(overhead)
+        );
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn to_tun_config(&self, transform_overhead: usize) -> anyhow::Result<tun::Configuration> {
+    self.validate_mtu(transform_overhead)?;
+    let mut config = tun::Configuration::default();
+
+    if self.gso {
+      if !cfg!(target_os = "linux") {
+        anyhow::bail!("Segmentation offload on tun devices is only supported on Linux");
+      }
+      #[cfg(target_os = "linux")]
+      config.platform_config(|config| {
+        config.vnet_hdr(true);
+      });
+    }
+
+    if self.persist {
+      self.ensure_persistent()?;
+      // The device is configured once when it's created, so attaching to it doesn't need root.
+      #[cfg(target_os = "linux")]
+      config.platform_config(|config| {
+        config.ensure_root_privileges(false);
+      });
+      config.tun_name(&self.name).mtu(self.mtu());
+      return Ok(config);
+    }
+
+    config.tun_name(resolve_name(&self.name)?).address(self.address).netmask(self.netmask);
+
+    if self.up {
+      config.up();
+    }
+
+    if let Some(mtu) = self.mtu {
+      config.mtu(mtu);
+    }
+
+    Ok(config)
+  }
+
+  /// Creates the persistent device unless a previous run already did.
+  fn ensure_persistent(&self) -> anyhow::Result<()> {
+    if self.name.contains(NAME_INDEX_PLACEHOLDER) {
+      anyhow::bail!("Persistent interface {} needs a fixed name", self.name);
+    }
+
+    if !cfg!(target_os = "linux") {
+      anyhow::bail!("Persistent interfaces are only supported on Linux");
+    }
+
+    match interface_state(&self.name) {
+      InterfaceState::StaleTun => match self.stale_mismatch()? {
+        None => return Ok(()),
+        Some(reason) => {
+          warn!(target: logging::TUN, "Recreating persistent interface {}: {}", self.name, reason);
+          remove_interface(&self.name)?;
+        }
+      },
+      InterfaceState::InUse => {
+        anyhow::bail!("Interface {} already exists and is in use; configure another name", self.name)
+      }
+      InterfaceState::Missing => {}
+    }
+
+    let mut add = vec!["tuntap", "add", "dev", &self.name, "mode", "tun"];
+    if let Some(ref owner) = self.owner {
+      add.extend(["user", owner]);
+    }
+    if self.gso {
+      add.push("vnet_hdr");
+    }
+    ip(&add)?;
+
+    let address = format!("{}/{}", self.address, u32::from(self.netmask).count_ones());
+    ip(&["addr", "add", &address, "dev", &self.name])?;
+    ip(&["link", "set", "dev", &self.name, "mtu", &self.mtu().to_string()])?;
+    if self.up {
+      ip(&["link", "set", "dev", &self.name, "up"])?;
+    }
+
+    info!(target: logging::TUN, "Created persistent interface {}", self.name);
+    Ok(())
+  }
+
+  /// Why the persistent device a previous run left can't be attached to as configured now, if it can't.
+  fn stale_mismatch(&self) -> anyhow::Result<Option<String>> {
+    let sys = std::path::Path::new("/sys/class/net").join(&self.name);
+    let read = |attribute: &str| -> anyhow::Result<String> {
+      Ok(std::fs::read_to_string(sys.join(attribute))?.trim().to_string())
+    };
+    let owner = match self.owner {
+      Some(ref owner) => Some(user_id(owner)?),
+      None => None,
+    };
+    let flags = if self.gso { TUN_FLAGS | IFF_VNET_HDR } else { TUN_FLAGS };
+    Ok(persistent_mismatch(&read("owner")?, &read("tun_flags")?, &read("mtu")?, (owner, flags, self.mtu())))
+  }
+}
+
+/// Flags of `linux/if_tun.h` a device must be created with for the tun crate to attach to it.
+const TUN_FLAGS: u32 = IFF_TUN | IFF_NO_PI;
+const TUN_FLAGS_MASK: u32 = IFF_TUN | IFF_TAP | IFF_MULTI_QUEUE | IFF_NO_PI | IFF_VNET_HDR;
+const IFF_TUN: u32 = 0x0001;
+const IFF_TAP: u32 = 0x0002;
+const IFF_MULTI_QUEUE: u32 = 0x0100;
+const IFF_NO_PI: u32 = 0x1000;
+const IFF_VNET_HDR: u32 = 0x4000;
+
+/// Compares the `owner`, `tun_flags` and `mtu` a device shows in sysfs with the configured ones.
+fn persistent_mismatch(
+  owner: &str,
+  flags: &str,
+  mtu: &str,
+  (expected_owner, expected_flags, expected_mtu): (Option<u32>, u32, u16),
+) -> Option<String> {
+  // The kernel shows -1 for a device anyone may attach to.
+  let expected = expected_owner.map_or("-1".to_string(), |uid| uid.to_string());
+  if owner != expected {
+    return Some(format!("owned by {} instead of {}", owner, expected));
+  }
+
+  let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).unwrap_or_default() & TUN_FLAGS_MASK;
+  if flags != expected_flags {
+    return Some(format!("has flags {:#06x} instead of {:#06x}", flags, expected_flags));
+  }
+
+  if mtu != expected_mtu.to_string() {
+    return Some(format!("has MTU {} instead of {}", mtu, expected_mtu));
+  }
+  None
+}
+
+fn user_id(name: &str) -> anyhow::Result<u32> {
+  let output = Command::new("id").args(["-u", name]).output()?;
+  if !output.status.success() {
+    anyhow::bail!("Unknown user {}", name);
+  }
+  Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InterfaceState {
+  Missing,
+  /// A TUN device without a process attached to it, e.g. a persistent device left by a crashed run.
+  StaleTun,
+  InUse,
+}
+
+/// Resolves a `vpn%d`-style template to the first free interface name, cleaning up stale TUN devices
+/// along the way. Names without a placeholder are used as is, unless they're taken by a live interface.
+pub fn resolve_name(template: &str) -> anyhow::Result<String> {
+  resolve_name_with(template, |name| match interface_state(name) {
+    InterfaceState::Missing => Ok(true),
+    InterfaceState::StaleTun => {
+      warn!(target: logging::TUN, "Removing stale interface {} left behind by a previous run", name);
+      remove_interface(name)?;
+      Ok(true)
+    }
+    InterfaceState::InUse => Ok(false),
+  })
+}
+
+fn resolve_name_with<F>(template: &str, mut is_free: F) -> anyhow::Result<String>
+where
+  F: FnMut(&str) -> anyhow::Result<bool>,
+{
+  if !template.contains(NAME_INDEX_PLACEHOLDER) {
+    if !is_free(template)? {
+      anyhow::bail!(
+        "Interface {} already exists and is in use; configure another name, or a template such as vpn%d",
+        template
+      );
+    }
+    return Ok(template.to_string());
+  }
+
+  for index in 0..=MAX_NAME_INDEX {
+    let name = template.replacen(NAME_INDEX_PLACEHOLDER, &index.to_string(), 1);
+    if is_free(&name)? {
+      return Ok(name);
+    }
+  }
+
+  anyhow::bail!("No free interface name for template {}", template)
+}
+
+#[cfg(target_os = "linux")]
+pub fn interface_state(name: &str) -> InterfaceState {
+  let sys = std::path::Path::new("/sys/class/net").join(name);
+  if !sys.exists() {
+    return InterfaceState::Missing;
+  }
+
+  if !sys.join("tun_flags").exists() {
+    return InterfaceState::InUse;
+  }
+
+  match std::fs::read_to_string(sys.join("carrier")) {
+    Ok(carrier) if carrier.trim() == "1" => InterfaceState::InUse,
+    _ => InterfaceState::StaleTun,
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn interface_state(name: &str) -> InterfaceState {
+  match Command::new("ifconfig").arg(name).output() {
+    Ok(output) if output.status.success() => InterfaceState::InUse,
+    _ => InterfaceState::Missing,
+  }
+}
+
+/// Whether reading or writing the tun device `name` failed because the device is gone, e.g. deleted with
+/// `ip link del`, rather than for a reason that may pass.
+pub fn is_removed(name: &str, error: &io::Error) -> bool {
+  // Linux detaches the file descriptor of a deleted device.
+  #[cfg(target_os = "linux")]
+  if error.raw_os_error() == Some(libc::EBADFD) {
+    return true;
+  }
+  #[cfg(not(target_os = "linux"))]
+  let _ = error;
+  interface_state(name) == InterfaceState::Missing
+}
+
+pub fn remove_interface(name: &str) -> anyhow::Result<()> {
+  ip(&["link", "delete", name])
+}
+
+pub fn set_description(name: &str, description: &str) -> anyhow::Result<()> {
+  ip(&["link", "set", "dev", name, "alias", description])
+}
+
+fn ip(args: &[&str]) -> anyhow::Result<()> {
+  let status = Command::new("ip").args(args).status()?;
+  if !status.success() {
+    anyhow::bail!("`ip {}` exited with {}", args.join(" "), status);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tun_config(mtu: Option<u16>, link_mtu: Option<u16>) -> TunConfig {
+    TunConfig {
+      name: "tun0".into(),
+      address: Ipv4Addr::new(10, 0, 0, 1),
+      netmask: Ipv4Addr::new(255, 255, 255, 0),
+      mtu,
+      link_mtu,
+      up: true,
+      persist: false,
+      owner: None,
+      description: None,
+      gso: false,
+    }
+  }
+
+  #[test]
+  fn test_persistent_needs_fixed_name() {
+    let config = TunConfig { name: "vpn%d".into(), persist: true, ..tun_config(None, None) };
+    assert!(config.to_tun_config(0).is_err());
+  }
+
+  #[test]
+  fn test_persistent_mismatch() {
+    let expected = (Some(1000), TUN_FLAGS, 1400);
+    assert_eq!(persistent_mismatch("1000", "0x1001", "1400", expected), None);
+    // IFF_PERSIST and other flags that don't matter for attaching are ignored.
+    assert_eq!(persistent_mismatch("-1", "0x1801", "1500", (None, TUN_FLAGS, 1500)), None);
+
+    assert!(persistent_mismatch("-1", "0x1001", "1400", expected).unwrap().contains("owned by -1"));
+    assert!(persistent_mismatch("1000", "0x1002", "1400", expected).unwrap().contains("flags"));
+    assert!(persistent_mismatch("1000", "0x0001", "1400", expected).unwrap().contains("flags"));
+    assert!(persistent_mismatch("1000", "0x1001", "1500", expected).unwrap().contains("MTU 1500"));
+    // A device made for segmentation offload can only be attached to with it.
+    assert!(persistent_mismatch("1000", "0x5001", "1400", expected).unwrap().contains("flags"));
+    assert_eq!(
+      persistent_mismatch("1000", "0x5001", "1400", (Some(1000), TUN_FLAGS | IFF_VNET_HDR, 1400)),
+      None
+    );
+  }
+
+  #[test]
+  fn test_validate_mtu() {
+    assert!(tun_config(None, None).validate_mtu(0).is_ok());
+    assert!(tun_config(Some(9000), None).validate_mtu(0).is_ok());
+    assert!(tun_config(Some(9001), None).validate_mtu(0).is_err());
+    assert!(tun_config(Some(1500), Some(1500)).validate_mtu(0).is_err());
+    assert!(tun_config(Some(1420), Some(1500)).validate_mtu(0).is_ok());
+    assert!(tun_config(Some(1420), Some(1500)).validate_mtu(65).is_err());
+  }
+
+  #[test]
+  fn test_plain_name() {
+    assert_eq!(resolve_name_with("tun0", |_| Ok(true)).unwrap(), "tun0");
+    assert!(resolve_name_with("tun0", |_| Ok(false)).is_err());
+  }
+
+  #[test]
+  fn test_template_picks_first_free() {
+    let taken = ["vpn0", "vpn1"];
+    let name = resolve_name_with("vpn%d", |name| Ok(!taken.contains(&name))).unwrap();
+    assert_eq!(name, "vpn2");
+  }
+
+  #[test]
+  fn test_template_exhausted() {
+    assert!(resolve_name_with("vpn%d", |_| Ok(false)).is_err());
+  }
+
+  #[test]
+  fn test_is_removed() {
+    let interrupted = io::Error::from(io::ErrorKind::Interrupted);
+    assert!(is_removed("vpn-missing-tun", &interrupted));
+    #[cfg(target_os = "linux")]
+    assert!(!is_removed("lo", &interrupted));
+  }
+}
@@ -0,0 +1,413 @@
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+
+pub const IPV4_HEADER_MIN_LEN: usize = 20;
+
+/// ECN codepoints in the low two bits of the traffic class byte (RFC 3168).
+pub const ECN_NOT_ECT: u8 = 0b00;
+pub const ECN_CE: u8 = 0b11;
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+/// IPv4 and TCP headers without options, what the MSS leaves room for out of the MTU.
+pub const TCP_IPV4_OVERHEAD: u16 = 40;
+
+/// Codes of ICMP destination unreachable messages (RFC 792, RFC 1812).
+pub const ICMP_NET_UNREACHABLE: u8 = 0;
+pub const ICMP_ADMIN_PROHIBITED: u8 = 13;
+
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+/// Types of ICMP error messages, which are never answered with another one.
+const ICMP_ERRORS: [u8; 5] = [3, 4, 5, 11, 12];
+
+fn ipv4_header(packet: &[u8]) -> Option<&[u8]> {
+  if packet.len() < IPV4_HEADER_MIN_LEN || packet[0] >> 4 != 4 {
+    return None;
+  }
+  Some(&packet[..IPV4_HEADER_MIN_LEN])
+}
+
+pub fn ipv4_source(packet: &[u8]) -> Option<Ipv4Addr> {
+  ipv4_header(packet).map(|header| Ipv4Addr::new(header[12], header[13], header[14], header[15]))
+}
+
+pub fn ipv4_destination(packet: &[u8]) -> Option<Ipv4Addr> {
+  ipv4_header(packet).map(|header| Ipv4Addr::new(header[16], header[17], header[18], header[19]))
+}
+
+pub fn ipv4_ecn(packet: &[u8]) -> Option<u8> {
+  ipv4_header(packet).map(|header| header[1] & ECN_CE)
+}
+
+/// TCP or UDP flow an IPv4 packet belongs to, as seen from its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+  pub protocol: u8,
+  pub source: SocketAddrV4,
+  pub destination_port: u16,
+}
+
+/// Flow of a TCP segment or UDP datagram; `None` for other protocols and fragments but the first.
+pub fn flow(packet: &[u8]) -> Option<Flow> {
+  let header = ipv4_header(packet)?;
+  let header_len = (header[0] & 0x0f) as usize * 4;
+  let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+  if !matches!(header[9], PROTO_TCP | PROTO_UDP) || fragment_offset != 0 || header_len < IPV4_HEADER_MIN_LEN {
+    return None;
+  }
+
+  let ports = packet.get(header_len..header_len + 4)?;
+  let source = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+  Some(Flow {
+    protocol: header[9],
+    source: SocketAddrV4::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+    destination_port: u16::from_be_bytes([ports[2], ports[3]]),
+  })
+}
+
+/// Whether the packet is a TCP SYN without ACK, the first segment of a connection.
+pub fn is_tcp_syn(packet: &[u8]) -> bool {
+  let Some(header) = ipv4_header(packet) else {
+    return false;
+  };
+  let header_len = (header[0] & 0x0f) as usize * 4;
+  let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+  header[9] == PROTO_TCP
+    && fragment_offset == 0
+    && packet.get(header_len + 13).is_some_and(|flags| flags & (TCP_SYN | TCP_ACK) == TCP_SYN)
+}
+
+/// Sets the ECN field of an IPv4 packet and fixes up its header checksum.
+pub fn set_ipv4_ecn(packet: &mut [u8], ecn: u8) {
+  if ipv4_header(packet).is_none() {
+    return;
+  }
+
+  packet[1] = (packet[1] & !ECN_CE) | (ecn & ECN_CE);
+
+  let header_len = ((packet[0] & 0x0f) as usize * 4).clamp(IPV4_HEADER_MIN_LEN, packet.len());
+  packet[10..12].fill(0);
+  let sum = checksum(&packet[..header_len]);
+  packet[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Internet checksum of `bytes`, odd lengths padded with a zero byte.
+pub(crate) fn checksum(bytes: &[u8]) -> u16 {
+  let mut sum =
+    bytes.chunks(2).map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32).sum::<u32>();
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+/// ICMP destination unreachable with `code` for `packet`, sent from `source` back to the packet's sender so
+/// it fails fast instead of timing out. `None` for ICMP errors and non-first fragments, which must not be
+/// answered with one.
+pub fn icmp_unreachable(packet: &[u8], source: Ipv4Addr, code: u8) -> Option<Vec<u8>> {
+  let header = ipv4_header(packet)?;
+  let header_len = (header[0] & 0x0f) as usize * 4;
+  let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+  if header_len < IPV4_HEADER_MIN_LEN || fragment_offset != 0 {
+    return None;
+  }
+  if header[9] == PROTO_ICMP && packet.get(header_len).is_none_or(|kind| ICMP_ERRORS.contains(kind)) {
+    return None;
+  }
+
+  // The original header and the first 8 bytes of its payload, enough to find the socket it came from.
+  let quoted = &packet[..packet.len().min(header_len + 8)];
+  let mut icmp = vec![ICMP_DEST_UNREACHABLE, code, 0, 0, 0, 0, 0, 0];
+  icmp.extend_from_slice(quoted);
+  let sum = checksum(&icmp);
+  icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+  let mut reply = vec![0u8; IPV4_HEADER_MIN_LEN];
+  reply[0] = 0x45;
+  // Internetwork control, as routers send their errors with.
+  reply[1] = 0xc0;
+  reply[2..4].copy_from_slice(&((IPV4_HEADER_MIN_LEN + icmp.len()) as u16).to_be_bytes());
+  reply[8] = 64;
+  reply[9] = PROTO_ICMP;
+  reply[12..16].copy_from_slice(&source.octets());
+  reply[16..20].copy_from_slice(&header[12..16]);
+  let sum = checksum(&reply);
+  reply[10..12].copy_from_slice(&sum.to_be_bytes());
+  reply.extend(icmp);
+  Some(reply)
+}
+
+/// Lowers the MSS option of a TCP SYN to `mss` if it's larger, fixing up the TCP checksum, so neither end
+/// sends segments that don't fit the tunnel. Returns whether the packet was changed.
+pub fn clamp_tcp_mss(packet: &mut [u8], mss: u16) -> bool {
+  let Some(header) = ipv4_header(packet) else {
+    return false;
+  };
+  let header_len = (header[0] & 0x0f) as usize * 4;
+  let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+  let total_len = (u16::from_be_bytes([header[2], header[3]]) as usize).min(packet.len());
+  if header[9] != PROTO_TCP || fragment_offset != 0 || header_len < IPV4_HEADER_MIN_LEN {
+    return false;
+  }
+
+  let Some(tcp) = packet.get(header_len..total_len) else {
+    return false;
+  };
+  if tcp.len() < 20 || tcp[13] & TCP_SYN == 0 {
+    return false;
+  }
+  let options_end = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+
+  let mut pos = 20;
+  let mut found = None;
+  while pos < options_end {
+    match tcp[pos] {
+      TCP_OPTION_END => break,
+      TCP_OPTION_NOP => pos += 1,
+      kind => {
+        let Some(&len) = tcp.get(pos + 1).filter(|&&len| len >= 2) else {
+          break;
+        };
+        if kind == TCP_OPTION_MSS && len == 4 && pos + 4 <= options_end {
+          found = Some(pos + 2);
+          break;
+        }
+        pos += len as usize;
+      }
+    }
+  }
+  let Some(offset) = found else {
+    return false;
+  };
+  if u16::from_be_bytes([tcp[offset], tcp[offset + 1]]) <= mss {
+    return false;
+  }
+
+  let mut pseudo = Vec::with_capacity(12 + tcp.len());
+  pseudo.extend_from_slice(&packet[12..20]);
+  pseudo.extend([0, PROTO_TCP]);
+  pseudo.extend((tcp.len() as u16).to_be_bytes());
+
+  let tcp = &mut packet[header_len..total_len];
+  tcp[offset..offset + 2].copy_from_slice(&mss.to_be_bytes());
+  tcp[16..18].fill(0);
+  pseudo.extend_from_slice(tcp);
+  let sum = checksum(&pseudo);
+  tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+  true
+}
+
+/// Source, destination and payload of an unfragmented UDP datagram.
+pub fn udp_datagram(packet: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4, &[u8])> {
+  let header = ipv4_header(packet)?;
+  let header_len = (header[0] & 0x0f) as usize * 4;
+  // Fragment offset and more fragments.
+  let fragmented = u16::from_be_bytes([header[6], header[7]]) & 0x3fff != 0;
+  if header[9] != PROTO_UDP || fragmented || header_len < IPV4_HEADER_MIN_LEN {
+    return None;
+  }
+
+  let udp = packet.get(header_len..)?;
+  let len = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+  let payload = udp.get(8..len.max(8))?;
+  let source = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+  let destination = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+  Some((
+    SocketAddrV4::new(source, u16::from_be_bytes([udp[0], udp[1]])),
+    SocketAddrV4::new(destination, u16::from_be_bytes([udp[2], udp[3]])),
+    payload,
+  ))
+}
+
+/// IPv4 packet carrying `payload` in a UDP datagram, with both checksums.
+pub fn udp_packet(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+  let udp_len = (8 + payload.len()) as u16;
+  let mut udp = Vec::with_capacity(udp_len as usize);
+  for field in [source.port(), destination.port(), udp_len, 0] {
+    udp.extend(field.to_be_bytes());
+  }
+  udp.extend_from_slice(payload);
+  let mut pseudo = Vec::with_capacity(12 + udp.len());
+  pseudo.extend(source.ip().octets());
+  pseudo.extend(destination.ip().octets());
+  pseudo.extend([0, PROTO_UDP]);
+  pseudo.extend(udp_len.to_be_bytes());
+  pseudo.extend(&udp);
+  // Zero means no checksum, so a computed zero goes as all ones.
+  let sum = match checksum(&pseudo) {
+    0 => 0xffff,
+    sum => sum,
+  };
+  udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+  let mut packet = vec![0u8; IPV4_HEADER_MIN_LEN];
+  packet[0] = 0x45;
+  packet[2..4].copy_from_slice(&(IPV4_HEADER_MIN_LEN as u16 + udp_len).to_be_bytes());
+  packet[8] = 64;
+  packet[9] = PROTO_UDP;
+  packet[12..16].copy_from_slice(&source.ip().octets());
+  packet[16..20].copy_from_slice(&destination.ip().octets());
+  let sum = checksum(&packet);
+  packet[10..12].copy_from_slice(&sum.to_be_bytes());
+  packet.extend(udp);
+  packet
+}
+
+/// Marks congestion on an ECN-capable packet; returns false, leaving the packet as is, for ones that aren't.
+pub fn mark_congestion(packet: &mut [u8]) -> bool {
+  match ipv4_ecn(packet) {
+    None | Some(ECN_NOT_ECT) => false,
+    Some(ECN_CE) => true,
+    Some(_) => {
+      set_ipv4_ecn(packet, ECN_CE);
+      true
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  pub fn ipv4_packet(source: Ipv4Addr, destination: Ipv4Addr) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV4_HEADER_MIN_LEN];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(IPV4_HEADER_MIN_LEN as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[12..16].copy_from_slice(&source.octets());
+    packet[16..20].copy_from_slice(&destination.octets());
+    packet
+  }
+
+  #[test]
+  fn test_ipv4_addresses() {
+    let packet = ipv4_packet(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+
+    assert_eq!(ipv4_source(&packet), Some(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(ipv4_destination(&packet), Some(Ipv4Addr::new(1, 1, 1, 1)));
+  }
+
+  #[test]
+  fn test_flow() {
+    let mut packet = ipv4_packet(Ipv4Addr::new(203, 0, 113, 7), Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(flow(&packet), None);
+
+    packet[9] = PROTO_TCP;
+    packet.extend([0xc3, 0x50, 0x00, 0x16, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, TCP_SYN, 0xff, 0xff, 0, 0, 0, 0]);
+    let expected = Flow {
+      protocol: PROTO_TCP,
+      source: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 50000),
+      destination_port: 22,
+    };
+    assert_eq!(flow(&packet), Some(expected));
+    assert!(is_tcp_syn(&packet));
+    packet[33] |= TCP_ACK;
+    assert!(!is_tcp_syn(&packet));
+
+    packet[9] = PROTO_UDP;
+    assert_eq!(flow(&packet), Some(Flow { protocol: PROTO_UDP, ..expected }));
+    assert!(!is_tcp_syn(&packet));
+    packet[7] = 1;
+    assert_eq!(flow(&packet), None);
+  }
+
+  #[test]
+  fn test_mark_congestion() {
+    let mut packet = ipv4_packet(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+    assert!(!mark_congestion(&mut packet));
+
+    set_ipv4_ecn(&mut packet, 0b10);
+    assert!(mark_congestion(&mut packet));
+    assert_eq!(ipv4_ecn(&packet), Some(ECN_CE));
+
+    // A header with a valid checksum sums to 0xffff.
+    let sum = packet.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]]) as u32).sum::<u32>();
+    assert_eq!((sum & 0xffff) + (sum >> 16), 0xffff);
+  }
+
+  #[test]
+  fn test_icmp_unreachable() {
+    let client = Ipv4Addr::new(10, 0, 0, 2);
+    let mut packet = ipv4_packet(client, Ipv4Addr::new(10, 20, 0, 1));
+    packet[9] = 6;
+    packet.extend([0x12, 0x34, 0x00, 0x50, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+    let reply = icmp_unreachable(&packet, Ipv4Addr::new(10, 20, 0, 1), ICMP_ADMIN_PROHIBITED).unwrap();
+    assert_eq!(ipv4_source(&reply), Some(Ipv4Addr::new(10, 20, 0, 1)));
+    assert_eq!(ipv4_destination(&reply), Some(client));
+    assert_eq!(reply.len(), 20 + 8 + 28);
+    assert_eq!(&reply[20..22], [3, ICMP_ADMIN_PROHIBITED]);
+    assert_eq!(&reply[28..], &packet[..28]);
+    assert_eq!(checksum(&reply[..20]), 0);
+    assert_eq!(checksum(&reply[20..]), 0);
+
+    let mut error = ipv4_packet(client, Ipv4Addr::new(10, 20, 0, 1));
+    error[9] = PROTO_ICMP;
+    error.extend([3, 1, 0, 0]);
+    assert_eq!(icmp_unreachable(&error, Ipv4Addr::UNSPECIFIED, ICMP_NET_UNREACHABLE), None);
+    error[20] = 8;
+    assert!(icmp_unreachable(&error, Ipv4Addr::UNSPECIFIED, ICMP_NET_UNREACHABLE).is_some());
+
+    packet[7] = 1;
+    assert_eq!(icmp_unreachable(&packet, Ipv4Addr::UNSPECIFIED, ICMP_ADMIN_PROHIBITED), None);
+  }
+
+  #[test]
+  fn test_clamp_tcp_mss() {
+    let mut packet = ipv4_packet(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 20, 0, 1));
+    packet[9] = PROTO_TCP;
+    // SYN with NOP, MSS 1460 and window scale options; the MSS value at an odd offset.
+    let mut tcp = vec![0x12, 0x34, 0x00, 0x50, 0, 0, 0, 1, 0, 0, 0, 0, 0x70, TCP_SYN, 0xff, 0xff, 0, 0, 0, 0];
+    tcp.extend([1, 2, 4, 0x05, 0xb4, 3, 3, 7]);
+    packet.extend(&tcp);
+    let len = packet.len() as u16;
+    packet[2..4].copy_from_slice(&len.to_be_bytes());
+    let tcp_checksum = |packet: &[u8]| {
+      let mut pseudo = packet[12..20].to_vec();
+      pseudo.extend([0, PROTO_TCP, 0, (packet.len() - 20) as u8]);
+      pseudo.extend(&packet[20..]);
+      checksum(&pseudo)
+    };
+
+    assert!(clamp_tcp_mss(&mut packet, 1360));
+    assert_eq!(&packet[43..45], 1360u16.to_be_bytes());
+    assert_eq!(tcp_checksum(&packet), 0);
+    assert!(!clamp_tcp_mss(&mut packet, 1400));
+
+    // Established flows aren't touched.
+    packet[33] = 0x10;
+    assert!(!clamp_tcp_mss(&mut packet, 1200));
+  }
+
+  #[test]
+  fn test_udp() {
+    let client = SocketAddrV4::new(Ipv4Addr::new(10, 8, 0, 2), 40000);
+    let resolver = SocketAddrV4::new(Ipv4Addr::new(10, 8, 0, 1), 53);
+    let packet = udp_packet(client, resolver, b"query");
+    assert_eq!(udp_datagram(&packet), Some((client, resolver, &b"query"[..])));
+    assert_eq!(flow(&packet).map(|flow| flow.destination_port), Some(53));
+    assert_eq!(checksum(&packet[..20]), 0);
+    let mut pseudo = packet[12..20].to_vec();
+    pseudo.extend([0, PROTO_UDP, 0, 13]);
+    pseudo.extend(&packet[20..]);
+    assert_eq!(checksum(&pseudo), 0);
+
+    assert_eq!(udp_datagram(&packet[..26]), None);
+    let mut fragment = packet.clone();
+    fragment[6] = 0x20;
+    assert_eq!(udp_datagram(&fragment), None);
+  }
+
+  #[test]
+  fn test_not_ipv4() {
+    assert_eq!(ipv4_source(&[0x60; 40]), None);
+    assert_eq!(ipv4_destination(&[0x45; 10]), None);
+  }
+}
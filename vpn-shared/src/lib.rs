@@ -1,2 +1,30 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cert;
 pub mod creds;
+pub mod diagnose;
+pub mod dns;
+pub mod ecn;
+pub mod fragment;
+pub mod gso;
+pub mod handshake;
+pub mod iface;
+pub mod ip;
+pub mod limits;
+pub mod logging;
+#[cfg(any(feature = "smol", feature = "async-std"))]
+pub mod nonblocking;
+pub mod outer;
+pub mod output;
 pub mod packet;
+pub mod peer;
+pub mod protocol;
+pub mod rate;
+pub mod recorder;
+pub mod reference;
+pub mod rendezvous;
+pub mod selftest;
+pub mod socket;
+pub mod stream;
+pub mod supervisor;
+pub mod transform;
@@ -0,0 +1,154 @@
+//! Limits of the protocol: how large a packet, a credential and a list of routes get. Peers advertise theirs
+//! in the key exchange, see `Features::LIMITS`, and hold each other to the lower of each; what they receive
+//! is checked against their own, so that a malformed or hostile peer can't make them hold more than that.
+
+use std::fmt;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::creds::Credentials;
+use crate::packet::MAX_DATAGRAM_SIZE;
+
+/// Bytes of the largest serialized packet, the largest datagram; larger ones aren't decoded.
+pub const MAX_PACKET_SIZE: u32 = MAX_DATAGRAM_SIZE as u32;
+
+/// Bytes of the longest username, password, token or ticket.
+pub const MAX_CREDENTIAL_LEN: u32 = 8192;
+
+/// Most networks in a list of routes or subnets, e.g. `ServerPacket::Routes` or `ClientPacket::RegisterSubnets`.
+pub const MAX_ROUTES: u32 = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+  pub max_packet_size: u32,
+  pub max_credential_len: u32,
+  pub max_routes: u32,
+}
+
+impl Limits {
+  /// Limits of this version, also assumed of peers predating them, to which they applied implicitly.
+  pub const LOCAL: Self =
+    Self { max_packet_size: MAX_PACKET_SIZE, max_credential_len: MAX_CREDENTIAL_LEN, max_routes: MAX_ROUTES };
+
+  /// The lower of each of ours and the ones the peer advertised.
+  pub fn negotiate(self, peer: Option<Self>) -> Self {
+    let peer = peer.unwrap_or(Self::LOCAL);
+    Self {
+      max_packet_size: self.max_packet_size.min(peer.max_packet_size),
+      max_credential_len: self.max_credential_len.min(peer.max_credential_len),
+      max_routes: self.max_routes.min(peer.max_routes),
+    }
+  }
+
+  /// Fails if a username, password, token or ticket of `credentials` is too long.
+  pub fn check_credentials(&self, credentials: &Credentials) -> anyhow::Result<()> {
+    match credentials {
+      Credentials::Password { username, password } => {
+        self.check_credential(username.as_bytes())?;
+        self.check_credential(password.as_bytes())
+      }
+      Credentials::Token(token) => self.check_credential(token.as_bytes()),
+      Credentials::Certificate { certificate, .. } => self.check_credential(certificate.username.as_bytes()),
+      Credentials::Ticket(ticket) => self.check_credential(ticket),
+    }
+  }
+
+  /// Fails if `credential`, e.g. the username of key-based authentication, is too long.
+  pub fn check_credential(&self, credential: &[u8]) -> anyhow::Result<()> {
+    if credential.len() > self.max_credential_len as usize {
+      anyhow::bail!(
+        "Credential of {} bytes is longer than {} bytes",
+        credential.len(),
+        self.max_credential_len
+      );
+    }
+    Ok(())
+  }
+
+  /// Decodes a packet as `bincode::deserialize` does, but reading no more than `max_packet_size` bytes:
+  /// lengths inside it are checked against that before anything is allocated for them.
+  pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+    self.check_datagram(bytes.len())?;
+    bincode::DefaultOptions::new()
+      .with_fixint_encoding()
+      .allow_trailing_bytes()
+      .with_limit(self.max_packet_size.into())
+      .deserialize(bytes)
+      .map_err(|e| anyhow::anyhow!("Deserialization failed: {}", e))
+  }
+
+  /// Fails if a datagram or packet of `len` bytes is larger than the peers agreed on.
+  pub fn check_datagram(&self, len: usize) -> anyhow::Result<()> {
+    if len > self.max_packet_size as usize {
+      anyhow::bail!("Datagram of {} bytes is larger than {} bytes", len, self.max_packet_size);
+    }
+    Ok(())
+  }
+
+  /// Fails if a list of `count` routes or subnets is too long.
+  pub fn check_routes(&self, count: usize) -> anyhow::Result<()> {
+    if count > self.max_routes as usize {
+      anyhow::bail!("{} routes are more than {}", count, self.max_routes);
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for Limits {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "packets of {} bytes, credentials of {} bytes, {} routes",
+      self.max_packet_size, self.max_credential_len, self.max_routes
+    )
+  }
+}
+
+/// `Limits::decode` with the limits of this version, for packets decoded before the peers agreed on any or
+/// that no peer sent, e.g. certificates.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<T> {
+  Limits::LOCAL.decode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::packet::ClientPacket;
+
+  #[test]
+  fn test_negotiate() {
+    let peer = Limits { max_packet_size: 1500, max_credential_len: 16_384, max_routes: 16 };
+    let limits = Limits::LOCAL.negotiate(Some(peer));
+    assert_eq!(
+      limits,
+      Limits { max_packet_size: 1500, max_credential_len: MAX_CREDENTIAL_LEN, max_routes: 16 }
+    );
+    assert_eq!(Limits::LOCAL.negotiate(None), Limits::LOCAL);
+
+    assert!(limits.check_routes(16).is_ok());
+    assert!(limits.check_routes(17).is_err());
+    assert!(limits.check_credentials(&Credentials::new("alice", "pass")).is_ok());
+    let long = Credentials::Token("t".repeat(MAX_CREDENTIAL_LEN as usize + 1));
+    assert!(limits.check_credentials(&long).is_err());
+  }
+
+  #[test]
+  fn test_decode() {
+    let packet = ClientPacket::RegisterHostname("alice".to_string());
+    let bytes = bincode::serialize(&packet).unwrap();
+    assert!(matches!(decode(&bytes).unwrap(), ClientPacket::RegisterHostname(name) if name == "alice"));
+
+    // A length far past the end of the packet is refused rather than allocated for.
+    let mut hostile = bytes[..4].to_vec();
+    hostile.extend_from_slice(&u64::MAX.to_le_bytes());
+    assert!(decode::<ClientPacket>(&hostile).is_err());
+
+    // As are packets larger than agreed on.
+    let small = Limits { max_packet_size: 8, ..Limits::LOCAL };
+    assert!(small.decode::<ClientPacket>(&bytes).is_err());
+    assert!(small.check_datagram(9).is_err() && small.check_datagram(8).is_ok());
+  }
+}
@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::Event;
+use tracing::Level;
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::Registry;
+
+use crate::recorder;
+use crate::recorder::FlightRecorderConfig;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogConfig {
+  /// `error`, `warn`, `info`, `debug` or `trace`.
+  #[serde(default = "default_level")]
+  pub level: String,
+
+  /// Levels of the subsystems in `SUBSYSTEMS`, overriding `level` for their events, e.g. `handshake: debug`
+  /// without the per-packet events of `datapath`.
+  #[serde(default)]
+  pub subsystems: BTreeMap<String, String>,
+
+  #[serde(default)]
+  pub target: LogTarget,
+
+  /// Recent events kept in memory and dumped to disk on a panic or by an administrator.
+  #[serde(default)]
+  pub flight_recorder: Option<FlightRecorderConfig>,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    Self {
+      level: default_level(),
+      subsystems: BTreeMap::new(),
+      target: LogTarget::default(),
+      flight_recorder: None,
+    }
+  }
+}
+
+/// Key exchanges, authentication, renegotiation and session tickets.
+pub const HANDSHAKE: &str = "handshake";
+/// Per-packet forwarding decisions between the tunnel and the network.
+pub const DATAPATH: &str = "datapath";
+/// Admin and health endpoints and what administrators do through them.
+pub const ADMIN: &str = "admin";
+/// The tun device, or what stands in for it.
+pub const TUN: &str = "tun";
+/// Packets that fail to decrypt or encrypt.
+pub const CRYPTO: &str = "crypto";
+
+/// Targets events of the subsystems are logged under, see `LogConfig::subsystems`.
+pub const SUBSYSTEMS: [&str; 5] = [HANDSHAKE, DATAPATH, ADMIN, TUN, CRYPTO];
+
+fn default_level() -> String {
+  "info".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum LogTarget {
+  #[default]
+  Stderr,
+  Syslog(SyslogConfig),
+  /// Windows event log, under the application's name as the source.
+  EventLog,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SyslogConfig {
+  #[serde(default)]
+  pub transport: SyslogTransport,
+
+  /// Socket path for `unix`, `host:port` otherwise; defaults to `/dev/log`.
+  #[serde(default)]
+  pub address: Option<String>,
+
+  /// `kern`, `user`, `daemon`, `auth` or `local0` to `local7`.
+  #[serde(default = "default_facility")]
+  pub facility: String,
+
+  /// Defaults to the name of the binary.
+  #[serde(default)]
+  pub app_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyslogTransport {
+  #[default]
+  Unix,
+  Udp,
+  /// Frames are prefixed with their length as described in RFC 6587.
+  Tcp,
+}
+
+fn default_facility() -> String {
+  "daemon".to_string()
+}
+
+/// Filter of the global subscriber, along with the level it was configured with.
+static LEVEL: OnceLock<(reload::Handle<Targets, Registry>, LevelFilter)> = OnceLock::new();
+
+/// Installs the global subscriber, along with the flight recorder if configured; `app_name` names the binary
+/// in syslog messages.
+pub fn init(config: &LogConfig, app_name: &str) -> anyhow::Result<()> {
+  let level = parse_level(&config.level)?;
+  let (filter, handle) = reload::Layer::new(filter(level, &config.subsystems)?);
+
+  let layer: Box<dyn Layer<Registry> + Send + Sync> = match config.target {
+    LogTarget::Stderr => Box::new(tracing_subscriber::fmt::layer()),
+    LogTarget::Syslog(ref syslog) => Box::new(SyslogLayer::new(syslog, app_name)?),
+    #[cfg(windows)]
+    LogTarget::EventLog => Box::new(eventlog::EventLogLayer::new(app_name)?),
+    #[cfg(not(windows))]
+    LogTarget::EventLog => anyhow::bail!("The event log is only available on Windows"),
+  };
+  // Filtered per layer, so that the recorder gets its events whatever is logged.
+  let recorder = config.flight_recorder.as_ref().map(recorder::install).transpose()?;
+  tracing_subscriber::registry().with(layer.with_filter(filter)).with(recorder).try_init()?;
+
+  _ = LEVEL.set((handle, level));
+  Ok(())
+}
+
+pub fn parse_level(level: &str) -> anyhow::Result<LevelFilter> {
+  level.parse().map_err(|_| anyhow::anyhow!("Invalid log level: {}", level))
+}
+
+/// Filter logging at `level`, except for the subsystems given a level of their own.
+pub(crate) fn filter(level: LevelFilter, subsystems: &BTreeMap<String, String>) -> anyhow::Result<Targets> {
+  let mut targets = Targets::new().with_default(level);
+  for (subsystem, level) in subsystems {
+    if !SUBSYSTEMS.contains(&subsystem.as_str()) {
+      anyhow::bail!("Unknown log subsystem {}; expected one of {}", subsystem, SUBSYSTEMS.join(", "));
+    }
+    targets = targets.with_target(subsystem.clone(), parse_level(level)?);
+  }
+  Ok(targets)
+}
+
+/// Current level of the subscriber installed by `init`, outside of subsystems with their own level.
+pub fn level() -> Option<LevelFilter> {
+  LEVEL.get().and_then(|(handle, _)| handle.with_current(Targets::default_level).ok().flatten())
+}
+
+/// Changes the level of the subscriber installed by `init` without restarting the process; subsystems
+/// configured with their own level keep it.
+pub fn set_level(level: LevelFilter) -> anyhow::Result<()> {
+  let Some((handle, _)) = LEVEL.get() else {
+    anyhow::bail!("Logging isn't initialized");
+  };
+  handle.modify(|targets| *targets = std::mem::take(targets).with_default(level))?;
+  tracing::info!("Log level set to {}", level);
+  Ok(())
+}
+
+/// Makes logging one step more verbose, going back to the configured level after `trace`.
+pub fn cycle_level() -> anyhow::Result<LevelFilter> {
+  let (Some((_, configured)), Some(current)) = (LEVEL.get(), level()) else {
+    anyhow::bail!("Logging isn't initialized");
+  };
+  let next = next_level(current, *configured);
+  set_level(next)?;
+  Ok(next)
+}
+
+fn next_level(current: LevelFilter, configured: LevelFilter) -> LevelFilter {
+  match current {
+    LevelFilter::OFF => LevelFilter::ERROR,
+    LevelFilter::ERROR => LevelFilter::WARN,
+    LevelFilter::WARN => LevelFilter::INFO,
+    LevelFilter::INFO => LevelFilter::DEBUG,
+    LevelFilter::DEBUG => LevelFilter::TRACE,
+    _ if configured == LevelFilter::TRACE => LevelFilter::INFO,
+    _ => configured,
+  }
+}
+
+/// Cycles the log level, see `cycle_level`, each time the process receives SIGUSR1.
+#[cfg(unix)]
+pub async fn cycle_on_sigusr1() -> anyhow::Result<()> {
+  let mut signals = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+  while signals.recv().await.is_some() {
+    if let Err(e) = cycle_level() {
+      tracing::error!("Failed to change the log level: {}", e);
+    }
+  }
+  Ok(())
+}
+
+enum Sink {
+  #[cfg(unix)]
+  Unix(UnixDatagram, String),
+  Udp(UdpSocket),
+  Tcp(Option<TcpStream>, String),
+}
+
+/// Sends each event as an RFC 5424 message.
+pub struct SyslogLayer {
+  sink: Mutex<Sink>,
+  facility: u8,
+  hostname: String,
+  app_name: String,
+}
+
+impl SyslogLayer {
+  pub fn new(config: &SyslogConfig, app_name: &str) -> anyhow::Result<Self> {
+    let facility = facility_code(&config.facility)?;
+    let sink = match (config.transport, config.address.clone()) {
+      #[cfg(unix)]
+      (SyslogTransport::Unix, address) => {
+        Sink::Unix(UnixDatagram::unbound()?, address.unwrap_or("/dev/log".into()))
+      }
+      #[cfg(not(unix))]
+      (SyslogTransport::Unix, _) => {
+        anyhow::bail!("Syslog over a unix socket isn't available on this platform")
+      }
+      (SyslogTransport::Udp, Some(address)) => {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(&address)?;
+        Sink::Udp(socket)
+      }
+      (SyslogTransport::Tcp, Some(address)) => Sink::Tcp(None, address),
+      (transport, None) => anyhow::bail!("Syslog over {:?} requires an address", transport),
+    };
+
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").map(|h| h.trim().to_string());
+
+    Ok(Self {
+      sink: Mutex::new(sink),
+      facility,
+      hostname: hostname.ok().filter(|h| !h.is_empty()).unwrap_or("-".into()),
+      app_name: config.app_name.clone().unwrap_or(app_name.to_string()),
+    })
+  }
+
+  fn format(&self, level: &Level, message: &str, time: SystemTime) -> String {
+    let severity = match *level {
+      Level::ERROR => 3,
+      Level::WARN => 4,
+      Level::INFO => 6,
+      Level::DEBUG | Level::TRACE => 7,
+    };
+
+    format!(
+      "<{}>1 {} {} {} {} - - {}",
+      self.facility * 8 + severity,
+      rfc3339(time),
+      self.hostname,
+      self.app_name,
+      std::process::id(),
+      message
+    )
+  }
+
+  fn send(&self, frame: &str) {
+    let mut sink = self.sink.lock().unwrap();
+    // Logging must never take the process down, so failures are only reported on stderr.
+    let result = match &mut *sink {
+      #[cfg(unix)]
+      Sink::Unix(socket, path) => socket.send_to(frame.as_bytes(), &*path).map(drop),
+      Sink::Udp(socket) => socket.send(frame.as_bytes()).map(drop),
+      Sink::Tcp(stream, address) => {
+        if stream.is_none() {
+          *stream = TcpStream::connect(&*address).ok();
+        }
+        match stream {
+          Some(tcp) => {
+            let result = write!(tcp, "{} {}", frame.len(), frame);
+            if result.is_err() {
+              *stream = None;
+            }
+            result
+          }
+          None => Err(std::io::Error::other(format!("Failed to connect to {}", address))),
+        }
+      }
+    };
+
+    if let Err(e) = result {
+      eprintln!("Failed to send to syslog: {}", e);
+    }
+  }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    let mut message = MessageVisitor::default();
+    event.record(&mut message);
+    message.message.push_str(&message.fields);
+    self.send(&self.format(event.metadata().level(), &message.message, SystemTime::now()));
+  }
+}
+
+/// Renders the message followed by the other fields as `name=value`, like the default formatter.
+#[derive(Default)]
+pub(crate) struct MessageVisitor {
+  pub(crate) message: String,
+  pub(crate) fields: String,
+}
+
+impl Visit for MessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    match field.name() {
+      "message" => _ = write!(self.message, "{:?}", value),
+      name => _ = write!(self.fields, " {}={:?}", name, value),
+    }
+  }
+}
+
+#[cfg(windows)]
+mod eventlog {
+  use std::ffi::OsStr;
+  use std::os::windows::ffi::OsStrExt;
+
+  use tracing::Event;
+  use tracing::Level;
+  use tracing::Subscriber;
+  use tracing_subscriber::layer::Context;
+  use tracing_subscriber::Layer;
+  use windows_sys::Win32::Foundation::HANDLE;
+  use windows_sys::Win32::System::EventLog::*;
+
+  use super::MessageVisitor;
+
+  fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+  }
+
+  /// Reports each event to the Application log. The source isn't registered, so Event Viewer shows the
+  /// message as an insertion string rather than a formatted description.
+  pub struct EventLogLayer {
+    handle: HANDLE,
+  }
+
+  // The handle is only passed to ReportEventW, which is thread safe.
+  unsafe impl Send for EventLogLayer {}
+  unsafe impl Sync for EventLogLayer {}
+
+  impl EventLogLayer {
+    pub fn new(app_name: &str) -> anyhow::Result<Self> {
+      let name = wide(app_name);
+      let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+      if handle.is_null() {
+        anyhow::bail!("Failed to open the event log: {}", std::io::Error::last_os_error());
+      }
+      Ok(Self { handle })
+    }
+  }
+
+  impl Drop for EventLogLayer {
+    fn drop(&mut self) {
+      unsafe { DeregisterEventSource(self.handle) };
+    }
+  }
+
+  impl<S: Subscriber> Layer<S> for EventLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+      let mut message = MessageVisitor::default();
+      event.record(&mut message);
+      message.message.push_str(&message.fields);
+
+      let kind = match *event.metadata().level() {
+        Level::ERROR => EVENTLOG_ERROR_TYPE,
+        Level::WARN => EVENTLOG_WARNING_TYPE,
+        _ => EVENTLOG_INFORMATION_TYPE,
+      };
+      let text = wide(&message.message);
+      let strings = [text.as_ptr()];
+      unsafe {
+        ReportEventW(self.handle, kind, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null())
+      };
+    }
+  }
+}
+
+fn facility_code(name: &str) -> anyhow::Result<u8> {
+  Ok(match name {
+    "kern" => 0,
+    "user" => 1,
+    "daemon" => 3,
+    "auth" => 4,
+    _ => match name.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+      Some(n) if n <= 7 => 16 + n,
+      _ => anyhow::bail!("Unknown syslog facility: {}", name),
+    },
+  })
+}
+
+/// UTC timestamp with second precision, e.g. `2024-03-01T12:00:00Z`.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+  let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+  let (days, rem) = (secs / 86400, secs % 86400);
+
+  // Civil date from days since the epoch, after Howard Hinnant's algorithm.
+  let z = days as i64 + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+
+  format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn test_rfc3339() {
+    assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1709294400)), "2024-03-01T12:00:00Z");
+  }
+
+  #[test]
+  fn test_next_level() {
+    assert_eq!(next_level(LevelFilter::INFO, LevelFilter::INFO), LevelFilter::DEBUG);
+    assert_eq!(next_level(LevelFilter::DEBUG, LevelFilter::INFO), LevelFilter::TRACE);
+    assert_eq!(next_level(LevelFilter::TRACE, LevelFilter::WARN), LevelFilter::WARN);
+    assert_eq!(next_level(LevelFilter::TRACE, LevelFilter::TRACE), LevelFilter::INFO);
+  }
+
+  #[test]
+  fn test_subsystem_levels() {
+    let subsystems = BTreeMap::from([(HANDSHAKE.to_string(), "debug".to_string())]);
+    let targets = filter(LevelFilter::WARN, &subsystems).unwrap();
+    assert_eq!(targets.default_level(), Some(LevelFilter::WARN));
+
+    let events = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(targets).with(Capture(events.clone()));
+    tracing::subscriber::with_default(subscriber, || {
+      tracing::debug!(target: HANDSHAKE, "Key exchange completed");
+      tracing::debug!(target: DATAPATH, "Dropping packet");
+      tracing::warn!(target: DATAPATH, "Send queue full");
+    });
+    assert_eq!(*events.lock().unwrap(), ["Key exchange completed", "Send queue full"]);
+
+    let subsystems = BTreeMap::from([("handshakes".to_string(), "debug".to_string())]);
+    assert!(filter(LevelFilter::WARN, &subsystems)
+      .unwrap_err()
+      .to_string()
+      .contains("Unknown log subsystem"));
+  }
+
+  /// Layer collecting the messages of events.
+  struct Capture(std::sync::Arc<Mutex<Vec<String>>>);
+
+  impl<S: Subscriber> Layer<S> for Capture {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+      let mut message = MessageVisitor::default();
+      event.record(&mut message);
+      self.0.lock().unwrap().push(message.message);
+    }
+  }
+
+  #[test]
+  fn test_facility_code() {
+    assert_eq!(facility_code("daemon").unwrap(), 3);
+    assert_eq!(facility_code("local7").unwrap(), 23);
+    assert!(facility_code("local8").is_err());
+  }
+
+  #[test]
+  fn test_udp_syslog() {
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let config = SyslogConfig {
+      transport: SyslogTransport::Udp,
+      address: Some(collector.local_addr().unwrap().to_string()),
+      facility: "local0".into(),
+      app_name: Some("vpn-test".into()),
+    };
+    let subscriber = tracing_subscriber::registry().with(SyslogLayer::new(&config, "unused").unwrap());
+    tracing::subscriber::with_default(subscriber, || tracing::warn!(client = 7, "Client {} is stale", "a"));
+
+    let mut buf = [0u8; 1024];
+    let len = collector.recv(&mut buf).unwrap();
+    let frame = String::from_utf8_lossy(&buf[..len]);
+
+    assert!(frame.starts_with("<132>1 "), "{}", frame);
+    assert!(frame.contains(&format!(" vpn-test {} - - ", std::process::id())), "{}", frame);
+    assert!(frame.ends_with("Client a is stale client=7"), "{}", frame);
+  }
+}
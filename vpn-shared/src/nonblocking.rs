@@ -0,0 +1,289 @@
+//! Driver of `protocol::Connection` on async runtimes other than Tokio, for embedders whose applications run
+//! smol or async-std. The driver itself only needs a timer from the runtime, see `Runtime`; `Smol` and
+//! `AsyncStd` provide it, and the runtime's `UdpSocket` implements `Transport`.
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures_lite::future;
+use tracing::error;
+use tracing::trace;
+
+use crate::packet::ErrorCode;
+use crate::packet::MAX_DATAGRAM_SIZE;
+use crate::protocol::Connection;
+use crate::protocol::ConnectionConfig;
+use crate::protocol::Event;
+
+/// Datagrams to and from the server. `recv` is raced against the other inputs and must lose nothing when its
+/// future is dropped unfinished, as runtime sockets do.
+pub trait Transport {
+  fn send(&self, datagram: &[u8]) -> impl Future<Output = io::Result<()>>;
+  fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>>;
+}
+
+/// IP packets in and out of the tunnel, e.g. a tun device or the embedder's own network stack. `recv` has to
+/// be cancel safe, as `Transport::recv`.
+pub trait TunDevice {
+  fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<()>>;
+  fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>>;
+}
+
+/// What the driver needs of the runtime it runs on.
+pub trait Runtime {
+  fn sleep(duration: Duration) -> impl Future<Output = ()>;
+}
+
+#[cfg(feature = "smol")]
+pub use self::smol::Smol;
+
+#[cfg(feature = "async-std")]
+pub use self::async_std::AsyncStd;
+
+#[cfg(feature = "smol")]
+mod smol {
+  use super::*;
+
+  pub struct Smol;
+
+  impl Runtime for Smol {
+    async fn sleep(duration: Duration) {
+      ::smol::Timer::after(duration).await;
+    }
+  }
+
+  /// A socket connected to the server.
+  impl Transport for ::smol::net::UdpSocket {
+    async fn send(&self, datagram: &[u8]) -> io::Result<()> {
+      ::smol::net::UdpSocket::send(self, datagram).await.map(drop)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+      ::smol::net::UdpSocket::recv(self, buf).await
+    }
+  }
+}
+
+#[cfg(feature = "async-std")]
+mod async_std {
+  use super::*;
+
+  pub struct AsyncStd;
+
+  impl Runtime for AsyncStd {
+    async fn sleep(duration: Duration) {
+      ::async_std::task::sleep(duration).await;
+    }
+  }
+
+  /// A socket connected to the server.
+  impl Transport for ::async_std::net::UdpSocket {
+    async fn send(&self, datagram: &[u8]) -> io::Result<()> {
+      ::async_std::net::UdpSocket::send(self, datagram).await.map(drop)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+      ::async_std::net::UdpSocket::recv(self, buf).await
+    }
+  }
+}
+
+enum Input {
+  Timeout,
+  Datagram(io::Result<usize>),
+  Packet(io::Result<usize>),
+}
+
+/// Connects and forwards packets until the session ends, handing every event but `Data` to `on_event`.
+/// Returns the code and reason the session ended with, see `Event::Closed`, or the error reading from the
+/// transport or the tun device failed with.
+pub async fn run<R: Runtime>(
+  transport: &impl Transport,
+  tun: &impl TunDevice,
+  mtu: u16,
+  config: ConnectionConfig,
+  mut on_event: impl FnMut(&Event),
+) -> anyhow::Result<(Option<ErrorCode>, String)> {
+  let mut connection = Connection::new(config, Instant::now())?;
+  let mut datagram_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+  let mut packet_buf = vec![0u8; mtu as usize];
+
+  loop {
+    while let Some(datagram) = connection.poll_transmit() {
+      transport.send(&datagram).await?;
+    }
+
+    while let Some(event) = connection.poll_event() {
+      match event {
+        Event::Data(packet) => {
+          if let Err(e) = tun.send(&packet).await {
+            error!("Failed to write to tun: {}", e);
+          }
+        }
+        Event::Closed { code, reason } => {
+          on_event(&Event::Closed { code, reason: reason.clone() });
+          return Ok((code, reason));
+        }
+        event => on_event(&event),
+      }
+    }
+
+    let Some(timeout) = connection.poll_timeout() else {
+      anyhow::bail!("Session closed");
+    };
+    // The timer is polled first so a steady stream of datagrams can't hold timeouts off.
+    let input = future::or(
+      async {
+        R::sleep(timeout.saturating_duration_since(Instant::now())).await;
+        Input::Timeout
+      },
+      future::or(async { Input::Datagram(transport.recv(&mut datagram_buf).await) }, async {
+        Input::Packet(tun.recv(&mut packet_buf).await)
+      }),
+    )
+    .await;
+
+    match input {
+      Input::Timeout => connection.handle_timeout(Instant::now()),
+      Input::Datagram(len) => {
+        let established = connection.is_established();
+        match connection.handle_datagram(Instant::now(), &datagram_buf[..len?]) {
+          Err(e) if !established => return Err(e),
+          Err(e) => trace!("Dropping datagram from server: {}", e),
+          Ok(()) => {}
+        }
+      }
+      // Packets read before the session is established have nowhere to go yet.
+      Input::Packet(len) if connection.is_established() => {
+        if let Some(datagram) = connection.seal_data(Instant::now(), packet_buf[..len?].to_vec())? {
+          transport.send(&datagram).await?;
+        }
+      }
+      Input::Packet(len) => drop(len?),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::UdpSocket;
+  use std::thread;
+
+  use super::*;
+  use crate::creds::Credentials;
+  use crate::packet::ClientPacket;
+  use crate::packet::EncryptedPacket;
+  use crate::packet::Features;
+  use crate::packet::ServerPacket;
+  use crate::packet::KEY_SIZE;
+  use crate::peer::PeerEncryption;
+  use crate::protocol::Accepted;
+  use crate::protocol::ClientAuth;
+  use crate::protocol::Offer;
+  use crate::protocol::ServerHandshake;
+  use crate::transform::Registry;
+
+  /// Tun device standing in for the host over a socket.
+  struct Tun<S>(S);
+
+  impl<S: Transport> TunDevice for Tun<S> {
+    fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<()>> {
+      self.0.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> {
+      self.0.recv(buf)
+    }
+  }
+
+  /// Pair of sockets connected to each other, the second one blocking for at most two seconds.
+  fn link() -> (UdpSocket, UdpSocket) {
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    a.connect(b.local_addr().unwrap()).unwrap();
+    b.connect(a.local_addr().unwrap()).unwrap();
+    b.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    (a, b)
+  }
+
+  fn next(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let len = socket.recv(&mut buf).unwrap();
+    buf.truncate(len);
+    buf
+  }
+
+  /// Plays the server against the driver running on `R`, with sockets of the runtime made by `wrap`.
+  fn exchange<R: Runtime + 'static, S: Transport + 'static>(wrap: fn(UdpSocket) -> S) {
+    let (client, server) = link();
+    let (tun, host) = link();
+    let config = ConnectionConfig {
+      auth: ClientAuth::Credentials(Credentials::new("alice", "secret")),
+      server_public_key: None,
+      transforms: Registry::default(),
+      offered_transforms: Vec::new(),
+      handshake_timeout: Duration::from_secs(2),
+      mtu_probe: None,
+      subnets: Vec::new(),
+      reverse_forwards: Vec::new(),
+      peer_encryption: PeerEncryption::Off,
+      hostname: None,
+    };
+    let driver = thread::spawn(move || {
+      let mut events = Vec::new();
+      let (client, tun) = (wrap(client), Tun(wrap(tun)));
+      let closed =
+        future::block_on(run::<R>(&client, &tun, 1500, config, |event| events.push(format!("{:?}", event))));
+      (closed.unwrap(), events)
+    });
+
+    let Ok(ClientPacket::KeyExchange { key, transforms, features, limits, .. }) =
+      EncryptedPacket::from_bytes(&next(&server)).unwrap().decrypt(&[0u8; KEY_SIZE])
+    else {
+      panic!("Expected a key exchange");
+    };
+    let registry = Registry::default();
+    let handshake = ServerHandshake {
+      transforms: &registry,
+      accepted_transforms: &[],
+      static_key: None,
+      features: Features::SUPPORTED,
+    };
+    let Accepted { session, reply, .. } = handshake
+      .accept(&key, &transforms, Offer { features, limits }, "127.0.0.1:6969".parse().unwrap(), 42)
+      .unwrap();
+    server.send(&reply).unwrap();
+    assert!(matches!(session.pipeline.open(&session.key, &next(&server)), Ok(ClientPacket::Auth(_))));
+
+    let seal = |packet: &ServerPacket| session.pipeline.seal(&session.key, session.id, packet).unwrap();
+    server.send(&seal(&ServerPacket::AuthOk)).unwrap();
+    assert!(matches!(session.pipeline.open(&session.key, &next(&server)), Ok(ClientPacket::Ping)));
+
+    server.send(&seal(&ServerPacket::Data(vec![0x45, 1]))).unwrap();
+    assert_eq!(next(&host), [0x45, 1]);
+    host.send(&[0x45, 2]).unwrap();
+    assert!(
+      matches!(session.pipeline.open(&session.key, &next(&server)), Ok(ClientPacket::Data(d)) if d == [0x45, 2])
+    );
+
+    let disconnect = ServerPacket::Disconnect { code: ErrorCode::Kicked, reason: "bye".into() };
+    server.send(&seal(&disconnect)).unwrap();
+    let ((code, reason), events) = driver.join().unwrap();
+    assert_eq!((code, reason.as_str()), (Some(ErrorCode::Kicked), "bye"));
+    assert_eq!(events[0], "Established");
+  }
+
+  #[cfg(feature = "smol")]
+  #[test]
+  fn test_run_smol() {
+    exchange::<Smol, _>(|socket| ::smol::net::UdpSocket::try_from(socket).unwrap());
+  }
+
+  #[cfg(feature = "async-std")]
+  #[test]
+  fn test_run_async_std() {
+    exchange::<AsyncStd, _>(::async_std::net::UdpSocket::from);
+  }
+}
@@ -0,0 +1,95 @@
+//! Options of the outer IPv4 packets carrying tunnel datagrams, the only transport the tunnel has.
+
+use std::io;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct OuterConfig {
+  /// Set DF so that datagrams too large for the path are dropped with an ICMP error instead of fragmented
+  /// on the way, which path MTU discovery needs; `false` lets routers fragment them. Defaults to the
+  /// kernel's choice, `ip_no_pmtu_disc` on Linux.
+  #[serde(default)]
+  pub dont_fragment: Option<bool>,
+
+  /// TTL of outer packets, for networks that filter by it; defaults to the kernel's.
+  #[serde(default)]
+  pub ttl: Option<u8>,
+}
+
+impl OuterConfig {
+  pub fn apply(&self, socket: &UdpSocket) -> io::Result<()> {
+    if let Some(ttl) = self.ttl {
+      if ttl == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Outer TTL must be at least 1"));
+      }
+      socket.set_ttl(ttl as u32)?;
+    }
+    if let Some(dont_fragment) = self.dont_fragment {
+      sys::set_dont_fragment(socket, dont_fragment)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+  use std::io;
+  use std::mem::size_of;
+  use std::os::fd::AsRawFd;
+
+  use tokio::net::UdpSocket;
+
+  pub fn set_dont_fragment(socket: &UdpSocket, dont_fragment: bool) -> io::Result<()> {
+    // Unlike IP_PMTUDISC_PROBE, DO also refuses sends larger than the discovered path MTU with EMSGSIZE.
+    let mode = match dont_fragment {
+      true => libc::IP_PMTUDISC_DO,
+      false => libc::IP_PMTUDISC_DONT,
+    };
+    let result = unsafe {
+      libc::setsockopt(
+        socket.as_raw_fd(),
+        libc::IPPROTO_IP,
+        libc::IP_MTU_DISCOVER,
+        &mode as *const _ as *const libc::c_void,
+        size_of::<libc::c_int>() as libc::socklen_t,
+      )
+    };
+    if result < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+  use std::io;
+
+  use tokio::net::UdpSocket;
+
+  pub fn set_dont_fragment(_socket: &UdpSocket, _dont_fragment: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Setting DF on outer packets is only supported on Linux"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_apply() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    OuterConfig::default().apply(&socket).unwrap();
+    let default_ttl = socket.ttl().unwrap();
+
+    OuterConfig { dont_fragment: cfg!(target_os = "linux").then_some(true), ttl: Some(7) }
+      .apply(&socket)
+      .unwrap();
+    assert_eq!(socket.ttl().unwrap(), 7);
+    assert!(OuterConfig { ttl: Some(0), ..Default::default() }.apply(&socket).is_err());
+    assert_ne!(default_ttl, 7);
+  }
+}
@@ -0,0 +1,122 @@
+//! `--output` of the status and diagnostic commands of both binaries: aligned tables for people, or the
+//! JSON the tables are made from for scripts, which stays the same whatever the wording of the text.
+
+use serde_json::Map;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+  #[default]
+  Text,
+  Json,
+}
+
+impl Format {
+  pub fn parse(format: &str) -> anyhow::Result<Self> {
+    match format {
+      "text" => Ok(Self::Text),
+      "json" => Ok(Self::Json),
+      _ => anyhow::bail!("Unknown output format {}, expected text or json", format),
+    }
+  }
+
+  /// `value` as pretty JSON, or as text with `text`.
+  pub fn render(self, value: &Value) -> String {
+    match self {
+      Self::Text => text(value),
+      Self::Json => serde_json::to_string_pretty(value).unwrap_or_default() + "\n",
+    }
+  }
+}
+
+/// Renders a list as a table with a column per field of its objects, and an object as its fields, one per
+/// line, followed by a table of each list among them.
+pub fn text(value: &Value) -> String {
+  match value {
+    Value::Array(rows) => table(rows),
+    Value::Object(fields) => {
+      let scalars: Vec<_> = fields.iter().filter(|(_, value)| !value.is_array()).collect();
+      let width = scalars.iter().map(|(name, _)| name.len()).max().unwrap_or_default();
+      let mut text: String =
+        scalars.iter().map(|(name, value)| format!("{:width$}  {}\n", name, cell(value))).collect();
+      for (name, rows) in fields.iter().filter_map(|(name, value)| Some((name, value.as_array()?))) {
+        text += &format!("\n{}:\n{}", name, table(rows));
+      }
+      text
+    }
+    value => format!("{}\n", cell(value)),
+  }
+}
+
+fn table(rows: &[Value]) -> String {
+  let mut columns: Vec<&str> = Vec::new();
+  for field in rows.iter().filter_map(Value::as_object).flat_map(Map::keys) {
+    if !columns.contains(&field.as_str()) {
+      columns.push(field);
+    }
+  }
+  if columns.is_empty() {
+    return rows.iter().map(|row| format!("{}\n", cell(row))).collect();
+  }
+
+  let mut lines = vec![columns.iter().map(|column| column.to_uppercase()).collect::<Vec<_>>()];
+  for row in rows {
+    lines.push(columns.iter().map(|column| row.get(column).map_or("-".to_string(), cell)).collect());
+  }
+  let widths: Vec<_> =
+    (0..columns.len()).map(|i| lines.iter().map(|line| line[i].chars().count()).max().unwrap()).collect();
+
+  let mut text = String::new();
+  for line in lines {
+    let cells: Vec<_> = line.iter().zip(&widths).map(|(cell, &width)| format!("{:width$}", cell)).collect();
+    text += cells.join("  ").trim_end();
+    text += "\n";
+  }
+  text
+}
+
+/// A value in a table: strings unquoted, nothing as `-` and nested lists as how many items they have.
+fn cell(value: &Value) -> String {
+  match value {
+    Value::Null => "-".to_string(),
+    Value::String(value) => value.clone(),
+    Value::Array(items) => format!("{} items", items.len()),
+    value => value.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn test_table() {
+    let rows = json!([
+      { "username": "alice", "bytes_in": 1024, "device": "laptop" },
+      { "username": "bob", "bytes_in": 7, "virtual_ip": null },
+    ]);
+    assert_eq!(
+      text(&rows),
+      "BYTES_IN  DEVICE  USERNAME  VIRTUAL_IP\n\
+       1024      laptop  alice     -\n\
+       7         -       bob       -\n"
+    );
+    assert_eq!(text(&json!(["home", "office"])), "home\noffice\n");
+    assert_eq!(text(&json!([])), "");
+  }
+
+  #[test]
+  fn test_object() {
+    let trace = json!({ "session_id": "01", "recording": true, "entries": [{ "seq": 1, "kind": "ping" }] });
+    assert_eq!(text(&trace), "recording   true\nsession_id  01\n\nentries:\nKIND  SEQ\nping  1\n");
+  }
+
+  #[test]
+  fn test_format() {
+    assert_eq!(Format::parse("json").unwrap(), Format::Json);
+    assert!(Format::parse("yaml").is_err());
+    assert_eq!(Format::Json.render(&json!({ "valid": true })), "{\n  \"valid\": true\n}\n");
+  }
+}
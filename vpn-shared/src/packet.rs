@@ -1,60 +1,109 @@
-use chacha20poly1305::aead::Aead;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+
+use chacha20poly1305::aead::AeadInPlace;
 use chacha20poly1305::ChaCha20Poly1305;
 use chacha20poly1305::KeyInit;
 use chacha20poly1305::Tag;
+use ipnet::Ipv4Net;
 use rand::RngCore;
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 
 use crate::creds::Credentials;
+use crate::fragment::Fragment;
+use crate::limits;
+use crate::limits::Limits;
 
 pub const NONCE_SIZE: usize = 12;
 pub const KEY_SIZE: usize = 32;
 pub const TAG_SIZE: usize = 16;
 
+pub const SESSION_ID_SIZE: usize = 8;
+
+/// Bytes a `Data` packet adds on top of its payload: the header, the tag and the bincode variant and length,
+/// of which sessions with `Features::RAW_DATA` only send one byte, see `WirePacket`.
+pub const DATA_OVERHEAD: usize = SESSION_ID_SIZE + NONCE_SIZE + TAG_SIZE + 4 + 8;
+
+/// Largest UDP payload; receive buffers take this much since transforms may grow datagrams past
+/// `datagram_size`.
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
 pub type Key = [u8; KEY_SIZE];
+pub type SessionId = u64;
 
-#[derive(Debug)]
+/// Session of packets sent before a session is established; they're encrypted with the all-zero key.
+pub const HANDSHAKE_SESSION: SessionId = 0;
+
+/// Session of packets between servers, clients and a rendezvous, see `rendezvous`.
+pub const RENDEZVOUS_SESSION: SessionId = SessionId::MAX;
+
+#[derive(Debug, Clone)]
 pub struct EncryptedPacket {
+  session_id: SessionId,
   nonce: [u8; NONCE_SIZE],
   data: Vec<u8>,
   tag: Tag,
 }
 
 impl EncryptedPacket {
-  pub fn encrypt<P: Serialize>(key: &Key, packet: &P) -> anyhow::Result<Self> {
-    let packet = bincode::serialize(packet)?;
+  pub fn encrypt<P: Serialize>(key: &Key, session_id: SessionId, packet: &P) -> anyhow::Result<Self> {
+    Self::seal(key, session_id, &bincode::serialize(packet)?)
+  }
+
+  /// Encrypts an already serialized packet, see `transform::Pipeline`.
+  pub fn seal(key: &Key, session_id: SessionId, plaintext: &[u8]) -> anyhow::Result<Self> {
+    Self::seal_in_place(key, session_id, plaintext.to_vec())
+  }
+
+  /// `seal` encrypting the buffer it's given rather than a copy.
+  pub fn seal_in_place(key: &Key, session_id: SessionId, mut data: Vec<u8>) -> anyhow::Result<Self> {
     let cipher = ChaCha20Poly1305::new(key.into());
 
     let mut nonce = [0u8; NONCE_SIZE];
     rand::thread_rng().fill_bytes(&mut nonce);
 
-    let ciphertext = cipher
-      .encrypt((&nonce).into(), packet.as_slice())
+    let tag = cipher
+      .encrypt_in_place_detached((&nonce).into(), &session_id.to_be_bytes(), &mut data)
       .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
 
-    let tag_start = ciphertext.len() - TAG_SIZE;
-    let tag = Tag::clone_from_slice(&ciphertext[tag_start..]);
+    Ok(Self { session_id, nonce, data, tag })
+  }
 
-    Ok(Self { nonce, data: ciphertext[..tag_start].to_vec(), tag })
+  pub fn session_id(&self) -> SessionId {
+    self.session_id
   }
 
   pub fn decrypt<P: for<'de> Deserialize<'de>>(&self, key: &Key) -> anyhow::Result<P> {
-    let cipher = ChaCha20Poly1305::new(key.into());
+    let decrypted = self.open(key)?;
+    limits::decode(&decrypted)
+  }
 
-    let mut ciphertext = self.data.clone();
-    ciphertext.extend_from_slice(&self.tag);
+  /// Decrypts to the serialized packet, see `transform::Pipeline`.
+  pub fn open(&self, key: &Key) -> anyhow::Result<Vec<u8>> {
+    self.clone().open_in_place(key)
+  }
 
-    let decrypted: Vec<u8> = cipher
-      .decrypt((&self.nonce).into(), ciphertext.as_ref())
-      .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))?;
+  /// `open` decrypting the packet's own buffer.
+  pub fn open_in_place(self, key: &Key) -> anyhow::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
 
-    bincode::deserialize(&decrypted).map_err(|e| anyhow::anyhow!("Deserialization failed: {}", e))
+    let mut data = self.data;
+    cipher
+      .decrypt_in_place_detached((&self.nonce).into(), &self.session_id.to_be_bytes(), &mut data, &self.tag)
+      .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))?;
+    Ok(data)
   }
 
   pub fn to_bytes(&self) -> Vec<u8> {
-    let mut bytes = Vec::with_capacity(NONCE_SIZE + self.data.len() + TAG_SIZE);
+    let mut bytes = Vec::with_capacity(SESSION_ID_SIZE + NONCE_SIZE + self.data.len() + TAG_SIZE);
+    bytes.extend_from_slice(&self.session_id.to_be_bytes());
     bytes.extend_from_slice(&self.nonce);
     bytes.extend_from_slice(&self.data);
     bytes.extend_from_slice(&self.tag);
@@ -62,10 +111,13 @@ impl EncryptedPacket {
   }
 
   pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
-    if bytes.len() < NONCE_SIZE + TAG_SIZE {
+    if bytes.len() < SESSION_ID_SIZE + NONCE_SIZE + TAG_SIZE {
       anyhow::bail!("Packet too short");
     }
 
+    let (session_id, bytes) = bytes.split_at(SESSION_ID_SIZE);
+    let session_id = SessionId::from_be_bytes(session_id.try_into()?);
+
     let nonce: [u8; NONCE_SIZE] =
       bytes[..NONCE_SIZE].try_into().map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
 
@@ -74,32 +126,633 @@ impl EncryptedPacket {
 
     let data = bytes[NONCE_SIZE..tag_start].to_vec();
 
-    Ok(Self { nonce, data, tag })
+    Ok(Self { session_id, nonce, data, tag })
   }
 }
 
+/// Session id of a datagram, which transforms leave readable.
+pub fn peek_session_id(datagram: &[u8]) -> Option<SessionId> {
+  Some(SessionId::from_be_bytes(datagram.get(..SESSION_ID_SIZE)?.try_into().ok()?))
+}
+
+/// Largest datagram carrying a `Data` packet with a payload of up to `mtu` bytes.
+pub fn datagram_size(mtu: u16) -> usize {
+  mtu as usize + DATA_OVERHEAD
+}
+
 pub fn fill_random_bytes(bytes: &mut [u8]) {
   rand::thread_rng().fill_bytes(bytes);
 }
 
+/// First byte of a `Data` packet sent as the payload alone; bincode starts packets with the index of their
+/// variant, which is never this large.
+pub const RAW_DATA_TAG: u8 = 0xff;
+
+/// Packets as they're serialized into datagrams. Bulk `Data` packets skip bincode's variant index and
+/// length when the session has `Features::RAW_DATA`: they're `RAW_DATA_TAG` followed by the payload, which
+/// saves 11 bytes and bincode's work on every packet. Those are understood from any peer, as older ones never
+/// start a packet with the tag; only sending them waits for the feature.
+pub trait WirePacket: Serialize + DeserializeOwned {
+  /// Payload of a `Data` packet.
+  fn data(&self) -> Option<&[u8]>;
+
+  fn from_data(data: Vec<u8>) -> Self;
+
+  fn encode(&self, raw_data: bool) -> anyhow::Result<Vec<u8>> {
+    match self.data() {
+      Some(data) if raw_data => {
+        let mut bytes = Vec::with_capacity(1 + data.len());
+        bytes.push(RAW_DATA_TAG);
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+      }
+      _ => Ok(bincode::serialize(self)?),
+    }
+  }
+
+  /// Reads a packet from the decrypted datagram; the payload of a raw one is copied from after the tag, as
+  /// bincode copies that of a framed one, rather than shifted down in the buffer.
+  fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+    match bytes.split_first() {
+      Some((&RAW_DATA_TAG, payload)) => Ok(Self::from_data(payload.to_vec())),
+      _ => limits::decode(bytes),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[non_exhaustive]
 pub enum ClientPacket {
   Auth(Credentials),
-  KeyExchange(Key),
+  /// Opens a session; `transforms` are offered in order of preference, see `transform::Registry::negotiate`.
+  KeyExchange {
+    key: Key,
+    transforms: Vec<String>,
+    /// When the exchange was sent, see `handshake::unix_time`; lets the server reject replayed ones.
+    timestamp: u64,
+    /// Features the client supports; `None` from clients predating them.
+    #[serde(with = "trailing")]
+    features: Option<Features>,
+    /// Limits of the client, see `limits`; `None` from clients predating them.
+    #[serde(with = "trailing")]
+    limits: Option<Limits>,
+  },
   Data(Vec<u8>),
   Ping,
   Disconnect,
+  /// Key-based alternative to `Auth`; `proof` is `handshake::client_auth_proof` for this session.
+  KeyAuth {
+    username: String,
+    public_key: Key,
+    proof: Key,
+  },
+  /// Answer to `ServerPacket::PathChallenge`, sent from the address being validated.
+  PathResponse(u64),
+  /// Part of an `Auth` or `KeyAuth` too large for one datagram, see `fragment`.
+  Fragment(Fragment),
+  /// Asks to change settings of the established session, answered with `ServerPacket::Renegotiated`.
+  Renegotiate(SessionParams),
+  /// Padding as large as the data packets of the session, echoed back with `ServerPacket::Probe`; shows
+  /// whether packets that large get through both ways.
+  Probe(Vec<u8>),
+  /// LANs behind the client the server should route to it, replacing ones registered before; answered with
+  /// `ServerPacket::Subnets`.
+  RegisterSubnets(Vec<Ipv4Net>),
+  /// Asks for all of the subnets of other sites after missing a `ServerPacket::RouteUpdate`; answered with
+  /// `ServerPacket::Routes`.
+  RequestRoutes,
+  /// Replaces the password the user authenticated with, if `old` is it; answered with
+  /// `ServerPacket::PasswordChanged`.
+  ChangePassword {
+    old: String,
+    new: String,
+  },
+  /// Ports of the server to forward to the same ports of the client's address while the session lasts,
+  /// replacing ones registered before; answered with `ServerPacket::Forwards`.
+  RegisterForwards(Vec<ReverseForward>),
+  /// Sent before giving up on the session without hearing the server end it, so that a server refusing key
+  /// exchanges from the address of established sessions takes the next one; not answered.
+  Rehandshake,
+  /// Answer to `ServerPacket::Rekey`: the client's new ephemeral key, sealed with the key being replaced.
+  /// Both sides use the key derived from the two from then on, see `handshake::client_rekey`.
+  Rekey {
+    key: Key,
+  },
+  /// Ephemeral key for a pairwise key with the client at `peer`, which the server passes on as a
+  /// `ServerPacket::PeerKey`; `reply` when it answers one, see `peer::Peers`.
+  PeerKey {
+    peer: Ipv4Addr,
+    key: Key,
+    reply: bool,
+  },
+  /// IP packet for the client at `peer`, sealed with the pairwise key, see `peer::Peers`.
+  PeerData {
+    peer: Ipv4Addr,
+    sealed: Vec<u8>,
+  },
+  /// Name other clients resolve the client's address by through the server's DNS, as a single label;
+  /// answered with `ServerPacket::Hostname`.
+  RegisterHostname(String),
+  /// The client took its routes down after being idle (`true`), or brought them back (`false`); the server
+  /// sends `Notice::Wake` ahead of the next packet for a suspended client, so that it restores them.
+  Suspended(bool),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[non_exhaustive]
 pub enum ServerPacket {
   AuthOk,
-  AuthError(String),
-  KeyExchange([u8; KEY_SIZE]),
+  AuthError {
+    code: ErrorCode,
+    message: String,
+  },
+  KeyExchange {
+    key: Key,
+    session_id: SessionId,
+    observed: SocketAddr,
+    /// Transforms of the session, picked from the client's offer.
+    transforms: Vec<String>,
+    /// Features of the session, the ones both sides support; only sent to clients that sent theirs.
+    #[serde(with = "trailing")]
+    features: Option<Features>,
+    /// Server's clock in seconds since the Unix epoch, mixed into the session key, see
+    /// `handshake::bind_time`; only sent with `Features::CLOCK`.
+    #[serde(with = "trailing")]
+    time: Option<u64>,
+    /// Limits of the server, see `limits`; only sent with `Features::LIMITS`, which comes with `CLOCK`.
+    #[serde(with = "trailing")]
+    limits: Option<Limits>,
+    /// Tag over `observed` under the server's static key, see `handshake::server_address_tag`; only sent
+    /// with `Features::PINNED_KEY`, and after `limits`.
+    #[serde(with = "trailing")]
+    address_tag: Option<Key>,
+  },
   Data(Vec<u8>),
   Error(String),
   Pong,
-  Disconnect { reason: String },
+  Disconnect {
+    code: ErrorCode,
+    reason: String,
+  },
+  /// Sent to a new address of a session; the session moves there once the client echoes the nonce back.
+  PathChallenge(u64),
+  /// Sent after `AuthOk` by servers leasing addresses; the client takes the address and resolvers.
+  NetworkConfig {
+    address: Ipv4Addr,
+    prefix_len: u8,
+    dns: Vec<Ipv4Addr>,
+  },
+  /// Sent periodically by servers configured to, so client UIs can show usage without asking for it.
+  Stats {
+    /// Bytes the client sent and received in the session.
+    sent: u64,
+    received: u64,
+    /// Left of the user's data quota; `None` without one.
+    quota_remaining: Option<u64>,
+    clients: u32,
+    max_clients: u32,
+  },
+  Notice(Notice),
+  /// The settings the server agreed to, which both sides use from now on; sealed with the transforms the
+  /// session had before.
+  Renegotiated(SessionParams),
+  /// Answer to a `KeyExchange` the server is too loaded to take on now.
+  Deferred {
+    retry_after_secs: u32,
+  },
+  /// Sent after `AuthOk` by servers issuing session tickets: `Credentials::Ticket` with `ticket` authenticates
+  /// as the same user, and gets the same address, for `lifetime_secs`.
+  Ticket {
+    ticket: Vec<u8>,
+    lifetime_secs: u64,
+  },
+  /// Echo of a `ClientPacket::Probe`.
+  Probe(Vec<u8>),
+  /// Answer to `ClientPacket::RegisterSubnets`: the LANs routed to the client from now on, and the ones it
+  /// may not route or that are taken.
+  Subnets {
+    accepted: Vec<Ipv4Net>,
+    rejected: Vec<Ipv4Net>,
+  },
+  /// Sent to clients with registered subnets by servers exchanging routes between sites: the subnets of
+  /// the other sites the client may reach through the tunnel, replacing the ones sent before. `revision`
+  /// counts the changes made to them, see `RouteUpdate`.
+  Routes {
+    revision: u32,
+    routes: Vec<Ipv4Net>,
+  },
+  /// Change to the subnets sent with `Routes`, so that sites joining and leaving don't resend all of them
+  /// to every site; applies on top of `revision - 1`.
+  RouteUpdate {
+    revision: u32,
+    added: Vec<Ipv4Net>,
+    removed: Vec<Ipv4Net>,
+  },
+  /// Answer to `ClientPacket::ChangePassword`: why the password wasn't changed, or `None` if it was. Tickets
+  /// issued before no longer resume the session; a new one follows.
+  PasswordChanged {
+    error: Option<String>,
+  },
+  /// Sent instead of `AuthError` when the credentials were fine but no address is free to lease the client;
+  /// it may retry after `retry_after_secs`.
+  PoolExhausted {
+    retry_after_secs: u32,
+  },
+  /// Answer to `ClientPacket::RegisterForwards`: the ports forwarded to the client from now on, and the ones
+  /// it may not have or that are taken.
+  Forwards {
+    accepted: Vec<ReverseForward>,
+    rejected: Vec<ReverseForward>,
+  },
+  /// A new ephemeral key of the server, asking the client to replace the session key without a new
+  /// handshake, e.g. after the session roamed; answered with `ClientPacket::Rekey`.
+  Rekey {
+    key: Key,
+  },
+  /// Ephemeral key the client at `peer` sent with `ClientPacket::PeerKey`.
+  PeerKey {
+    peer: Ipv4Addr,
+    key: Key,
+    reply: bool,
+  },
+  /// Packet the client at `peer` sealed with its pairwise key, passed on as the server got it.
+  PeerData {
+    peer: Ipv4Addr,
+    sealed: Vec<u8>,
+  },
+  /// The server doesn't pass pairwise keys to `peer`: no client has that address, or, with `client`, the
+  /// client there doesn't take them or may not be reached.
+  PeerUnavailable {
+    peer: Ipv4Addr,
+    client: bool,
+  },
+  /// Answer to `ClientPacket::RegisterHostname`: the full name the client resolves as, or `None` if `name`
+  /// was refused, e.g. because another user's client holds it.
+  Hostname {
+    name: String,
+    fqdn: Option<String>,
+  },
+}
+
+impl WirePacket for ClientPacket {
+  fn data(&self) -> Option<&[u8]> {
+    match self {
+      Self::Data(data) => Some(data),
+      _ => None,
+    }
+  }
+
+  fn from_data(data: Vec<u8>) -> Self {
+    Self::Data(data)
+  }
+}
+
+impl WirePacket for ServerPacket {
+  fn data(&self) -> Option<&[u8]> {
+    match self {
+      Self::Data(data) => Some(data),
+      _ => None,
+    }
+  }
+
+  fn from_data(data: Vec<u8>) -> Self {
+    Self::Data(data)
+  }
+}
+
+/// Port of the server forwarded to the same port of a client's address, see
+/// `ClientPacket::RegisterForwards`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReverseForward {
+  /// IP protocol number, `ip::PROTO_TCP` or `ip::PROTO_UDP`.
+  pub protocol: u8,
+  pub port: u16,
+}
+
+/// Settings of an established session that can change without a new handshake, e.g. after the path
+/// changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionParams {
+  /// Largest IP packet sent through the tunnel; the server lowers it to its own.
+  pub mtu: u16,
+  /// How often the client pings; the server keeps quiet sessions for at least three times as long.
+  pub keepalive_secs: u32,
+  /// Transforms offered in order of preference, or the ones the server picked from them.
+  pub transforms: Vec<String>,
+}
+
+/// Optional parts of the protocol, negotiated in the key exchange so that peers of different versions use
+/// the ones both support rather than sending what the other side drops. Bits unknown to a version are
+/// ignored by it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Features(u32);
+
+impl Features {
+  /// Packets compressed before encryption; reserved, no version supports it yet.
+  pub const COMPRESSION: Self = Self(1 << 0);
+  /// Sessions following the client to a new address, see `ServerPacket::PathChallenge`.
+  pub const ROAMING: Self = Self(1 << 1);
+  /// `ServerPacket::Stats` sent periodically.
+  pub const STATS_PUSH: Self = Self(1 << 2);
+  /// Control packets too large for one datagram sent as `ClientPacket::Fragment`s.
+  pub const FRAGMENTATION: Self = Self(1 << 3);
+  /// Ports of the server forwarded to the client on its request, see `ClientPacket::RegisterForwards`.
+  pub const REVERSE_FORWARDS: Self = Self(1 << 4);
+  /// `ClientPacket::Rehandshake` understood.
+  pub const REHANDSHAKE: Self = Self(1 << 5);
+  /// Session keys replaced in place, see `ServerPacket::Rekey`.
+  pub const REKEY: Self = Self(1 << 6);
+  /// `Data` packets sent without bincode's framing, see `WirePacket`.
+  pub const RAW_DATA: Self = Self(1 << 7);
+  /// Pairwise keys between clients passed on by the server, see `peer::Peers`; only sent by clients and
+  /// servers configured to use them.
+  pub const PEER_KEYS: Self = Self(1 << 8);
+  /// Hostnames of clients served by the server's DNS, see `ClientPacket::RegisterHostname`; only sent by
+  /// servers with internal DNS.
+  pub const HOSTNAMES: Self = Self(1 << 9);
+  /// `ClientPacket::Suspended` and `Notice::Wake`, see `ClientPacket::Suspended`.
+  pub const IDLE_SUSPEND: Self = Self(1 << 10);
+  /// `time` in the server's `KeyExchange`.
+  pub const CLOCK: Self = Self(1 << 11);
+  /// `limits` in `KeyExchange`, see `limits`.
+  pub const LIMITS: Self = Self(1 << 12);
+  /// The client pinned the server's static key, which the server then mixes into the session key, see
+  /// `handshake::server_session_key`; only sent by clients pinning it and servers having one.
+  pub const PINNED_KEY: Self = Self(1 << 13);
+
+  /// Features of this version.
+  pub const SUPPORTED: Self = Self(
+    Self::ROAMING.0
+      | Self::STATS_PUSH.0
+      | Self::FRAGMENTATION.0
+      | Self::REVERSE_FORWARDS.0
+      | Self::REHANDSHAKE.0
+      | Self::REKEY.0
+      | Self::RAW_DATA.0
+      | Self::PEER_KEYS.0
+      | Self::HOSTNAMES.0
+      | Self::IDLE_SUSPEND.0
+      | Self::CLOCK.0
+      | Self::LIMITS.0
+      | Self::PINNED_KEY.0,
+  );
+  /// Features of the versions before they were negotiated, assumed of peers that don't send any.
+  pub const LEGACY: Self = Self(Self::ROAMING.0 | Self::STATS_PUSH.0 | Self::FRAGMENTATION.0);
+
+  pub(crate) const NAMES: [(Self, &'static str); 14] = [
+    (Self::COMPRESSION, "compression"),
+    (Self::ROAMING, "roaming"),
+    (Self::STATS_PUSH, "stats-push"),
+    (Self::FRAGMENTATION, "fragmentation"),
+    (Self::REVERSE_FORWARDS, "reverse-forwards"),
+    (Self::REHANDSHAKE, "rehandshake"),
+    (Self::REKEY, "rekey"),
+    (Self::RAW_DATA, "raw-data"),
+    (Self::PEER_KEYS, "peer-keys"),
+    (Self::HOSTNAMES, "hostnames"),
+    (Self::IDLE_SUSPEND, "idle-suspend"),
+    (Self::CLOCK, "clock"),
+    (Self::LIMITS, "limits"),
+    (Self::PINNED_KEY, "pinned-key"),
+  ];
+
+  pub const fn empty() -> Self {
+    Self(0)
+  }
+
+  pub const fn from_bits(bits: u32) -> Self {
+    Self(bits)
+  }
+
+  pub const fn bits(self) -> u32 {
+    self.0
+  }
+
+  pub const fn contains(self, other: Self) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  pub const fn intersection(self, other: Self) -> Self {
+    Self(self.0 & other.0)
+  }
+
+  pub const fn union(self, other: Self) -> Self {
+    Self(self.0 | other.0)
+  }
+
+  /// Features of `self` missing from `other`.
+  pub const fn difference(self, other: Self) -> Self {
+    Self(self.0 & !other.0)
+  }
+
+  /// The feature called `name`, as `names` gives it.
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::NAMES.iter().find(|(_, known)| *known == name).map(|(feature, _)| *feature)
+  }
+
+  /// Names of the known features, in order of their bits.
+  pub fn names(self) -> Vec<&'static str> {
+    Self::NAMES.iter().filter(|(feature, _)| self.contains(*feature)).map(|(_, name)| *name).collect()
+  }
+}
+
+impl fmt::Display for Features {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.names() {
+      names if names.is_empty() => f.write_str("none"),
+      names => f.write_str(&names.join(", ")),
+    }
+  }
+}
+
+/// Field added to the end of a packet after peers were released: left out when `None`, and `None` when the
+/// packet ends before it, so that packets are the same to and from peers that don't know it.
+mod trailing {
+  use super::*;
+
+  pub fn serialize<T: Serialize, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+      Some(value) => value.serialize(serializer),
+      None => serializer.serialize_unit(),
+    }
+  }
+
+  pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Option<T>, D::Error> {
+    Ok(T::deserialize(deserializer).ok())
+  }
+}
+
+/// Heads-up about the session that doesn't end it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Notice {
+  /// The user has used `percent` of their data quota, `remaining` bytes are left.
+  QuotaWarning { percent: u8, remaining: u64 },
+  /// `source` opened a connection to `port` of the client through a port forward of the user; `protocol` is
+  /// the IP protocol number, see `ip::PROTO_TCP` and `ip::PROTO_UDP`.
+  InboundConnection { protocol: u8, source: SocketAddrV4, port: u16 },
+  /// The server has traffic for a client that suspended its routes, see `ClientPacket::Suspended`.
+  Wake,
+}
+
+/// Why the server refused or ended a session; the message alongside it is for humans.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+  InvalidCredentials,
+  Revoked,
+  Expired,
+  ServerFull,
+  QuotaExceeded,
+  /// The server no longer knows the session, e.g. it dropped it as stale.
+  SessionLost,
+  /// An administrator ended the session.
+  Kicked,
+  /// The server was full and a higher-priority user took the session's place.
+  Preempted,
+  /// The server deferred the key exchange while shedding load and asked to retry later.
+  Overloaded,
+  /// Every address the server leases is taken; see `ServerPacket::PoolExhausted`.
+  PoolExhausted,
+  /// The client lacks features the server requires of clients, so it has to be upgraded.
+  VersionTooOld,
+}
+
+impl ErrorCode {
+  /// Whether connecting again with the same credentials is bound to fail the same way, or the server wants
+  /// the client to stay away.
+  pub fn is_permanent(self) -> bool {
+    matches!(
+      self,
+      Self::InvalidCredentials | Self::Revoked | Self::Expired | Self::Kicked | Self::VersionTooOld
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_roundtrip() {
+    let key = [7u8; KEY_SIZE];
+    let packet = EncryptedPacket::encrypt(&key, 42, &ClientPacket::Data(vec![1, 2, 3])).unwrap();
+    let packet = EncryptedPacket::from_bytes(&packet.to_bytes()).unwrap();
+
+    assert_eq!(packet.session_id(), 42);
+    assert!(matches!(packet.decrypt(&key).unwrap(), ClientPacket::Data(data) if data == vec![1, 2, 3]));
+  }
+
+  #[test]
+  fn test_limits_trailing() {
+    let key = [7u8; KEY_SIZE];
+    let limits = Limits { max_packet_size: 1500, max_credential_len: 256, max_routes: 8 };
+    let packet = ClientPacket::KeyExchange {
+      key,
+      transforms: Vec::new(),
+      timestamp: 1,
+      features: Some(Features::SUPPORTED),
+      limits: Some(limits),
+    };
+    let bytes = bincode::serialize(&packet).unwrap();
+    assert!(
+      matches!(limits::decode(&bytes).unwrap(), ClientPacket::KeyExchange { limits: Some(l), .. } if l == limits)
+    );
+
+    // Peers predating limits leave them out, which reads as none; they ignore the trailing bytes of ours.
+    let legacy = ClientPacket::KeyExchange {
+      key,
+      transforms: Vec::new(),
+      timestamp: 1,
+      features: Some(Features::SUPPORTED),
+      limits: None,
+    };
+    let without = bincode::serialize(&legacy).unwrap();
+    assert_eq!(bytes.len(), without.len() + 12);
+    assert!(matches!(limits::decode(&without).unwrap(), ClientPacket::KeyExchange { limits: None, .. }));
+  }
+
+  #[test]
+  fn test_features_trailing() {
+    let key = [7u8; KEY_SIZE];
+    let features = Features::from_bits(Features::SUPPORTED.bits() | 1 << 31);
+    let packet = ClientPacket::KeyExchange {
+      key,
+      transforms: Vec::new(),
+      timestamp: 1,
+      features: Some(features),
+      limits: None,
+    };
+    let legacy =
+      ClientPacket::KeyExchange { key, transforms: Vec::new(), timestamp: 1, features: None, limits: None };
+    let (with, without) = (bincode::serialize(&packet).unwrap(), bincode::serialize(&legacy).unwrap());
+    assert_eq!(with.len(), without.len() + 4);
+
+    // Packets of peers predating features read as having none.
+    assert!(
+      matches!(bincode::deserialize(&with).unwrap(), ClientPacket::KeyExchange { features: Some(f), .. } if f == features)
+    );
+    assert!(matches!(
+      bincode::deserialize(&without).unwrap(),
+      ClientPacket::KeyExchange { features: None, .. }
+    ));
+
+    assert_eq!(features.intersection(Features::SUPPORTED), Features::SUPPORTED);
+    assert_eq!(
+      Features::SUPPORTED.to_string(),
+      "roaming, stats-push, fragmentation, reverse-forwards, rehandshake, rekey, raw-data, peer-keys, \
+       hostnames, idle-suspend, clock, limits, pinned-key"
+    );
+    assert_eq!(Features::empty().to_string(), "none");
+  }
+
+  #[test]
+  fn test_credentials_roundtrip() {
+    let key = [7u8; KEY_SIZE];
+    let credentials = [
+      Credentials::new("alice", "secret"),
+      Credentials::Token("eyJ...".into()),
+      Credentials::Ticket(vec![1, 2, 3]),
+    ];
+    for credentials in credentials {
+      let packet = EncryptedPacket::encrypt(&key, 42, &ClientPacket::Auth(credentials.clone())).unwrap();
+      assert!(matches!(packet.decrypt(&key).unwrap(), ClientPacket::Auth(c) if c == credentials));
+    }
+  }
+
+  #[test]
+  fn test_datagram_size() {
+    let key = [7u8; KEY_SIZE];
+    let bytes = EncryptedPacket::encrypt(&key, 42, &ServerPacket::Data(vec![0; 9000])).unwrap().to_bytes();
+    assert_eq!(bytes.len(), datagram_size(9000));
+  }
+
+  #[test]
+  fn test_raw_data() {
+    let packet = ServerPacket::Data(vec![0x45; 1400]);
+    let (raw, framed) = (packet.encode(true).unwrap(), packet.encode(false).unwrap());
+    assert_eq!(raw.len() + 11, framed.len());
+    assert_eq!(raw[0], RAW_DATA_TAG);
+
+    // Read either way, whatever the session negotiated.
+    for bytes in [raw, framed] {
+      assert!(matches!(ServerPacket::decode(&bytes).unwrap(), ServerPacket::Data(d) if d == [0x45; 1400]));
+    }
+    assert!(matches!(
+      ClientPacket::decode(&ClientPacket::Ping.encode(true).unwrap()).unwrap(),
+      ClientPacket::Ping
+    ));
+    assert!(matches!(ClientPacket::decode(&[RAW_DATA_TAG]).unwrap(), ClientPacket::Data(d) if d.is_empty()));
+  }
+
+  #[test]
+  fn test_session_id_is_authenticated() {
+    let key = [7u8; KEY_SIZE];
+    let mut bytes = EncryptedPacket::encrypt(&key, 42, &ClientPacket::Ping).unwrap().to_bytes();
+    bytes[SESSION_ID_SIZE - 1] = 43;
+
+    let packet = EncryptedPacket::from_bytes(&bytes).unwrap();
+    assert_eq!(packet.session_id(), 43);
+    assert!(packet.decrypt::<ClientPacket>(&key).is_err());
+  }
 }
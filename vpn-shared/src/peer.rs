@@ -0,0 +1,522 @@
+//! Traffic between clients sealed with keys only the two of them hold, so that the server relaying it can't
+//! read it. The first packet to another client of the tunnel's network sends the server an ephemeral key for
+//! it, which it passes on; the peer answers with its own and both derive the pairwise key, see
+//! `handshake::peer_key`. Packets wait for the answer, then go sealed as `ClientPacket::PeerData`, which the
+//! server passes on without the tun, its filters or its mirrors.
+//!
+//! The server could still swap the ephemeral keys for its own; `handshake::fingerprint` of the key, logged
+//! on both sides, tells the users whether it did. Once agreed on, a key stays for the session: offers of a
+//! new one are refused and reported, so that the server can't swap it after the users compared
+//! fingerprints, until the user trusts the peer again with `Peers::trust`. Each sealed packet carries a
+//! counter, and ones seen before are dropped. Subnets behind other clients aren't covered, as their traffic
+//! leaves the peer anyway.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use std::time::Instant;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+use tracing::info;
+use tracing::trace;
+use tracing::warn;
+
+use crate::handshake;
+use crate::handshake::KeyPair;
+use crate::ip;
+use crate::logging;
+use crate::packet::ClientPacket;
+use crate::packet::EncryptedPacket;
+use crate::packet::Key;
+
+/// How long to wait for the answer to an offer before sending it again.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(1);
+/// Offers sent before taking the peer for one that doesn't answer.
+const OFFER_ATTEMPTS: u32 = 3;
+/// Packets held for a peer while its key is being agreed on; later ones are dropped.
+const MAX_QUEUED: usize = 16;
+/// How long an address is taken to be no client, or one without pairwise keys, before asking again.
+const RECHECK: Duration = Duration::from_secs(60);
+/// Bytes of the counter sealed in front of each packet.
+const COUNTER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerEncryption {
+  /// Traffic to other clients goes through the server like any other.
+  #[default]
+  Off,
+  /// Sealed for peers that take pairwise keys; others are reached through the server as before.
+  Prefer,
+  /// Like `Prefer`, but packets to clients without pairwise keys are dropped.
+  Require,
+}
+
+enum Peer {
+  /// Key offered, waiting for the answer.
+  Offered { ephemeral: KeyPair, deadline: Instant, attempts: u32, queued: Vec<Vec<u8>> },
+  /// `peer_ephemeral` is the key of the peer it was agreed on with, and `answer` the one of ours that
+  /// answered it, sent again if the offer arrives again. `sent` counts the packets sealed with the key.
+  Keyed { key: Key, peer_ephemeral: Key, answer: Option<Key>, sent: u64, window: Window },
+  /// No client has the address, or, with `client`, the one there takes no pairwise keys.
+  Unavailable { client: bool, until: Instant },
+}
+
+/// Pairwise keys of a client with the other clients it talks to.
+pub struct Peers {
+  mode: PeerEncryption,
+  /// Address of the client with the prefix of the tunnel's network, once the server assigned it.
+  network: Option<Ipv4Net>,
+  peers: HashMap<Ipv4Addr, Peer>,
+  /// Packets for the server, to be sealed with the session key.
+  packets: VecDeque<ClientPacket>,
+  /// Packets from peers, for the tun.
+  received: VecDeque<Vec<u8>>,
+  /// Peers whose offer of a new key was refused, see `Peers::trust`.
+  refused: VecDeque<Ipv4Addr>,
+}
+
+impl Peers {
+  pub fn new(mode: PeerEncryption) -> Self {
+    Self {
+      mode,
+      network: None,
+      peers: HashMap::new(),
+      packets: VecDeque::new(),
+      received: VecDeque::new(),
+      refused: VecDeque::new(),
+    }
+  }
+
+  /// Turns pairwise keys off for a server that doesn't pass them on.
+  pub fn disable(&mut self) {
+    if self.mode == PeerEncryption::Require {
+      warn!(target: logging::HANDSHAKE, "Server doesn't pass on pairwise keys; traffic to other clients is readable by it");
+    }
+    self.mode = PeerEncryption::Off;
+  }
+
+  /// Sets the address of the client; keys agreed on for another one are dropped.
+  pub fn set_network(&mut self, address: Ipv4Addr, prefix_len: u8) {
+    let network = Ipv4Net::new(address, prefix_len).ok();
+    if self.network.map(|network| network.addr()) != Some(address) {
+      self.peers.clear();
+    }
+    self.network = network;
+  }
+
+  /// Address of the client at the other end of `packet`, if it's one pairwise keys are used with.
+  fn peer_of(&self, packet: &[u8]) -> Option<Ipv4Addr> {
+    let network = self.network.filter(|_| self.mode != PeerEncryption::Off)?;
+    let destination = ip::ipv4_destination(packet)?;
+    let own = destination == network.addr() || destination == network.network();
+    (network.contains(&destination) && !own && destination != network.broadcast()).then_some(destination)
+  }
+
+  /// What to send for an IP packet from the tun: the packet itself, sealed for its peer, or nothing while
+  /// the key of the peer is agreed on or when it's dropped.
+  pub fn send(&mut self, now: Instant, packet: Vec<u8>) -> anyhow::Result<Option<ClientPacket>> {
+    let Some(peer) = self.peer_of(&packet) else {
+      return Ok(Some(ClientPacket::Data(packet)));
+    };
+    match self.peers.get_mut(&peer) {
+      Some(Peer::Keyed { key, sent, .. }) => {
+        *sent += 1;
+        let sealed = seal(key, self.network.map(|network| network.addr()), *sent, packet)?;
+        Ok(Some(ClientPacket::PeerData { peer, sealed }))
+      }
+      Some(Peer::Offered { queued, .. }) => {
+        if queued.len() < MAX_QUEUED {
+          queued.push(packet);
+        }
+        Ok(None)
+      }
+      Some(Peer::Unavailable { client, until }) if now < *until => match (*client, self.mode) {
+        (true, PeerEncryption::Require) => {
+          trace!(target: logging::DATAPATH, "Dropping packet to {}, which takes no pairwise keys", peer);
+          Ok(None)
+        }
+        _ => Ok(Some(ClientPacket::Data(packet))),
+      },
+      _ => {
+        self.offer(now, peer, vec![packet]);
+        Ok(None)
+      }
+    }
+  }
+
+  fn offer(&mut self, now: Instant, peer: Ipv4Addr, queued: Vec<Vec<u8>>) {
+    let ephemeral = KeyPair::generate();
+    debug!(target: logging::HANDSHAKE, "Offering a pairwise key to {}", peer);
+    self.packets.push_back(ClientPacket::PeerKey { peer, key: ephemeral.public(), reply: false });
+    self.peers.insert(peer, Peer::Offered { ephemeral, deadline: now + OFFER_TIMEOUT, attempts: 1, queued });
+  }
+
+  /// Takes the ephemeral key of `peer`: completes an offer of ours, or answers one of its own.
+  pub fn handle_key(&mut self, peer: Ipv4Addr, peer_key: Key, reply: bool) -> anyhow::Result<()> {
+    let Some(own) = self.network.map(|network| network.addr()).filter(|_| self.mode != PeerEncryption::Off)
+    else {
+      anyhow::bail!("Pairwise key from {} without pairwise keys in use", peer);
+    };
+    let (ephemeral, answer, queued) = match self.peers.remove(&peer) {
+      // Offers that crossed are both completed with the keys offered, so neither is answered.
+      Some(Peer::Offered { ephemeral, queued, .. }) => (ephemeral, None, queued),
+      // An offer sent again as the answer was on its way.
+      Some(keyed @ Peer::Keyed { peer_ephemeral, answer, .. }) if peer_ephemeral == peer_key => {
+        if let (Some(answer), false) = (answer, reply) {
+          self.packets.push_back(ClientPacket::PeerKey { peer, key: answer, reply: true });
+        }
+        self.peers.insert(peer, keyed);
+        return Ok(());
+      }
+      // Late answer to an offer that was given up on or replaced.
+      previous if reply => {
+        if let Some(previous) = previous {
+          self.peers.insert(peer, previous);
+        }
+        return Ok(());
+      }
+      // The key compared by the users isn't replaced behind their backs, whether the peer restarted or the
+      // server swapped it.
+      Some(keyed @ Peer::Keyed { .. }) => {
+        warn!(
+          target: logging::HANDSHAKE,
+          "Client {} offered a new pairwise key in place of the agreed one; refusing it until the peer is trusted again", peer
+        );
+        self.peers.insert(peer, keyed);
+        self.refused.push_back(peer);
+        return Ok(());
+      }
+      // An offer of a peer without a key.
+      _ => {
+        let ephemeral = KeyPair::generate();
+        self.packets.push_back(ClientPacket::PeerKey { peer, key: ephemeral.public(), reply: true });
+        let answer = ephemeral.public();
+        (ephemeral, Some(answer), Vec::new())
+      }
+    };
+
+    let key = handshake::peer_key(&ephemeral, &peer_key, own, peer)?;
+    info!(
+      target: logging::HANDSHAKE,
+      "Agreed on a pairwise key with {}; fingerprint {}", peer, handshake::fingerprint(&key)
+    );
+    let mut sent = 0;
+    for packet in queued {
+      sent += 1;
+      let sealed = seal(&key, Some(own), sent, packet)?;
+      self.packets.push_back(ClientPacket::PeerData { peer, sealed });
+    }
+    let window = Window::default();
+    self.peers.insert(peer, Peer::Keyed { key, peer_ephemeral: peer_key, answer, sent, window });
+    Ok(())
+  }
+
+  /// Opens a packet of `peer`. One that can't be opened while there's no key, e.g. sealed with a key from
+  /// before this client restarted, has a key offered; with a key, it's dropped, as the key is kept.
+  pub fn handle_data(&mut self, now: Instant, peer: Ipv4Addr, sealed: &[u8]) -> anyhow::Result<()> {
+    match self.peers.get_mut(&peer) {
+      Some(Peer::Keyed { key, window, .. }) => {
+        let (counter, packet) = open(key, peer, sealed)?;
+        if !window.accept(counter) {
+          anyhow::bail!("Replayed packet of {}", peer);
+        }
+        self.received.push_back(packet);
+        Ok(())
+      }
+      Some(Peer::Offered { .. }) => anyhow::bail!("No pairwise key with {}", peer),
+      _ => {
+        if self.mode != PeerEncryption::Off {
+          self.offer(now, peer, Vec::new());
+        }
+        anyhow::bail!("No pairwise key with {}", peer)
+      }
+    }
+  }
+
+  /// Drops the key agreed on with `peer`, so that the next offer of a key, whether its own or ours, is
+  /// taken; for once the users compared the fingerprint of the one refused, see `poll_refused`.
+  pub fn trust(&mut self, peer: Ipv4Addr) {
+    if let Some(Peer::Keyed { .. }) = self.peers.get(&peer) {
+      info!(target: logging::HANDSHAKE, "Trusting the next pairwise key of {}", peer);
+      self.peers.remove(&peer);
+    }
+  }
+
+  /// Peers whose offer of a new key was refused, as the one agreed on before is kept.
+  pub fn poll_refused(&mut self) -> Option<Ipv4Addr> {
+    self.refused.pop_front()
+  }
+
+  /// The server won't pass keys on to `peer`; packets held for it go through the server or are dropped.
+  pub fn handle_unavailable(&mut self, now: Instant, peer: Ipv4Addr, client: bool) {
+    let queued = match self.peers.remove(&peer) {
+      Some(Peer::Offered { queued, .. }) => queued,
+      _ => Vec::new(),
+    };
+    self.unavailable(now, peer, client, queued);
+  }
+
+  fn unavailable(&mut self, now: Instant, peer: Ipv4Addr, client: bool, queued: Vec<Vec<u8>>) {
+    if client {
+      info!(target: logging::HANDSHAKE, "Client {} takes no pairwise keys", peer);
+    }
+    if !(client && self.mode == PeerEncryption::Require) {
+      self.packets.extend(queued.into_iter().map(ClientPacket::Data));
+    }
+    self.peers.insert(peer, Peer::Unavailable { client, until: now + RECHECK });
+  }
+
+  pub fn poll_timeout(&self) -> Option<Instant> {
+    self
+      .peers
+      .values()
+      .filter_map(|peer| match peer {
+        Peer::Offered { deadline, .. } => Some(*deadline),
+        _ => None,
+      })
+      .min()
+  }
+
+  /// Offers again what went unanswered, and gives up on peers that never answer.
+  pub fn handle_timeout(&mut self, now: Instant) {
+    let expired: Vec<_> = self
+      .peers
+      .iter()
+      .filter(|(_, peer)| matches!(peer, Peer::Offered { deadline, .. } if *deadline <= now))
+      .map(|(addr, _)| *addr)
+      .collect();
+    for addr in expired {
+      let Some(Peer::Offered { ephemeral, attempts, queued, .. }) = self.peers.remove(&addr) else {
+        continue;
+      };
+      if attempts >= OFFER_ATTEMPTS {
+        warn!(target: logging::HANDSHAKE, "Client {} didn't answer the offer of a pairwise key", addr);
+        self.unavailable(now, addr, true, queued);
+        continue;
+      }
+      self.packets.push_back(ClientPacket::PeerKey { peer: addr, key: ephemeral.public(), reply: false });
+      let deadline = now + OFFER_TIMEOUT;
+      self.peers.insert(addr, Peer::Offered { ephemeral, deadline, attempts: attempts + 1, queued });
+    }
+  }
+
+  pub fn poll_packet(&mut self) -> Option<ClientPacket> {
+    self.packets.pop_front()
+  }
+
+  pub fn poll_received(&mut self) -> Option<Vec<u8>> {
+    self.received.pop_front()
+  }
+}
+
+/// Counters of the packets received from a peer: the highest, and which of the 64 before it were seen.
+#[derive(Debug, Default)]
+struct Window {
+  highest: u64,
+  seen: u64,
+}
+
+impl Window {
+  /// Whether `counter` is new and not too old, marking it as seen.
+  fn accept(&mut self, counter: u64) -> bool {
+    if counter > self.highest {
+      let shift = counter - self.highest;
+      self.seen = if shift >= u64::BITS.into() { 0 } else { self.seen << shift };
+      self.seen |= 1;
+      self.highest = counter;
+      return true;
+    }
+    let age = self.highest - counter;
+    if counter == 0 || age >= u64::BITS.into() || self.seen & (1 << age) != 0 {
+      return false;
+    }
+    self.seen |= 1 << age;
+    true
+  }
+}
+
+/// Seals `packet` from `own` behind its `counter`. The address is authenticated along with it so that a
+/// packet can't be reflected back to its sender under the key both sides share.
+fn seal(key: &Key, own: Option<Ipv4Addr>, counter: u64, packet: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+  let own = own.ok_or_else(|| anyhow::anyhow!("No address assigned"))?;
+  let mut data = Vec::with_capacity(COUNTER_SIZE + packet.len());
+  data.extend_from_slice(&counter.to_be_bytes());
+  data.extend_from_slice(&packet);
+  Ok(EncryptedPacket::seal_in_place(key, u32::from(own).into(), data)?.to_bytes())
+}
+
+/// Opens a packet of `peer` into its counter and the packet.
+fn open(key: &Key, peer: Ipv4Addr, sealed: &[u8]) -> anyhow::Result<(u64, Vec<u8>)> {
+  let sealed = EncryptedPacket::from_bytes(sealed)?;
+  if sealed.session_id() != u64::from(u32::from(peer)) {
+    anyhow::bail!("Packet of {} sealed by another client", peer);
+  }
+  let mut packet = sealed.open_in_place(key)?;
+  let Some(counter) = packet.get(..COUNTER_SIZE) else {
+    anyhow::bail!("Packet of {} without a counter", peer);
+  };
+  let counter = u64::from_be_bytes(counter.try_into()?);
+  packet.drain(..COUNTER_SIZE);
+  if ip::ipv4_source(&packet) != Some(peer) {
+    anyhow::bail!("Packet of {} from another address", peer);
+  }
+  Ok((counter, packet))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn packet(source: Ipv4Addr, destination: Ipv4Addr) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0];
+    packet.extend(source.octets());
+    packet.extend(destination.octets());
+    packet
+  }
+
+  fn peers(mode: PeerEncryption, address: Ipv4Addr) -> Peers {
+    let mut peers = Peers::new(mode);
+    peers.set_network(address, 24);
+    peers
+  }
+
+  /// Passes the packets of `from` to `to` as the server would, from the address of `from`.
+  fn relay(from: &mut Peers, to: &mut Peers, now: Instant) {
+    let source = from.network.unwrap().addr();
+    while let Some(packet) = from.poll_packet() {
+      match packet {
+        ClientPacket::PeerKey { key, reply, .. } => to.handle_key(source, key, reply).unwrap(),
+        ClientPacket::PeerData { sealed, .. } => to.handle_data(now, source, &sealed).unwrap(),
+        packet => panic!("unexpected {:?}", packet),
+      }
+    }
+  }
+
+  #[test]
+  fn test_pairwise_keys() {
+    let now = Instant::now();
+    let (a, b) = (Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(10, 8, 0, 3));
+    let (mut alice, mut bob) = (peers(PeerEncryption::Prefer, a), peers(PeerEncryption::Prefer, b));
+
+    // Held until the key is agreed on, then sent sealed.
+    assert!(alice.send(now, packet(a, b)).unwrap().is_none());
+    relay(&mut alice, &mut bob, now);
+    relay(&mut bob, &mut alice, now);
+    relay(&mut alice, &mut bob, now);
+    assert_eq!(bob.poll_received(), Some(packet(a, b)));
+
+    let Some(ClientPacket::PeerData { peer, sealed }) = bob.send(now, packet(b, a)).unwrap() else {
+      panic!("expected a sealed packet");
+    };
+    assert_eq!(peer, a);
+    alice.handle_data(now, b, &sealed).unwrap();
+    assert_eq!(alice.poll_received(), Some(packet(b, a)));
+    // Reflected back to its sender, or claiming another source, it doesn't open; nor does it a second time.
+    assert!(bob.handle_data(now, a, &sealed).is_err());
+    assert!(alice.handle_data(now, b, &sealed).unwrap_err().to_string().contains("Replayed"));
+
+    // An offer sent again while the answer was on its way is answered the same.
+    let d = Ipv4Addr::new(10, 8, 0, 5);
+    let mut dave = peers(PeerEncryption::Prefer, d);
+    assert!(alice.send(now, packet(a, d)).unwrap().is_none());
+    let offer = alice.poll_packet().unwrap();
+    alice.handle_timeout(now + OFFER_TIMEOUT);
+    alice.packets.push_front(offer);
+    relay(&mut alice, &mut dave, now);
+    relay(&mut dave, &mut alice, now);
+    relay(&mut alice, &mut dave, now);
+    assert_eq!(dave.poll_received(), Some(packet(a, d)));
+
+    // Offers that cross agree on the same key.
+    let c = Ipv4Addr::new(10, 8, 0, 4);
+    let mut carol = peers(PeerEncryption::Prefer, c);
+    assert!(alice.send(now, packet(a, c)).unwrap().is_none());
+    assert!(carol.send(now, packet(c, a)).unwrap().is_none());
+    relay(&mut alice, &mut carol, now);
+    relay(&mut carol, &mut alice, now);
+    relay(&mut alice, &mut carol, now);
+    assert_eq!(carol.poll_received(), Some(packet(a, c)));
+    assert_eq!(alice.poll_received(), Some(packet(c, a)));
+    assert!(alice.poll_packet().is_none() && carol.poll_packet().is_none());
+
+    // Outside of the network, packets go through the server.
+    let outside = packet(a, Ipv4Addr::new(192, 0, 2, 1));
+    assert!(matches!(alice.send(now, outside).unwrap(), Some(ClientPacket::Data(_))));
+  }
+
+  #[test]
+  fn test_key_change() {
+    let now = Instant::now();
+    let (a, b) = (Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(10, 8, 0, 3));
+    let (mut alice, mut bob) = (peers(PeerEncryption::Prefer, a), peers(PeerEncryption::Prefer, b));
+    assert!(alice.send(now, packet(a, b)).unwrap().is_none());
+    relay(&mut alice, &mut bob, now);
+    relay(&mut bob, &mut alice, now);
+    relay(&mut alice, &mut bob, now);
+    assert_eq!(bob.poll_received(), Some(packet(a, b)));
+
+    // Bob restarted, or the server made up an offer: it's refused, and the agreed key stays.
+    let mut restarted = peers(PeerEncryption::Prefer, b);
+    assert!(restarted.send(now, packet(b, a)).unwrap().is_none());
+    relay(&mut restarted, &mut alice, now);
+    assert_eq!(alice.poll_refused(), Some(b));
+    assert!(alice.poll_packet().is_none());
+    assert!(matches!(alice.send(now, packet(a, b)).unwrap(), Some(ClientPacket::PeerData { .. })));
+
+    // Once trusted again, the next offer is taken.
+    alice.trust(b);
+    restarted.handle_timeout(now + OFFER_TIMEOUT);
+    relay(&mut restarted, &mut alice, now);
+    relay(&mut alice, &mut restarted, now);
+    assert_eq!(restarted.poll_received(), None);
+    assert!(matches!(restarted.send(now, packet(b, a)).unwrap(), Some(ClientPacket::PeerData { .. })));
+  }
+
+  #[test]
+  fn test_window() {
+    let mut window = Window::default();
+    assert!(window.accept(2) && window.accept(1) && window.accept(100));
+    assert!(!window.accept(2) && !window.accept(100) && !window.accept(0));
+    assert!(window.accept(99) && !window.accept(99));
+    // Too old to tell whether it was seen.
+    assert!(!window.accept(36));
+  }
+
+  #[test]
+  fn test_unavailable() {
+    let now = Instant::now();
+    let (a, b) = (Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(10, 8, 0, 3));
+
+    let mut prefer = peers(PeerEncryption::Prefer, a);
+    assert!(prefer.send(now, packet(a, b)).unwrap().is_none());
+    prefer.handle_unavailable(now, b, true);
+    assert!(matches!(prefer.poll_packet(), Some(ClientPacket::PeerKey { .. })));
+    assert!(matches!(prefer.poll_packet(), Some(ClientPacket::Data(_))));
+    assert!(matches!(prefer.send(now, packet(a, b)).unwrap(), Some(ClientPacket::Data(_))));
+
+    let mut require = peers(PeerEncryption::Require, a);
+    assert!(require.send(now, packet(a, b)).unwrap().is_none());
+    require.handle_unavailable(now, b, true);
+    assert!(matches!(require.poll_packet(), Some(ClientPacket::PeerKey { .. })));
+    assert!(require.poll_packet().is_none());
+    assert!(require.send(now, packet(a, b)).unwrap().is_none());
+    // Asked again later, in case the peer changed.
+    assert!(require.send(now + RECHECK, packet(a, b)).unwrap().is_none());
+    assert!(matches!(require.poll_packet(), Some(ClientPacket::PeerKey { .. })));
+
+    // Offers go unanswered a few times before the peer is given up on.
+    let mut silent = peers(PeerEncryption::Prefer, a);
+    assert!(silent.send(now, packet(a, b)).unwrap().is_none());
+    while let Some(deadline) = silent.poll_timeout() {
+      silent.handle_timeout(deadline);
+    }
+    let packets: Vec<_> = std::iter::from_fn(|| silent.poll_packet()).collect();
+    assert_eq!(packets.iter().filter(|p| matches!(p, ClientPacket::PeerKey { .. })).count(), 3);
+    assert!(matches!(packets.last(), Some(ClientPacket::Data(_))));
+  }
+}
@@ -0,0 +1,1521 @@
+//! Sans-io core of the protocol: state machines that take datagrams and the current time in and hand
+//! datagrams and events out, leaving sockets, tun devices and timers to the driver. They're driven the same
+//! way under any runtime and can be tested step by step without one.
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use ipnet::Ipv4Net;
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::cert::Certificate;
+use crate::creds::Credentials;
+use crate::fragment;
+use crate::handshake;
+use crate::handshake::KeyPair;
+use crate::iface::MIN_MTU;
+use crate::limits::Limits;
+use crate::logging;
+use crate::packet;
+use crate::packet::EncryptedPacket;
+use crate::packet::ErrorCode;
+use crate::packet::Features;
+use crate::packet::Key;
+use crate::packet::Notice;
+use crate::packet::ReverseForward;
+use crate::packet::SessionId;
+use crate::packet::SessionParams;
+use crate::packet::HANDSHAKE_SESSION;
+use crate::packet::KEY_SIZE;
+use crate::packet::{ClientPacket, ServerPacket};
+use crate::peer::PeerEncryption;
+use crate::peer::Peers;
+use crate::transform::Pipeline;
+use crate::transform::Registry;
+
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest ping interval a session can be renegotiated to.
+pub const MAX_KEEPALIVE: Duration = Duration::from_secs(300);
+
+/// How long the server may stay silent, pongs included, before the session is considered lost; three
+/// ping intervals when they're longer.
+pub const SERVER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Ping rounds in a row whose pong came back but whose probe didn't before the MTU is lowered.
+pub const PROBE_LOSSES: u32 = 3;
+
+/// Offset of the client's clock from the server's at which `Event::ClockSkew` warns: time-based tokens,
+/// one-time passwords and the replay check of key exchanges start failing not far beyond it.
+pub const CLOCK_SKEW_WARNING: Duration = Duration::from_secs(10);
+
+/// MTUs stepped down to, in order, when full-size packets stop getting through.
+const MTU_STEPS: [u16; 4] = [1400, 1280, 1024, MIN_MTU];
+
+/// Key and transforms of an established session.
+#[derive(Debug, Clone)]
+pub struct Session {
+  pub key: Key,
+  pub id: SessionId,
+  pub pipeline: Arc<Pipeline>,
+}
+
+impl Session {
+  pub fn encrypt(&self, packet: &ClientPacket) -> anyhow::Result<Vec<u8>> {
+    self.pipeline.seal(&self.key, self.id, packet)
+  }
+
+  /// Datagrams carrying `packet`, split into fragments if it'd make one too large to pass unfragmented.
+  pub fn encrypt_control(&self, packet: &ClientPacket, features: Features) -> anyhow::Result<Vec<Vec<u8>>> {
+    let serialized = bincode::serialize(packet)?;
+    if serialized.len() <= fragment::FRAGMENT_SIZE {
+      return Ok(vec![self.encrypt(packet)?]);
+    }
+    if !features.contains(Features::FRAGMENTATION) {
+      anyhow::bail!(
+        "Packet of {} bytes needs fragments, which the server doesn't reassemble",
+        serialized.len()
+      );
+    }
+    fragment::split(&serialized)?.into_iter().map(|f| self.encrypt(&ClientPacket::Fragment(f))).collect()
+  }
+
+  pub fn decrypt(&self, datagram: &[u8]) -> anyhow::Result<ServerPacket> {
+    let session_id = packet::peek_session_id(datagram).unwrap_or(HANDSHAKE_SESSION);
+    if session_id != self.id {
+      anyhow::bail!("Packet for session {:#x}, expected {:#x}", session_id, self.id);
+    }
+    self.pipeline.open(&self.key, datagram)
+  }
+}
+
+/// Packets of the key exchange, sent before there's a session key.
+fn handshake_datagram<P: serde::Serialize>(packet: &P) -> anyhow::Result<Vec<u8>> {
+  Ok(EncryptedPacket::encrypt(&[0u8; KEY_SIZE], HANDSHAKE_SESSION, packet)?.to_bytes())
+}
+
+/// How the client proves who it is; a key or certificate is proven against the session being set up.
+#[derive(Clone)]
+pub enum ClientAuth {
+  Credentials(Credentials),
+  Key { username: String, key: KeyPair },
+  Certificate { certificate: Certificate, key: KeyPair },
+}
+
+impl ClientAuth {
+  fn packet(&self, server_ephemeral: &Key, session_key: &Key) -> anyhow::Result<ClientPacket> {
+    Ok(match self {
+      Self::Credentials(credentials) => ClientPacket::Auth(credentials.clone()),
+      Self::Key { username, key } => ClientPacket::KeyAuth {
+        username: username.clone(),
+        public_key: key.public(),
+        proof: handshake::client_auth_proof(key, server_ephemeral, session_key)?,
+      },
+      Self::Certificate { certificate, key } => ClientPacket::Auth(Credentials::Certificate {
+        certificate: certificate.clone(),
+        proof: handshake::client_auth_proof(key, server_ephemeral, session_key)?,
+      }),
+    })
+  }
+}
+
+#[derive(Clone)]
+pub struct ConnectionConfig {
+  pub auth: ClientAuth,
+  /// Pinned static key of the server, see `handshake::client_session_key`.
+  pub server_public_key: Option<Key>,
+  pub transforms: Registry,
+  /// Transforms to offer, in order of preference.
+  pub offered_transforms: Vec<String>,
+  /// Allowed for each of the key exchange and authentication.
+  pub handshake_timeout: Duration,
+  /// MTU the session starts with, to send a probe that large along with each ping and step down from when
+  /// only the probes are lost, see `Event::MtuReduced`; `None` to not probe. Needs a server answering probes.
+  pub mtu_probe: Option<u16>,
+  /// LANs behind the client to register once the session is established, see `Event::Subnets`.
+  pub subnets: Vec<Ipv4Net>,
+  /// Ports of the server to have forwarded to the client once the session is established, see
+  /// `Event::Forwards`.
+  pub reverse_forwards: Vec<ReverseForward>,
+  /// Whether traffic to other clients is sealed with pairwise keys, see `peer`.
+  pub peer_encryption: PeerEncryption,
+  /// Name to register once the session is established, for other clients to reach this one by through the
+  /// server's DNS, see `Event::Hostname`.
+  pub hostname: Option<String>,
+}
+
+impl ConnectionConfig {
+  /// Features sent in the key exchange; pairwise keys only when they're to be used, and the pinned key
+  /// only when there is one.
+  fn features(&self) -> Features {
+    let mut features = Features::SUPPORTED;
+    if let PeerEncryption::Off = self.peer_encryption {
+      features = features.difference(Features::PEER_KEYS);
+    }
+    if self.server_public_key.is_none() {
+      features = features.difference(Features::PINNED_KEY);
+    }
+    features
+  }
+}
+
+/// What the driver of a `Connection` has to act on.
+#[derive(Debug)]
+pub enum Event {
+  /// Authentication succeeded; data can be sent from now on.
+  Established,
+  /// IP packet for the tun device.
+  Data(Vec<u8>),
+  NetworkConfig {
+    address: Ipv4Addr,
+    prefix_len: u8,
+    dns: Vec<Ipv4Addr>,
+  },
+  Stats {
+    sent: u64,
+    received: u64,
+    quota_remaining: Option<u64>,
+    clients: u32,
+    max_clients: u32,
+  },
+  Notice(Notice),
+  Pong {
+    rtt: Duration,
+  },
+  /// The server agreed to settings asked for with `Connection::renegotiate`, which are in use from now on;
+  /// the driver applies the MTU.
+  Renegotiated(SessionParams),
+  /// Probes of the MTU kept getting lost while pings got through, which points at a path dropping large
+  /// packets; a renegotiation to `to` has been sent, and `Event::Renegotiated` follows once the server agrees.
+  MtuReduced {
+    from: u16,
+    to: u16,
+    reason: String,
+  },
+  /// The server routes `accepted` of the registered subnets to the client and refused `rejected`.
+  Subnets {
+    accepted: Vec<Ipv4Net>,
+    rejected: Vec<Ipv4Net>,
+  },
+  /// Subnets behind other sites the server routes to, replacing the ones it sent before.
+  Routes(Vec<Ipv4Net>),
+  /// The server forwards `accepted` of the requested ports to the client and refused `rejected`, all of
+  /// them if it doesn't support forwarding.
+  Forwards {
+    accepted: Vec<ReverseForward>,
+    rejected: Vec<ReverseForward>,
+  },
+  /// The server resolves the registered hostname `name` as `fqdn`, or refused it; also sent without a
+  /// registration when the server has no DNS to serve it.
+  Hostname {
+    name: String,
+    fqdn: Option<String>,
+  },
+  /// The client's clock is `offset_secs` ahead of the time the server sent in the handshake, behind when
+  /// negative, by at least `CLOCK_SKEW_WARNING`.
+  ClockSkew {
+    offset_secs: i64,
+  },
+  /// `peer` offered a new pairwise key in place of the one agreed on, which is kept until
+  /// `Connection::trust_peer` is called, see `peer`.
+  PeerKeyRefused {
+    peer: Ipv4Addr,
+  },
+  /// Answer to `Connection::change_password`: the password was changed, or why it wasn't.
+  PasswordChanged(Result<(), String>),
+  /// Ticket to resume the session with after the client restarts, see `ServerPacket::Ticket`.
+  Ticket {
+    ticket: Vec<u8>,
+    lifetime: Duration,
+  },
+  /// The connection is over: the server refused or ended the session with `code`, or it timed out without.
+  Closed {
+    code: Option<ErrorCode>,
+    reason: String,
+  },
+}
+
+enum State {
+  KeyExchange { ephemeral: KeyPair },
+  Authenticating { session: Session },
+  Established { session: Session },
+  Closed,
+}
+
+/// Outcome of the probe sent with the last ping, see `ConnectionConfig::mtu_probe`.
+struct Probe {
+  mtu: u16,
+  sent: bool,
+  answered: bool,
+  ponged: bool,
+  /// Rounds in a row in which only the probe was lost.
+  lost: u32,
+}
+
+impl Probe {
+  fn new(mtu: u16) -> Self {
+    Self { mtu, sent: false, answered: false, ponged: false, lost: 0 }
+  }
+
+  /// Accounts for the last round and starts the next one; true once the MTU should be lowered.
+  fn next_round(&mut self) -> bool {
+    if self.sent && self.answered {
+      self.lost = 0;
+    } else if self.sent && self.ponged {
+      self.lost += 1;
+    }
+    self.sent = true;
+    self.answered = false;
+    self.ponged = false;
+    self.lost >= PROBE_LOSSES
+  }
+}
+
+/// Client side of a connection to one server, from the key exchange until the session ends.
+pub struct Connection {
+  config: ConnectionConfig,
+  state: State,
+  /// Handshake step timing out, or the next ping once established.
+  deadline: Instant,
+  last_received: Instant,
+  last_ping_sent: Option<Instant>,
+  /// How long a server that deferred the key exchange asked to wait.
+  retry_after: Option<Duration>,
+  keepalive: Duration,
+  /// Transforms offered in a renegotiation the server hasn't answered yet.
+  renegotiating: Option<Vec<String>>,
+  probe: Option<Probe>,
+  /// Subnets of other sites the server advertised, at `routes_revision`.
+  routes: Vec<Ipv4Net>,
+  routes_revision: u32,
+  /// Features of the session, once the server answered the key exchange.
+  features: Features,
+  /// Lower of our limits and the server's, once it answered the key exchange.
+  limits: Limits,
+  /// Session a rekey replaced, still opening packets the server sealed before it took the new key.
+  previous: Option<Session>,
+  peers: Peers,
+  transmits: VecDeque<Vec<u8>>,
+  events: VecDeque<Event>,
+}
+
+impl Connection {
+  /// Starts the key exchange; its first datagram is ready in `poll_transmit`.
+  pub fn new(config: ConnectionConfig, now: Instant) -> anyhow::Result<Self> {
+    let ephemeral = KeyPair::generate();
+    let key_exchange = handshake_datagram(&ClientPacket::KeyExchange {
+      key: ephemeral.public(),
+      transforms: config.offered_transforms.clone(),
+      timestamp: handshake::unix_time(),
+      features: Some(config.features()),
+      limits: Some(Limits::LOCAL),
+    })?;
+
+    Ok(Self {
+      peers: Peers::new(config.peer_encryption),
+      deadline: now + config.handshake_timeout,
+      probe: config.mtu_probe.map(Probe::new),
+      config,
+      state: State::KeyExchange { ephemeral },
+      last_received: now,
+      last_ping_sent: None,
+      retry_after: None,
+      keepalive: PING_INTERVAL,
+      renegotiating: None,
+      routes: Vec::new(),
+      routes_revision: 0,
+      features: Features::empty(),
+      limits: Limits::LOCAL,
+      previous: None,
+      transmits: VecDeque::from([key_exchange]),
+      events: VecDeque::new(),
+    })
+  }
+
+  pub fn is_established(&self) -> bool {
+    matches!(self.state, State::Established { .. })
+  }
+
+  /// Features both sides support, see `Features`; empty until the key exchange is answered.
+  pub fn features(&self) -> Features {
+    self.features
+  }
+
+  /// Limits both sides hold each other to, see `limits`.
+  pub fn limits(&self) -> Limits {
+    self.limits
+  }
+
+  /// Set once the connection closed with `ErrorCode::Overloaded` or `ErrorCode::PoolExhausted`: how long to wait before connecting again.
+  pub fn retry_after(&self) -> Option<Duration> {
+    self.retry_after
+  }
+
+  /// Handles a datagram from the server. Errors during the handshake fail the connection; afterwards they
+  /// only mean the datagram was dropped.
+  pub fn handle_datagram(&mut self, now: Instant, datagram: &[u8]) -> anyhow::Result<()> {
+    self.limits.check_datagram(datagram.len())?;
+    match std::mem::replace(&mut self.state, State::Closed) {
+      State::KeyExchange { ephemeral } => {
+        let packet = EncryptedPacket::from_bytes(datagram)?.decrypt(&[0u8; KEY_SIZE]);
+        if let Ok(ServerPacket::Deferred { retry_after_secs }) = packet {
+          self.retry_after = Some(Duration::from_secs(retry_after_secs.into()));
+          self.close(
+            Some(ErrorCode::Overloaded),
+            format!("Server is overloaded; retry in {}s", retry_after_secs),
+          );
+          return Ok(());
+        }
+        let session = self.accept_key_exchange(&ephemeral, packet?)?;
+        self.state = State::Authenticating { session };
+        self.deadline = now + self.config.handshake_timeout;
+      }
+      State::Authenticating { session } => match session.decrypt(datagram)? {
+        ServerPacket::AuthOk => {
+          info!(target: logging::HANDSHAKE, "Authentication successful");
+          self.state = State::Established { session };
+          self.last_received = now;
+          self.deadline = now;
+          self.events.push_back(Event::Established);
+          if !self.config.subnets.is_empty() {
+            let mut subnets = self.config.subnets.clone();
+            if subnets.len() > self.limits.max_routes as usize {
+              warn!("Server takes {} subnets; registering the first ones only", self.limits.max_routes);
+              subnets.truncate(self.limits.max_routes as usize);
+            }
+            self.send(ClientPacket::RegisterSubnets(subnets))?;
+          }
+          if !self.config.reverse_forwards.is_empty() {
+            let requested = self.config.reverse_forwards.clone();
+            if self.features.contains(Features::REVERSE_FORWARDS) {
+              self.send(ClientPacket::RegisterForwards(requested))?;
+            } else {
+              warn!("Server doesn't support reverse forwards; going without");
+              self.events.push_back(Event::Forwards { accepted: Vec::new(), rejected: requested });
+            }
+          }
+          if let Some(ref name) = self.config.hostname {
+            if self.features.contains(Features::HOSTNAMES) {
+              self.send(ClientPacket::RegisterHostname(name.clone()))?;
+            } else {
+              warn!("Server doesn't serve hostnames; going without");
+              self.events.push_back(Event::Hostname { name: name.clone(), fqdn: None });
+            }
+          }
+        }
+        ServerPacket::AuthError { code, message } => {
+          self.close(Some(code), format!("Authentication failed: {}", message));
+        }
+        ServerPacket::PoolExhausted { retry_after_secs } => {
+          self.retry_after = Some(Duration::from_secs(retry_after_secs.into()));
+          self.close(
+            Some(ErrorCode::PoolExhausted),
+            format!("Server has no free addresses; retry in {}s", retry_after_secs),
+          );
+        }
+        _ => anyhow::bail!("Unexpected response from server"),
+      },
+      State::Established { session } => {
+        let (session, packet) = match session.decrypt(datagram) {
+          Ok(packet) => {
+            self.previous = None;
+            (session, packet)
+          }
+          Err(e) => match self.previous.as_ref().map(|previous| previous.decrypt(datagram)) {
+            // The server asking again means it didn't get the answer to a rekey and still has the key
+            // the rekey replaced, which the next one starts from.
+            Some(Ok(packet @ ServerPacket::Rekey { .. })) => (self.previous.take().unwrap(), packet),
+            Some(Ok(packet)) => (session, packet),
+            _ => {
+              self.state = State::Established { session };
+              return Err(e);
+            }
+          },
+        };
+        self.state = State::Established { session };
+        self.last_received = now;
+        self.handle_packet(now, packet)?;
+      }
+      State::Closed => {}
+    }
+    Ok(())
+  }
+
+  fn accept_key_exchange(&mut self, ephemeral: &KeyPair, packet: ServerPacket) -> anyhow::Result<Session> {
+    let ServerPacket::KeyExchange {
+      key: server_key,
+      session_id,
+      observed,
+      transforms,
+      features,
+      time,
+      limits,
+      address_tag,
+    } = packet
+    else {
+      anyhow::bail!("Failed to establish secure connection");
+    };
+
+    debug!(target: logging::HANDSHAKE, "Server received the key exchange from {}", observed);
+    // Anyone on the path can answer with a different address; only the pinned key vouches for it.
+    let pinned = features.is_some_and(|features| features.contains(Features::PINNED_KEY));
+    if let (Some(server_static), true, Some(_)) = (self.config.server_public_key.as_ref(), pinned, limits) {
+      let tag = address_tag.ok_or_else(|| anyhow::anyhow!("Server didn't vouch for the address it saw"))?;
+      if !handshake::verify_address_tag(ephemeral, server_static, &server_key, observed, &tag)? {
+        anyhow::bail!("Server's reply doesn't match its pinned key");
+      }
+    }
+    let mut session_key = handshake::client_session_key(
+      ephemeral,
+      &server_key,
+      self.config.server_public_key.as_ref(),
+      observed,
+    )?;
+    if let Some(time) = time {
+      session_key = handshake::bind_time(&session_key, time);
+      self.check_clock(time);
+    }
+
+    if let Some(name) = transforms.iter().find(|name| !self.config.offered_transforms.contains(name)) {
+      anyhow::bail!("Server picked transform {} which wasn't offered", name);
+    }
+    // Servers sending none predate features, and anything the server supports but this version doesn't is
+    // left unused.
+    self.features = features.unwrap_or(Features::LEGACY).intersection(self.config.features());
+    let missing = self.config.features().difference(self.features);
+    if missing != Features::empty() {
+      info!(target: logging::HANDSHAKE, "Server doesn't support {}; going without", missing);
+    }
+    if !self.features.contains(Features::PEER_KEYS) {
+      self.peers.disable();
+    }
+    self.limits = Limits::LOCAL.negotiate(limits);
+
+    let pipeline = self.config.transforms.pipeline(&transforms, &session_key)?;
+    let pipeline = Arc::new(pipeline.with_raw_data(self.features.contains(Features::RAW_DATA)));
+    if !transforms.is_empty() {
+      info!(target: logging::HANDSHAKE, "Using transforms {:?}", transforms);
+    }
+
+    info!(target: logging::HANDSHAKE, "Successfully established secure connection; Authenticating...");
+    let session = Session { key: session_key, id: session_id, pipeline };
+    let auth = self.config.auth.packet(&server_key, &session.key)?;
+    let checked = match auth {
+      ClientPacket::Auth(ref credentials) => self.limits.check_credentials(credentials),
+      ClientPacket::KeyAuth { ref username, .. } => self.limits.check_credential(username.as_bytes()),
+      _ => Ok(()),
+    };
+    checked.map_err(|e| anyhow::anyhow!("The server doesn't take these credentials: {}", e))?;
+    self.transmits.extend(session.encrypt_control(&auth, self.features)?);
+    Ok(session)
+  }
+
+  /// Warns about a clock far from the server's `time`, which nothing else would tell about: tokens and
+  /// one-time passwords are just refused as invalid.
+  fn check_clock(&mut self, time: u64) {
+    let offset_secs = handshake::unix_time() as i64 - time as i64;
+    if offset_secs.unsigned_abs() < CLOCK_SKEW_WARNING.as_secs() {
+      return;
+    }
+    let direction = if offset_secs > 0 { "ahead of" } else { "behind" };
+    warn!(
+      target: logging::HANDSHAKE,
+      "The clock is {}s {} the server's; time-based tokens and one-time passwords may be refused",
+      offset_secs.unsigned_abs(),
+      direction
+    );
+    self.events.push_back(Event::ClockSkew { offset_secs });
+  }
+
+  fn handle_packet(&mut self, now: Instant, packet: ServerPacket) -> anyhow::Result<()> {
+    let event = match packet {
+      ServerPacket::Data(data) => Event::Data(data),
+      ServerPacket::Error(message) => {
+        error!("Server error: {}", message);
+        return Ok(());
+      }
+      ServerPacket::PathChallenge(nonce) => {
+        debug!(target: logging::HANDSHAKE, "Server is validating our new address");
+        self.send(ClientPacket::PathResponse(nonce))?;
+        return Ok(());
+      }
+      ServerPacket::NetworkConfig { address, prefix_len, dns } => {
+        self.peers.set_network(address, prefix_len);
+        Event::NetworkConfig { address, prefix_len, dns }
+      }
+      ServerPacket::PeerKey { peer, key, reply } => {
+        self.peers.handle_key(peer, key, reply)?;
+        return self.flush_peers();
+      }
+      ServerPacket::PeerData { peer, sealed } => {
+        let opened = self.peers.handle_data(now, peer, &sealed);
+        self.flush_peers()?;
+        return opened;
+      }
+      ServerPacket::PeerUnavailable { peer, client } => {
+        self.peers.handle_unavailable(now, peer, client);
+        return self.flush_peers();
+      }
+      ServerPacket::Stats { sent, received, quota_remaining, clients, max_clients } => {
+        Event::Stats { sent, received, quota_remaining, clients, max_clients }
+      }
+      ServerPacket::Notice(notice) => Event::Notice(notice),
+      ServerPacket::Subnets { accepted, rejected } => {
+        self.limits.check_routes(accepted.len() + rejected.len())?;
+        Event::Subnets { accepted, rejected }
+      }
+      ServerPacket::Forwards { accepted, rejected } => Event::Forwards { accepted, rejected },
+      // Reordered behind an update it answered.
+      ServerPacket::Routes { revision, .. } if revision < self.routes_revision => return Ok(()),
+      ServerPacket::Routes { revision, routes } => {
+        self.limits.check_routes(routes.len())?;
+        self.routes_revision = revision;
+        self.routes = routes.clone();
+        Event::Routes(routes)
+      }
+      ServerPacket::RouteUpdate { revision, added, removed } => {
+        if revision != self.routes_revision.wrapping_add(1) {
+          debug!("Missed route updates after revision {}; asking for all routes", self.routes_revision);
+          self.send(ClientPacket::RequestRoutes)?;
+          return Ok(());
+        }
+        self.limits.check_routes(self.routes.len() + added.len())?;
+        self.routes_revision = revision;
+        self.routes.retain(|route| !removed.contains(route));
+        self.routes.extend(added);
+        self.routes.sort();
+        self.routes.dedup();
+        Event::Routes(self.routes.clone())
+      }
+      ServerPacket::Hostname { name, fqdn } => Event::Hostname { name, fqdn },
+      ServerPacket::PasswordChanged { error } => Event::PasswordChanged(error.map_or(Ok(()), Err)),
+      ServerPacket::Ticket { ticket, lifetime_secs } => {
+        Event::Ticket { ticket, lifetime: Duration::from_secs(lifetime_secs) }
+      }
+      ServerPacket::Pong => {
+        if let Some(ref mut probe) = self.probe {
+          probe.ponged = true;
+        }
+        match self.last_ping_sent {
+          Some(sent) => Event::Pong { rtt: now.saturating_duration_since(sent) },
+          None => return Ok(()),
+        }
+      }
+      ServerPacket::Probe(padding) => {
+        // Late echoes of probes sent before the MTU changed don't count.
+        if let Some(ref mut probe) = self.probe {
+          probe.answered |= padding.len() == probe.mtu as usize;
+        }
+        return Ok(());
+      }
+      ServerPacket::Renegotiated(params) => {
+        let Some(offered) = self.renegotiating.take() else {
+          anyhow::bail!("Renegotiation that wasn't asked for");
+        };
+        if let Some(name) = params.transforms.iter().find(|name| !offered.contains(name)) {
+          anyhow::bail!("Server picked transform {} which wasn't offered", name);
+        }
+        if let State::Established { ref mut session } = self.state {
+          if session.pipeline.names() != params.transforms {
+            let pipeline = self.config.transforms.pipeline(&params.transforms, &session.key)?;
+            session.pipeline = Arc::new(pipeline.with_raw_data(session.pipeline.raw_data()));
+          }
+        }
+        self.keepalive = Duration::from_secs(params.keepalive_secs.into());
+        if let Some(ref mut probe) = self.probe {
+          *probe = Probe::new(params.mtu);
+        }
+        self.deadline = self.deadline.min(now + self.keepalive);
+        info!(target: logging::HANDSHAKE, "Renegotiated the session: {:?}", params);
+        Event::Renegotiated(params)
+      }
+      ServerPacket::Rekey { key } => {
+        self.rekey(&key)?;
+        return Ok(());
+      }
+      ServerPacket::Disconnect { code, reason } => {
+        info!("Disconnected from server: {}", reason);
+        self.close(Some(code), reason);
+        return Ok(());
+      }
+      ServerPacket::AuthError { code, message } => {
+        self.close(Some(code), message);
+        return Ok(());
+      }
+      packet => {
+        error!("Unexpected packet from server: {:?}", packet);
+        return Ok(());
+      }
+    };
+    self.events.push_back(event);
+    Ok(())
+  }
+
+  /// Answers `ServerPacket::Rekey` with a new ephemeral key, sealed with the current session key, and
+  /// switches to the key derived from the two; the current one is kept to open packets still on the way.
+  fn rekey(&mut self, server_key: &Key) -> anyhow::Result<()> {
+    let State::Established { ref mut session } = self.state else {
+      anyhow::bail!("Session is not established");
+    };
+    let ephemeral = KeyPair::generate();
+    let key = handshake::client_rekey(&ephemeral, server_key, &session.key)?;
+    let pipeline = self.config.transforms.pipeline(session.pipeline.names(), &key)?;
+    let pipeline = Arc::new(pipeline.with_raw_data(session.pipeline.raw_data()));
+    let answer = session.encrypt(&ClientPacket::Rekey { key: ephemeral.public() })?;
+
+    let id = session.id;
+    self.previous = Some(std::mem::replace(session, Session { key, id, pipeline }));
+    self.transmits.push_back(answer);
+    info!(target: logging::HANDSHAKE, "Replaced the session key at the server's request");
+    Ok(())
+  }
+
+  fn send(&mut self, packet: ClientPacket) -> anyhow::Result<()> {
+    let State::Established { ref session } = self.state else {
+      anyhow::bail!("Session is not established");
+    };
+    self.transmits.push_back(session.encrypt(&packet)?);
+    Ok(())
+  }
+
+  /// Sends what pairwise keys left for the server, and hands packets opened from peers to the driver.
+  fn flush_peers(&mut self) -> anyhow::Result<()> {
+    while let Some(packet) = self.peers.poll_received() {
+      self.events.push_back(Event::Data(packet));
+    }
+    while let Some(packet) = self.peers.poll_packet() {
+      self.send(packet)?;
+    }
+    while let Some(peer) = self.peers.poll_refused() {
+      self.events.push_back(Event::PeerKeyRefused { peer });
+    }
+    Ok(())
+  }
+
+  /// Takes the next pairwise key `peer` offers in place of the one agreed on, once the users compared its
+  /// fingerprint, see `Event::PeerKeyRefused`.
+  pub fn trust_peer(&mut self, peer: Ipv4Addr) {
+    self.peers.trust(peer);
+  }
+
+  /// Asks the server to change settings of the established session. Until `Event::Renegotiated` the old
+  /// ones stay in use; when transforms change, data crossing the answer on the way is lost.
+  pub fn renegotiate(&mut self, params: SessionParams) -> anyhow::Result<()> {
+    self.config.transforms.check(&params.transforms)?;
+    self.send(ClientPacket::Renegotiate(params.clone()))?;
+    self.renegotiating = Some(params.transforms);
+    Ok(())
+  }
+
+  /// Asks the server to replace the password the session authenticated with by `new`; answered with
+  /// `Event::PasswordChanged`.
+  pub fn change_password(&mut self, old: &str, new: &str) -> anyhow::Result<()> {
+    self.send(ClientPacket::ChangePassword { old: old.to_string(), new: new.to_string() })
+  }
+
+  /// Tells the server the routes went down after idleness (`true`), so that it sends `Notice::Wake` when
+  /// traffic for the client arrives, or that they're back (`false`). Does nothing without
+  /// `Features::IDLE_SUSPEND`; returns whether it was sent.
+  pub fn suspend(&mut self, suspended: bool) -> anyhow::Result<bool> {
+    if !self.features.contains(Features::IDLE_SUSPEND) {
+      return Ok(false);
+    }
+    self.send(ClientPacket::Suspended(suspended))?;
+    Ok(true)
+  }
+
+  /// Ends the session on the server's side too, rather than leaving it to time out.
+  pub fn disconnect(&mut self) -> anyhow::Result<()> {
+    self.send(ClientPacket::Disconnect)?;
+    self.state = State::Closed;
+    Ok(())
+  }
+
+  /// Sends the probe going with a ping, after lowering the MTU if the last ones were lost.
+  fn probe(&mut self) -> anyhow::Result<()> {
+    let Some(ref mut probe) = self.probe else {
+      return Ok(());
+    };
+    if probe.next_round() && self.renegotiating.is_none() {
+      let from = probe.mtu;
+      let Some(to) = MTU_STEPS.into_iter().find(|&step| step < from) else {
+        warn!(target: logging::DATAPATH, "Probes of the MTU are lost, but it can't go below {}", from);
+        self.probe = None;
+        return Ok(());
+      };
+      let State::Established { ref session } = self.state else {
+        anyhow::bail!("Session is not established");
+      };
+      let params = SessionParams {
+        mtu: to,
+        keepalive_secs: self.keepalive.as_secs() as u32,
+        transforms: session.pipeline.names().to_vec(),
+      };
+      let reason = format!("{} probes of {} bytes were lost while pings got through", PROBE_LOSSES, from);
+      warn!(target: logging::DATAPATH, "Lowering the MTU from {} to {}: {}", from, to, reason);
+      self.renegotiate(params)?;
+      self.events.push_back(Event::MtuReduced { from, to, reason });
+      if let Some(ref mut probe) = self.probe {
+        *probe = Probe::new(to);
+      }
+      return Ok(());
+    }
+
+    let mtu = probe.mtu as usize;
+    self.send(ClientPacket::Probe(vec![0; mtu]))
+  }
+
+  fn server_timeout(&self) -> Duration {
+    SERVER_TIMEOUT.max(self.keepalive * 3)
+  }
+
+  fn close(&mut self, code: Option<ErrorCode>, reason: String) {
+    self.state = State::Closed;
+    self.transmits.clear();
+    self.events.push_back(Event::Closed { code, reason });
+  }
+
+  /// Datagram carrying an IP packet from the tun device; sent right away rather than queued, so the driver
+  /// can set its ECN field. `None` for a packet to another client held until a pairwise key is agreed on,
+  /// see `peer`.
+  pub fn seal_data(&mut self, now: Instant, packet: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+    let State::Established { ref session } = self.state else {
+      anyhow::bail!("Session is not established");
+    };
+    let datagram = match self.peers.send(now, packet)? {
+      Some(packet) => Some(session.encrypt(&packet)?),
+      None => None,
+    };
+    self.flush_peers()?;
+    Ok(datagram)
+  }
+
+  /// When `handle_timeout` should be called next; `None` once the connection is closed.
+  pub fn poll_timeout(&self) -> Option<Instant> {
+    match self.state {
+      State::KeyExchange { .. } | State::Authenticating { .. } => Some(self.deadline),
+      State::Established { .. } => {
+        let deadline = self.deadline.min(self.last_received + self.server_timeout());
+        Some(self.peers.poll_timeout().map_or(deadline, |peers| deadline.min(peers)))
+      }
+      State::Closed => None,
+    }
+  }
+
+  pub fn handle_timeout(&mut self, now: Instant) {
+    match self.state {
+      State::KeyExchange { .. } if now >= self.deadline => {
+        self.close(None, "Connection handshake timeout".into());
+      }
+      State::Authenticating { .. } if now >= self.deadline => self.close(None, "Connection timeout".into()),
+      State::Established { ref session } if now >= self.last_received + self.server_timeout() => {
+        // The server may still hold the session, and refuse the key exchange of the next one from this
+        // address until told.
+        let rehandshake = match self.features.contains(Features::REHANDSHAKE) {
+          true => session.encrypt(&ClientPacket::Rehandshake).ok(),
+          false => None,
+        };
+        self.close(None, format!("No response from server for {:?}", self.server_timeout()));
+        self.transmits.extend(rehandshake);
+      }
+      State::Established { .. } if now >= self.deadline => {
+        self.handle_peers_timeout(now);
+        match self.send(ClientPacket::Ping) {
+          Ok(()) => self.last_ping_sent = Some(now),
+          Err(e) => error!(target: logging::CRYPTO, "Failed to encrypt ping packet: {}", e),
+        }
+        if let Err(e) = self.probe() {
+          error!(target: logging::CRYPTO, "Failed to probe the MTU: {}", e);
+        }
+        self.deadline = now + self.keepalive;
+      }
+      State::Established { .. } => self.handle_peers_timeout(now),
+      _ => {}
+    }
+  }
+
+  fn handle_peers_timeout(&mut self, now: Instant) {
+    self.peers.handle_timeout(now);
+    if let Err(e) = self.flush_peers() {
+      error!(target: logging::CRYPTO, "Failed to offer pairwise keys: {}", e);
+    }
+  }
+
+  pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+    self.transmits.pop_front()
+  }
+
+  pub fn poll_event(&mut self) -> Option<Event> {
+    self.events.pop_front()
+  }
+}
+
+/// Server half of the key exchange. Authentication stays with the server, which owns the credential stores.
+pub struct ServerHandshake<'a> {
+  pub transforms: &'a Registry,
+  /// Transforms clients may negotiate.
+  pub accepted_transforms: &'a [String],
+  pub static_key: Option<&'a KeyPair>,
+  /// Features the server offers, `Features::SUPPORTED` or fewer.
+  pub features: Features,
+}
+
+/// What a client offers in its `KeyExchange` besides its key and transforms; `None`s from clients predating
+/// them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Offer {
+  pub features: Option<Features>,
+  pub limits: Option<Limits>,
+}
+
+pub struct Accepted {
+  pub session: Session,
+  /// Features both sides support.
+  pub features: Features,
+  /// Lower of the server's limits and the client's.
+  pub limits: Limits,
+  /// Kept to verify key-based authentication, see `handshake::server_auth_proof`.
+  pub ephemeral: KeyPair,
+  /// Answer to the client's `KeyExchange`.
+  pub reply: Vec<u8>,
+}
+
+impl ServerHandshake<'_> {
+  /// Answers a `KeyExchange` from `observed` with a new session under `session_id`, which the server picks
+  /// so that it's unique among its sessions. Clients that don't send `features` are answered without.
+  pub fn accept(
+    &self,
+    client_key: &Key,
+    offered_transforms: &[String],
+    offer: Offer,
+    observed: SocketAddr,
+    session_id: SessionId,
+  ) -> anyhow::Result<Accepted> {
+    let ephemeral = KeyPair::generate();
+    let agreed = offer.features.unwrap_or(Features::LEGACY).intersection(self.features);
+    // Clients that didn't pin the static key can't mix it in; ones that did fail against servers without it.
+    let static_key = self.static_key.filter(|_| agreed.contains(Features::PINNED_KEY));
+    let mut key = handshake::server_session_key(&ephemeral, client_key, static_key, observed)?;
+    let transforms = self.transforms.negotiate(offered_transforms, self.accepted_transforms);
+    let time = agreed.contains(Features::CLOCK).then(handshake::unix_time);
+    // Sent after `time`, which has to be there for it to be read as limits.
+    let limits = (agreed.contains(Features::LIMITS) && time.is_some()).then_some(Limits::LOCAL);
+    if let Some(time) = time {
+      key = handshake::bind_time(&key, time);
+    }
+    let pipeline = self.transforms.pipeline(&transforms, &key)?;
+    let pipeline = Arc::new(pipeline.with_raw_data(agreed.contains(Features::RAW_DATA)));
+    // Sent after `limits` like `limits` after `time`.
+    let address_tag = match static_key {
+      Some(static_key) if limits.is_some() => {
+        Some(handshake::server_address_tag(static_key, client_key, &ephemeral.public(), observed)?)
+      }
+      _ => None,
+    };
+
+    let reply = handshake_datagram(&ServerPacket::KeyExchange {
+      key: ephemeral.public(),
+      session_id,
+      observed,
+      transforms,
+      features: offer.features.map(|_| agreed),
+      time,
+      limits,
+      address_tag,
+    })?;
+    Ok(Accepted {
+      session: Session { key, id: session_id, pipeline },
+      features: agreed,
+      limits: Limits::LOCAL.negotiate(offer.limits),
+      ephemeral,
+      reply,
+    })
+  }
+}
+
+/// Answer to a key exchange the server defers while shedding load.
+pub fn deferral(retry_after: Duration) -> anyhow::Result<Vec<u8>> {
+  handshake_datagram(&ServerPacket::Deferred { retry_after_secs: retry_after.as_secs() as u32 })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transform;
+
+  fn addr() -> SocketAddr {
+    "192.0.2.1:6969".parse().unwrap()
+  }
+
+  fn config(auth: ClientAuth) -> ConnectionConfig {
+    ConnectionConfig {
+      auth,
+      server_public_key: None,
+      transforms: Registry::default(),
+      offered_transforms: vec![transform::PAD.to_string()],
+      handshake_timeout: Duration::from_secs(5),
+      mtu_probe: None,
+      subnets: Vec::new(),
+      reverse_forwards: Vec::new(),
+      peer_encryption: PeerEncryption::Off,
+      hostname: None,
+    }
+  }
+
+  /// Runs the key exchange against a `ServerHandshake`; returns the client's auth packet and the session.
+  fn key_exchange(connection: &mut Connection, now: Instant) -> (ClientPacket, Session, KeyPair) {
+    let request = EncryptedPacket::from_bytes(&connection.poll_transmit().unwrap()).unwrap();
+    let ClientPacket::KeyExchange { key, transforms, features, limits, .. } =
+      request.decrypt(&[0u8; KEY_SIZE]).unwrap()
+    else {
+      panic!("Expected a key exchange");
+    };
+
+    let registry = Registry::default();
+    let accepted = [transform::PAD.to_string()];
+    let server = ServerHandshake {
+      transforms: &registry,
+      accepted_transforms: &accepted,
+      static_key: None,
+      features: Features::SUPPORTED,
+    };
+    let Accepted { session, ephemeral, reply, .. } =
+      server.accept(&key, &transforms, Offer { features, limits }, addr(), 42).unwrap();
+    connection.handle_datagram(now, &reply).unwrap();
+
+    let auth = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    (auth, session, ephemeral)
+  }
+
+  fn reply(session: &Session, packet: &ServerPacket) -> Vec<u8> {
+    session.pipeline.seal(&session.key, session.id, packet).unwrap()
+  }
+
+  #[test]
+  fn test_handshake() {
+    let now = Instant::now();
+    let client_key = KeyPair::generate();
+    let auth = ClientAuth::Key { username: "alice".into(), key: client_key.clone() };
+    let mut connection = Connection::new(config(auth), now).unwrap();
+    assert!(connection.poll_event().is_none());
+
+    let (auth, session, ephemeral) = key_exchange(&mut connection, now);
+    assert_eq!(session.pipeline.names(), [transform::PAD]);
+    let ClientPacket::KeyAuth { username, public_key, proof } = auth else {
+      panic!("Expected key authentication");
+    };
+    assert_eq!(username, "alice");
+    let expected = handshake::server_auth_proof(&ephemeral, &public_key, &session.key).unwrap();
+    assert!(handshake::keys_match(&proof, &expected));
+
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Established)));
+    assert!(connection.is_established());
+
+    let data = connection.seal_data(now, vec![0x45, 0, 0, 20]).unwrap().unwrap();
+    assert!(
+      matches!(session.pipeline.open(&session.key, &data).unwrap(), ClientPacket::Data(d) if d == [0x45, 0, 0, 20])
+    );
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::Data(vec![1, 2, 3]))).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Data(d)) if d == [1, 2, 3]));
+
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::PathChallenge(7))).unwrap();
+    let response = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(response, ClientPacket::PathResponse(7)));
+  }
+
+  #[test]
+  fn test_static_key() {
+    let now = Instant::now();
+    let static_key = KeyPair::generate();
+    let registry = Registry::default();
+    let server = ServerHandshake {
+      transforms: &registry,
+      accepted_transforms: &[],
+      static_key: Some(&static_key),
+      features: Features::SUPPORTED,
+    };
+
+    // Clients that don't pin the key get sessions from servers having one all the same.
+    for pinned in [None, Some(static_key.public())] {
+      let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+      config.server_public_key = pinned;
+      let mut connection = Connection::new(config, now).unwrap();
+      let request = EncryptedPacket::from_bytes(&connection.poll_transmit().unwrap()).unwrap();
+      let ClientPacket::KeyExchange { key, features, limits, .. } =
+        request.decrypt(&[0u8; KEY_SIZE]).unwrap()
+      else {
+        panic!("Expected a key exchange");
+      };
+      assert_eq!(features.unwrap().contains(Features::PINNED_KEY), pinned.is_some());
+
+      let Accepted { session, reply, .. } =
+        server.accept(&key, &[], Offer { features, limits }, addr(), 42).unwrap();
+      connection.handle_datagram(now, &reply).unwrap();
+      let auth = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+      assert!(matches!(auth, ClientPacket::Auth(_)));
+    }
+
+    // The address in the reply is vouched for by the pinned key, so one changed on the way is refused.
+    let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+    config.server_public_key = Some(static_key.public());
+    let mut connection = Connection::new(config, now).unwrap();
+    let request = EncryptedPacket::from_bytes(&connection.poll_transmit().unwrap()).unwrap();
+    let ClientPacket::KeyExchange { key, features, limits, .. } = request.decrypt(&[0u8; KEY_SIZE]).unwrap()
+    else {
+      panic!("Expected a key exchange");
+    };
+    let Accepted { reply, .. } = server.accept(&key, &[], Offer { features, limits }, addr(), 42).unwrap();
+    let mut packet = EncryptedPacket::from_bytes(&reply).unwrap().decrypt(&[0u8; KEY_SIZE]).unwrap();
+    if let ServerPacket::KeyExchange { ref mut observed, .. } = packet {
+      *observed = "198.51.100.7:6969".parse().unwrap();
+    }
+    let error = connection.handle_datagram(now, &handshake_datagram(&packet).unwrap()).unwrap_err();
+    assert!(error.to_string().contains("doesn't match its pinned key"), "{}", error);
+  }
+
+  #[test]
+  fn test_auth_error() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    let (auth, session, _) = key_exchange(&mut connection, now);
+    assert!(matches!(auth, ClientPacket::Auth(Credentials::Password { .. })));
+
+    let error = ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message: "nope".into() };
+    connection.handle_datagram(now, &reply(&session, &error)).unwrap();
+    assert!(matches!(
+      connection.poll_event(),
+      Some(Event::Closed { code: Some(ErrorCode::InvalidCredentials), reason }) if reason == "Authentication failed: nope"
+    ));
+    assert_eq!(connection.poll_timeout(), None);
+  }
+
+  #[test]
+  fn test_transform_not_offered() {
+    let now = Instant::now();
+    let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+    config.offered_transforms.clear();
+    let mut connection = Connection::new(config, now).unwrap();
+    connection.poll_transmit();
+
+    let reply = handshake_datagram(&ServerPacket::KeyExchange {
+      key: KeyPair::generate().public(),
+      session_id: 42,
+      observed: addr(),
+      transforms: vec![transform::PAD.to_string()],
+      features: None,
+      time: None,
+      limits: None,
+      address_tag: None,
+    })
+    .unwrap();
+    assert!(connection.handle_datagram(now, &reply).is_err());
+  }
+
+  #[test]
+  fn test_features() {
+    let now = Instant::now();
+    let auth = ClientAuth::Credentials(Credentials::new("a", &"p".repeat(2000)));
+    let mut connection = Connection::new(config(auth.clone()), now).unwrap();
+    let (auth_packet, _, _) = key_exchange(&mut connection, now);
+    // Pairwise keys are only asked for when the client seals traffic to other clients, and the static key
+    // only mixed in when it's pinned.
+    let unused = Features::PEER_KEYS.union(Features::PINNED_KEY);
+    assert_eq!(connection.features(), Features::SUPPORTED.difference(unused));
+    assert!(matches!(auth_packet, ClientPacket::Fragment(_)));
+
+    // A server without fragmentation would drop the fragments, so the connection fails instead.
+    let mut connection = Connection::new(config(auth), now).unwrap();
+    connection.poll_transmit();
+    let reply = handshake_datagram(&ServerPacket::KeyExchange {
+      key: KeyPair::generate().public(),
+      session_id: 42,
+      observed: addr(),
+      transforms: Vec::new(),
+      features: Some(Features::ROAMING),
+      time: None,
+      limits: None,
+      address_tag: None,
+    })
+    .unwrap();
+    let error = connection.handle_datagram(now, &reply).unwrap_err();
+    assert!(error.to_string().contains("doesn't reassemble"), "{}", error);
+  }
+
+  #[test]
+  fn test_limits() {
+    let now = Instant::now();
+    let auth = ClientAuth::Credentials(Credentials::new("alice", "pass"));
+    let mut connection = Connection::new(config(auth.clone()), now).unwrap();
+    key_exchange(&mut connection, now);
+    assert_eq!(connection.limits(), Limits::LOCAL);
+
+    // Credentials the server would refuse fail the connection before they're sent.
+    let mut connection = Connection::new(config(auth), now).unwrap();
+    connection.poll_transmit();
+    let reply = handshake_datagram(&ServerPacket::KeyExchange {
+      key: KeyPair::generate().public(),
+      session_id: 42,
+      observed: addr(),
+      transforms: Vec::new(),
+      features: Some(Features::SUPPORTED),
+      time: Some(handshake::unix_time()),
+      limits: Some(Limits { max_credential_len: 4, ..Limits::LOCAL }),
+      address_tag: None,
+    })
+    .unwrap();
+    let error = connection.handle_datagram(now, &reply).unwrap_err();
+    assert!(error.to_string().contains("doesn't take these credentials"), "{}", error);
+    assert_eq!(connection.limits().max_credential_len, 4);
+  }
+
+  #[test]
+  fn test_clock_skew() {
+    let now = Instant::now();
+    for (time, skewed) in [(handshake::unix_time(), false), (handshake::unix_time() + 120, true)] {
+      let mut connection =
+        Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+      connection.poll_transmit();
+      let reply = handshake_datagram(&ServerPacket::KeyExchange {
+        key: KeyPair::generate().public(),
+        session_id: 42,
+        observed: addr(),
+        transforms: Vec::new(),
+        features: Some(Features::SUPPORTED),
+        time: Some(time),
+        limits: None,
+        address_tag: None,
+      })
+      .unwrap();
+      connection.handle_datagram(now, &reply).unwrap();
+      let event = connection.poll_event();
+      assert_eq!(
+        skewed,
+        matches!(event, Some(Event::ClockSkew { offset_secs }) if offset_secs <= -110),
+        "{:?}",
+        event
+      );
+    }
+  }
+
+  #[test]
+  fn test_timers() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    assert_eq!(connection.poll_timeout(), Some(now + Duration::from_secs(5)));
+    let (_, session, _) = key_exchange(&mut connection, now);
+
+    let established = now + Duration::from_secs(1);
+    connection.handle_datagram(established, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    connection.poll_event();
+
+    // Pings go out right away and every `PING_INTERVAL` after.
+    assert_eq!(connection.poll_timeout(), Some(established));
+    connection.handle_timeout(established);
+    let ping = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(ping, ClientPacket::Ping));
+    assert_eq!(connection.poll_timeout(), Some(established + PING_INTERVAL));
+
+    let pong = established + Duration::from_millis(30);
+    connection.handle_datagram(pong, &reply(&session, &ServerPacket::Pong)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Pong { rtt }) if rtt == Duration::from_millis(30)));
+
+    connection.handle_timeout(pong + SERVER_TIMEOUT);
+    assert!(matches!(connection.poll_event(), Some(Event::Closed { code: None, .. })));
+    // The server may still hold the session.
+    let rehandshake = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(rehandshake, ClientPacket::Rehandshake));
+  }
+
+  #[test]
+  fn test_renegotiate() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    connection.poll_event();
+    connection.handle_timeout(now);
+    connection.poll_transmit();
+
+    let renegotiated = SessionParams { mtu: 1280, keepalive_secs: 60, transforms: Vec::new() };
+    // Only answers to a renegotiation are taken.
+    assert!(connection
+      .handle_datagram(now, &reply(&session, &ServerPacket::Renegotiated(renegotiated.clone())))
+      .is_err());
+
+    let params = SessionParams { mtu: 1400, ..renegotiated.clone() };
+    assert!(connection
+      .renegotiate(SessionParams { transforms: vec!["unknown".into()], ..params.clone() })
+      .is_err());
+    connection.renegotiate(params.clone()).unwrap();
+    let request = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(request, ClientPacket::Renegotiate(p) if p == params));
+
+    // Sealed with the transforms the session had, then dropping them for the ones agreed to.
+    connection
+      .handle_datagram(now, &reply(&session, &ServerPacket::Renegotiated(renegotiated.clone())))
+      .unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Renegotiated(p)) if p == renegotiated));
+    let data = connection.seal_data(now, vec![0x45]).unwrap().unwrap();
+    assert!(session.pipeline.open::<ClientPacket>(&session.key, &data).is_err());
+    assert!(Pipeline::default().open::<ClientPacket>(&session.key, &data).is_ok());
+
+    // Pings and the server timeout follow the new interval.
+    assert_eq!(connection.poll_timeout(), Some(now + PING_INTERVAL));
+    connection.handle_timeout(now + PING_INTERVAL);
+    assert_eq!(connection.poll_timeout(), Some(now + PING_INTERVAL + Duration::from_secs(60)));
+    connection.handle_timeout(now + SERVER_TIMEOUT);
+    assert!(connection.poll_event().is_none());
+    connection.handle_timeout(now + Duration::from_secs(180));
+    assert!(matches!(connection.poll_event(), Some(Event::Closed { code: None, .. })));
+  }
+
+  #[test]
+  fn test_rekey() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    connection.poll_event();
+
+    let rekey = |connection: &mut Connection, previous: &Session| {
+      let server = KeyPair::generate();
+      connection
+        .handle_datagram(now, &reply(previous, &ServerPacket::Rekey { key: server.public() }))
+        .unwrap();
+      let answer = previous.pipeline.open(&previous.key, &connection.poll_transmit().unwrap()).unwrap();
+      let ClientPacket::Rekey { key } = answer else {
+        panic!("Expected a rekey");
+      };
+      let key = handshake::server_rekey(&server, &key, &previous.key).unwrap();
+      Session { key, id: previous.id, pipeline: previous.pipeline.clone() }
+    };
+
+    // The server asks again when the answer is lost, still sealing with the key it had.
+    rekey(&mut connection, &session);
+    let rekeyed = rekey(&mut connection, &session);
+    let data = connection.seal_data(now, vec![0x45]).unwrap().unwrap();
+    assert!(session.pipeline.open::<ClientPacket>(&session.key, &data).is_err());
+    assert!(rekeyed.pipeline.open::<ClientPacket>(&rekeyed.key, &data).is_ok());
+
+    // Packets sealed with the old key still open until one sealed with the new key arrives.
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::Data(vec![1]))).unwrap();
+    connection.handle_datagram(now, &reply(&rekeyed, &ServerPacket::Data(vec![2]))).unwrap();
+    assert!(connection.handle_datagram(now, &reply(&session, &ServerPacket::Data(vec![3]))).is_err());
+    assert!(matches!(connection.poll_event(), Some(Event::Data(d)) if d == [1]));
+    assert!(matches!(connection.poll_event(), Some(Event::Data(d)) if d == [2]));
+    assert!(connection.poll_event().is_none());
+  }
+
+  #[test]
+  fn test_mtu_fallback() {
+    let now = Instant::now();
+    let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+    config.mtu_probe = Some(1500);
+    let mut connection = Connection::new(config, now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    connection.poll_event();
+
+    let open = |datagram: Vec<u8>| session.pipeline.open::<ClientPacket>(&session.key, &datagram).unwrap();
+    // Each round pings and probes, then the server answers with `answers`.
+    let round = |connection: &mut Connection, at: Instant, answers: &[ServerPacket]| {
+      connection.handle_timeout(at);
+      let sent: Vec<_> = std::iter::from_fn(|| connection.poll_transmit()).map(open).collect();
+      for answer in answers {
+        connection.handle_datagram(at, &reply(&session, answer)).unwrap();
+      }
+      (sent, std::iter::from_fn(|| connection.poll_event()).collect::<Vec<_>>())
+    };
+
+    let (sent, _) = round(&mut connection, now, &[ServerPacket::Pong, ServerPacket::Probe(vec![0; 1500])]);
+    assert!(matches!(&sent[..], [ClientPacket::Ping, ClientPacket::Probe(p)] if p.len() == 1500));
+
+    // Rounds losing everything aren't about size; only the probes going missing lower the MTU.
+    let mut at = now;
+    for answers in [&[][..], &[ServerPacket::Pong], &[ServerPacket::Pong], &[ServerPacket::Pong]] {
+      at += PING_INTERVAL;
+      let (sent, events) = round(&mut connection, at, answers);
+      assert_eq!(sent.len(), 2);
+      assert!(!events.iter().any(|event| matches!(event, Event::MtuReduced { .. })));
+    }
+    at += PING_INTERVAL;
+    let (sent, events) = round(&mut connection, at, &[]);
+    assert!(matches!(&sent[..], [ClientPacket::Ping, ClientPacket::Renegotiate(p)] if p.mtu == 1400));
+    assert!(matches!(&events[..], [Event::MtuReduced { from: 1500, to: 1400, .. }]));
+
+    let agreed = SessionParams { mtu: 1400, keepalive_secs: 5, transforms: vec![transform::PAD.to_string()] };
+    connection.handle_datagram(at, &reply(&session, &ServerPacket::Renegotiated(agreed))).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Renegotiated(p)) if p.mtu == 1400));
+    let (sent, _) = round(&mut connection, at + PING_INTERVAL, &[]);
+    assert!(matches!(&sent[..], [ClientPacket::Ping, ClientPacket::Probe(p)] if p.len() == 1400));
+  }
+
+  #[test]
+  fn test_subnets() {
+    let now = Instant::now();
+    let lan: Ipv4Net = "192.168.1.0/24".parse().unwrap();
+    let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+    config.subnets = vec![lan];
+    let mut connection = Connection::new(config, now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+
+    // Registered as soon as the session is up.
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    let request = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(request, ClientPacket::RegisterSubnets(subnets) if subnets == [lan]));
+
+    let answer = ServerPacket::Subnets { accepted: Vec::new(), rejected: vec![lan] };
+    connection.handle_datagram(now, &reply(&session, &answer)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Established)));
+    assert!(
+      matches!(connection.poll_event(), Some(Event::Subnets { accepted, rejected }) if accepted.is_empty() && rejected == [lan])
+    );
+  }
+
+  #[test]
+  fn test_reverse_forwards() {
+    let now = Instant::now();
+    let ssh = ReverseForward { protocol: crate::ip::PROTO_TCP, port: 2222 };
+    let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+    config.reverse_forwards = vec![ssh];
+    let mut connection = Connection::new(config, now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    let request = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(request, ClientPacket::RegisterForwards(forwards) if forwards == [ssh]));
+
+    let answer = ServerPacket::Forwards { accepted: vec![ssh], rejected: Vec::new() };
+    connection.handle_datagram(now, &reply(&session, &answer)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Established)));
+    assert!(
+      matches!(connection.poll_event(), Some(Event::Forwards { accepted, rejected }) if accepted == [ssh] && rejected.is_empty())
+    );
+  }
+
+  #[test]
+  fn test_hostname() {
+    let now = Instant::now();
+    let mut config = config(ClientAuth::Credentials(Credentials::new("a", "b")));
+    config.hostname = Some("laptop".to_string());
+    let mut connection = Connection::new(config, now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    let request = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(request, ClientPacket::RegisterHostname(name) if name == "laptop"));
+
+    let answer =
+      ServerPacket::Hostname { name: "laptop".to_string(), fqdn: Some("laptop.vpn.internal".to_string()) };
+    connection.handle_datagram(now, &reply(&session, &answer)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Established)));
+    assert!(
+      matches!(connection.poll_event(), Some(Event::Hostname { fqdn: Some(fqdn), .. }) if fqdn == "laptop.vpn.internal")
+    );
+  }
+
+  #[test]
+  fn test_suspend() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Established)));
+
+    assert!(connection.suspend(true).unwrap());
+    let packet = session.pipeline.open(&session.key, &connection.poll_transmit().unwrap()).unwrap();
+    assert!(matches!(packet, ClientPacket::Suspended(true)));
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::Notice(Notice::Wake))).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Notice(Notice::Wake))));
+  }
+
+  #[test]
+  fn test_route_updates() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+    connection.handle_datagram(now, &reply(&session, &ServerPacket::AuthOk)).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Established)));
+
+    let [north, south, west]: [Ipv4Net; 3] =
+      ["192.168.50.0/24", "192.168.60.0/24", "192.168.70.0/24"].map(|net| net.parse().unwrap());
+    let mut receive = |packet: ServerPacket| {
+      connection.handle_datagram(now, &reply(&session, &packet)).unwrap();
+      let sent =
+        connection.poll_transmit().map(|datagram| session.pipeline.open(&session.key, &datagram).unwrap());
+      (connection.poll_event(), sent)
+    };
+
+    let (event, _) = receive(ServerPacket::Routes { revision: 1, routes: vec![north] });
+    assert!(matches!(event, Some(Event::Routes(routes)) if routes == [north]));
+    let (event, _) =
+      receive(ServerPacket::RouteUpdate { revision: 2, added: vec![west, south], removed: vec![north] });
+    assert!(matches!(event, Some(Event::Routes(routes)) if routes == [south, west]));
+
+    // Revision 3 got lost, so the whole list is asked for instead of applying 4 to the wrong one.
+    let (event, sent) =
+      receive(ServerPacket::RouteUpdate { revision: 4, added: vec![north], removed: Vec::new() });
+    assert!(event.is_none());
+    assert!(matches!(sent, Some(ClientPacket::RequestRoutes)));
+    let (event, _) = receive(ServerPacket::Routes { revision: 4, routes: vec![north, west] });
+    assert!(matches!(event, Some(Event::Routes(routes)) if routes == [north, west]));
+  }
+
+  #[test]
+  fn test_deferred() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    connection.poll_transmit();
+    assert_eq!(connection.retry_after(), None);
+
+    connection.handle_datagram(now, &deferral(Duration::from_secs(7)).unwrap()).unwrap();
+    assert!(matches!(connection.poll_event(), Some(Event::Closed { code: Some(ErrorCode::Overloaded), .. })));
+    assert_eq!(connection.retry_after(), Some(Duration::from_secs(7)));
+    assert_eq!(connection.poll_timeout(), None);
+  }
+
+  #[test]
+  fn test_pool_exhausted() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    let (_, session, _) = key_exchange(&mut connection, now);
+
+    connection
+      .handle_datagram(now, &reply(&session, &ServerPacket::PoolExhausted { retry_after_secs: 30 }))
+      .unwrap();
+    assert!(matches!(
+      connection.poll_event(),
+      Some(Event::Closed { code: Some(ErrorCode::PoolExhausted), .. })
+    ));
+    assert_eq!(connection.retry_after(), Some(Duration::from_secs(30)));
+  }
+
+  #[test]
+  fn test_handshake_timeout() {
+    let now = Instant::now();
+    let mut connection =
+      Connection::new(config(ClientAuth::Credentials(Credentials::new("a", "b"))), now).unwrap();
+    connection.handle_timeout(now + Duration::from_secs(1));
+    assert!(connection.poll_event().is_none());
+    connection.handle_timeout(now + Duration::from_secs(5));
+    assert!(
+      matches!(connection.poll_event(), Some(Event::Closed { code: None, reason }) if reason == "Connection handshake timeout")
+    );
+  }
+}
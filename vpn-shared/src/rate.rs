@@ -0,0 +1,108 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::packet::fill_random_bytes;
+
+/// Token bucket refilled at `rate` tokens per second up to `burst`. Taking more than is available leaves the
+/// bucket in debt; the returned delay is how long to wait until the debt is paid off.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+  rate: f64,
+  burst: f64,
+  tokens: f64,
+  last_refill: Instant,
+}
+
+impl TokenBucket {
+  pub fn new(rate: f64, burst: f64) -> Self {
+    Self { rate, burst, tokens: burst, last_refill: Instant::now() }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    self.last_refill = now;
+  }
+
+  pub fn take(&mut self, amount: f64) -> Duration {
+    self.take_at(amount, Instant::now())
+  }
+
+  /// Takes `amount` only if it's available, for policing: traffic over the rate is dropped, not delayed.
+  pub fn try_take(&mut self, amount: f64) -> bool {
+    self.try_take_at(amount, Instant::now())
+  }
+
+  fn try_take_at(&mut self, amount: f64, now: Instant) -> bool {
+    self.refill(now);
+    if self.tokens < amount {
+      return false;
+    }
+    self.tokens -= amount;
+    true
+  }
+
+  fn take_at(&mut self, amount: f64, now: Instant) -> Duration {
+    self.refill(now);
+    self.tokens -= amount;
+
+    match self.tokens >= 0.0 {
+      true => Duration::ZERO,
+      false => Duration::from_secs_f64(-self.tokens / self.rate),
+    }
+  }
+}
+
+/// `duration` stretched by a random share of up to `fraction` of it, so that timers many peers start at
+/// once, e.g. reconnecting after the server restarts, don't all fire at once too.
+pub fn jitter(duration: Duration, fraction: f64) -> Duration {
+  let mut roll = [0u8; 4];
+  fill_random_bytes(&mut roll);
+  duration.mul_f64(1.0 + fraction * (u32::from_be_bytes(roll) as f64 / u32::MAX as f64))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_jitter() {
+    let delay = Duration::from_secs(4);
+    assert_eq!(jitter(delay, 0.0), delay);
+    let delays: Vec<_> = (0..32).map(|_| jitter(delay, 0.5)).collect();
+    assert!(delays.iter().all(|jittered| (delay..=delay.mul_f64(1.5)).contains(jittered)));
+    assert!(delays.iter().any(|jittered| *jittered != delays[0]));
+  }
+
+  #[test]
+  fn test_burst_then_delay() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 2.0);
+    bucket.last_refill = now;
+
+    assert_eq!(bucket.take_at(1.0, now), Duration::ZERO);
+    assert_eq!(bucket.take_at(1.0, now), Duration::ZERO);
+    assert_eq!(bucket.take_at(1.0, now), Duration::from_millis(100));
+  }
+
+  #[test]
+  fn test_try_take() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 2.0);
+    bucket.last_refill = now;
+
+    assert!(bucket.try_take_at(2.0, now));
+    assert!(!bucket.try_take_at(1.0, now));
+    assert!(bucket.try_take_at(1.0, now + Duration::from_millis(100)));
+  }
+
+  #[test]
+  fn test_refill_is_capped() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 2.0);
+    bucket.last_refill = now;
+
+    bucket.take_at(2.0, now);
+    assert_eq!(bucket.take_at(3.0, now + Duration::from_secs(10)), Duration::from_millis(100));
+  }
+}
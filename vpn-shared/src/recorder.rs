@@ -0,0 +1,216 @@
+//! Flight recorder: the latest significant events, such as handshakes, errors and drops, kept in memory
+//! whatever the log level and written to disk when the process panics or an administrator asks, so that
+//! intermittent problems can be diagnosed after the fact.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use tracing::Event;
+use tracing::Level;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Filtered;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::logging;
+use crate::logging::MessageVisitor;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlightRecorderConfig {
+  /// Directory the dumps are written to, a file each.
+  pub dir: PathBuf,
+
+  /// Events kept; older ones are overwritten.
+  #[serde(default = "default_capacity")]
+  pub capacity: usize,
+
+  /// Level of the events recorded, independent of the level logged at.
+  #[serde(default = "default_level")]
+  pub level: String,
+
+  /// As in `LogConfig::subsystems`, e.g. `datapath: debug` to record dropped packets as well.
+  #[serde(default)]
+  pub subsystems: BTreeMap<String, String>,
+}
+
+fn default_capacity() -> usize {
+  1000
+}
+
+fn default_level() -> String {
+  "info".to_string()
+}
+
+struct Record {
+  time: SystemTime,
+  level: Level,
+  target: String,
+  message: String,
+}
+
+#[derive(Default)]
+struct Ring {
+  records: VecDeque<Record>,
+  overwritten: u64,
+}
+
+pub struct FlightRecorder {
+  dir: PathBuf,
+  capacity: usize,
+  ring: Mutex<Ring>,
+}
+
+/// Recorder installed along with logging, for `dump`.
+static RECORDER: OnceLock<Arc<FlightRecorder>> = OnceLock::new();
+
+impl FlightRecorder {
+  pub fn new(dir: PathBuf, capacity: usize) -> Self {
+    Self { dir, capacity: capacity.max(1), ring: Mutex::default() }
+  }
+
+  pub fn record(&self, level: Level, target: &str, message: String) {
+    // A panic while the ring was locked mustn't keep the panic hook from dumping it.
+    let mut ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+    if ring.records.len() >= self.capacity {
+      ring.records.pop_front();
+      ring.overwritten += 1;
+    }
+    ring.records.push_back(Record { time: SystemTime::now(), level, target: target.to_string(), message });
+  }
+
+  /// The events, oldest first, after a line saying why they were dumped.
+  pub fn render(&self, reason: &str) -> String {
+    let ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+    let mut dump = format!(
+      "# Flight recorder of process {}, dumped on {} at {}; {} events, {} earlier ones overwritten\n",
+      std::process::id(),
+      reason,
+      logging::rfc3339(SystemTime::now()),
+      ring.records.len(),
+      ring.overwritten
+    );
+    for record in &ring.records {
+      let millis = record.time.duration_since(UNIX_EPOCH).unwrap_or_default().subsec_millis();
+      let time = logging::rfc3339(record.time);
+      _ = writeln!(
+        dump,
+        "{}.{:03}Z {:>5} {}: {}",
+        time.trim_end_matches('Z'),
+        millis,
+        record.level,
+        record.target,
+        record.message
+      );
+    }
+    dump
+  }
+
+  /// Writes the events to a new file in `dir`, named after the time and `reason`; returns its path.
+  pub fn dump(&self, reason: &str) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(&self.dir)?;
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let path = self.dir.join(format!("flight-{}-{}.log", millis, reason));
+    std::fs::write(&path, self.render(reason))?;
+    Ok(path)
+  }
+}
+
+/// Dumps the recorder installed by `logging::init`, see `FlightRecorder::dump`.
+pub fn dump(reason: &str) -> anyhow::Result<PathBuf> {
+  let Some(recorder) = RECORDER.get() else {
+    anyhow::bail!("The flight recorder isn't configured");
+  };
+  recorder.dump(reason)
+}
+
+/// Records the events it's given, see `FlightRecorderConfig::level`.
+pub struct RecorderLayer(Arc<FlightRecorder>);
+
+impl<S: Subscriber> Layer<S> for RecorderLayer {
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    let mut message = MessageVisitor::default();
+    event.record(&mut message);
+    message.message.push_str(&message.fields);
+    self.0.record(*event.metadata().level(), event.metadata().target(), message.message);
+  }
+}
+
+/// Creates the recorder of `config` for `dump`, and has it dumped when the process panics; returns the
+/// layer feeding it.
+pub(crate) fn install<S: Subscriber>(
+  config: &FlightRecorderConfig,
+) -> anyhow::Result<Filtered<RecorderLayer, Targets, S>> {
+  let filter = logging::filter(logging::parse_level(&config.level)?, &config.subsystems)?;
+  let recorder = Arc::new(FlightRecorder::new(config.dir.clone(), config.capacity));
+  if RECORDER.set(Arc::clone(&recorder)).is_err() {
+    anyhow::bail!("The flight recorder is already installed");
+  }
+
+  let previous = std::panic::take_hook();
+  let on_panic = Arc::clone(&recorder);
+  std::panic::set_hook(Box::new(move |info| {
+    on_panic.record(Level::ERROR, "panic", info.to_string());
+    match on_panic.dump("panic") {
+      Ok(path) => eprintln!("Flight recorder dumped to {}", path.display()),
+      Err(e) => eprintln!("Failed to dump the flight recorder: {}", e),
+    }
+    previous(info);
+  }));
+
+  Ok(RecorderLayer(recorder).with_filter(filter))
+}
+
+#[cfg(test)]
+mod tests {
+  use tracing_subscriber::filter::LevelFilter;
+  use tracing_subscriber::layer::SubscriberExt;
+
+  use super::*;
+
+  #[test]
+  fn test_ring() {
+    let recorder = Arc::new(FlightRecorder::new(PathBuf::new(), 2));
+    let subsystems = BTreeMap::from([(logging::DATAPATH.to_string(), "debug".to_string())]);
+    let filter = logging::filter(LevelFilter::INFO, &subsystems).unwrap();
+    let subscriber =
+      tracing_subscriber::registry().with(RecorderLayer(Arc::clone(&recorder)).with_filter(filter));
+    tracing::subscriber::with_default(subscriber, || {
+      tracing::info!(target: logging::HANDSHAKE, "Key exchange completed");
+      tracing::debug!(target: logging::HANDSHAKE, "Not recorded");
+      tracing::debug!(target: logging::DATAPATH, "Dropping packet from {}", "a");
+      tracing::warn!(client = 7, "Client is stale");
+    });
+
+    let dump = recorder.render("test");
+    let lines: Vec<_> = dump.lines().collect();
+    assert!(
+      lines[0].contains("dumped on test") && lines[0].ends_with("2 events, 1 earlier ones overwritten"),
+      "{}",
+      dump
+    );
+    assert!(lines[1].ends_with("DEBUG datapath: Dropping packet from a"), "{}", dump);
+    assert!(lines[2].ends_with(" WARN vpn_shared::recorder::tests: Client is stale client=7"), "{}", dump);
+  }
+
+  #[test]
+  fn test_dump() {
+    let dir = std::env::temp_dir().join(format!("vpn-flight-recorder-{}", std::process::id()));
+    let recorder = FlightRecorder::new(dir.clone(), 10);
+    recorder.record(Level::ERROR, "handshake", "Failed to decrypt".to_string());
+
+    let path = recorder.dump("admin").unwrap();
+    assert!(path.file_name().unwrap().to_string_lossy().ends_with("-admin.log"));
+    assert!(std::fs::read_to_string(&path).unwrap().contains("ERROR handshake: Failed to decrypt"));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
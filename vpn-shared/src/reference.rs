@@ -0,0 +1,896 @@
+//! The wire protocol as the packet definitions describe it: every packet with its bincode variant index,
+//! fields and a sample encoding, and the constants peers have to agree on. `markdown` renders the protocol
+//! reference from it, and implementations in other languages can test their encoding against the samples,
+//! so neither falls behind the code.
+//!
+//! Fields are read off the `Serialize` impls by a serializer that records types instead of writing bytes,
+//! so they can't go stale; the samples, one per variant, have to be added along with new variants.
+
+use std::fmt;
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+
+use serde::ser;
+use serde::Serialize;
+
+use crate::cert::Certificate;
+use crate::creds::Credentials;
+use crate::fragment;
+use crate::fragment::Fragment;
+use crate::limits;
+use crate::limits::Limits;
+use crate::packet;
+use crate::packet::ClientPacket;
+use crate::packet::ErrorCode;
+use crate::packet::Features;
+use crate::packet::Notice;
+use crate::packet::ReverseForward;
+use crate::packet::ServerPacket;
+use crate::packet::SessionParams;
+use crate::protocol;
+use crate::rendezvous::RendezvousPacket;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Field {
+  /// Position of the field for tuple variants.
+  pub name: String,
+  /// Type as encoded, e.g. `seq<[u8; 5]>` for a `Vec<Ipv4Net>`.
+  pub ty: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Variant {
+  pub name: &'static str,
+  /// Little-endian u32 the variant is encoded as before its fields.
+  pub index: u32,
+  pub fields: Vec<Field>,
+  /// Sample of the variant encoded with bincode.
+  pub sample: Vec<u8>,
+}
+
+/// Enum sent on the wire, the packets themselves or one of their fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Type {
+  pub name: &'static str,
+  pub doc: &'static str,
+  pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Constant {
+  pub name: &'static str,
+  pub value: u64,
+  pub doc: &'static str,
+}
+
+/// The enums sent on the wire, packets first.
+pub fn types() -> Vec<Type> {
+  vec![
+    describe("ClientPacket", "Sent by clients.", client_samples()),
+    describe("ServerPacket", "Sent by servers.", server_samples()),
+    describe("Credentials", "What `ClientPacket::Auth` authenticates with.", credentials_samples()),
+    describe("Notice", "Heads-up about the session that doesn't end it.", notice_samples()),
+    describe("ErrorCode", "Why the server refused or ended a session.", error_code_samples()),
+    describe(
+      "RendezvousPacket",
+      "Exchanged with a rendezvous under `RENDEZVOUS_SESSION` by servers behind NAT and their clients.",
+      rendezvous_samples(),
+    ),
+  ]
+}
+
+pub fn constants() -> Vec<Constant> {
+  vec![
+    Constant {
+      name: "SESSION_ID_SIZE",
+      value: packet::SESSION_ID_SIZE as u64,
+      doc: "Bytes of the big-endian session id every datagram starts with.",
+    },
+    Constant { name: "NONCE_SIZE", value: packet::NONCE_SIZE as u64, doc: "Bytes of the nonce after it." },
+    Constant {
+      name: "TAG_SIZE",
+      value: packet::TAG_SIZE as u64,
+      doc: "Bytes of the Poly1305 tag every datagram ends with.",
+    },
+    Constant { name: "KEY_SIZE", value: packet::KEY_SIZE as u64, doc: "Bytes of X25519 and session keys." },
+    Constant {
+      name: "HANDSHAKE_SESSION",
+      value: packet::HANDSHAKE_SESSION,
+      doc: "Session id of packets sent before a session is established, sealed with the all-zero key.",
+    },
+    Constant {
+      name: "RENDEZVOUS_SESSION",
+      value: packet::RENDEZVOUS_SESSION,
+      doc: "Session id of `RendezvousPacket`s, sealed with the all-zero key.",
+    },
+    Constant {
+      name: "DATA_OVERHEAD",
+      value: packet::DATA_OVERHEAD as u64,
+      doc: "Bytes a `Data` packet adds on top of its payload before transforms.",
+    },
+    Constant {
+      name: "MAX_CONTROL_DATAGRAM",
+      value: fragment::MAX_CONTROL_DATAGRAM as u64,
+      doc: "Bytes of the largest control datagram; larger `Auth` and `KeyAuth` packets are fragmented.",
+    },
+    Constant {
+      name: "FRAGMENT_SIZE",
+      value: fragment::FRAGMENT_SIZE as u64,
+      doc: "Bytes of a serialized packet carried by one `Fragment`.",
+    },
+    Constant { name: "MAX_FRAGMENTS", value: fragment::MAX_FRAGMENTS as u64, doc: "Fragments of a packet." },
+    Constant {
+      name: "MAX_PACKET_SIZE",
+      value: limits::MAX_PACKET_SIZE.into(),
+      doc: "Bytes of the largest serialized packet a peer decodes, unless it advertises less in `limits`.",
+    },
+    Constant {
+      name: "MAX_CREDENTIAL_LEN",
+      value: limits::MAX_CREDENTIAL_LEN.into(),
+      doc: "Bytes of the longest username, password, token or ticket, unless a peer advertises less.",
+    },
+    Constant {
+      name: "MAX_ROUTES",
+      value: limits::MAX_ROUTES.into(),
+      doc: "Networks in the longest list of routes or subnets, unless a peer advertises fewer.",
+    },
+    Constant {
+      name: "PING_INTERVAL",
+      value: protocol::PING_INTERVAL.as_secs(),
+      doc: "Seconds between pings unless renegotiated.",
+    },
+    Constant {
+      name: "MAX_KEEPALIVE",
+      value: protocol::MAX_KEEPALIVE.as_secs(),
+      doc: "Seconds of the longest ping interval a session can be renegotiated to.",
+    },
+    Constant {
+      name: "SERVER_TIMEOUT",
+      value: protocol::SERVER_TIMEOUT.as_secs(),
+      doc: "Seconds the server may stay silent before the client considers the session lost.",
+    },
+  ]
+}
+
+/// Names and bits of the features peers negotiate in `KeyExchange`.
+pub fn features() -> Vec<(&'static str, u32)> {
+  Features::NAMES.iter().map(|&(feature, name)| (name, feature.bits())).collect()
+}
+
+/// The protocol reference in Markdown.
+pub fn markdown() -> String {
+  let mut out = String::new();
+  out.push_str(
+    "# Protocol reference\n\n\
+     Generated by `vpn_shared::reference::markdown` from the packet definitions.\n\n\
+     Every datagram is the session id, the nonce, the ciphertext and the tag: a packet serialized with \
+     bincode and sealed with ChaCha20-Poly1305 under the session key, with the session id as associated \
+     data. Transforms negotiated in the key exchange, e.g. `pad`, apply on top. In sessions with the \
+     `raw-data` feature `Data` packets are sent as the byte `0xff` followed by the payload; either form is \
+     read from any peer.\n\n\
+     Bincode's default encoding is used: integers little-endian at full width, enums as the u32 index of \
+     the variant followed by its fields in order, `seq<T>`, `string` and `bytes` as a u64 length followed \
+     by the contents, `option<T>` as a byte 0 or 1 followed by the value, and `[T; N]` as the elements \
+     alone. Networks are `[u8; 5]`, the address and the prefix length, and `SocketAddr` is an \
+     enum of `V4([u8; 4], u16)` and `V6([u8; 16], u16)`. `features` and `limits` of \
+     `KeyExchange` packets are left out, rather than encoded as options, by peers predating them; a session \
+     is held to the lower of each limit the peers advertise.\n\n",
+  );
+
+  out.push_str("## Constants\n\n| Name | Value | |\n|---|---|---|\n");
+  for constant in constants() {
+    let _ = writeln!(out, "| `{}` | {} | {} |", constant.name, constant.value, constant.doc);
+  }
+
+  out.push_str(
+    "\n## Features\n\nA session has the features both peers set.\n\n| Feature | Bit |\n|---|---|\n",
+  );
+  for (name, bits) in features() {
+    let _ = writeln!(out, "| `{}` | `{:#x}` |", name, bits);
+  }
+
+  for ty in types() {
+    let _ = write!(
+      out,
+      "\n## {}\n\n{}\n\n| Index | Variant | Fields | Sample |\n|---|---|---|---|\n",
+      ty.name, ty.doc
+    );
+    for variant in ty.variants {
+      let fields: Vec<_> = variant
+        .fields
+        .iter()
+        .map(|field| match field.name.parse::<usize>() {
+          Ok(_) => format!("`{}`", field.ty),
+          Err(_) => format!("`{}: {}`", field.name, field.ty),
+        })
+        .collect();
+      let sample: String = variant.sample.iter().map(|byte| format!("{:02x}", byte)).collect();
+      let _ =
+        writeln!(out, "| {} | `{}` | {} | `{}` |", variant.index, variant.name, fields.join(", "), sample);
+    }
+  }
+  out
+}
+
+fn describe<T: Serialize>(name: &'static str, doc: &'static str, samples: Vec<T>) -> Type {
+  let variants = samples
+    .iter()
+    .map(|sample| {
+      let shape = sample.serialize(Describe).expect("samples describe");
+      let (variant, index) = shape.variant.expect("samples are enum variants");
+      Variant {
+        name: variant,
+        index,
+        fields: shape.fields,
+        sample: bincode::serialize(sample).expect("samples serialize"),
+      }
+    })
+    .collect();
+  Type { name, doc, variants }
+}
+
+const KEY: packet::Key = [0x42; packet::KEY_SIZE];
+
+fn net(net: &str) -> ipnet::Ipv4Net {
+  net.parse().unwrap()
+}
+
+/// One of every variant, in order; `tests::test_samples_cover_variants` fails to compile without the new
+/// one.
+fn client_samples() -> Vec<ClientPacket> {
+  vec![
+    ClientPacket::Auth(Credentials::new("alice", "secret")),
+    ClientPacket::KeyExchange {
+      key: KEY,
+      transforms: vec!["pad".to_string()],
+      timestamp: 1_700_000_000,
+      features: Some(Features::SUPPORTED),
+      limits: Some(Limits::LOCAL),
+    },
+    ClientPacket::Data(vec![0x45, 0x00]),
+    ClientPacket::Ping,
+    ClientPacket::Disconnect,
+    ClientPacket::KeyAuth { username: "alice".to_string(), public_key: KEY, proof: KEY },
+    ClientPacket::PathResponse(7),
+    ClientPacket::Fragment(Fragment { id: 1, index: 0, count: 2, bytes: vec![0x00] }),
+    ClientPacket::Renegotiate(session_params()),
+    ClientPacket::Probe(vec![0x00; 4]),
+    ClientPacket::RegisterSubnets(vec![net("192.168.1.0/24")]),
+    ClientPacket::RequestRoutes,
+    ClientPacket::ChangePassword { old: "secret".to_string(), new: "hunter2".to_string() },
+    ClientPacket::RegisterForwards(vec![ReverseForward { protocol: 6, port: 8080 }]),
+    ClientPacket::Rehandshake,
+    ClientPacket::Rekey { key: KEY },
+    ClientPacket::PeerKey { peer: Ipv4Addr::new(10, 0, 0, 3), key: KEY, reply: false },
+    ClientPacket::PeerData { peer: Ipv4Addr::new(10, 0, 0, 3), sealed: vec![0x01; 4] },
+    ClientPacket::RegisterHostname("laptop".to_string()),
+    ClientPacket::Suspended(true),
+  ]
+}
+
+fn server_samples() -> Vec<ServerPacket> {
+  let forward = ReverseForward { protocol: 6, port: 8080 };
+  vec![
+    ServerPacket::AuthOk,
+    ServerPacket::AuthError { code: ErrorCode::InvalidCredentials, message: "Invalid password".to_string() },
+    ServerPacket::KeyExchange {
+      key: KEY,
+      session_id: 1,
+      observed: SocketAddr::from(([203, 0, 113, 7], 40000)),
+      transforms: vec!["pad".to_string()],
+      features: Some(Features::SUPPORTED),
+      time: Some(1_700_000_000),
+      limits: Some(Limits::LOCAL),
+      address_tag: None,
+    },
+    ServerPacket::Data(vec![0x45, 0x00]),
+    ServerPacket::Error("Bad packet".to_string()),
+    ServerPacket::Pong,
+    ServerPacket::Disconnect { code: ErrorCode::Kicked, reason: "Kicked".to_string() },
+    ServerPacket::PathChallenge(7),
+    ServerPacket::NetworkConfig {
+      address: Ipv4Addr::new(10, 0, 0, 2),
+      prefix_len: 24,
+      dns: vec![Ipv4Addr::new(10, 0, 0, 1)],
+    },
+    ServerPacket::Stats { sent: 1, received: 2, quota_remaining: Some(3), clients: 1, max_clients: 10 },
+    ServerPacket::Notice(Notice::QuotaWarning { percent: 90, remaining: 1024 }),
+    ServerPacket::Renegotiated(session_params()),
+    ServerPacket::Deferred { retry_after_secs: 5 },
+    ServerPacket::Ticket { ticket: vec![0x01; 4], lifetime_secs: 3600 },
+    ServerPacket::Probe(vec![0x00; 4]),
+    ServerPacket::Subnets { accepted: vec![net("192.168.1.0/24")], rejected: vec![net("10.0.0.0/8")] },
+    ServerPacket::Routes { revision: 1, routes: vec![net("192.168.2.0/24")] },
+    ServerPacket::RouteUpdate {
+      revision: 2,
+      added: vec![net("192.168.3.0/24")],
+      removed: vec![net("192.168.2.0/24")],
+    },
+    ServerPacket::PasswordChanged { error: Some("The old password is wrong".to_string()) },
+    ServerPacket::PoolExhausted { retry_after_secs: 30 },
+    ServerPacket::Forwards { accepted: vec![forward], rejected: vec![forward] },
+    ServerPacket::Rekey { key: KEY },
+    ServerPacket::PeerKey { peer: Ipv4Addr::new(10, 0, 0, 2), key: KEY, reply: true },
+    ServerPacket::PeerData { peer: Ipv4Addr::new(10, 0, 0, 2), sealed: vec![0x01; 4] },
+    ServerPacket::PeerUnavailable { peer: Ipv4Addr::new(10, 0, 0, 4), client: false },
+    ServerPacket::Hostname { name: "laptop".to_string(), fqdn: Some("laptop.vpn.internal".to_string()) },
+  ]
+}
+
+fn credentials_samples() -> Vec<Credentials> {
+  vec![
+    Credentials::new("alice", "secret"),
+    Credentials::Token("eyJ".to_string()),
+    Credentials::Certificate { certificate: certificate(), proof: KEY },
+    Credentials::Ticket(vec![0x01; 4]),
+  ]
+}
+
+fn notice_samples() -> Vec<Notice> {
+  vec![
+    Notice::QuotaWarning { percent: 90, remaining: 1024 },
+    Notice::InboundConnection {
+      protocol: 6,
+      source: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 40000),
+      port: 22,
+    },
+    Notice::Wake,
+  ]
+}
+
+fn rendezvous_samples() -> Vec<RendezvousPacket> {
+  let addr = SocketAddr::from(([203, 0, 113, 7], 40000));
+  vec![
+    RendezvousPacket::Register { name: "home".to_string() },
+    RendezvousPacket::Registered { observed: addr },
+    RendezvousPacket::Lookup { name: "home".to_string() },
+    RendezvousPacket::Located { name: "home".to_string(), addr: Some(addr) },
+    RendezvousPacket::Introduce { client: addr },
+    RendezvousPacket::Punch,
+  ]
+}
+
+fn error_code_samples() -> Vec<ErrorCode> {
+  vec![
+    ErrorCode::InvalidCredentials,
+    ErrorCode::Revoked,
+    ErrorCode::Expired,
+    ErrorCode::ServerFull,
+    ErrorCode::QuotaExceeded,
+    ErrorCode::SessionLost,
+    ErrorCode::Kicked,
+    ErrorCode::Preempted,
+    ErrorCode::Overloaded,
+    ErrorCode::PoolExhausted,
+    ErrorCode::VersionTooOld,
+  ]
+}
+
+fn session_params() -> SessionParams {
+  SessionParams { mtu: 1400, keepalive_secs: 25, transforms: vec!["pad".to_string()] }
+}
+
+/// Certificate with fixed fields, which `Certificate::issue` doesn't give; its signature is made up.
+fn certificate() -> Certificate {
+  let fields = (1u64, "alice", KEY, 1_700_000_000u64, 1_700_086_400u64, vec![0x5a_u8; 64]);
+  bincode::deserialize(&bincode::serialize(&fields).unwrap()).unwrap()
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+  fn custom<T: fmt::Display>(msg: T) -> Self {
+    Self(msg.to_string())
+  }
+}
+
+/// What a value serializes as: its type and, for an enum, the variant and its fields.
+#[derive(Default)]
+struct Shape {
+  ty: String,
+  variant: Option<(&'static str, u32)>,
+  fields: Vec<Field>,
+}
+
+impl Shape {
+  fn of(ty: impl Into<String>) -> Self {
+    Self { ty: ty.into(), ..Default::default() }
+  }
+
+  /// The type as a field of another: enums by name, structs along with their fields.
+  fn inline(self) -> String {
+    if self.variant.is_some() || self.fields.is_empty() {
+      return self.ty;
+    }
+    let fields: Vec<_> = self.fields.iter().map(|field| format!("{}: {}", field.name, field.ty)).collect();
+    format!("{} {{ {} }}", self.ty, fields.join(", "))
+  }
+}
+
+fn type_of<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+  Ok(value.serialize(Describe)?.inline())
+}
+
+/// Serializer recording the types values are encoded as instead of encoding them.
+struct Describe;
+
+enum Kind {
+  Seq,
+  Tuple,
+  TupleStruct(&'static str),
+  Map,
+  Struct(&'static str),
+  Variant(&'static str, u32, &'static str),
+}
+
+struct Compound {
+  kind: Kind,
+  fields: Vec<Field>,
+}
+
+impl Compound {
+  fn new(kind: Kind) -> Result<Self, Error> {
+    Ok(Self { kind, fields: Vec::new() })
+  }
+
+  fn push<T: Serialize + ?Sized>(&mut self, name: Option<&str>, value: &T) -> Result<(), Error> {
+    let name = name.map(str::to_string).unwrap_or_else(|| self.fields.len().to_string());
+    self.fields.push(Field { name, ty: type_of(value)? });
+    Ok(())
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    let types: Vec<_> = self.fields.iter().map(|field| field.ty.as_str()).collect();
+    Ok(match self.kind {
+      Kind::Seq => Shape::of(format!("seq<{}>", types.first().unwrap_or(&"_"))),
+      Kind::Tuple if !types.is_empty() && types.iter().all(|ty| *ty == types[0]) => {
+        Shape::of(format!("[{}; {}]", types[0], types.len()))
+      }
+      Kind::Tuple => Shape::of(format!("({})", types.join(", "))),
+      Kind::TupleStruct(name) => Shape::of(format!("{}({})", name, types.join(", "))),
+      Kind::Map => {
+        Shape::of(format!("map<{}>", types.iter().take(2).copied().collect::<Vec<_>>().join(", ")))
+      }
+      Kind::Struct(name) => Shape { ty: name.to_string(), variant: None, fields: self.fields },
+      Kind::Variant(name, index, variant) => {
+        Shape { ty: name.to_string(), variant: Some((variant, index)), fields: self.fields }
+      }
+    })
+  }
+}
+
+impl ser::Serializer for Describe {
+  type Ok = Shape;
+  type Error = Error;
+  type SerializeSeq = Compound;
+  type SerializeTuple = Compound;
+  type SerializeTupleStruct = Compound;
+  type SerializeTupleVariant = Compound;
+  type SerializeMap = Compound;
+  type SerializeStruct = Compound;
+  type SerializeStructVariant = Compound;
+
+  /// Addresses and networks are written as text otherwise, but bincode isn't human-readable.
+  fn is_human_readable(&self) -> bool {
+    false
+  }
+
+  fn serialize_bool(self, _: bool) -> Result<Shape, Error> {
+    Ok(Shape::of("bool"))
+  }
+
+  fn serialize_i8(self, _: i8) -> Result<Shape, Error> {
+    Ok(Shape::of("i8"))
+  }
+
+  fn serialize_i16(self, _: i16) -> Result<Shape, Error> {
+    Ok(Shape::of("i16"))
+  }
+
+  fn serialize_i32(self, _: i32) -> Result<Shape, Error> {
+    Ok(Shape::of("i32"))
+  }
+
+  fn serialize_i64(self, _: i64) -> Result<Shape, Error> {
+    Ok(Shape::of("i64"))
+  }
+
+  fn serialize_u8(self, _: u8) -> Result<Shape, Error> {
+    Ok(Shape::of("u8"))
+  }
+
+  fn serialize_u16(self, _: u16) -> Result<Shape, Error> {
+    Ok(Shape::of("u16"))
+  }
+
+  fn serialize_u32(self, _: u32) -> Result<Shape, Error> {
+    Ok(Shape::of("u32"))
+  }
+
+  fn serialize_u64(self, _: u64) -> Result<Shape, Error> {
+    Ok(Shape::of("u64"))
+  }
+
+  fn serialize_f32(self, _: f32) -> Result<Shape, Error> {
+    Ok(Shape::of("f32"))
+  }
+
+  fn serialize_f64(self, _: f64) -> Result<Shape, Error> {
+    Ok(Shape::of("f64"))
+  }
+
+  fn serialize_char(self, _: char) -> Result<Shape, Error> {
+    Ok(Shape::of("char"))
+  }
+
+  fn serialize_str(self, _: &str) -> Result<Shape, Error> {
+    Ok(Shape::of("string"))
+  }
+
+  fn serialize_bytes(self, _: &[u8]) -> Result<Shape, Error> {
+    Ok(Shape::of("bytes"))
+  }
+
+  fn serialize_none(self) -> Result<Shape, Error> {
+    Ok(Shape::of("option<_>"))
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Shape, Error> {
+    Ok(Shape::of(format!("option<{}>", type_of(value)?)))
+  }
+
+  fn serialize_unit(self) -> Result<Shape, Error> {
+    Ok(Shape::of("()"))
+  }
+
+  fn serialize_unit_struct(self, name: &'static str) -> Result<Shape, Error> {
+    Ok(Shape::of(name))
+  }
+
+  fn serialize_unit_variant(
+    self,
+    name: &'static str,
+    index: u32,
+    variant: &'static str,
+  ) -> Result<Shape, Error> {
+    Ok(Shape { ty: name.to_string(), variant: Some((variant, index)), fields: Vec::new() })
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(
+    self,
+    name: &'static str,
+    value: &T,
+  ) -> Result<Shape, Error> {
+    Ok(Shape::of(format!("{}({})", name, type_of(value)?)))
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    name: &'static str,
+    index: u32,
+    variant: &'static str,
+    value: &T,
+  ) -> Result<Shape, Error> {
+    let mut compound = Compound::new(Kind::Variant(name, index, variant))?;
+    compound.push(None, value)?;
+    compound.end()
+  }
+
+  fn serialize_seq(self, _: Option<usize>) -> Result<Compound, Error> {
+    Compound::new(Kind::Seq)
+  }
+
+  fn serialize_tuple(self, _: usize) -> Result<Compound, Error> {
+    Compound::new(Kind::Tuple)
+  }
+
+  fn serialize_tuple_struct(self, name: &'static str, _: usize) -> Result<Compound, Error> {
+    Compound::new(Kind::TupleStruct(name))
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    name: &'static str,
+    index: u32,
+    variant: &'static str,
+    _: usize,
+  ) -> Result<Compound, Error> {
+    Compound::new(Kind::Variant(name, index, variant))
+  }
+
+  fn serialize_map(self, _: Option<usize>) -> Result<Compound, Error> {
+    Compound::new(Kind::Map)
+  }
+
+  fn serialize_struct(self, name: &'static str, _: usize) -> Result<Compound, Error> {
+    Compound::new(Kind::Struct(name))
+  }
+
+  fn serialize_struct_variant(
+    self,
+    name: &'static str,
+    index: u32,
+    variant: &'static str,
+    _: usize,
+  ) -> Result<Compound, Error> {
+    Compound::new(Kind::Variant(name, index, variant))
+  }
+}
+
+impl ser::SerializeSeq for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+    self.push(None, value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+impl ser::SerializeTuple for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+    self.push(None, value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+impl ser::SerializeTupleStruct for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+    self.push(None, value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+impl ser::SerializeTupleVariant for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+    self.push(None, value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+impl ser::SerializeMap for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+    self.push(None, key)
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+    self.push(None, value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+impl ser::SerializeStruct for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+    self.push(Some(key), value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+impl ser::SerializeStructVariant for Compound {
+  type Ok = Shape;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+    self.push(Some(key), value)
+  }
+
+  fn end(self) -> Result<Shape, Error> {
+    Compound::end(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Breaks the build of the tests when a variant is added without a sample.
+  fn client_index(packet: &ClientPacket) -> u32 {
+    match packet {
+      ClientPacket::Auth(_) => 0,
+      ClientPacket::KeyExchange { .. } => 1,
+      ClientPacket::Data(_) => 2,
+      ClientPacket::Ping => 3,
+      ClientPacket::Disconnect => 4,
+      ClientPacket::KeyAuth { .. } => 5,
+      ClientPacket::PathResponse(_) => 6,
+      ClientPacket::Fragment(_) => 7,
+      ClientPacket::Renegotiate(_) => 8,
+      ClientPacket::Probe(_) => 9,
+      ClientPacket::RegisterSubnets(_) => 10,
+      ClientPacket::RequestRoutes => 11,
+      ClientPacket::ChangePassword { .. } => 12,
+      ClientPacket::RegisterForwards(_) => 13,
+      ClientPacket::Rehandshake => 14,
+      ClientPacket::Rekey { .. } => 15,
+      ClientPacket::PeerKey { .. } => 16,
+      ClientPacket::PeerData { .. } => 17,
+      ClientPacket::RegisterHostname(_) => 18,
+      ClientPacket::Suspended(_) => 19,
+    }
+  }
+
+  fn server_index(packet: &ServerPacket) -> u32 {
+    match packet {
+      ServerPacket::AuthOk => 0,
+      ServerPacket::AuthError { .. } => 1,
+      ServerPacket::KeyExchange { .. } => 2,
+      ServerPacket::Data(_) => 3,
+      ServerPacket::Error(_) => 4,
+      ServerPacket::Pong => 5,
+      ServerPacket::Disconnect { .. } => 6,
+      ServerPacket::PathChallenge(_) => 7,
+      ServerPacket::NetworkConfig { .. } => 8,
+      ServerPacket::Stats { .. } => 9,
+      ServerPacket::Notice(_) => 10,
+      ServerPacket::Renegotiated(_) => 11,
+      ServerPacket::Deferred { .. } => 12,
+      ServerPacket::Ticket { .. } => 13,
+      ServerPacket::Probe(_) => 14,
+      ServerPacket::Subnets { .. } => 15,
+      ServerPacket::Routes { .. } => 16,
+      ServerPacket::RouteUpdate { .. } => 17,
+      ServerPacket::PasswordChanged { .. } => 18,
+      ServerPacket::PoolExhausted { .. } => 19,
+      ServerPacket::Forwards { .. } => 20,
+      ServerPacket::Rekey { .. } => 21,
+      ServerPacket::PeerKey { .. } => 22,
+      ServerPacket::PeerData { .. } => 23,
+      ServerPacket::PeerUnavailable { .. } => 24,
+      ServerPacket::Hostname { .. } => 25,
+    }
+  }
+
+  fn credentials_index(credentials: &Credentials) -> u32 {
+    match credentials {
+      Credentials::Password { .. } => 0,
+      Credentials::Token(_) => 1,
+      Credentials::Certificate { .. } => 2,
+      Credentials::Ticket(_) => 3,
+    }
+  }
+
+  fn notice_index(notice: &Notice) -> u32 {
+    match notice {
+      Notice::QuotaWarning { .. } => 0,
+      Notice::InboundConnection { .. } => 1,
+      Notice::Wake => 2,
+    }
+  }
+
+  fn error_code_index(code: &ErrorCode) -> u32 {
+    match code {
+      ErrorCode::InvalidCredentials => 0,
+      ErrorCode::Revoked => 1,
+      ErrorCode::Expired => 2,
+      ErrorCode::ServerFull => 3,
+      ErrorCode::QuotaExceeded => 4,
+      ErrorCode::SessionLost => 5,
+      ErrorCode::Kicked => 6,
+      ErrorCode::Preempted => 7,
+      ErrorCode::Overloaded => 8,
+      ErrorCode::PoolExhausted => 9,
+      ErrorCode::VersionTooOld => 10,
+    }
+  }
+
+  fn rendezvous_index(packet: &RendezvousPacket) -> u32 {
+    match packet {
+      RendezvousPacket::Register { .. } => 0,
+      RendezvousPacket::Registered { .. } => 1,
+      RendezvousPacket::Lookup { .. } => 2,
+      RendezvousPacket::Located { .. } => 3,
+      RendezvousPacket::Introduce { .. } => 4,
+      RendezvousPacket::Punch => 5,
+    }
+  }
+
+  fn check<T: Serialize>(samples: Vec<T>, index: fn(&T) -> u32) {
+    for (i, sample) in samples.iter().enumerate() {
+      assert_eq!(index(sample), i as u32);
+      let encoded = bincode::serialize(sample).unwrap();
+      assert_eq!(encoded[..4], (i as u32).to_le_bytes());
+    }
+  }
+
+  #[test]
+  fn test_samples_cover_variants() {
+    check(client_samples(), client_index);
+    check(server_samples(), server_index);
+    check(credentials_samples(), credentials_index);
+    check(notice_samples(), notice_index);
+    check(error_code_samples(), error_code_index);
+    check(rendezvous_samples(), rendezvous_index);
+  }
+
+  #[test]
+  fn test_samples_decode() {
+    let types = types();
+    for variant in &types[0].variants {
+      let packet: ClientPacket = bincode::deserialize(&variant.sample).unwrap();
+      assert_eq!(bincode::serialize(&packet).unwrap(), variant.sample, "{}", variant.name);
+    }
+    for variant in &types[1].variants {
+      let packet: ServerPacket = bincode::deserialize(&variant.sample).unwrap();
+      assert_eq!(bincode::serialize(&packet).unwrap(), variant.sample, "{}", variant.name);
+    }
+  }
+
+  #[test]
+  fn test_fields() {
+    let types = types();
+    let key_exchange = &types[0].variants[1];
+    assert_eq!(key_exchange.name, "KeyExchange");
+    let fields: Vec<_> = key_exchange.fields.iter().map(|f| format!("{}: {}", f.name, f.ty)).collect();
+    assert_eq!(
+      fields,
+      [
+        "key: [u8; 32]",
+        "transforms: seq<string>",
+        "timestamp: u64",
+        "features: Features(u32)",
+        "limits: Limits { max_packet_size: u32, max_credential_len: u32, max_routes: u32 }"
+      ]
+    );
+
+    let subnets = &types[1].variants[15];
+    assert_eq!(subnets.fields[0].ty, "seq<[u8; 5]>");
+    let renegotiate = &types[0].variants[8];
+    assert_eq!(
+      renegotiate.fields[0].ty,
+      "SessionParams { mtu: u16, keepalive_secs: u32, transforms: seq<string> }"
+    );
+    let auth_error = &types[1].variants[1];
+    assert_eq!(auth_error.fields[0].ty, "ErrorCode");
+    assert_eq!(types[1].variants[0].sample, [0, 0, 0, 0]);
+
+    let markdown = markdown();
+    assert!(markdown.contains("| `SESSION_ID_SIZE` | 8 |"), "{}", markdown);
+    assert!(markdown.contains("| `MAX_ROUTES` | 1024 |"), "{}", markdown);
+    assert!(markdown.contains("| `reverse-forwards` | `0x10` |"), "{}", markdown);
+    assert!(markdown.contains("| 3 | `Ping` |  | `03000000` |"), "{}", markdown);
+  }
+}
@@ -0,0 +1,144 @@
+//! Rendezvous for servers behind NAT without port forwarding. The server keeps a mapping of its NAT open
+//! by registering a name with a public rendezvous, any server with `rendezvous-service`, from its listen
+//! socket. Clients look the name up to learn the mapped address, and the rendezvous introduces them to the
+//! server, which sends a datagram towards the client first so that its NAT lets the key exchange in.
+//!
+//! Packets go under `RENDEZVOUS_SESSION` sealed with the all-zero key, like handshake packets: they carry
+//! nothing secret, and a name belongs to whoever registered it first until its registrations stop, so
+//! clients should pin the server's key.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::ip;
+use crate::packet;
+use crate::packet::EncryptedPacket;
+use crate::packet::KEY_SIZE;
+use crate::packet::MAX_DATAGRAM_SIZE;
+use crate::packet::RENDEZVOUS_SESSION;
+use crate::socket::Socket;
+
+/// Longest name a server can register.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Lookups unanswered for this long are sent again.
+const LOOKUP_RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RendezvousPacket {
+  /// Sent by a server every `interval-secs` of its `rendezvous`, keeping its NAT mapping open.
+  Register { name: String },
+  /// Answer to `Register` with the address the rendezvous saw it from, i.e. where clients will be sent.
+  Registered { observed: SocketAddr },
+  /// Sent by clients to learn the address of the server registered as `name`.
+  Lookup { name: String },
+  /// Answer to `Lookup`; no address if no server is registered as `name`.
+  Located { name: String, addr: Option<SocketAddr> },
+  /// Sent to the server along with `Located`, so that it opens its NAT to `client`.
+  Introduce { client: SocketAddr },
+  /// Sent by the server to an introduced client; ignored on arrival.
+  Punch,
+}
+
+impl RendezvousPacket {
+  pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+    Ok(EncryptedPacket::encrypt(&[0u8; KEY_SIZE], RENDEZVOUS_SESSION, self)?.to_bytes())
+  }
+
+  pub fn decode(datagram: &[u8]) -> anyhow::Result<Self> {
+    let packet = EncryptedPacket::from_bytes(datagram)?;
+    anyhow::ensure!(packet.session_id() == RENDEZVOUS_SESSION, "Not a rendezvous packet");
+    packet.decrypt(&[0u8; KEY_SIZE])
+  }
+}
+
+/// Whether `datagram` is a rendezvous packet rather than one of a session.
+pub fn is_rendezvous(datagram: &[u8]) -> bool {
+  packet::peek_session_id(datagram) == Some(RENDEZVOUS_SESSION)
+}
+
+/// Looks up the server registered as `name` with the rendezvous at `rendezvous`, from the socket the key
+/// exchange will be sent from: the server is introduced to the address the lookup came from.
+pub async fn lookup(
+  socket: &Socket,
+  rendezvous: SocketAddr,
+  name: &str,
+  timeout: Duration,
+) -> anyhow::Result<SocketAddr> {
+  let deadline = Instant::now() + timeout;
+  let lookup = RendezvousPacket::Lookup { name: name.to_string() }.encode()?;
+  let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+  loop {
+    socket.send_to(&lookup, rendezvous, ip::ECN_NOT_ECT).await?;
+    let retry = (Instant::now() + LOOKUP_RETRY).min(deadline);
+    loop {
+      let (len, from, _) = tokio::select! {
+        received = socket.recv_from(&mut buf) => received?,
+        _ = tokio::time::sleep_until(retry.into()) => break,
+      };
+      if from != rendezvous {
+        continue;
+      }
+      match RendezvousPacket::decode(&buf[..len]) {
+        Ok(RendezvousPacket::Located { name: located, addr }) if located == name => {
+          return addr
+            .ok_or_else(|| anyhow::anyhow!("No server is registered as {} at {}", name, rendezvous));
+        }
+        _ => continue,
+      }
+    }
+    if Instant::now() >= deadline {
+      anyhow::bail!("Rendezvous {} didn't answer", rendezvous);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::socket::Network;
+
+  #[test]
+  fn test_encoding() {
+    let packet = RendezvousPacket::Lookup { name: "home".to_string() };
+    let datagram = packet.encode().unwrap();
+    assert!(is_rendezvous(&datagram));
+    assert_eq!(RendezvousPacket::decode(&datagram).unwrap(), packet);
+
+    let handshake = EncryptedPacket::encrypt(&[0u8; KEY_SIZE], packet::HANDSHAKE_SESSION, &packet).unwrap();
+    assert!(!is_rendezvous(&handshake.to_bytes()));
+    assert!(RendezvousPacket::decode(&handshake.to_bytes()).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_lookup() {
+    let network = Network::new();
+    let rendezvous = Socket::Memory(network.bind("10.0.0.1:6969".parse().unwrap()).unwrap());
+    let client = Socket::Memory(network.bind("10.0.0.2:0".parse().unwrap()).unwrap());
+    let rendezvous_addr = rendezvous.local_addr().unwrap();
+    let server: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+
+    let answering = tokio::spawn(async move {
+      let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+      // The first lookup goes unanswered, so the client has to send it again.
+      rendezvous.recv_from(&mut buf).await.unwrap();
+      for addr in [Some(server), None] {
+        let (len, from, _) = rendezvous.recv_from(&mut buf).await.unwrap();
+        let RendezvousPacket::Lookup { name } = RendezvousPacket::decode(&buf[..len]).unwrap() else {
+          panic!("Expected a lookup");
+        };
+        let located = RendezvousPacket::Located { name, addr }.encode().unwrap();
+        rendezvous.send_to(&located, from, ip::ECN_NOT_ECT).await.unwrap();
+      }
+    });
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(lookup(&client, rendezvous_addr, "home", timeout).await.unwrap(), server);
+    assert!(lookup(&client, rendezvous_addr, "away", timeout).await.is_err());
+    answering.await.unwrap();
+  }
+}
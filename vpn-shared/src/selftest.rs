@@ -0,0 +1,171 @@
+//! Checks behind `--selftest` of both binaries, for provisioning to verify an install before enabling it:
+//! the crypto primitives work, a tun device can be created, the configuration is valid and its sockets can
+//! be bound. The report is printed as JSON.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::net::UdpSocket;
+
+use crate::cert::Certificate;
+use crate::cert::SigningKey;
+use crate::diagnose;
+use crate::diagnose::Protocol;
+use crate::handshake;
+use crate::handshake::KeyPair;
+use crate::packet::fill_random_bytes;
+use crate::packet::EncryptedPacket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+  Pass,
+  Fail,
+  /// Not applicable to the configuration, or depending on a check that failed.
+  Skip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+  pub name: &'static str,
+  pub status: Status,
+  pub detail: String,
+}
+
+impl Check {
+  pub fn new(name: &'static str, result: anyhow::Result<String>) -> Self {
+    match result {
+      Ok(detail) => Self { name, status: Status::Pass, detail },
+      Err(e) => Self { name, status: Status::Fail, detail: e.to_string() },
+    }
+  }
+
+  pub fn skip(name: &'static str, reason: &str) -> Self {
+    Self { name, status: Status::Skip, detail: reason.to_string() }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+  pub program: &'static str,
+  pub version: &'static str,
+  /// Whether no check failed.
+  pub passed: bool,
+  pub checks: Vec<Check>,
+}
+
+impl Report {
+  pub fn new(program: &'static str, version: &'static str, checks: Vec<Check>) -> Self {
+    let passed = checks.iter().all(|check| check.status != Status::Fail);
+    Self { program, version, passed, checks }
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("reports are serializable")
+  }
+}
+
+/// Key agreement, authenticated encryption and signatures, on fresh keys.
+pub fn crypto() -> Check {
+  Check::new("crypto", exercise_crypto())
+}
+
+fn exercise_crypto() -> anyhow::Result<String> {
+  let mut random = [0u8; 32];
+  fill_random_bytes(&mut random);
+  if random == [0u8; 32] {
+    anyhow::bail!("Random number generator returned zeros");
+  }
+
+  let (client, server, server_static) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+  let observed = SocketAddr::from(([192, 0, 2, 1], 6969));
+  let client_key =
+    handshake::client_session_key(&client, &server.public(), Some(&server_static.public()), observed)?;
+  let server_key = handshake::server_session_key(&server, &client.public(), Some(&server_static), observed)?;
+  if !handshake::keys_match(&client_key, &server_key) {
+    anyhow::bail!("Both sides of the key exchange derived different keys");
+  }
+
+  let mut sealed = EncryptedPacket::seal(&client_key, 1, b"selftest")?.to_bytes();
+  if EncryptedPacket::from_bytes(&sealed)?.open(&server_key)? != b"selftest" {
+    anyhow::bail!("Decryption returned something else than was encrypted");
+  }
+  *sealed.last_mut().unwrap() ^= 1;
+  if EncryptedPacket::from_bytes(&sealed)?.open(&server_key).is_ok() {
+    anyhow::bail!("Tampered ciphertext was accepted");
+  }
+
+  let ca = SigningKey::from_bytes(&random);
+  let certificate = Certificate::issue(&ca, "selftest", client.public(), Duration::from_secs(60))?;
+  certificate.verify(&ca.verifying_key(), SystemTime::now())?;
+  let mut forged = certificate.clone();
+  forged.username = "someone-else".to_string();
+  if forged.verify(&ca.verifying_key(), SystemTime::now()).is_ok() {
+    anyhow::bail!("Forged certificate was accepted");
+  }
+
+  Ok("X25519, ChaCha20-Poly1305 and Ed25519 work".to_string())
+}
+
+/// Creates and drops a tun device named by the system, to check for the permissions without touching the
+/// configured one.
+pub fn tun() -> Check {
+  Check::new(
+    "tun",
+    tun::create(&tun::Configuration::default())
+      .map(|_| "A tun device can be created".to_string())
+      .map_err(diagnose::tun_error),
+  )
+}
+
+pub async fn udp_bind(name: &'static str, addr: SocketAddr) -> Check {
+  let bound = UdpSocket::bind(addr).await.map(drop);
+  Check::new(
+    name,
+    bound
+      .map(|_| format!("UDP {} can be bound", addr))
+      .map_err(|e| diagnose::bind_error(Protocol::Udp, addr, e)),
+  )
+}
+
+pub async fn tcp_bind(name: &'static str, addr: SocketAddr) -> Check {
+  let bound = TcpListener::bind(addr).await.map(drop);
+  Check::new(
+    name,
+    bound
+      .map(|_| format!("TCP {} can be bound", addr))
+      .map_err(|e| diagnose::bind_error(Protocol::Tcp, addr, e)),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_crypto() {
+    let check = crypto();
+    assert_eq!(check.status, Status::Pass, "{}", check.detail);
+  }
+
+  #[tokio::test]
+  async fn test_report() {
+    let taken = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let checks = vec![
+      crypto(),
+      udp_bind("socket", taken.local_addr().unwrap()).await,
+      Check::skip("tun", "No tun section configured"),
+    ];
+    let report = Report::new("vpn-test", "0.0.0", checks);
+    assert!(!report.passed);
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["passed"], false);
+    assert_eq!(json["checks"][0]["status"], "pass");
+    assert_eq!(json["checks"][1]["status"], "fail");
+    assert_eq!(json["checks"][2]["name"], "tun");
+  }
+}
@@ -0,0 +1,222 @@
+//! Datagram sockets of clients and servers: UDP, or endpoints of an in-memory `Network` that links clients
+//! and servers running in the same process, e.g. in tests or a binary that both serves local clients and
+//! dials out to another site, without going through the network stack. Datagrams can also go over TCP
+//! where UDP is blocked, see `stream`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::ecn;
+use crate::ip;
+use crate::stream::TcpTransport;
+
+/// Datagrams an endpoint holds before further ones to it are dropped, as a full socket buffer would.
+const QUEUE_DEPTH: usize = 1024;
+
+/// First port handed out to endpoints bound to port 0.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+type Datagram = (Vec<u8>, SocketAddr, u8);
+
+pub enum Socket {
+  Udp(UdpSocket),
+  Memory(Endpoint),
+  /// A connection to a server over TCP.
+  Tcp(TcpTransport),
+  /// UDP along with TCP connections accepted by a server; replies go back the way the peer came in.
+  UdpAndTcp(UdpSocket, TcpTransport),
+}
+
+impl Socket {
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    match self {
+      Self::Udp(socket) | Self::UdpAndTcp(socket, _) => socket.local_addr(),
+      Self::Memory(endpoint) => Ok(endpoint.addr),
+      Self::Tcp(transport) => Ok(transport.local_addr()),
+    }
+  }
+
+  /// Sends `buf` with `ecn` in the outer header, see `ecn::send_to`.
+  pub async fn send_to(&self, buf: &[u8], addr: SocketAddr, ecn: u8) -> io::Result<usize> {
+    self.send_from(buf, addr, ecn, None).await
+  }
+
+  /// Like `send_to`, from `source` if given, see `ecn::send_from`; endpoints and TCP connections have only
+  /// one address, and TCP carries no ECN.
+  pub async fn send_from(
+    &self,
+    buf: &[u8],
+    addr: SocketAddr,
+    ecn: u8,
+    source: Option<Ipv4Addr>,
+  ) -> io::Result<usize> {
+    match self {
+      Self::Udp(socket) => ecn::send_from(socket, buf, addr, ecn, source).await,
+      Self::Memory(endpoint) => Ok(endpoint.send_to(buf, addr, ecn)),
+      Self::Tcp(transport) => transport.send_to(buf, addr),
+      Self::UdpAndTcp(_, transport) if transport.has_peer(addr) => transport.send_to(buf, addr),
+      Self::UdpAndTcp(socket, _) => ecn::send_from(socket, buf, addr, ecn, source).await,
+    }
+  }
+
+  /// Next datagram with its sender and the ECN field of its outer header, see `ecn::recv_from`.
+  pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u8)> {
+    let (len, addr, ecn, _) = self.recv_from_to(buf).await?;
+    Ok((len, addr, ecn))
+  }
+
+  /// Like `recv_from`, also returning the local address the datagram was sent to, see `ecn::recv_from_to`.
+  pub async fn recv_from_to(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u8, Option<Ipv4Addr>)> {
+    match self {
+      Self::Udp(socket) => ecn::recv_from_to(socket, buf).await,
+      Self::Memory(endpoint) => {
+        let (len, addr, ecn) = endpoint.recv_from(buf).await?;
+        Ok((len, addr, ecn, None))
+      }
+      Self::Tcp(transport) => {
+        let (datagram, addr) = transport.recv().await?;
+        Ok((truncate_into(buf, &datagram), addr, ip::ECN_NOT_ECT, None))
+      }
+      Self::UdpAndTcp(socket, transport) => {
+        let (datagram, addr) = tokio::select! {
+          received = ecn::recv_from_to(socket, buf) => return received,
+          received = transport.recv() => received?,
+        };
+        Ok((truncate_into(buf, &datagram), addr, ip::ECN_NOT_ECT, None))
+      }
+    }
+  }
+}
+
+/// Copies `datagram` into `buf`, truncated like a UDP datagram larger than the buffer.
+fn truncate_into(buf: &mut [u8], datagram: &[u8]) -> usize {
+  let len = datagram.len().min(buf.len());
+  buf[..len].copy_from_slice(&datagram[..len]);
+  len
+}
+
+/// Addresses of the endpoints bound on a network; addresses are only names here, so any will do as long as
+/// peers agree on them.
+#[derive(Clone, Default)]
+pub struct Network {
+  endpoints: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Datagram>>>>,
+}
+
+impl Network {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Takes `addr`, or a free port of its address if the port is 0.
+  pub fn bind(&self, mut addr: SocketAddr) -> io::Result<Endpoint> {
+    let mut endpoints = self.endpoints.lock().unwrap();
+    if addr.port() == 0 {
+      let port = (EPHEMERAL_PORTS..=u16::MAX)
+        .find(|&port| !endpoints.contains_key(&SocketAddr::new(addr.ip(), port)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "No free ports left"))?;
+      addr.set_port(port);
+    }
+    if endpoints.contains_key(&addr) {
+      return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already bound", addr)));
+    }
+
+    let (sender, receiver) = mpsc::channel(QUEUE_DEPTH);
+    endpoints.insert(addr, sender);
+    Ok(Endpoint { addr, network: self.clone(), receiver: tokio::sync::Mutex::new(receiver) })
+  }
+}
+
+/// Address bound on a `Network`, until it's dropped.
+pub struct Endpoint {
+  addr: SocketAddr,
+  network: Network,
+  receiver: tokio::sync::Mutex<mpsc::Receiver<Datagram>>,
+}
+
+impl Endpoint {
+  /// Like UDP, datagrams to addresses nobody is bound to and to endpoints that are behind are lost.
+  fn send_to(&self, buf: &[u8], addr: SocketAddr, ecn: u8) -> usize {
+    let sender = self.network.endpoints.lock().unwrap().get(&addr).cloned();
+    if let Some(sender) = sender {
+      _ = sender.try_send((buf.to_vec(), self.addr, ecn));
+    }
+    buf.len()
+  }
+
+  async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u8)> {
+    // The network holds a sender until the endpoint is dropped, so the channel stays open.
+    let (datagram, addr, ecn) = self.receiver.lock().await.recv().await.expect("sender is kept");
+    Ok((truncate_into(buf, &datagram), addr, ecn))
+  }
+}
+
+impl Drop for Endpoint {
+  fn drop(&mut self) {
+    self.network.endpoints.lock().unwrap().remove(&self.addr);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_network() {
+    let network = Network::new();
+    let server = Socket::Memory(network.bind("10.0.0.1:6969".parse().unwrap()).unwrap());
+    assert!(network.bind("10.0.0.1:6969".parse().unwrap()).is_err());
+    let client = Socket::Memory(network.bind("10.0.0.2:0".parse().unwrap()).unwrap());
+    let client_addr = client.local_addr().unwrap();
+    assert_eq!(client_addr, "10.0.0.2:49152".parse().unwrap());
+
+    client.send_to(b"ping", server.local_addr().unwrap(), ip::ECN_CE).await.unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(server.recv_from(&mut buf).await.unwrap(), (4, client_addr, ip::ECN_CE));
+    assert_eq!(&buf[..4], b"ping");
+
+    // Nobody is bound there once the client is gone, so replies are lost.
+    drop(client);
+    assert_eq!(server.send_to(b"pong", client_addr, ip::ECN_NOT_ECT).await.unwrap(), 4);
+    assert!(network.bind(client_addr).is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_udp_and_tcp() {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let tcp = TcpTransport::listen("127.0.0.1:0".parse().unwrap(), 16, std::time::Duration::from_secs(30))
+      .await
+      .unwrap();
+    let tcp_addr = tcp.local_addr();
+    let server = Socket::UdpAndTcp(udp, tcp);
+    let server_addr = server.local_addr().unwrap();
+
+    let mut buf = [0u8; 16];
+    let over_tcp = Socket::Tcp(TcpTransport::connect(tcp_addr));
+    over_tcp.send_to(b"ping", tcp_addr, ip::ECN_NOT_ECT).await.unwrap();
+    let (len, peer, _) = server.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    server.send_to(b"pong", peer, ip::ECN_NOT_ECT).await.unwrap();
+    assert_eq!(over_tcp.recv_from(&mut buf).await.unwrap(), (4, tcp_addr, ip::ECN_NOT_ECT));
+    assert_eq!(&buf[..4], b"pong");
+
+    // Peers without a connection are answered over UDP.
+    let over_udp = Socket::Udp(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    over_udp.send_to(b"ping", server_addr, ip::ECN_NOT_ECT).await.unwrap();
+    let (_, peer, _) = server.recv_from(&mut buf).await.unwrap();
+    assert_eq!(peer, over_udp.local_addr().unwrap());
+    server.send_to(b"pong", peer, ip::ECN_NOT_ECT).await.unwrap();
+    assert_eq!(over_udp.recv_from(&mut buf).await.unwrap().0, 4);
+
+    // Nobody listens there, so receiving fails once the connection does.
+    drop(server);
+    let refused = Socket::Tcp(TcpTransport::connect(tcp_addr));
+    assert!(refused.recv_from(&mut buf).await.is_err());
+  }
+}
@@ -0,0 +1,248 @@
+//! Datagrams over TCP, for networks that block UDP: each is framed by its length in two big-endian bytes.
+//! Peers are told apart by the address of their connection like UDP senders are, so the protocol above
+//! doesn't see a difference. TCP inside the tunnel retransmits on top of the outer connection and suffers on
+//! lossy links, so this is a fallback rather than a default.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tracing::debug;
+use tracing::warn;
+
+/// Datagrams queued for a connection before further ones are dropped, as a full socket buffer would.
+const QUEUE_DEPTH: usize = 1024;
+/// Bounds of the pause after a failure to accept, e.g. when out of file descriptors.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+pub struct TcpTransport {
+  local: SocketAddr,
+  peers: Peers,
+  incoming: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+  tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl TcpTransport {
+  /// Accepts up to `max_connections` connections at once on `addr`; each is a peer until it's closed, or
+  /// until nothing arrives on it for `idle_timeout`.
+  pub async fn listen(addr: SocketAddr, max_connections: usize, idle_timeout: Duration) -> io::Result<Self> {
+    let listener = TcpListener::bind(addr).await?;
+    let (sender, receiver) = mpsc::channel(QUEUE_DEPTH);
+    let transport = Self::new(listener.local_addr()?, receiver);
+
+    let (peers, tasks) = (Arc::clone(&transport.peers), Arc::clone(&transport.tasks));
+    let accept = tokio::spawn(async move {
+      let mut backoff = MIN_ACCEPT_BACKOFF;
+      loop {
+        match listener.accept().await {
+          Ok((stream, peer)) => {
+            backoff = MIN_ACCEPT_BACKOFF;
+            if peers.lock().unwrap().len() >= max_connections {
+              debug!("Refusing a TCP connection from {}: {} connections open", peer, max_connections);
+              continue;
+            }
+            debug!("Accepted a TCP connection from {}", peer);
+            spawn_connection(stream, peer, &peers, &tasks, sender.clone(), idle_timeout);
+          }
+          Err(e) => {
+            warn!("Failed to accept a TCP connection: {}; pausing for {:?}", e, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+          }
+        }
+      }
+    });
+    transport.tasks.lock().unwrap().push(accept.abort_handle());
+    Ok(transport)
+  }
+
+  /// Connects to `server` in the background. Datagrams sent meanwhile are queued, and receiving fails once
+  /// the connection can't be made or is closed; the local address stays unspecified.
+  pub fn connect(server: SocketAddr) -> Self {
+    let (sender, receiver) = mpsc::channel(QUEUE_DEPTH);
+    let transport = Self::new(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0), receiver);
+    let (writes_tx, writes_rx) = mpsc::channel(QUEUE_DEPTH);
+    transport.peers.lock().unwrap().insert(server, writes_tx);
+
+    let peers = Arc::clone(&transport.peers);
+    let task = tokio::spawn(async move {
+      match TcpStream::connect(server).await {
+        Ok(stream) => serve(stream, server, writes_rx, sender, peers, None).await,
+        Err(e) => {
+          debug!("Failed to connect to {} over TCP: {}", server, e);
+          peers.lock().unwrap().remove(&server);
+        }
+      }
+    });
+    transport.tasks.lock().unwrap().push(task.abort_handle());
+    transport
+  }
+
+  fn new(local: SocketAddr, receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>) -> Self {
+    Self {
+      local,
+      peers: Default::default(),
+      incoming: tokio::sync::Mutex::new(receiver),
+      tasks: Default::default(),
+    }
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local
+  }
+
+  /// Whether `addr` has a connection to send to.
+  pub fn has_peer(&self, addr: SocketAddr) -> bool {
+    self.peers.lock().unwrap().contains_key(&addr)
+  }
+
+  /// Queues `buf` on the connection of `addr`; like UDP, it's dropped if the connection is behind.
+  pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    if buf.len() > u16::MAX as usize {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "Datagram too large to frame"));
+    }
+    let sender = self.peers.lock().unwrap().get(&addr).cloned();
+    match sender {
+      Some(sender) => {
+        _ = sender.try_send(buf.to_vec());
+        Ok(buf.len())
+      }
+      None => Err(io::Error::new(io::ErrorKind::NotConnected, format!("No TCP connection to {}", addr))),
+    }
+  }
+
+  /// Next datagram from any of the connections, with the address of its peer.
+  pub async fn recv(&self) -> io::Result<(Vec<u8>, SocketAddr)> {
+    // Listeners keep a sender for as long as they accept, so only a connection to a server ends.
+    self
+      .incoming
+      .lock()
+      .await
+      .recv()
+      .await
+      .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionReset, "TCP connection closed"))
+  }
+}
+
+impl Drop for TcpTransport {
+  fn drop(&mut self) {
+    for task in self.tasks.lock().unwrap().drain(..) {
+      task.abort();
+    }
+  }
+}
+
+fn spawn_connection(
+  stream: TcpStream,
+  peer: SocketAddr,
+  peers: &Peers,
+  tasks: &Mutex<Vec<AbortHandle>>,
+  incoming: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+  idle_timeout: Duration,
+) {
+  let (writes_tx, writes_rx) = mpsc::channel(QUEUE_DEPTH);
+  peers.lock().unwrap().insert(peer, writes_tx);
+  let task = tokio::spawn(serve(stream, peer, writes_rx, incoming, Arc::clone(peers), Some(idle_timeout)));
+
+  let mut tasks = tasks.lock().unwrap();
+  tasks.retain(|task| !task.is_finished());
+  tasks.push(task.abort_handle());
+}
+
+/// Moves frames between the connection with `peer` and the transport until either side closes, or until
+/// no frame arrives within `idle_timeout`.
+async fn serve(
+  stream: TcpStream,
+  peer: SocketAddr,
+  mut writes: mpsc::Receiver<Vec<u8>>,
+  incoming: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+  peers: Peers,
+  idle_timeout: Option<Duration>,
+) {
+  // Datagrams are small and latency matters more than the number of segments.
+  _ = stream.set_nodelay(true);
+  let (mut reader, mut writer) = stream.into_split();
+  let reading = async {
+    loop {
+      let frame = match idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read_frame(&mut reader))
+          .await
+          .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Idle for too long"))??,
+        None => read_frame(&mut reader).await?,
+      };
+      if incoming.send((frame, peer)).await.is_err() {
+        return Ok(());
+      }
+    }
+  };
+  let writing = async {
+    while let Some(datagram) = writes.recv().await {
+      write_frame(&mut writer, &datagram).await?;
+    }
+    Ok(())
+  };
+  let result: io::Result<()> = tokio::select! {
+    result = reading => result,
+    result = writing => result,
+  };
+  if let Err(e) = result {
+    debug!("TCP connection with {} closed: {}", peer, e);
+  }
+  peers.lock().unwrap().remove(&peer);
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+  let len = reader.read_u16().await?;
+  let mut frame = vec![0u8; len as usize];
+  reader.read_exact(&mut frame).await?;
+  Ok(frame)
+}
+
+async fn write_frame(writer: &mut OwnedWriteHalf, datagram: &[u8]) -> io::Result<()> {
+  let mut frame = Vec::with_capacity(2 + datagram.len());
+  frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+  frame.extend_from_slice(datagram);
+  writer.write_all(&frame).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_listen_limits() {
+    let transport =
+      TcpTransport::listen("127.0.0.1:0".parse().unwrap(), 1, Duration::from_millis(200)).await.unwrap();
+    let addr = transport.local_addr();
+
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    first.write_all(&[0, 2, 1, 2]).await.unwrap();
+    let (frame, peer) = transport.recv().await.unwrap();
+    assert_eq!((frame, peer), (vec![1, 2], first.local_addr().unwrap()));
+
+    // Past the limit, connections are closed right away.
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_millis(100), second.read_u8()).await.unwrap();
+    assert!(closed.is_err());
+
+    // Connections sending nothing are closed once idle for long enough, which makes room for others.
+    let closed = tokio::time::timeout(Duration::from_secs(1), first.read_u8()).await.unwrap();
+    assert!(closed.is_err());
+    assert!(!transport.has_peer(peer));
+  }
+}
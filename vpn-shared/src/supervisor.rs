@@ -0,0 +1,211 @@
+//! Supervision of the long-running tasks of a process. A task that panics or ends, which none of them is
+//! meant to, is logged and restarted after a delay; one that can't be restarted, or keeps failing, is
+//! reported through `Supervisor::failed` so that the process shuts down instead of running half-alive.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tracing::error;
+
+/// Delay before a task is restarted.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Restarts of a task within `RESTART_WINDOW` after which it's given up on.
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+  /// Restarted unless it keeps failing.
+  Always,
+  /// Holds state that can't be rebuilt, so its end is fatal.
+  Never,
+}
+
+/// What a task returns when it ends: nothing, or why it failed.
+pub trait Outcome {
+  fn into_result(self) -> anyhow::Result<()>;
+}
+
+impl Outcome for () {
+  fn into_result(self) -> anyhow::Result<()> {
+    Ok(())
+  }
+}
+
+impl Outcome for anyhow::Result<()> {
+  fn into_result(self) -> anyhow::Result<()> {
+    self
+  }
+}
+
+/// Failure of a task that restarting can't fix, e.g. because something it depends on is gone; it's given up
+/// on right away.
+#[derive(Debug)]
+pub struct Fatal(pub String);
+
+impl fmt::Display for Fatal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for Fatal {}
+
+/// Tasks run until `stop`, even when the supervisor is dropped.
+#[derive(Clone, Default)]
+pub struct Supervisor {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  tasks: Mutex<Vec<AbortHandle>>,
+  failures: mpsc::UnboundedSender<anyhow::Error>,
+  failed: tokio::sync::Mutex<mpsc::UnboundedReceiver<anyhow::Error>>,
+}
+
+impl Default for Inner {
+  fn default() -> Self {
+    let (failures, failed) = mpsc::unbounded_channel();
+    Self { tasks: Mutex::default(), failures, failed: tokio::sync::Mutex::new(failed) }
+  }
+}
+
+/// Aborts a task when dropped, so that it stops along with its supervision.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+impl Supervisor {
+  /// Runs the task `start` makes, making it again after a failure if `restart` allows.
+  pub fn spawn<F, Fut>(&self, name: &'static str, restart: Restart, start: F)
+  where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Outcome + Send,
+  {
+    let failures = self.inner.failures.clone();
+    let supervision = tokio::spawn(async move {
+      let mut restarts = VecDeque::new();
+      loop {
+        let mut task = tokio::spawn(start());
+        let _abort = AbortOnDrop(task.abort_handle());
+        let (outcome, fatal) = match (&mut task).await {
+          Ok(outcome) => match outcome.into_result() {
+            Ok(()) => ("exited".to_string(), false),
+            Err(e) => (format!("failed: {}", e), e.is::<Fatal>()),
+          },
+          Err(e) if e.is_panic() => (format!("panicked: {}", panic_message(e.into_panic())), false),
+          Err(_) => return,
+        };
+
+        let now = Instant::now();
+        while restarts.front().is_some_and(|at| now.duration_since(*at) > RESTART_WINDOW) {
+          restarts.pop_front();
+        }
+        if restart == Restart::Never || fatal || restarts.len() >= MAX_RESTARTS {
+          error!("Task {} {}; shutting down", name, outcome);
+          _ = failures.send(anyhow::anyhow!("Task {} {}", name, outcome));
+          return;
+        }
+        restarts.push_back(now);
+        error!("Task {} {}; restarting it in {:?}", name, outcome, RESTART_DELAY);
+        tokio::time::sleep(RESTART_DELAY).await;
+      }
+    });
+
+    let mut tasks = self.inner.tasks.lock().unwrap();
+    tasks.retain(|task| !task.is_finished());
+    tasks.push(supervision.abort_handle());
+  }
+
+  /// Stops every task.
+  pub fn stop(&self) {
+    for task in self.inner.tasks.lock().unwrap().drain(..) {
+      task.abort();
+    }
+  }
+
+  /// Waits for a task to fail for good, returning why.
+  pub async fn failed(&self) -> anyhow::Error {
+    // The supervisor keeps a sender itself, so the channel never closes.
+    match self.inner.failed.lock().await.recv().await {
+      Some(e) => e,
+      None => std::future::pending().await,
+    }
+  }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+  match payload.downcast::<String>() {
+    Ok(message) => *message,
+    Err(payload) => payload.downcast_ref::<&str>().map_or("unknown panic".to_string(), |s| s.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn test_restart() {
+    let supervisor = Supervisor::default();
+    let starts = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&starts);
+    supervisor.spawn("flaky", Restart::Always, move || {
+      let starts = counted.fetch_add(1, Ordering::Relaxed) + 1;
+      async move {
+        if starts == 1 {
+          panic!("first start");
+        }
+        std::future::pending::<()>().await
+      }
+    });
+
+    tokio::time::sleep(RESTART_DELAY + Duration::from_millis(200)).await;
+    assert_eq!(starts.load(Ordering::Relaxed), 2);
+    assert!(tokio::time::timeout(Duration::from_millis(50), supervisor.failed()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_fatal() {
+    let supervisor = Supervisor::default();
+    supervisor.spawn("once", Restart::Never, || async { Err(anyhow::anyhow!("socket closed")) });
+    let error = tokio::time::timeout(Duration::from_secs(1), supervisor.failed()).await.unwrap();
+    assert_eq!(error.to_string(), "Task once failed: socket closed");
+
+    supervisor.spawn("tun", Restart::Always, || async { Err(Fatal("tun0 was removed".into()).into()) });
+    let error = tokio::time::timeout(Duration::from_millis(500), supervisor.failed()).await.unwrap();
+    assert_eq!(error.to_string(), "Task tun failed: tun0 was removed");
+  }
+
+  #[tokio::test]
+  async fn test_stop() {
+    let supervisor = Supervisor::default();
+    let (sender, mut receiver) = mpsc::channel::<()>(1);
+    supervisor.spawn("holder", Restart::Always, move || {
+      let sender = sender.clone();
+      async move {
+        std::future::pending::<()>().await;
+        drop(sender);
+      }
+    });
+    supervisor.stop();
+    // The task dropping its sender closes the channel.
+    assert!(tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await.unwrap().is_none());
+  }
+}
@@ -0,0 +1,551 @@
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::Tag;
+use chacha20poly1305::XChaCha20Poly1305;
+
+use crate::packet::fill_random_bytes;
+use crate::packet::peek_session_id;
+use crate::packet::EncryptedPacket;
+use crate::packet::Key;
+use crate::packet::SessionId;
+use crate::packet::WirePacket;
+use crate::packet::KEY_SIZE;
+use crate::packet::MAX_DATAGRAM_SIZE;
+use crate::packet::NONCE_SIZE;
+use crate::packet::SESSION_ID_SIZE;
+use crate::packet::TAG_SIZE;
+
+/// Pads datagrams with random bytes to a multiple of `PAD_BLOCK`, hiding the exact sizes of packets.
+pub const PAD: &str = "pad";
+const PAD_BLOCK: usize = 64;
+
+/// Compresses packets with a small LZ77 variant, sending them as they are when that doesn't make them
+/// shorter. The length of a compressed packet tells something of its contents, so it's best left to
+/// traffic that isn't encrypted already and doesn't mix secrets with data others control.
+pub const LZ: &str = "lz";
+const LZ_STORED: u8 = 0;
+const LZ_COMPRESSED: u8 = 1;
+const LZ_MIN_MATCH: usize = 4;
+const LZ_MAX_MATCH: usize = LZ_MIN_MATCH + 0x7f;
+const LZ_MAX_LITERALS: usize = 0x80;
+const LZ_HASH_BITS: u32 = 12;
+
+/// Encrypts with XChaCha20-Poly1305 instead of ChaCha20-Poly1305, whose random 24-byte nonces don't
+/// repeat however long the session.
+pub const XCHACHA20: &str = "xchacha20";
+const XNONCE_SIZE: usize = 24;
+
+/// Where a transform runs. Packets are compressed, encrypted and then obfuscated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+  /// On the serialized packet, before encryption.
+  Compress,
+  /// Encryption with the session key, ChaCha20-Poly1305 unless a `Cipher` is agreed on.
+  Encrypt,
+  /// On the encrypted packet; the session id in front stays readable so the server can find the session.
+  Obfuscate,
+}
+
+pub trait Transform: Send + Sync {
+  /// Applied to packets being sent.
+  fn apply(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+  /// Undoes `apply` on received packets.
+  fn reverse(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+  /// Most bytes `apply` adds to a packet, which the MTU of the session leaves room for.
+  fn overhead(&self) -> usize {
+    0
+  }
+}
+
+/// Encryption of the `Encrypt` stage, taking the place of the built-in one.
+pub trait Cipher: Send + Sync {
+  /// Encrypts a packet into what follows the session id in the datagram, authenticating the session id.
+  fn seal(&self, session_id: SessionId, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+  /// Undoes `seal`.
+  fn open(&self, session_id: SessionId, sealed: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+  /// Bytes `seal` adds to a packet.
+  fn overhead(&self) -> usize;
+}
+
+/// Makes the transform of a session, which may be keyed by the session key.
+pub type Factory = fn(&Key) -> Box<dyn Transform>;
+
+/// Makes the cipher of a session from the session key.
+pub type CipherFactory = fn(&Key) -> Box<dyn Cipher>;
+
+#[derive(Clone, Copy)]
+enum Build {
+  Transform(Factory),
+  Cipher(CipherFactory),
+}
+
+#[derive(Clone)]
+struct Entry {
+  name: &'static str,
+  stage: Stage,
+  build: Build,
+}
+
+/// Transforms peers can agree on by name; new ones are registered here and need no changes to the data path.
+#[derive(Clone)]
+pub struct Registry {
+  entries: Vec<Entry>,
+}
+
+impl Default for Registry {
+  fn default() -> Self {
+    let mut registry = Self { entries: Vec::new() };
+    registry.register(LZ, Stage::Compress, |_| Box::new(Lz));
+    registry.register_cipher(XCHACHA20, |key| Box::new(XChaCha20(XChaCha20Poly1305::new(key.into()))));
+    registry.register(PAD, Stage::Obfuscate, |_| Box::new(Pad));
+    registry
+  }
+}
+
+impl Registry {
+  /// Adds a transform, replacing one of the same name. Ciphers are added with `register_cipher`.
+  pub fn register(&mut self, name: &'static str, stage: Stage, factory: Factory) {
+    assert_ne!(stage, Stage::Encrypt, "Transform {} of the encryption stage must be a cipher", name);
+    self.add(Entry { name, stage, build: Build::Transform(factory) });
+  }
+
+  /// Adds a cipher of the `Encrypt` stage, replacing a transform of the same name.
+  pub fn register_cipher(&mut self, name: &'static str, factory: CipherFactory) {
+    self.add(Entry { name, stage: Stage::Encrypt, build: Build::Cipher(factory) });
+  }
+
+  fn add(&mut self, entry: Entry) {
+    self.entries.retain(|existing| existing.name != entry.name);
+    self.entries.push(entry);
+  }
+
+  fn get(&self, name: &str) -> Option<&Entry> {
+    self.entries.iter().find(|entry| entry.name == name)
+  }
+
+  /// Fails on names that aren't registered, so a typo in a configuration is caught at startup.
+  pub fn check(&self, names: &[String]) -> anyhow::Result<()> {
+    match names.iter().find(|name| self.get(name).is_none()) {
+      Some(name) => anyhow::bail!("Unknown transform {}", name),
+      None => Ok(()),
+    }
+  }
+
+  /// Picks, in the client's order of preference, the first offered transform of every stage the server
+  /// accepts.
+  pub fn negotiate(&self, offered: &[String], accepted: &[String]) -> Vec<String> {
+    let mut stages = Vec::new();
+    let mut picked = Vec::new();
+    for name in offered.iter().filter(|name| accepted.contains(name)) {
+      if let Some(entry) = self.get(name).filter(|entry| !stages.contains(&entry.stage)) {
+        stages.push(entry.stage);
+        picked.push(name.clone());
+      }
+    }
+    picked
+  }
+
+  /// Builds the pipeline of a session from the negotiated transforms.
+  pub fn pipeline(&self, names: &[String], key: &Key) -> anyhow::Result<Pipeline> {
+    let mut stages: Vec<(Stage, Box<dyn Transform>)> = Vec::new();
+    let mut cipher = None;
+    for name in names {
+      let Some(entry) = self.get(name) else {
+        anyhow::bail!("Unknown transform {}", name);
+      };
+      if stages.iter().any(|(stage, _)| *stage == entry.stage)
+        || (entry.stage == Stage::Encrypt && cipher.is_some())
+      {
+        anyhow::bail!("More than one {:?} transform", entry.stage);
+      }
+      match entry.build {
+        Build::Transform(factory) => stages.push((entry.stage, factory(key))),
+        Build::Cipher(factory) => cipher = Some(factory(key)),
+      }
+    }
+    stages.sort_by_key(|(stage, _)| *stage);
+
+    Ok(Pipeline { names: names.to_vec(), stages, cipher, raw_data: false })
+  }
+
+  /// Most bytes a pipeline negotiated from `names` adds to datagrams on top of `packet::DATA_OVERHEAD`, see
+  /// `Pipeline::overhead`.
+  pub fn overhead(&self, names: &[String]) -> anyhow::Result<usize> {
+    let mut stages: Vec<(Stage, usize)> = Vec::new();
+    for name in names {
+      let overhead = self.pipeline(std::slice::from_ref(name), &[0; KEY_SIZE])?.overhead();
+      let stage = self.get(name).map(|entry| entry.stage);
+      match stages.iter_mut().find(|(s, _)| Some(*s) == stage) {
+        Some((_, most)) => *most = (*most).max(overhead),
+        None => stages.extend(stage.map(|stage| (stage, overhead))),
+      }
+    }
+    Ok(stages.iter().map(|(_, overhead)| overhead).sum())
+  }
+}
+
+/// Transforms of a session around encryption; without any, datagrams are plain `EncryptedPacket`s.
+#[derive(Default)]
+pub struct Pipeline {
+  names: Vec<String>,
+  stages: Vec<(Stage, Box<dyn Transform>)>,
+  cipher: Option<Box<dyn Cipher>>,
+  /// Whether `Data` packets are sent without bincode's framing, see `Features::RAW_DATA`.
+  raw_data: bool,
+}
+
+impl std::fmt::Debug for Pipeline {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_tuple("Pipeline").field(&self.names).finish()
+  }
+}
+
+impl Pipeline {
+  pub fn names(&self) -> &[String] {
+    &self.names
+  }
+
+  pub fn with_raw_data(mut self, raw_data: bool) -> Self {
+    self.raw_data = raw_data;
+    self
+  }
+
+  pub fn raw_data(&self) -> bool {
+    self.raw_data
+  }
+
+  /// Most bytes the transforms add to a datagram over the built-in encryption alone, which the MTU of the
+  /// session gives up so datagrams stay as large as they'd be without them.
+  pub fn overhead(&self) -> usize {
+    let cipher =
+      self.cipher.as_ref().map_or(0, |cipher| cipher.overhead().saturating_sub(NONCE_SIZE + TAG_SIZE));
+    self.stages.iter().map(|(_, transform)| transform.overhead()).sum::<usize>() + cipher
+  }
+
+  fn stage(&self, stage: Stage) -> impl DoubleEndedIterator<Item = &dyn Transform> {
+    self.stages.iter().filter(move |(s, _)| *s == stage).map(|(_, transform)| transform.as_ref())
+  }
+
+  /// Serializes, transforms and encrypts a packet into a datagram.
+  pub fn seal<P: WirePacket>(&self, key: &Key, session_id: SessionId, packet: &P) -> anyhow::Result<Vec<u8>> {
+    let mut data = packet.encode(self.raw_data)?;
+    for transform in self.stage(Stage::Compress) {
+      data = transform.apply(data)?;
+    }
+
+    let mut datagram = match self.cipher {
+      Some(ref cipher) => {
+        let mut datagram = session_id.to_be_bytes().to_vec();
+        datagram.extend(cipher.seal(session_id, data)?);
+        datagram
+      }
+      None => EncryptedPacket::seal_in_place(key, session_id, data)?.to_bytes(),
+    };
+    for transform in self.stage(Stage::Obfuscate) {
+      let body = datagram.split_off(SESSION_ID_SIZE);
+      datagram.extend(transform.apply(body)?);
+    }
+    Ok(datagram)
+  }
+
+  /// Undoes `seal`.
+  pub fn open<P: WirePacket>(&self, key: &Key, datagram: &[u8]) -> anyhow::Result<P> {
+    let mut datagram = datagram.to_vec();
+    for transform in self.stage(Stage::Obfuscate).rev() {
+      if datagram.len() < SESSION_ID_SIZE {
+        anyhow::bail!("Packet too short");
+      }
+      let body = datagram.split_off(SESSION_ID_SIZE);
+      datagram.extend(transform.reverse(body)?);
+    }
+
+    let mut data = match self.cipher {
+      Some(ref cipher) => {
+        let Some(session_id) = peek_session_id(&datagram) else {
+          anyhow::bail!("Packet too short");
+        };
+        cipher.open(session_id, datagram.split_off(SESSION_ID_SIZE))?
+      }
+      None => EncryptedPacket::from_bytes(&datagram)?.open_in_place(key)?,
+    };
+    for transform in self.stage(Stage::Compress).rev() {
+      data = transform.reverse(data)?;
+    }
+    P::decode(&data)
+  }
+}
+
+struct Pad;
+
+impl Transform for Pad {
+  fn apply(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let padding = (PAD_BLOCK - (data.len() + 2) % PAD_BLOCK) % PAD_BLOCK;
+    let start = data.len();
+    data.resize(start + padding, 0);
+    fill_random_bytes(&mut data[start..]);
+    data.extend_from_slice(&(padding as u16).to_be_bytes());
+    Ok(data)
+  }
+
+  fn reverse(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let Some(trailer) = data.len().checked_sub(2) else {
+      anyhow::bail!("Packet too short");
+    };
+    let padding = u16::from_be_bytes([data[trailer], data[trailer + 1]]) as usize;
+    let Some(end) = trailer.checked_sub(padding) else {
+      anyhow::bail!("Invalid padding");
+    };
+    data.truncate(end);
+    Ok(data)
+  }
+
+  fn overhead(&self) -> usize {
+    PAD_BLOCK - 1 + 2
+  }
+}
+
+struct Lz;
+
+impl Transform for Lz {
+  fn apply(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let compressed = lz_compress(&data);
+    if compressed.len() <= data.len() {
+      return Ok(compressed);
+    }
+    let mut stored = Vec::with_capacity(data.len() + 1);
+    stored.push(LZ_STORED);
+    stored.extend(data);
+    Ok(stored)
+  }
+
+  fn reverse(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match data.split_first() {
+      Some((&LZ_STORED, _)) => Ok(data[1..].to_vec()),
+      Some((&LZ_COMPRESSED, compressed)) => lz_decompress(compressed),
+      _ => anyhow::bail!("Invalid compressed packet"),
+    }
+  }
+
+  fn overhead(&self) -> usize {
+    1
+  }
+}
+
+/// Runs of literals are a byte of their length less one below `0x80` followed by the literals; matches are
+/// `0x80` with their length less `LZ_MIN_MATCH` and two bytes of how far back they start.
+fn lz_compress(data: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(data.len() + 1);
+  out.push(LZ_COMPRESSED);
+
+  let mut table = vec![0usize; 1 << LZ_HASH_BITS];
+  let (mut start, mut i) = (0, 0);
+  while i + LZ_MIN_MATCH <= data.len() {
+    let word = u32::from_le_bytes(data[i..i + LZ_MIN_MATCH].try_into().unwrap());
+    let hash = (word.wrapping_mul(2654435761) >> (32 - LZ_HASH_BITS)) as usize;
+    // Positions are stored plus one, so zero is an empty slot.
+    let candidate = std::mem::replace(&mut table[hash], i + 1);
+    let matched = candidate
+      .checked_sub(1)
+      .filter(|&at| i - at <= u16::MAX as usize && data[at..at + LZ_MIN_MATCH] == data[i..i + LZ_MIN_MATCH]);
+    let Some(at) = matched else {
+      i += 1;
+      continue;
+    };
+
+    let mut len = LZ_MIN_MATCH;
+    while len < LZ_MAX_MATCH && i + len < data.len() && data[at + len] == data[i + len] {
+      len += 1;
+    }
+    lz_literals(&mut out, &data[start..i]);
+    out.push(0x80 | (len - LZ_MIN_MATCH) as u8);
+    out.extend_from_slice(&((i - at) as u16).to_be_bytes());
+    i += len;
+    start = i;
+  }
+  lz_literals(&mut out, &data[start..]);
+  out
+}
+
+fn lz_literals(out: &mut Vec<u8>, literals: &[u8]) {
+  for chunk in literals.chunks(LZ_MAX_LITERALS) {
+    out.push((chunk.len() - 1) as u8);
+    out.extend_from_slice(chunk);
+  }
+}
+
+fn lz_decompress(mut data: &[u8]) -> anyhow::Result<Vec<u8>> {
+  let mut out = Vec::with_capacity(data.len() * 2);
+  while let Some((&token, rest)) = data.split_first() {
+    if token & 0x80 == 0 {
+      let Some(literals) = rest.get(..token as usize + 1) else {
+        anyhow::bail!("Truncated compressed packet");
+      };
+      out.extend_from_slice(literals);
+      data = &rest[literals.len()..];
+    } else {
+      let Some(&[high, low]) = rest.get(..2) else {
+        anyhow::bail!("Truncated compressed packet");
+      };
+      let distance = u16::from_be_bytes([high, low]) as usize;
+      let Some(from) = out.len().checked_sub(distance).filter(|_| distance > 0) else {
+        anyhow::bail!("Invalid match in compressed packet");
+      };
+      // Matches may overlap what they produce, so they're copied a byte at a time.
+      for offset in 0..(token & 0x7f) as usize + LZ_MIN_MATCH {
+        out.push(out[from + offset]);
+      }
+      data = &rest[2..];
+    }
+    if out.len() > MAX_DATAGRAM_SIZE {
+      anyhow::bail!("Compressed packet expands past {} bytes", MAX_DATAGRAM_SIZE);
+    }
+  }
+  Ok(out)
+}
+
+struct XChaCha20(XChaCha20Poly1305);
+
+impl Cipher for XChaCha20 {
+  fn seal(&self, session_id: SessionId, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0u8; XNONCE_SIZE];
+    fill_random_bytes(&mut nonce);
+    let tag = self
+      .0
+      .encrypt_in_place_detached((&nonce).into(), &session_id.to_be_bytes(), &mut data)
+      .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+
+    let mut sealed = Vec::with_capacity(XNONCE_SIZE + data.len() + TAG_SIZE);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend(data);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+  }
+
+  fn open(&self, session_id: SessionId, mut sealed: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if sealed.len() < XNONCE_SIZE + TAG_SIZE {
+      anyhow::bail!("Packet too short");
+    }
+    let tag = Tag::clone_from_slice(&sealed.split_off(sealed.len() - TAG_SIZE));
+    let mut data = sealed.split_off(XNONCE_SIZE);
+    self
+      .0
+      .decrypt_in_place_detached(sealed[..].into(), &session_id.to_be_bytes(), &mut data, &tag)
+      .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))?;
+    Ok(data)
+  }
+
+  fn overhead(&self) -> usize {
+    XNONCE_SIZE + TAG_SIZE
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::packet::ClientPacket;
+  use crate::packet::KEY_SIZE;
+
+  /// Reverses the packet, standing in for a compressor to check the order of stages.
+  struct Reverse;
+
+  impl Transform for Reverse {
+    fn apply(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+      data.reverse();
+      Ok(data)
+    }
+
+    fn reverse(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+      self.apply(data)
+    }
+  }
+
+  fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+  }
+
+  #[test]
+  fn test_negotiate() {
+    let mut registry = Registry::default();
+    registry.register("reverse", Stage::Compress, |_| Box::new(Reverse));
+    registry.register("other", Stage::Compress, |_| Box::new(Reverse));
+
+    let offered = names(&["unknown", "other", "pad", "reverse"]);
+    assert_eq!(registry.negotiate(&offered, &names(&["pad", "reverse", "other"])), names(&["other", "pad"]));
+    assert_eq!(registry.negotiate(&offered, &names(&["reverse"])), names(&["reverse"]));
+    assert!(registry.negotiate(&offered, &[]).is_empty());
+    assert!(registry.check(&names(&["pad", "unknown"])).is_err());
+  }
+
+  #[test]
+  fn test_pipeline_roundtrip() {
+    let mut registry = Registry::default();
+    registry.register("reverse", Stage::Compress, |_| Box::new(Reverse));
+    let key = [7u8; KEY_SIZE];
+
+    let pipeline = registry.pipeline(&names(&["pad", "reverse"]), &key).unwrap();
+    for len in [0, 1, 61, 62, 63, 1400] {
+      let datagram = pipeline.seal(&key, 42, &ClientPacket::Data(vec![1; len])).unwrap();
+      assert_eq!((datagram.len() - SESSION_ID_SIZE) % PAD_BLOCK, 0);
+      assert_eq!(crate::packet::peek_session_id(&datagram), Some(42));
+      let packet: ClientPacket = pipeline.open(&key, &datagram).unwrap();
+      assert!(matches!(packet, ClientPacket::Data(data) if data == vec![1; len]));
+    }
+
+    // Without the pipeline the padding is taken for part of the packet.
+    let datagram = pipeline.seal(&key, 42, &ClientPacket::Ping).unwrap();
+    assert!(Pipeline::default().open::<ClientPacket>(&key, &datagram).is_err());
+    assert!(registry.pipeline(&names(&["pad", "pad"]), &key).is_err());
+  }
+
+  #[test]
+  fn test_lz() {
+    let repetitive: Vec<u8> = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n".repeat(20);
+    let random = {
+      let mut random = vec![0u8; 1400];
+      fill_random_bytes(&mut random);
+      random
+    };
+    for data in [Vec::new(), vec![0; 3], vec![0; 1400], repetitive.clone(), random.clone()] {
+      let compressed = Lz.apply(data.clone()).unwrap();
+      assert!(compressed.len() <= data.len() + Lz.overhead());
+      assert_eq!(Lz.reverse(compressed).unwrap(), data);
+    }
+    assert!(Lz.apply(repetitive.clone()).unwrap().len() < repetitive.len() / 4);
+
+    assert!(Lz.reverse(Vec::new()).is_err());
+    assert!(Lz.reverse(vec![LZ_COMPRESSED, 0x80, 0, 1]).is_err());
+    assert!(Lz.reverse(vec![LZ_COMPRESSED, 5, 1]).is_err());
+    // A run of matches can't expand past the largest datagram.
+    let mut bomb = vec![LZ_COMPRESSED, 0, 0];
+    bomb.extend([0xff, 0, 1].repeat(1000));
+    assert!(Lz.reverse(bomb).is_err());
+  }
+
+  #[test]
+  fn test_cipher() {
+    let registry = Registry::default();
+    let key = [7u8; KEY_SIZE];
+    let pipeline = registry.pipeline(&names(&["xchacha20", "lz"]), &key).unwrap();
+
+    let datagram = pipeline.seal(&key, 42, &ClientPacket::Data(vec![1; 1400])).unwrap();
+    assert!(datagram.len() < 100);
+    assert_eq!(crate::packet::peek_session_id(&datagram), Some(42));
+    let packet: ClientPacket = pipeline.open(&key, &datagram).unwrap();
+    assert!(matches!(packet, ClientPacket::Data(data) if data == vec![1; 1400]));
+
+    // The session id is authenticated, and the built-in cipher can't open the packet.
+    let mut moved = datagram.clone();
+    moved[..SESSION_ID_SIZE].copy_from_slice(&43u64.to_be_bytes());
+    assert!(pipeline.open::<ClientPacket>(&key, &moved).is_err());
+    assert!(Pipeline::default().open::<ClientPacket>(&key, &datagram).is_err());
+
+    assert_eq!(registry.overhead(&names(&["xchacha20", "lz", "pad"])).unwrap(), 12 + 1 + 65);
+    assert_eq!(registry.overhead(&[]).unwrap(), 0);
+    // Only one transform of each stage is picked.
+    assert_eq!(registry.overhead(&names(&["pad", "lz", "pad"])).unwrap(), 65 + 1);
+  }
+}